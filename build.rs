@@ -1,16 +1,79 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
 fn main() {
     let target = &*std::env::var("TARGET")
         .expect("Expected TARGET environment variable");
 
     match target {
         "x86_64-nucloid" => build_x86(target),
+        "aarch64-nucloid" => build_aarch64(),
         _ => (),
     }
 
+    emit_build_id(target);
+
     println!("cargo:rerun-if-changed=src/arch/x86/multiboot2.S");
     println!("cargo:rerun-if-changed=src/arch/x86/start64.S");
     println!("cargo:rerun-if-changed=src/arch/x86/isr_entry64.S");
     println!("cargo:rerun-if-changed=targets/x86_64.ld");
+    println!("cargo:rerun-if-changed=src/arch/aarch64/start.S");
+    println!("cargo:rerun-if-changed=src/arch/aarch64/exceptions.S");
+    println!("cargo:rerun-if-changed=targets/aarch64.ld");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=media");
+}
+
+/// The build ID is derived from the sources, the target and the profile: two
+/// builds of the same tree yield the same ID, which is what we want to match a
+/// crash report with the exact kernel that produced it.
+fn emit_build_id(target: &str) {
+    let profile = std::env::var("PROFILE").unwrap_or_default();
+    let mut files = Vec::new();
+    collect_files(Path::new("src"), &mut files);
+    collect_files(Path::new("media"), &mut files);
+    files.sort();
+
+    // Two FNV-1a 64-bit hashes with different offset bases make 128 bits.
+    let mut hashes = [0xcbf29ce484222325u64, 0x6c62272e07bb0142u64];
+    let mut feed = |bytes: &[u8]| {
+        for hash in hashes.iter_mut() {
+            for &byte in bytes {
+                *hash ^= byte as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    };
+
+    feed(target.as_bytes());
+    feed(profile.as_bytes());
+    feed(env!("CARGO_PKG_VERSION").as_bytes());
+    for file in &files {
+        feed(file.to_string_lossy().as_bytes());
+        feed(&fs::read(file).expect("couldn't read source file"));
+    }
+
+    let [hi, lo] = hashes;
+    println!(
+        "cargo:rustc-env=NUCLOID_BUILD_ID={:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32, (hi >> 16) & 0xffff, hi & 0xffff,
+        lo >> 48, lo & 0xffff_ffff_ffff
+    );
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
 }
 
 fn build_x86(target: &str) {
@@ -28,6 +91,14 @@ fn build_x86(target: &str) {
         .compile("nucloid_c");
 }
 
+fn build_aarch64() {
+    make_c_builder()
+        .file("src/arch/aarch64/start.S")
+        .file("src/arch/aarch64/exceptions.S")
+        .link_lib_modifier("+whole-archive")
+        .compile("nucloid_c");
+}
+
 fn make_c_builder() -> cc::Build {
     let mut build = cc::Build::new();
 
@@ -41,10 +112,15 @@ fn add_c_flags(build: &mut cc::Build) {
     build.pic(false);
     build.flag("-ffreestanding");
     build.flag("-nostdlib");
-    build.flag("-mno-red-zone");
-    build.flag("-mno-sse");
-    build.flag("-mno-avx");
     build.flag("-no-pie");
+
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("x86_64") {
+        build.flag("-mno-red-zone");
+        build.flag("-mno-sse");
+        build.flag("-mno-avx");
+    } else {
+        build.flag("-mgeneral-regs-only");
+    }
 }
 
 fn set_compiler(build: &mut cc::Build) {
@@ -53,6 +129,7 @@ fn set_compiler(build: &mut cc::Build) {
 
     let compiler = match target.as_ref() {
         "x86_64-nucloid" => "x86_64-elf-gcc",
+        "aarch64-nucloid" => "aarch64-elf-gcc",
         other => panic!("unsupported target '{}'", other),
     };
 
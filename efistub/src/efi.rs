@@ -0,0 +1,239 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The few UEFI interfaces the stub needs, as defined by the UEFI
+//! specification. Only the boot services called are typed, the others are
+//! placeholders keeping the layout.
+
+#![allow(dead_code)]
+
+use core::ffi::c_void;
+
+pub type Handle = *mut c_void;
+pub type Status = usize;
+
+pub const SUCCESS: Status = 0;
+pub const LOAD_ERROR: Status = (1 << 63) | 1;
+
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+pub const ALLOCATE_ADDRESS: u32 = 2;
+
+pub const PAGE_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+pub const LOADED_IMAGE_GUID: Guid = Guid(
+    0x5b1b31a1, 0x9562, 0x11d2,
+    [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+);
+pub const GRAPHICS_OUTPUT_GUID: Guid = Guid(
+    0x9042a9de, 0x23dc, 0x4a38,
+    [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
+);
+pub const ACPI_20_TABLE_GUID: Guid = Guid(
+    0x8868e871, 0xe4f1, 0x11d3,
+    [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+);
+pub const ACPI_10_TABLE_GUID: Guid = Guid(
+    0xeb9d2d30, 0x2d88, 0x11d3,
+    [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+);
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryType {
+    Reserved = 0,
+    LoaderCode = 1,
+    LoaderData = 2,
+    BootServicesCode = 3,
+    BootServicesData = 4,
+    RuntimeServicesCode = 5,
+    RuntimeServicesData = 6,
+    Conventional = 7,
+    Unusable = 8,
+    AcpiReclaim = 9,
+    AcpiNvs = 10,
+    MemoryMappedIo = 11,
+    MemoryMappedIoPortSpace = 12,
+    PalCode = 13,
+    Persistent = 14,
+}
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut c_void,
+    pub console_out_handle: Handle,
+    pub con_out: *mut SimpleTextOutput,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut SimpleTextOutput,
+    pub runtime_services: *mut c_void,
+    pub boot_services: *mut BootServices,
+    pub nr_configuration_tables: usize,
+    pub configuration_tables: *const ConfigurationTable,
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *const c_void,
+}
+
+#[repr(C)]
+pub struct SimpleTextOutput {
+    pub reset: usize,
+    pub output_string: unsafe extern "efiapi" fn(
+        this: *mut SimpleTextOutput,
+        string: *const u16,
+    ) -> Status,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+    pub raise_tpl: usize,
+    pub restore_tpl: usize,
+    pub allocate_pages: unsafe extern "efiapi" fn(
+        typ: u32,
+        memory_type: MemoryType,
+        nr_pages: usize,
+        memory: *mut u64,
+    ) -> Status,
+    pub free_pages: unsafe extern "efiapi" fn(
+        memory: u64,
+        nr_pages: usize,
+    ) -> Status,
+    pub get_memory_map: unsafe extern "efiapi" fn(
+        map_size: *mut usize,
+        map: *mut u8,
+        map_key: *mut usize,
+        descriptor_size: *mut usize,
+        descriptor_version: *mut u32,
+    ) -> Status,
+    pub allocate_pool: usize,
+    pub free_pool: usize,
+    pub create_event: usize,
+    pub set_timer: usize,
+    pub wait_for_event: usize,
+    pub signal_event: usize,
+    pub close_event: usize,
+    pub check_event: usize,
+    pub install_protocol_interface: usize,
+    pub reinstall_protocol_interface: usize,
+    pub uninstall_protocol_interface: usize,
+    pub handle_protocol: unsafe extern "efiapi" fn(
+        handle: Handle,
+        protocol: *const Guid,
+        interface: *mut *mut c_void,
+    ) -> Status,
+    pub reserved: usize,
+    pub register_protocol_notify: usize,
+    pub locate_handle: usize,
+    pub locate_device_path: usize,
+    pub install_configuration_table: usize,
+    pub load_image: usize,
+    pub start_image: usize,
+    pub exit: usize,
+    pub unload_image: usize,
+    pub exit_boot_services: unsafe extern "efiapi" fn(
+        image: Handle,
+        map_key: usize,
+    ) -> Status,
+    pub get_next_monotonic_count: usize,
+    pub stall: usize,
+    pub set_watchdog_timer: usize,
+    pub connect_controller: usize,
+    pub disconnect_controller: usize,
+    pub open_protocol: usize,
+    pub close_protocol: usize,
+    pub open_protocol_information: usize,
+    pub protocols_per_handle: usize,
+    pub locate_handle_buffer: usize,
+    pub locate_protocol: unsafe extern "efiapi" fn(
+        protocol: *const Guid,
+        registration: *mut c_void,
+        interface: *mut *mut c_void,
+    ) -> Status,
+}
+
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub typ: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub nr_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *mut SystemTable,
+    pub device_handle: Handle,
+    pub file_path: *mut c_void,
+    pub reserved: *mut c_void,
+    pub load_options_size: u32,
+    pub load_options: *const u16,
+    pub image_base: *mut c_void,
+    pub image_size: u64,
+    pub image_code_type: MemoryType,
+    pub image_data_type: MemoryType,
+    pub unload: usize,
+}
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    pub query_mode: usize,
+    pub set_mode: usize,
+    pub blt: usize,
+    pub mode: *const GraphicsOutputMode,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsModeInfo,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+pub const PIXEL_RGB_RESERVED_8BIT: u32 = 0;
+pub const PIXEL_BGR_RESERVED_8BIT: u32 = 1;
+pub const PIXEL_BIT_MASK: u32 = 2;
+
+#[repr(C)]
+pub struct GraphicsModeInfo {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+    pub pixels_per_scan_line: u32,
+}
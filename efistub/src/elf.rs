@@ -0,0 +1,123 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Loading the kernel ELF at the physical addresses it was linked for.
+
+use crate::efi::{BootServices, MemoryType, ALLOCATE_ADDRESS, PAGE_SIZE, SUCCESS};
+
+const PT_LOAD: u32 = 1;
+
+const MULTIBOOT2_MAGIC: u32 = 0xe85250d6;
+/// How far into the file the Multiboot2 header must be.
+const MULTIBOOT2_SEARCH_LEN: usize = 32768;
+const MULTIBOOT2_TAG_ENTRY: u16 = 3;
+
+/// The kernel, once loaded.
+pub struct Kernel {
+    /// The physical address of the 32-bit entry point.
+    pub entry: u32,
+    /// The physical address past the last loaded byte, page-aligned.
+    pub end: u64,
+}
+
+/// Load the segments of the kernel ELF `image` into memory allocated from the
+/// firmware at their physical addresses.
+///
+/// # Safety #
+///
+/// `bs` must be the firmware's boot services, which must not have been exited.
+pub unsafe fn load(bs: &BootServices, image: &[u8]) -> Result<Kernel, &'static str> {
+    if image.get(0..5) != Some(b"\x7fELF\x02") {
+        return Err("the kernel is not a 64-bit ELF");
+    }
+    let entry = multiboot2_entry(image)
+        .ok_or("no Multiboot2 entry address in the kernel")?;
+
+    let phoff = read_u64(image, 32).ok_or("invalid ELF header")? as usize;
+    let phentsize = read_u16(image, 54).ok_or("invalid ELF header")? as usize;
+    let phnum = read_u16(image, 56).ok_or("invalid ELF header")? as usize;
+
+    let mut end = 0;
+    for i in 0..phnum {
+        let ph = image.get((phoff + i * phentsize)..)
+            .ok_or("invalid program header")?;
+        if read_u32(ph, 0) != Some(PT_LOAD) {
+            continue;
+        }
+        let (Some(offset), Some(paddr), Some(file_bsize), Some(mem_bsize)) =
+            (read_u64(ph, 8), read_u64(ph, 24), read_u64(ph, 32),
+             read_u64(ph, 40)) else {
+            return Err("invalid program header");
+        };
+
+        // Segments may share a page: only allocate the pages not allocated
+        // for the previous ones.
+        let seg_end = align_up(paddr + mem_bsize);
+        let mut alloc_start = (paddr & !(PAGE_SIZE as u64 - 1)).max(end);
+        if alloc_start < seg_end {
+            let nr_pages = ((seg_end - alloc_start) / PAGE_SIZE as u64) as usize;
+            let status = (bs.allocate_pages)(ALLOCATE_ADDRESS,
+                                             MemoryType::LoaderData,
+                                             nr_pages, &mut alloc_start);
+            if status != SUCCESS {
+                return Err("the kernel's physical memory is not available");
+            }
+        }
+        end = end.max(seg_end);
+
+        let data = image.get((offset as usize)..((offset + file_bsize) as usize))
+            .ok_or("truncated segment")?;
+        let dst = paddr as *mut u8;
+        dst.copy_from_nonoverlapping(data.as_ptr(), data.len());
+        dst.add(data.len()).write_bytes(0, (mem_bsize - file_bsize) as usize);
+    }
+
+    Ok(Kernel { entry, end })
+}
+
+/// The entry address in the kernel's Multiboot2 header.
+fn multiboot2_entry(image: &[u8]) -> Option<u32> {
+    let search_len = image.len().min(MULTIBOOT2_SEARCH_LEN);
+    let header = (0..search_len).step_by(8)
+        .find(|&off| read_u32(image, off) == Some(MULTIBOOT2_MAGIC))?;
+    let header_len = read_u32(image, header + 8)? as usize;
+
+    let mut tag = header + 16;
+    while tag < header + header_len {
+        let typ = read_u16(image, tag)?;
+        let size = read_u32(image, tag + 4)? as usize;
+        if size < 8 {
+            return None;
+        }
+        match typ {
+            0 => return None,
+            MULTIBOOT2_TAG_ENTRY => return read_u32(image, tag + 8),
+            _ => tag += (size + 7) & !7,
+        }
+    }
+
+    None
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..(offset + 2))?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..(offset + 4))?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..(offset + 8))?.try_into().ok()?))
+}
@@ -0,0 +1,97 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Jumping to the kernel's Multiboot2 entry point. The kernel expects to be
+//! entered like from a Multiboot2 bootloader on BIOS: in 32-bit protected mode,
+//! with paging disabled, flat segments, `EAX` holding the bootloader magic and
+//! `EBX` the physical address of the boot information. The firmware left us in
+//! long mode, which a trampoline in identity-mapped memory below 4 Gio leaves.
+
+use core::arch::global_asm;
+use core::ptr::addr_of;
+
+use crate::mbi::BOOTLOADER_MAGIC;
+
+/// Where the GDT and its descriptor are written in the trampoline's page.
+const GDT_OFFSET: usize = 0x800;
+const GDTR_OFFSET: usize = 0x880;
+
+/// The GDT of the trampoline: a null descriptor, then flat 32-bit code and
+/// data segments, with selectors 0x08 and 0x10.
+const GDT: [u64; 3] = [0, 0x00cf9a000000ffff, 0x00cf92000000ffff];
+
+extern "C" {
+    static efistub_trampoline: u8;
+    static efistub_trampoline_end: u8;
+}
+
+// Arguments, as per the System V calling convention: the entry point in EDI,
+// the boot information in ESI, the GDT descriptor in RDX, the magic in ECX.
+global_asm!(r#"
+.global efistub_trampoline
+.global efistub_trampoline_end
+.code64
+efistub_trampoline:
+    cli
+    lgdt    (%rdx)
+    lea     1f(%rip), %rax
+    pushq   $0x08
+    pushq   %rax
+    lretq
+.code32
+1:
+    mov     $0x10, %eax
+    mov     %eax, %ds
+    mov     %eax, %es
+    mov     %eax, %fs
+    mov     %eax, %gs
+    mov     %eax, %ss
+
+    // Disabling paging leaves long mode; the EFER.LME bit must then be
+    // cleared for the kernel to enable paging in 32-bit mode again.
+    mov     %cr0, %eax
+    and     $0x7fffffff, %eax
+    mov     %eax, %cr0
+    mov     %ecx, %ebp
+    mov     $0xc0000080, %ecx
+    rdmsr
+    and     $~(1 << 8), %eax
+    wrmsr
+
+    mov     %ebp, %eax
+    mov     %esi, %ebx
+    jmp     *%edi
+efistub_trampoline_end:
+.code64
+"#, options(att_syntax));
+
+/// Copy the trampoline into the page at `page`, and jump to the kernel's
+/// `entry` with the boot information at `mbi`.
+///
+/// # Safety #
+///
+/// Boot services must have been exited. `page` must be an executable page
+/// below 4 Gio, and `mbi` a valid Multiboot2 boot information below 4 Gio.
+pub unsafe fn enter_kernel(page: u64, entry: u32, mbi: u64) -> ! {
+    let start = addr_of!(efistub_trampoline);
+    let len = addr_of!(efistub_trampoline_end) as usize - start as usize;
+    let page = page as *mut u8;
+    page.copy_from_nonoverlapping(start, len);
+
+    let gdt = page.add(GDT_OFFSET) as *mut [u64; 3];
+    gdt.write(GDT);
+    let gdtr = page.add(GDTR_OFFSET);
+    (gdtr as *mut u16).write_unaligned((core::mem::size_of_val(&GDT) - 1) as u16);
+    (gdtr.add(2) as *mut u64).write_unaligned(gdt as u64);
+
+    let trampoline: extern "sysv64" fn(u32, u32, u64, u32) -> !
+        = core::mem::transmute(page);
+    trampoline(entry, mbi as u32, gdtr as u64, BOOTLOADER_MAGIC);
+}
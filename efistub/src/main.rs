@@ -0,0 +1,309 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The EFI stub, booting Nucloid on UEFI machines without GRUB. It is a UEFI
+//! application embedding the kernel ELF, given at build time through the
+//! `NUCLOID_KERNEL` environment variable. It loads the kernel, gathers what a
+//! Multiboot2 bootloader would tell it from the boot services (memory map,
+//! GOP framebuffer, ACPI root pointer, command line), exits boot services,
+//! and enters the kernel through its regular Multiboot2 entry point: the boot
+//! process is the same from there on.
+
+#![no_std]
+#![no_main]
+
+mod efi;
+mod elf;
+mod handoff;
+mod mbi;
+
+use core::ffi::c_void;
+use core::panic::PanicInfo;
+use core::ptr::null_mut;
+use core::slice;
+
+use crate::efi::*;
+use crate::mbi::{area_type, Framebuffer, MbiWriter, MemoryArea};
+
+static KERNEL: &[u8] = include_bytes!(env!("NUCLOID_KERNEL"));
+
+/// The number of pages for the boot information.
+const MBI_PAGES: usize = 4;
+/// The maximum number of memory areas passed to the kernel, once adjacent
+/// ones of the same type are merged.
+const MAX_MEMORY_AREAS: usize = 128;
+/// The kernel must find its boot information in the memory it maps to boot.
+const MBI_MAX_ADDRESS: u64 = (32 << 20) - 1;
+const CMDLINE_MAX: usize = 1024;
+
+#[no_mangle]
+extern "efiapi" fn efi_main(image: Handle, st: *mut SystemTable) -> Status {
+    let st = unsafe { &*st };
+
+    match unsafe { boot(image, st) } {
+        Ok(never) => match never {},
+        Err(msg) => {
+            print(st, "nucloid: ");
+            print(st, msg);
+            print(st, "\r\n");
+            LOAD_ERROR
+        },
+    }
+}
+
+unsafe fn boot(
+    image: Handle,
+    st: &SystemTable,
+) -> Result<core::convert::Infallible, &'static str> {
+    let bs = &*st.boot_services;
+
+    let kernel = elf::load(bs, KERNEL)?;
+
+    let mut mbi_addr = kernel.end;
+    if (bs.allocate_pages)(ALLOCATE_ADDRESS, MemoryType::LoaderData,
+                           MBI_PAGES, &mut mbi_addr) != SUCCESS {
+        mbi_addr = MBI_MAX_ADDRESS;
+        if (bs.allocate_pages)(ALLOCATE_MAX_ADDRESS, MemoryType::LoaderData,
+                               MBI_PAGES, &mut mbi_addr) != SUCCESS {
+            return Err("couldn't allocate the boot information");
+        }
+    }
+    let mut trampoline = u32::MAX as u64;
+    if (bs.allocate_pages)(ALLOCATE_MAX_ADDRESS, MemoryType::LoaderCode, 1,
+                           &mut trampoline) != SUCCESS {
+        return Err("couldn't allocate the trampoline");
+    }
+
+    let mbi_buf = slice::from_raw_parts_mut(mbi_addr as *mut u8,
+                                            MBI_PAGES * PAGE_SIZE);
+    let mut mbi = MbiWriter::new(mbi_buf);
+    mbi.bootloader_name("Nucloid EFI stub");
+
+    let mut cmdline = [0; CMDLINE_MAX];
+    let cmdline_len = load_options(bs, image, &mut cmdline);
+    mbi.cmdline(&cmdline[..cmdline_len]);
+
+    match framebuffer(bs) {
+        Some(fb) => mbi.framebuffer(&fb),
+        None => print(st, "nucloid: no GOP framebuffer\r\n"),
+    }
+    if let Some((rsdp, v2)) = acpi_rsdp(st) {
+        mbi.rsdp(rsdp, v2);
+    }
+
+    let (map, desc_bsize) = exit_boot_services(bs, image)?;
+
+    // From now on, the firmware's services are gone.
+    let mut areas = [MemoryArea { base: 0, length: 0, typ: 0 }; MAX_MEMORY_AREAS];
+    let nr_areas = memory_areas(map, desc_bsize, &mut areas);
+    mbi.memory_map(&areas[..nr_areas]);
+    mbi.finish();
+
+    handoff::enter_kernel(trampoline, kernel.entry, mbi_addr);
+}
+
+/// Exit the boot services; return the final memory map, and the size of its
+/// descriptors.
+unsafe fn exit_boot_services(
+    bs: &BootServices,
+    image: Handle,
+) -> Result<(&'static [u8], usize), &'static str> {
+    let mut map_bsize = 0;
+    let mut key = 0;
+    let mut desc_bsize = 0;
+    let mut desc_version = 0;
+    (bs.get_memory_map)(&mut map_bsize, null_mut(), &mut key, &mut desc_bsize,
+                        &mut desc_version);
+
+    // Allocating the map's buffer adds descriptors to the map.
+    let buf_bsize = map_bsize + 8 * desc_bsize.max(48);
+    let mut buf = u64::MAX;
+    if (bs.allocate_pages)(ALLOCATE_MAX_ADDRESS, MemoryType::LoaderData,
+                           buf_bsize.div_ceil(PAGE_SIZE), &mut buf) != SUCCESS {
+        return Err("couldn't allocate the memory map");
+    }
+
+    // The map key changes when the firmware allocates memory, which it may do
+    // on its own: try again with a fresh map if so.
+    for _ in 0..4 {
+        map_bsize = buf_bsize;
+        if (bs.get_memory_map)(&mut map_bsize, buf as *mut u8, &mut key,
+                               &mut desc_bsize, &mut desc_version) != SUCCESS {
+            return Err("couldn't get the memory map");
+        }
+        if (bs.exit_boot_services)(image, key) == SUCCESS {
+            let map = slice::from_raw_parts(buf as *const u8, map_bsize);
+            return Ok((map, desc_bsize));
+        }
+    }
+
+    Err("couldn't exit boot services")
+}
+
+/// Convert the firmware's memory map `map` into Multiboot2 memory areas in
+/// `areas`, sorted and merged; return their number.
+fn memory_areas(map: &[u8], desc_bsize: usize, areas: &mut [MemoryArea]) -> usize {
+    let mut len = 0;
+
+    for desc in map.chunks_exact(desc_bsize) {
+        let desc = unsafe { &*(desc.as_ptr() as *const MemoryDescriptor) };
+        if len == areas.len() {
+            break;
+        }
+        areas[len] = MemoryArea {
+            base: desc.physical_start,
+            length: desc.nr_pages * PAGE_SIZE as u64,
+            typ: area_type_of(desc.typ),
+        };
+        len += 1;
+    }
+
+    areas[..len].sort_unstable_by_key(|area| area.base);
+
+    let mut merged = 0;
+    for i in 0..len {
+        let area = areas[i];
+        if merged > 0 {
+            let prev = &mut areas[merged - 1];
+            if prev.typ == area.typ && prev.base + prev.length == area.base {
+                prev.length += area.length;
+                continue;
+            }
+        }
+        areas[merged] = area;
+        merged += 1;
+    }
+
+    merged
+}
+
+/// The Multiboot2 memory area type of the UEFI memory type `typ`. The memory
+/// used by the boot services, and by this stub, is free for the kernel.
+fn area_type_of(typ: u32) -> u32 {
+    const CONVENTIONAL: u32 = MemoryType::Conventional as u32;
+    const LOADER_CODE: u32 = MemoryType::LoaderCode as u32;
+    const LOADER_DATA: u32 = MemoryType::LoaderData as u32;
+    const BS_CODE: u32 = MemoryType::BootServicesCode as u32;
+    const BS_DATA: u32 = MemoryType::BootServicesData as u32;
+    const ACPI_RECLAIM: u32 = MemoryType::AcpiReclaim as u32;
+    const ACPI_NVS: u32 = MemoryType::AcpiNvs as u32;
+    const UNUSABLE: u32 = MemoryType::Unusable as u32;
+
+    match typ {
+        CONVENTIONAL | LOADER_CODE | LOADER_DATA | BS_CODE | BS_DATA => {
+            area_type::AVAILABLE
+        },
+        ACPI_RECLAIM => area_type::ACPI_RECLAIMABLE,
+        ACPI_NVS => area_type::ACPI_NVS,
+        UNUSABLE => area_type::DEFECTIVE,
+        _ => area_type::RESERVED,
+    }
+}
+
+/// Copy the image's load options, i.e. the kernel command line, into `buf` as
+/// ASCII; return its length.
+unsafe fn load_options(bs: &BootServices, image: Handle, buf: &mut [u8]) -> usize {
+    let mut loaded_image: *mut c_void = null_mut();
+    if (bs.handle_protocol)(image, &LOADED_IMAGE_GUID, &mut loaded_image)
+        != SUCCESS {
+        return 0;
+    }
+    let loaded_image = &*(loaded_image as *const LoadedImage);
+    if loaded_image.load_options.is_null() {
+        return 0;
+    }
+
+    let options = slice::from_raw_parts(
+        loaded_image.load_options,
+        loaded_image.load_options_size as usize / 2,
+    );
+    let mut len = 0;
+    for &c in options.iter().take_while(|&&c| c != 0).take(buf.len()) {
+        buf[len] = if c < 0x80 { c as u8 } else { b'?' };
+        len += 1;
+    }
+
+    len
+}
+
+/// The current mode of the GOP framebuffer, if in a direct color mode.
+unsafe fn framebuffer(bs: &BootServices) -> Option<Framebuffer> {
+    let mut gop: *mut c_void = null_mut();
+    if (bs.locate_protocol)(&GRAPHICS_OUTPUT_GUID, null_mut(), &mut gop)
+        != SUCCESS {
+        return None;
+    }
+    let mode = &*(*(gop as *const GraphicsOutput)).mode;
+    let info = &*mode.info;
+
+    let field = |mask: u32| {
+        (mask.trailing_zeros() as u8, mask.count_ones() as u8)
+    };
+    let (bpp, red, green, blue) = match info.pixel_format {
+        PIXEL_RGB_RESERVED_8BIT => (32, (0, 8), (8, 8), (16, 8)),
+        PIXEL_BGR_RESERVED_8BIT => (32, (16, 8), (8, 8), (0, 8)),
+        PIXEL_BIT_MASK => {
+            let all = info.red_mask | info.green_mask | info.blue_mask
+                | info.reserved_mask;
+            let bpp = (32 - all.leading_zeros() as u8).next_multiple_of(8);
+            (bpp, field(info.red_mask), field(info.green_mask),
+             field(info.blue_mask))
+        },
+        _ => return None,
+    };
+
+    Some(Framebuffer {
+        addr: mode.frame_buffer_base,
+        pitch: info.pixels_per_scan_line * (bpp as u32 / 8),
+        width: info.horizontal_resolution,
+        height: info.vertical_resolution,
+        bpp,
+        red,
+        green,
+        blue,
+    })
+}
+
+/// The ACPI root pointer from the configuration tables, and whether it is of
+/// revision 2 or later.
+unsafe fn acpi_rsdp(st: &SystemTable) -> Option<(&'static [u8], bool)> {
+    let tables = slice::from_raw_parts(st.configuration_tables,
+                                       st.nr_configuration_tables);
+    let find = |guid| tables.iter()
+        .find(|table| table.vendor_guid == guid)
+        .map(|table| table.vendor_table as *const u8);
+
+    if let Some(rsdp) = find(ACPI_20_TABLE_GUID) {
+        let len = (rsdp.add(20) as *const u32).read_unaligned();
+        Some((slice::from_raw_parts(rsdp, len as usize), true))
+    } else {
+        find(ACPI_10_TABLE_GUID).map(|rsdp| (slice::from_raw_parts(rsdp, 20), false))
+    }
+}
+
+fn print(st: &SystemTable, msg: &str) {
+    let mut buf = [0u16; 64];
+
+    for chunk in msg.as_bytes().chunks(buf.len() - 1) {
+        for (dst, &c) in buf.iter_mut().zip(chunk) {
+            *dst = c as u16;
+        }
+        buf[chunk.len()] = 0;
+        unsafe { ((*st.con_out).output_string)(st.con_out, buf.as_ptr()) };
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    // The boot services may be gone, there is no way to report anything.
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
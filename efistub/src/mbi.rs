@@ -0,0 +1,144 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Writing a Multiboot2 boot information structure, as the kernel expects to
+//! be given by its bootloader.
+
+/// The magic value a Multiboot2 bootloader passes in `EAX`.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_RSDP_V1: u32 = 14;
+const TAG_RSDP_V2: u32 = 15;
+
+/// The memory area types of the memory map.
+pub mod area_type {
+    pub const AVAILABLE: u32 = 1;
+    pub const RESERVED: u32 = 2;
+    pub const ACPI_RECLAIMABLE: u32 = 3;
+    pub const ACPI_NVS: u32 = 4;
+    pub const DEFECTIVE: u32 = 5;
+}
+
+#[derive(Copy, Clone)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub length: u64,
+    pub typ: u32,
+}
+
+/// A linear framebuffer in a direct color mode, with the position and size in
+/// bits of each color channel.
+pub struct Framebuffer {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub red: (u8, u8),
+    pub green: (u8, u8),
+    pub blue: (u8, u8),
+}
+
+pub struct MbiWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> MbiWriter<'a> {
+    /// Start writing the structure into `buf`, which must be 8-byte aligned.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 8 }
+    }
+
+    pub fn cmdline(&mut self, cmdline: &[u8]) {
+        let tag = self.begin_tag(TAG_CMDLINE);
+        self.push(cmdline);
+        self.push(&[0]);
+        self.end_tag(tag);
+    }
+
+    pub fn bootloader_name(&mut self, name: &str) {
+        let tag = self.begin_tag(TAG_BOOTLOADER_NAME);
+        self.push(name.as_bytes());
+        self.push(&[0]);
+        self.end_tag(tag);
+    }
+
+    pub fn memory_map(&mut self, areas: &[MemoryArea]) {
+        let tag = self.begin_tag(TAG_MEMORY_MAP);
+        self.push(&24u32.to_le_bytes());
+        self.push(&0u32.to_le_bytes());
+        for area in areas {
+            self.push(&area.base.to_le_bytes());
+            self.push(&area.length.to_le_bytes());
+            self.push(&area.typ.to_le_bytes());
+            self.push(&0u32.to_le_bytes());
+        }
+        self.end_tag(tag);
+    }
+
+    pub fn framebuffer(&mut self, fb: &Framebuffer) {
+        const TYPE_RGB: u8 = 1;
+
+        let tag = self.begin_tag(TAG_FRAMEBUFFER);
+        self.push(&fb.addr.to_le_bytes());
+        self.push(&fb.pitch.to_le_bytes());
+        self.push(&fb.width.to_le_bytes());
+        self.push(&fb.height.to_le_bytes());
+        self.push(&[fb.bpp, TYPE_RGB, 0, 0]);
+        self.push(&[fb.red.0, fb.red.1, fb.green.0, fb.green.1,
+                    fb.blue.0, fb.blue.1]);
+        self.end_tag(tag);
+    }
+
+    /// Add a copy of the ACPI root pointer `rsdp`, of revision 2 or later if
+    /// `v2`.
+    pub fn rsdp(&mut self, rsdp: &[u8], v2: bool) {
+        let tag = self.begin_tag(if v2 { TAG_RSDP_V2 } else { TAG_RSDP_V1 });
+        self.push(rsdp);
+        self.end_tag(tag);
+    }
+
+    /// Terminate the structure; return its size.
+    pub fn finish(mut self) -> usize {
+        let tag = self.begin_tag(TAG_END);
+        self.end_tag(tag);
+        self.buf[0..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        self.buf[4..8].fill(0);
+        self.len
+    }
+
+    fn begin_tag(&mut self, typ: u32) -> usize {
+        let start = self.len;
+        self.push(&typ.to_le_bytes());
+        self.push(&0u32.to_le_bytes());
+        start
+    }
+
+    /// Write the size of the tag started at `start`, and pad it so that the
+    /// next one is 8-byte aligned.
+    fn end_tag(&mut self, start: usize) {
+        let size = (self.len - start) as u32;
+        self.buf[(start + 4)..(start + 8)].copy_from_slice(&size.to_le_bytes());
+        while self.len & 7 != 0 {
+            self.push(&[0]);
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf[self.len..(self.len + bytes.len())].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
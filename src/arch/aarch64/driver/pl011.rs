@@ -0,0 +1,130 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The ARM PrimeCell PL011 UART, the serial line of QEMU virt.
+
+use core::fmt;
+use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::logging::{Logger, Severity};
+use crate::mem::{PAddr, VAddr};
+
+/// The first UART of QEMU virt, and the frequency of its reference clock.
+pub const QEMU_VIRT_UART0: PAddr = PAddr(0x0900_0000);
+pub const QEMU_VIRT_UART_CLOCK: u32 = 24_000_000;
+
+const REG_DATA: usize       = 0x00;
+const REG_FLAGS: usize      = 0x18;
+const REG_INT_BAUD: usize   = 0x24;
+const REG_FRAC_BAUD: usize  = 0x28;
+const REG_LINE_CTRL: usize  = 0x2c;
+const REG_CTRL: usize       = 0x30;
+const REG_IRQ_MASK: usize   = 0x38;
+
+const FLAGS_RX_EMPTY: u32   = 1 << 4;
+const FLAGS_TX_FULL: u32    = 1 << 5;
+const FLAGS_BUSY: u32       = 1 << 3;
+
+const LINE_CTRL_FIFO: u32   = 1 << 4;
+const LINE_CTRL_8BITS: u32  = 0b11 << 5;
+
+const CTRL_ENABLE: u32      = 1 << 0;
+const CTRL_TX_ENABLE: u32   = 1 << 8;
+const CTRL_RX_ENABLE: u32   = 1 << 9;
+
+pub struct Pl011 {
+    base: VAddr,
+}
+
+impl Pl011 {
+    /// Set up the UART whose registers are mapped at `base` for 8N1 at
+    /// `baud_rate`, given the frequency of its reference clock.
+    ///
+    /// # Safety #
+    ///
+    /// `base` must map the registers of a PL011 as device memory, which no
+    /// one else accesses.
+    pub unsafe fn new(
+        base: VAddr,
+        clock: u32,
+        baud_rate: u32,
+    ) -> Result<Self, &'static str> {
+        // The divisor is in 1/64ths, with a 16× oversampling.
+        let divisor = (clock as u64 * 4 + baud_rate as u64 / 2)
+            / baud_rate as u64;
+        if divisor < 64 || divisor >= 1 << 22 {
+            return Err("Unsupported baud rate, no divisor available");
+        }
+
+        let dev = Self { base };
+        dev.write_reg(REG_CTRL, 0);
+        while dev.read_reg(REG_FLAGS) & FLAGS_BUSY != 0 {}
+
+        dev.write_reg(REG_INT_BAUD, (divisor >> 6) as u32);
+        dev.write_reg(REG_FRAC_BAUD, (divisor & 0x3f) as u32);
+        dev.write_reg(REG_LINE_CTRL, LINE_CTRL_8BITS | LINE_CTRL_FIFO);
+        dev.write_reg(REG_IRQ_MASK, 0);
+        dev.write_reg(REG_CTRL, CTRL_ENABLE | CTRL_TX_ENABLE | CTRL_RX_ENABLE);
+
+        Ok(dev)
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset).as_ptr()) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset).as_mut_ptr(), value) }
+    }
+
+    pub fn try_read(&self) -> Option<u8> {
+        if self.read_reg(REG_FLAGS) & FLAGS_RX_EMPTY == 0 {
+            Some(self.read_reg(REG_DATA) as u8)
+        } else {
+            None
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        while self.read_reg(REG_FLAGS) & FLAGS_TX_FULL != 0 {}
+
+        self.write_reg(REG_DATA, byte as u32);
+    }
+}
+
+impl fmt::Write for Pl011 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes().iter() {
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
+impl Logger for Pl011 {
+    fn log(&mut self, severity: Severity, args: fmt::Arguments) {
+        let (color, severity_str) = match severity {
+            Severity::Debug => ("\x1b[90m", "debug"),
+            Severity::Info => ("\x1b[37m", "info"),
+            Severity::Notice => ("\x1b[97m", "notice"),
+            Severity::Warning => ("\x1b[93m", "warning"),
+            Severity::Error => ("\x1b[31m", "error"),
+            Severity::Critical => ("\x1b[1;31m", "critic."),
+            Severity::Alert => ("\x1b[1;97;41m", "ALERT"),
+            Severity::Emergency => ("\x1b[1;93;41m", "EMERG."),
+        };
+
+        write!(self, "{}{:>7}: ", color, severity_str).unwrap();
+        self.write_fmt(args).unwrap();
+        write!(self, "\x1b[0m\n").unwrap();
+    }
+}
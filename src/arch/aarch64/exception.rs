@@ -0,0 +1,142 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The Rust side of the exception vectors of `exceptions.S`. Without an
+//! interrupt controller driver, only synchronous exceptions are expected:
+//! aborts are reported as page faults, anything else is fatal.
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+
+use crate::arch::cpu::MachineState;
+use crate::mem::{handle_pagefault, AccessAttempt, VAddr};
+use crate::panic::panic_at_state;
+
+const EC_INSN_ABORT_LOWER: u64 = 0x20;
+const EC_INSN_ABORT_SAME: u64 = 0x21;
+const EC_DATA_ABORT_LOWER: u64 = 0x24;
+const EC_DATA_ABORT_SAME: u64 = 0x25;
+
+/// The registers saved by the exception vectors, in `exceptions.S`'s layout.
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+}
+
+impl ExceptionFrame {
+    fn machine_state(&self) -> MachineState {
+        MachineState {
+            x: self.x,
+            sp: self.sp,
+            pc: self.elr,
+            pstate: self.spsr,
+        }
+    }
+}
+
+/// The syndrome of a synchronous exception, from `ESR_EL1`.
+#[derive(Debug, Copy, Clone)]
+pub struct Syndrome(pub u64);
+
+impl Syndrome {
+    /// The exception class, telling what caused the exception.
+    pub fn class(&self) -> u64 {
+        (self.0 >> 26) & 0x3f
+    }
+
+    /// For data aborts, whether the access was a write.
+    pub fn is_write(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+}
+
+/// Formats as "data abort (ESR=0x96000045)".
+impl Display for Syndrome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self.class() {
+            0x00 => "unknown reason",
+            0x01 => "trapped WFI/WFE",
+            0x07 => "trapped FP/SIMD access",
+            0x0e => "illegal execution state",
+            0x15 => "SVC",
+            0x18 => "trapped system register access",
+            EC_INSN_ABORT_LOWER | EC_INSN_ABORT_SAME => "instruction abort",
+            0x22 => "PC alignment fault",
+            EC_DATA_ABORT_LOWER | EC_DATA_ABORT_SAME => "data abort",
+            0x26 => "SP alignment fault",
+            0x2f => "SError",
+            0x3c => "BRK",
+            _ => "exception",
+        };
+
+        write!(f, "{name} (ESR={:#x})", self.0)
+    }
+}
+
+/// Called by the exception vectors; `kind` is the index of the vector.
+#[no_mangle]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame, kind: u64) {
+    let machine = frame.machine_state();
+    let origin = match kind / 4 {
+        0 => "EL1t",
+        1 => "EL1h",
+        2 => "EL0 (AArch64)",
+        _ => "EL0 (AArch32)",
+    };
+
+    match kind % 4 {
+        0 => handle_sync(frame, &machine),
+        1 => panic_at_state(
+            format_args!("Unexpected IRQ from {origin}"),
+            Some(&machine),
+            0,
+        ),
+        2 => panic_at_state(
+            format_args!("Unexpected FIQ from {origin}"),
+            Some(&machine),
+            0,
+        ),
+        _ => panic_at_state(
+            format_args!("SError from {origin}: {}", Syndrome(frame.esr)),
+            Some(&machine),
+            0,
+        ),
+    }
+}
+
+fn handle_sync(frame: &ExceptionFrame, machine: &MachineState) {
+    let syndrome = Syndrome(frame.esr);
+
+    match syndrome.class() {
+        EC_INSN_ABORT_LOWER | EC_INSN_ABORT_SAME => {
+            handle_pagefault(VAddr(frame.far as usize), AccessAttempt::Execute,
+                             format_args!("{syndrome}"), machine);
+        },
+        EC_DATA_ABORT_LOWER | EC_DATA_ABORT_SAME => {
+            let access = if syndrome.is_write() {
+                AccessAttempt::Write
+            } else {
+                AccessAttempt::Read
+            };
+            handle_pagefault(VAddr(frame.far as usize), access,
+                             format_args!("{syndrome}"), machine);
+        },
+        _ => panic_at_state(
+            format_args!("{syndrome} at {:#x}", frame.elr),
+            Some(machine),
+            0,
+        ),
+    }
+}
@@ -0,0 +1,173 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::arch::asm;
+use core::fmt;
+use core::fmt::{Formatter, Display};
+use core::sync::atomic::AtomicU64;
+
+use crate::arch::aarch64::export::power;
+use crate::arch::aarch64::random;
+use crate::driver::vga::VgaScreen;
+use crate::{print, println};
+
+const REGISTER_NAMES: [&str; 34] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7",
+    "x8", "x9", "x10", "x11", "x12", "x13", "x14", "x15",
+    "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23",
+    "x24", "x25", "x26", "x27", "x28", "fp", "lr",
+    "sp", "pc", "pstate",
+];
+
+#[derive(Clone)]
+pub struct MachineState {
+    /// The general purpose registers x0 to x30; x29 is the frame pointer and
+    /// x30 the link register.
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u64,
+}
+
+impl MachineState {
+    #[inline(always)]
+    pub fn here() -> Self {
+        let pc;
+        let sp;
+        let fp;
+        let lr;
+
+        unsafe {
+            asm!(
+                "adr    {}, .",
+                "mov    {}, sp",
+                "mov    {}, x29",
+                "mov    {}, x30",
+                out(reg) pc,
+                out(reg) sp,
+                out(reg) fp,
+                out(reg) lr,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+
+        let mut x = [0; 31];
+        x[29] = fp;
+        x[30] = lr;
+
+        Self {
+            x,
+            sp,
+            pc,
+            pstate: 0,
+        }
+    }
+
+    pub fn print(&self, vga: &mut impl VgaScreen) -> fmt::Result {
+        for row in self.registers().chunks(4) {
+            for (name, value) in row {
+                write!(vga, "{:<3}{:016x} ", name, value)?;
+            }
+            writeln!(vga)?;
+        }
+
+        Ok(())
+    }
+
+    /// All saved registers with their name, in a fixed order; this is meant
+    /// for machine-readable outputs such as the crash dump.
+    pub fn registers(&self) -> [(&'static str, u64); 34] {
+        let mut values = [0; 34];
+        values[..31].copy_from_slice(&self.x);
+        values[31] = self.sp;
+        values[32] = self.pc;
+        values[33] = self.pstate;
+
+        core::array::from_fn(|i| (REGISTER_NAMES[i], values[i]))
+    }
+
+    /// The stack pointer at the time the state was captured.
+    pub fn stack_ptr(&self) -> u64 {
+        self.sp
+    }
+
+    /// Whether the state was captured in user mode, at EL0.
+    pub fn is_user(&self) -> bool {
+        self.pstate & 0b1111 == 0
+    }
+
+    pub fn print_term(&self) {
+        use crate::screen::R;
+
+        for row in self.registers().chunks(4) {
+            for (name, value) in row {
+                print!("\x1b<fg=fff>{:>3}=\x1b<!fg>{:x}   ",
+                       name, R(*value));
+            }
+            println!();
+        }
+    }
+}
+
+impl Display for MachineState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use crate::screen::R;
+
+        for row in self.registers().chunks(4) {
+            for (name, value) in row {
+                write!(f, "{:>3}={:x}  ", name, R(*value))?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `f` with the kernel allowed to access user-space memory. There is no
+/// user space yet, hence no PAN to lift.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+/// Return a random value, not suitable for cryptography.
+pub fn random_u64() -> u64 {
+    random::random_u64()
+}
+
+pub fn halt() {
+    unsafe { asm!("wfi", options(nomem, nostack, preserves_flags)); }
+}
+
+/// Put the CPU into a low-power state until the next interrupt, or until
+/// `wakeup` no longer holds `seen`. An exclusive load of `wakeup` arms the
+/// exclusive monitor: a write to it from another CPU generates the event that
+/// wakes `WFE` up, without an IPI.
+pub fn idle_wait(wakeup: &AtomicU64, seen: u64) {
+    unsafe {
+        let value: u64;
+        asm!("ldaxr {}, [{}]", out(reg) value, in(reg) wakeup.as_ptr(),
+             options(nostack, preserves_flags));
+        if value == seen {
+            asm!("wfe", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+pub fn perm_halt() -> ! {
+    unsafe { asm!("msr daifset, #0b1111", options(nomem, nostack)); }
+    loop {
+        halt();
+    }
+}
+
+pub fn reset() -> ! {
+    power::reboot();
+}
@@ -0,0 +1,24 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! AArch64 has no I/O port space: devices are all memory-mapped.
+
+use arrayvec::ArrayVec;
+
+#[derive(Debug, Copy, Clone)]
+pub struct PortClaim {
+    pub base: u16,
+    pub len: u16,
+    pub owner: &'static str,
+}
+
+pub fn claims() -> ArrayVec<PortClaim, 0> {
+    ArrayVec::new()
+}
@@ -0,0 +1,15 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::driver::keyboard::KeyboardLeds;
+
+/// There is no PS/2 keyboard on QEMU virt, hence no LEDs to drive.
+pub fn set_leds(_leds: KeyboardLeds) {
+}
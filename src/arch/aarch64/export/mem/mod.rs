@@ -0,0 +1,151 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::fmt::{self, Debug, Formatter};
+use core::ops::Range;
+
+use crate::mem::{CacheMode, Mapping, PagePermissions, get_lowmem_va_end,
+                 VAddr};
+use crate::arch::aarch64::mem::paging::{self, locate_leaf};
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PAddr(pub u64);
+
+impl Debug for PAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PA {:#016x}", self.0)
+    }
+}
+
+impl PAddr {
+    /// Convert the physical address into a virtual address.
+    pub fn into_vaddr(self) -> VAddr {
+        VAddr(self.0 as usize) + LOWMEM_VA_START
+    }
+
+    pub fn from_lowmem_vaddr(vaddr: VAddr) -> Option<PAddr> {
+        if vaddr < LOWMEM_VA_START || vaddr >= get_lowmem_va_end() {
+            None
+        } else {
+            Some(Self(vaddr.0 as u64 - LOWMEM_VA_START.0 as u64))
+        }
+    }
+}
+
+impl VAddr {
+    /// Retrieve the physical address at which this virtual address is mapped to
+    /// if such mapping exists. This operation is rather costful since it
+    /// requires traversing the translation tables.
+    pub fn to_paddr(self) -> Option<PAddr> {
+        let leaf = locate_leaf(self)?;
+
+        Some(leaf.paddr() + (self.0 & (leaf.bsize() - 1)) as u64)
+    }
+}
+
+/// Where the physical memory is mapped as a whole, by `_start`.
+pub const LOWMEM_VA_START: VAddr = VAddr(0xffff8000_00000000);
+/// The size of the low memory mapped by `_start`: the first 4 Gio.
+pub const LOWMEM_SIZE: usize = 4 << 30;
+
+/// The window of kernel virtual addresses reserved for `iomap()`, mapping
+/// device registers.
+pub const IOMAP_VA_START: VAddr = VAddr(0xffffff00_00000000);
+pub const IOMAP_VA_SIZE: usize = 512 << 30;
+
+/// The window of kernel virtual addresses of the guarded heap allocations, see
+/// `mem::kalloc::guard`.
+pub const GUARD_VA_START: VAddr = VAddr(0xfffffe80_00000000);
+pub const GUARD_VA_SIZE: usize = 512 << 30;
+
+pub const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE_BITS: usize = 12;
+pub const FRAME_SIZE: usize = 4096;
+pub const FRAME_SIZE_BITS: usize = 12;
+
+pub fn page_permissions(vaddr: VAddr) -> PagePermissions {
+    match locate_leaf(vaddr) {
+        Some(leaf) => PagePermissions {
+            accessible: true,
+            readable: true,
+            writable: leaf.is_writable(),
+            executable: leaf.is_executable(),
+        },
+        None => PagePermissions {
+            accessible: false,
+            readable: false,
+            writable: false,
+            executable: false,
+        },
+    }
+}
+
+/// Set the write and execute permissions of the page mapped at `vaddr`.
+/// Returns `false` if the page isn't mapped, or is part of a block mapping.
+///
+/// # Safety #
+///
+/// The caller must ensure no code relies on the permissions being removed.
+pub unsafe fn set_page_permissions(vaddr: VAddr,
+                                   writable: bool,
+                                   executable: bool) -> bool {
+    paging::set_page_protection(vaddr, writable, executable)
+}
+
+/// Call `f` for every page mapped in the kernel half of the address space,
+/// with its virtual address, its size in bytes, and its effective permissions.
+pub fn walk_kernel_mappings(mut f: impl FnMut(VAddr, usize, PagePermissions)) {
+    paging::walk_kernel_mappings(|vaddr, bsize, writable, executable| {
+        f(vaddr, bsize, PagePermissions {
+            accessible: true,
+            readable: true,
+            writable,
+            executable,
+        });
+    });
+}
+
+/// Call `f` for every page mapped in `range`.
+pub fn walk_mappings(range: Range<VAddr>, f: impl FnMut(Mapping)) {
+    paging::walk_mappings(range, f);
+}
+
+/// Map the page at `vaddr` onto the frame at `paddr` as writable data of the
+/// memory type `cache`. Returns `false` if the page couldn't be mapped.
+///
+/// # Safety #
+///
+/// `vaddr` must be reserved for this mapping, and `paddr` must not be RAM in
+/// use elsewhere.
+pub unsafe fn map_page(vaddr: VAddr, paddr: PAddr, cache: CacheMode) -> bool {
+    paging::map_page(vaddr, paddr, cache)
+}
+
+/// Unmap the page at `vaddr` mapped with `map_page()`.
+///
+/// # Safety #
+///
+/// The page must not be accessed anymore.
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    paging::unmap_page(vaddr)
+}
+
+/// Copy `len` bytes from `src` to `dst`; return the number of bytes left
+/// uncopied. There is no user space yet, hence no exception table to recover
+/// from faults with: a fault panics.
+///
+/// # Safety #
+///
+/// Both sides of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::ptr::copy(src, dst, len);
+    0
+}
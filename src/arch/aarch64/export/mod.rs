@@ -0,0 +1,24 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+pub mod cpu;
+pub mod ioport;
+pub mod keyboard;
+pub mod mem;
+pub mod pci;
+pub mod power;
+pub mod screen;
+pub mod smp;
+pub mod sync;
+pub mod task;
+pub mod logging;
+pub mod time;
+
+pub use self::screen::VesaFramebuffer;
@@ -0,0 +1,25 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The PCI configuration space of QEMU virt is the ECAM window described by
+//! the device tree, which is not parsed yet: no device is found.
+
+pub fn config_read32(_bus: u8, _device: u8, _function: u8, _offset: u8) -> u32 {
+    u32::MAX
+}
+
+pub fn config_write32(
+    _bus: u8,
+    _device: u8,
+    _function: u8,
+    _offset: u8,
+    _value: u32,
+) {
+}
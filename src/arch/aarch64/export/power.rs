@@ -0,0 +1,67 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Powering off and rebooting through PSCI, the firmware interface of ARM
+//! systems. The firmware implements it either at EL2 or at EL3, called with
+//! `HVC` or `SMC` respectively, as told by the device tree.
+
+use core::arch::asm;
+
+use crate::arch::aarch64::export::cpu::perm_halt;
+use crate::warning;
+
+const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u64 = 0x8400_0009;
+
+/// The instruction calling into the PSCI firmware.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PsciConduit {
+    Hvc,
+    Smc,
+}
+
+static mut PSCI_CONDUIT: Option<PsciConduit> = None;
+
+/// Set how to call the PSCI firmware; until then, or if there is none,
+/// `shutdown()` and `reboot()` only halt the CPU.
+///
+/// # Safety #
+///
+/// This must be called during the boot process, before any other CPU runs.
+pub unsafe fn init(conduit: Option<PsciConduit>) {
+    PSCI_CONDUIT = conduit;
+}
+
+pub fn shutdown() -> ! {
+    psci_call(PSCI_SYSTEM_OFF);
+    warning!("PSCI SYSTEM_OFF failed, halting instead");
+    perm_halt();
+}
+
+pub fn reboot() -> ! {
+    psci_call(PSCI_SYSTEM_RESET);
+    warning!("PSCI SYSTEM_RESET failed, halting instead");
+    perm_halt();
+}
+
+/// Call the PSCI function `function`, which only returns on failure.
+fn psci_call(function: u64) {
+    match unsafe { PSCI_CONDUIT } {
+        Some(PsciConduit::Hvc) => unsafe {
+            asm!("hvc #0", inout("x0") function => _, clobber_abi("C"),
+                 options(nostack));
+        },
+        Some(PsciConduit::Smc) => unsafe {
+            asm!("smc #0", inout("x0") function => _, clobber_abi("C"),
+                 options(nostack));
+        },
+        None => (),
+    }
+}
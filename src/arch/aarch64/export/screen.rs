@@ -0,0 +1,34 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::driver::screen::{Color, FramebufferMode, FramebufferScreen};
+
+/// There is no firmware framebuffer on QEMU virt: the display, if any, is a
+/// virtio GPU. This type has no value; `PANIC_FRAMEBUFFER` is only set once
+/// the kernel terminal moves onto the GPU's framebuffer.
+pub enum VesaFramebuffer {}
+
+impl FramebufferScreen for VesaFramebuffer {
+    fn mode(&self) -> FramebufferMode {
+        match *self {}
+    }
+
+    fn put(&mut self, _x: usize, _y: usize, _color: Color) {
+        match *self {}
+    }
+
+    fn copy(&mut self, _x: usize, _y: usize, _data: &[u32]) {
+        match *self {}
+    }
+
+    fn clear(&mut self) {
+        match *self {}
+    }
+}
@@ -0,0 +1,23 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+/// Interrupt the CPU of index `cpu` so that it runs its pending cross-CPU
+/// calls. Only the boot CPU is brought up, there is no other CPU to interrupt.
+pub fn send_call_ipi(_cpu: usize) {
+}
+
+/// Make the other CPUs call `panic::stop_if_panicking()`; there is none.
+pub fn stop_others() {
+}
+
+/// Send a non-maskable interrupt to the CPU of index `cpu`; there is no other
+/// CPU.
+pub fn send_nmi(_cpu: usize) {
+}
@@ -0,0 +1,47 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::latency::{self, Section};
+use crate::task::cpu::{raw_cpu_index, MAX_CPUS};
+
+/// How many critical regions each CPU is nested in.
+static CRITICAL_REGION_DEPTH: [AtomicU32; MAX_CPUS]
+    = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// When the outermost critical region of each CPU was entered, for the latency
+/// watchdog.
+static CRITICAL_REGION_START: [AtomicU64; MAX_CPUS]
+    = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+pub fn push_critical_region() {
+    let prev = CRITICAL_REGION_DEPTH[raw_cpu_index()]
+        .fetch_add(1, Ordering::SeqCst);
+
+    if prev == 0 {
+        unsafe { asm!("msr daifset, #0b0011", options(nomem, nostack)) };
+        CRITICAL_REGION_START[raw_cpu_index()]
+            .store(latency::start(), Ordering::Relaxed);
+    }
+}
+
+pub fn pop_critical_region() {
+    let prev = CRITICAL_REGION_DEPTH[raw_cpu_index()]
+        .fetch_sub(1, Ordering::SeqCst);
+
+    if prev == 1 {
+        let start = CRITICAL_REGION_START[raw_cpu_index()]
+            .load(Ordering::Relaxed);
+        unsafe { asm!("msr daifclr, #0b0011", options(nomem, nostack)) };
+        latency::check(Section::IrqsDisabled, start);
+    }
+}
@@ -0,0 +1,92 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::mem::VAddr;
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+
+/// The ELF machine type of the programs run by this architecture, EM_AARCH64.
+pub const ELF_MACHINE: u16 = 183;
+
+/// The code signal handlers return to: `mov x8, #15; svc #0`, i.e.
+/// `rt_sigreturn()`, with the system call numbers of x86-64.
+pub const SIGRETURN_TRAMPOLINE: &[u8] = &[
+    0xe8, 0x01, 0x80, 0xd2,
+    0x01, 0x00, 0x00, 0xd4,
+];
+
+/// The PSTATE of EL1 using its own stack pointer, SP_EL1, interrupts
+/// unmasked.
+const PSTATE_EL1H: u64 = 0b0101;
+
+#[derive(Clone)]
+pub struct TaskMachineContext {
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub pc: u64,
+    pub pstate: u64,
+}
+
+impl TaskMachineContext {
+    /// The context of a task entering user space, at EL0, at `entry` with its
+    /// stack pointer at `stack`.
+    pub fn user(entry: VAddr, stack: VAddr) -> Self {
+        Self {
+            x: [0; 31],
+            sp: stack.0 as u64,
+            pc: entry.0 as u64,
+            pstate: 0,
+        }
+    }
+
+    /// The context of a kernel thread calling `entry(arg)` at EL1, with its
+    /// own stack pointer ending at `stack`; `entry` must never return.
+    pub fn kernel(
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+        stack: VAddr,
+    ) -> Self {
+        let mut x = [0; 31];
+        x[0] = arg as u64;
+
+        Self {
+            x,
+            sp: stack.0 as u64 & !0xf,
+            pc: entry as usize as u64,
+            pstate: PSTATE_EL1H,
+        }
+    }
+
+    /// The value returned to the task by the system call it is in.
+    pub fn return_value(&self) -> usize {
+        self.x[0] as usize
+    }
+
+    /// Set the value returned to the task by the system call it is in.
+    pub fn set_return_value(&mut self, value: usize) {
+        self.x[0] = value as u64;
+    }
+
+    /// Make the task call the signal handler `handler` with `signal` as its
+    /// argument, returning to `trampoline` through the link register.
+    pub fn enter_signal_handler(
+        &mut self,
+        _vm: &VirtualMemory,
+        handler: VAddr,
+        signal: usize,
+        trampoline: VAddr,
+    ) -> Result<(), Errno> {
+        self.sp &= !0xf;
+        self.pc = handler.0 as u64;
+        self.x[0] = signal as u64;
+        self.x[30] = trampoline.0 as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::arch::asm;
+
+/// Read the virtual count of the generic timer. It is monotonic, increments at
+/// a constant rate, and is synchronized across CPUs.
+#[inline]
+pub fn timestamp() -> u64 {
+    let count: u64;
+    unsafe {
+        asm!("isb", "mrs {}, cntvct_el0", out(reg) count,
+             options(nomem, nostack, preserves_flags));
+    }
+    count
+}
+
+/// The frequency in Hz of the generic timer, as programmed by the firmware in
+/// `CNTFRQ_EL0`; `None` if it was left unset.
+pub fn timestamp_frequency() -> Option<u64> {
+    let freq: u64;
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) freq,
+             options(nomem, nostack, preserves_flags));
+    }
+    (freq != 0).then_some(freq)
+}
+
+/// Busy-wait for at least `us` microseconds with the generic timer. This
+/// requires no interrupt, which makes it usable from the panic handler.
+pub fn delay_us(us: u64) {
+    let Some(freq) = timestamp_frequency() else {
+        return;
+    };
+
+    let end = timestamp() + (us as u128 * freq as u128 / 1_000_000) as u64;
+    while timestamp() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// There is no timer interrupt to sample from yet: the generic timer is only
+/// read.
+pub fn start_sample_timer(_hz: u32) -> bool {
+    false
+}
+
+pub fn stop_sample_timer() {
+}
@@ -0,0 +1,151 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::slice;
+
+use crate::arch::aarch64::driver::pl011::{Pl011, QEMU_VIRT_UART0,
+                                          QEMU_VIRT_UART_CLOCK};
+use crate::arch::aarch64::export::logging::LOGGER_SERIAL;
+use crate::arch::aarch64::export::power::{self, PsciConduit};
+use crate::arch::aarch64::mem;
+use crate::arch::mem::{LOWMEM_SIZE, LOWMEM_VA_START};
+use crate::arch::sync::{pop_critical_region, push_critical_region};
+use crate::arch::time::timestamp_frequency;
+use crate::boot::{self, BootInfo};
+use crate::boot::cmdline::{self, CmdLine, DEFAULT_SERIAL_BAUD};
+use crate::boot::fdt::DeviceTree;
+use crate::logging::{self, DEFAULT_LOGGER};
+use crate::initcall::{self, InitLevel};
+use crate::mem::{PAddr, LOWMEM_VA_END, PHYS_MEM_SIZE};
+use crate::mem::load::{kernel_image, kernel_rodata_segment, kernel_text_segment};
+use crate::screen::R;
+use crate::{buildinfo, debug, info, main, notice, stack_protector, time,
+            warning};
+
+/// The start of RAM on QEMU virt, where QEMU puts the device tree when it
+/// boots an ELF kernel; it then passes no pointer to it in x0.
+const QEMU_VIRT_RAM_START: PAddr = PAddr(0x4000_0000);
+
+/// The first Rust code to run, called by `_start` with the MMU on, on the boot
+/// stack, with `dtb_pa` the physical address of the device tree or zero.
+///
+/// The device tree is read first, since it holds the command line setting the
+/// serial line up; then the memory management is set up from the RAM it
+/// describes. There is no interrupt controller driver yet: interrupts are
+/// unmasked but none is ever raised, hence only the early and late initcalls
+/// run; the drivers of the core and device levels would wait for interrupts
+/// in vain.
+#[no_mangle]
+pub unsafe extern "C" fn arch_init(dtb_pa: PAddr) -> ! {
+    push_critical_region();
+
+    let dtb_pa = if dtb_pa.0 == 0 { QEMU_VIRT_RAM_START } else { dtb_pa };
+    let fdt = device_tree(dtb_pa).expect("No device tree found");
+
+    boot::init(collect_boot_info(&fdt));
+    let cmdline = cmdline::get();
+
+    let serial = |baud| unsafe {
+        Pl011::new(QEMU_VIRT_UART0.into_vaddr(), QEMU_VIRT_UART_CLOCK, baud)
+    };
+    LOGGER_SERIAL = Some(serial(cmdline.serial_baud())
+        .or_else(|_| serial(DEFAULT_SERIAL_BAUD))
+        .expect("Couldn't initialize serial device"));
+    *DEFAULT_LOGGER.lock() = LOGGER_SERIAL.as_mut().unwrap();
+
+    notice!("Nucloid v{} (build {})", env!("CARGO_PKG_VERSION"),
+            buildinfo::BUILD_ID);
+    if !cmdline.as_str().is_empty() {
+        info!("Command line: {}", cmdline.as_str());
+    }
+    apply_boot_options(cmdline);
+
+    stack_protector::init();
+
+    let (ram_start, ram_bsize) = fdt.memory()
+        .expect("No memory node in the device tree");
+
+    unsafe {
+        PHYS_MEM_SIZE = ram_start + ram_bsize;
+        LOWMEM_VA_END = LOWMEM_VA_START
+            + (PHYS_MEM_SIZE as usize).min(LOWMEM_SIZE);
+    }
+
+    debug!("phys_mem_size = 0x{:x}, va_size = 0x{:x}",
+           R(PHYS_MEM_SIZE), R(LOWMEM_VA_END));
+    debug!("Kernel image:   {:#?}", kernel_image());
+    debug!("Text segment:   {:#?}", kernel_text_segment());
+    debug!("Rodata segment: {:#?}", kernel_rodata_segment());
+
+    power::init(psci_conduit(&fdt));
+
+    {
+        time::scope!("memory");
+        info!("Setting up memory management...");
+        mem::boot_setup(PAddr(ram_start), ram_bsize);
+        initcall::run(InitLevel::Early);
+    }
+
+    pop_critical_region();
+
+    {
+        time::scope!("late");
+        initcall::run(InitLevel::Late);
+    }
+
+    match timestamp_frequency() {
+        Some(freq) => info!("Generic timer running at {freq} Hz"),
+        None => warning!("The generic timer's frequency is not set"),
+    }
+
+    time::print_timings();
+
+    main();
+}
+
+/// The device tree at `pa`, if there is a valid one.
+unsafe fn device_tree(pa: PAddr) -> Option<DeviceTree<'static>> {
+    let header = &*pa.into_vaddr().as_ptr();
+    let bsize = DeviceTree::total_size(header)?;
+    let data = slice::from_raw_parts(pa.into_vaddr().as_ptr(), bsize);
+
+    DeviceTree::new(data).ok()
+}
+
+/// Copy what the kernel needs from the device tree, whose memory is not
+/// reserved past the boot process.
+fn collect_boot_info(fdt: &DeviceTree) -> BootInfo {
+    let mut info = BootInfo::new();
+
+    if let Some(bootargs) = fdt.bootargs() {
+        info.cmdline = CmdLine::parse(bootargs);
+    }
+
+    info
+}
+
+fn psci_conduit(fdt: &DeviceTree) -> Option<PsciConduit> {
+    match fdt.property_str("/psci", "method")? {
+        "hvc" => Some(PsciConduit::Hvc),
+        "smc" => Some(PsciConduit::Smc),
+        method => {
+            warning!("unknown PSCI method '{method}'");
+            None
+        },
+    }
+}
+
+/// Apply the command line options that are not read by the subsystems
+/// themselves.
+fn apply_boot_options(cmdline: &CmdLine) {
+    if let Some(severity) = cmdline.log_level() {
+        logging::set_min_severity(severity);
+    }
+}
@@ -0,0 +1,74 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::arch::mem::LOWMEM_VA_START;
+use crate::mem::bootmem::{BootMem, RegionKind};
+use crate::mem::frame::{AllocatorBuilder, FRAME_ALLOCATOR};
+#[cfg(feature = "kasan")]
+use crate::mem::kasan;
+use crate::mem::{get_lowmem_va_end, PAddr, PHYS_MEM_SIZE};
+use crate::mem::load::kernel_image;
+use crate::mem::resource::{self, ResourceKind};
+use crate::debug;
+use crate::misc::BinSize;
+
+pub mod paging;
+
+/// Set up the frame allocator for the RAM at `ram_start`, spanning
+/// `ram_bsize` bytes, its array of frames being taken from the early-boot
+/// allocator. The RAM up to the end of the kernel image is kept allocated: on
+/// QEMU virt, it holds the device tree, at the start of RAM.
+///
+/// The translation tables set up by `_start` are kept as they are: they
+/// already map the low memory.
+pub unsafe fn boot_setup(ram_start: PAddr, ram_bsize: u64) {
+    debug!("RAM: {:?} -> {:?}    ({})", ram_start, ram_start + ram_bsize,
+           BinSize(ram_bsize));
+
+    let image_end = (kernel_image().end - LOWMEM_VA_START).0 as u64;
+
+    resource::declare_boot_region(ram_start, ram_bsize,
+                                  ResourceKind::SystemRam);
+
+    let mut bootmem = BootMem::new();
+    bootmem.add_region(ram_start, ram_bsize, RegionKind::Ram)
+        .expect("Couldn't declare the RAM");
+    bootmem.reserve(ram_start, image_end - ram_start.0, "kernel image")
+        .expect("Couldn't reserve the kernel image");
+
+    let lowmem_end = PAddr((get_lowmem_va_end() - LOWMEM_VA_START).0 as u64);
+    let frames = bootmem
+        .alloc(AllocatorBuilder::array_bsize(PHYS_MEM_SIZE) as u64, lowmem_end,
+               "frames")
+        .expect("No memory left for the array of frames");
+
+    let mut allocator_b = AllocatorBuilder::new(frames.into_vaddr(),
+                                                PHYS_MEM_SIZE);
+
+    #[cfg(feature = "kasan")]
+    {
+        let shadow_bsize = kasan::shadow_bsize(lowmem_end.0 - ram_start.0);
+        let shadow = bootmem.alloc(shadow_bsize, lowmem_end, "kasan shadow")
+            .expect("No memory left for the KASAN shadow map");
+        kasan::init(ram_start.into_vaddr(), shadow.into_vaddr(),
+                    shadow_bsize as usize);
+    }
+
+    allocator_b.declare_unusable(PAddr(0), ram_start.0);
+    bootmem.transfer(&mut allocator_b);
+
+    {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        assert!(allocator.is_none());
+        let mut built = allocator_b.build();
+        resource::mark_claimed_frames(&mut built);
+        *allocator = Some(built);
+    }
+}
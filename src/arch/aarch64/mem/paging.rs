@@ -0,0 +1,271 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel's translation tables, those of TTBR1_EL1, with a 4 Kio granule
+//! and four levels. The low memory is mapped by `_start` with 1 Gio blocks at
+//! level 1; `map_page()` adds 4 Kio pages at level 3, allocating the tables
+//! on the way.
+
+use core::arch::asm;
+use core::ops::Range;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::mem::{CacheMode, Mapping, PAddr, VAddr};
+use crate::mem::frame::allocate_frames;
+
+const NR_ENTRIES: usize = 512;
+
+const DESC_VALID: u64 = 1 << 0;
+/// A table descriptor at levels 0 to 2, a page descriptor at level 3; a block
+/// descriptor if unset.
+const DESC_TABLE_OR_PAGE: u64 = 1 << 1;
+const DESC_ATTR_SHIFT: u64 = 2;
+const DESC_AP_EL0: u64 = 1 << 6;
+const DESC_AP_READ_ONLY: u64 = 1 << 7;
+const DESC_SH_INNER: u64 = 3 << 8;
+const DESC_AF: u64 = 1 << 10;
+const DESC_NOT_GLOBAL: u64 = 1 << 11;
+const DESC_PXN: u64 = 1 << 53;
+const DESC_UXN: u64 = 1 << 54;
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// The MAIR_EL1 attribute indices, as set by `_start`.
+const ATTR_WRITE_BACK: u64 = 0;
+const ATTR_DEVICE: u64 = 1;
+const ATTR_NON_CACHEABLE: u64 = 2;
+const ATTR_WRITE_THROUGH: u64 = 3;
+
+/// The first address of the upper half, translated by TTBR1_EL1.
+const UPPER_HALF_START: usize = 0xffff_0000_0000_0000;
+
+type Table = [u64; NR_ENTRIES];
+
+/// A leaf descriptor, mapping a page or a block.
+#[derive(Copy, Clone)]
+pub struct Leaf {
+    desc: *mut u64,
+    level: usize,
+}
+
+impl Leaf {
+    fn value(&self) -> u64 {
+        unsafe { self.desc.read_volatile() }
+    }
+
+    /// The number of bytes mapped by the descriptor.
+    pub fn bsize(&self) -> usize {
+        level_bsize(self.level)
+    }
+
+    pub fn is_block(&self) -> bool {
+        self.level < 3
+    }
+
+    pub fn paddr(&self) -> PAddr {
+        PAddr(self.value() & DESC_ADDR_MASK)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.value() & DESC_AP_READ_ONLY == 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.value() & DESC_PXN == 0
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.value() & DESC_AP_EL0 != 0
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.value() & DESC_NOT_GLOBAL == 0
+    }
+}
+
+fn level_bsize(level: usize) -> usize {
+    PAGE_SIZE << (9 * (3 - level))
+}
+
+fn table_index(vaddr: VAddr, level: usize) -> usize {
+    (vaddr.0 >> (39 - 9 * level)) & (NR_ENTRIES - 1)
+}
+
+fn kernel_root() -> *mut Table {
+    let ttbr1: u64;
+    unsafe {
+        asm!("mrs {}, ttbr1_el1", out(reg) ttbr1,
+             options(nomem, nostack, preserves_flags));
+    }
+    PAddr(ttbr1 & DESC_ADDR_MASK).into_vaddr().as_mut_ptr()
+}
+
+fn next_table(desc: u64) -> *mut Table {
+    PAddr(desc & DESC_ADDR_MASK).into_vaddr().as_mut_ptr()
+}
+
+/// Locate the leaf descriptor mapping `vaddr`, if any.
+pub fn locate_leaf(vaddr: VAddr) -> Option<Leaf> {
+    if vaddr.0 < UPPER_HALF_START {
+        return None;
+    }
+
+    let mut table = kernel_root();
+    for level in 0..=3 {
+        let desc = unsafe { &mut (*table)[table_index(vaddr, level)] };
+        let value = *desc;
+
+        if value & DESC_VALID == 0 {
+            return None;
+        } else if level == 3 || value & DESC_TABLE_OR_PAGE == 0 {
+            return Some(Leaf { desc, level });
+        }
+        table = next_table(value);
+    }
+
+    unreachable!()
+}
+
+/// Invalidate the TLB entries of `vaddr` on all CPUs, once its descriptor
+/// was changed.
+unsafe fn flush_tlb_page(vaddr: VAddr) {
+    asm!(
+        "dsb ishst",
+        "tlbi vaae1is, {}",
+        "dsb ish",
+        "isb",
+        in(reg) (vaddr.0 >> 12) as u64 & 0xfff_ffff_ffff,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Set the write and execute permissions of the page mapped at `vaddr`.
+/// Returns `false` if the page isn't mapped, or if it is part of a block:
+/// blocks are not split yet.
+pub unsafe fn set_page_protection(
+    vaddr: VAddr,
+    writable: bool,
+    executable: bool,
+) -> bool {
+    let Some(leaf) = locate_leaf(vaddr) else {
+        return false;
+    };
+    if leaf.is_block() {
+        return false;
+    }
+
+    let mut value = leaf.value() & !(DESC_AP_READ_ONLY | DESC_PXN);
+    if !writable {
+        value |= DESC_AP_READ_ONLY;
+    }
+    if !executable {
+        value |= DESC_PXN;
+    }
+    leaf.desc.write_volatile(value);
+    flush_tlb_page(VAddr(vaddr.0 & !(PAGE_SIZE - 1)));
+
+    true
+}
+
+pub unsafe fn map_page(vaddr: VAddr, paddr: PAddr, cache: CacheMode) -> bool {
+    if vaddr.0 < UPPER_HALF_START {
+        return false;
+    }
+
+    let mut table = kernel_root();
+    for level in 0..3 {
+        let desc = &mut (*table)[table_index(vaddr, level)];
+
+        if *desc & DESC_VALID == 0 {
+            let Some(new_table) = allocate_frames().zero_mem().allocate() else {
+                return false;
+            };
+            *desc = new_table.0 | DESC_VALID | DESC_TABLE_OR_PAGE;
+        } else if *desc & DESC_TABLE_OR_PAGE == 0 {
+            return false;
+        }
+        table = next_table(*desc);
+    }
+
+    let desc = &mut (*table)[table_index(vaddr, 3)];
+    if *desc & DESC_VALID != 0 {
+        return false;
+    }
+
+    let (attr, shareability) = match cache {
+        CacheMode::WriteBack => (ATTR_WRITE_BACK, DESC_SH_INNER),
+        CacheMode::WriteThrough => (ATTR_WRITE_THROUGH, DESC_SH_INNER),
+        CacheMode::WriteCombining => (ATTR_NON_CACHEABLE, DESC_SH_INNER),
+        CacheMode::Uncached => (ATTR_DEVICE, 0),
+    };
+
+    (desc as *mut u64).write_volatile(
+        paddr.0 | attr << DESC_ATTR_SHIFT | shareability | DESC_VALID
+            | DESC_TABLE_OR_PAGE | DESC_AF | DESC_PXN | DESC_UXN
+    );
+    asm!("dsb ishst", "isb", options(nostack, preserves_flags));
+
+    true
+}
+
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    let Some(leaf) = locate_leaf(vaddr) else {
+        return;
+    };
+    assert!(!leaf.is_block(), "can't unmap a page out of a block");
+
+    leaf.desc.write_volatile(0);
+    flush_tlb_page(vaddr);
+}
+
+/// Call `f` for every leaf descriptor of the upper half, with the virtual
+/// address it maps.
+fn walk_leaves(mut f: impl FnMut(VAddr, Leaf)) {
+    fn walk(table: *mut Table, level: usize, base: usize,
+            f: &mut impl FnMut(VAddr, Leaf)) {
+        for index in 0..NR_ENTRIES {
+            let desc = unsafe { &mut (*table)[index] };
+            let vaddr = base + index * level_bsize(level);
+
+            if *desc & DESC_VALID == 0 {
+                continue;
+            } else if level == 3 || *desc & DESC_TABLE_OR_PAGE == 0 {
+                f(VAddr(vaddr), Leaf { desc, level });
+            } else {
+                walk(next_table(*desc), level + 1, vaddr, f);
+            }
+        }
+    }
+
+    walk(kernel_root(), 0, UPPER_HALF_START, &mut f);
+}
+
+pub fn walk_kernel_mappings(mut f: impl FnMut(VAddr, usize, bool, bool)) {
+    walk_leaves(|vaddr, leaf| {
+        f(vaddr, leaf.bsize(), leaf.is_writable(), leaf.is_executable());
+    });
+}
+
+pub fn walk_mappings(range: Range<VAddr>, mut f: impl FnMut(Mapping)) {
+    walk_leaves(|vaddr, leaf| {
+        if vaddr < range.start || vaddr >= range.end {
+            return;
+        }
+
+        f(Mapping {
+            vaddr,
+            paddr: leaf.paddr(),
+            bsize: leaf.bsize(),
+            writable: leaf.is_writable(),
+            executable: leaf.is_executable(),
+            user: leaf.is_user(),
+            global: leaf.is_global(),
+        });
+    });
+}
@@ -0,0 +1,21 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The AArch64 port, targeting QEMU's `virt` machine. This is a skeleton: the
+//! kernel boots on a single CPU with the MMU on, logs to the PL011 UART and
+//! keeps time with the generic timer, but there is no interrupt controller
+//! driver yet, hence no interrupts.
+
+pub mod init;
+pub mod driver;
+pub(super) mod export;
+pub mod exception;
+pub mod mem;
+pub mod random;
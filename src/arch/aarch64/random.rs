@@ -0,0 +1,60 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Random numbers for the kernel's own hardening needs, such as the stack
+//! protector's canary. This is not a cryptographic RNG: when the CPU lacks
+//! `RNDR` (FEAT_RNG), values are derived from the generic timer's count.
+
+use core::arch::asm;
+
+use crate::arch::aarch64::export::time::timestamp;
+
+/// RNDR fails when the hardware entropy source is momentarily drained.
+const RNDR_RETRIES: usize = 10;
+
+/// Return a random value from the CPU's hardware RNG, if there is one.
+pub fn hw_random_u64() -> Option<u64> {
+    let isar0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0,
+             options(nomem, nostack, preserves_flags));
+    }
+    if isar0 >> 60 == 0 {
+        return None;
+    }
+
+    for _ in 0..RNDR_RETRIES {
+        let value: u64;
+        let ok: u64;
+        unsafe {
+            // RNDR, which sets Z on failure.
+            asm!("mrs {}, s3_3_c2_c4_0", "cset {}, ne",
+                 out(reg) value, out(reg) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Return a random value, from the hardware RNG if available, or else from a
+/// mix of the generic timer's count.
+pub fn random_u64() -> u64 {
+    hw_random_u64().unwrap_or_else(|| splitmix64(timestamp()))
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -8,20 +8,65 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-/*#[cfg(all(
-    //target_arch = "x86_64",
-    not(test)
-))]*/
+//! The architecture-specific code. Each architecture lives in its own module,
+//! selected from the target architecture, and provides an `export` module
+//! whose items are re-exported here: the rest of the kernel only ever goes
+//! through `crate::arch::*`, never through `crate::arch::x86` and the like.
+//! The host tests build against `test`, an emulated architecture.
+//!
+//! Every `export` module must provide the following items, with the same
+//! signatures as the `test` architecture, which serves as the reference:
+//!
+//!   * `cpu`: `MachineState`, the registers captured on a fault or by
+//!     `MachineState::here()`, with `print()`, `print_term()`, `registers()`,
+//!     `stack_ptr()`, `is_user()`, `Display` and `Clone`; and
+//!     `with_user_access()`, `random_u64()`, `halt()`, `idle_wait()`,
+//!     `perm_halt()` and `reset()`;
+//!   * `ioport`: `PortClaim` and `claims()`, empty where there are no I/O
+//!     ports;
+//!   * `keyboard`: `set_leds()`;
+//!   * `logging`: `LOGGER_SERIAL`, the serial logger used before the screen
+//!     is set up and by the panic handler;
+//!   * `mem`: `PAddr`, the layout constants (`LOWMEM_VA_START`, `LOWMEM_SIZE`,
+//!     `IOMAP_VA_START`, `IOMAP_VA_SIZE`, `GUARD_VA_START`, `GUARD_VA_SIZE`,
+//!     `PAGE_SIZE`, `FRAME_SIZE`), the
+//!     low-memory conversions, and the page table accessors
+//!     `page_permissions()`, `set_page_permissions()`, `walk_kernel_mappings()`,
+//!     `walk_mappings()`, `map_page()`, `unmap_page()` and `copy_user()`;
+//!   * `pci`: `config_read32()` and `config_write32()`;
+//!   * `power`: `shutdown()` and `reboot()`;
+//!   * `smp`: `send_call_ipi()`, interrupting a CPU so that it runs
+//!     `task::smp::handle_call_ipi()`; `stop_others()`, sending the other
+//!     CPUs a non-maskable interrupt that calls `panic::stop_if_panicking()`;
+//!     and `send_nmi()`, sending one to a CPU, that calls `watchdog::on_nmi()`;
+//!   * `sync`: `push_critical_region()` and `pop_critical_region()`;
+//!   * `task`: `TaskMachineContext`, with `Clone`, `user()`, `kernel()`,
+//!     `return_value()`, `set_return_value()` and `enter_signal_handler()`;
+//!     `ELF_MACHINE`, the ELF machine type of the programs it runs; and
+//!     `SIGRETURN_TRAMPOLINE`, the code of the signal trampoline;
+//!   * `time`: `timestamp()`, `timestamp_frequency()` and `delay_us()`; and
+//!     `start_sample_timer()` and `stop_sample_timer()`, a periodic interrupt
+//!     calling `profile::sample()`, if there is a timer for it;
+//!   * `VesaFramebuffer`, the firmware-provided framebuffer used on panic.
+//!
+//! The architecture's entry point sets up the CPU, the serial logger and the
+//! memory management, then calls `crate::main()`. It must also define the
+//! linker symbols of `mem::load`, and call `stack_protector::init()` early.
+
+#[cfg(all(not(test), target_arch = "x86_64"))]
 mod x86;
 
-/*#[cfg(all(
-    //target_arch = "x86_64",
-    not(test)
-))]*/
+#[cfg(all(not(test), target_arch = "x86_64"))]
 pub use crate::arch::x86::export::*;
 
-/*#[cfg(test)]
-mod test;
+#[cfg(all(not(test), target_arch = "aarch64"))]
+mod aarch64;
+
+#[cfg(all(not(test), target_arch = "aarch64"))]
+pub use crate::arch::aarch64::export::*;
+
+#[cfg(test)]
+pub mod test;
 
 #[cfg(test)]
-pub use crate::arch::test::export::*;*/
+pub use crate::arch::test::export::*;
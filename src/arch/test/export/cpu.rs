@@ -1,12 +1,46 @@
-use crate::driver::vga::VgaScreen;
+use core::cell::Cell;
 use core::fmt;
 use core::fmt::{Display, Formatter};
+use core::sync::atomic::AtomicU64;
+use std::thread;
+
+use crate::driver::vga::VgaScreen;
 
-pub struct MachineState {}
+/// The machine state of the host tests: there are no registers to capture,
+/// an empty backtrace is unwound from it.
+#[derive(Clone)]
+pub struct MachineState {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+}
 
 impl MachineState {
+    pub fn here() -> Self {
+        Self {
+            rip: 0,
+            rsp: 0,
+            rbp: 0,
+        }
+    }
+
     pub fn print(&self, _vga: &mut impl VgaScreen) -> fmt::Result {
-        unimplemented!();
+        Ok(())
+    }
+
+    pub fn registers(&self) -> [(&'static str, u64); 3] {
+        [("rip", self.rip), ("rsp", self.rsp), ("rbp", self.rbp)]
+    }
+
+    pub fn stack_ptr(&self) -> u64 {
+        self.rsp
+    }
+
+    pub fn is_user(&self) -> bool {
+        false
+    }
+
+    pub fn print_term(&self) {
     }
 }
 
@@ -16,10 +50,39 @@ impl Display for MachineState {
     }
 }
 
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(0x2545_f491_4f6c_dd1d);
+}
+
+/// A deterministic xorshift sequence, so that tests are reproducible.
+pub fn random_u64() -> u64 {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+/// There is no interrupt to wait for: let the other test threads run instead.
 pub fn halt() {
-    unimplemented!();
+    thread::yield_now();
+}
+
+pub fn idle_wait(_wakeup: &AtomicU64, _seen: u64) {
+    thread::yield_now();
 }
 
 pub fn perm_halt() -> ! {
-    unimplemented!();
+    panic!("the CPU was halted permanently");
+}
+
+pub fn reset() -> ! {
+    panic!("the machine was reset");
 }
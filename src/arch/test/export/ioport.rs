@@ -0,0 +1,12 @@
+use arrayvec::ArrayVec;
+
+#[derive(Debug, Copy, Clone)]
+pub struct PortClaim {
+    pub base: u16,
+    pub len: u16,
+    pub owner: &'static str,
+}
+
+pub fn claims() -> ArrayVec<PortClaim, 0> {
+    ArrayVec::new()
+}
@@ -1,7 +1,31 @@
-use crate::mem::{PagePermissions, VAddr};
-use crate::mem::highmem::HighmemGuard;
+//! Fake physical memory and paging for the host tests. The physical memory is
+//! the `MEMORY` array, which the low-memory area maps as a whole. The page
+//! tables only record the mappings made with `map_page()`: the memory behind
+//! them is not accessible through their virtual addresses.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Formatter};
+use core::ops::Range;
+
+use crate::mem::{CacheMode, Mapping, PagePermissions, VAddr};
 use crate::sync::Spinlock;
 
+/// The nominal start of the low-memory area; the actual one is the address of
+/// `MEMORY`, which isn't known at compile time.
+pub const LOWMEM_VA_START: VAddr = VAddr(0);
+pub const LOWMEM_SIZE: usize = NR_PHYS_FRAMES << FRAME_SIZE_BITS;
+
+/// A window of the fake address space mapping nothing else, for `iomap()`.
+pub const IOMAP_VA_START: VAddr = VAddr(0x7f00_0000_0000);
+pub const IOMAP_VA_SIZE: usize = 1 << 30;
+
+/// Another one, for the guarded heap allocations.
+pub const GUARD_VA_START: VAddr = VAddr(0x7e00_0000_0000);
+pub const GUARD_VA_SIZE: usize = 1 << 30;
+
+pub const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE_BITS: usize = 12;
 pub const FRAME_SIZE: usize = 4096;
 pub const FRAME_SIZE_BITS: usize = 12;
 pub const NR_PHYS_FRAMES: usize = 32;
@@ -10,40 +34,152 @@ pub const NR_PHYS_FRAMES: usize = 32;
 pub struct VmMemory(pub [u8; NR_PHYS_FRAMES << 12]);
 
 pub static mut MEMORY: VmMemory = VmMemory([0xf9; NR_PHYS_FRAMES << 12]);
+
+/// Serializes the tests using `MEMORY` or the frame allocator.
 pub static MEMORY_MUTEX: Spinlock<()> = Spinlock::new(());
 
+/// The pages mapped with `map_page()`, by virtual address.
+static PAGE_TABLE: Spinlock<BTreeMap<usize, Mapping>>
+    = Spinlock::new(BTreeMap::new());
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct PAddr(pub u64);
 
-impl PAddr {
-    pub fn into_vaddr(self, _nr_pages: usize) -> Option<HighmemGuard> {
-        unimplemented!()
+impl Debug for PAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PA {:#016x}", self.0)
     }
+}
 
-    pub fn into_lowmem_vaddr(self) -> Option<VAddr> {
-        Some(VAddr(
-            unsafe { MEMORY.0.get(self.0 as usize)? } as *const u8 as usize
-        ))
+impl PAddr {
+    /// Convert the physical address into its low-memory virtual address, in
+    /// `MEMORY`.
+    pub fn into_vaddr(self) -> VAddr {
+        VAddr(memory_base() + self.0 as usize)
     }
 
-    pub fn from_lowmem_vaddr(_vaddr: usize) -> Option<PAddr> {
-        unimplemented!()
+    pub fn from_lowmem_vaddr(vaddr: VAddr) -> Option<PAddr> {
+        let offset = vaddr.0.checked_sub(memory_base())?;
+
+        (offset < LOWMEM_SIZE).then_some(PAddr(offset as u64))
     }
+}
+
+impl VAddr {
+    /// Retrieve the physical address at which this virtual address is mapped to
+    /// if such mapping exists.
+    pub fn to_paddr(self) -> Option<PAddr> {
+        let page = self.0 & !(PAGE_SIZE - 1);
 
-    pub fn is_highmem(&self) -> bool {
-        false
+        match PAGE_TABLE.lock().get(&page) {
+            Some(mapping) => {
+                Some(PAddr(mapping.paddr.0 + (self.0 - page) as u64))
+            },
+            None => PAddr::from_lowmem_vaddr(self),
+        }
     }
 }
 
+fn memory_base() -> usize {
+    unsafe { MEMORY.0.as_ptr() as usize }
+}
+
+/// Fill the physical memory with `0xf9` bytes and unmap all the pages.
 pub fn reset_memory() {
     unsafe { MEMORY.0.fill(0xf9); }
+    PAGE_TABLE.lock().clear();
+}
+
+pub fn page_permissions(vaddr: VAddr) -> PagePermissions {
+    let page = vaddr.0 & !(PAGE_SIZE - 1);
+
+    if let Some(mapping) = PAGE_TABLE.lock().get(&page) {
+        PagePermissions {
+            accessible: true,
+            readable: true,
+            writable: mapping.writable,
+            executable: mapping.executable,
+        }
+    } else {
+        let is_lowmem = PAddr::from_lowmem_vaddr(vaddr).is_some();
+
+        PagePermissions {
+            accessible: is_lowmem,
+            readable: is_lowmem,
+            writable: is_lowmem,
+            executable: false,
+        }
+    }
+}
+
+/// Set the write and execute permissions of the page mapped at `vaddr`.
+/// Returns `false` if the page isn't mapped.
+pub unsafe fn set_page_permissions(vaddr: VAddr,
+                                   writable: bool,
+                                   executable: bool) -> bool {
+    let page = vaddr.0 & !(PAGE_SIZE - 1);
+
+    match PAGE_TABLE.lock().get_mut(&page) {
+        Some(mapping) => {
+            mapping.writable = writable;
+            mapping.executable = executable;
+            true
+        },
+        None => false,
+    }
+}
+
+pub fn walk_kernel_mappings(mut f: impl FnMut(VAddr, usize, PagePermissions)) {
+    let mappings: Vec<Mapping> = PAGE_TABLE.lock().values().copied().collect();
+
+    for mapping in mappings {
+        f(mapping.vaddr, mapping.bsize, PagePermissions {
+            accessible: true,
+            readable: true,
+            writable: mapping.writable,
+            executable: mapping.executable,
+        });
+    }
+}
+
+pub fn walk_mappings(range: Range<VAddr>, mut f: impl FnMut(Mapping)) {
+    let mappings: Vec<Mapping> = PAGE_TABLE.lock()
+        .range(range.start.0..range.end.0)
+        .map(|(_, &mapping)| mapping)
+        .collect();
+
+    for mapping in mappings {
+        f(mapping);
+    }
+}
+
+/// Map the page at `vaddr` onto the frame at `paddr`. Returns `false` if the
+/// page is already mapped.
+pub unsafe fn map_page(vaddr: VAddr, paddr: PAddr, _cache: CacheMode) -> bool {
+    let mut page_table = PAGE_TABLE.lock();
+    if page_table.contains_key(&vaddr.0) {
+        return false;
+    }
+
+    page_table.insert(vaddr.0, Mapping {
+        vaddr,
+        paddr,
+        bsize: PAGE_SIZE,
+        writable: true,
+        executable: false,
+        user: false,
+        global: false,
+    });
+
+    true
 }
 
-pub fn page_permissions(_vaddr: VAddr) -> PagePermissions {
-    unimplemented!()
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    PAGE_TABLE.lock().remove(&vaddr.0);
 }
 
-pub unsafe fn unmap_highmem_vaddr(_vaddr: VAddr) {
-    unimplemented!()
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::ptr::copy(src, dst, len);
+    0
 }
@@ -3,3 +3,12 @@ pub mod mem;
 pub mod cpu;
 pub mod logging;
 pub mod task;
+pub mod time;
+pub mod ioport;
+pub mod keyboard;
+pub mod pci;
+pub mod power;
+pub mod screen;
+pub mod smp;
+
+pub use self::screen::VesaFramebuffer;
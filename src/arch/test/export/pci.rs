@@ -0,0 +1,12 @@
+pub fn config_read32(_bus: u8, _device: u8, _function: u8, _offset: u8) -> u32 {
+    u32::MAX
+}
+
+pub fn config_write32(
+    _bus: u8,
+    _device: u8,
+    _function: u8,
+    _offset: u8,
+    _value: u32,
+) {
+}
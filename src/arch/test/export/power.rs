@@ -0,0 +1,7 @@
+pub fn shutdown() -> ! {
+    panic!("shutdown() called in a host test");
+}
+
+pub fn reboot() -> ! {
+    panic!("reboot() called in a host test");
+}
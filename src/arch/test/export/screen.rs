@@ -0,0 +1,44 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::driver::screen::{Color, FramebufferMode, FramebufferScreen,
+                            PixelFormat};
+
+/// A framebuffer in host memory, standing for the one set up by the firmware.
+pub struct VesaFramebuffer {
+    pub pixels: Vec<u32>,
+    mode: FramebufferMode,
+}
+
+impl VesaFramebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            pixels: vec![0; width * height],
+            mode: FramebufferMode {
+                width,
+                height,
+                pitch: width * 4,
+                format: PixelFormat::XRGB8888,
+            },
+        }
+    }
+}
+
+impl FramebufferScreen for VesaFramebuffer {
+    fn mode(&self) -> FramebufferMode {
+        self.mode
+    }
+
+    fn put(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y * self.mode.width + x] = color.to_rgb();
+    }
+
+    fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
+        let offset = y * self.mode.width + x;
+        self.pixels[offset..(offset + data.len())].copy_from_slice(data);
+    }
+
+    fn clear(&mut self) {
+        self.pixels.fill(0);
+    }
+}
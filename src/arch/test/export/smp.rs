@@ -0,0 +1,19 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+/// The emulated machine has a single CPU: there is no other CPU to interrupt.
+pub fn send_call_ipi(_cpu: usize) {
+}
+
+pub fn stop_others() {
+}
+
+pub fn send_nmi(_cpu: usize) {
+}
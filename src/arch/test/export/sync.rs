@@ -1,11 +1,22 @@
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::cell::Cell;
 
-static CRITICAL_REGION_DEPTH: AtomicU32 = AtomicU32::new(0);
+thread_local! {
+    /// Each test thread stands for a CPU, with its own critical regions.
+    static CRITICAL_REGION_DEPTH: Cell<u32> = Cell::new(0);
+}
 
 pub fn push_critical_region() {
-    CRITICAL_REGION_DEPTH.fetch_add(1, Ordering::SeqCst);
+    CRITICAL_REGION_DEPTH.with(|depth| depth.set(depth.get() + 1));
 }
 
 pub fn pop_critical_region() {
-    CRITICAL_REGION_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    CRITICAL_REGION_DEPTH.with(|depth| {
+        assert!(depth.get() > 0, "unbalanced critical region");
+        depth.set(depth.get() - 1);
+    });
+}
+
+/// The number of critical regions the current test thread is in.
+pub fn critical_region_depth() -> u32 {
+    CRITICAL_REGION_DEPTH.with(|depth| depth.get())
 }
@@ -1,6 +1,73 @@
+use crate::mem::uaccess::copy_to_user;
+use crate::mem::VAddr;
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+
+pub const ELF_MACHINE: u16 = 62;
+
+pub const SIGRETURN_TRAMPOLINE: &[u8] = &[
+    0xb8, 0x0f, 0x00, 0x00, 0x00,
+    0x0f, 0x05,
+];
+
+/// The saved context of a task in the host tests: only what the unwinder
+/// needs, and the registers of system calls and signal handlers.
+#[derive(Clone)]
 pub struct TaskMachineContext {
-    pub a: u64,
-    pub b: u64,
-    pub c: u64,
-    pub d: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rax: u64,
+    pub rdi: u64,
+}
+
+impl TaskMachineContext {
+    pub fn user(entry: VAddr, stack: VAddr) -> Self {
+        Self {
+            rip: entry.0 as u64,
+            rsp: stack.0 as u64,
+            rbp: 0,
+            rax: 0,
+            rdi: 0,
+        }
+    }
+
+    pub fn kernel(
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+        stack: VAddr,
+    ) -> Self {
+        Self {
+            rip: entry as usize as u64,
+            rsp: stack.0 as u64,
+            rbp: 0,
+            rax: 0,
+            rdi: arg as u64,
+        }
+    }
+
+    pub fn return_value(&self) -> usize {
+        self.rax as usize
+    }
+
+    pub fn set_return_value(&mut self, value: usize) {
+        self.rax = value as u64;
+    }
+
+    pub fn enter_signal_handler(
+        &mut self,
+        vm: &VirtualMemory,
+        handler: VAddr,
+        signal: usize,
+        trampoline: VAddr,
+    ) -> Result<(), Errno> {
+        let rsp = (self.rsp.wrapping_sub(128) & !0xf).wrapping_sub(8);
+        copy_to_user(vm, VAddr(rsp as usize),
+                     &(trampoline.0 as u64).to_ne_bytes())?;
+
+        self.rsp = rsp;
+        self.rip = handler.0 as u64;
+        self.rdi = signal as u64;
+        Ok(())
+    }
 }
@@ -0,0 +1,21 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+pub fn timestamp() -> u64 {
+    TIMESTAMP.fetch_add(1, Ordering::SeqCst)
+}
+
+pub fn timestamp_frequency() -> Option<u64> {
+    None
+}
+
+pub fn delay_us(_us: u64) {
+}
+
+pub fn start_sample_timer(_hz: u32) -> bool {
+    false
+}
+
+pub fn stop_sample_timer() {
+}
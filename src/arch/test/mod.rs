@@ -1,2 +1,6 @@
+//! The emulated architecture the host tests run on: fake physical memory and
+//! page tables, per-thread critical regions, and stubs for the devices.
+
 pub mod export;
 pub mod frame;
+mod symbols;
@@ -0,0 +1,33 @@
+//! The symbols that the linker script defines for the kernel image, defined
+//! here for the host tests as empty ranges: there is no kernel image, no
+//! unwind information and no kernel data segment to scan.
+
+use core::arch::global_asm;
+
+global_asm!(r#"
+.pushsection .rodata
+.balign 8
+__kernel_test_empty:
+.popsection
+
+.global __kernel_image_start, __kernel_image_end, __kernel_image_size
+.global __kernel_text_start, __kernel_text_end
+.global __kernel_rodata_start, __kernel_rodata_end
+.global __kernel_data_start, __kernel_data_end
+.global __kernel_eh_frame_hdr, __kernel_eh_frame_hdr_end
+.global __kernel_eh_frame, __kernel_eh_frame_end
+
+.set __kernel_image_start, __kernel_test_empty
+.set __kernel_image_end, __kernel_test_empty
+.set __kernel_image_size, 0
+.set __kernel_text_start, __kernel_test_empty
+.set __kernel_text_end, __kernel_test_empty
+.set __kernel_rodata_start, __kernel_test_empty
+.set __kernel_rodata_end, __kernel_test_empty
+.set __kernel_data_start, __kernel_test_empty
+.set __kernel_data_end, __kernel_test_empty
+.set __kernel_eh_frame_hdr, __kernel_test_empty
+.set __kernel_eh_frame_hdr_end, __kernel_test_empty
+.set __kernel_eh_frame, __kernel_test_empty
+.set __kernel_eh_frame_end, __kernel_test_empty
+"#);
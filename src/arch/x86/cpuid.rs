@@ -8,6 +8,8 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::string::String;
+use core::fmt::{self, Write};
 use x86::cpuid::CpuId;
 
 static mut CPUID: Option<CpuId> = None;
@@ -19,3 +21,67 @@ pub unsafe fn init() {
 pub fn get() -> &'static CpuId {
     unsafe { CPUID.as_ref().expect("CPUID not initialized") }
 }
+
+/// Write the identification and the main features of the CPU; this is the
+/// content of `/proc/cpuinfo`.
+pub fn write_info(out: &mut String) -> fmt::Result {
+    let cpuid = get();
+
+    if let Some(vendor) = cpuid.get_vendor_info() {
+        writeln!(out, "vendor    {}", vendor.as_str())?;
+    }
+    if let Some(brand) = cpuid.get_processor_brand_string() {
+        writeln!(out, "model     {}", brand.as_str().trim())?;
+    }
+
+    if let Some(info) = cpuid.get_feature_info() {
+        writeln!(out, "family    {}", info.family_id())?;
+        writeln!(out, "model id  {}", info.model_id())?;
+        writeln!(out, "stepping  {}", info.stepping_id())?;
+
+        let features = [
+            ("fpu", info.has_fpu()),
+            ("tsc", info.has_tsc()),
+            ("msr", info.has_msr()),
+            ("pae", info.has_pae()),
+            ("apic", info.has_apic()),
+            ("pge", info.has_pge()),
+            ("pat", info.has_pat()),
+            ("mmx", info.has_mmx()),
+            ("sse", info.has_sse()),
+            ("sse2", info.has_sse2()),
+            ("sse3", info.has_sse3()),
+            ("ssse3", info.has_ssse3()),
+            ("sse4.1", info.has_sse41()),
+            ("sse4.2", info.has_sse42()),
+            ("popcnt", info.has_popcnt()),
+            ("aes", info.has_aesni()),
+            ("xsave", info.has_xsave()),
+            ("avx", info.has_avx()),
+            ("rdrand", info.has_rdrand()),
+            ("x2apic", info.has_x2apic()),
+            ("hypervisor", info.has_hypervisor()),
+        ];
+        write!(out, "features ")?;
+        for (name, _) in features.iter().filter(|(_, has)| *has) {
+            write!(out, " {name}")?;
+        }
+        if let Some(ext) = cpuid.get_extended_feature_info() {
+            let features = [
+                ("fsgsbase", ext.has_fsgsbase()),
+                ("bmi1", ext.has_bmi1()),
+                ("bmi2", ext.has_bmi2()),
+                ("avx2", ext.has_avx2()),
+                ("smep", ext.has_smep()),
+                ("smap", ext.has_smap()),
+                ("rdseed", ext.has_rdseed()),
+            ];
+            for (name, _) in features.iter().filter(|(_, has)| *has) {
+                write!(out, " {name}")?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
@@ -8,7 +8,26 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+//! The local APIC, through which the CPUs send each other inter-processor
+//! interrupts. Device interrupts still go through the 8259 PIC: the LAPIC is
+//! only software-enabled, leaving its LINT0 pin in the virtual-wire mode set
+//! up by the firmware.
+//!
+//! Its timer, whose rate depends on the bus clock, is calibrated against the
+//! timestamp counter, see `arch::time::delay_us()`, the first time it is
+//! started; it only drives the sampling profiler, the PIT still being the
+//! system's timer.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86::msr::{rdmsr, IA32_APIC_BASE};
+
+use crate::arch::time::delay_us;
 use crate::arch::x86::cpuid;
+use crate::driver::mmio::RegBlock;
+use crate::mem::resource;
+use crate::mem::{iomap, CacheMode, PAddr};
+use crate::task::cpu::MAX_CPUS;
+use crate::warning;
 
 pub fn is_supported() -> bool {
     if let Some(features) = cpuid::get().get_feature_info() {
@@ -22,29 +41,194 @@ mod register {
     pub const LOCAL_APIC_ID: usize = 0x20;
     pub const LOCAL_APIC_VERSION: usize = 0x30;
     pub const EOI: usize = 0xb0;
+    pub const SPURIOUS_VECTOR: usize = 0xf0;
+    pub const ICR_LOW: usize = 0x300;
+    pub const ICR_HIGH: usize = 0x310;
+    pub const LVT_TIMER: usize = 0x320;
+    pub const TIMER_INITIAL_COUNT: usize = 0x380;
+    pub const TIMER_CURRENT_COUNT: usize = 0x390;
+    pub const TIMER_DIVIDE: usize = 0x3e0;
 }
 
+/// The size of the LAPIC's register page.
+const REGISTERS_BSIZE: usize = 0x400;
+
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// The timer counts down at the bus clock divided by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the timer is left counting down to calibrate it.
+const CALIBRATION_MS: u32 = 10;
+
+/// The timer's count decrements per millisecond, once calibrated.
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// The APIC ID of each CPU, by index; `u32::MAX` for CPUs not brought up.
+static CPU_APIC_IDS: [AtomicU32; MAX_CPUS] = [NO_APIC_ID; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+
+static mut LOCAL_APIC: Option<Apic> = None;
+
 pub struct Apic {
-    regs: *mut u32,
+    regs: RegBlock,
 }
 
 impl Apic {
     pub unsafe fn new(registers: *mut u32) -> Apic {
         Apic {
-            regs: registers,
+            regs: RegBlock::new(registers as *mut u8, REGISTERS_BSIZE),
         }
     }
 
+    pub fn id(&self) -> u32 {
+        self.read(register::LOCAL_APIC_ID) >> 24
+    }
+
+    /// Software-enable the LAPIC, with its spurious interrupts delivered on
+    /// `spurious_vector`.
+    pub fn enable(&self, spurious_vector: u8) {
+        self.write(register::SPURIOUS_VECTOR,
+                   SPURIOUS_APIC_ENABLE | spurious_vector as u32);
+    }
+
     pub fn eoi(&self) {
         self.write(register::EOI, 0);
     }
 
-    fn write(&self, reg: usize, value: u32) {
-        let index = reg >> 2;
-        assert!(index < 252);
+    /// Send the interrupt `vector` to the CPU of APIC ID `apic_id`.
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.wait_delivery();
+        self.write(register::ICR_HIGH, apic_id << 24);
+        self.write(register::ICR_LOW,
+                   ICR_LEVEL_ASSERT | ICR_DELIVERY_FIXED | vector as u32);
+        self.wait_delivery();
+    }
 
-        unsafe {
-            core::ptr::write_volatile(self.regs.add(index), value);
+    /// Send a non-maskable interrupt to the CPU of APIC ID `apic_id`.
+    pub fn send_nmi(&self, apic_id: u32) {
+        self.wait_delivery();
+        self.write(register::ICR_HIGH, apic_id << 24);
+        self.write(register::ICR_LOW, ICR_LEVEL_ASSERT | ICR_DELIVERY_NMI);
+        self.wait_delivery();
+    }
+
+    /// Send a non-maskable interrupt to all the other CPUs.
+    pub fn send_nmi_to_others(&self) {
+        self.wait_delivery();
+        self.write(register::ICR_LOW, ICR_ALL_EXCLUDING_SELF
+                   | ICR_LEVEL_ASSERT | ICR_DELIVERY_NMI);
+        self.wait_delivery();
+    }
+
+    /// Interrupt this CPU on `vector`, `hz` times a second, until
+    /// `stop_timer()`.
+    pub fn start_timer(&self, vector: u8, hz: u32) {
+        let ticks_per_ms = match TIMER_TICKS_PER_MS.load(Ordering::Relaxed) {
+            0 => {
+                let ticks_per_ms = self.calibrate_timer();
+                TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+                ticks_per_ms
+            },
+            ticks_per_ms => ticks_per_ms,
+        };
+
+        self.write(register::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(register::LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        self.write(register::TIMER_INITIAL_COUNT,
+                   (ticks_per_ms as u64 * 1000 / hz.max(1) as u64)
+                       .clamp(1, u32::MAX as u64) as u32);
+    }
+
+    pub fn stop_timer(&self) {
+        self.write(register::LVT_TIMER, LVT_MASKED);
+        self.write(register::TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Count the timer's decrements per millisecond, with its interrupt
+    /// masked.
+    fn calibrate_timer(&self) -> u32 {
+        self.write(register::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(register::LVT_TIMER, LVT_MASKED);
+        self.write(register::TIMER_INITIAL_COUNT, u32::MAX);
+        delay_us(CALIBRATION_MS as u64 * 1000);
+        let elapsed = u32::MAX - self.read(register::TIMER_CURRENT_COUNT);
+        self.write(register::TIMER_INITIAL_COUNT, 0);
+
+        (elapsed / CALIBRATION_MS).max(1)
+    }
+
+    /// Wait for the previous IPI to be accepted.
+    fn wait_delivery(&self) {
+        while self.read(register::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
         }
     }
+
+    fn read(&self, reg: usize) -> u32 {
+        self.regs.read::<u32>(reg)
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        self.regs.write::<u32>(reg, value);
+    }
+}
+
+/// Map and enable the boot CPU's LAPIC, registering it as CPU 0.
+///
+/// # Safety #
+///
+/// Must be called once, during the early boot process, after the memory
+/// management is set up.
+pub unsafe fn init(spurious_vector: u8) {
+    if !is_supported() {
+        warning!("apic: no local APIC, there will be no IPIs");
+        return;
+    }
+
+    let addr = PAddr(rdmsr(IA32_APIC_BASE) & APIC_BASE_ADDR_MASK);
+    if let Err(e) = resource::request_region(addr, REGISTERS_BSIZE as u64,
+                                             "lapic") {
+        warning!("apic: {e}");
+    }
+    let vaddr = match iomap(addr, REGISTERS_BSIZE, CacheMode::Uncached) {
+        Ok(region) => region.leak(),
+        Err(e) => {
+            warning!("apic: {e}");
+            return;
+        },
+    };
+
+    let apic = Apic::new(vaddr.as_mut_ptr());
+    apic.enable(spurious_vector);
+    set_cpu_apic_id(0, apic.id());
+    LOCAL_APIC = Some(apic);
+}
+
+/// The local APIC, the same registers on every CPU; `None` if there is none.
+pub fn local() -> Option<&'static Apic> {
+    unsafe { LOCAL_APIC.as_ref() }
+}
+
+/// Register the APIC ID of the CPU of index `cpu`, once it is brought up.
+pub fn set_cpu_apic_id(cpu: usize, apic_id: u32) {
+    CPU_APIC_IDS[cpu].store(apic_id, Ordering::SeqCst);
+}
+
+/// The APIC ID of the CPU of index `cpu`, if it was brought up.
+pub fn cpu_apic_id(cpu: usize) -> Option<u32> {
+    let apic_id = CPU_APIC_IDS.get(cpu)?.load(Ordering::SeqCst);
+    (apic_id != u32::MAX).then_some(apic_id)
 }
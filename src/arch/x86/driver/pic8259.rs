@@ -9,43 +9,46 @@
  ******************************************************************************/
 
 use crate::arch::x86::Ioport;
-use x86::io::outb;
+use crate::arch::x86::ioport::{self, PortRange};
 
 pub struct Pic8259 {
-    master_port: Ioport,
-    slave_port: Ioport,
+    master: PortRange,
+    slave: PortRange,
 }
 
 impl Pic8259 {
     pub unsafe fn new(master_port: Ioport, slave_port: Ioport) -> Pic8259 {
         Pic8259 {
-            master_port,
-            slave_port,
+            master: ioport::claim(master_port, 2, "pic8259")
+                .expect("couldn't claim master PIC I/O ports"),
+            slave: ioport::claim(slave_port, 2, "pic8259")
+                .expect("couldn't claim slave PIC I/O ports"),
         }
     }
 
     pub unsafe fn init(&mut self, master_vec_base: u8, slave_vec_base: u8) {
-        outb(self.master_port,     0b0001_0001);
-        outb(self.slave_port,      0b0001_0001);
-        outb(self.master_port + 1, master_vec_base);
-        outb(self.slave_port + 1,  slave_vec_base);
-        outb(self.master_port + 1, 1 << 2); // Slave on IRQ 2
-        outb(self.slave_port + 1,  1 << 1); // Slave ID 1
-        outb(self.master_port + 1, 0b0000_0001);
-        outb(self.slave_port + 1,  0b0000_0001);
-        outb(self.master_port + 1, 0b0000_0000);
-        outb(self.slave_port + 1,  0b0000_0000);
+        let (master_cmd, master_data) =
+            (self.master.port::<u8>(0), self.master.port::<u8>(1));
+        let (slave_cmd, slave_data) =
+            (self.slave.port::<u8>(0), self.slave.port::<u8>(1));
+
+        master_cmd.write(0b0001_0001);
+        slave_cmd.write(0b0001_0001);
+        master_data.write(master_vec_base);
+        slave_data.write(slave_vec_base);
+        master_data.write(1 << 2); // Slave on IRQ 2
+        slave_data.write(1 << 1); // Slave ID 1
+        master_data.write(0b0000_0001);
+        slave_data.write(0b0000_0001);
+        master_data.write(0b0000_0000);
+        slave_data.write(0b0000_0000);
     }
 
     pub fn ack_irq(&mut self, irq: u32) {
         if irq >= 8 {
-            unsafe {
-                outb(self.slave_port, 0x20);
-            }
+            self.slave.port::<u8>(0).write(0x20);
         }
 
-        unsafe {
-            outb(self.master_port, 0x20);
-        }
+        self.master.port::<u8>(0).write(0x20);
     }
 }
@@ -8,52 +8,156 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use x86::io::{inb, outb};
+use arrayvec::ArrayVec;
 
 use crate::arch::sync::{pop_critical_region, push_critical_region};
-use crate::driver::keyboard::{Key, KeyEvent, on_key_event};
+use crate::arch::time::delay_us;
+use crate::arch::x86::ioport::{self, Port};
+use crate::driver::keyboard::{Key, KeyEvent, KeyboardLeds, on_key_event};
+use crate::driver::mouse::{MouseButtons, MouseEvent, on_mouse_event};
 use crate::sync::Spinlock;
+use crate::{initcall, warning};
 
-const DATA_PORT: u16 = 0x60;
-const STATUS_REGISTER: u16 = 0x64;
-const COMMAND_REGISTER: u16 = 0x64;
+pub const MOUSE_IRQ: usize = 12;
+
+const DATA_PORT: Port<u8> = unsafe { Port::new(0x60) };
+const STATUS_REGISTER: Port<u8> = unsafe { Port::new(0x64) };
+const COMMAND_REGISTER: Port<u8> = unsafe { Port::new(0x64) };
 
 const CMD_READ_CONF: u8 = 0x20;
-const CMD_WRITE_CONF: u8 = 0x20;
-const CMD_DISABLE_DEV1: u8 = 0xae;
+const CMD_WRITE_CONF: u8 = 0x60;
+const CMD_DISABLE_DEV1: u8 = 0xad;
 const CMD_DISABLE_DEV2: u8 = 0xa7;
 const CMD_ENABLE_DEV1: u8 = 0xae;
+const CMD_ENABLE_DEV2: u8 = 0xa8;
+const CMD_WRITE_DEV2: u8 = 0xd4;
+
+const DEV_CMD_SET_LEDS: u8 = 0xed;
+const DEV_CMD_SET_TYPEMATIC: u8 = 0xf3;
+
+const MOUSE_CMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_CMD_GET_ID: u8 = 0xf2;
+const MOUSE_CMD_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_CMD_SET_DEFAULTS: u8 = 0xf6;
+
+/// The device ID of a mouse with the IntelliMouse extension, i.e. a wheel.
+const MOUSE_ID_INTELLIMOUSE: u8 = 0x03;
+
+const MOUSE_PACKET_LEFT: u8 = 1 << 0;
+const MOUSE_PACKET_RIGHT: u8 = 1 << 1;
+const MOUSE_PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set in the first byte of a packet, which allows to resynchronize.
+const MOUSE_PACKET_ALWAYS_1: u8 = 1 << 3;
+const MOUSE_PACKET_X_SIGN: u8 = 1 << 4;
+const MOUSE_PACKET_Y_SIGN: u8 = 1 << 5;
+const MOUSE_PACKET_X_OVERFLOW: u8 = 1 << 6;
+const MOUSE_PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+const DEV_REPLY_ACK: u8 = 0xfa;
+const DEV_REPLY_RESEND: u8 = 0xfe;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// The slowest typematic setting: 1 s delay, 2 repeats per second. Key repeats
+/// are synthesized by `driver::keyboard`, the keyboard's own are ignored; they
+/// should thus cost as few interrupts as possible.
+const TYPEMATIC_SLOWEST: u8 = 0x7f;
+
+/// The maximum number of bytes of device commands waiting to be sent.
+const MAX_PENDING_BYTES: usize = 8;
+
+/// How long to poll for a device's reply before giving up, e.g. because there
+/// is no mouse.
+const REPLY_TIMEOUT_US: u64 = 20_000;
+const REPLY_POLL_PERIOD_US: u64 = 10;
 
 const STATUS_OUTPUT_BUSY: u8 = 1 << 0;
 const STATUS_INPUT_BUSY: u8 = 1 << 1;
+/// The byte in the output buffer comes from the auxiliary device, the mouse.
+const STATUS_AUX_OUTPUT: u8 = 1 << 5;
 
 const CTRL_CONF_DEV1_INTERRUPT: u8 = 1 << 0;
 const CTRL_CONF_DEV2_INTERRUPT: u8 = 1 << 1;
 const CTRL_CONF_DEV1_TRANSLATION: u8 = 1 << 6;
 
 static PS2_KEYBOARD: Spinlock<Option<PS2Keyboard>> = Spinlock::new(None);
+static PS2_MOUSE: Spinlock<Option<PS2Mouse>> = Spinlock::new(None);
 
 pub struct PS2Keyboard {
     is_e0_state: bool,
+
+    /// The bytes of the device commands not yet acknowledged by the keyboard;
+    /// the first one was sent and is awaiting its acknowledgment if
+    /// `awaiting_ack`.
+    pending: ArrayVec<u8, MAX_PENDING_BYTES>,
+    awaiting_ack: bool,
 }
 
 impl PS2Keyboard {
     pub fn new() -> Self {
         Self {
             is_e0_state: false,
+            pending: ArrayVec::new(),
+            awaiting_ack: false,
         }
     }
 
-    pub fn on_irq(&mut self) {
-        if is_output_full() {
-            let byte = unsafe { inb(DATA_PORT) };
-            if byte == 0xe0 {
+    /// Handle the keyboard's interrupt; return the key event it reported, if
+    /// any.
+    pub fn on_irq(&mut self) -> Option<KeyEvent> {
+        // A mouse byte is left for the mouse's interrupt.
+        let status = STATUS_REGISTER.read();
+        if status & STATUS_OUTPUT_BUSY == 0 || status & STATUS_AUX_OUTPUT != 0 {
+            return None;
+        }
+
+        let byte = DATA_PORT.read();
+        match byte {
+            DEV_REPLY_ACK if self.awaiting_ack => {
+                self.pending.remove(0);
+                self.awaiting_ack = false;
+                self.send_next();
+                None
+            },
+            DEV_REPLY_RESEND if self.awaiting_ack => {
+                self.awaiting_ack = false;
+                self.send_next();
+                None
+            },
+            0xe0 => {
                 self.is_e0_state = true;
-            } else {
+                None
+            },
+            _ => {
                 let ev = self.read_key(byte);
                 self.is_e0_state = false;
-                on_key_event(ev);
-            }
+                Some(ev)
+            },
+        }
+    }
+
+    /// Queue the bytes of a device command; each byte is sent once the
+    /// previous one was acknowledged.
+    fn send_command(&mut self, bytes: &[u8]) {
+        if self.pending.remaining_capacity() < bytes.len() {
+            warning!("PS/2 keyboard: command {:#04x} dropped, too many pending",
+                     bytes[0]);
+            return;
+        }
+
+        self.pending.try_extend_from_slice(bytes).unwrap();
+        if !self.awaiting_ack {
+            self.send_next();
+        }
+    }
+
+    fn send_next(&mut self) {
+        if let Some(&byte) = self.pending.first() {
+            wait_input_ready();
+            DATA_PORT.write(byte);
+            self.awaiting_ack = true;
         }
     }
 
@@ -175,7 +279,84 @@ impl PS2Keyboard {
     }
 }
 
+pub struct PS2Mouse {
+    packet: ArrayVec<u8, 4>,
+    /// 4 with the IntelliMouse extension, 3 otherwise.
+    packet_len: usize,
+}
+
+impl PS2Mouse {
+    pub fn new(packet_len: usize) -> Self {
+        Self {
+            packet: ArrayVec::new(),
+            packet_len,
+        }
+    }
+
+    /// Handle the mouse's interrupt; return the event reported if it completed
+    /// a packet.
+    pub fn on_irq(&mut self) -> Option<MouseEvent> {
+        let status = STATUS_REGISTER.read();
+        if status & STATUS_OUTPUT_BUSY == 0 || status & STATUS_AUX_OUTPUT == 0 {
+            return None;
+        }
+
+        let byte = DATA_PORT.read();
+        if self.packet.is_empty() && byte & MOUSE_PACKET_ALWAYS_1 == 0 {
+            // Out of sync: this can't be the first byte of a packet.
+            return None;
+        }
+
+        self.packet.push(byte);
+        if self.packet.len() < self.packet_len {
+            return None;
+        }
+
+        let event = decode_mouse_packet(&self.packet);
+        self.packet.clear();
+        Some(event)
+    }
+}
+
+fn decode_mouse_packet(packet: &[u8]) -> MouseEvent {
+    let flags = packet[0];
+    let axis = |value: u8, sign: u8, overflow: u8| {
+        if flags & overflow != 0 {
+            0
+        } else if flags & sign != 0 {
+            value as i32 - 0x100
+        } else {
+            value as i32
+        }
+    };
+
+    MouseEvent {
+        dx: axis(packet[1], MOUSE_PACKET_X_SIGN, MOUSE_PACKET_X_OVERFLOW),
+        // The mouse counts upwards, the screen downwards.
+        dy: -axis(packet[2], MOUSE_PACKET_Y_SIGN, MOUSE_PACKET_Y_OVERFLOW),
+        // The wheel motion is a signed 4-bit value.
+        wheel: packet.get(3).map_or(0, |&z| ((z << 4) as i8) >> 4),
+        buttons: MouseButtons {
+            left: flags & MOUSE_PACKET_LEFT != 0,
+            right: flags & MOUSE_PACKET_RIGHT != 0,
+            middle: flags & MOUSE_PACKET_MIDDLE != 0,
+        },
+    }
+}
+
 pub fn init() {
+    // The controller's ports are never released: it is also used to reset the
+    // machine.
+    for port in [0x60, 0x64] {
+        match ioport::claim(port, 1, "ps2") {
+            Ok(range) => core::mem::forget(range),
+            Err(e) => {
+                warning!("PS/2 controller unavailable: {e}");
+                return;
+            },
+        }
+    }
+
     push_critical_region();
 
     drain_output();
@@ -183,33 +364,79 @@ pub fn init() {
     send_cmd(CMD_DISABLE_DEV1);
     send_cmd(CMD_DISABLE_DEV2);
 
+    drain_output();
+
+    // The scan codes are decoded as set 1, hence the translation.
     let mut ctrl = read_conf_byte(0);
     ctrl &= !CTRL_CONF_DEV1_INTERRUPT;
     ctrl &= !CTRL_CONF_DEV2_INTERRUPT;
-    ctrl &= !CTRL_CONF_DEV1_TRANSLATION;
+    ctrl |= CTRL_CONF_DEV1_TRANSLATION;
     write_conf_byte(0, ctrl);
 
     send_cmd(CMD_ENABLE_DEV1);
 
     drain_output();
 
+    // Interrupts are not enabled yet, the replies are polled for.
+    send_device_cmd_sync(false, &[DEV_CMD_SET_TYPEMATIC, TYPEMATIC_SLOWEST]);
+    send_device_cmd_sync(false, &[DEV_CMD_SET_LEDS, 0]);
     *PS2_KEYBOARD.lock() = Some(PS2Keyboard::new());
+    ctrl |= CTRL_CONF_DEV1_INTERRUPT;
+
+    match init_mouse() {
+        Some(packet_len) => {
+            *PS2_MOUSE.lock() = Some(PS2Mouse::new(packet_len));
+            ctrl |= CTRL_CONF_DEV2_INTERRUPT;
+        },
+        None => send_cmd(CMD_DISABLE_DEV2),
+    }
+
+    write_conf_byte(0, ctrl);
 
     pop_critical_region();
 }
 
+initcall!(device, init, "Probing PS/2 devices...");
+
 pub fn on_irq() {
-    if let Some(kb) = PS2_KEYBOARD.lock().as_mut() {
-        kb.on_irq();
+    // The lock is released before reporting the event, which may well update
+    // the LEDs.
+    let event = PS2_KEYBOARD.lock().as_mut().and_then(|kb| kb.on_irq());
+    if let Some(event) = event {
+        on_key_event(event);
+    }
+}
+
+pub fn on_mouse_irq() {
+    let event = PS2_MOUSE.lock().as_mut().and_then(|mouse| mouse.on_irq());
+    if let Some(event) = event {
+        on_mouse_event(event);
     }
 }
 
-pub fn hard_reset() -> ! {
-    unsafe {
-        outb(COMMAND_REGISTER, 0xfe);
+/// Light the keyboard's LEDs according to `leds`.
+pub fn set_leds(leds: KeyboardLeds) {
+    let mut byte = 0;
+    if leds.scroll_lock {
+        byte |= LED_SCROLL_LOCK;
+    }
+    if leds.num_lock {
+        byte |= LED_NUM_LOCK;
+    }
+    if leds.caps_lock {
+        byte |= LED_CAPS_LOCK;
     }
 
-    unreachable!()
+    if let Some(kb) = PS2_KEYBOARD.lock().as_mut() {
+        kb.send_command(&[DEV_CMD_SET_LEDS, byte]);
+    }
+}
+
+/// Pulse the CPU reset line through the controller's output port; this
+/// returns if the controller didn't reset the system.
+pub fn pulse_reset_line() {
+    wait_input_ready();
+    COMMAND_REGISTER.write(0xfe);
 }
 
 fn read_conf_byte(offset: u8) -> u8 {
@@ -219,31 +446,89 @@ fn read_conf_byte(offset: u8) -> u8 {
 
     send_cmd(CMD_READ_CONF + offset);
     wait_for_output();
-    unsafe {
-        inb(DATA_PORT)
-    }
+    DATA_PORT.read()
 }
 
 fn write_conf_byte(offset: u8, byte: u8) {
     if offset > 17 {
         panic!("Invalid offset");
     }
+    send_cmd(CMD_WRITE_CONF + offset);
     wait_input_ready();
-    unsafe {
-        outb(DATA_PORT, byte);
+    DATA_PORT.write(byte);
+}
+
+/// Enable the mouse on the auxiliary port, if there is one; return the size
+/// of its packets.
+fn init_mouse() -> Option<usize> {
+    send_cmd(CMD_ENABLE_DEV2);
+    if !send_device_cmd_sync(true, &[MOUSE_CMD_SET_DEFAULTS]) {
+        return None;
     }
-    send_cmd(CMD_WRITE_CONF + offset);
+
+    // This magic sequence of sample rates enables the IntelliMouse extension,
+    // which reports the wheel in a 4th byte, on mice that support it.
+    send_device_cmd_sync(true, &[
+        MOUSE_CMD_SET_SAMPLE_RATE, 200,
+        MOUSE_CMD_SET_SAMPLE_RATE, 100,
+        MOUSE_CMD_SET_SAMPLE_RATE, 80,
+    ]);
+    let has_wheel = send_device_cmd_sync(true, &[MOUSE_CMD_GET_ID])
+        && poll_reply(true) == Some(MOUSE_ID_INTELLIMOUSE);
+
+    if !send_device_cmd_sync(true, &[MOUSE_CMD_ENABLE_REPORTING]) {
+        return None;
+    }
+
+    Some(if has_wheel { 4 } else { 3 })
+}
+
+/// Send a command to the keyboard, or to the mouse if `aux`, polling for the
+/// acknowledgment of each of its bytes; return whether they were all
+/// acknowledged. A byte the device asks to resend is resent once.
+fn send_device_cmd_sync(aux: bool, bytes: &[u8]) -> bool {
+    bytes.iter().all(|&byte| {
+        for _ in 0..2 {
+            if aux {
+                send_cmd(CMD_WRITE_DEV2);
+            }
+            wait_input_ready();
+            DATA_PORT.write(byte);
+
+            match poll_reply(aux) {
+                Some(DEV_REPLY_RESEND) => continue,
+                reply => return reply == Some(DEV_REPLY_ACK),
+            }
+        }
+        false
+    })
+}
+
+/// Poll for a byte from the mouse if `aux`, or from the keyboard otherwise;
+/// the other device's bytes are discarded.
+fn poll_reply(aux: bool) -> Option<u8> {
+    for _ in 0..(REPLY_TIMEOUT_US / REPLY_POLL_PERIOD_US) {
+        let status = STATUS_REGISTER.read();
+        if status & STATUS_OUTPUT_BUSY != 0 {
+            let byte = DATA_PORT.read();
+            if (status & STATUS_AUX_OUTPUT != 0) == aux {
+                return Some(byte);
+            }
+        } else {
+            delay_us(REPLY_POLL_PERIOD_US);
+        }
+    }
+
+    None
 }
 
 fn send_cmd(cmd: u8) {
     wait_input_ready();
-    unsafe {
-        outb(COMMAND_REGISTER, cmd);
-    }
+    COMMAND_REGISTER.write(cmd);
 }
 
 fn wait_input_ready() {
-    while unsafe { inb(STATUS_REGISTER) } & STATUS_INPUT_BUSY > 0 {}
+    while STATUS_REGISTER.read() & STATUS_INPUT_BUSY > 0 {}
 }
 
 fn wait_for_output() {
@@ -251,13 +536,60 @@ fn wait_for_output() {
 }
 
 fn is_output_full() -> bool {
-    (unsafe { inb(STATUS_REGISTER) } & STATUS_OUTPUT_BUSY) > 0
+    (STATUS_REGISTER.read() & STATUS_OUTPUT_BUSY) > 0
 }
 
 fn drain_output() {
     while is_output_full() {
-        unsafe {
-            inb(DATA_PORT);
+        DATA_PORT.read();
+    }
+}
+
+#[cfg(all(feature = "ktest", not(test)))]
+mod ktests {
+    use crate::ktest;
+    use super::*;
+
+    ktest! {
+        fn it_decodes_mouse_packets() {
+            let event = decode_mouse_packet(&[
+                MOUSE_PACKET_ALWAYS_1 | MOUSE_PACKET_LEFT, 5, 3,
+            ]);
+            assert_eq!(event, MouseEvent {
+                dx: 5,
+                dy: -3,
+                wheel: 0,
+                buttons: MouseButtons {
+                    left: true,
+                    right: false,
+                    middle: false,
+                },
+            });
+        }
+
+        fn it_sign_extends_mouse_motions() {
+            let event = decode_mouse_packet(&[
+                MOUSE_PACKET_ALWAYS_1 | MOUSE_PACKET_X_SIGN
+                    | MOUSE_PACKET_Y_SIGN,
+                0xfb, 0xfe,
+            ]);
+            assert_eq!((event.dx, event.dy), (-5, 2));
+        }
+
+        fn it_drops_overflowed_mouse_motions() {
+            let event = decode_mouse_packet(&[
+                MOUSE_PACKET_ALWAYS_1 | MOUSE_PACKET_X_OVERFLOW
+                    | MOUSE_PACKET_X_SIGN | MOUSE_PACKET_Y_OVERFLOW,
+                0x80, 0x7f,
+            ]);
+            assert_eq!((event.dx, event.dy), (0, 0));
+        }
+
+        fn it_decodes_the_mouse_wheel() {
+            let flags = MOUSE_PACKET_ALWAYS_1;
+            assert_eq!(decode_mouse_packet(&[flags, 0, 0, 0x0f]).wheel, -1);
+            // The upper bits are the 4th and 5th buttons.
+            assert_eq!(decode_mouse_packet(&[flags, 0, 0, 0x31]).wheel, 1);
         }
     }
 }
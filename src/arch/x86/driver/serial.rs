@@ -8,11 +8,18 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use x86::io::{outb, inb};
+use alloc::sync::Arc;
 use core::fmt;
 use core::fmt::Write;
+use arrayvec::ArrayVec;
 
+use crate::arch::x86::export::logging::LOGGER_SERIAL;
+use crate::arch::x86::ioport::{self, Port, PortRange};
+use crate::fs::devfs;
 use crate::logging::{Logger, Severity};
+use crate::sync::Spinlock;
+use crate::ui::tty::Tty;
+use crate::{initcall, warning};
 
 pub const COM1_IOPORT: u16 = 0x03f8;
 pub const COM2_IOPORT: u16 = 0x02f8;
@@ -30,8 +37,18 @@ const REG_LINE_STATUS: u16  = 5;
 const REG_MODEM_STATUS: u16 = 6;
 const REG_SCRATCH: u16      = 7;
 
+const IRQ_ENABLE_RX_AVAILABLE: u8 = 1 << 0;
+const MODEM_CTRL_DTR: u8          = 1 << 0;
+const MODEM_CTRL_RTS: u8          = 1 << 1;
+const MODEM_CTRL_OUT2: u8         = 1 << 3;
+
+/// The IRQ line of COM1 (and COM3) on the legacy PIC.
+pub const COM1_IRQ: usize = 4;
+
+static SERIAL_CONSOLE: Spinlock<Option<SerialConsole>> = Spinlock::new(None);
+
 pub struct SerialDevice {
-    ioport_base: u16,
+    ports: PortRange,
     baud_rate: u32,
     parity: ParityMode,
     bits: u8,
@@ -60,7 +77,8 @@ impl SerialDevice {
         stop_bits: StopBits,
     ) -> Result<Self, &'static str> {
         let mut dev = Self {
-            ioport_base,
+            ports: ioport::claim(ioport_base, 8, "serial")
+                .map_err(|_| "I/O ports already claimed")?,
             baud_rate,
             parity,
             bits,
@@ -110,39 +128,54 @@ impl SerialDevice {
         };
         let line_ctrl: u8 = (parity_bits << 3) | (stop_bits << 2) | (bits << 0);
 
-        unsafe {
-            outb(self.ioport_base + REG_LINE_CTRL, 1 << 7); // DLAB = 1
-            outb(self.ioport_base + REG_DIVISOR_MSB, (divisor >> 8) as u8);
-            outb(self.ioport_base + REG_DIVISOR_LSB, (divisor & 0xff) as u8);
-            outb(self.ioport_base + REG_LINE_CTRL, line_ctrl); // DLAB = 0
-            outb(self.ioport_base + REG_IRQ_ENABLE, 0x00);
-        }
+        self.reg(REG_LINE_CTRL).write(1 << 7); // DLAB = 1
+        self.reg(REG_DIVISOR_MSB).write((divisor >> 8) as u8);
+        self.reg(REG_DIVISOR_LSB).write((divisor & 0xff) as u8);
+        self.reg(REG_LINE_CTRL).write(line_ctrl); // DLAB = 0
+        self.reg(REG_IRQ_ENABLE).write(0x00);
 
         Ok(())
     }
 
+    /// Have the UART raise an interrupt whenever a byte was received. The OUT2
+    /// line must be asserted as well since it gates the IRQ line on PCs.
+    pub fn enable_rx_irq(&mut self) {
+        self.reg(REG_MODEM_CTRL)
+            .write(MODEM_CTRL_DTR | MODEM_CTRL_RTS | MODEM_CTRL_OUT2);
+        self.reg(REG_IRQ_ENABLE).write(IRQ_ENABLE_RX_AVAILABLE);
+    }
+
+    #[inline]
+    fn reg(&self, reg: u16) -> Port<u8> {
+        self.ports.port(reg)
+    }
+
     pub fn may_read(&self) -> bool {
-        (unsafe { inb(self.ioport_base + REG_LINE_STATUS) } & (1 << 0)) > 0
+        (self.reg(REG_LINE_STATUS).read() & (1 << 0)) > 0
+    }
+
+    pub fn try_read(&self) -> Option<u8> {
+        if self.may_read() {
+            Some(self.reg(REG_DATA).read())
+        } else {
+            None
+        }
     }
 
     pub fn read_blocking(&self) -> u8 {
         while !self.may_read() {}
 
-        unsafe {
-            inb(self.ioport_base + REG_DATA)
-        }
+        self.reg(REG_DATA).read()
     }
 
     pub fn may_write(&self) -> bool {
-        (unsafe { inb(self.ioport_base + REG_LINE_STATUS) } & (1 << 5)) > 0
+        (self.reg(REG_LINE_STATUS).read() & (1 << 5)) > 0
     }
 
     pub fn write_byte(&mut self, byte: u8) {
         while !self.may_write() {}
 
-        unsafe {
-            outb(self.ioport_base + REG_DATA, byte);
-        }
+        self.reg(REG_DATA).write(byte);
     }
 }
 
@@ -174,3 +207,75 @@ impl Logger for SerialDevice {
         write!(self, "\x1b[0m\n").unwrap();
     }
 }
+
+/// The serial line used as an interactive console: received bytes are decoded
+/// as UTF-8 and fed to its TTY, which echoes back onto the same line.
+struct SerialConsole {
+    tty: Arc<Tty>,
+    utf8_buf: ArrayVec<u8, 4>,
+}
+
+impl SerialConsole {
+    fn on_byte(&mut self, byte: u8) {
+        if self.utf8_buf.try_push(byte).is_err() {
+            self.utf8_buf.clear();
+            return;
+        }
+
+        match core::str::from_utf8(&self.utf8_buf) {
+            Ok(s) => {
+                let c = s.chars().next().unwrap();
+                self.utf8_buf.clear();
+                self.tty.input(c);
+            },
+            // Incomplete sequence, wait for the next bytes.
+            Err(e) if e.error_len().is_none() => (),
+            Err(_) => self.utf8_buf.clear(),
+        }
+    }
+}
+
+/// Turn the logging serial device into an interactive console by enabling its
+/// receive interrupt, and register its TTY as `/dev/ttyS0`. `COM1_IRQ` must be
+/// routed to `on_irq()`, as done when setting up interrupts.
+pub fn init_console() {
+    let dev = unsafe { LOGGER_SERIAL.as_mut() };
+
+    if let Some(dev) = dev {
+        let tty = Arc::new(Tty::new(write_tty));
+        *SERIAL_CONSOLE.lock() = Some(SerialConsole {
+            tty: tty.clone(),
+            utf8_buf: ArrayVec::new(),
+        });
+        dev.enable_rx_irq();
+
+        if let Err(e) = devfs::register("ttyS0", tty) {
+            warning!("devfs: ttyS0: {e}");
+        }
+    }
+}
+
+initcall!(device, init_console, "Starting the serial console...");
+
+/// The TTY of the serial console, `ttyS0`, if there is one.
+pub fn tty() -> Option<Arc<Tty>> {
+    SERIAL_CONSOLE.lock().as_ref().map(|console| console.tty.clone())
+}
+
+fn write_tty(data: &[u8]) {
+    if let Some(dev) = unsafe { LOGGER_SERIAL.as_mut() } {
+        for &byte in data {
+            dev.write_byte(byte);
+        }
+    }
+}
+
+pub fn on_irq() {
+    if let Some(console) = SERIAL_CONSOLE.lock().as_mut() {
+        // Not borrowing the device across `on_byte()`, which echoes onto it.
+        while let Some(byte) = unsafe { LOGGER_SERIAL.as_mut() }
+            .and_then(|dev| dev.try_read()) {
+            console.on_byte(byte);
+        }
+    }
+}
@@ -8,56 +8,78 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use crate::driver::screen::{FramebufferScreen, Color};
+use crate::driver::mmio::RegBlock;
+use crate::driver::screen::{Color, FramebufferMode, FramebufferScreen,
+                            PixelFormat};
 
+/// The linear framebuffer set up by the firmware (VBE or GOP), as described by
+/// the bootloader.
 pub struct VesaFramebuffer {
-    mem: &'static mut [u32],
-    width: usize,
-    height: usize,
-    pitch: usize,
-    bpp: u8,
+    mem: RegBlock,
+    mode: FramebufferMode,
 }
 
 impl VesaFramebuffer {
-    pub unsafe fn new(buffer: *mut u32,
-                      width: usize,
-                      height: usize,
-                      pitch: usize,
-                      bpp: u8) -> Self {
-        let buff_size = pitch * height;
-
-        assert_eq!(bpp, 32);
+    /// # Safety #
+    ///
+    /// `buffer` must be the virtual address of a mapping of the whole
+    /// framebuffer described by `mode`.
+    pub unsafe fn new(buffer: *mut u8, mode: FramebufferMode) -> Self {
+        assert!(matches!(mode.format.bpp, 16 | 24 | 32),
+                "unsupported framebuffer depth of {} bpp", mode.format.bpp);
+        let buff_size = mode.pitch * mode.height;
 
         VesaFramebuffer {
-            mem: core::slice::from_raw_parts_mut(buffer, buff_size >> 2),
-            width,
-            height,
-            pitch,
-            bpp
+            mem: RegBlock::new(buffer, buff_size),
+            mode,
+        }
+    }
+
+    fn write_pixel(&self, offset: usize, px: u32) {
+        match self.mode.format.bpp {
+            32 => self.mem.write::<u32>(offset, px),
+            16 => self.mem.write::<u16>(offset, px as u16),
+            _ => {
+                self.mem.write::<u8>(offset, px as u8);
+                self.mem.write::<u8>(offset + 1, (px >> 8) as u8);
+                self.mem.write::<u8>(offset + 2, (px >> 16) as u8);
+            },
         }
     }
 }
 
 impl FramebufferScreen for VesaFramebuffer {
-    fn dimensions(&self) -> (usize, usize) {
-        (self.width, self.height)
+    fn mode(&self) -> FramebufferMode {
+        self.mode
     }
 
     fn put(&mut self, x: usize, y: usize, color: Color) {
-        let px = (color.r as u32) << 16
-                 | (color.g as u32) << 8
-                 | (color.b as u32);
-        let index = (self.pitch >> 2) * y + x;
-        self.mem[index] = px;
+        let format = self.mode.format;
+        self.write_pixel(self.mode.pitch * y + x * format.bytes_per_pixel(),
+                         format.encode(color));
     }
 
     fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
-        let index = (self.pitch >> 2) * y + x;
-        let target = &mut self.mem[index..(index + data.len())];
-        target.copy_from_slice(data);
+        let format = self.mode.format;
+        let offset = self.mode.pitch * y + x * format.bytes_per_pixel();
+
+        if format == PixelFormat::XRGB8888 {
+            return self.mem.write_slice(offset, data);
+        }
+
+        for (i, &px) in data.iter().enumerate() {
+            let color = Color {
+                r: (px >> 16) as u8,
+                g: (px >> 8) as u8,
+                b: px as u8,
+            };
+            self.write_pixel(offset + i * format.bytes_per_pixel(),
+                             format.encode(color));
+        }
     }
 
     fn clear(&mut self) {
-        todo!()
+        let (width, height) = self.dimensions();
+        self.fill_rect(0, 0, width, height, Color { r: 0, g: 0, b: 0 });
     }
 }
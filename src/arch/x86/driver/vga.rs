@@ -8,11 +8,37 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use crate::driver::screen::{CharAttrs, Color, TextScreen};
 use crate::driver::vga::VgaScreen;
 
 use core::fmt;
 use core::slice;
 
+/// The physical address of the text mode memory, and its default dimensions.
+pub const TEXT_MEMORY: u64 = 0xb8000;
+pub const TEXT_COLUMNS: u8 = 80;
+pub const TEXT_ROWS: u8 = 25;
+
+/// The 16 colors of the text mode, in attribute order.
+const PALETTE: [Color; 16] = [
+    Color { r: 0x00, g: 0x00, b: 0x00 },
+    Color { r: 0x00, g: 0x00, b: 0xaa },
+    Color { r: 0x00, g: 0xaa, b: 0x00 },
+    Color { r: 0x00, g: 0xaa, b: 0xaa },
+    Color { r: 0xaa, g: 0x00, b: 0x00 },
+    Color { r: 0xaa, g: 0x00, b: 0xaa },
+    Color { r: 0xaa, g: 0x55, b: 0x00 },
+    Color { r: 0xaa, g: 0xaa, b: 0xaa },
+    Color { r: 0x55, g: 0x55, b: 0x55 },
+    Color { r: 0x55, g: 0x55, b: 0xff },
+    Color { r: 0x55, g: 0xff, b: 0x55 },
+    Color { r: 0x55, g: 0xff, b: 0xff },
+    Color { r: 0xff, g: 0x55, b: 0x55 },
+    Color { r: 0xff, g: 0x55, b: 0xff },
+    Color { r: 0xff, g: 0xff, b: 0x55 },
+    Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
 pub struct Vga<'a> {
     mem:    &'a mut [u8],
     width:  u8,
@@ -69,7 +95,7 @@ impl<'a> VgaScreen for Vga<'a> {
         }
 
         if self.curs_y >= self.height {
-            self.scroll_up(self.curs_y - self.height + 1);
+            VgaScreen::scroll_up(self, self.curs_y - self.height + 1);
         }
     }
 
@@ -113,6 +139,48 @@ impl<'a> VgaScreen for Vga<'a> {
     }
 }
 
+impl<'a> TextScreen for Vga<'a> {
+    fn dimensions(&self) -> (usize, usize) {
+        (self.width as usize, self.height as usize)
+    }
+
+    fn put(&mut self, x: usize, y: usize, c: char, attrs: CharAttrs) {
+        // Only 8 background colors: the attribute's top bit makes it blink.
+        let attr = nearest_color(attrs.bg_color, 8) << 4
+                   | nearest_color(attrs.color, 16);
+        let index = (y * self.width as usize + x) * 2;
+        self.mem[index] = if c.is_ascii() { c as u8 } else { b'?' };
+        self.mem[index + 1] = attr;
+    }
+
+    fn scroll_up(&mut self, lines: u8) {
+        let lines = lines.min(self.height);
+        let start = lines as usize * self.width as usize * 2;
+
+        self.mem.copy_within(start.., 0);
+        let len = self.mem.len();
+        self.mem[(len - start)..].fill(0);
+    }
+
+    fn clear(&mut self) {
+        self.mem.fill(0);
+    }
+}
+
+/// The index of the color closest to `color` among the first `nr_colors` of
+/// the palette.
+fn nearest_color(color: Color, nr_colors: usize) -> u8 {
+    let distance = |other: &Color| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(color.r, other.r) + d(color.g, other.g) + d(color.b, other.b)
+    };
+
+    PALETTE[..nr_colors].iter()
+        .enumerate()
+        .min_by_key(|(_, other)| distance(other))
+        .map_or(0, |(i, _)| i as u8)
+}
+
 impl<'a> fmt::Write for Vga<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.put_str(s);
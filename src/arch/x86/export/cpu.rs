@@ -11,11 +11,16 @@
 use core::arch::asm;
 use core::fmt;
 use core::fmt::{Formatter, Display};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use crate::arch::x86::driver::ps2;
+use crate::arch::x86::cpuid;
+use crate::arch::x86::export::power;
+use crate::arch::x86::random;
+use crate::arch::x86::security::UserAccessGuard;
 use crate::driver::vga::VgaScreen;
 use crate::println;
 
+#[derive(Clone)]
 pub struct MachineState {
     pub rax: u64,
     pub rbx: u64,
@@ -125,6 +130,35 @@ impl MachineState {
                  self.rip, self.cs, self.ss, self.ds, self.es, self.fs, self.gs)
     }
 
+    /// All saved registers with their name, in a fixed order; this is meant
+    /// for machine-readable outputs such as the crash dump.
+    pub fn registers(&self) -> [(&'static str, u64); 24] {
+        [
+            ("rax", self.rax), ("rbx", self.rbx),
+            ("rcx", self.rcx), ("rdx", self.rdx),
+            ("r8", self.r8), ("r9", self.r9),
+            ("r10", self.r10), ("r11", self.r11),
+            ("r12", self.r12), ("r13", self.r13),
+            ("r14", self.r14), ("r15", self.r15),
+            ("rdi", self.rdi), ("rsi", self.rsi),
+            ("rsp", self.rsp), ("rbp", self.rbp),
+            ("rip", self.rip), ("rflags", self.rflags),
+            ("cs", self.cs as u64), ("ss", self.ss as u64),
+            ("ds", self.ds as u64), ("es", self.es as u64),
+            ("fs", self.fs as u64), ("gs", self.gs as u64),
+        ]
+    }
+
+    /// The stack pointer at the time the state was captured.
+    pub fn stack_ptr(&self) -> u64 {
+        self.rsp
+    }
+
+    /// Whether the state was captured in user mode, at CPL 3.
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+
     pub fn print_term(&self) {
         use crate::screen::R;
 
@@ -158,10 +192,57 @@ impl Display for MachineState {
     }
 }
 
+/// Run `f` with the kernel allowed to access user-space memory; outside of it,
+/// such accesses fault if the CPU supports SMAP. The user access must not
+/// outlive `f`, and `f` must not call into unrelated code.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = UserAccessGuard::new();
+    f()
+}
+
+/// Return a random value, not suitable for cryptography.
+pub fn random_u64() -> u64 {
+    random::random_u64()
+}
+
+/// Whether the CPU can idle with `MONITOR`/`MWAIT`, as detected by
+/// `init_idle()`.
+static HAS_MWAIT: AtomicBool = AtomicBool::new(false);
+
 pub fn halt() {
     unsafe { x86::halt(); }
 }
 
+/// Detect whether the CPU supports `MONITOR`/`MWAIT`; until this is called,
+/// `idle_wait()` falls back to `HLT`.
+pub fn init_idle() {
+    let has_mwait = cpuid::get().get_feature_info()
+        .is_some_and(|info| info.has_monitor_mwait());
+    HAS_MWAIT.store(has_mwait, Ordering::Relaxed);
+}
+
+/// Put the CPU into a low-power state until the next interrupt, or until
+/// `wakeup` no longer holds `seen`. With `MWAIT`, a write to `wakeup` from
+/// another CPU is enough to wake this one up, without an IPI; with `HLT`, only
+/// an interrupt does.
+pub fn idle_wait(wakeup: &AtomicU64, seen: u64) {
+    if !HAS_MWAIT.load(Ordering::Relaxed) {
+        halt();
+        return;
+    }
+
+    unsafe {
+        asm!("monitor", in("rax") wakeup.as_ptr(), in("ecx") 0, in("edx") 0,
+             options(nostack, preserves_flags));
+        // A write between the caller's check and the MONITOR isn't seen by
+        // MWAIT: check again once the address is armed.
+        if wakeup.load(Ordering::SeqCst) == seen {
+            asm!("mwait", in("eax") 0, in("ecx") 0,
+                 options(nostack, preserves_flags));
+        }
+    }
+}
+
 pub fn perm_halt() -> ! {
     unsafe { x86::irq::disable() };
     loop {
@@ -170,5 +251,5 @@ pub fn perm_halt() -> ! {
 }
 
 pub fn reset() -> ! {
-    ps2::hard_reset();
+    power::reboot();
 }
@@ -0,0 +1,17 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::arch::x86::driver::ps2;
+use crate::driver::keyboard::KeyboardLeds;
+
+/// Light the keyboard's LEDs according to `leds`.
+pub fn set_leds(leds: KeyboardLeds) {
+    ps2::set_leds(leds);
+}
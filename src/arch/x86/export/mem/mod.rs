@@ -9,9 +9,12 @@
  ******************************************************************************/
 
 use core::fmt::{self, Debug, Formatter};
+use core::ops::Range;
 
-use crate::mem::{PagePermissions, get_lowmem_va_end, VAddr};
-use crate::arch::x86::mem::paging::{locate_page_entry, AnyEntry};
+use crate::mem::{CacheMode, Mapping, PagePermissions, get_lowmem_va_end,
+                 VAddr};
+use crate::arch::x86::mem::paging::{self, locate_page_entry, AnyEntry};
+use crate::arch::x86::uaccess;
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -73,6 +76,16 @@ pub const LOWMEM_VA_START: VAddr = VAddr(0xffff8000_00000000);
 
 pub const LOWMEM_SIZE: usize = (128 << 40) - 1; // 128 Tio - 1
 
+/// The virtual address window where device memory is mapped by `iomap()`, the
+/// last but one PML4 entry.
+pub const IOMAP_VA_START: VAddr = VAddr(0xffffff00_00000000);
+pub const IOMAP_VA_SIZE: usize = 512 << 30;
+
+/// The virtual address window of the guarded heap allocations, see
+/// `mem::kalloc::guard`, the PML4 entry before the `iomap()` one.
+pub const GUARD_VA_START: VAddr = VAddr(0xfffffe80_00000000);
+pub const GUARD_VA_SIZE: usize = 512 << 30;
+
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_SIZE_BITS: usize = 12;
 pub const FRAME_SIZE: usize = 4096;
@@ -108,3 +121,64 @@ pub fn page_permissions(vaddr: VAddr) -> PagePermissions {
         }
     }
 }
+
+/// Set the write and execute permissions of the page mapped at `vaddr`, which
+/// is split out of its huge page if needed. Returns `false` if the page isn't
+/// mapped.
+///
+/// # Safety #
+///
+/// The caller must ensure no code relies on the permissions being removed.
+pub unsafe fn set_page_permissions(vaddr: VAddr,
+                                   writable: bool,
+                                   executable: bool) -> bool {
+    paging::set_page_protection(vaddr, writable, executable)
+}
+
+/// Call `f` for every page mapped in the kernel half of the address space,
+/// with its virtual address, its size in bytes, and its effective permissions.
+pub fn walk_kernel_mappings(mut f: impl FnMut(VAddr, usize, PagePermissions)) {
+    paging::walk_kernel_mappings(|vaddr, bsize, writable, executable| {
+        f(vaddr, bsize, PagePermissions {
+            accessible: true,
+            readable: true,
+            writable,
+            executable,
+        });
+    });
+}
+
+/// Call `f` for every page mapped in `range`.
+pub fn walk_mappings(range: Range<VAddr>, f: impl FnMut(Mapping)) {
+    paging::walk_mappings(range, f);
+}
+
+/// Map the page at `vaddr` onto the frame at `paddr` as writable data of the
+/// memory type `cache`. Returns `false` if the page couldn't be mapped.
+///
+/// # Safety #
+///
+/// `vaddr` must be reserved for this mapping, and `paddr` must not be RAM in
+/// use elsewhere.
+pub unsafe fn map_page(vaddr: VAddr, paddr: PAddr, cache: CacheMode) -> bool {
+    paging::map_page(vaddr, paddr, cache)
+}
+
+/// Unmap the page at `vaddr` mapped with `map_page()`.
+///
+/// # Safety #
+///
+/// The page must not be accessed anymore.
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    paging::unmap_page(vaddr)
+}
+
+/// Copy `len` bytes from `src` to `dst`, either of which may be in user space,
+/// without panicking on page faults; return the number of bytes left uncopied.
+///
+/// # Safety #
+///
+/// The kernel-space side of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    uaccess::copy_user(dst, src, len)
+}
@@ -9,9 +9,15 @@
  ******************************************************************************/
 
 pub mod cpu;
+pub mod keyboard;
 pub mod mem;
+pub mod pci;
+pub mod power;
+pub mod smp;
 pub mod sync;
 pub mod task;
 pub mod logging;
+pub mod time;
 
 pub use super::driver::vesa::VesaFramebuffer;
+pub use super::ioport;
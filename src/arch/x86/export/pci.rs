@@ -0,0 +1,87 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Access to the PCI configuration space through the legacy I/O ports
+//! mechanism: the address of a configuration register is written to
+//! `CONFIG_ADDRESS`, then the register is accessed through `CONFIG_DATA`.
+
+use crate::arch::x86::ioport::{self, IoportError, PortRange};
+use crate::driver::iommu;
+use crate::sync::Spinlock;
+use crate::{initcall, warning};
+
+const CONFIG_PORTS_BASE: u16 = 0xcf8;
+
+const CONFIG_ADDRESS: u16 = 0;
+const CONFIG_DATA: u16 = 4;
+
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// The configuration ports, claimed by `init()`; the lock makes the write of
+/// the address and the access to the data a single operation.
+static CONFIG_PORTS: Spinlock<Option<PortRange>> = Spinlock::new(None);
+
+/// Claim the configuration ports.
+pub fn init() -> Result<(), IoportError> {
+    *CONFIG_PORTS.lock() = Some(ioport::claim(CONFIG_PORTS_BASE, 8, "pci")?);
+    Ok(())
+}
+
+/// Make the PCI devices reachable, then turn DMA remapping on for them before
+/// their drivers allocate DMA buffers.
+fn start() {
+    if let Err(e) = init() {
+        warning!("pci: {e}");
+    }
+    iommu::init();
+}
+
+initcall!(core, start, "Enumerating PCI devices...");
+
+/// Read the 32-bit configuration register at `offset`, which is rounded down
+/// to a multiple of 4, of a PCI function; all ones if there is no such function
+/// or if PCI is not initialized.
+pub fn config_read32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let ports = CONFIG_PORTS.lock();
+    let Some(ports) = ports.as_ref() else {
+        return u32::MAX;
+    };
+
+    ports.port::<u32>(CONFIG_ADDRESS)
+        .write(config_address(bus, device, function, offset));
+    ports.port::<u32>(CONFIG_DATA).read()
+}
+
+/// Write the 32-bit configuration register at `offset`, which is rounded down
+/// to a multiple of 4, of a PCI function.
+pub fn config_write32(
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u8,
+    value: u32,
+) {
+    let ports = CONFIG_PORTS.lock();
+    let Some(ports) = ports.as_ref() else {
+        return;
+    };
+
+    ports.port::<u32>(CONFIG_ADDRESS)
+        .write(config_address(bus, device, function, offset));
+    ports.port::<u32>(CONFIG_DATA).write(value);
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    CONFIG_ADDRESS_ENABLE
+        | (bus as u32) << 16
+        | (device as u32 & 0x1f) << 11
+        | (function as u32 & 0x07) << 8
+        | (offset as u32 & 0xfc)
+}
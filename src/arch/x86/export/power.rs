@@ -0,0 +1,129 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Powering the machine off and rebooting it. Each operation tries the ACPI
+//! way first, as described by the FADT, then falls back to legacy or emulator
+//! specific mechanisms.
+
+use core::arch::asm;
+
+use x86::dtables::{lidt, DescriptorTablePointer};
+
+use crate::arch::cpu::perm_halt;
+use crate::arch::ioport::Port;
+use crate::arch::time::delay_us;
+use crate::arch::x86::driver::ps2;
+use crate::driver::acpi::{self, GenericAddress};
+use crate::mem::{iomap, CacheMode, PAddr};
+use crate::{info, warning};
+
+/// The `SCI_EN` bit of PM1 control registers, set when in ACPI mode.
+const PM1_SCI_EN: u16 = 1 << 0;
+/// The `SLP_EN` bit of PM1 control registers, entering the sleep state whose
+/// type is in `SLP_TYP`, bits 10 to 12.
+const PM1_SLP_EN: u16 = 1 << 13;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+
+/// The I/O port of QEMU's `isa-debug-exit` device, as used by `make run`.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// How long to wait for each mechanism to take effect before trying the next.
+const SETTLE_DELAY_US: u64 = 100_000;
+
+/// Power the machine off through ACPI's S5 state; under QEMU without ACPI,
+/// exit the emulator; halt the CPU if all else failed.
+pub fn shutdown() -> ! {
+    unsafe { x86::irq::disable() };
+    info!("Powering off...");
+
+    if let Some(info) = acpi::power_info() {
+        if let Some((slp_typa, slp_typb)) = info.s5_sleep_types {
+            unsafe {
+                enable_acpi_mode(info.pm1a_control, info.smi_command,
+                                 info.acpi_enable);
+                enter_sleep_state(info.pm1a_control, slp_typa);
+                if info.pm1b_control != 0 {
+                    enter_sleep_state(info.pm1b_control, slp_typb);
+                }
+            }
+            delay_us(SETTLE_DELAY_US);
+            warning!("ACPI power off failed");
+        }
+    }
+
+    unsafe { Port::<u32>::new(DEBUG_EXIT_PORT).write(0) };
+
+    warning!("Couldn't power off, halting");
+    perm_halt();
+}
+
+/// Reset the machine through the ACPI reset register, then the keyboard
+/// controller, and as a last resort by triple faulting.
+pub fn reboot() -> ! {
+    unsafe { x86::irq::disable() };
+    info!("Rebooting...");
+
+    if let Some((register, value)) = acpi::power_info().and_then(|i| i.reset) {
+        match register {
+            GenericAddress::Io(port) => unsafe {
+                Port::<u8>::new(port).write(value);
+            },
+            GenericAddress::Memory(paddr) => {
+                if let Ok(region) = iomap(PAddr(paddr), 1, CacheMode::Uncached) {
+                    region.write8(0, value);
+                }
+            },
+            GenericAddress::Other { .. } => (),
+        }
+        delay_us(SETTLE_DELAY_US);
+    }
+
+    ps2::pulse_reset_line();
+    delay_us(SETTLE_DELAY_US);
+
+    triple_fault();
+}
+
+/// Switch from legacy to ACPI mode if needed; this hands power management
+/// events over to the OS, and is required for `SLP_EN` to be honored.
+unsafe fn enable_acpi_mode(pm1_control: u16, smi_command: u16, enable: u8) {
+    let pm1 = Port::<u16>::new(pm1_control);
+    if pm1.read() & PM1_SCI_EN != 0 || smi_command == 0 || enable == 0 {
+        return;
+    }
+
+    Port::<u8>::new(smi_command).write(enable);
+    for _ in 0..300 {
+        if pm1.read() & PM1_SCI_EN != 0 {
+            break;
+        }
+        delay_us(10_000);
+    }
+}
+
+unsafe fn enter_sleep_state(pm1_control: u16, slp_typ: u8) {
+    let pm1 = Port::<u16>::new(pm1_control);
+    let value = pm1.read() & !(0b111 << PM1_SLP_TYP_SHIFT);
+    pm1.write(value | ((slp_typ as u16 & 0b111) << PM1_SLP_TYP_SHIFT)
+                    | PM1_SLP_EN);
+}
+
+/// Load an empty IDT and raise an exception: the CPU can't deliver it, nor the
+/// resulting double fault, and resets.
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer::<u64> {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    unsafe {
+        lidt(&empty);
+        asm!("int3", options(noreturn));
+    }
+}
@@ -0,0 +1,42 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::arch::x86::driver::apic;
+use crate::arch::x86::irq::CALL_IPI_VECTOR;
+
+/// Interrupt the CPU of index `cpu` so that it runs its pending cross-CPU
+/// calls; nothing happens if it was not brought up.
+pub fn send_call_ipi(cpu: usize) {
+    let (Some(lapic), Some(apic_id)) = (apic::local(), apic::cpu_apic_id(cpu))
+    else {
+        return;
+    };
+
+    lapic.send_ipi(apic_id, CALL_IPI_VECTOR);
+}
+
+/// Send a non-maskable interrupt to all the other CPUs, whose handler calls
+/// `panic::stop_if_panicking()`.
+pub fn stop_others() {
+    if let Some(lapic) = apic::local() {
+        lapic.send_nmi_to_others();
+    }
+}
+
+/// Send a non-maskable interrupt to the CPU of index `cpu`, whose handler
+/// calls `watchdog::on_nmi()`; nothing happens if it was not brought up.
+pub fn send_nmi(cpu: usize) {
+    let (Some(lapic), Some(apic_id)) = (apic::local(), apic::cpu_apic_id(cpu))
+    else {
+        return;
+    };
+
+    lapic.send_nmi(apic_id);
+}
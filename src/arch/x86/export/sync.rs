@@ -8,23 +8,39 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-// FIXME: implement per SMP processor
-static CRITICAL_REGION_DEPTH: AtomicU32 = AtomicU32::new(0);
+use crate::latency::{self, Section};
+use crate::task::cpu::{raw_cpu_index, MAX_CPUS};
+
+/// How many critical regions each CPU is nested in.
+static CRITICAL_REGION_DEPTH: [AtomicU32; MAX_CPUS]
+    = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// When the outermost critical region of each CPU was entered, for the latency
+/// watchdog.
+static CRITICAL_REGION_START: [AtomicU64; MAX_CPUS]
+    = [const { AtomicU64::new(0) }; MAX_CPUS];
 
 pub fn push_critical_region() {
-    let prev = CRITICAL_REGION_DEPTH.fetch_add(1, Ordering::SeqCst);
+    let prev = CRITICAL_REGION_DEPTH[raw_cpu_index()]
+        .fetch_add(1, Ordering::SeqCst);
 
     if prev == 0 {
         unsafe { x86::irq::disable() };
+        CRITICAL_REGION_START[raw_cpu_index()]
+            .store(latency::start(), Ordering::Relaxed);
     }
 }
 
 pub fn pop_critical_region() {
-    let prev = CRITICAL_REGION_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    let prev = CRITICAL_REGION_DEPTH[raw_cpu_index()]
+        .fetch_sub(1, Ordering::SeqCst);
 
     if prev == 1 {
+        let start = CRITICAL_REGION_START[raw_cpu_index()]
+            .load(Ordering::Relaxed);
         unsafe { x86::irq::enable() };
+        latency::check(Section::IrqsDisabled, start);
     }
 }
@@ -8,6 +8,32 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use crate::arch::x86::gdt::{
+    KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR,
+};
+use crate::mem::uaccess::copy_to_user;
+use crate::mem::VAddr;
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+
+/// The ELF machine type of the programs run by this architecture, EM_X86_64.
+pub const ELF_MACHINE: u16 = 62;
+
+/// The code signal handlers return to: `mov eax, 15; syscall`, i.e.
+/// `rt_sigreturn()`.
+pub const SIGRETURN_TRAMPOLINE: &[u8] = &[
+    0xb8, 0x0f, 0x00, 0x00, 0x00,
+    0x0f, 0x05,
+];
+
+/// The interrupt flag of RFLAGS.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// The area below the stack pointer that functions may use without moving it,
+/// as defined by the System V ABI.
+const RED_ZONE_BSIZE: u64 = 128;
+
+#[derive(Clone, Default)]
 pub struct TaskMachineContext {
     pub rax: u64,
     pub rbx: u64,
@@ -41,3 +67,80 @@ pub struct TaskMachineContext {
 
     pub cr3: u64,
 }
+
+impl TaskMachineContext {
+    /// The context of a task entering user space at `entry`, with its stack
+    /// pointer at `stack`. Its `cr3` is left null, the address space's own page
+    /// tables being set up separately.
+    pub fn user(entry: VAddr, stack: VAddr) -> Self {
+        let data = USER_DATA_SELECTOR.bits();
+
+        Self {
+            rip: entry.0 as u64,
+            rsp: stack.0 as u64,
+            rflags: RFLAGS_IF,
+            cs: USER_CODE_SELECTOR.bits(),
+            ss: data,
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ..Default::default()
+        }
+    }
+
+    /// The context of a kernel thread calling `entry(arg)` on the stack ending
+    /// at `stack`; `entry` must never return. The data segment selectors are
+    /// left null, as usual in 64-bit kernel mode.
+    pub fn kernel(
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+        stack: VAddr,
+    ) -> Self {
+        // As if `entry` was called: the stack is aligned on 16 bytes before
+        // the return address is pushed, a null one here.
+        let rsp = (stack.0 as u64 & !0xf) - 8;
+
+        Self {
+            rip: entry as usize as u64,
+            rsp,
+            rdi: arg as u64,
+            rflags: RFLAGS_IF,
+            cs: KERNEL_CODE_SELECTOR.bits(),
+            ..Default::default()
+        }
+    }
+
+    /// The value returned to the task by the system call it is in.
+    pub fn return_value(&self) -> usize {
+        self.rax as usize
+    }
+
+    /// Set the value returned to the task by the system call it is in.
+    pub fn set_return_value(&mut self, value: usize) {
+        self.rax = value as u64;
+    }
+
+    /// Make the task call the signal handler `handler` with `signal` as its
+    /// argument, returning to `trampoline`. The return address is pushed onto
+    /// the user stack, in `vm`, past the red zone.
+    pub fn enter_signal_handler(
+        &mut self,
+        vm: &VirtualMemory,
+        handler: VAddr,
+        signal: usize,
+        trampoline: VAddr,
+    ) -> Result<(), Errno> {
+        // The stack is aligned on 16 bytes before the call pushes the return
+        // address.
+        let rsp = (self.rsp.wrapping_sub(RED_ZONE_BSIZE) & !0xf)
+            .wrapping_sub(8);
+        copy_to_user(vm, VAddr(rsp as usize),
+                     &(trampoline.0 as u64).to_ne_bytes())?;
+
+        self.rsp = rsp;
+        self.rip = handler.0 as u64;
+        self.rdi = signal as u64;
+        Ok(())
+    }
+}
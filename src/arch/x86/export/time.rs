@@ -0,0 +1,62 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use crate::arch::x86::driver::apic;
+use crate::arch::x86::{cpuid, irq};
+
+/// Read the CPU's timestamp counter. The value is monotonic on the current CPU
+/// and increments at a constant rate on all reasonably modern processors
+/// (invariant TSC); it is however not synchronized across CPUs.
+#[inline]
+pub fn timestamp() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// The frequency in Hz of the timestamp counter, as reported by CPUID leaf
+/// 0x15; `None` if the processor does not enumerate it, in which case
+/// timestamps can only be reported as raw cycles.
+pub fn timestamp_frequency() -> Option<u64> {
+    cpuid::get().get_tsc_info()?.tsc_frequency()
+}
+
+/// Busy-wait for at least `us` microseconds. The timestamp counter is used if
+/// its frequency is known; otherwise, we fall back to writing to the POST
+/// diagnostic port 0x80, each write taking roughly a microsecond on the ISA
+/// bus. This is imprecise but requires neither interrupts nor a calibrated
+/// timer, which makes it usable from the panic handler.
+pub fn delay_us(us: u64) {
+    if let Some(freq) = timestamp_frequency() {
+        let end = timestamp() + (us as u128 * freq as u128 / 1_000_000) as u64;
+        while timestamp() < end {
+            core::hint::spin_loop();
+        }
+    } else {
+        for _ in 0..us {
+            unsafe { x86::io::outb(0x80, 0); }
+        }
+    }
+}
+
+/// Interrupt the current CPU `hz` times a second with the LAPIC timer, each
+/// interrupt calling `profile::sample()`; return `false` if there is no LAPIC.
+pub fn start_sample_timer(hz: u32) -> bool {
+    let Some(lapic) = apic::local() else {
+        return false;
+    };
+
+    lapic.start_timer(irq::SAMPLE_TIMER_VECTOR, hz);
+    true
+}
+
+pub fn stop_sample_timer() {
+    if let Some(lapic) = apic::local() {
+        lapic.stop_timer();
+    }
+}
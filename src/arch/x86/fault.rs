@@ -0,0 +1,140 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Decoding of the error codes pushed by the CPU for some exceptions, so that
+//! fault reports tell what actually happened.
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+
+/// The error code of a page fault (#PF, vector 14).
+#[derive(Debug, Copy, Clone)]
+pub struct PageFaultErrorCode(pub u64);
+
+impl PageFaultErrorCode {
+    /// The fault was caused by a page-level protection violation; otherwise,
+    /// the page was not present.
+    pub fn is_protection_violation(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// The access was performed in user mode (CPL=3).
+    pub fn is_user(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// A reserved bit was set in one of the paging structures.
+    pub fn is_reserved_bit(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// The fault was caused by an instruction fetch.
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    /// The fault was caused by a protection key violation.
+    pub fn is_protection_key(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+}
+
+/// Formats as a sentence like "supervisor-mode write to a non-present page".
+impl Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mode = if self.is_user() { "user" } else { "supervisor" };
+        let access = if self.is_instruction_fetch() {
+            "instruction fetch from"
+        } else if self.is_write() {
+            "write to"
+        } else {
+            "read from"
+        };
+
+        write!(f, "{mode}-mode {access} a ")?;
+
+        if self.is_reserved_bit() {
+            write!(f, "page with a reserved bit set in its paging entries")
+        } else if self.is_protection_key() {
+            write!(f, "page protected by its protection key")
+        } else if self.is_protection_violation() {
+            write!(f, "present page, denied by its protection")
+        } else {
+            write!(f, "non-present page")
+        }
+    }
+}
+
+/// The selector error code pushed by #TS, #NP, #SS and #GP when the fault is
+/// related to a segment selector or an IDT gate.
+#[derive(Debug, Copy, Clone)]
+pub struct SelectorErrorCode(pub u64);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorErrorCode {
+    /// Return `None` for a null error code, meaning that the fault isn't
+    /// related to a particular selector.
+    pub fn new(errc: u64) -> Option<Self> {
+        (errc != 0).then_some(Self(errc))
+    }
+
+    /// The fault was caused by an event external to the program, such as an
+    /// hardware interrupt.
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b01 | 0b11 => DescriptorTable::Idt,
+            _ => DescriptorTable::Ldt,
+        }
+    }
+
+    pub fn index(&self) -> u16 {
+        ((self.0 >> 3) & 0x1fff) as u16
+    }
+}
+
+/// Formats as "GDT selector 0x28 (index 5)" or "IDT vector 13".
+impl Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.table() {
+            DescriptorTable::Idt => write!(f, "IDT vector {}", self.index())?,
+            DescriptorTable::Gdt => write!(f, "GDT selector {:#x} (index {})",
+                                           self.index() << 3, self.index())?,
+            DescriptorTable::Ldt => write!(f, "LDT selector {:#x} (index {})",
+                                           self.index() << 3 | 0b100,
+                                           self.index())?,
+        }
+
+        if self.is_external() {
+            write!(f, ", external event")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the exception `vector` pushes a selector error code.
+pub fn has_selector_error_code(vector: usize) -> bool {
+    matches!(vector, 10 | 11 | 12 | 13)
+}
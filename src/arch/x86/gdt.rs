@@ -14,7 +14,7 @@ use x86::segmentation::{Descriptor as Descriptor32,
                         load_ss, load_ds, load_es, load_fs, load_gs,
                         GateDescriptorBuilder};
 use x86::dtables::{DescriptorTablePointer, lgdt};
-use x86::Ring::Ring0;
+use x86::Ring::{Ring0, Ring3};
 use x86::current::task::TaskStateSegment;
 use x86::task::load_tr;
 
@@ -22,6 +22,7 @@ use x86::task::load_tr;
 use x86::bits64::segmentation::Descriptor64;
 
 use crate::mem::{PAddr, VAddr};
+use crate::mem::protect;
 
 type DescriptorN = Descriptor64;
 
@@ -39,7 +40,11 @@ struct Gdt {
     pub tss: DescriptorN,
 }
 
-static mut BSP_GDT: Gdt = Gdt {
+/// The GDT sits on a page of its own so that it can be made read-only.
+#[repr(C, align(4096))]
+struct PageAlignedGdt(Gdt);
+
+static mut BSP_GDT: PageAlignedGdt = PageAlignedGdt(Gdt {
     null: Descriptor32::NULL,
     kernel_cs: Descriptor32::NULL,
     kernel_ds: Descriptor32::NULL,
@@ -47,54 +52,78 @@ static mut BSP_GDT: Gdt = Gdt {
     user_cs64: Descriptor32::NULL,
     user_ds: Descriptor32::NULL,
     tss: DescriptorN::NULL,
-};
+});
 
 pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, Ring0);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, Ring3);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(5, Ring3);
 
 static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
 
+/// The Interrupt Stack Table slot used by the double-fault handler; IST indexes
+/// start at 1, zero meaning the current stack is used.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+const EMERGENCY_STACK_SIZE: usize = 4 * 4096;
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+/// A double fault is mostly caused by a kernel stack overflow: the CPU can't
+/// push the exception frame of a page fault onto the guard page. The handler
+/// thus needs its own stack to be able to report it rather than triple
+/// faulting.
+static mut DOUBLE_FAULT_STACK: EmergencyStack
+    = EmergencyStack([0; EMERGENCY_STACK_SIZE]);
+
 pub unsafe fn setup_table() {
     use x86::segmentation::CodeSegmentType::*;
     use x86::segmentation::DataSegmentType::*;
     use x86::Ring::*;
 
-    let mut cs = DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteRead)
+    // The segments are created with their accessed flag set, otherwise the CPU
+    // would write it on the first load, which would fault once the GDT is
+    // read-only.
+    let mut cs = DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteReadAccessed)
         .present()
         .dpl(Ring0)
         .limit_granularity_4kb();
     cs = cs.l();
-    BSP_GDT.kernel_cs = cs.finish();
+    BSP_GDT.0.kernel_cs = cs.finish();
 
-    BSP_GDT.kernel_ds =
-        DescriptorBuilder::data_descriptor(0, 0xfffff, ReadWrite)
+    BSP_GDT.0.kernel_ds =
+        DescriptorBuilder::data_descriptor(0, 0xfffff, ReadWriteAccessed)
             .present()
             .dpl(Ring0)
             .limit_granularity_4kb()
             .db()
             .finish();
-    BSP_GDT.user_cs32 =
-        DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteRead)
+    BSP_GDT.0.user_cs32 =
+        DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteReadAccessed)
             .present()
             .dpl(Ring3)
             .limit_granularity_4kb()
             .db()
             .finish();
-    BSP_GDT.user_cs64 =
-        DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteRead)
+    BSP_GDT.0.user_cs64 =
+        DescriptorBuilder::code_descriptor(0, 0xfffff, ExecuteReadAccessed)
             .present()
             .dpl(Ring3)
             .limit_granularity_4kb()
             .l()
             .finish();
-    BSP_GDT.user_ds =
-        DescriptorBuilder::data_descriptor(0, 0xfffff, ReadWrite)
+    BSP_GDT.0.user_ds =
+        DescriptorBuilder::data_descriptor(0, 0xfffff, ReadWriteAccessed)
             .present()
             .dpl(Ring3)
             .limit_granularity_4kb()
             .db()
             .finish();
 
-    BSP_GDT.tss =
+    let df_stack_top = DOUBLE_FAULT_STACK.0.as_ptr_range().end as u64;
+    BSP_TSS.set_ist(DOUBLE_FAULT_IST as usize - 1, df_stack_top);
+
+    BSP_GDT.0.tss =
         <DescriptorBuilder as GateDescriptorBuilder<UsizeT>>::tss_descriptor(
             PAddr::from_lowmem_vaddr(VAddr(&BSP_TSS as *const _ as _)).unwrap().0 as _,
             core::mem::size_of_val(&BSP_TSS) as _,
@@ -102,7 +131,7 @@ pub unsafe fn setup_table() {
         ).present()
         .finish();
 
-    let ptr = DescriptorTablePointer::new(&BSP_GDT);
+    let ptr = DescriptorTablePointer::new(&BSP_GDT.0);
     lgdt(&ptr);
 }
 
@@ -117,3 +146,10 @@ pub unsafe fn load_kernel_selectors() {
     load_gs(SegmentSelector::new(2, Ring0));
     load_tr(SegmentSelector::new(6, Ring0));
 }
+
+/// Make the GDT read-only. Must be called after `load_kernel_selectors()`, as
+/// loading the task register sets the busy flag of the TSS descriptor.
+pub unsafe fn protect_table() {
+    protect::set_read_only(VAddr(&BSP_GDT as *const _ as usize),
+                           core::mem::size_of::<Gdt>());
+}
@@ -0,0 +1,157 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Reporting of hardware errors signaled through the non-maskable interrupt
+//! (NMI, vector 2) and the machine-check exception (#MC, vector 18).
+
+use x86::controlregs::{cr4, cr4_write, Cr4};
+use x86::io::inb;
+use x86::msr::{rdmsr, wrmsr, IA32_MCG_CAP, IA32_MCG_STATUS};
+
+use crate::arch::cpu::MachineState;
+use crate::arch::x86::cpuid;
+use crate::logging::{log_lockless, Severity};
+use crate::panic::panic_at_state;
+use crate::{info, warning};
+
+/// The System Control Port B tells about the source of legacy NMIs.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+const PORT_B_IOCHK: u8 = 1 << 6;
+const PORT_B_SERR: u8 = 1 << 7;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+/// Log from the NMI and machine-check handlers, which may have interrupted the
+/// holder of the logging locks.
+macro_rules! hw_log {
+    ($severity:ident, $($arg:tt)*) => {
+        log_lockless(Severity::$severity, format_args!($($arg)*))
+    };
+}
+
+const fn mci_status(bank: u32) -> u32 { 0x401 + 4 * bank }
+const fn mci_addr(bank: u32) -> u32 { 0x402 + 4 * bank }
+const fn mci_misc(bank: u32) -> u32 { 0x403 + 4 * bank }
+
+fn has_machine_check() -> bool {
+    cpuid::get().get_feature_info()
+        .map(|info| info.has_mce() && info.has_mca())
+        .unwrap_or(false)
+}
+
+fn nr_banks() -> u32 {
+    (unsafe { rdmsr(IA32_MCG_CAP) } & 0xff) as u32
+}
+
+/// Enable machine-check exceptions. Errors logged in the banks before we got
+/// there, e.g. those that caused a reset, are reported and cleared.
+pub unsafe fn init() {
+    if !has_machine_check() {
+        info!("Machine-check architecture not supported");
+        return;
+    }
+
+    for bank in 0..nr_banks() {
+        let status = rdmsr(mci_status(bank));
+        if status & MCI_STATUS_VAL != 0 {
+            warning!("machine-check bank {bank} holds an error from a \
+                      previous boot: {status:#018x}");
+            wrmsr(mci_status(bank), 0);
+        }
+    }
+
+    cr4_write(cr4() | Cr4::CR4_ENABLE_MACHINE_CHECK);
+}
+
+pub fn handle_nmi(machine_state: &MachineState) {
+    let port_b = unsafe { inb(SYSTEM_CONTROL_PORT_B) };
+
+    if port_b & PORT_B_SERR != 0 {
+        panic_at_state(
+            format_args!("NMI: memory parity error or PCI system error \
+                          (port B = {port_b:#04x})"),
+            Some(machine_state),
+            0,
+        );
+    }
+
+    if port_b & PORT_B_IOCHK != 0 {
+        panic_at_state(
+            format_args!("NMI: I/O channel check error \
+                          (port B = {port_b:#04x})"),
+            Some(machine_state),
+            0,
+        );
+    }
+
+    // Watchdogs and debuggers may legitimately send NMIs; there is no reason
+    // to bring the whole system down for one we don't know about.
+    hw_log!(Warning, "NMI received for an unknown reason \
+                      (port B = {port_b:#04x}) at rip={:#x}",
+            machine_state.rip);
+}
+
+pub fn handle_machine_check(machine_state: &MachineState) -> ! {
+    let mcg_status = unsafe { rdmsr(IA32_MCG_STATUS) };
+    let mut nr_errors = 0;
+    let mut uncorrected = false;
+
+    hw_log!(Alert, "Machine check: MCG_STATUS={mcg_status:#x}{}{}",
+            if mcg_status & MCG_STATUS_RIPV != 0 { " RIPV" } else { "" },
+            if mcg_status & MCG_STATUS_EIPV != 0 { " EIPV" } else { "" });
+
+    for bank in 0..nr_banks() {
+        let status = unsafe { rdmsr(mci_status(bank)) };
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+
+        nr_errors += 1;
+        uncorrected |= status & MCI_STATUS_UC != 0;
+
+        hw_log!(Alert, "  bank {bank}: status={status:#018x}{}{}{}{} \
+                        MCA code={:#06x} model code={:#06x}",
+                if status & MCI_STATUS_OVER != 0 { " overflow" } else { "" },
+                if status & MCI_STATUS_UC != 0 { " uncorrected" } else { "" },
+                if status & MCI_STATUS_EN != 0 { " enabled" } else { "" },
+                if status & MCI_STATUS_PCC != 0 {
+                    " context-corrupt"
+                } else {
+                    ""
+                },
+                status & 0xffff,
+                (status >> 16) & 0xffff);
+
+        if status & MCI_STATUS_ADDRV != 0 {
+            hw_log!(Alert, "    address={:#x}",
+                    unsafe { rdmsr(mci_addr(bank)) });
+        }
+        if status & MCI_STATUS_MISCV != 0 {
+            hw_log!(Alert, "    misc={:#x}",
+                    unsafe { rdmsr(mci_misc(bank)) });
+        }
+    }
+
+    panic_at_state(
+        format_args!("Machine check: {nr_errors} error(s) logged{}",
+                     if uncorrected { ", uncorrected" } else { "" }),
+        Some(machine_state),
+        0,
+    );
+}
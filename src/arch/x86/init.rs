@@ -9,26 +9,55 @@
  ******************************************************************************/
 
 use alloc::boxed::Box;
-use core::mem;
+use arrayvec::ArrayString;
+use multiboot2::{BootInformation, FramebufferField, FramebufferTag,
+                FramebufferType};
 use crate::arch::x86::driver::serial::{SerialDevice, COM1_IOPORT, ParityMode,
                                        StopBits};
-use crate::arch::x86::{gdt, irq};
-use crate::{debug, info, main, notice};
-use crate::mem::{PAddr, PHYS_MEM_SIZE, LOWMEM_VA_END};
+use crate::arch::x86::{cpuid, gdt, hwerror, irq, pat, security};
+use crate::{buildinfo, debug, info, initcall, main, notice, stack_protector,
+            task, warning};
+use crate::mem::{iomap, CacheMode, PAddr, PHYS_MEM_SIZE, LOWMEM_VA_END};
+use crate::mem::{numa, protect, resource};
+use crate::mem::kalloc::{quarantine, tracker};
 use crate::arch::sync::{push_critical_region, pop_critical_region};
 use crate::arch::mem::LOWMEM_VA_START;
 use crate::arch;
-use crate::arch::x86::driver::ps2;
+use crate::arch::x86::driver::apic;
 
 use crate::screen::R;
 use crate::arch::x86::mem::{lowmem_va_size, physical_memory_size};
+#[cfg(feature = "fb-terminal")]
 use crate::arch::x86::driver::vesa::VesaFramebuffer;
+use crate::arch::x86::driver::vga::{self, Vga};
 use crate::arch::x86::export::logging::LOGGER_SERIAL;
-use crate::driver::keyboard;
-use crate::logging::{DEFAULT_LOGGER, reset_logger};
+use crate::driver::{acpi, iommu};
+use crate::driver::acpi::RootTable;
+use crate::driver::screen::{ChannelField, FramebufferMode, PixelFormat};
+use crate::boot::{self, BootFramebuffer, BootInfo, BootModule, BootSymbols,
+                  PaletteColor};
+use crate::boot::cmdline::{self, BootOption, CmdLine, Console,
+                          DEFAULT_SERIAL_BAUD};
+use crate::logging::{self, DEFAULT_LOGGER, reset_logger};
+use crate::panic::{PanicPolicy, set_panic_policy};
+use crate::fs;
+use crate::initcall::InitLevel;
+use crate::time;
+use crate::trace;
+use crate::crashdump;
+use crate::latency;
+use crate::watchdog;
+use crate::lockdep;
 use crate::mem::load::{kernel_image, kernel_rodata_segment, kernel_text_segment};
-use crate::ui::kterm::{KERNEL_TERMINAL, TerminalLogger};
+use crate::ui::kterm::{KERNEL_TEXT_TERMINAL, PANIC_TEXT_SCREEN,
+                       TerminalLogger};
+#[cfg(feature = "fb-terminal")]
+use crate::ui::kterm::{self, KERNEL_TERMINAL, PANIC_FRAMEBUFFER};
+#[cfg(feature = "fb-terminal")]
+use crate::ui::splash;
+#[cfg(feature = "fb-terminal")]
 use crate::ui::term::Terminal;
+use crate::ui::textterm::TextTerminal;
 
 /// Welcome in Rust land! This is the very first Rust code to run on the CPU
 /// once the previous `_start` routine in assembly ran. We did the bare
@@ -57,7 +86,8 @@ use crate::ui::term::Terminal;
 ///     * creating and configuring the physical frames allocator;
 ///     * (i386) constructing the high-memory allocator.
 ///
-/// Interrupts can now be enabled.
+/// Interrupts can now be enabled. The drivers and subsystems are then started
+/// by their initcalls, level by level, see `initcall`.
 ///
 /// Finally, we call the kernel's `main` function to start the architecture-
 /// agnostic code.
@@ -66,16 +96,31 @@ pub unsafe extern "C" fn arch_init(multiboot_info_pa: PAddr) -> ! {
     // We are not yet ready to handle interruptions: we don't even have an IDT!
     push_critical_region();
 
-    LOGGER_SERIAL = Some(unsafe { SerialDevice::new(
-        COM1_IOPORT, 115200, ParityMode::None, 8, StopBits::One
-    ).expect("Couldn't initialize serial device") });
-    *DEFAULT_LOGGER.lock() = LOGGER_SERIAL.as_mut().unwrap();
-
     let mbi = multiboot2::load(
         multiboot_info_pa.into_vaddr().0
     ).unwrap();
 
-    notice!("Nucloid v{}", env!("CARGO_PKG_VERSION"));
+    boot::init(collect_boot_info(&mbi));
+    let cmdline = cmdline::get();
+
+    let serial = |baud| unsafe {
+        SerialDevice::new(COM1_IOPORT, baud, ParityMode::None, 8, StopBits::One)
+    };
+    LOGGER_SERIAL = Some(serial(cmdline.serial_baud())
+        .or_else(|_| serial(DEFAULT_SERIAL_BAUD))
+        .expect("Couldn't initialize serial device"));
+    *DEFAULT_LOGGER.lock() = LOGGER_SERIAL.as_mut().unwrap();
+
+    notice!("Nucloid v{} (build {})", env!("CARGO_PKG_VERSION"),
+            buildinfo::BUILD_ID);
+    if !cmdline.as_str().is_empty() {
+        info!("Command line: {}", cmdline.as_str());
+    }
+    apply_boot_options(cmdline);
+
+    cpuid::init();
+    arch::cpu::init_idle();
+    stack_protector::init();
 
     let mem_map = mbi.memory_map_tag()
         .expect("No memory map provided by the bootloader");
@@ -91,46 +136,369 @@ pub unsafe extern "C" fn arch_init(multiboot_info_pa: PAddr) -> ! {
     debug!("Text segment:   {:#?}", kernel_text_segment());
     debug!("Rodata segment: {:#?}", kernel_rodata_segment());
 
-    info!("Setting up GDT...");
-    gdt::setup_table();
-    gdt::load_kernel_selectors();
+    security::init();
+    if cmdline.nopat() {
+        info!("PAT left as set by the firmware");
+    } else {
+        pat::init();
+    }
+
+    {
+        time::scope!("gdt");
+        info!("Setting up GDT...");
+        gdt::setup_table();
+        gdt::load_kernel_selectors();
+    }
 
-    info!("Setting up interrupts...");
-    irq::setup();
+    {
+        time::scope!("interrupts");
+        info!("Setting up interrupts...");
+        irq::setup();
+        hwerror::init();
+    }
 
-    let fb_info = mbi.framebuffer_tag().expect("No framebuffer");
-    let fb_addr = PAddr(fb_info.address);
-    let fb_width = fb_info.width;
-    let fb_height = fb_info.height;
-    let fb_pitch = fb_info.pitch;
-    let fb_bpp = fb_info.bpp;
+    {
+        time::scope!("memory");
+        info!("Setting up memory management...");
+        arch::x86::mem::boot_setup(&mem_map, boot::info().loaded_end());
+        initcall::run(InitLevel::Early);
+    }
 
-    info!("Setting up memory management...");
-    arch::x86::mem::boot_setup(&mem_map);
-    mem::forget(mbi); // FIXME: Multiboot info is invalidated
+    {
+        time::scope!("apic");
+        apic::init(irq::SPURIOUS_VECTOR);
+    }
+    // The paging setup reclaimed the MBI's memory: `mbi` must not be used from
+    // here on, all that is needed was copied into the boot information.
+
+    {
+        time::scope!("acpi");
+        acpi::init(boot::info().acpi_root);
+        numa::init(apic::cpu_apic_id);
+    }
 
     // We can now activate and handle interruptions safely.
     pop_critical_region();
 
-    let fb_bsize = fb_pitch as usize * fb_height as usize;
-    let fb_vaddr = fb_addr.into_vaddr();
+    {
+        time::scope!("screen");
+        match &boot::info().framebuffer {
+            #[cfg(feature = "fb-terminal")]
+            Some(BootFramebuffer::Direct { addr, mode }) => {
+                init_framebuffer(*addr, *mode);
+            },
+            #[cfg(not(feature = "fb-terminal"))]
+            Some(BootFramebuffer::Direct { .. }) => {
+                info!("Built without the graphical terminal, the framebuffer \
+                       is left unused");
+            },
+            Some(BootFramebuffer::Text { addr, columns, rows }) => {
+                init_text_screen(*addr, *columns, *rows);
+            },
+            Some(BootFramebuffer::Indexed { bpp, .. }) => {
+                warning!("{bpp} bpp indexed color framebuffer unsupported");
+                init_text_screen(PAddr(vga::TEXT_MEMORY), vga::TEXT_COLUMNS,
+                                 vga::TEXT_ROWS);
+            },
+            None => {
+                init_text_screen(PAddr(vga::TEXT_MEMORY), vga::TEXT_COLUMNS,
+                                 vga::TEXT_ROWS);
+            },
+        }
+        if cmdline.console() == Console::Terminal {
+            let term_logger = Box::leak(Box::new(
+                TerminalLogger::new(reset_logger())
+            ));
+            *DEFAULT_LOGGER.lock() = term_logger;
+        }
+    }
 
-    let fb = VesaFramebuffer::new(
-        fb_vaddr.0 as _,
-        fb_width as usize,
-        fb_height as usize,
-        fb_pitch as usize,
-        fb_bpp
-    );
+    {
+        time::scope!("drivers");
+        initcall::run(InitLevel::Core);
+        initcall::run(InitLevel::Device);
+    }
 
-    debug!("fb ({fb_width}×{fb_height}) paddr = {:?}, vaddr = {:?}, size = {}",
-           fb_addr, fb_vaddr, fb_bsize);
-    *KERNEL_TERMINAL.lock() = Some(Terminal::create(fb));
-    let term_logger = Box::leak(Box::new(TerminalLogger::new(reset_logger())));
-    *DEFAULT_LOGGER.lock() = term_logger;
+    #[cfg(feature = "fb-terminal")]
+    {
+        time::scope!("terminal");
+        splash::step("Starting the terminal...");
+        if let Some(fb) = splash::finish() {
+            *KERNEL_TERMINAL.lock() = Some(Terminal::create(fb));
+            kterm::replay_log();
+        }
+    }
+
+    {
+        time::scope!("late");
+        initcall::run(InitLevel::Late);
+    }
+
+    gdt::protect_table();
+    irq::protect_idt();
+    protect::warn_wx_mappings();
+
+    time::print_timings();
 
-    keyboard::init();
-    ps2::init();
+    #[cfg(feature = "ktest")]
+    crate::ktest::run();
 
     main();
 }
+
+/// Register the x86-specific files of the procfs.
+fn register_proc_files() {
+    let files: [(&str, fs::procfs::Generator); 2] = [
+        ("cpuinfo", cpuid::write_info),
+        ("interrupts", irq::write_interrupts),
+    ];
+    for (name, generate) in files {
+        if let Err(e) = fs::procfs::register(name, generate) {
+            warning!("procfs: {name}: {e}");
+        }
+    }
+}
+
+initcall!(late, register_proc_files, "Creating the kernel files...");
+
+/// Deep-copy what the kernel needs from the Multiboot information, before its
+/// memory is reclaimed.
+fn collect_boot_info(mbi: &BootInformation) -> BootInfo {
+    let mut info = BootInfo::new();
+
+    if let Some(line) = mbi.command_line_tag()
+        .and_then(|tag| tag.command_line().ok()) {
+        info.cmdline = CmdLine::parse(line);
+    }
+
+    for tag in mbi.module_tags().take(boot::MAX_MODULES) {
+        let mut cmdline = ArrayString::new();
+        for c in tag.cmdline().unwrap_or("").chars() {
+            if cmdline.try_push(c).is_err() {
+                break;
+            }
+        }
+        info.modules.push(BootModule {
+            start: PAddr(tag.start_address() as u64),
+            end: PAddr(tag.end_address() as u64),
+            cmdline,
+        });
+    }
+
+    info.acpi_root = mbi.rsdp_v2_tag()
+        .map(|tag| RootTable::Xsdt(tag.xsdt_address() as u64))
+        .or_else(|| mbi.rsdp_v1_tag()
+            .map(|tag| RootTable::Rsdt(tag.rsdt_address() as u64)));
+
+    info.framebuffer = mbi.framebuffer_tag().map(|tag| boot_framebuffer(&tag));
+    info.symbols = boot_symbols(mbi);
+
+    info
+}
+
+/// Find the kernel's `.symtab` and `.strtab` sections, which the bootloader
+/// loads at the physical addresses it gives in the ELF sections tag.
+fn boot_symbols(mbi: &BootInformation) -> Option<BootSymbols> {
+    let mut symtab = None;
+    let mut strtab = None;
+
+    for section in mbi.elf_sections_tag()?.sections() {
+        let range = PAddr(section.start_address())
+            ..PAddr(section.end_address());
+        match section.name() {
+            Ok(".symtab") => symtab = Some(range),
+            Ok(".strtab") => strtab = Some(range),
+            _ => (),
+        }
+    }
+
+    Some(BootSymbols { symtab: symtab?, strtab: strtab? })
+}
+
+fn boot_framebuffer(tag: &FramebufferTag) -> BootFramebuffer {
+    let addr = PAddr(tag.address);
+
+    match &tag.buffer_type {
+        FramebufferType::Text => BootFramebuffer::Text {
+            addr,
+            columns: tag.width as u8,
+            rows: tag.height as u8,
+        },
+        FramebufferType::Indexed { palette } => BootFramebuffer::Indexed {
+            addr,
+            width: tag.width as usize,
+            height: tag.height as usize,
+            pitch: tag.pitch as usize,
+            bpp: tag.bpp,
+            palette: palette.iter()
+                .take(256)
+                .map(|color| PaletteColor {
+                    red: color.red,
+                    green: color.green,
+                    blue: color.blue,
+                })
+                .collect(),
+        },
+        FramebufferType::RGB { .. } => BootFramebuffer::Direct {
+            addr,
+            mode: framebuffer_mode(tag)
+                .expect("The framebuffer is not in a direct color mode"),
+        },
+    }
+}
+
+/// The number of boot steps done before the framebuffer could be mapped, and
+/// reported by the splash screen: GDT, interrupts and memory.
+#[cfg(feature = "fb-terminal")]
+const SPLASH_STEPS_DONE: usize = 3;
+
+/// Map the framebuffer at `fb_addr`, and show the boot splash onto it until
+/// the kernel terminal is created.
+///
+/// # Safety #
+///
+/// Must be called once, during the early boot process.
+#[cfg(feature = "fb-terminal")]
+unsafe fn init_framebuffer(fb_addr: PAddr, fb_mode: FramebufferMode) {
+    let fb_bsize = fb_mode.pitch * fb_mode.height;
+    if let Err(e) = resource::request_region(fb_addr, fb_bsize as u64,
+                                             "framebuffer") {
+        warning!("framebuffer: {e}");
+    }
+    let fb_vaddr = iomap(fb_addr, fb_bsize, CacheMode::WriteCombining)
+        .expect("Couldn't map the framebuffer")
+        .leak();
+
+    let fb = VesaFramebuffer::new(fb_vaddr.as_mut_ptr(), fb_mode);
+    PANIC_FRAMEBUFFER = Some(Box::new(
+        VesaFramebuffer::new(fb_vaddr.as_mut_ptr(), fb_mode),
+    ));
+
+    debug!("fb ({}×{}, {} bpp) paddr = {:?}, vaddr = {:?}, size = {}",
+           fb_mode.width, fb_mode.height, fb_mode.format.bpp,
+           fb_addr, fb_vaddr, fb_bsize);
+    // The drivers' initcalls, then the terminal.
+    let nr_steps = SPLASH_STEPS_DONE + initcall::count(InitLevel::Core)
+        + initcall::count(InitLevel::Device) + 1;
+    splash::start(Box::new(fb), nr_steps, SPLASH_STEPS_DONE);
+}
+
+/// Map the VGA text memory at `addr`, and create the kernel terminal onto it;
+/// this is the fallback when the bootloader provided no linear framebuffer.
+///
+/// # Safety #
+///
+/// Must be called once, during the early boot process.
+unsafe fn init_text_screen(addr: PAddr, columns: u8, rows: u8) {
+    let bsize = columns as usize * rows as usize * 2;
+    if let Err(e) = resource::request_region(addr, bsize as u64, "vga") {
+        warning!("vga: {e}");
+    }
+    let vaddr = iomap(addr, bsize, CacheMode::Uncached)
+        .expect("Couldn't map the VGA text memory")
+        .leak();
+
+    let screen = || Box::new(Vga::new(vaddr.as_mut_ptr(), bsize, columns, rows));
+    PANIC_TEXT_SCREEN = Some(screen());
+    *KERNEL_TEXT_TERMINAL.lock() = Some(TextTerminal::new(screen()));
+    debug!("no framebuffer, using the {columns}×{rows} VGA text mode");
+}
+
+/// The video mode of the framebuffer described by the bootloader, if it is
+/// in a direct color mode that `VesaFramebuffer` can drive.
+fn framebuffer_mode(tag: &FramebufferTag) -> Option<FramebufferMode> {
+    let FramebufferType::RGB { red, green, blue } = &tag.buffer_type else {
+        return None;
+    };
+    let field = |field: &FramebufferField| ChannelField {
+        shift: field.position,
+        size: field.size,
+    };
+
+    matches!(tag.bpp, 16 | 24 | 32).then_some(FramebufferMode {
+        width: tag.width as usize,
+        height: tag.height as usize,
+        pitch: tag.pitch as usize,
+        format: PixelFormat {
+            bpp: tag.bpp,
+            red: field(red),
+            green: field(green),
+            blue: field(blue),
+        },
+    })
+}
+
+/// Apply the options of the kernel command line that configure subsystems
+/// early; unknown options are ignored.
+///
+/// # Safety #
+///
+/// Must be called during the early boot process, with only one CPU running.
+unsafe fn apply_boot_options(cmdline: &CmdLine) {
+    if let Some(severity) = cmdline.log_level() {
+        logging::set_min_severity(severity);
+    }
+
+    for option in cmdline.options() {
+        let BootOption::Value(key, value) = option else {
+            continue;
+        };
+
+        match (key, value) {
+            ("panic", value) => {
+                match value.parse::<PanicPolicy>() {
+                    Ok(policy) => set_panic_policy(policy),
+                    Err(_) => warning!("invalid panic policy '{value}'"),
+                }
+            },
+            ("crashdump", "serial") => crashdump::set_enabled(true),
+            ("crashdump", "off") => crashdump::set_enabled(false),
+            ("crashdump", value) => {
+                warning!("invalid crash dump target '{value}'");
+            },
+            ("trace", "on") => trace::set_enabled(true),
+            ("trace", "off") => trace::set_enabled(false),
+            ("trace", value) => {
+                warning!("invalid trace mode '{value}'");
+            },
+            ("latency", "off") => latency::set_threshold_us(None),
+            ("latency", value) => match value.parse() {
+                Ok(us) => latency::set_threshold_us(Some(us)),
+                Err(_) => warning!("invalid latency threshold '{value}'"),
+            },
+            ("watchdog", "off") => watchdog::set_timeout_s(None),
+            ("watchdog", value) => match value.parse() {
+                Ok(secs) => watchdog::set_timeout_s(Some(secs)),
+                Err(_) => warning!("invalid watchdog timeout '{value}'"),
+            },
+            ("lockdep", "on") => lockdep::set_enabled(true),
+            ("lockdep", "off") => lockdep::set_enabled(false),
+            ("lockdep", value) => {
+                warning!("invalid lockdep mode '{value}'");
+            },
+            ("kmemleak", "on") => tracker::set_enabled(true),
+            ("kmemleak", "off") => tracker::set_enabled(false),
+            ("kmemleak", value) => {
+                warning!("invalid kmemleak mode '{value}'");
+            },
+            ("quarantine", "off") => quarantine::set_limit_bsize(0),
+            ("quarantine", value) => match value.parse::<usize>() {
+                Ok(kib) => {
+                    quarantine::set_limit_bsize(kib.saturating_mul(1024));
+                },
+                Err(_) => warning!("invalid quarantine size '{value}'"),
+            },
+            ("iommu", "on") => iommu::set_enabled(true),
+            ("iommu", "off") => iommu::set_enabled(false),
+            ("iommu", value) => {
+                warning!("invalid IOMMU mode '{value}'");
+            },
+            ("init", path) => {
+                if !task::init::set_init_path(path) {
+                    warning!("init path '{path}' is too long");
+                }
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Port-mapped I/O. Drivers claim the ranges of I/O ports they drive before
+//! using them, which detects two drivers fighting over the same device; the
+//! claims are listed by the `lsports` shell command.
+
+use core::marker::PhantomData;
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
+use x86::io::{inb, inl, inw, outb, outl, outw};
+
+use crate::arch::x86::Ioport;
+use crate::sync::Spinlock;
+
+const MAX_CLAIMS: usize = 32;
+
+static CLAIMS: Spinlock<ArrayVec<PortClaim, MAX_CLAIMS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+#[derive(Debug, Copy, Clone)]
+pub struct PortClaim {
+    pub base: Ioport,
+    pub len: u16,
+    pub owner: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum IoportError {
+    #[error("I/O ports {base:#x}..{end:#x} are already claimed by {owner}")]
+    Conflict { base: Ioport, end: u32, owner: &'static str },
+
+    #[error("too many I/O port claims")]
+    TooManyClaims,
+}
+
+/// A value that can be transferred with a single port I/O instruction.
+pub trait PortValue: Copy {
+    unsafe fn read_port(port: Ioport) -> Self;
+    unsafe fn write_port(port: Ioport, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_port(port: Ioport) -> Self { inb(port) }
+    unsafe fn write_port(port: Ioport, value: Self) { outb(port, value) }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_port(port: Ioport) -> Self { inw(port) }
+    unsafe fn write_port(port: Ioport, value: Self) { outw(port, value) }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_port(port: Ioport) -> Self { inl(port) }
+    unsafe fn write_port(port: Ioport, value: Self) { outl(port, value) }
+}
+
+/// A single I/O port accessed with values of type `T`.
+#[derive(Copy, Clone)]
+pub struct Port<T: PortValue> {
+    port: Ioport,
+    _value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    /// # Safety #
+    ///
+    /// Accessing an I/O port can have any side-effect on the system; the
+    /// caller must own the port, normally through a `PortRange`.
+    pub const unsafe fn new(port: Ioport) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { T::read_port(self.port) }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { T::write_port(self.port, value) }
+    }
+}
+
+/// A claimed range of consecutive I/O ports, released when dropped.
+pub struct PortRange {
+    base: Ioport,
+    len: u16,
+}
+
+impl PortRange {
+    #[inline]
+    pub fn base(&self) -> Ioport {
+        self.base
+    }
+
+    /// Return the port at `offset` within the range.
+    ///
+    /// # Panics #
+    ///
+    /// If `offset` is out of the range.
+    #[inline]
+    pub fn port<T: PortValue>(&self, offset: u16) -> Port<T> {
+        assert!(offset < self.len, "I/O port offset {offset} out of range");
+        unsafe { Port::new(self.base + offset) }
+    }
+}
+
+impl Drop for PortRange {
+    fn drop(&mut self) {
+        CLAIMS.lock().retain(|claim| claim.base != self.base);
+    }
+}
+
+/// Claim the `len` I/O ports starting at `base` on behalf of `owner`.
+pub fn claim(
+    base: Ioport,
+    len: u16,
+    owner: &'static str,
+) -> Result<PortRange, IoportError> {
+    let end = base as u32 + len as u32;
+    let mut claims = CLAIMS.lock();
+
+    if let Some(other) = claims.iter().find(|claim| {
+        (base as u32) < claim.base as u32 + claim.len as u32
+            && (claim.base as u32) < end
+    }) {
+        return Err(IoportError::Conflict {
+            base: other.base,
+            end: other.base as u32 + other.len as u32,
+            owner: other.owner,
+        });
+    }
+
+    claims.try_push(PortClaim { base, len, owner })
+        .map_err(|_| IoportError::TooManyClaims)?;
+
+    Ok(PortRange { base, len })
+}
+
+/// Return the current claims, sorted by port.
+pub fn claims() -> ArrayVec<PortClaim, MAX_CLAIMS> {
+    let mut claims = CLAIMS.lock().clone();
+    claims.sort_unstable_by_key(|claim| claim.base);
+    claims
+}
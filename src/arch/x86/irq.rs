@@ -8,6 +8,9 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86::segmentation::{DescriptorBuilder, GateDescriptorBuilder,
                         BuildDescriptor};
 use x86::dtables::{lidt, DescriptorTablePointer};
@@ -18,10 +21,23 @@ use crate::arch::cpu::MachineState;
 use crate::panic::panic_at_state;
 use crate::arch::sync::{push_critical_region, pop_critical_region};
 use crate::mem::{handle_pagefault, AccessAttempt, VAddr};
+use crate::mem::protect;
+use crate::arch::mem::page_permissions;
 use crate::arch::x86::driver::pic8259::Pic8259;
-use crate::arch::x86::driver::ps2;
-use crate::arch::x86::gdt::KERNEL_CODE_SELECTOR;
-use crate::println;
+use crate::arch::x86::driver::{apic, ps2, serial};
+use crate::driver::usb;
+#[cfg(feature = "net")]
+use crate::driver::net;
+#[cfg(feature = "profiler")]
+use crate::profile;
+use crate::task::{sched, timer};
+#[cfg(feature = "smp")]
+use crate::task::smp;
+use crate::arch::x86::{hwerror, uaccess};
+use crate::arch::x86::fault::{PageFaultErrorCode, SelectorErrorCode,
+                              has_selector_error_code};
+use crate::arch::x86::gdt::{DOUBLE_FAULT_IST, KERNEL_CODE_SELECTOR};
+use crate::{println, trace_irq_entry, watchdog};
 
 #[repr(C, packed)]
 struct IsrRegisters {
@@ -100,8 +116,23 @@ extern {
     fn isr_entry_irq_13();
     fn isr_entry_irq_14();
     fn isr_entry_irq_15();
+    fn isr_entry_call_ipi();
+    fn isr_entry_sample_timer();
+    fn isr_entry_spurious();
 }
 
+/// The vector of the IPI asking a CPU to run its pending cross-CPU calls.
+pub const CALL_IPI_VECTOR: u8 = 48;
+
+/// The vector of the LAPIC timer, which drives the sampling profiler.
+pub const SAMPLE_TIMER_VECTOR: u8 = 49;
+
+/// The vector of the legacy PIC's IRQ 0, followed by the 15 others.
+const IRQ_BASE_VECTOR: usize = 32;
+
+/// The vector of the LAPIC's spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 63;
+
 static VECTORS: [unsafe extern fn(); 48] = [
     isr_entry_exception_0,
     isr_entry_exception_1,
@@ -155,9 +186,19 @@ static VECTORS: [unsafe extern fn(); 48] = [
 
 static mut PIC8259: Option<Pic8259> = None;
 
+/// The number of timer interrupts received since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of interrupts received on each vector since boot.
+static COUNTS: [AtomicU64; 64] = [const { AtomicU64::new(0) }; 64];
+
 type DescriptorType = x86::bits64::segmentation::Descriptor64;
 
-static mut IDT: [DescriptorType; 64] = [DescriptorType::NULL; 64];
+/// The IDT sits on a page of its own so that it can be made read-only.
+#[repr(C, align(4096))]
+struct Idt([DescriptorType; 64]);
+
+static mut IDT: Idt = Idt([DescriptorType::NULL; 64]);
 
 pub unsafe fn get_pic() -> &'static mut Pic8259 {
     PIC8259.as_mut().unwrap()
@@ -170,32 +211,59 @@ pub unsafe fn setup() {
     pic.init(32, 40);
     PIC8259 = Some(pic);
 
-    let mut vec = 0;
-
-    for isr in VECTORS.iter() {
-        let offset = core::mem::transmute::<_, usize>(*isr);
+    let gate = |isr: unsafe extern fn()| {
+        let offset = core::mem::transmute::<_, usize>(isr);
 
-        IDT[vec] = <DescriptorBuilder as GateDescriptorBuilder<IdtType>>
-                   ::interrupt_descriptor(
+        <DescriptorBuilder as GateDescriptorBuilder<IdtType>>
+            ::interrupt_descriptor(
             KERNEL_CODE_SELECTOR,
             offset as IdtType
         ).present()
             .dpl(Ring0)
-            .finish();
-        vec += 1;
+    };
+
+    for (vec, isr) in VECTORS.iter().enumerate() {
+        let mut desc = gate(*isr);
+
+        if vec == x86::irq::DOUBLE_FAULT_VECTOR as usize {
+            desc = desc.ist(DOUBLE_FAULT_IST);
+        }
+
+        IDT.0[vec] = desc.finish();
     }
+    IDT.0[CALL_IPI_VECTOR as usize] = gate(isr_entry_call_ipi).finish();
+    IDT.0[SAMPLE_TIMER_VECTOR as usize]
+        = gate(isr_entry_sample_timer).finish();
+    IDT.0[SPURIOUS_VECTOR as usize] = gate(isr_entry_spurious).finish();
 
-    let ptr = DescriptorTablePointer::new(&IDT);
+    let ptr = DescriptorTablePointer::new(&IDT.0);
     lidt(&ptr);
 }
 
+/// Make the IDT read-only; no vector can be changed afterwards.
+pub unsafe fn protect_idt() {
+    protect::set_read_only(VAddr(&IDT as *const _ as usize),
+                           core::mem::size_of::<Idt>());
+}
+
 #[no_mangle]
 unsafe extern "C" fn isr_exception(
     vec_i: usize,
     errc: usize,
-    isr_regs: &IsrRegisters,
+    isr_regs: &mut IsrRegisters,
     regs: &GPRegisters,
 ) {
+    // A fault on a user address from an accessor listed in the exception table
+    // is reported to the accessor instead.
+    let is_kernel = isr_regs.cs & 0b11 == 0;
+    if is_kernel && (vec_i == x86::irq::PAGE_FAULT_VECTOR as usize
+                     || vec_i == x86::irq::GENERAL_PROTECTION_FAULT_VECTOR as usize) {
+        if let Some(fixup) = uaccess::search_exception_table(isr_regs.rip) {
+            isr_regs.rip = fixup;
+            return;
+        }
+    }
+
     let machine_state = MachineState {
         rax: regs.rax, rbx: regs.rbx, rcx: regs.rcx, rdx: regs.rdx,
         r8: regs.r8, r9: regs.r9, r10: regs.r10, r11: regs.r11,
@@ -215,6 +283,7 @@ unsafe fn handle_exception(
     machine_state: &MachineState,
 ) {
     push_critical_region();
+    COUNTS[vec_i].fetch_add(1, Ordering::Relaxed);
 
     let ex = x86::irq::EXCEPTIONS.get(vec_i as usize)
         .unwrap_or(&InterruptDescription {
@@ -225,36 +294,78 @@ unsafe fn handle_exception(
         source: "???",
     });
 
+    if vec_i == x86::irq::NONMASKABLE_INTERRUPT_VECTOR as usize {
+        crate::panic::stop_if_panicking(machine_state);
+        if !watchdog::on_nmi(machine_state) {
+            hwerror::handle_nmi(machine_state);
+        }
+        pop_critical_region();
+        return;
+    }
+
+    if vec_i == x86::irq::MACHINE_CHECK_VECTOR as usize {
+        hwerror::handle_machine_check(machine_state);
+    }
+
     if vec_i == x86::irq::PAGE_FAULT_VECTOR as usize {
-        let errc = errc.expect("Page fault must provide an error code");
-        let is_write = errc & (1 << 1) > 0;
-        let is_exec = errc & (1 << 4) > 0;
+        let errc = PageFaultErrorCode(
+            errc.expect("Page fault must provide an error code") as u64
+        );
 
         let addr = VAddr(unsafe { x86::controlregs::cr2() });
 
-        let access = if is_exec {
+        let access = if errc.is_instruction_fetch() {
             AccessAttempt::Execute
-        } else if is_write {
+        } else if errc.is_write() {
             AccessAttempt::Write
         } else {
             AccessAttempt::Read
         };
 
-        handle_pagefault(addr, access, machine_state);
+        handle_pagefault(addr, access, format_args!("{errc}"), machine_state);
         return;
     }
 
-    if let Some(errc) = errc {
+    if vec_i == x86::irq::DOUBLE_FAULT_VECTOR as usize {
+        // The faulting stack pointer lying on an unmapped page, such as the
+        // stack's guard page, is the telltale sign of a stack overflow.
+        let stack_addr = VAddr(machine_state.rsp.saturating_sub(8) as usize);
+        if !page_permissions(stack_addr).accessible {
+            panic_at_state(
+                format_args!("Exception ({}) {} {}: kernel stack overflow",
+                             vec_i, ex.mnemonic, ex.description),
+                Some(machine_state),
+                0,
+            );
+        }
+    }
+
+    // TODO: once user tasks exist, a fault in user mode must only terminate
+    //       the faulting task instead of panicking the whole kernel.
+    let mode = if machine_state.cs & 0b11 == 3 { " in user mode" } else { "" };
+
+    let selector = errc
+        .filter(|_| has_selector_error_code(vec_i))
+        .and_then(|errc| SelectorErrorCode::new(errc as u64));
+
+    if let Some(selector) = selector {
         panic_at_state(
-            format_args!("Exception ({}; errc={}) {} {}",
-                         vec_i, errc, ex.mnemonic, ex.description),
+            format_args!("Exception ({}) {} {}{}: {}",
+                         vec_i, ex.mnemonic, ex.description, mode, selector),
+            Some(machine_state),
+            0,
+        );
+    } else if let Some(errc) = errc {
+        panic_at_state(
+            format_args!("Exception ({}; errc={}) {} {}{}",
+                         vec_i, errc, ex.mnemonic, ex.description, mode),
             Some(machine_state),
             0,
         );
     } else {
         panic_at_state(
-            format_args!("Exception ({}) {} {}",
-                         vec_i, ex.mnemonic, ex.description),
+            format_args!("Exception ({}) {} {}{}",
+                         vec_i, ex.mnemonic, ex.description, mode),
             Some(machine_state),
             0,
         );
@@ -264,11 +375,24 @@ unsafe fn handle_exception(
 #[no_mangle]
 unsafe extern "C" fn isr_irq(irq: usize) {
     push_critical_region();
+    trace_irq_entry!(irq);
+    COUNTS[IRQ_BASE_VECTOR + irq].fetch_add(1, Ordering::Relaxed);
 
     if irq == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        watchdog::tick();
+        sched::tick();
+        timer::on_tick();
+        usb::poll();
+        #[cfg(feature = "net")]
+        crate::net::poll();
     } else if irq == 1 {
         ps2::on_irq();
-    } else {
+    } else if irq == ps2::MOUSE_IRQ {
+        ps2::on_mouse_irq();
+    } else if irq == serial::COM1_IRQ {
+        serial::on_irq();
+    } else if !on_net_irq(irq) {
         println!("IRQ={}", irq);
     }
 
@@ -276,3 +400,105 @@ unsafe extern "C" fn isr_irq(irq: usize) {
 
     pop_critical_region();
 }
+
+/// Dispatch `irq` to the network drivers; return whether one handled it.
+fn on_net_irq(irq: usize) -> bool {
+    #[cfg(feature = "net")]
+    return net::on_irq(irq);
+
+    #[cfg(not(feature = "net"))]
+    false
+}
+
+#[no_mangle]
+unsafe extern "C" fn isr_call_ipi() {
+    push_critical_region();
+    COUNTS[CALL_IPI_VECTOR as usize].fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "smp")]
+    smp::handle_call_ipi();
+    if let Some(lapic) = apic::local() {
+        lapic.eoi();
+    }
+
+    pop_critical_region();
+}
+
+#[no_mangle]
+#[cfg_attr(not(feature = "profiler"), allow(unused_variables))]
+unsafe extern "C" fn isr_sample_timer(isr_regs: &IsrRegisters) {
+    push_critical_region();
+    COUNTS[SAMPLE_TIMER_VECTOR as usize].fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "profiler")]
+    profile::sample(VAddr(isr_regs.rip as usize));
+    if let Some(lapic) = apic::local() {
+        lapic.eoi();
+    }
+
+    pop_critical_region();
+}
+
+/// Write the vectors that received interrupts, with their count and what they
+/// are for; this is the content of `/proc/interrupts`.
+pub fn write_interrupts(out: &mut String) -> fmt::Result {
+    writeln!(out, "VEC            COUNT  SOURCE")?;
+
+    for (vec, count) in COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+
+        write!(out, "{vec:>3} {count:>16}  ")?;
+        match vec {
+            _ if vec < IRQ_BASE_VECTOR => {
+                match x86::irq::EXCEPTIONS.get(vec) {
+                    Some(ex) => {
+                        writeln!(out, "{} {}", ex.mnemonic, ex.description)?
+                    },
+                    None => writeln!(out, "exception")?,
+                }
+            },
+            _ if vec == CALL_IPI_VECTOR as usize => writeln!(out, "call IPI")?,
+            _ if vec == SAMPLE_TIMER_VECTOR as usize => {
+                writeln!(out, "LAPIC timer (profiler)")?
+            },
+            _ => {
+                let irq = vec - IRQ_BASE_VECTOR;
+                let name = match irq {
+                    0 => " (timer)",
+                    1 => " (keyboard)",
+                    ps2::MOUSE_IRQ => " (mouse)",
+                    serial::COM1_IRQ => " (serial)",
+                    _ => "",
+                };
+                writeln!(out, "IRQ {irq}{name}")?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(feature = "ktest", not(test)))]
+mod ktests {
+    use crate::arch::cpu::halt;
+    use crate::ktest;
+    use super::*;
+
+    ktest! {
+        fn it_receives_timer_interrupts() {
+            let start = TICKS.load(Ordering::Relaxed);
+
+            for _ in 0..1000 {
+                if TICKS.load(Ordering::Relaxed) > start {
+                    return;
+                }
+                halt();
+            }
+
+            panic!("no timer interrupt was received");
+        }
+    }
+}
@@ -15,10 +15,16 @@ use multiboot2::MemoryMapTag;
 
 use crate::arch::x86::mem::paging::setup_kernel_paging;
 use crate::arch::mem::{LOWMEM_VA_START, LOWMEM_SIZE};
+use crate::mem::bootmem::{BootMem, RegionKind, MAX_REGIONS};
 use crate::mem::frame::{AllocatorBuilder, FRAME_ALLOCATOR};
-use crate::mem::{PAddr, PHYS_MEM_SIZE};
-use crate::debug;
+#[cfg(feature = "kasan")]
+use crate::mem::kasan;
+use crate::mem::{get_lowmem_va_end, PAddr, PHYS_MEM_SIZE};
+use crate::mem::load::kernel_image;
+use crate::mem::resource::{self, ResourceKind};
+use crate::{debug, warning};
 use crate::misc::BinSize;
+use crate::time;
 
 pub mod paging;
 
@@ -44,7 +50,11 @@ pub fn physical_memory_size(mem_maps: &MemoryMapTag) -> u64 {
     mem_maps.all_memory_areas().map(|area| area.end_address()).max().unwrap()
 }
 
-pub unsafe fn boot_setup(mem_maps: &MemoryMapTag) {
+/// Set up the kernel's paging and the frame allocator, whose memory is taken
+/// from the early-boot allocator, see `mem::bootmem`. The memory up to
+/// `loaded_end`, where the boot modules and the kernel's symbol table end, is
+/// kept allocated along with the kernel image.
+pub unsafe fn boot_setup(mem_maps: &MemoryMapTag, loaded_end: PAddr) {
     // We must first copy the array of memory area in the Multiboot struct that
     // will be destroyed by the call to `setup_kernel_paging()`.
     let mem_maps = copy_mbi_mem_areas(mem_maps);
@@ -56,47 +66,83 @@ pub unsafe fn boot_setup(mem_maps: &MemoryMapTag) {
                area.length, BinSize(area.length));
     }
 
-    let curr_heap = setup_kernel_paging();
+    let mut bootmem = BootMem::new();
+    for area in mem_maps.iter() {
+        let kind = match area.typ {
+            1 => RegionKind::Ram,
+            2 | 3 => RegionKind::Reserved,
+            _ => RegionKind::Unusable,
+        };
+        if let Err(e) = bootmem.add_region(PAddr(area.base_addr), area.length,
+                                           kind) {
+            warning!("bootmem: {e}, ignoring the last memory areas");
+            break;
+        }
+    }
 
-    assert_eq!(curr_heap.0 & 0xfff, 0);
-    let boot_used_bytes = (curr_heap - LOWMEM_VA_START).0 as u64;
+    // The low memory, used by the BIOS, is kept allocated up to the kernel
+    // image, the boot modules and the kernel's symbol table.
+    let image_end = kernel_image().end.0.max(loaded_end.into_vaddr().0);
+    bootmem.reserve(PAddr(0), (image_end - LOWMEM_VA_START.0) as u64,
+                    "kernel image")
+        .expect("Couldn't reserve the kernel image");
 
-    let mut allocator_b = AllocatorBuilder::new(curr_heap, PHYS_MEM_SIZE);
+    {
+        time::scope!("paging");
+        setup_kernel_paging(&mut bootmem);
+    }
 
-    for area in mem_maps {
-        let paddr = PAddr(area.base_addr);
-        let mut bsize = area.length;
+    time::scope!("frame allocator");
+    let lowmem_end = PAddr((get_lowmem_va_end() - LOWMEM_VA_START).0 as u64);
+    let frames = bootmem
+        .alloc(AllocatorBuilder::array_bsize(PHYS_MEM_SIZE) as u64, lowmem_end,
+               "frames")
+        .expect("No memory left for the array of frames");
+    let mut allocator_b = AllocatorBuilder::new(frames.into_vaddr(),
+                                                PHYS_MEM_SIZE);
 
-        if paddr.0 == 0x9fc00 {
-            continue;
-        } else if paddr.0 == 0 {
-            bsize &= !0xfff;
-        }
+    #[cfg(feature = "kasan")]
+    {
+        let shadow_bsize = kasan::shadow_bsize(lowmem_end.0);
+        let shadow = bootmem.alloc(shadow_bsize, lowmem_end, "kasan shadow")
+            .expect("No memory left for the KASAN shadow map");
+        kasan::init(LOWMEM_VA_START, shadow.into_vaddr(),
+                    shadow_bsize as usize);
+    }
 
-        match area.typ {
-            1 => {
-                allocator_b.declare_unused_ram(paddr, bsize);
-            },
-            2 | 3 => {
-                allocator_b.declare_reserved(paddr, bsize);
+    for area in mem_maps.iter() {
+        resource::declare_boot_region(
+            PAddr(area.base_addr),
+            area.length,
+            match area.typ {
+                1 => ResourceKind::SystemRam,
+                2 => ResourceKind::Reserved,
+                3 | 4 => ResourceKind::Acpi,
+                _ => ResourceKind::Unusable,
             },
-            _ => {
-                allocator_b.declare_unusable(paddr, bsize);
-            }
-        }
+        );
     }
 
-    allocator_b.declare_allocated_ram(PAddr(0), boot_used_bytes);
+    for reservation in bootmem.reservations() {
+        debug!("bootmem: {:?} -> {:?} ({}) {}", reservation.start,
+               reservation.end(), BinSize(reservation.bsize),
+               reservation.owner);
+    }
+    bootmem.transfer(&mut allocator_b);
 
     {
         let mut allocator = FRAME_ALLOCATOR.lock();
         assert!(allocator.is_none());
-        *allocator = Some(allocator_b.build());
+        let mut built = allocator_b.build();
+        resource::mark_claimed_frames(&mut built);
+        *allocator = Some(built);
     }
 }
 
-fn copy_mbi_mem_areas(mem_maps: &MemoryMapTag) -> ArrayVec<MbiMemArea, 16> {
-    let mut mem_areas: ArrayVec<MbiMemArea, 16> = ArrayVec::new();
+fn copy_mbi_mem_areas(
+    mem_maps: &MemoryMapTag,
+) -> ArrayVec<MbiMemArea, MAX_REGIONS> {
+    let mut mem_areas: ArrayVec<MbiMemArea, MAX_REGIONS> = ArrayVec::new();
     for area in mem_maps.all_memory_areas() {
         let mut area_copy = MaybeUninit::<MbiMemArea>::uninit();
         unsafe {
@@ -106,7 +152,10 @@ fn copy_mbi_mem_areas(mem_maps: &MemoryMapTag) -> ArrayVec<MbiMemArea, 16> {
                 core::mem::size_of::<MbiMemArea>(),
             );
         }
-        mem_areas.push(unsafe { area_copy.assume_init() });
+        if mem_areas.try_push(unsafe { area_copy.assume_init() }).is_err() {
+            warning!("too many memory areas, ignoring the last ones");
+            break;
+        }
     }
 
     mem_areas
@@ -8,9 +8,13 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use crate::mem::{PAddr, get_lowmem_va_end, VAddr};
+use core::ops::Range;
+use crate::mem::{CacheMode, Mapping, PAddr, get_lowmem_va_end, VAddr};
+use crate::mem::bootmem::BootMem;
+use crate::mem::frame::allocate_frames;
 use crate::sync::Spinlock;
 use crate::arch::mem::LOWMEM_VA_START;
+use crate::arch::x86::pat;
 use crate::debug;
 use crate::mem::load::{kernel_image, kernel_rodata_segment, kernel_text_segment};
 
@@ -31,6 +35,13 @@ extern "C" {
 
 const PDPT_ENTRY_COUNT: usize = 512;
 
+/// The size of the pages mapped by PD entries.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// The size of the low memory mapped by `_start` to boot, i.e. by its `NR_PT`
+/// page tables.
+const BOOT_MAPPED_BSIZE: usize = 16 * HUGE_PAGE_SIZE;
+
 #[repr(C)]
 pub struct PML4(pub [PML4Entry; 512]);
 
@@ -131,6 +142,22 @@ impl PML4Entry {
             self.0 &= !(1 << 0);
         }
     }
+
+    pub fn is_writable(&self) -> bool {
+        self.0 & (1 << 1) > 0
+    }
+
+    pub fn set_writable(&mut self, writable: bool) {
+        if writable {
+            self.0 |= 1 << 1;
+        } else {
+            self.0 &= !(1 << 1);
+        }
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
 }
 
 impl PDPTEntry {
@@ -183,6 +210,10 @@ impl PDPTEntry {
             self.0 &= !(1 << 1);
         }
     }
+
+    pub fn is_user(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
 }
 
 impl PDEntry {
@@ -236,6 +267,14 @@ impl PDEntry {
         }
     }
 
+    pub fn is_user(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.0 & (1 << 8) > 0
+    }
+
     pub fn is_huge(&self) -> bool {
         self.0 & (1 << 7) > 0
     }
@@ -298,6 +337,14 @@ impl PTEntry {
         }
     }
 
+    pub fn is_user(&self) -> bool {
+        self.0 & (1 << 2) > 0
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.0 & (1 << 8) > 0
+    }
+
     pub fn is_executable(&self) -> bool {
         self.0 & (1 << 63) == 0
     }
@@ -309,6 +356,25 @@ impl PTEntry {
             self.0 |= 1 << 63;
         }
     }
+
+    /// Set the PWT, PCD and PAT bits selecting the page's memory type, as
+    /// configured in `pat::init()`.
+    pub fn set_cache_mode(&mut self, cache: CacheMode) {
+        let cache = match cache {
+            CacheMode::WriteCombining if !pat::has_write_combining() => {
+                CacheMode::Uncached
+            },
+            cache => cache,
+        };
+
+        self.0 &= !(1 << 7 | 0b11 << 3);
+        self.0 |= match cache {
+            CacheMode::WriteBack => 0,
+            CacheMode::WriteThrough => 1 << 3,
+            CacheMode::Uncached => 0b11 << 3,
+            CacheMode::WriteCombining => 1 << 7,
+        };
+    }
 }
 
 #[derive(Debug)]
@@ -385,7 +451,9 @@ pub fn locate_page_entry(vaddr: VAddr) -> Option<AnyEntry> {
 /// After that, we continue to map physical memory past the kernel image up to
 /// the maximum physical memory or the virtual address limit (up to 896 Mio),
 /// whichever comes first. The mapped physical memory is readable and writable
-/// but is not executable.
+/// but is not executable. Away from the kernel image, which needs per-page
+/// permissions, memory is mapped with 2 Mio huge pages, saving page-tables and
+/// TLB entries; they are split into 4 Kio pages on demand.
 ///
 /// We take advantage of the 8 page-tables statically allocated within the
 /// kernel image to bootstrap this process: in fact, mapping more virtual
@@ -393,13 +461,8 @@ pub fn locate_page_entry(vaddr: VAddr) -> Option<AnyEntry> {
 /// structures requires more virtual memory. Since creating a new page-table
 /// gives us 2 Mio of additional virtual addresses for 4096 bytes for the table
 /// itself, we only need a single spare memory page to map the entire virtual
-/// address space.
-///
-/// # Return value
-///
-/// This function returns the virtual address of the first free byte right after
-/// what this function used for new page-tables and where further allocations
-/// can start.
+/// address space. The new page-tables are allocated from `bootmem`, within
+/// the memory already mapped by `_start`.
 ///
 /// # Side effects
 ///
@@ -409,10 +472,11 @@ pub fn locate_page_entry(vaddr: VAddr) -> Option<AnyEntry> {
 /// executable, the kernel's .rodata segment which is read-only.
 ///
 /// This function is the first one to write past the preallocated memory space
-/// loaded by the bootloader (`__kernel_image_end`), to make new page-tables.
-/// Any data located just after the kernel image are therefore overwritten which
-/// notably includes the Multiboot structure provided by the bootloader; it is
-/// thus vital to copy any needed information from this structure before
+/// loaded by the bootloader (`__kernel_image_end`), to make new page-tables
+/// in the RAM left free by `bootmem`, where the kernel image and the boot
+/// modules must be reserved. Any data located there are therefore overwritten
+/// which notably includes the Multiboot structure provided by the bootloader;
+/// it is thus vital to copy any needed information from this structure before
 /// calling this function.
 ///
 /// The entire TLB is invalidated.
@@ -425,8 +489,7 @@ pub fn locate_page_entry(vaddr: VAddr) -> Option<AnyEntry> {
 /// the linker script are correct, and that the entire paging structure tree
 /// starting at the fourth PDPT entry (as set up by `_start`) is valid.
 /// After calling this function, the Multiboot information structure is invalid.
-pub unsafe fn setup_kernel_paging() -> VAddr {
-    let mut heap_addr = kernel_image().end;
+pub unsafe fn setup_kernel_paging(bootmem: &mut BootMem) {
     let mut vaddr: VAddr = LOWMEM_VA_START;
     let mut pml4 = GLOBAL_PML4.lock();
 
@@ -443,13 +506,13 @@ pub unsafe fn setup_kernel_paging() -> VAddr {
 
         for pdpt_entry in pdpt.iter_mut() {
             if !pdpt_entry.is_present() {
-                make_pd(pdpt_entry, &mut heap_addr);
+                make_pd(pdpt_entry, bootmem);
             }
 
             let pd = pdpt_entry.pd_mut().expect("No PD in PDPT entry");
             let pd = unsafe { &mut *pd };
 
-            walk_pd(pd, &mut heap_addr, &mut vaddr);
+            walk_pd(pd, bootmem, &mut vaddr);
             if vaddr >= get_lowmem_va_end() {
                 break 'each_pml4e;
             }
@@ -463,18 +526,34 @@ pub unsafe fn setup_kernel_paging() -> VAddr {
     unsafe {
         x86::controlregs::cr3_write(x86::controlregs::cr3());
     }
-
-    heap_addr
 }
 
-fn walk_pd(pd: &mut PD, heap_addr: &mut VAddr, vaddr: &mut VAddr) {
+fn walk_pd(pd: &mut PD, bootmem: &mut BootMem, vaddr: &mut VAddr) {
     let text_segment = kernel_text_segment();
     let rodata_segment = kernel_rodata_segment();
     let stack_guard = VAddr(unsafe { &boot_stack_bottom_guard as *const u8 as usize });
+    let image = kernel_image();
 
     for pd_entry in pd.iter_mut() {
+        let huge_end = *vaddr + HUGE_PAGE_SIZE;
+        if !pd_entry.is_present()
+            && huge_end <= get_lowmem_va_end()
+            && (huge_end <= image.start || *vaddr >= image.end) {
+            pd_entry.set_addr(PAddr::from_lowmem_vaddr(*vaddr).unwrap());
+            pd_entry.set_huge(true);
+            pd_entry.set_writable(true);
+            pd_entry.set_executable(false);
+            pd_entry.set_present(true);
+
+            *vaddr = huge_end;
+            if *vaddr >= get_lowmem_va_end() {
+                return;
+            }
+            continue;
+        }
+
         if !pd_entry.is_present() {
-            make_pt(pd_entry, heap_addr);
+            make_pt(pd_entry, bootmem);
         }
 
         let pt = pd_entry.pt_mut().expect("PDE does not reference a PT");
@@ -507,52 +586,40 @@ fn walk_pd(pd: &mut PD, heap_addr: &mut VAddr, vaddr: &mut VAddr) {
 }
 
 // TODO: factorize with `make_pt()`
-fn make_pd(pdpt_entry: &mut PDPTEntry, heap_addr: &mut VAddr) {
-    assert_eq!(heap_addr.0 & 0xfff, 0);
-    //assert!(*heap_addr + 4096 <= get_boot_lowmem_va_end());
-
-    let pt_ptr = heap_addr.as_mut_ptr::<u8>();
-    unsafe {
-        pt_ptr.write_bytes(0, 4096);
-    }
-
-    pdpt_entry.set_addr(
-        PAddr::from_lowmem_vaddr(*heap_addr)
-            .expect("Virtual address must be in low memory")
-    );
+fn make_pd(pdpt_entry: &mut PDPTEntry, bootmem: &mut BootMem) {
+    pdpt_entry.set_addr(alloc_table(bootmem));
     pdpt_entry.set_present(true);
     pdpt_entry.set_writable(true);
 
-    *heap_addr += 4096;
-
     // TODO: make more efficient by not trashing TLB at each new PD
     unsafe {
         x86::controlregs::cr3_write(x86::controlregs::cr3());
     }
 }
 
-fn make_pt(pd_entry: &mut PDEntry, heap_addr: &mut VAddr) {
-    assert_eq!(heap_addr.0 & 0xfff, 0);
-    //assert!(*heap_addr + 4096 <= get_boot_lowmem_va_end());
-
-    let pt_ptr = heap_addr.as_mut_ptr::<u8>();
-    unsafe {
-        pt_ptr.write_bytes(0, 4096);
-    }
-
-    pd_entry.set_addr(
-        PAddr::from_lowmem_vaddr(*heap_addr)
-            .expect("Virtual address must be in low memory")
-    );
+fn make_pt(pd_entry: &mut PDEntry, bootmem: &mut BootMem) {
+    pd_entry.set_addr(alloc_table(bootmem));
     pd_entry.set_present(true);
     pd_entry.set_writable(true);
 
-    *heap_addr += 4096;
-
     // TODO: make more efficient by not trashing TLB at each new PT
     unsafe { reload_tlb(); }
 }
 
+/// Allocate a zeroed page-table from `bootmem`, within the memory mapped by
+/// `_start`.
+fn alloc_table(bootmem: &mut BootMem) -> PAddr {
+    let paddr = bootmem
+        .alloc(4096, PAddr(BOOT_MAPPED_BSIZE as u64), "page tables")
+        .expect("No memory left for the page tables");
+
+    unsafe {
+        paddr.into_vaddr().as_mut_ptr::<u8>().write_bytes(0, 4096);
+    }
+
+    paddr
+}
+
 pub unsafe fn reload_tlb() {
     unsafe {
         x86::controlregs::cr3_write(x86::controlregs::cr3());
@@ -563,3 +630,241 @@ fn get_boot_lowmem_va_end() -> VAddr {
     // 16 PDs are contained in the first 16 entries of PML4[256].PDPT
     unsafe { LOWMEM_VA_START + 16 * (2 << 20) }
 }
+
+fn current_pml4() -> *mut PML4 {
+    unsafe {
+        PAddr(x86::controlregs::cr3() & 0x7fffffff_fffff000)
+            .into_vaddr()
+            .as_mut_ptr::<PML4>()
+    }
+}
+
+/// Change the protection of the 4 KiB page mapped at `vaddr` and invalidate its
+/// TLB entry; a huge page is split first. Returns `false` if the page isn't
+/// mapped, or if no frame could be allocated to split a huge page.
+///
+/// # Safety #
+///
+/// Removing permissions from a page still in use in a way that requires them
+/// will fault.
+pub unsafe fn set_page_protection(
+    vaddr: VAddr,
+    writable: bool,
+    executable: bool,
+) -> bool {
+    let _pml4_guard = GLOBAL_PML4.lock();
+    let pml4 = &mut *current_pml4();
+
+    let Some(pdpt) = pml4.0[vaddr.pml4e()].pdpt_mut() else { return false };
+    let Some(pd) = (*pdpt).0[vaddr.pdpte()].pd_mut() else { return false };
+    let pde = &mut (*pd).0[vaddr.pde()];
+    if pde.is_huge() && !split_huge_page(pde, vaddr) {
+        return false;
+    }
+    let Some(pt) = pde.pt_mut() else { return false };
+    let pte = &mut (*pt).0[vaddr.pte()];
+
+    if !pte.is_present() {
+        return false;
+    }
+
+    pte.set_writable(writable);
+    pte.set_executable(executable);
+    x86::tlb::flush(vaddr.0);
+
+    true
+}
+
+/// Map the 4 KiB page at `vaddr` onto the frame at `paddr`, as writable and
+/// non-executable memory of type `cache`; missing paging structures are
+/// allocated. Returns `false` if `vaddr` is already mapped, is part of a huge
+/// page, or if no frame could be allocated for a paging structure.
+///
+/// # Safety #
+///
+/// `paddr` must not be general-purpose RAM used elsewhere with a different
+/// memory type.
+pub unsafe fn map_page(vaddr: VAddr, paddr: PAddr, cache: CacheMode) -> bool {
+    let _pml4_guard = GLOBAL_PML4.lock();
+    let pml4 = &mut *current_pml4();
+
+    let pml4e = &mut pml4.0[vaddr.pml4e()];
+    if !pml4e.is_present() {
+        let Some(table) = allocate_table() else { return false };
+        pml4e.set_addr(table);
+        pml4e.set_writable(true);
+        pml4e.set_present(true);
+    }
+
+    let pdpt = &mut *pml4e.pdpt_mut().unwrap();
+    let pdpte = &mut pdpt.0[vaddr.pdpte()];
+    if !pdpte.is_present() {
+        let Some(table) = allocate_table() else { return false };
+        pdpte.set_addr(table);
+        pdpte.set_writable(true);
+        pdpte.set_present(true);
+    }
+
+    let pd = &mut *pdpte.pd_mut().unwrap();
+    let pde = &mut pd.0[vaddr.pde()];
+    if pde.is_huge() {
+        return false;
+    } else if !pde.is_present() {
+        let Some(table) = allocate_table() else { return false };
+        pde.set_addr(table);
+        pde.set_writable(true);
+        pde.set_present(true);
+    }
+
+    let pt = &mut *pde.pt_mut().unwrap();
+    let pte = &mut pt.0[vaddr.pte()];
+    if pte.is_present() {
+        return false;
+    }
+
+    *pte = PTEntry(0);
+    pte.set_addr(paddr);
+    pte.set_writable(true);
+    pte.set_executable(false);
+    pte.set_cache_mode(cache);
+    pte.set_present(true);
+    x86::tlb::flush(vaddr.0);
+
+    true
+}
+
+/// Unmap the 4 KiB page at `vaddr`, previously mapped with `map_page()`; the
+/// paging structures are kept.
+///
+/// # Safety #
+///
+/// The page must not be accessed anymore.
+pub unsafe fn unmap_page(vaddr: VAddr) {
+    let _pml4_guard = GLOBAL_PML4.lock();
+    let pml4 = &mut *current_pml4();
+
+    let Some(pdpt) = pml4.0[vaddr.pml4e()].pdpt_mut() else { return };
+    let Some(pd) = (*pdpt).0[vaddr.pdpte()].pd_mut() else { return };
+    let pde = &mut (*pd).0[vaddr.pde()];
+    if pde.is_huge() && !split_huge_page(pde, vaddr) {
+        panic!("couldn't split the huge page of {vaddr:?} to unmap it");
+    }
+    let Some(pt) = pde.pt_mut() else { return };
+
+    (*pt).0[vaddr.pte()] = PTEntry(0);
+    x86::tlb::flush(vaddr.0);
+}
+
+fn allocate_table() -> Option<PAddr> {
+    allocate_frames().zero_mem().allocate()
+}
+
+/// Replace the 2 MiB page of `pde`, which contains `vaddr`, with a page-table
+/// of 4 KiB pages of the same permissions and memory type. Returns `false` if
+/// no frame could be allocated for the page-table.
+///
+/// # Safety #
+///
+/// The GLOBAL_PML4 lock must be held.
+unsafe fn split_huge_page(pde: &mut PDEntry, vaddr: VAddr) -> bool {
+    // The NX, global, PCD, PWT, user, writable and present bits are at the
+    // same place in both entries; the PAT bit moves from bit 12 to bit 7.
+    const PTE_FLAGS_MASK: u64 = 1 << 63 | 1 << 8 | 0b11111;
+    const PDE_PAT: u64 = 1 << 12;
+    const PTE_PAT: u64 = 1 << 7;
+
+    let Some(table) = allocate_table() else { return false };
+    let pt = &mut *table.into_vaddr().as_mut_ptr::<PT>();
+    // Unlike `addr()`, leave out the PAT bit of the 2 MiB page's address.
+    let base = pde.0 & 0x3fffffff_ffe00000;
+    let pat = if pde.0 & PDE_PAT != 0 { PTE_PAT } else { 0 };
+
+    for (i, pte) in pt.iter_mut().enumerate() {
+        *pte = PTEntry((pde.0 & PTE_FLAGS_MASK) | pat);
+        pte.set_addr(PAddr(base + (i * 4096) as u64));
+    }
+
+    pde.set_huge(false);
+    pde.set_addr(table);
+    x86::tlb::flush(vaddr.0 & !(HUGE_PAGE_SIZE - 1));
+
+    true
+}
+
+/// Call `f` for every present page of the kernel's half of the address space,
+/// with its virtual address, size, and effective write and execute permissions.
+pub fn walk_kernel_mappings(mut f: impl FnMut(VAddr, usize, bool, bool)) {
+    walk_mappings(LOWMEM_VA_START..VAddr(usize::MAX), |mapping| {
+        f(mapping.vaddr, mapping.bsize, mapping.writable, mapping.executable);
+    });
+}
+
+/// Call `f` for every present page overlapping `range`, with its effective
+/// permissions, i.e. combined across all paging levels.
+pub fn walk_mappings(range: Range<VAddr>, mut f: impl FnMut(Mapping)) {
+    let pml4 = unsafe { &*current_pml4() };
+    let overlaps = |base: usize, bsize: usize| {
+        base < range.end.0 && base + (bsize - 1) >= range.start.0
+    };
+
+    for (pml4_i, pml4e) in pml4.0.iter().enumerate() {
+        let pml4_base = if pml4_i >= 256 { 0xffff0000_00000000 } else { 0 }
+            | pml4_i << 39;
+        if !overlaps(pml4_base, 1 << 39) {
+            continue;
+        }
+        let Some(pdpt) = pml4e.pdpt() else { continue };
+        let pdpt = unsafe { &*pdpt };
+
+        for (pdpt_i, pdpte) in pdpt.0.iter().enumerate() {
+            let pdpt_base = pml4_base | pdpt_i << 30;
+            if !overlaps(pdpt_base, 1 << 30) {
+                continue;
+            }
+            let Some(pd) = pdpte.pd() else { continue };
+            let pd = unsafe { &*pd };
+            let user = pml4e.is_user() && pdpte.is_user();
+            let writable = pml4e.is_writable() && pdpte.is_writable();
+
+            for (pd_i, pde) in pd.0.iter().enumerate() {
+                let base = pdpt_base | pd_i << 21;
+                if !pde.is_present() || !overlaps(base, HUGE_PAGE_SIZE) {
+                    continue;
+                }
+
+                let user = user && pde.is_user();
+                let writable = writable && pde.is_writable();
+
+                if pde.is_huge() {
+                    f(Mapping {
+                        vaddr: VAddr(base),
+                        paddr: pde.addr(),
+                        bsize: HUGE_PAGE_SIZE,
+                        writable,
+                        executable: pde.is_executable(),
+                        user,
+                        global: pde.is_global(),
+                    });
+                    continue;
+                }
+
+                let pt = unsafe { &*pde.pt().unwrap() };
+                for (pt_i, pte) in pt.0.iter().enumerate() {
+                    let vaddr = base | pt_i << 12;
+                    if pte.is_present() && overlaps(vaddr, 4096) {
+                        f(Mapping {
+                            vaddr: VAddr(vaddr),
+                            paddr: pte.addr(),
+                            bsize: 4096,
+                            writable: writable && pte.is_writable(),
+                            executable: pde.is_executable()
+                                && pte.is_executable(),
+                            user: user && pte.is_user(),
+                            global: pte.is_global(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
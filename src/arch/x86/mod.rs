@@ -15,5 +15,12 @@ pub mod irq;
 pub(super) mod export;
 pub mod mem;
 pub mod cpuid;
+pub mod fault;
+pub mod hwerror;
+pub mod security;
+pub mod uaccess;
+pub mod pat;
+pub mod ioport;
+pub mod random;
 
 pub type Ioport = u16;
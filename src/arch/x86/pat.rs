@@ -0,0 +1,73 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The Page Attribute Table, selecting the memory type of each page from its
+//! PAT, PCD and PWT bits. The first four entries keep their power-up value so
+//! that PCD and PWT alone keep their usual meaning; the fifth one, selected by
+//! the PAT bit alone, is made write-combining for framebuffers.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::msr::{wrmsr, IA32_PAT};
+
+use crate::arch::x86::cpuid;
+use crate::arch::x86::mem::paging::reload_tlb;
+use crate::{info, warning};
+
+mod memory_type {
+    pub const UNCACHEABLE: u64 = 0x00;
+    pub const WRITE_COMBINING: u64 = 0x01;
+    pub const WRITE_THROUGH: u64 = 0x04;
+    pub const WRITE_BACK: u64 = 0x06;
+    pub const UNCACHED_MINUS: u64 = 0x07;
+}
+
+/// The memory types of the PAT entries 0 to 7.
+const PAT_ENTRIES: [u64; 8] = {
+    use memory_type::*;
+    [
+        WRITE_BACK, WRITE_THROUGH, UNCACHED_MINUS, UNCACHEABLE,
+        WRITE_COMBINING, WRITE_THROUGH, UNCACHED_MINUS, UNCACHEABLE,
+    ]
+};
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Program the PAT MSR.
+///
+/// # Safety #
+///
+/// Must be called during the early boot process, with only one CPU running and
+/// before any page is mapped with the PAT bit set.
+pub unsafe fn init() {
+    let has_pat = cpuid::get().get_feature_info()
+        .is_some_and(|features| features.has_pat());
+    if !has_pat {
+        warning!("no PAT, write-combining mappings are uncached");
+        return;
+    }
+
+    let value = PAT_ENTRIES.iter()
+        .enumerate()
+        .fold(0, |value, (i, &typ)| value | typ << (i * 8));
+
+    asm!("wbinvd", options(nostack));
+    wrmsr(IA32_PAT, value);
+    asm!("wbinvd", options(nostack));
+    reload_tlb();
+
+    PAT_ENABLED.store(true, Ordering::SeqCst);
+    info!("PAT configured, write-combining available");
+}
+
+/// Whether pages can be mapped as write-combining.
+pub fn has_write_combining() -> bool {
+    PAT_ENABLED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,51 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Random numbers for the kernel's own hardening needs, such as the stack
+//! protector's canary. This is not a cryptographic RNG: when the CPU lacks
+//! RDRAND, values are derived from the time-stamp counter.
+
+use x86::random::rdrand64;
+use x86::time::rdtsc;
+
+use crate::arch::x86::cpuid;
+
+/// RDRAND can transiently fail when the hardware entropy source is drained;
+/// Intel recommends retrying 10 times.
+const RDRAND_RETRIES: usize = 10;
+
+/// Return a random value from the CPU's hardware RNG, if there is one.
+pub fn hw_random_u64() -> Option<u64> {
+    if !cpuid::get().get_feature_info()?.has_rdrand() {
+        return None;
+    }
+
+    let mut value = 0;
+    for _ in 0..RDRAND_RETRIES {
+        if unsafe { rdrand64(&mut value) } {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Return a random value, from the hardware RNG if available, or else from a
+/// mix of the time-stamp counter.
+pub fn random_u64() -> u64 {
+    hw_random_u64().unwrap_or_else(|| splitmix64(unsafe { rdtsc() }))
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
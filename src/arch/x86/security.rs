@@ -0,0 +1,86 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! CPU security features protecting the kernel from user space and from its
+//! own bugs:
+//!   * CR0.WP: read-only pages are also read-only for the kernel;
+//!   * SMEP: the kernel faults when executing user-space pages;
+//!   * SMAP: the kernel faults when accessing user-space pages, unless it
+//!     explicitly allows it with `with_user_access`;
+//!   * UMIP: user space can't read descriptor tables with SGDT, SIDT, etc.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86::controlregs::{cr0, cr0_write, cr4, cr4_write, Cr0, Cr4};
+
+use crate::arch::x86::cpuid;
+use crate::{info, warning};
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub unsafe fn init() {
+    cr0_write(cr0() | Cr0::CR0_WRITE_PROTECT);
+    if !cr0().contains(Cr0::CR0_WRITE_PROTECT) {
+        warning!("couldn't enable CR0.WP: kernel writes ignore page protections");
+    }
+
+    let Some(features) = cpuid::get().get_extended_feature_info() else {
+        warning!("no extended CPU features, SMEP, SMAP and UMIP are disabled");
+        return;
+    };
+
+    let mut flags = cr4();
+
+    if features.has_smep() {
+        flags |= Cr4::CR4_ENABLE_SMEP;
+    }
+    if features.has_smap() {
+        flags |= Cr4::CR4_ENABLE_SMAP;
+    }
+    if features.has_umip() {
+        flags |= Cr4::CR4_ENABLE_UMIP;
+    }
+
+    cr4_write(flags);
+    SMAP_ENABLED.store(features.has_smap(), Ordering::SeqCst);
+
+    info!("CPU protections: WP=on SMEP={} SMAP={} UMIP={}",
+          on_off(features.has_smep()),
+          on_off(features.has_smap()),
+          on_off(features.has_umip()));
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+/// While alive, the kernel is allowed to access user-space pages despite SMAP;
+/// see `crate::arch::cpu::with_user_access`.
+///
+/// `stac` and `clac` are not declared `nomem`, so that they act as compiler
+/// memory barriers: no user access can be moved out of the guard's lifetime.
+pub struct UserAccessGuard(());
+
+impl UserAccessGuard {
+    pub fn new() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        Self(())
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
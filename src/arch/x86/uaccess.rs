@@ -0,0 +1,82 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Fault-tolerant accesses to user-space memory. The instructions that may
+//! fault on a user address are listed in the exception table, the `.ex_table`
+//! section, along with a fixup address: when one of them faults, the exception
+//! handler resumes execution at the fixup instead of panicking.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::slice;
+
+use crate::arch::x86::security::UserAccessGuard;
+
+#[repr(C)]
+struct ExceptionTableEntry {
+    /// The address of the instruction allowed to fault.
+    insn: u64,
+    /// Where to resume execution when it does.
+    fixup: u64,
+}
+
+extern "C" {
+    static __kernel_ex_table: u8;
+    static __kernel_ex_table_end: u8;
+
+    /// Copy `len` bytes from `src` to `dst`; return the number of bytes left
+    /// uncopied because of a fault.
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+global_asm!(r#"
+.global __copy_user
+__copy_user:
+    mov rcx, rdx
+1:  rep movsb
+    xor eax, eax
+    ret
+2:  mov rax, rcx
+    ret
+
+.pushsection .ex_table, "a"
+    .balign 8
+    .quad 1b, 2b
+.popsection
+"#);
+
+/// Copy `len` bytes from `src` to `dst`, either of which may be a user-space
+/// address; return the number of bytes that couldn't be copied because of a
+/// page fault.
+///
+/// # Safety #
+///
+/// The kernel-space side of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let _guard = UserAccessGuard::new();
+    __copy_user(dst, src, len)
+}
+
+/// Return the fixup address for a fault at `rip`, if the instruction there is
+/// allowed to fault.
+pub fn search_exception_table(rip: u64) -> Option<u64> {
+    let start = unsafe { addr_of!(__kernel_ex_table) };
+    let end = unsafe { addr_of!(__kernel_ex_table_end) };
+    let len = (end as usize - start as usize) / size_of::<ExceptionTableEntry>();
+
+    let table = unsafe {
+        slice::from_raw_parts(start as *const ExceptionTableEntry, len)
+    };
+
+    table.iter()
+        .find(|entry| entry.insn == rip)
+        .map(|entry| entry.fixup)
+}
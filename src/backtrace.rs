@@ -8,19 +8,23 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use alloc::boxed::Box;
 use core::fmt::{Debug, Formatter};
 use core::mem::size_of;
-use core::ptr::addr_of;
+use core::ops::Range;
+use core::ptr::{addr_of, addr_of_mut};
 use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use gimli::{
     BaseAddresses, CfaRule, EhFrame, EhFrameHdr, EhHdrTable, EndianSlice,
-    LittleEndian, ParsedEhFrameHdr, Register, RegisterRule, UnwindContext,
-    UnwindSection,
+    LittleEndian, ParsedEhFrameHdr, Reader, Register, RegisterRule,
+    UnwindContext, UnwindContextStorage, UnwindSection, UnwindTableRow,
 };
 
 use crate::arch::cpu::MachineState;
+use crate::arch::task::TaskMachineContext;
+use crate::ksyms;
 use crate::mem::VAddr;
+use crate::task::Task;
 
 pub struct Backtrace {
     unwinder: Unwinder,
@@ -37,11 +41,37 @@ impl Backtrace {
     pub fn from_machine_state(machine: &MachineState) -> Self {
         Self {
             unwinder: Unwinder::new(
-                EhInfo::new(),
+                eh_info(),
                 RegisterSet::from_machine_state(machine),
+                None,
             ),
         }
     }
+
+    /// The backtrace of a switched-out task, from its saved context `ctx`;
+    /// the unwinding stops at the first frame outside of `stack`, its kernel
+    /// stack.
+    pub fn from_task_context(
+        ctx: &TaskMachineContext,
+        stack: Range<VAddr>,
+    ) -> Self {
+        Self {
+            unwinder: Unwinder::new(
+                eh_info(),
+                RegisterSet::from_task_context(ctx),
+                Some((stack.start.0 as u64)..(stack.end.0 as u64)),
+            ),
+        }
+    }
+
+    /// The backtrace of `task`, unwound from its saved context; `None` if it
+    /// is running, since its saved context is then stale. The caller must hold
+    /// the lock `task` was borrowed from, so that the task can't be scheduled
+    /// while its stack is walked.
+    pub fn of_task(task: &Task) -> Option<Self> {
+        let ctx = task.saved_context()?;
+        Some(Self::from_task_context(ctx, task.kernel_stack()))
+    }
 }
 
 impl Iterator for Backtrace {
@@ -54,15 +84,32 @@ impl Iterator for Backtrace {
             return None;
         }
 
+        let pc = VAddr(pc as usize);
+        let symbol = ksyms::lookup(pc);
+
         Some(CallFrame {
-            pc: VAddr(pc as usize),
-            symbol: None,
-            sym_off: None,
+            pc,
+            symbol: symbol.map(|(name, _)| name),
+            sym_off: symbol.map(|(_, offset)| offset),
             file_line: None,
         })
     }
 }
 
+/// The start address of the function containing `pc`, according to the unwind
+/// information; `None` if no function's unwind information covers `pc`.
+pub fn function_start(pc: VAddr) -> Option<VAddr> {
+    let eh_info = eh_info()?;
+    let fde = eh_info.table()?.fde_for_address(
+        &eh_info.eh_frame,
+        &eh_info.base_addrs,
+        pc.0 as u64,
+        |section, bases, offset| section.cie_from_offset(bases, offset),
+    ).ok()?;
+
+    Some(VAddr(fde.initial_address() as usize))
+}
+
 extern "C" {
     static __kernel_eh_frame_hdr: u8;
     static __kernel_eh_frame_hdr_end: u8;
@@ -79,29 +126,58 @@ enum UnwinderError {
     NoUnwindInfo,
     NoPcRegister,
     NoReturnAddr,
+    OutsideStack,
+    UnwindContextBusy,
 }
 
+type Slice = EndianSlice<'static, LittleEndian>;
+
+/// The unwind context's storage, of fixed size so that unwinding never
+/// allocates: backtraces must work within a panic caused by a corrupted heap.
+struct StaticStorage;
+
+impl<R: Reader> UnwindContextStorage<R> for StaticStorage {
+    type Rules = [(Register, RegisterRule<R>); 32];
+    type Stack = [UnwindTableRow<R, Self>; 4];
+}
+
+const EH_INFO_UNINIT: u8 = 0;
+const EH_INFO_PARSING: u8 = 1;
+const EH_INFO_READY: u8 = 2;
+
+static EH_INFO_STATE: AtomicU8 = AtomicU8::new(EH_INFO_UNINIT);
+/// The parsed unwind information, `None` if it is invalid; only set once
+/// `EH_INFO_STATE` is `EH_INFO_READY`.
+static mut EH_INFO: Option<EhInfo> = None;
+
+/// The unwind context, shared by all backtraces; too large for the stack.
+static mut UNWIND_CONTEXT: Option<UnwindContext<Slice, StaticStorage>> = None;
+/// Set while a backtrace holds `UNWIND_CONTEXT`.
+static UNWIND_CONTEXT_BUSY: AtomicBool = AtomicBool::new(false);
+
 struct EhInfo {
     base_addrs: BaseAddresses,
-    hdr: &'static ParsedEhFrameHdr<EndianSlice<'static, LittleEndian>>,
-    hdr_table: EhHdrTable<'static, EndianSlice<'static, LittleEndian>>,
-    eh_frame: EhFrame<EndianSlice<'static, LittleEndian>>,
+    hdr: ParsedEhFrameHdr<Slice>,
+    eh_frame: EhFrame<Slice>,
 }
 
 impl EhInfo {
-    fn new() -> Self {
+    fn parse() -> Option<Self> {
         let hdr = unsafe { addr_of!(__kernel_eh_frame_hdr) };
-        let hdr_len = (unsafe { addr_of!(__kernel_eh_frame_hdr_end) } as usize) - (hdr as usize);
+        let hdr_len = (unsafe { addr_of!(__kernel_eh_frame_hdr_end) } as usize)
+            - (hdr as usize);
         let eh_frame = unsafe { addr_of!(__kernel_eh_frame) };
-        let eh_frame_len = (unsafe { addr_of!(__kernel_eh_frame_end) } as usize) - (eh_frame as usize);
+        let eh_frame_len = (unsafe { addr_of!(__kernel_eh_frame_end) } as usize)
+            - (eh_frame as usize);
 
         let mut base_addrs = BaseAddresses::default();
         base_addrs = base_addrs.set_eh_frame_hdr(hdr as u64);
 
-        let hdr = Box::leak(Box::new(EhFrameHdr::new( // TODO: remove Box
+        let hdr = EhFrameHdr::new(
             unsafe { slice::from_raw_parts(hdr, hdr_len) },
             LittleEndian,
-        ).parse(&base_addrs, size_of::<usize>() as u8).unwrap()));
+        ).parse(&base_addrs, size_of::<usize>() as u8).ok()?;
+        hdr.table()?;
 
         base_addrs = base_addrs.set_eh_frame(eh_frame as u64);
 
@@ -110,21 +186,68 @@ impl EhInfo {
             LittleEndian,
         );
 
-        Self {
+        Some(Self {
             base_addrs,
             hdr,
-            hdr_table: hdr.table().unwrap(),
             eh_frame,
+        })
+    }
+
+    fn table(&self) -> Option<EhHdrTable<'_, Slice>> {
+        self.hdr.table()
+    }
+}
+
+/// The unwind information, parsed on first use; `None` if it is invalid, or
+/// being parsed, e.g. when panicking while parsing it.
+fn eh_info() -> Option<&'static EhInfo> {
+    match EH_INFO_STATE.compare_exchange(EH_INFO_UNINIT, EH_INFO_PARSING,
+                                         Ordering::Acquire, Ordering::Acquire) {
+        Ok(_) => {
+            unsafe { EH_INFO = EhInfo::parse(); }
+            EH_INFO_STATE.store(EH_INFO_READY, Ordering::Release);
+        },
+        Err(EH_INFO_PARSING) => return None,
+        Err(_) => (),
+    }
+
+    unsafe { (*addr_of!(EH_INFO)).as_ref() }
+}
+
+/// Exclusive access to `UNWIND_CONTEXT`, released on drop.
+struct UnwindContextGuard(&'static mut UnwindContext<Slice, StaticStorage>);
+
+impl UnwindContextGuard {
+    /// Take the unwind context; `None` if another backtrace holds it, which
+    /// happens when one is taken while iterating over another, such as from an
+    /// interrupt handler or when panicking within the unwinder.
+    fn acquire() -> Option<Self> {
+        if UNWIND_CONTEXT_BUSY.swap(true, Ordering::Acquire) {
+            return None;
         }
+
+        let ctx = unsafe { &mut *addr_of_mut!(UNWIND_CONTEXT) }
+            .get_or_insert_with(UnwindContext::new_in);
+        Some(Self(ctx))
+    }
+}
+
+impl Drop for UnwindContextGuard {
+    fn drop(&mut self) {
+        UNWIND_CONTEXT_BUSY.store(false, Ordering::Release);
     }
 }
 
 struct Unwinder {
-    eh_info: EhInfo,
-    unwind_ctx: UnwindContext<EndianSlice<'static, LittleEndian>>,
+    eh_info: Option<&'static EhInfo>,
+    /// Taken on the first unwinding step.
+    unwind_ctx: Option<UnwindContextGuard>,
     regs: RegisterSet,
     cfa: u64,
     is_first: bool,
+    /// The bounds of the stack being unwound, if known; no memory is read
+    /// outside of it.
+    stack: Option<Range<u64>>,
 }
 
 impl Debug for Unwinder {
@@ -138,15 +261,17 @@ impl Debug for Unwinder {
 
 impl Unwinder {
     fn new(
-        eh_info: EhInfo,
+        eh_info: Option<&'static EhInfo>,
         register_set: RegisterSet,
+        stack: Option<Range<u64>>,
     ) -> Self {
         Self {
             eh_info,
-            unwind_ctx: UnwindContext::new(), // TODO: no alloc
+            unwind_ctx: None,
             regs: register_set,
             cfa: 0,
             is_first: true,
+            stack,
         }
     }
 
@@ -158,10 +283,18 @@ impl Unwinder {
             return Ok(Some(pc));
         }
 
-        let row = self.eh_info.hdr_table.unwind_info_for_address(
-            &self.eh_info.eh_frame,
-            &self.eh_info.base_addrs,
-            &mut self.unwind_ctx,
+        let eh_info = self.eh_info.ok_or(UnwinderError::NoUnwindInfo)?;
+        if self.unwind_ctx.is_none() {
+            self.unwind_ctx = UnwindContextGuard::acquire();
+        }
+        let ctx = self.unwind_ctx.as_mut()
+            .ok_or(UnwinderError::UnwindContextBusy)?;
+
+        let table = eh_info.table().ok_or(UnwinderError::NoUnwindInfo)?;
+        let row = table.unwind_info_for_address(
+            &eh_info.eh_frame,
+            &eh_info.base_addrs,
+            &mut *ctx.0,
             pc,
             |section, bases, offset| section.cie_from_offset(bases, offset),
         ).map_err(|_| UnwinderError::NoUnwindInfo)?;
@@ -175,6 +308,13 @@ impl Unwinder {
             _ => return Err(UnwinderError::UnsupportedCfaRule),
         }
 
+        // The CFA is just above the frame, at most the top of the stack.
+        if let Some(stack) = &self.stack {
+            if self.cfa <= stack.start || self.cfa > stack.end {
+                return Err(UnwinderError::OutsideStack);
+            }
+        }
+
         for reg in RegisterSet::iter() {
             match row.register(reg) {
                 RegisterRule::Undefined => {
@@ -201,6 +341,7 @@ impl Unwinder {
 mod arch {
     use gimli::{Register, X86_64};
     use crate::arch::cpu::MachineState;
+    use crate::arch::task::TaskMachineContext;
     use crate::backtrace::UnwinderError;
 
     #[derive(Debug, Default)]
@@ -221,6 +362,15 @@ mod arch {
             }
         }
 
+        pub(super) fn from_task_context(ctx: &TaskMachineContext) -> Self {
+            Self {
+                rip: Some(ctx.rip),
+                rsp: Some(ctx.rsp),
+                rbp: Some(ctx.rbp),
+                ret: None,
+            }
+        }
+
         pub(super) fn get(&self, reg: Register) -> Option<u64> {
             match reg {
                 X86_64::RSP => self.rsp,
@@ -272,4 +422,89 @@ mod arch {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use gimli::{AArch64, Register};
+    use crate::arch::cpu::MachineState;
+    use crate::arch::task::TaskMachineContext;
+    use crate::backtrace::UnwinderError;
+
+    #[derive(Debug, Default)]
+    pub(super) struct RegisterSet {
+        pc: Option<u64>,
+        sp: Option<u64>,
+        fp: Option<u64>,
+        lr: Option<u64>,
+    }
+
+    impl RegisterSet {
+        pub(super) fn from_machine_state(machine: &MachineState) -> Self {
+            Self {
+                pc: Some(machine.pc),
+                sp: Some(machine.sp),
+                fp: Some(machine.x[29]),
+                lr: Some(machine.x[30]),
+            }
+        }
+
+        pub(super) fn from_task_context(ctx: &TaskMachineContext) -> Self {
+            Self {
+                pc: Some(ctx.pc),
+                sp: Some(ctx.sp),
+                fp: Some(ctx.x[29]),
+                lr: Some(ctx.x[30]),
+            }
+        }
+
+        pub(super) fn get(&self, reg: Register) -> Option<u64> {
+            match reg {
+                AArch64::SP => self.sp,
+                AArch64::X29 => self.fp,
+                AArch64::X30 => self.lr,
+                _ => None,
+            }
+        }
+
+        pub(super) fn set(&mut self, reg: Register, val: u64) -> Result<(), UnwinderError> {
+            *match reg {
+                AArch64::SP => &mut self.sp,
+                AArch64::X29 => &mut self.fp,
+                AArch64::X30 => &mut self.lr,
+                _ => return Err(UnwinderError::UnexpectedRegister(reg)),
+            } = Some(val);
+
+            Ok(())
+        }
+
+        pub(super) fn undef(&mut self, reg: Register) {
+            *match reg {
+                AArch64::SP => &mut self.sp,
+                AArch64::X29 => &mut self.fp,
+                AArch64::X30 => &mut self.lr,
+                _ => return,
+            } = None;
+        }
+
+        pub(super) fn get_pc(&self) -> Option<u64> {
+            self.pc
+        }
+
+        pub(super) fn set_pc(&mut self, val: u64) {
+            self.pc = Some(val);
+        }
+
+        pub(super) fn get_ret(&self) -> Option<u64> {
+            self.lr
+        }
+
+        pub(super) fn set_stack_ptr(&mut self, val: u64) {
+            self.sp = Some(val);
+        }
+
+        pub(super) fn iter() -> impl Iterator<Item=Register> {
+            [AArch64::SP, AArch64::X29, AArch64::X30].into_iter()
+        }
+    }
+}
+
 use arch::RegisterSet;
@@ -0,0 +1,264 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel command line, as given by the bootloader. It is a list of
+//! whitespace-separated options, each either a flag, e.g. `nopat`, or a
+//! `key=value` pair, e.g. `loglevel=info`; when an option is repeated, the last
+//! one wins.
+//!
+//! The line is copied into the boot information during early boot, and can be
+//! read from then on by any subsystem. Options that are not understood are ignored, so that the
+//! same line can be shared with other kernels.
+
+use core::str::FromStr;
+use arrayvec::ArrayString;
+
+use crate::boot;
+use crate::logging::Severity;
+#[cfg(feature = "net")]
+use crate::net::ipv4::{Ipv4Address, Ipv4Cidr};
+#[cfg(feature = "net")]
+use crate::net::syslog::SYSLOG_PORT;
+#[cfg(feature = "net")]
+use crate::net::udp::Endpoint;
+use crate::warning;
+
+/// The maximum length of the stored command line; options that don't fit
+/// entirely are dropped.
+pub const CMDLINE_MAX: usize = 1024;
+
+/// The serial line's baud rate when not set by `serial=`.
+pub const DEFAULT_SERIAL_BAUD: u32 = 115200;
+
+/// An option of the command line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BootOption<'a> {
+    Flag(&'a str),
+    Value(&'a str, &'a str),
+}
+
+/// Where the kernel log and the shell are shown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Console {
+    /// The kernel terminal, on screen; the serial line still gets the early
+    /// boot log.
+    Terminal,
+    /// The serial line only.
+    Serial,
+}
+
+impl FromStr for Console {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tty" => Ok(Console::Terminal),
+            "serial" => Ok(Console::Serial),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct CmdLine {
+    line: ArrayString<CMDLINE_MAX>,
+}
+
+impl CmdLine {
+    pub const fn new() -> Self {
+        Self {
+            line: ArrayString::new_const(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Self {
+        let mut cmdline = Self::new();
+
+        for option in line.split_whitespace() {
+            let sep = if cmdline.line.is_empty() { "" } else { " " };
+            if cmdline.line.len() + sep.len() + option.len() > CMDLINE_MAX {
+                continue;
+            }
+            cmdline.line.push_str(sep);
+            cmdline.line.push_str(option);
+        }
+
+        cmdline
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+
+    /// All the options, in order.
+    pub fn options(&self) -> impl Iterator<Item = BootOption<'_>> {
+        self.line.split(' ')
+            .filter(|option| !option.is_empty())
+            .map(|option| match option.split_once('=') {
+                Some((key, value)) => BootOption::Value(key, value),
+                None => BootOption::Flag(option),
+            })
+    }
+
+    /// The value of the last `key=value` option.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.options()
+            .filter_map(|option| match option {
+                BootOption::Value(k, value) if k == key => Some(value),
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Whether the flag `name` is present.
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.options().any(|option| option == BootOption::Flag(name))
+    }
+
+    /// The value of `key` parsed as a `T`; an invalid value is warned about
+    /// and ignored.
+    pub fn parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        let value = self.value(key)?;
+
+        match value.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                warning!("invalid value '{value}' for boot option '{key}'");
+                None
+            },
+        }
+    }
+
+    /// The least severe log messages to print, from `loglevel=`.
+    pub fn log_level(&self) -> Option<Severity> {
+        let label = self.value("loglevel")?;
+        let severity = Severity::from_label(label);
+        if severity.is_none() {
+            warning!("invalid log level '{label}'");
+        }
+        severity
+    }
+
+    /// The serial line's baud rate, from `serial=`.
+    pub fn serial_baud(&self) -> u32 {
+        self.parsed("serial").unwrap_or(DEFAULT_SERIAL_BAUD)
+    }
+
+    /// The console, from `console=`.
+    pub fn console(&self) -> Console {
+        self.parsed("console").unwrap_or(Console::Terminal)
+    }
+
+    /// The name of the keymap to load instead of the default one, from
+    /// `keymap=`.
+    pub fn keymap(&self) -> Option<&str> {
+        self.value("keymap")
+    }
+
+    /// Whether to keep the other CPUs offline, from the `nosmp` flag.
+    pub fn nosmp(&self) -> bool {
+        self.has_flag("nosmp")
+    }
+
+    /// Whether to leave the PAT as set by the firmware, from the `nopat` flag.
+    pub fn nopat(&self) -> bool {
+        self.has_flag("nopat")
+    }
+
+    /// The IPv4 address of the first network device, from `ip=`, e.g.
+    /// `ip=10.0.2.15/24`.
+    #[cfg(feature = "net")]
+    pub fn ip(&self) -> Option<Ipv4Cidr> {
+        self.parsed("ip")
+    }
+
+    /// The default gateway, from `gateway=`.
+    #[cfg(feature = "net")]
+    pub fn gateway(&self) -> Option<Ipv4Address> {
+        self.parsed("gateway")
+    }
+
+    /// The syslog collector to ship the kernel log to, from `syslog=`; the
+    /// port defaults to `SYSLOG_PORT`.
+    #[cfg(feature = "net")]
+    pub fn syslog(&self) -> Option<Endpoint> {
+        let value = self.value("syslog")?;
+        if value.contains(':') {
+            return self.parsed("syslog");
+        }
+
+        self.parsed("syslog")
+            .map(|addr| Endpoint { addr, port: SYSLOG_PORT })
+    }
+
+    /// The TCP port to serve the kernel shell on, from `kshell=`.
+    pub fn kshell(&self) -> Option<u16> {
+        self.parsed("kshell")
+    }
+
+    /// Whether to stream the kernel log over the first network device, from
+    /// the `netlog` flag.
+    pub fn netlog(&self) -> bool {
+        self.has_flag("netlog")
+    }
+}
+
+/// The kernel command line; empty if the bootloader gave none.
+pub fn get() -> &'static CmdLine {
+    &boot::info().cmdline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_flags_and_values() {
+        let cmdline = CmdLine::parse("  nopat loglevel=info\tinit=/bin/sh \
+                                     loglevel=warn console=serial");
+
+        assert!(cmdline.nopat());
+        assert!(!cmdline.nosmp());
+        assert_eq!(cmdline.value("init"), Some("/bin/sh"));
+        assert_eq!(cmdline.value("keymap"), None);
+        assert!(matches!(cmdline.log_level(), Some(Severity::Warning)));
+        assert_eq!(cmdline.console(), Console::Serial);
+        assert_eq!(cmdline.options().next(), Some(BootOption::Flag("nopat")));
+    }
+
+    #[test]
+    fn it_falls_back_on_invalid_values() {
+        let cmdline = CmdLine::parse("serial=fast console=lcd");
+
+        assert_eq!(cmdline.serial_baud(), DEFAULT_SERIAL_BAUD);
+        assert_eq!(cmdline.console(), Console::Terminal);
+        assert_eq!(CmdLine::parse("serial=9600").serial_baud(), 9600);
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn it_parses_network_options() {
+        let collector = Ipv4Address([10, 0, 2, 2]);
+
+        assert_eq!(CmdLine::parse("syslog=10.0.2.2").syslog(),
+                   Some(Endpoint { addr: collector, port: SYSLOG_PORT }));
+        assert_eq!(CmdLine::parse("syslog=10.0.2.2:1514").syslog(),
+                   Some(Endpoint { addr: collector, port: 1514 }));
+        assert_eq!(CmdLine::parse("ip=10.0.2.15").ip(), None);
+        assert_eq!(CmdLine::parse("kshell=23").kshell(), Some(23));
+    }
+
+    #[test]
+    fn it_drops_options_that_dont_fit() {
+        let long = "x".repeat(CMDLINE_MAX);
+        let cmdline = CmdLine::parse(&alloc::format!("a=1 {long} b"));
+
+        assert_eq!(cmdline.as_str(), "a=1 b");
+    }
+}
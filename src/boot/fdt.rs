@@ -0,0 +1,288 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A reader for the flattened device tree (FDT), the blob describing the
+//! machine that firmwares and bootloaders hand over on platforms without ACPI,
+//! such as QEMU's ARM virt. Nodes are looked up by path, e.g. `/chosen`; a
+//! path component without a unit address matches any, so `/memory` matches
+//! `/memory@40000000`.
+
+use core::str;
+use thiserror_no_std::Error;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FdtError {
+    #[error("invalid magic number")]
+    BadMagic,
+
+    #[error("truncated device tree")]
+    Truncated,
+}
+
+pub struct DeviceTree<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> DeviceTree<'a> {
+    /// Check the header of the device tree in `data`, which may be longer than
+    /// the tree itself.
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        if data.len() < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if be32(data, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+
+        let header = |index: usize| be32(data, index * 4).unwrap() as usize;
+        let data = data.get(..header(1)).ok_or(FdtError::Truncated)?;
+        let section = |offset: usize, size: usize| {
+            offset.checked_add(size)
+                .and_then(|end| data.get(offset..end))
+                .ok_or(FdtError::Truncated)
+        };
+
+        Ok(Self {
+            structs: section(header(2), header(9))?,
+            strings: section(header(3), header(8))?,
+        })
+    }
+
+    /// The size in bytes of the device tree whose header is `header`, read
+    /// before the whole tree can be accessed; `None` if it isn't one.
+    pub fn total_size(header: &[u8; HEADER_SIZE]) -> Option<usize> {
+        (be32(header, 0)? == FDT_MAGIC)
+            .then(|| be32(header, 4).unwrap() as usize)
+    }
+
+    /// The value of the property `name` of the node at `path`.
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let nr_components = path.split('/').filter(|c| !c.is_empty()).count();
+        let mut depth = 0;
+        // The depth of the deepest node on the current branch matching the
+        // start of `path`.
+        let mut matched = 0;
+        let mut offset = 0;
+
+        loop {
+            let token = be32(self.structs, offset)?;
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node_name = c_str(self.structs.get(offset..)?)?;
+                    offset += align4(node_name.len() + 1);
+                    depth += 1;
+
+                    if depth == 1 {
+                        matched = 1;
+                    } else if matched == depth - 1 {
+                        let component = path.split('/')
+                            .filter(|c| !c.is_empty())
+                            .nth(depth - 2);
+                        if component.is_some_and(
+                            |c| node_matches(c, node_name)) {
+                            matched = depth;
+                        }
+                    }
+                },
+                FDT_END_NODE => {
+                    if matched == depth {
+                        matched -= 1;
+                    }
+                    depth = depth.checked_sub(1)?;
+                },
+                FDT_PROP => {
+                    let len = be32(self.structs, offset)? as usize;
+                    let name_offset = be32(self.structs, offset + 4)? as usize;
+                    let value = self.structs
+                        .get((offset + 8)..(offset + 8 + len))?;
+                    offset += 8 + align4(len);
+
+                    if matched == depth && depth == nr_components + 1
+                        && c_str(self.strings.get(name_offset..)?)? == name {
+                        return Some(value);
+                    }
+                },
+                FDT_NOP => (),
+                // FDT_END, or an invalid token.
+                _ => return None,
+            }
+        }
+    }
+
+    /// The value of the property `name` of the node at `path`, as a string.
+    pub fn property_str(&self, path: &str, name: &str) -> Option<&'a str> {
+        c_str(self.property(path, name)?)
+    }
+
+    /// The value of the property `name` of the node at `path`, as a single
+    /// 32-bit cell.
+    pub fn property_u32(&self, path: &str, name: &str) -> Option<u32> {
+        let value = self.property(path, name)?;
+        (value.len() == 4).then(|| be32(value, 0).unwrap())
+    }
+
+    /// The first physical memory range of the `/memory` node, as its start
+    /// address and size.
+    pub fn memory(&self) -> Option<(u64, u64)> {
+        let address_cells = self.property_u32("/", "#address-cells")
+            .unwrap_or(2);
+        let size_cells = self.property_u32("/", "#size-cells").unwrap_or(1);
+        let reg = self.property("/memory", "reg")?;
+
+        let start = cells(reg, 0, address_cells as usize)?;
+        let size = cells(reg, address_cells as usize, size_cells as usize)?;
+
+        Some((start, size))
+    }
+
+    /// The kernel command line, in the `bootargs` property of `/chosen`.
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.property_str("/chosen", "bootargs")
+    }
+}
+
+fn node_matches(component: &str, node_name: &str) -> bool {
+    if component.contains('@') {
+        component == node_name
+    } else {
+        node_name.split('@').next() == Some(component)
+    }
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read the `nr_cells` 32-bit cells starting at cell `index` as a number.
+fn cells(data: &[u8], index: usize, nr_cells: usize) -> Option<u64> {
+    (index..(index + nr_cells)).try_fold(0u64, |value, i| {
+        Some(value.checked_shl(32).unwrap_or(0) | be32(data, i * 4)? as u64)
+    })
+}
+
+/// The NUL-terminated string at the start of `data`.
+fn c_str(data: &[u8]) -> Option<&str> {
+    let len = data.iter().position(|&b| b == 0)?;
+    str::from_utf8(&data[..len]).ok()
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    fn push_node(structs: &mut Vec<u8>, name: &str) {
+        structs.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        structs.extend_from_slice(name.as_bytes());
+        structs.resize(structs.len() + align4(name.len() + 1) - name.len(), 0);
+    }
+
+    fn push_end_node(structs: &mut Vec<u8>) {
+        structs.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    }
+
+    fn push_prop(
+        structs: &mut Vec<u8>,
+        strings: &mut Vec<u8>,
+        name: &str,
+        value: &[u8],
+    ) {
+        structs.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structs.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structs.extend_from_slice(&(strings.len() as u32).to_be_bytes());
+        structs.extend_from_slice(value);
+        structs.resize(structs.len() + align4(value.len()) - value.len(), 0);
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+    }
+
+    /// Build a device tree with the nodes `/`, `/chosen`, `/memory@40000000`
+    /// and `/memory@40000000/sub`.
+    fn build_tree() -> Vec<u8> {
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+
+        push_node(&mut structs, "");
+        push_prop(&mut structs, &mut strings, "#address-cells", &[0, 0, 0, 2]);
+        push_prop(&mut structs, &mut strings, "#size-cells", &[0, 0, 0, 2]);
+
+        push_node(&mut structs, "chosen");
+        push_prop(&mut structs, &mut strings, "bootargs", b"loglevel=debug\0");
+        push_end_node(&mut structs);
+
+        push_node(&mut structs, "memory@40000000");
+        push_prop(&mut structs, &mut strings, "reg",
+                  &[0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0]);
+        push_node(&mut structs, "sub");
+        push_prop(&mut structs, &mut strings, "bootargs", b"wrong\0");
+        push_end_node(&mut structs);
+        push_end_node(&mut structs);
+
+        push_end_node(&mut structs);
+        structs.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let structs_offset = HEADER_SIZE;
+        let strings_offset = structs_offset + structs.len();
+        let total_size = strings_offset + strings.len();
+        let header = [
+            FDT_MAGIC, total_size as u32, structs_offset as u32,
+            strings_offset as u32, 0, 17, 16, 0, strings.len() as u32,
+            structs.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        blob.append(&mut structs);
+        blob.append(&mut strings);
+        blob
+    }
+
+    #[test]
+    fn it_finds_properties_by_path() {
+        let blob = build_tree();
+        let tree = DeviceTree::new(&blob).unwrap();
+
+        assert_eq!(tree.bootargs(), Some("loglevel=debug"));
+        assert_eq!(tree.memory(), Some((0x4000_0000, 0x0800_0000)));
+        assert_eq!(tree.property_str("/memory/sub", "bootargs"), Some("wrong"));
+        assert_eq!(tree.property("/memory", "bootargs"), None);
+        assert_eq!(tree.property("/psci", "method"), None);
+    }
+
+    #[test]
+    fn it_rejects_invalid_trees() {
+        let mut blob = build_tree();
+
+        assert_eq!(DeviceTree::new(&blob[..20]).err(),
+                   Some(FdtError::Truncated));
+        assert_eq!(DeviceTree::new(&blob[..60]).err(),
+                   Some(FdtError::Truncated));
+        blob[0] = 0;
+        assert_eq!(DeviceTree::new(&blob).err(), Some(FdtError::BadMagic));
+    }
+}
@@ -0,0 +1,160 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! What the bootloader tells the kernel. The bootloader's structures live in
+//! memory that the kernel reclaims early, e.g. for its page tables, so all
+//! that is needed is deep-copied into the `BootInfo` singleton during early
+//! boot, before the memory management is set up.
+
+use core::ops::Range;
+use core::ptr::addr_of;
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::boot::cmdline::CmdLine;
+use crate::driver::acpi::RootTable;
+use crate::driver::screen::FramebufferMode;
+use crate::mem::{get_lowmem_va_end, PAddr};
+
+pub mod cmdline;
+pub mod fdt;
+
+/// The maximum number of boot modules kept; any further one is ignored.
+pub const MAX_MODULES: usize = 16;
+
+/// The maximum length of a boot module's command line; longer ones are
+/// truncated.
+pub const MODULE_CMDLINE_MAX: usize = 128;
+
+static mut BOOT_INFO: BootInfo = BootInfo::new();
+
+pub struct BootInfo {
+    pub cmdline: CmdLine,
+    pub modules: ArrayVec<BootModule, MAX_MODULES>,
+    /// The ACPI root table, if the bootloader found one.
+    pub acpi_root: Option<RootTable>,
+    pub framebuffer: Option<BootFramebuffer>,
+    /// The kernel's symbol table, if the bootloader loaded it.
+    pub symbols: Option<BootSymbols>,
+}
+
+impl BootInfo {
+    pub const fn new() -> Self {
+        Self {
+            cmdline: CmdLine::new(),
+            modules: ArrayVec::new_const(),
+            acpi_root: None,
+            framebuffer: None,
+            symbols: None,
+        }
+    }
+
+    /// The physical address past the last boot module or the kernel's symbol
+    /// table, whose memory must not be reused; zero if there is none.
+    pub fn loaded_end(&self) -> PAddr {
+        let modules = self.modules.iter().map(|module| module.end.0);
+        let symbols = self.symbols.iter()
+            .flat_map(|symbols| [symbols.symtab.end.0, symbols.strtab.end.0]);
+
+        PAddr(modules.chain(symbols).max().unwrap_or(0))
+    }
+}
+
+/// A file loaded into memory by the bootloader along with the kernel.
+pub struct BootModule {
+    pub start: PAddr,
+    pub end: PAddr,
+    /// The command line the module was given, usually its name.
+    pub cmdline: ArrayString<MODULE_CMDLINE_MAX>,
+}
+
+impl BootModule {
+    /// The content of the module; `None` if it lies outside low memory.
+    pub fn data(&self) -> Option<&'static [u8]> {
+        lowmem_slice(self.start..self.end)
+    }
+}
+
+/// The `.symtab` and `.strtab` sections of the kernel image, which the
+/// bootloader loads apart from the image itself, see `ksyms`.
+pub struct BootSymbols {
+    /// The symbols, an array of ELF64 symbol entries.
+    pub symtab: Range<PAddr>,
+    /// The symbols' names.
+    pub strtab: Range<PAddr>,
+}
+
+impl BootSymbols {
+    /// The content of the `.symtab` and `.strtab` sections; `None` if they lie
+    /// outside low memory.
+    pub fn data(&self) -> Option<(&'static [u8], &'static [u8])> {
+        Some((lowmem_slice(self.symtab.clone())?,
+              lowmem_slice(self.strtab.clone())?))
+    }
+}
+
+/// The low memory of the physical `range`; `None` if it lies outside.
+fn lowmem_slice(range: Range<PAddr>) -> Option<&'static [u8]> {
+    let start = range.start.into_vaddr();
+    let end = range.end.into_vaddr();
+    if end > get_lowmem_va_end() || end < start {
+        return None;
+    }
+
+    Some(unsafe {
+        core::slice::from_raw_parts(start.as_ptr(), (end - start).0)
+    })
+}
+
+/// The screen set up by the bootloader.
+pub enum BootFramebuffer {
+    /// A linear framebuffer in a direct color mode.
+    Direct {
+        addr: PAddr,
+        mode: FramebufferMode,
+    },
+    /// A linear framebuffer whose pixels are indices into `palette`.
+    Indexed {
+        addr: PAddr,
+        width: usize,
+        height: usize,
+        pitch: usize,
+        bpp: u8,
+        palette: ArrayVec<PaletteColor, 256>,
+    },
+    /// A text mode, with the address of its memory, and its number of columns
+    /// and rows.
+    Text {
+        addr: PAddr,
+        columns: u8,
+        rows: u8,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PaletteColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/// Store the information collected from the bootloader.
+///
+/// # Safety #
+///
+/// Must be called during the early boot process, with only one CPU running and
+/// before `info()` is.
+pub unsafe fn init(info: BootInfo) {
+    BOOT_INFO = info;
+}
+
+/// The information given by the bootloader.
+pub fn info() -> &'static BootInfo {
+    unsafe { &*addr_of!(BOOT_INFO) }
+}
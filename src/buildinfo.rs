@@ -0,0 +1,24 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Identification of the running kernel build. The build ID is computed by the
+//! build script from the sources; it is printed at boot and in every panic
+//! report, so that a crash can be matched with the exact kernel image.
+
+/// The build ID, formatted as a UUID.
+pub const BUILD_ID: &str = env!("NUCLOID_BUILD_ID");
+
+/// The build ID as found in the kernel image, so that it can be retrieved from
+/// the file or a memory dump, e.g. with `strings vmnucloid | grep build-id`.
+#[used]
+#[no_mangle]
+static NUCLOID_BUILD_ID_TAG: &str
+    = concat!("nucloid-build-id:", env!("NUCLOID_BUILD_ID"));
+
@@ -0,0 +1,358 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// The link embedded in the elements of a `List`.
+pub struct Link<T> {
+    prev: Option<NonNull<T>>,
+    next: Option<NonNull<T>>,
+}
+
+impl<T> Link<T> {
+    pub const fn new() -> Self {
+        Self {
+            prev: None,
+            next: None,
+        }
+    }
+}
+
+impl<T> Default for Link<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tell how to reach the `Link` inside the elements of a `List`.
+pub trait ListAdapter {
+    type Node;
+
+    /// Return a pointer to the link of `node`, which must be valid.
+    fn link(node: NonNull<Self::Node>) -> NonNull<Link<Self::Node>>;
+}
+
+/// An intrusive doubly-linked list. The list doesn't own its elements: the
+/// caller is responsible for keeping them alive, and at the same address, for
+/// as long as they are linked.
+pub struct List<A: ListAdapter> {
+    head: Option<NonNull<A::Node>>,
+    tail: Option<NonNull<A::Node>>,
+    len: usize,
+    _marker: PhantomData<A>,
+}
+
+unsafe impl<A: ListAdapter> Send for List<A> where A::Node: Send {}
+
+impl<A: ListAdapter> List<A> {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn front(&self) -> Option<NonNull<A::Node>> {
+        self.head
+    }
+
+    pub fn back(&self) -> Option<NonNull<A::Node>> {
+        self.tail
+    }
+
+    /// Insert `node` at the start of the list.
+    ///
+    /// # Safety #
+    ///
+    /// `node` must be valid and not already linked through this adapter; it
+    /// must remain valid and must not move until it is removed.
+    pub unsafe fn push_front(&mut self, node: NonNull<A::Node>) {
+        self.insert_between(node, None, self.head);
+    }
+
+    /// Insert `node` at the end of the list; see `push_front()`.
+    ///
+    /// # Safety #
+    ///
+    /// Same as `push_front()`.
+    pub unsafe fn push_back(&mut self, node: NonNull<A::Node>) {
+        self.insert_between(node, self.tail, None);
+    }
+
+    pub fn pop_front(&mut self) -> Option<NonNull<A::Node>> {
+        let node = self.head?;
+        unsafe { self.remove(node); }
+        Some(node)
+    }
+
+    pub fn pop_back(&mut self) -> Option<NonNull<A::Node>> {
+        let node = self.tail?;
+        unsafe { self.remove(node); }
+        Some(node)
+    }
+
+    /// Unlink `node` from the list.
+    ///
+    /// # Safety #
+    ///
+    /// `node` must be linked in this very list.
+    pub unsafe fn remove(&mut self, node: NonNull<A::Node>) {
+        let link = A::link(node).as_mut();
+        let (prev, next) = (link.prev.take(), link.next.take());
+
+        match prev {
+            Some(prev) => A::link(prev).as_mut().next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => A::link(next).as_mut().prev = prev,
+            None => self.tail = prev,
+        }
+
+        self.len -= 1;
+    }
+
+    /// Iterate over the elements, from front to back.
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            next: self.head,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return a cursor on the first element, or on nothing if the list is
+    /// empty.
+    pub fn cursor_front_mut(&mut self) -> CursorMut<'_, A> {
+        CursorMut {
+            current: self.head,
+            list: self,
+        }
+    }
+
+    /// Return a cursor on the last element, or on nothing if the list is empty.
+    pub fn cursor_back_mut(&mut self) -> CursorMut<'_, A> {
+        CursorMut {
+            current: self.tail,
+            list: self,
+        }
+    }
+
+    unsafe fn insert_between(
+        &mut self,
+        node: NonNull<A::Node>,
+        prev: Option<NonNull<A::Node>>,
+        next: Option<NonNull<A::Node>>,
+    ) {
+        let link = A::link(node).as_mut();
+        link.prev = prev;
+        link.next = next;
+
+        match prev {
+            Some(prev) => A::link(prev).as_mut().next = Some(node),
+            None => self.head = Some(node),
+        }
+        match next {
+            Some(next) => A::link(next).as_mut().prev = Some(node),
+            None => self.tail = Some(node),
+        }
+
+        self.len += 1;
+    }
+}
+
+impl<A: ListAdapter> Default for List<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Iter<'a, A: ListAdapter> {
+    next: Option<NonNull<A::Node>>,
+    _marker: PhantomData<&'a List<A>>,
+}
+
+impl<'a, A: ListAdapter> Iterator for Iter<'a, A> {
+    type Item = NonNull<A::Node>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?;
+        self.next = unsafe { A::link(node).as_ref() }.next;
+        Some(node)
+    }
+}
+
+/// A position in a `List` from which elements can be inserted or removed.
+/// Past the last element or before the first one, the cursor points to nothing
+/// and moving it again wraps around.
+pub struct CursorMut<'a, A: ListAdapter> {
+    list: &'a mut List<A>,
+    current: Option<NonNull<A::Node>>,
+}
+
+impl<'a, A: ListAdapter> CursorMut<'a, A> {
+    pub fn current(&self) -> Option<NonNull<A::Node>> {
+        self.current
+    }
+
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            Some(node) => unsafe { A::link(node).as_ref() }.next,
+            None => self.list.head,
+        };
+    }
+
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            Some(node) => unsafe { A::link(node).as_ref() }.prev,
+            None => self.list.tail,
+        };
+    }
+
+    /// Insert `node` before the current element, or at the end of the list if
+    /// the cursor points to nothing.
+    ///
+    /// # Safety #
+    ///
+    /// Same as `List::push_front()`.
+    pub unsafe fn insert_before(&mut self, node: NonNull<A::Node>) {
+        let prev = match self.current {
+            Some(current) => A::link(current).as_ref().prev,
+            None => self.list.tail,
+        };
+        self.list.insert_between(node, prev, self.current);
+    }
+
+    /// Insert `node` after the current element, or at the start of the list if
+    /// the cursor points to nothing.
+    ///
+    /// # Safety #
+    ///
+    /// Same as `List::push_front()`.
+    pub unsafe fn insert_after(&mut self, node: NonNull<A::Node>) {
+        let next = match self.current {
+            Some(current) => A::link(current).as_ref().next,
+            None => self.list.head,
+        };
+        self.list.insert_between(node, self.current, next);
+    }
+
+    /// Unlink the current element and move to the next one; return the removed
+    /// element, `None` if the cursor points to nothing.
+    pub fn remove_current(&mut self) -> Option<NonNull<A::Node>> {
+        let node = self.current?;
+        self.current = unsafe { A::link(node).as_ref() }.next;
+        unsafe { self.list.remove(node); }
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use core::ptr::addr_of_mut;
+    use super::*;
+
+    struct Item {
+        value: u32,
+        link: Link<Item>,
+    }
+
+    struct ItemAdapter;
+
+    impl ListAdapter for ItemAdapter {
+        type Node = Item;
+
+        fn link(node: NonNull<Item>) -> NonNull<Link<Item>> {
+            unsafe { NonNull::new_unchecked(addr_of_mut!((*node.as_ptr()).link)) }
+        }
+    }
+
+    fn items<const N: usize>() -> [Item; N] {
+        core::array::from_fn(|i| Item { value: i as u32, link: Link::new() })
+    }
+
+    fn values(list: &List<ItemAdapter>) -> Vec<u32> {
+        list.iter().map(|item| unsafe { item.as_ref() }.value).collect()
+    }
+
+    #[test]
+    fn it_pushes_and_pops_at_both_ends() {
+        let mut items = items::<3>();
+        let mut list = List::<ItemAdapter>::new();
+
+        unsafe {
+            list.push_back(NonNull::from(&mut items[1]));
+            list.push_back(NonNull::from(&mut items[2]));
+            list.push_front(NonNull::from(&mut items[0]));
+        }
+        assert_eq!(values(&list), [0, 1, 2]);
+        assert_eq!(list.len(), 3);
+
+        assert_eq!(list.pop_front(), Some(NonNull::from(&mut items[0])));
+        assert_eq!(list.pop_back(), Some(NonNull::from(&mut items[2])));
+        assert_eq!(values(&list), [1]);
+        assert_eq!(list.pop_back(), Some(NonNull::from(&mut items[1])));
+        assert!(list.is_empty());
+        assert_eq!(list.pop_front(), None);
+    }
+
+    #[test]
+    fn it_removes_from_the_middle() {
+        let mut items = items::<3>();
+        let mut list = List::<ItemAdapter>::new();
+
+        unsafe {
+            for item in &mut items {
+                list.push_back(NonNull::from(item));
+            }
+            list.remove(NonNull::from(&mut items[1]));
+        }
+
+        assert_eq!(values(&list), [0, 2]);
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn it_inserts_and_removes_through_a_cursor() {
+        let mut items = items::<4>();
+        let mut list = List::<ItemAdapter>::new();
+
+        unsafe {
+            list.push_back(NonNull::from(&mut items[0]));
+            list.push_back(NonNull::from(&mut items[2]));
+
+            let mut cursor = list.cursor_front_mut();
+            cursor.insert_after(NonNull::from(&mut items[1]));
+            cursor.move_next();
+            cursor.move_next();
+            cursor.move_next();
+            assert_eq!(cursor.current(), None);
+            cursor.insert_before(NonNull::from(&mut items[3]));
+        }
+        assert_eq!(values(&list), [0, 1, 2, 3]);
+
+        let mut cursor = list.cursor_front_mut();
+        cursor.move_next();
+        assert_eq!(cursor.remove_current(), Some(NonNull::from(&mut items[1])));
+        assert_eq!(cursor.current(), Some(NonNull::from(&mut items[2])));
+        assert_eq!(values(&list), [0, 2, 3]);
+    }
+}
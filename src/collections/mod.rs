@@ -0,0 +1,24 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Intrusive collections: the links are embedded in the elements themselves,
+//! so that inserting or removing an element never allocates. This is what the
+//! scheduler, the memory management and the timers use to keep track of
+//! objects they don't own.
+//!
+//! Each collection is parameterized by an *adapter*, a type which tells how to
+//! reach the link inside an element; an element can thus belong to several
+//! collections at once, through distinct links and adapters.
+
+pub mod list;
+pub mod rbtree;
+
+pub use list::{Link, List, ListAdapter};
+pub use rbtree::{RbTree, TreeAdapter, TreeLink};
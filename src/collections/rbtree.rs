@@ -0,0 +1,605 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Color {
+    Red,
+    Black,
+}
+
+/// The link embedded in the elements of an `RbTree`.
+pub struct TreeLink<T> {
+    parent: Option<NonNull<T>>,
+    left: Option<NonNull<T>>,
+    right: Option<NonNull<T>>,
+    color: Color,
+}
+
+impl<T> TreeLink<T> {
+    pub const fn new() -> Self {
+        Self {
+            parent: None,
+            left: None,
+            right: None,
+            color: Color::Red,
+        }
+    }
+}
+
+impl<T> Default for TreeLink<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tell how to reach the `TreeLink` inside the elements of an `RbTree`, and
+/// the key by which they are ordered.
+pub trait TreeAdapter {
+    type Node;
+    type Key: Ord;
+
+    /// Return a pointer to the link of `node`, which must be valid.
+    fn link(node: NonNull<Self::Node>) -> NonNull<TreeLink<Self::Node>>;
+
+    /// The key of `node`; it must not change while the node is in a tree.
+    fn key(node: &Self::Node) -> &Self::Key;
+}
+
+/// An intrusive red-black tree, keeping its elements ordered by key. Several
+/// elements may have the same key, they are then kept in insertion order. Like
+/// `List`, the tree doesn't own its elements.
+pub struct RbTree<A: TreeAdapter> {
+    root: Option<NonNull<A::Node>>,
+    len: usize,
+    _marker: PhantomData<A>,
+}
+
+unsafe impl<A: TreeAdapter> Send for RbTree<A> where A::Node: Send {}
+
+impl<A: TreeAdapter> RbTree<A> {
+    pub const fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// The element with the smallest key.
+    pub fn first(&self) -> Option<NonNull<A::Node>> {
+        self.root.map(|root| Self::leftmost(root))
+    }
+
+    /// The element with the greatest key.
+    pub fn last(&self) -> Option<NonNull<A::Node>> {
+        let mut node = self.root?;
+        while let Some(right) = Self::link(node).right {
+            node = right;
+        }
+        Some(node)
+    }
+
+    /// Return an element whose key is `key`, if any.
+    pub fn find(&self, key: &A::Key) -> Option<NonNull<A::Node>> {
+        let mut curr = self.root;
+
+        while let Some(node) = curr {
+            let node_key = A::key(unsafe { node.as_ref() });
+            if key == node_key {
+                return Some(node);
+            }
+            curr = if key < node_key {
+                Self::link(node).left
+            } else {
+                Self::link(node).right
+            };
+        }
+
+        None
+    }
+
+    /// Return the element with the greatest key less than or equal to `key`;
+    /// this is the one to look at first to find which range contains `key`.
+    pub fn floor(&self, key: &A::Key) -> Option<NonNull<A::Node>> {
+        let mut curr = self.root;
+        let mut found = None;
+
+        while let Some(node) = curr {
+            if A::key(unsafe { node.as_ref() }) <= key {
+                found = Some(node);
+                curr = Self::link(node).right;
+            } else {
+                curr = Self::link(node).left;
+            }
+        }
+
+        found
+    }
+
+    /// The element following `node` in key order.
+    pub fn next(&self, node: NonNull<A::Node>) -> Option<NonNull<A::Node>> {
+        if let Some(right) = Self::link(node).right {
+            return Some(Self::leftmost(right));
+        }
+
+        let mut node = node;
+        while let Some(parent) = Self::link(node).parent {
+            if Self::link(parent).left == Some(node) {
+                return Some(parent);
+            }
+            node = parent;
+        }
+
+        None
+    }
+
+    /// The element preceding `node` in key order.
+    pub fn prev(&self, node: NonNull<A::Node>) -> Option<NonNull<A::Node>> {
+        if let Some(mut left) = Self::link(node).left {
+            while let Some(right) = Self::link(left).right {
+                left = right;
+            }
+            return Some(left);
+        }
+
+        let mut node = node;
+        while let Some(parent) = Self::link(node).parent {
+            if Self::link(parent).right == Some(node) {
+                return Some(parent);
+            }
+            node = parent;
+        }
+
+        None
+    }
+
+    /// Iterate over the elements in key order.
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            tree: self,
+            next: self.first(),
+        }
+    }
+
+    /// Insert `node` into the tree.
+    ///
+    /// # Safety #
+    ///
+    /// `node` must be valid and not already linked through this adapter; it
+    /// must remain valid and must not move until it is removed.
+    pub unsafe fn insert(&mut self, node: NonNull<A::Node>) {
+        let key = A::key(node.as_ref());
+        let mut parent = None;
+        let mut curr = self.root;
+        let mut is_left = false;
+
+        while let Some(c) = curr {
+            parent = Some(c);
+            is_left = key < A::key(c.as_ref());
+            curr = if is_left {
+                Self::link(c).left
+            } else {
+                Self::link(c).right
+            };
+        }
+
+        *Self::link(node) = TreeLink {
+            parent,
+            left: None,
+            right: None,
+            color: Color::Red,
+        };
+
+        match parent {
+            None => self.root = Some(node),
+            Some(parent) if is_left => Self::link(parent).left = Some(node),
+            Some(parent) => Self::link(parent).right = Some(node),
+        }
+
+        self.len += 1;
+        self.insert_fixup(node);
+    }
+
+    /// Unlink `node` from the tree.
+    ///
+    /// # Safety #
+    ///
+    /// `node` must be linked in this very tree.
+    pub unsafe fn remove(&mut self, node: NonNull<A::Node>) {
+        let mut removed_color = Self::link(node).color;
+        let child;
+        let child_parent;
+
+        if Self::link(node).left.is_none() {
+            child = Self::link(node).right;
+            child_parent = Self::link(node).parent;
+            self.transplant(node, child);
+        } else if Self::link(node).right.is_none() {
+            child = Self::link(node).left;
+            child_parent = Self::link(node).parent;
+            self.transplant(node, child);
+        } else {
+            // Replace the node with its successor, which has no left child.
+            let succ = Self::leftmost(Self::link(node).right.unwrap());
+            removed_color = Self::link(succ).color;
+            child = Self::link(succ).right;
+
+            if Self::link(succ).parent == Some(node) {
+                child_parent = Some(succ);
+            } else {
+                child_parent = Self::link(succ).parent;
+                self.transplant(succ, child);
+                let right = Self::link(node).right;
+                Self::link(succ).right = right;
+                Self::link(right.unwrap()).parent = Some(succ);
+            }
+
+            self.transplant(node, Some(succ));
+            let left = Self::link(node).left;
+            Self::link(succ).left = left;
+            Self::link(left.unwrap()).parent = Some(succ);
+            Self::link(succ).color = Self::link(node).color;
+        }
+
+        if removed_color == Color::Black {
+            self.remove_fixup(child, child_parent);
+        }
+
+        *Self::link(node) = TreeLink::new();
+        self.len -= 1;
+    }
+
+    fn link<'a>(node: NonNull<A::Node>) -> &'a mut TreeLink<A::Node> {
+        unsafe { A::link(node).as_mut() }
+    }
+
+    fn is_red(node: Option<NonNull<A::Node>>) -> bool {
+        node.is_some_and(|node| Self::link(node).color == Color::Red)
+    }
+
+    fn set_color(node: Option<NonNull<A::Node>>, color: Color) {
+        if let Some(node) = node {
+            Self::link(node).color = color;
+        }
+    }
+
+    fn leftmost(mut node: NonNull<A::Node>) -> NonNull<A::Node> {
+        while let Some(left) = Self::link(node).left {
+            node = left;
+        }
+        node
+    }
+
+    /// Put `new` in place of `old` in the child slot of `old`'s parent.
+    fn transplant(
+        &mut self,
+        old: NonNull<A::Node>,
+        new: Option<NonNull<A::Node>>,
+    ) {
+        let parent = Self::link(old).parent;
+
+        match parent {
+            None => self.root = new,
+            Some(p) if Self::link(p).left == Some(old) => {
+                Self::link(p).left = new;
+            },
+            Some(p) => Self::link(p).right = new,
+        }
+
+        if let Some(new) = new {
+            Self::link(new).parent = parent;
+        }
+    }
+
+    fn rotate_left(&mut self, node: NonNull<A::Node>) {
+        let pivot = Self::link(node).right.expect("no right child to rotate");
+
+        let pivot_left = Self::link(pivot).left;
+        Self::link(node).right = pivot_left;
+        if let Some(pivot_left) = pivot_left {
+            Self::link(pivot_left).parent = Some(node);
+        }
+
+        self.transplant(node, Some(pivot));
+        Self::link(pivot).left = Some(node);
+        Self::link(node).parent = Some(pivot);
+    }
+
+    fn rotate_right(&mut self, node: NonNull<A::Node>) {
+        let pivot = Self::link(node).left.expect("no left child to rotate");
+
+        let pivot_right = Self::link(pivot).right;
+        Self::link(node).left = pivot_right;
+        if let Some(pivot_right) = pivot_right {
+            Self::link(pivot_right).parent = Some(node);
+        }
+
+        self.transplant(node, Some(pivot));
+        Self::link(pivot).right = Some(node);
+        Self::link(node).parent = Some(pivot);
+    }
+
+    fn insert_fixup(&mut self, mut node: NonNull<A::Node>) {
+        while let Some(mut parent) = Self::link(node).parent
+            .filter(|&p| Self::is_red(Some(p))) {
+            // A red node is never the root, it has a parent.
+            let grand = Self::link(parent).parent.unwrap();
+
+            if Self::link(grand).left == Some(parent) {
+                let uncle = Self::link(grand).right;
+                if Self::is_red(uncle) {
+                    Self::set_color(Some(parent), Color::Black);
+                    Self::set_color(uncle, Color::Black);
+                    Self::set_color(Some(grand), Color::Red);
+                    node = grand;
+                    continue;
+                }
+
+                if Self::link(parent).right == Some(node) {
+                    node = parent;
+                    self.rotate_left(node);
+                    parent = Self::link(node).parent.unwrap();
+                }
+                Self::set_color(Some(parent), Color::Black);
+                Self::set_color(Some(grand), Color::Red);
+                self.rotate_right(grand);
+            } else {
+                let uncle = Self::link(grand).left;
+                if Self::is_red(uncle) {
+                    Self::set_color(Some(parent), Color::Black);
+                    Self::set_color(uncle, Color::Black);
+                    Self::set_color(Some(grand), Color::Red);
+                    node = grand;
+                    continue;
+                }
+
+                if Self::link(parent).left == Some(node) {
+                    node = parent;
+                    self.rotate_right(node);
+                    parent = Self::link(node).parent.unwrap();
+                }
+                Self::set_color(Some(parent), Color::Black);
+                Self::set_color(Some(grand), Color::Red);
+                self.rotate_left(grand);
+            }
+        }
+
+        Self::set_color(self.root, Color::Black);
+    }
+
+    /// Restore the invariants after a black node was removed; `node` is the
+    /// node which took its place, possibly none, and `parent` its parent.
+    fn remove_fixup(
+        &mut self,
+        mut node: Option<NonNull<A::Node>>,
+        mut parent: Option<NonNull<A::Node>>,
+    ) {
+        while node != self.root && !Self::is_red(node) {
+            // Since `node` is not the root, it has a parent; and since it is
+            // one black node short, it has a sibling.
+            let p = parent.unwrap();
+
+            if Self::link(p).left == node {
+                let mut sibling = Self::link(p).right.unwrap();
+                if Self::is_red(Some(sibling)) {
+                    Self::set_color(Some(sibling), Color::Black);
+                    Self::set_color(Some(p), Color::Red);
+                    self.rotate_left(p);
+                    sibling = Self::link(p).right.unwrap();
+                }
+
+                if !Self::is_red(Self::link(sibling).left)
+                    && !Self::is_red(Self::link(sibling).right) {
+                    Self::set_color(Some(sibling), Color::Red);
+                    node = Some(p);
+                    parent = Self::link(p).parent;
+                    continue;
+                }
+
+                if !Self::is_red(Self::link(sibling).right) {
+                    Self::set_color(Self::link(sibling).left, Color::Black);
+                    Self::set_color(Some(sibling), Color::Red);
+                    self.rotate_right(sibling);
+                    sibling = Self::link(p).right.unwrap();
+                }
+                Self::link(sibling).color = Self::link(p).color;
+                Self::set_color(Some(p), Color::Black);
+                Self::set_color(Self::link(sibling).right, Color::Black);
+                self.rotate_left(p);
+            } else {
+                let mut sibling = Self::link(p).left.unwrap();
+                if Self::is_red(Some(sibling)) {
+                    Self::set_color(Some(sibling), Color::Black);
+                    Self::set_color(Some(p), Color::Red);
+                    self.rotate_right(p);
+                    sibling = Self::link(p).left.unwrap();
+                }
+
+                if !Self::is_red(Self::link(sibling).left)
+                    && !Self::is_red(Self::link(sibling).right) {
+                    Self::set_color(Some(sibling), Color::Red);
+                    node = Some(p);
+                    parent = Self::link(p).parent;
+                    continue;
+                }
+
+                if !Self::is_red(Self::link(sibling).left) {
+                    Self::set_color(Self::link(sibling).right, Color::Black);
+                    Self::set_color(Some(sibling), Color::Red);
+                    self.rotate_left(sibling);
+                    sibling = Self::link(p).left.unwrap();
+                }
+                Self::link(sibling).color = Self::link(p).color;
+                Self::set_color(Some(p), Color::Black);
+                Self::set_color(Self::link(sibling).left, Color::Black);
+                self.rotate_right(p);
+            }
+
+            node = self.root;
+            break;
+        }
+
+        Self::set_color(node, Color::Black);
+    }
+}
+
+impl<A: TreeAdapter> Default for RbTree<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Iter<'a, A: TreeAdapter> {
+    tree: &'a RbTree<A>,
+    next: Option<NonNull<A::Node>>,
+}
+
+impl<'a, A: TreeAdapter> Iterator for Iter<'a, A> {
+    type Item = NonNull<A::Node>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?;
+        self.next = self.tree.next(node);
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use core::ptr::addr_of_mut;
+    use super::*;
+
+    struct Item {
+        key: u32,
+        link: TreeLink<Item>,
+    }
+
+    struct ItemAdapter;
+
+    impl TreeAdapter for ItemAdapter {
+        type Node = Item;
+        type Key = u32;
+
+        fn link(node: NonNull<Item>) -> NonNull<TreeLink<Item>> {
+            unsafe { NonNull::new_unchecked(addr_of_mut!((*node.as_ptr()).link)) }
+        }
+
+        fn key(node: &Item) -> &u32 {
+            &node.key
+        }
+    }
+
+    type Tree = RbTree<ItemAdapter>;
+
+    fn items(n: u32) -> Vec<Item> {
+        // Visit the keys in a scrambled order, 37 being coprime with `n`.
+        (0..n).map(|i| Item { key: (i * 37) % n, link: TreeLink::new() })
+            .collect()
+    }
+
+    fn keys(tree: &Tree) -> Vec<u32> {
+        tree.iter().map(|node| unsafe { node.as_ref() }.key).collect()
+    }
+
+    /// Check the red-black invariants below `node` and return its black
+    /// height.
+    fn check(node: Option<NonNull<Item>>, parent: Option<NonNull<Item>>) -> usize {
+        let Some(node) = node else {
+            return 1;
+        };
+        let link = Tree::link(node);
+
+        assert_eq!(link.parent, parent);
+        if link.color == Color::Red {
+            assert!(!Tree::is_red(link.left) && !Tree::is_red(link.right),
+                    "a red node has a red child");
+        }
+
+        let left = check(link.left, Some(node));
+        let right = check(link.right, Some(node));
+        assert_eq!(left, right, "unbalanced black heights");
+
+        left + (link.color == Color::Black) as usize
+    }
+
+    #[test]
+    fn it_keeps_elements_ordered_and_balanced() {
+        let mut items = items(100);
+        let mut tree = Tree::new();
+
+        for item in &mut items {
+            unsafe { tree.insert(NonNull::from(item)); }
+            check(tree.root, None);
+        }
+
+        assert_eq!(tree.len(), 100);
+        assert!(!Tree::is_red(tree.root));
+        assert_eq!(keys(&tree), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn it_removes_elements() {
+        let mut items = items(100);
+        let mut tree = Tree::new();
+
+        for item in &mut items {
+            unsafe { tree.insert(NonNull::from(item)); }
+        }
+        for item in items.iter_mut().filter(|item| item.key % 3 != 0) {
+            unsafe { tree.remove(NonNull::from(item)); }
+            check(tree.root, None);
+        }
+
+        assert_eq!(keys(&tree), (0..100).step_by(3).collect::<Vec<_>>());
+
+        for item in items.iter_mut().filter(|item| item.key % 3 == 0) {
+            unsafe { tree.remove(NonNull::from(item)); }
+            check(tree.root, None);
+        }
+        assert!(tree.is_empty());
+        assert_eq!(tree.len(), 0);
+    }
+
+    #[test]
+    fn it_finds_elements() {
+        let mut items = items(10);
+        let mut tree = Tree::new();
+
+        for item in items.iter_mut().filter(|item| item.key % 2 == 0) {
+            unsafe { tree.insert(NonNull::from(item)); }
+        }
+
+        let key_of = |node: Option<NonNull<Item>>| {
+            node.map(|node| unsafe { node.as_ref() }.key)
+        };
+        assert_eq!(key_of(tree.find(&4)), Some(4));
+        assert_eq!(key_of(tree.find(&5)), None);
+        assert_eq!(key_of(tree.floor(&5)), Some(4));
+        assert_eq!(key_of(tree.floor(&100)), Some(8));
+        assert_eq!(key_of(tree.first()), Some(0));
+        assert_eq!(key_of(tree.last()), Some(8));
+
+        let four = tree.find(&4).unwrap();
+        assert_eq!(key_of(tree.next(four)), Some(6));
+        assert_eq!(key_of(tree.prev(four)), Some(2));
+    }
+}
@@ -0,0 +1,185 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Machine-readable crash dumps. When enabled with the `crashdump=serial` boot
+//! option, the panic handler writes, in addition to the human-readable report,
+//! a dump of the machine state, the backtrace, the log ring and registered
+//! memory regions onto the serial line, so that crashes on real hardware can be
+//! analyzed offline.
+//!
+//! The dump is line-oriented text, enclosed between `BEGIN_MARKER` and
+//! `END_MARKER` lines; each line starts with a record type followed by a space:
+//!
+//! ```text
+//! build <build ID>
+//! message <panic message>
+//! fingerprint <panic fingerprint>
+//! reg <name> <hex value>
+//! frame <hex pc> [<symbol>+<hex offset>]
+//! log <line of the log ring>
+//! mem <region name> <hex address> <hex bytes...>
+//! ```
+//!
+//! Memory lines hold up to 16 bytes each; pages that are not mapped are skipped
+//! and reported as `unmapped <region name> <hex address>`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::arch::cpu::MachineState;
+use crate::arch::mem::{page_permissions, PAGE_SIZE};
+use crate::backtrace::Backtrace;
+use crate::buildinfo::BUILD_ID;
+use crate::logging::log_ring_unlocked;
+use crate::mem::VAddr;
+use crate::panic::PanicFingerprint;
+use crate::sync::Spinlock;
+
+pub const BEGIN_MARKER: &str = "--- BEGIN NUCLOID CRASH DUMP v1 ---";
+pub const END_MARKER: &str = "--- END NUCLOID CRASH DUMP ---";
+
+/// The number of bytes dumped from the stack, starting at the stack pointer.
+const STACK_DUMP_SIZE: usize = 512;
+
+const MAX_REGIONS: usize = 8;
+
+static CRASH_DUMP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static REGIONS: Spinlock<ArrayVec<MemoryRegion, MAX_REGIONS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+#[derive(Copy, Clone)]
+struct MemoryRegion {
+    name: &'static str,
+    start: VAddr,
+    bsize: usize,
+}
+
+pub fn set_enabled(enabled: bool) {
+    CRASH_DUMP_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    CRASH_DUMP_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Register a memory region to be included in crash dumps; returns `false` if
+/// too many regions were already registered.
+pub fn register_region(name: &'static str, start: VAddr, bsize: usize) -> bool {
+    REGIONS.lock()
+        .try_push(MemoryRegion { name, start, bsize })
+        .is_ok()
+}
+
+/// Write a complete crash dump into `w`. This function is called from the
+/// panic handler, it must not allocate memory, nor wait on locks.
+pub fn write_crash_dump(
+    w: &mut impl fmt::Write,
+    message: fmt::Arguments,
+    fingerprint: PanicFingerprint,
+    machine: Option<&MachineState>,
+    skip_frames: usize,
+) -> fmt::Result {
+    writeln!(w, "{BEGIN_MARKER}")?;
+    writeln!(w, "build {BUILD_ID}")?;
+    writeln!(w, "message {message}")?;
+    writeln!(w, "fingerprint {fingerprint}")?;
+
+    if let Some(machine) = machine {
+        for (name, value) in machine.registers() {
+            writeln!(w, "reg {name} {value:x}")?;
+        }
+
+        for frame in Backtrace::from_machine_state(machine).skip(skip_frames) {
+            write!(w, "frame {:x}", frame.pc.0)?;
+            if let (Some(sym), Some(off)) = (frame.symbol, frame.sym_off) {
+                write!(w, " {sym}+{off:x}")?;
+            }
+            writeln!(w)?;
+        }
+
+        write_memory(w, "stack", VAddr(machine.stack_ptr() as usize),
+                     STACK_DUMP_SIZE)?;
+    }
+
+    write_log(w)?;
+
+    // SAFETY: we are panicking, no other CPU is running.
+    let regions = unsafe { &*REGIONS.bypass_lock() };
+    for region in regions.iter() {
+        write_memory(w, region.name, region.start, region.bsize)?;
+    }
+
+    writeln!(w, "{END_MARKER}")
+}
+
+fn write_log(w: &mut impl fmt::Write) -> fmt::Result {
+    // SAFETY: we are panicking, no other CPU is running.
+    let ring = unsafe { log_ring_unlocked() };
+    let (first, second) = ring.as_slices();
+    let mut at_line_start = true;
+
+    for &byte in first.iter().chain(second.iter()) {
+        if at_line_start {
+            w.write_str("log ")?;
+            at_line_start = false;
+        }
+
+        match byte {
+            b'\n' => {
+                w.write_char('\n')?;
+                at_line_start = true;
+            },
+            // Escape sequences would mess with the parsing, and we don't want
+            // to decode UTF-8 across the two halves of the ring.
+            0x20..=0x7e => w.write_char(byte as char)?,
+            _ => write!(w, "\\x{byte:02x}")?,
+        }
+    }
+
+    if !at_line_start {
+        w.write_char('\n')?;
+    }
+
+    Ok(())
+}
+
+fn write_memory(
+    w: &mut impl fmt::Write,
+    name: &str,
+    start: VAddr,
+    bsize: usize,
+) -> fmt::Result {
+    let mut addr = start.0;
+    let end = start.0.saturating_add(bsize);
+
+    while addr < end {
+        let page_end = (addr & !(PAGE_SIZE - 1)).saturating_add(PAGE_SIZE);
+
+        if !page_permissions(VAddr(addr)).readable {
+            writeln!(w, "unmapped {name} {addr:x}")?;
+            addr = page_end;
+            continue;
+        }
+
+        let line_end = end.min(page_end).min(addr + 16);
+        write!(w, "mem {name} {addr:x}")?;
+        for byte_addr in addr..line_end {
+            let byte = unsafe { (byte_addr as *const u8).read_volatile() };
+            write!(w, " {byte:02x}")?;
+        }
+        writeln!(w)?;
+
+        addr = line_end;
+    }
+
+    Ok(())
+}
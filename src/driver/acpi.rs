@@ -0,0 +1,321 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Minimal ACPI support: the firmware tables are only read for what power
+//! management needs, i.e. the PM1 control registers and the reset register
+//! from the FADT, and the sleep types of the S5 (soft-off) state from the
+//! `\_S5` object of the DSDT. The AML is not interpreted: the object is found
+//! by its name and decoded in the simple form every firmware uses. Other
+//! drivers look up the tables they need with `find()`, e.g. the DMAR.
+//!
+//! The tables must lie in low memory, which covers the ACPI regions of the
+//! boot memory map.
+
+use core::slice;
+
+use crate::mem::{get_lowmem_va_end, PAddr, VAddr};
+use crate::sync::Spinlock;
+use crate::{debug, info, warning};
+
+pub(crate) const SDT_HEADER_BSIZE: usize = 36;
+
+/// The bit of the FADT flags telling that the reset register is supported.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The region where PC firmware places the root pointer, when the bootloader
+/// doesn't give it.
+const BIOS_AREA: core::ops::Range<u64> = 0xe0000..0x100000;
+
+static POWER_INFO: Spinlock<Option<PowerInfo>> = Spinlock::new(None);
+static ROOT: Spinlock<Option<RootTable>> = Spinlock::new(None);
+
+/// The root table listing all other tables, as given by the root pointer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RootTable {
+    /// ACPI 1.0's RSDT, listing 32-bit physical addresses.
+    Rsdt(u64),
+    /// ACPI 2.0's XSDT, listing 64-bit physical addresses.
+    Xsdt(u64),
+}
+
+/// The address of a register, in one of several address spaces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GenericAddress {
+    Memory(u64),
+    Io(u16),
+    /// A register of the PCI configuration space, which we don't support.
+    Other { space: u8, address: u64 },
+}
+
+/// What the firmware tells about power management.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PowerInfo {
+    /// The I/O ports of the PM1a and PM1b control registers; PM1b is optional
+    /// and zero if absent.
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    /// The I/O port to write `acpi_enable` to in order to switch from legacy
+    /// to ACPI mode; zero if the system is always in ACPI mode.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    /// The register to write a value to in order to reset the system.
+    pub reset: Option<(GenericAddress, u8)>,
+    /// The `SLP_TYPa` and `SLP_TYPb` values of the S5 state.
+    pub s5_sleep_types: Option<(u8, u8)>,
+}
+
+/// Find the FADT and DSDT from the root table `root`, or from the root pointer
+/// in the BIOS area if the bootloader didn't give one, and keep the power
+/// management information.
+pub fn init(root: Option<RootTable>) {
+    let Some(root) = root.or_else(find_root_in_bios_area) else {
+        info!("acpi: no root table found");
+        return;
+    };
+    debug!("acpi: root table {root:x?}");
+    *ROOT.lock() = Some(root);
+
+    let Some(fadt) = find_table(root, b"FACP") else {
+        warning!("acpi: no FADT found");
+        return;
+    };
+    let Some(mut info) = parse_fadt(fadt) else {
+        warning!("acpi: invalid FADT");
+        return;
+    };
+
+    info.s5_sleep_types = fadt_dsdt(fadt)
+        .and_then(table_at)
+        .and_then(|dsdt| find_s5(&dsdt[SDT_HEADER_BSIZE..]));
+    if info.s5_sleep_types.is_none() {
+        warning!("acpi: no S5 sleep state found, can't power off");
+    }
+
+    *POWER_INFO.lock() = Some(info);
+}
+
+/// The table with `signature`, header included, if ACPI was found and the
+/// table has a valid checksum.
+pub fn find(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let root = (*ROOT.lock())?;
+    find_table(root, signature)
+}
+
+/// The power management information, if ACPI was found.
+pub fn power_info() -> Option<PowerInfo> {
+    *POWER_INFO.lock()
+}
+
+/// The bytes of the physical memory at `paddr`, if in low memory.
+fn phys_bytes(paddr: u64, bsize: usize) -> Option<&'static [u8]> {
+    let start = PAddr(paddr).into_vaddr();
+    let end = start.0.checked_add(bsize)?;
+    if VAddr(end) > get_lowmem_va_end() {
+        return None;
+    }
+
+    Some(unsafe { slice::from_raw_parts(start.as_ptr(), bsize) })
+}
+
+fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..(offset + 4))?.try_into().ok()?))
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..(offset + 8))?.try_into().ok()?))
+}
+
+/// Look for the root pointer, the "RSD PTR " structure, in the BIOS area.
+fn find_root_in_bios_area() -> Option<RootTable> {
+    let area = phys_bytes(BIOS_AREA.start,
+                          (BIOS_AREA.end - BIOS_AREA.start) as usize)?;
+
+    area.chunks_exact(16)
+        .enumerate()
+        .filter(|(_, chunk)| chunk.starts_with(b"RSD PTR "))
+        .find_map(|(i, _)| parse_root_pointer(&area[(i * 16)..]))
+}
+
+fn parse_root_pointer(rsdp: &[u8]) -> Option<RootTable> {
+    if !checksum_is_valid(rsdp.get(..20)?) {
+        return None;
+    }
+
+    match rsdp[15] {
+        0 => Some(RootTable::Rsdt(read_u32(rsdp, 16)? as u64)),
+        _ => {
+            checksum_is_valid(rsdp.get(..36)?)
+                .then_some(RootTable::Xsdt(read_u64(rsdp, 24)?))
+        },
+    }
+}
+
+/// The whole table whose header is at `paddr`, if its checksum is valid.
+fn table_at(paddr: u64) -> Option<&'static [u8]> {
+    let header = phys_bytes(paddr, SDT_HEADER_BSIZE)?;
+    let bsize = read_u32(header, 4)? as usize;
+    if bsize < SDT_HEADER_BSIZE {
+        return None;
+    }
+
+    let table = phys_bytes(paddr, bsize)?;
+    checksum_is_valid(table).then_some(table)
+}
+
+fn find_table(root: RootTable, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (paddr, entry_bsize) = match root {
+        RootTable::Rsdt(paddr) => (paddr, 4),
+        RootTable::Xsdt(paddr) => (paddr, 8),
+    };
+    let entries = &table_at(paddr)?[SDT_HEADER_BSIZE..];
+
+    entries.chunks_exact(entry_bsize)
+        .filter_map(|entry| match entry_bsize {
+            4 => read_u32(entry, 0).map(|paddr| paddr as u64),
+            _ => read_u64(entry, 0),
+        })
+        .filter_map(table_at)
+        .find(|table| table.starts_with(signature))
+}
+
+/// The physical address of the DSDT, the 64-bit field taking precedence.
+fn fadt_dsdt(fadt: &[u8]) -> Option<u64> {
+    read_u64(fadt, 140)
+        .filter(|&paddr| paddr != 0)
+        .or_else(|| read_u32(fadt, 40).map(|paddr| paddr as u64))
+        .filter(|&paddr| paddr != 0)
+}
+
+fn parse_fadt(fadt: &[u8]) -> Option<PowerInfo> {
+    let flags = read_u32(fadt, 112).unwrap_or(0);
+    let reset = if flags & FADT_RESET_REG_SUP != 0 && fadt.len() > 128 {
+        let address = read_u64(fadt, 120)?;
+        let register = match fadt[116] {
+            0 => GenericAddress::Memory(address),
+            1 => GenericAddress::Io(address as u16),
+            space => GenericAddress::Other { space, address },
+        };
+        Some((register, fadt[128]))
+    } else {
+        None
+    };
+
+    Some(PowerInfo {
+        pm1a_control: read_u32(fadt, 64)? as u16,
+        pm1b_control: read_u32(fadt, 68)? as u16,
+        smi_command: read_u32(fadt, 48)? as u16,
+        acpi_enable: *fadt.get(52)?,
+        reset,
+        s5_sleep_types: None,
+    })
+}
+
+/// Find the `\_S5` package in the AML `aml` and return its first two values,
+/// `SLP_TYPa` and `SLP_TYPb`.
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0a;
+
+    let pos = aml.windows(4).position(|name| name == b"_S5_")?;
+    let is_declared = match pos {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[pos - 1] == NAME_OP
+            || (aml[pos - 1] == b'\\' && aml[pos - 2] == NAME_OP),
+    };
+    if !is_declared || *aml.get(pos + 4)? != PACKAGE_OP {
+        return None;
+    }
+
+    // The two top bits of PkgLength's lead byte count the bytes that follow;
+    // the number of elements comes next.
+    let mut i = pos + 5;
+    i += (*aml.get(i)? >> 6) as usize + 1;
+    i += 1;
+
+    let mut next_value = || -> Option<u8> {
+        let value = match *aml.get(i)? {
+            BYTE_PREFIX => {
+                i += 1;
+                *aml.get(i)?
+            },
+            ZERO_OP => 0,
+            ONE_OP => 1,
+            _ => return None,
+        };
+        i += 1;
+        Some(value)
+    };
+
+    Some((next_value()?, next_value()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+
+    #[test]
+    fn it_finds_the_s5_sleep_types() {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x0a, 0x04,
+                   0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some((5, 5)));
+
+        // Name (\_S5, Package (0x04) { Zero, One, Zero, Zero })
+        let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04,
+                   0x00, 0x01, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some((0, 1)));
+
+        // A method named _S5_ isn't the package.
+        let aml = [0x14, b'_', b'S', b'5', b'_', 0x00];
+        assert_eq!(find_s5(&aml), None);
+    }
+
+    #[test]
+    fn it_parses_the_fadt() {
+        let mut fadt = vec![0u8; 244];
+        fadt[48..52].copy_from_slice(&0xb2u32.to_le_bytes());
+        fadt[52] = 0xf1;
+        fadt[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+        fadt[112..116].copy_from_slice(&FADT_RESET_REG_SUP.to_le_bytes());
+        fadt[116] = 1;
+        fadt[120..128].copy_from_slice(&0xcf9u64.to_le_bytes());
+        fadt[128] = 0x06;
+
+        assert_eq!(parse_fadt(&fadt), Some(PowerInfo {
+            pm1a_control: 0x604,
+            pm1b_control: 0,
+            smi_command: 0xb2,
+            acpi_enable: 0xf1,
+            reset: Some((GenericAddress::Io(0xcf9), 0x06)),
+            s5_sleep_types: None,
+        }));
+    }
+
+    #[test]
+    fn it_validates_root_pointers() {
+        let mut rsdp = [0u8; 20];
+        rsdp[..8].copy_from_slice(b"RSD PTR ");
+        rsdp[16..20].copy_from_slice(&0x7fe1234u32.to_le_bytes());
+        rsdp[8] = 0u8.wrapping_sub(rsdp.iter().fold(0u8, |s, &b| s.wrapping_add(b)));
+
+        assert_eq!(parse_root_pointer(&rsdp), Some(RootTable::Rsdt(0x7fe1234)));
+        rsdp[9] ^= 1;
+        assert_eq!(parse_root_pointer(&rsdp), None);
+    }
+}
@@ -0,0 +1,145 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The input event queue. Input drivers publish their events into a single
+//! ring buffer, without knowing who consumes them; every subscriber reads all
+//! the events published since it subscribed, at its own pace. A subscriber
+//! that falls behind by more than `QUEUE_LEN` events loses the oldest ones.
+
+use crate::driver::keyboard::Key;
+use crate::driver::mouse::MouseEvent;
+use crate::sync::{Spinlock, WaitQueue};
+
+/// The number of events kept in the ring buffer.
+pub const QUEUE_LEN: usize = 128;
+
+static QUEUE: Spinlock<EventQueue> = Spinlock::new(EventQueue::new());
+static WAITERS: WaitQueue = WaitQueue::new();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A key was pressed or released; `repeat` is set for the presses
+    /// repeating a key held down.
+    Key { key: Key, pressed: bool, repeat: bool },
+
+    /// A character was typed, as translated by the keymap; control characters
+    /// stand for key combinations, e.g. `\x03` for Ctrl+C.
+    Char(char),
+
+    /// A mouse moved, or had its buttons change state.
+    Mouse(MouseEvent),
+}
+
+struct EventQueue {
+    events: [Option<InputEvent>; QUEUE_LEN],
+    /// The sequence number of the next event to be published.
+    head: u64,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        Self {
+            events: [None; QUEUE_LEN],
+            head: 0,
+        }
+    }
+
+    fn push(&mut self, event: InputEvent) {
+        self.events[(self.head % QUEUE_LEN as u64) as usize] = Some(event);
+        self.head += 1;
+    }
+
+    /// Pop the event at sequence number `*next` for a subscriber, skipping
+    /// the events that were overwritten.
+    fn pop(&mut self, next: &mut u64) -> Option<InputEvent> {
+        *next = (*next).max(self.head.saturating_sub(QUEUE_LEN as u64));
+        if *next == self.head {
+            return None;
+        }
+
+        let event = self.events[(*next % QUEUE_LEN as u64) as usize];
+        *next += 1;
+        event
+    }
+}
+
+/// A consumer of the input events, receiving those published after it
+/// subscribed.
+pub struct Subscription {
+    next: u64,
+}
+
+/// Subscribe to the input events published from now on.
+pub fn subscribe() -> Subscription {
+    Subscription {
+        next: QUEUE.lock().head,
+    }
+}
+
+/// Publish an input event to all subscribers; this is meant to be called by
+/// input drivers, from interrupt handlers.
+pub fn publish(event: InputEvent) {
+    QUEUE.lock().push(event);
+    WAITERS.notify_all();
+}
+
+/// Pop the oldest event not read yet by `subscription`, if any.
+pub fn try_read_event(subscription: &mut Subscription) -> Option<InputEvent> {
+    QUEUE.lock().pop(&mut subscription.next)
+}
+
+/// Pop the oldest event not read yet by `subscription`, waiting for one to be
+/// published if there is none.
+pub fn read_event(subscription: &mut Subscription) -> InputEvent {
+    WAITERS.wait_until(|| try_read_event(subscription))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_delivers_events_to_every_subscriber() {
+        let mut queue = EventQueue::new();
+        let mut a = queue.head;
+        queue.push(InputEvent::Char('x'));
+        let mut b = queue.head;
+        queue.push(InputEvent::Char('y'));
+
+        assert_eq!(queue.pop(&mut a), Some(InputEvent::Char('x')));
+        assert_eq!(queue.pop(&mut a), Some(InputEvent::Char('y')));
+        assert_eq!(queue.pop(&mut a), None);
+        assert_eq!(queue.pop(&mut b), Some(InputEvent::Char('y')));
+        assert_eq!(queue.pop(&mut b), None);
+    }
+
+    #[test]
+    fn it_drops_the_oldest_events_of_slow_subscribers() {
+        let mut queue = EventQueue::new();
+        let mut next = queue.head;
+
+        for i in 0..(QUEUE_LEN + 2) {
+            queue.push(InputEvent::Key {
+                key: Key::F(i as u8),
+                pressed: true,
+                repeat: false,
+            });
+        }
+
+        assert_eq!(queue.pop(&mut next),
+                   Some(InputEvent::Key {
+                       key: Key::F(2),
+                       pressed: true,
+                       repeat: false,
+                   }));
+        assert_eq!((0..).map_while(|_| queue.pop(&mut next)).count(),
+                   QUEUE_LEN - 1);
+    }
+}
@@ -0,0 +1,528 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Intel VT-d DMA remapping. Once enabled, with the `iommu=on` boot option,
+//! the remapping units listed by the ACPI DMAR table translate the memory
+//! accesses of the PCI devices: all of them share a single domain, whose I/O
+//! page tables identity-map the DMA buffers of `mem::dma` as they are
+//! allocated, and the regions the firmware reserves for its own use of some
+//! devices (RMRRs). Accesses anywhere else are blocked and reported as faults
+//! by the unit, rather than corrupting memory.
+//!
+//! Only PCI segment 0 is supported, and the units must snoop the CPU caches
+//! when walking the page tables. Interrupt remapping is not used.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use thiserror_no_std::Error;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::arch::time::delay_us;
+use crate::driver::{acpi, pci};
+use crate::mem::frame::allocate_frames;
+use crate::mem::iomap::{iomap, IomapError, MmioRegion};
+use crate::mem::{CacheMode, PAddr};
+use crate::sync::Spinlock;
+use crate::{info, warning};
+
+const DMAR_STRUCTURES_OFFSET: usize = 48;
+const DMAR_TYPE_DRHD: u16 = 0;
+const DMAR_TYPE_RMRR: u16 = 1;
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+const REG_CAP: usize = 0x08;
+const REG_ECAP: usize = 0x10;
+const REG_GCMD: usize = 0x18;
+const REG_GSTS: usize = 0x1c;
+const REG_RTADDR: usize = 0x20;
+const REG_CCMD: usize = 0x28;
+const REGS_BSIZE: usize = 0x1000;
+
+const CAP_CM: u64 = 1 << 7;
+const ECAP_C: u64 = 1 << 0;
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+/// The status bits that are preserved when writing a command: the one-shot
+/// commands are left out.
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DRAIN: u64 = (1 << 49) | (1 << 48);
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const PTE_READ: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The domain all the devices are put in.
+const DOMAIN_ID: u64 = 1;
+
+/// How long to wait for a unit to complete a command.
+const COMMAND_TIMEOUT_US: u64 = 10_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static IOMMU: Spinlock<Option<Iommu>> = Spinlock::new(None);
+
+#[derive(Error, Debug)]
+pub enum IommuError {
+    #[error("no DMAR table found")]
+    NoDmar,
+
+    #[error("invalid DMAR table")]
+    InvalidDmar,
+
+    #[error("no address width supported by all units")]
+    UnsupportedWidth,
+
+    #[error("the unit at {0:?} doesn't snoop page table walks")]
+    NotCoherent(PAddr),
+
+    #[error("couldn't map the registers: {0}")]
+    Map(#[from] IomapError),
+
+    #[error("out of memory for the translation tables")]
+    OutOfMemory,
+
+    #[error("the unit at {0:?} didn't complete a command")]
+    Timeout(PAddr),
+}
+
+/// What the DMAR table describes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dmar {
+    /// The width of the physical addresses DMA can target, in bits.
+    pub host_address_width: u8,
+    pub units: Vec<RemappingUnit>,
+    /// The regions devices may access on behalf of the firmware, which must
+    /// stay identity-mapped.
+    pub reserved: Vec<Range<u64>>,
+}
+
+/// A DMA remapping hardware unit, DRHD.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RemappingUnit {
+    pub segment: u16,
+    pub registers: u64,
+    /// Whether the unit handles all the devices of the segment not listed by
+    /// another unit.
+    pub include_all: bool,
+}
+
+/// A page table translating the addresses of DMA accesses, in the second-level
+/// format of VT-d.
+struct IoPageTable {
+    root: PAddr,
+    levels: usize,
+}
+
+struct Iommu {
+    units: Vec<Unit>,
+    domain: IoPageTable,
+    /// Whether a unit caches non-present entries, which requires invalidating
+    /// the IOTLB on mappings too.
+    caching_mode: bool,
+}
+
+/// A remapping unit being programmed.
+struct Unit {
+    paddr: PAddr,
+    regs: MmioRegion,
+    cap: u64,
+    ecap: u64,
+}
+
+/// Enable DMA remapping with the `iommu=` boot option; it must be set before
+/// `init()` is called.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Whether DMA remapping is on: DMA buffers must then be mapped with
+/// `map_dma()` for devices to reach them.
+pub fn is_enabled() -> bool {
+    IOMMU.lock().is_some()
+}
+
+/// Turn on DMA remapping if enabled by the boot options, for the devices
+/// enumerated so far. This is meant to be called after the PCI enumeration,
+/// and before the drivers allocate DMA buffers.
+pub fn init() {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+
+    match setup() {
+        Ok(nr_units) => info!("iommu: DMA remapping on, {nr_units} units"),
+        Err(e) => warning!("iommu: {e}; DMA remapping stays off"),
+    }
+}
+
+/// Let the devices access the `bsize` bytes of memory at `paddr`, at the same
+/// address, if DMA remapping is on.
+pub fn map_dma(paddr: PAddr, bsize: usize) -> Result<(), IommuError> {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return Ok(());
+    };
+
+    for page in pages_of(paddr, bsize) {
+        if let Err(e) = iommu.domain.map(page, PAddr(page), true) {
+            for mapped in pages_of(paddr, bsize).take_while(|&p| p < page) {
+                iommu.domain.unmap(mapped);
+            }
+            return Err(e);
+        }
+    }
+    if iommu.caching_mode {
+        iommu.invalidate_iotlb();
+    }
+
+    Ok(())
+}
+
+/// Revoke the devices' access to memory mapped with `map_dma()`.
+pub fn unmap_dma(paddr: PAddr, bsize: usize) {
+    let mut iommu = IOMMU.lock();
+    let Some(iommu) = iommu.as_mut() else {
+        return;
+    };
+
+    for page in pages_of(paddr, bsize) {
+        iommu.domain.unmap(page);
+    }
+    iommu.invalidate_iotlb();
+}
+
+/// Parse the DMAR table `table`, header included.
+pub fn parse_dmar(table: &[u8]) -> Option<Dmar> {
+    let mut dmar = Dmar {
+        host_address_width: table.get(36)?.checked_add(1)?,
+        units: Vec::new(),
+        reserved: Vec::new(),
+    };
+
+    let mut offset = DMAR_STRUCTURES_OFFSET;
+    while offset < table.len() {
+        let structure = table.get(offset..)?;
+        let kind = u16::from_le_bytes(read_le(structure, 0)?);
+        let bsize = u16::from_le_bytes(read_le(structure, 2)?) as usize;
+        if bsize < 4 {
+            return None;
+        }
+        let structure = structure.get(..bsize)?;
+
+        match kind {
+            DMAR_TYPE_DRHD => dmar.units.push(RemappingUnit {
+                segment: u16::from_le_bytes(read_le(structure, 6)?),
+                registers: u64::from_le_bytes(read_le(structure, 8)?),
+                include_all: *structure.get(4)? & DRHD_INCLUDE_PCI_ALL != 0,
+            }),
+            DMAR_TYPE_RMRR => {
+                let base = u64::from_le_bytes(read_le(structure, 8)?);
+                let limit = u64::from_le_bytes(read_le(structure, 16)?);
+                dmar.reserved.push(base..limit.checked_add(1)?);
+            },
+            _ => (),
+        }
+        offset += bsize;
+    }
+
+    Some(dmar)
+}
+
+fn setup() -> Result<usize, IommuError> {
+    let dmar = acpi::find(b"DMAR").ok_or(IommuError::NoDmar)?;
+    let dmar = parse_dmar(dmar).ok_or(IommuError::InvalidDmar)?;
+
+    let mut units = Vec::new();
+    for unit in &dmar.units {
+        if unit.segment != 0 {
+            warning!("iommu: PCI segment {} unsupported", unit.segment);
+            continue;
+        }
+        units.push(Unit::new(PAddr(unit.registers))?);
+    }
+
+    // SAGAW bit 2 is 4-level tables for 48-bit addresses, bit 1 is 3-level
+    // ones for 39-bit addresses.
+    let levels = [4, 3].into_iter()
+        .find(|&levels| {
+            units.iter().all(|unit| (unit.cap >> 8) & (1 << (levels - 2)) != 0)
+        })
+        .ok_or(IommuError::UnsupportedWidth)?;
+    if let Some(unit) = units.iter().find(|unit| unit.ecap & ECAP_C == 0) {
+        return Err(IommuError::NotCoherent(unit.paddr));
+    }
+
+    let mut domain = IoPageTable::new(levels)?;
+    for region in &dmar.reserved {
+        for page in pages_of(PAddr(region.start),
+                             (region.end - region.start) as usize) {
+            domain.map(page, PAddr(page), true)?;
+        }
+    }
+
+    let root_table = context_tables(&domain)?;
+    for unit in &units {
+        unit.enable(root_table)?;
+    }
+
+    let nr_units = units.len();
+    *IOMMU.lock() = Some(Iommu {
+        caching_mode: units.iter().any(|unit| unit.cap & CAP_CM != 0),
+        units,
+        domain,
+    });
+    Ok(nr_units)
+}
+
+/// Build the root table pointing to the context tables that put each PCI
+/// device in `domain`; shared by all units, each of them only looks up its
+/// own devices.
+fn context_tables(domain: &IoPageTable) -> Result<PAddr, IommuError> {
+    let root_table = alloc_table()?;
+    // Address width 2 is 48-bit with 4 levels, 1 is 39-bit with 3 levels.
+    let address_width = domain.levels as u64 - 2;
+
+    for device in pci::devices() {
+        let addr = device.addr;
+        let root_entry = &mut table_entries(root_table)[2 * addr.bus as usize];
+        if *root_entry & ENTRY_PRESENT == 0 {
+            *root_entry = alloc_table()?.0 | ENTRY_PRESENT;
+        }
+
+        let context_table = PAddr(*root_entry & PTE_ADDR_MASK);
+        let devfn = (addr.device as usize) << 3 | addr.function as usize;
+        let entry = &mut table_entries(context_table)[(2 * devfn)..];
+        entry[1] = address_width | DOMAIN_ID << 8;
+        entry[0] = domain.root.0 | ENTRY_PRESENT;
+    }
+
+    Ok(root_table)
+}
+
+impl Unit {
+    fn new(paddr: PAddr) -> Result<Self, IommuError> {
+        let regs = iomap(paddr, REGS_BSIZE, CacheMode::Uncached)?;
+
+        Ok(Self {
+            paddr,
+            cap: regs.read64(REG_CAP),
+            ecap: regs.read64(REG_ECAP),
+            regs,
+        })
+    }
+
+    /// The offset of the IOTLB invalidation register.
+    fn iotlb_reg(&self) -> usize {
+        ((self.ecap >> 8 & 0x3ff) as usize) * 16 + 8
+    }
+
+    fn enable(&self, root_table: PAddr) -> Result<(), IommuError> {
+        self.regs.write64(REG_RTADDR, root_table.0);
+        self.command(GCMD_SRTP)?;
+
+        self.regs.write64(REG_CCMD, CCMD_ICC | CCMD_GLOBAL);
+        self.wait_until(|unit| unit.regs.read64(REG_CCMD) & CCMD_ICC == 0)?;
+        self.invalidate_iotlb()?;
+
+        self.command(GCMD_TE)
+    }
+
+    /// Issue `command` in the global command register and wait for the unit
+    /// to acknowledge it in its status.
+    fn command(&self, command: u32) -> Result<(), IommuError> {
+        let status = self.regs.read32(REG_GSTS) & GSTS_PERSISTENT;
+        self.regs.write32(REG_GCMD, status | command);
+        self.wait_until(|unit| unit.regs.read32(REG_GSTS) & command != 0)
+    }
+
+    fn invalidate_iotlb(&self) -> Result<(), IommuError> {
+        let reg = self.iotlb_reg();
+        self.regs.write64(reg, IOTLB_IVT | IOTLB_GLOBAL | IOTLB_DRAIN);
+        self.wait_until(|unit| unit.regs.read64(reg) & IOTLB_IVT == 0)
+    }
+
+    fn wait_until(
+        &self,
+        done: impl Fn(&Self) -> bool,
+    ) -> Result<(), IommuError> {
+        for _ in 0..COMMAND_TIMEOUT_US {
+            if done(self) {
+                return Ok(());
+            }
+            delay_us(1);
+        }
+
+        Err(IommuError::Timeout(self.paddr))
+    }
+}
+
+impl Iommu {
+    fn invalidate_iotlb(&self) {
+        for unit in &self.units {
+            if let Err(e) = unit.invalidate_iotlb() {
+                warning!("iommu: {e}");
+            }
+        }
+    }
+}
+
+impl IoPageTable {
+    fn new(levels: usize) -> Result<Self, IommuError> {
+        Ok(Self {
+            root: alloc_table()?,
+            levels,
+        })
+    }
+
+    /// Map the page at the I/O virtual address `iova` to the frame `paddr`.
+    fn map(
+        &mut self,
+        iova: u64,
+        paddr: PAddr,
+        writable: bool,
+    ) -> Result<(), IommuError> {
+        let mut table = self.root;
+        for level in (1..self.levels).rev() {
+            let entry = &mut table_entries(table)[index_at(iova, level)];
+            if *entry & (PTE_READ | PTE_WRITE) == 0 {
+                *entry = alloc_table()?.0 | PTE_READ | PTE_WRITE;
+            }
+            table = PAddr(*entry & PTE_ADDR_MASK);
+        }
+
+        let mut entry = paddr.0 & PTE_ADDR_MASK | PTE_READ;
+        if writable {
+            entry |= PTE_WRITE;
+        }
+        table_entries(table)[index_at(iova, 0)] = entry;
+        Ok(())
+    }
+
+    /// Unmap the page at `iova`; the intermediate tables are kept.
+    fn unmap(&mut self, iova: u64) {
+        if let Some(table) = self.leaf_table(iova) {
+            table_entries(table)[index_at(iova, 0)] = 0;
+        }
+    }
+
+    /// The frame the page at `iova` is mapped to, if any.
+    #[cfg(test)]
+    fn translate(&self, iova: u64) -> Option<PAddr> {
+        let entry = table_entries(self.leaf_table(iova)?)[index_at(iova, 0)];
+        (entry & PTE_READ != 0).then_some(PAddr(entry & PTE_ADDR_MASK))
+    }
+
+    fn leaf_table(&self, iova: u64) -> Option<PAddr> {
+        let mut table = self.root;
+        for level in (1..self.levels).rev() {
+            let entry = table_entries(table)[index_at(iova, level)];
+            if entry & (PTE_READ | PTE_WRITE) == 0 {
+                return None;
+            }
+            table = PAddr(entry & PTE_ADDR_MASK);
+        }
+
+        Some(table)
+    }
+}
+
+/// The index in a table of `level`, 0 for the last one, of the entry
+/// translating `iova`.
+fn index_at(iova: u64, level: usize) -> usize {
+    (iova >> (12 + 9 * level) & 0x1ff) as usize
+}
+
+/// Allocate a zeroed table of translation entries.
+fn alloc_table() -> Result<PAddr, IommuError> {
+    allocate_frames().zero_mem().allocate().ok_or(IommuError::OutOfMemory)
+}
+
+/// The 64-bit entries of the table in the frame at `table`; root and context
+/// entries are 128-bit, i.e. two of them.
+fn table_entries(table: PAddr) -> &'static mut [u64] {
+    unsafe {
+        slice::from_raw_parts_mut(table.into_vaddr().as_mut_ptr(),
+                                  PAGE_SIZE / 8)
+    }
+}
+
+/// The addresses of the pages spanning `paddr..paddr+bsize`.
+fn pages_of(paddr: PAddr, bsize: usize) -> impl Iterator<Item = u64> {
+    let start = paddr.0 & !(PAGE_SIZE as u64 - 1);
+    let end = paddr.0 + bsize as u64;
+    (start..end).step_by(PAGE_SIZE)
+}
+
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Option<[u8; N]> {
+    bytes.get(offset..(offset + N))?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use crate::arch::mem::{reset_memory, MEMORY_MUTEX};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use super::*;
+
+    #[test]
+    fn it_parses_the_dmar() {
+        let mut dmar = vec![0u8; 48];
+        dmar[36] = 38;
+
+        // A DRHD for all devices, and an RMRR.
+        let mut drhd = vec![0u8; 16];
+        drhd[2] = 16;
+        drhd[4] = DRHD_INCLUDE_PCI_ALL;
+        drhd[8..16].copy_from_slice(&0xfed9_0000u64.to_le_bytes());
+        let mut rmrr = vec![0u8; 24];
+        rmrr[0] = 1;
+        rmrr[2] = 24;
+        rmrr[8..16].copy_from_slice(&0x7d00_0000u64.to_le_bytes());
+        rmrr[16..24].copy_from_slice(&0x7d01_ffffu64.to_le_bytes());
+        dmar.extend(drhd);
+        dmar.extend(rmrr);
+
+        assert_eq!(parse_dmar(&dmar), Some(Dmar {
+            host_address_width: 39,
+            units: vec![RemappingUnit {
+                segment: 0,
+                registers: 0xfed9_0000,
+                include_all: true,
+            }],
+            reserved: vec![0x7d00_0000..0x7d02_0000],
+        }));
+
+        dmar[50] = 2;
+        assert_eq!(parse_dmar(&dmar), None);
+    }
+
+    #[test]
+    fn it_translates_mapped_pages_only() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut table = IoPageTable::new(4).unwrap();
+        table.map(0x7f_1234_5000, PAddr(0x3000), false).unwrap();
+
+        assert_eq!(table.translate(0x7f_1234_5000).map(|p| p.0), Some(0x3000));
+        assert!(table.translate(0x7f_1234_6000).is_none());
+        assert!(table.translate(0x1234_5000).is_none());
+
+        table.unmap(0x7f_1234_5000);
+        assert!(table.translate(0x7f_1234_5000).is_none());
+    }
+}
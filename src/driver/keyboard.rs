@@ -8,21 +8,30 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::format;
+use alloc::string::{String, ToString};
 use core::str::FromStr;
+use core::time::Duration;
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
 
-use crate::{arch, print, println, warning};
+use crate::{arch, fs, initcall, warning};
+use crate::boot::cmdline;
+use crate::driver::input::{self, InputEvent};
+use crate::fs::FsError;
 use crate::sync::Spinlock;
-use crate::ui::keymap::{Keymap, KeymapState};
-use crate::ui::kterm::KERNEL_TERMINAL;
+use crate::task::timer::Timer;
+use crate::ui::accents;
+use crate::ui::keymap::{Keymap, KeymapError, KeymapState};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum KeyEvent {
     Unknown,
     Pressed(Key),
     Released(Key),
 }
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub enum Key {
     LeftShift,
     RightShift,
@@ -142,65 +151,7 @@ impl FromStr for Key {
 
 impl Deadkey {
     pub fn apply(&self, c: char) -> Option<char> {
-        Some(match self {
-            Deadkey::Circumflex => {
-                match c {
-                    'a' => 'â',
-                    'z' => 'ẑ',
-                    'e' => 'ê',
-                    'y' => 'ŷ',
-                    'u' => 'û',
-                    'i' => 'î',
-                    'o' => 'ô',
-                    's' => 'ŝ',
-                    'g' => 'ĝ',
-                    'h' => 'ĥ',
-                    'j' => 'ĵ',
-                    'w' => 'ŵ',
-                    'c' => 'ĉ',
-                    'A' => 'Â',
-                    'Z' => 'Ẑ',
-                    'E' => 'Ê',
-                    'Y' => 'Ŷ',
-                    'U' => 'Û',
-                    'I' => 'Î',
-                    'O' => 'Ô',
-                    'S' => 'Ŝ',
-                    'G' => 'Ĝ',
-                    'H' => 'Ĥ',
-                    'J' => 'Ĵ',
-                    'W' => 'Ŵ',
-                    'C' => 'Ĉ',
-                    _ => return None,
-                }
-            },
-            Deadkey::Diaeresis => {
-                match c {
-                    'a' => 'ä',
-                    'e' => 'ë',
-                    't' => 'ẗ',
-                    'y' => 'ÿ',
-                    'u' => 'ü',
-                    'i' => 'ï',
-                    'o' => 'ö',
-                    'h' => 'ḧ',
-                    'w' => 'ẅ',
-                    'x' => 'ẍ',
-                    'A' => 'Ä',
-                    'E' => 'Ë',
-                    'Y' => 'Ÿ',
-                    'U' => 'Ü',
-                    'I' => 'Ï',
-                    'O' => 'Ö',
-                    'H' => 'Ḧ',
-                    'W' => 'Ẅ',
-                    'X' => 'Ẍ',
-                    _ => return None,
-                }
-            },
-            // TODO: Implement the rest
-            _ => return None,
-        })
+        accents::apply(self, c)
     }
 
     pub fn as_standalone(&self) -> Option<char> {
@@ -237,10 +188,57 @@ impl TryFrom<char> for Deadkey {
     }
 }
 
+/// The keymap loaded when the keyboard is initialized.
+pub const DEFAULT_KEYMAP_PATH: &str = "/keymaps/us.keymap";
+
+/// The directory of the file system holding the keymaps shipped with the
+/// kernel, as `<name>.keymap` files.
+pub const KEYMAP_DIR: &str = "/keymaps/";
+
+/// The default key repeat: after half a second, 20 times per second. Repeats
+/// are run by a software timer, which expires on timer ticks: the tick rate
+/// bounds the effective rate.
+pub const DEFAULT_TYPEMATIC: Typematic = Typematic {
+    delay_ms: 500,
+    rate_hz: 20,
+};
+
+/// The maximum number of keys tracked as held down at once.
+const MAX_HELD_KEYS: usize = 8;
+
 static KEYBOARD: Spinlock<Option<Keyboard>> = Spinlock::new(None);
 
+/// The state of the keyboard's LEDs.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct KeyboardLeds {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+/// The key repeat settings: a key held down for `delay_ms` milliseconds is
+/// repeated `rate_hz` times per second.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Typematic {
+    pub delay_ms: u64,
+    pub rate_hz: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum KeymapLoadError {
+    #[error("couldn't read keymap file: {0}")]
+    Read(#[source] FsError),
+
+    #[error("invalid keymap file")]
+    Invalid,
+
+    #[error("keyboard isn't initialized")]
+    NoKeyboard,
+}
+
 struct Keyboard {
     keymap: KeymapState,
+    keymap_path: String,
 
     lctrl: bool,
     rctrl: bool,
@@ -251,10 +249,23 @@ struct Keyboard {
     lmeta: bool,
     rmeta: bool,
     capslock: bool,
+    numlock: bool,
+
+    /// The keys currently held down, to tell the keyboard's own repeats apart.
+    held: ArrayVec<Key, MAX_HELD_KEYS>,
+    repeat: Option<Repeat>,
+    typematic: Typematic,
+}
+
+/// The key being repeated, and the timer of its next repeat.
+#[derive(Copy, Clone)]
+struct Repeat {
+    key: Key,
+    timer: Timer,
 }
 
 impl Keyboard {
-    pub fn new() -> Self {
+    pub fn new(keymap: Keymap, keymap_path: String) -> Self {
         Self {
             lctrl: false,
             rctrl: false,
@@ -265,9 +276,12 @@ impl Keyboard {
             lmeta: false,
             rmeta: false,
             capslock: false,
-            keymap: KeymapState::new(Keymap::from_file(include_bytes!(
-                concat!(env!("CARGO_MANIFEST_DIR"), "/media/us.keymap")
-            )).unwrap()),
+            numlock: false,
+            held: ArrayVec::new(),
+            repeat: None,
+            typematic: DEFAULT_TYPEMATIC,
+            keymap: KeymapState::new(keymap),
+            keymap_path,
         }
     }
 
@@ -285,43 +299,41 @@ impl Keyboard {
 
     pub fn on_key_event(&mut self, event: KeyEvent) {
         match event {
-            KeyEvent::Pressed(key) =>
-                match key {
-                    Key::Space => print!(" "),
-                    Key::Enter | Key::KeypadEnter => println!(),
-                    Key::ScrollLock => arch::cpu::reset(),
-
-                    Key::LeftShift => self.lshift = true,
-                    Key::RightShift => self.rshift = true,
-                    Key::LeftCtrl => self.lctrl = true,
-                    Key::RightCtrl => self.rctrl = true,
-                    Key::Alt => self.alt = true,
-                    Key::AltGr => self.altgr = true,
-                    Key::LeftMeta => self.lmeta = true,
-                    Key::RightMeta => self.rmeta = true,
-                    Key::CapsLock => self.capslock = !self.capslock, // TODO: LED
-
-                    _ => {
-                        if self.has_ctrl() {
-                            match key {
-                                Key::Letter('L') => KERNEL_TERMINAL.lock().as_mut().unwrap().clear(),
-                                _ => (),
-                            }
-                            return;
-                        }
-
-                        let c = self.keymap.glyph(
-                            key,
-                            self.altgr,
-                            self.capslock,
-                            self.has_shift()
-                        );
-                        if let Some(c) = c {
-                            print!("{c}");
-                        }
-                    },
-                },
-            KeyEvent::Released(key) =>
+            KeyEvent::Pressed(key) => {
+                // A key held down is repeated by the keyboard itself; these
+                // repeats are dropped in favor of ours, see `on_repeat()`.
+                if self.held.contains(&key) {
+                    return;
+                }
+                let _ = self.held.try_push(key);
+
+                if is_repeatable(key) {
+                    self.stop_repeat();
+                    let delay = Duration::from_millis(self.typematic.delay_ms);
+                    self.repeat = Some(Repeat {
+                        key,
+                        timer: Timer::after(delay, repeat_key),
+                    });
+                }
+
+                input::publish(InputEvent::Key {
+                    key,
+                    pressed: true,
+                    repeat: false,
+                });
+                self.on_press(key);
+            },
+            KeyEvent::Released(key) => {
+                self.held.retain(|held| *held != key);
+                if self.repeat.is_some_and(|repeat| repeat.key == key) {
+                    self.stop_repeat();
+                }
+
+                input::publish(InputEvent::Key {
+                    key,
+                    pressed: false,
+                    repeat: false,
+                });
                 match key {
                     Key::LeftShift => self.lshift = false,
                     Key::RightShift => self.rshift = false,
@@ -332,14 +344,189 @@ impl Keyboard {
                     Key::LeftMeta => self.lmeta = false,
                     Key::RightMeta => self.rmeta = false,
                     _ => (),
-                },
-            _ => (),
+                }
+            },
+            KeyEvent::Unknown => (),
+        }
+    }
+
+    /// Repeat the last key pressed if it is still held down, and arm the timer
+    /// of its next repeat.
+    fn on_repeat(&mut self) {
+        let Some(repeat) = self.repeat.as_mut() else {
+            return;
+        };
+
+        let period = 1000 / self.typematic.rate_hz.max(1);
+        repeat.timer = Timer::after(Duration::from_millis(period), repeat_key);
+        let key = repeat.key;
+
+        input::publish(InputEvent::Key {
+            key,
+            pressed: true,
+            repeat: true,
+        });
+        self.on_press(key);
+    }
+
+    fn stop_repeat(&mut self) {
+        if let Some(repeat) = self.repeat.take() {
+            repeat.timer.cancel();
+        }
+    }
+
+    fn on_press(&mut self, key: Key) {
+        match key {
+            Key::Space => {
+                if let Some(c) = self.keymap.translate(' ') {
+                    self.input(c);
+                }
+            },
+            Key::Enter | Key::KeypadEnter => self.input('\n'),
+            Key::Backspace => self.input('\x08'),
+            Key::ScrollLock => arch::cpu::reset(),
+            Key::Menu => self.keymap.start_compose(),
+
+            Key::LeftShift => self.lshift = true,
+            Key::RightShift => self.rshift = true,
+            Key::LeftCtrl => self.lctrl = true,
+            Key::RightCtrl => self.rctrl = true,
+            Key::Alt => self.alt = true,
+            Key::AltGr => self.altgr = true,
+            Key::LeftMeta => self.lmeta = true,
+            Key::RightMeta => self.rmeta = true,
+            Key::CapsLock => {
+                self.capslock = !self.capslock;
+                self.update_leds();
+            },
+            Key::KeypadNumLock => {
+                self.numlock = !self.numlock;
+                self.update_leds();
+            },
+
+            _ => {
+                if self.has_ctrl() {
+                    match key {
+                        Key::Letter('L') => self.input('\x0c'),
+                        Key::Letter('C') => self.input('\x03'),
+                        _ => (),
+                    }
+                    return;
+                }
+
+                let c = self.keymap.glyph(
+                    key,
+                    self.altgr,
+                    self.capslock,
+                    self.has_shift()
+                );
+                if let Some(c) = c {
+                    self.input(c);
+                }
+            },
         }
     }
+
+    fn update_leds(&self) {
+        arch::keyboard::set_leds(KeyboardLeds {
+            caps_lock: self.capslock,
+            num_lock: self.numlock,
+            scroll_lock: false,
+        });
+    }
+
+    fn input(&mut self, c: char) {
+        input::publish(InputEvent::Char(c));
+    }
 }
 
 pub fn init() {
-    *KEYBOARD.lock() = Some(Keyboard::new());
+    let keymap = read_keymap(DEFAULT_KEYMAP_PATH)
+        .expect("couldn't load the default keymap");
+    *KEYBOARD.lock() = Some(Keyboard::new(
+        keymap, DEFAULT_KEYMAP_PATH.to_string()
+    ));
+}
+
+/// Set the keyboard up with the keymap of the `keymap=` boot option, if any.
+fn start() {
+    init();
+    if let Some(name) = cmdline::get().keymap() {
+        if let Err(e) = load_keymap_by_name(name) {
+            warning!("keymap '{name}': {e}");
+        }
+    }
+}
+
+initcall!(core, start, "Initializing the keyboard...");
+
+/// Change the key repeat settings.
+pub fn set_typematic(typematic: Typematic) {
+    if let Some(kb) = KEYBOARD.lock().as_mut() {
+        kb.typematic = typematic;
+    }
+}
+
+/// Repeat the key held down; this is the callback of the repeat timer.
+fn repeat_key() {
+    if let Some(kb) = KEYBOARD.lock().as_mut() {
+        kb.on_repeat();
+    }
+}
+
+/// Replace the keyboard's keymap with the one read from the file at `path`. On
+/// error, the current keymap is left untouched.
+pub fn load_keymap(path: &str) -> Result<(), KeymapLoadError> {
+    let keymap = read_keymap(path)?;
+    let mut kb = KEYBOARD.lock();
+    let kb = kb.as_mut().ok_or(KeymapLoadError::NoKeyboard)?;
+
+    kb.keymap = KeymapState::new(keymap);
+    kb.keymap_path = path.to_string();
+
+    Ok(())
+}
+
+/// Replace the keyboard's keymap with the one named `name` in `KEYMAP_DIR`,
+/// e.g. `"fr"`.
+pub fn load_keymap_by_name(name: &str) -> Result<(), KeymapLoadError> {
+    load_keymap(&format!("{KEYMAP_DIR}{name}.keymap"))
+}
+
+/// The path of the current keymap, if the keyboard is initialized.
+pub fn keymap_path() -> Option<String> {
+    KEYBOARD.lock().as_ref().map(|kb| kb.keymap_path.clone())
+}
+
+/// Iterate over the names of the keymaps shipped in `KEYMAP_DIR`.
+pub fn available_keymaps() -> impl Iterator<Item = &'static str> {
+    fs::files().filter_map(|path| {
+        path.strip_prefix(KEYMAP_DIR)?.strip_suffix(".keymap")
+    })
+}
+
+/// Read the current keymap again from its file, e.g. after it was modified.
+pub fn reload_keymap() -> Result<(), KeymapLoadError> {
+    let path = KEYBOARD.lock().as_ref()
+        .ok_or(KeymapLoadError::NoKeyboard)?
+        .keymap_path.clone();
+    load_keymap(&path)
+}
+
+fn read_keymap(path: &str) -> Result<Keymap, KeymapLoadError> {
+    let data = fs::read(path).map_err(KeymapLoadError::Read)?;
+    Keymap::from_file(data).map_err(|e| match e {
+        KeymapError::InvalidKeymapFile => KeymapLoadError::Invalid,
+    })
+}
+
+/// Whether `key` is repeated when held down: modifiers and keys toggling a
+/// state aren't.
+fn is_repeatable(key: Key) -> bool {
+    !matches!(key,
+        Key::LeftShift | Key::RightShift | Key::LeftCtrl | Key::RightCtrl
+        | Key::LeftMeta | Key::RightMeta | Key::Alt | Key::AltGr | Key::Menu
+        | Key::CapsLock | Key::KeypadNumLock | Key::ScrollLock)
 }
 
 pub fn on_key_event(event: KeyEvent) {
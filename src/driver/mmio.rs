@@ -0,0 +1,178 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Typed accessors for memory-mapped I/O. Device registers must only ever be
+//! accessed with volatile operations of the right width, and in the device's
+//! byte order; drivers use `Reg` and `RegBlock` instead of raw pointers so that
+//! this is enforced in a single place.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+
+use crate::debug;
+
+/// A value that can be held by a device register.
+pub trait RegValue: Copy + core::fmt::LowerHex {
+    fn from_le(self) -> Self;
+    fn from_be(self) -> Self;
+}
+
+macro_rules! impl_reg_value {
+    ($($t:ty),*) => {
+        $(impl RegValue for $t {
+            #[inline(always)]
+            fn from_le(self) -> Self { <$t>::from_le(self) }
+
+            #[inline(always)]
+            fn from_be(self) -> Self { <$t>::from_be(self) }
+        })*
+    };
+}
+
+impl_reg_value!(u8, u16, u32, u64);
+
+/// The byte order of a device's registers.
+pub trait Endianness {
+    /// Convert between the device and the CPU byte orders; the conversion is
+    /// its own inverse.
+    fn convert<T: RegValue>(value: T) -> T;
+}
+
+pub struct LittleEndian;
+pub struct BigEndian;
+
+impl Endianness for LittleEndian {
+    #[inline(always)]
+    fn convert<T: RegValue>(value: T) -> T {
+        value.from_le()
+    }
+}
+
+impl Endianness for BigEndian {
+    #[inline(always)]
+    fn convert<T: RegValue>(value: T) -> T {
+        value.from_be()
+    }
+}
+
+/// A single device register of type `T`, in the byte order `E`.
+#[repr(transparent)]
+pub struct Reg<T: RegValue, E: Endianness = LittleEndian> {
+    value: UnsafeCell<T>,
+    _endianness: PhantomData<E>,
+}
+
+impl<T: RegValue, E: Endianness> Reg<T, E> {
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        E::convert(unsafe { self.value.get().read_volatile() })
+    }
+
+    #[inline(always)]
+    pub fn write(&self, value: T) {
+        unsafe { self.value.get().write_volatile(E::convert(value)) }
+    }
+
+    #[inline(always)]
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+/// A contiguous window of device registers, such as a PCI BAR or the LAPIC's
+/// page. Every access is bound-checked; accesses can optionally be logged to
+/// debug drivers.
+pub struct RegBlock<E: Endianness = LittleEndian> {
+    base: *mut u8,
+    bsize: usize,
+    log_name: Option<&'static str>,
+    _endianness: PhantomData<E>,
+}
+
+unsafe impl<E: Endianness> Send for RegBlock<E> {}
+
+impl<E: Endianness> RegBlock<E> {
+    /// # Safety #
+    ///
+    /// `base` must be the virtual address of a mapping of at least `bsize`
+    /// bytes of device memory, valid for as long as the block lives, and not
+    /// accessed by any other mean.
+    pub unsafe fn new(base: *mut u8, bsize: usize) -> Self {
+        Self {
+            base,
+            bsize,
+            log_name: None,
+            _endianness: PhantomData,
+        }
+    }
+
+    /// Log every read and write performed through this block at the debug
+    /// level, prefixed by `name`.
+    pub fn with_logging(mut self, name: &'static str) -> Self {
+        self.log_name = Some(name);
+        self
+    }
+
+    #[inline]
+    pub fn bsize(&self) -> usize {
+        self.bsize
+    }
+
+    /// Return the register of type `T` at byte `offset`.
+    ///
+    /// # Panics #
+    ///
+    /// If the register is out of bounds or misaligned.
+    #[inline]
+    pub fn reg<T: RegValue>(&self, offset: usize) -> &Reg<T, E> {
+        self.check::<T>(offset, 1);
+        unsafe { &*(self.base.add(offset) as *const Reg<T, E>) }
+    }
+
+    #[inline]
+    pub fn read<T: RegValue>(&self, offset: usize) -> T {
+        let value = self.reg::<T>(offset).read();
+        if let Some(name) = self.log_name {
+            debug!("mmio {name}: read  [{offset:#x}] -> {value:#x}");
+        }
+        value
+    }
+
+    #[inline]
+    pub fn write<T: RegValue>(&self, offset: usize, value: T) {
+        if let Some(name) = self.log_name {
+            debug!("mmio {name}: write [{offset:#x}] <- {value:#x}");
+        }
+        self.reg::<T>(offset).write(value);
+    }
+
+    /// Write consecutive registers starting at byte `offset`, e.g. a row of
+    /// pixels into a framebuffer. Accesses are never logged.
+    #[inline]
+    pub fn write_slice<T: RegValue>(&self, offset: usize, values: &[T]) {
+        self.check::<T>(offset, values.len());
+
+        let regs = unsafe { self.base.add(offset) as *mut T };
+        for (i, &value) in values.iter().enumerate() {
+            unsafe { regs.add(i).write_volatile(E::convert(value)) };
+        }
+    }
+
+    #[inline(always)]
+    fn check<T>(&self, offset: usize, count: usize) {
+        let end = count.checked_mul(size_of::<T>())
+            .and_then(|bsize| offset.checked_add(bsize));
+        assert!(end.is_some_and(|end| end <= self.bsize),
+                "MMIO access at {offset:#x} out of bounds ({:#x})", self.bsize);
+        assert_eq!((self.base as usize + offset) % align_of::<T>(), 0,
+                   "misaligned MMIO access at {offset:#x}");
+    }
+}
@@ -8,7 +8,17 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+pub mod acpi;
 pub mod gpio;
+pub mod input;
+pub mod iommu;
 pub mod vga;
 pub mod screen;
 pub mod keyboard;
+pub mod mmio;
+pub mod mouse;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod pci;
+pub mod usb;
+pub mod virtio;
@@ -0,0 +1,39 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Pointing devices. Mouse drivers decode their device's reports into
+//! `MouseEvent`s, which are published on the input event queue.
+
+use crate::driver::input::{self, InputEvent};
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// A report of a mouse's motion and button state.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// The horizontal motion, positive to the right.
+    pub dx: i32,
+    /// The vertical motion, positive downwards, as on screen.
+    pub dy: i32,
+    /// The wheel motion, positive when scrolling down.
+    pub wheel: i8,
+    /// The buttons held down.
+    pub buttons: MouseButtons,
+}
+
+/// Publish a mouse report on the input event queue.
+pub fn on_mouse_event(event: MouseEvent) {
+    input::publish(InputEvent::Mouse(event));
+}
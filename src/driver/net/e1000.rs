@@ -0,0 +1,347 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The Intel 8254x (e1000) gigabit ethernet controller, as emulated by QEMU
+//! and most hypervisors. Frames go through two rings of 16-byte descriptors
+//! shared in memory, each pointing to a 2 KiB buffer: the receive ring, filled
+//! by the controller, and the transmit ring, filled by the driver. The ring's
+//! tail register hands descriptors over to the controller, which writes back
+//! their status once done with them.
+//!
+//! Frames are copied to and from the buffers. The controller interrupts on
+//! received frames, transmitted frames and link changes.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::arch::time::delay_us;
+use crate::driver::net::{self, MacAddress, NetDevice, NetError};
+use crate::driver::pci::{self, Bar, PciDevice};
+use crate::mem::dma::DmaPages;
+use crate::mem::iomap::iomap;
+use crate::mem::{resource, CacheMode, MmioRegion};
+use crate::{info, warning};
+
+pub const PCI_VENDOR_INTEL: u16 = 0x8086;
+
+/// The supported controllers: the 82540EM, QEMU's default, and the 82545EM.
+const PCI_DEVICE_IDS: [u16; 2] = [0x100e, 0x100f];
+
+const REG_CTRL: usize = 0x0000;
+const REG_STATUS: usize = 0x0008;
+const REG_ICR: usize = 0x00c0;
+const REG_IMS: usize = 0x00d0;
+const REG_IMC: usize = 0x00d8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+const REG_MTA: usize = 0x5200;
+const REG_RAL0: usize = 0x5400;
+const REG_RAH0: usize = 0x5404;
+
+const MTA_LEN: usize = 128;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const STATUS_LU: u32 = 1 << 1;
+
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// The controller strips the frame check sequence from received frames.
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// The inter-packet gap recommended for the IEEE 802.3 standard.
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+const DESC_STATUS_DD: u32 = 1 << 0;
+const RX_STATUS_EOP: u32 = 1 << 1;
+
+const TX_CMD_EOP: u32 = 1 << 0;
+const TX_CMD_IFCS: u32 = 1 << 1;
+const TX_CMD_RS: u32 = 1 << 3;
+
+const DESC_SIZE: usize = 16;
+const NR_DESCS: usize = 32;
+/// The size of every buffer, the default receive buffer size of `RCTL`.
+const BUFFER_SIZE: usize = 2048;
+const BUFFERS_FRAMES: usize = NR_DESCS * BUFFER_SIZE / PAGE_SIZE;
+
+const RESET_TIMEOUT_US: u64 = 100_000;
+const POLL_PERIOD_US: u64 = 10;
+
+/// A descriptor ring, with the buffers of its descriptors.
+struct Ring {
+    descs: DmaPages,
+    buffers: DmaPages,
+    /// The next descriptor the driver processes.
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Result<Self, NetError> {
+        let ring = Self {
+            descs: DmaPages::new(1).ok_or(NetError::NoMemory)?,
+            buffers: DmaPages::new(BUFFERS_FRAMES).ok_or(NetError::NoMemory)?,
+            next: 0,
+        };
+
+        for i in 0..NR_DESCS {
+            ring.descs.write64(i * DESC_SIZE, ring.buffer_paddr(i));
+        }
+
+        Ok(ring)
+    }
+
+    fn buffer_paddr(&self, index: usize) -> u64 {
+        self.buffers.paddr().0 + (index * BUFFER_SIZE) as u64
+    }
+
+    /// The status byte of the descriptor `index`.
+    fn status(&self, index: usize) -> u32 {
+        self.descs.read32(index * DESC_SIZE + 12) & 0xff
+    }
+}
+
+pub struct E1000 {
+    regs: MmioRegion,
+    irq: u8,
+    mac: MacAddress,
+    rx: Ring,
+    tx: Ring,
+}
+
+impl E1000 {
+    /// Reset the controller `dev`, and start receiving and transmitting.
+    pub fn new(dev: PciDevice) -> Result<Self, NetError> {
+        let Some(Bar::Memory { paddr, bsize, .. }) = dev.bar(0) else {
+            return Err(NetError::NoRegisters);
+        };
+        resource::request_region(paddr, bsize, "e1000")
+            .map_err(NetError::Resource)?;
+        let regs = iomap(paddr, bsize as usize, CacheMode::Uncached)
+            .map_err(NetError::Iomap)?;
+        dev.enable();
+
+        let mut e1000 = Self {
+            regs,
+            irq: dev.interrupt_line(),
+            mac: MacAddress([0; 6]),
+            rx: Ring::new()?,
+            tx: Ring::new()?,
+        };
+
+        e1000.reset()?;
+        e1000.mac = e1000.read_mac_address();
+        e1000.start();
+
+        Ok(e1000)
+    }
+
+    fn reset(&self) -> Result<(), NetError> {
+        self.regs.write32(REG_IMC, u32::MAX);
+        self.regs.write32(REG_CTRL, self.regs.read32(REG_CTRL) | CTRL_RST);
+        delay_us(POLL_PERIOD_US);
+
+        for _ in 0..(RESET_TIMEOUT_US / POLL_PERIOD_US) {
+            if self.regs.read32(REG_CTRL) & CTRL_RST == 0 {
+                self.regs.write32(REG_IMC, u32::MAX);
+                self.regs.read32(REG_ICR);
+                return Ok(());
+            }
+            delay_us(POLL_PERIOD_US);
+        }
+
+        Err(NetError::Timeout)
+    }
+
+    /// The MAC address in the first receive address register, loaded from the
+    /// EEPROM on reset.
+    fn read_mac_address(&self) -> MacAddress {
+        let low = self.regs.read32(REG_RAL0).to_le_bytes();
+        let high = self.regs.read32(REG_RAH0).to_le_bytes();
+
+        MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+    }
+
+    fn start(&mut self) {
+        self.regs.write32(REG_CTRL,
+                          self.regs.read32(REG_CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        // Only accept frames to our address and broadcasts.
+        let [a, b, c, d, e, f] = self.mac.0;
+        self.regs.write32(REG_RAL0, u32::from_le_bytes([a, b, c, d]));
+        self.regs.write32(REG_RAH0, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+        for i in 0..MTA_LEN {
+            self.regs.write32(REG_MTA + 4 * i, 0);
+        }
+
+        // All the receive descriptors but one are given to the controller:
+        // the ring is full when the head reaches the tail.
+        let rx_paddr = self.rx.descs.paddr().0;
+        self.regs.write32(REG_RDBAL, rx_paddr as u32);
+        self.regs.write32(REG_RDBAH, (rx_paddr >> 32) as u32);
+        self.regs.write32(REG_RDLEN, (NR_DESCS * DESC_SIZE) as u32);
+        self.regs.write32(REG_RDH, 0);
+        self.regs.write32(REG_RDT, (NR_DESCS - 1) as u32);
+        self.regs.write32(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        // Transmit descriptors are free once done, so start them all done.
+        for i in 0..NR_DESCS {
+            self.tx.descs.write32(i * DESC_SIZE + 12, DESC_STATUS_DD);
+        }
+        let tx_paddr = self.tx.descs.paddr().0;
+        self.regs.write32(REG_TDBAL, tx_paddr as u32);
+        self.regs.write32(REG_TDBAH, (tx_paddr >> 32) as u32);
+        self.regs.write32(REG_TDLEN, (NR_DESCS * DESC_SIZE) as u32);
+        self.regs.write32(REG_TDH, 0);
+        self.regs.write32(REG_TDT, 0);
+        self.regs.write32(REG_TIPG, TIPG_DEFAULT);
+        self.regs.write32(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        self.regs.write32(REG_IMS,
+                          INT_RXT0 | INT_RXO | INT_RXDMT0 | INT_LSC | INT_TXDW);
+    }
+}
+
+impl NetDevice for E1000 {
+    fn driver(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.regs.read32(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.is_empty() || frame.len() > BUFFER_SIZE {
+            return Err(NetError::InvalidLength(frame.len()));
+        }
+        let index = self.tx.next;
+        if self.tx.status(index) & DESC_STATUS_DD == 0 {
+            return Err(NetError::QueueFull);
+        }
+
+        let desc = index * DESC_SIZE;
+        self.tx.buffers.write_bytes(index * BUFFER_SIZE, frame);
+        self.tx.descs.write32(
+            desc + 8,
+            frame.len() as u32 | (TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS) << 24,
+        );
+        self.tx.descs.write32(desc + 12, 0);
+
+        self.tx.next = (index + 1) % NR_DESCS;
+        fence(Ordering::SeqCst);
+        self.regs.write32(REG_TDT, self.tx.next as u32);
+
+        Ok(())
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Option<usize> {
+        loop {
+            let index = self.rx.next;
+            let status = self.rx.status(index);
+            if status & DESC_STATUS_DD == 0 {
+                return None;
+            }
+            fence(Ordering::Acquire);
+
+            let desc = index * DESC_SIZE;
+            let len = (self.rx.descs.read32(desc + 8) & 0xffff) as usize;
+            let errors = (self.rx.descs.read32(desc + 12) >> 8) & 0xff;
+            let len = len.min(buf.len());
+            self.rx.buffers.read_bytes(index * BUFFER_SIZE, &mut buf[..len]);
+
+            // Hand the descriptor back to the controller.
+            self.rx.descs.write32(desc + 12, 0);
+            self.rx.next = (index + 1) % NR_DESCS;
+            fence(Ordering::SeqCst);
+            self.regs.write32(REG_RDT, index as u32);
+
+            // Frames spanning several buffers can't happen with the maximum
+            // frame length; drop them along with erroneous ones anyway.
+            if status & RX_STATUS_EOP != 0 && errors == 0 {
+                return Some(len);
+            }
+        }
+    }
+
+    fn handle_irq(&mut self) -> bool {
+        // Reading the cause clears it, and lowers the interrupt line.
+        let cause = self.regs.read32(REG_ICR);
+
+        if cause & INT_LSC != 0 {
+            let state = if self.link_up() { "up" } else { "down" };
+            info!("e1000: link {state}");
+        }
+        if cause & INT_RXO != 0 {
+            warning!("e1000: receive overrun, frames were dropped");
+        }
+
+        cause != 0
+    }
+}
+
+/// Start all the e1000 controllers found on the PCI bus.
+pub fn init() {
+    let controllers = pci::devices().into_iter().filter(|dev| {
+        dev.vendor_id == PCI_VENDOR_INTEL
+            && PCI_DEVICE_IDS.contains(&dev.device_id)
+    });
+
+    for dev in controllers {
+        if let Err(e) = dev.claim("e1000") {
+            warning!("net: {e}");
+            continue;
+        }
+
+        match E1000::new(dev) {
+            Ok(e1000) => {
+                let mac = e1000.mac_address();
+                let index = net::register(Box::new(e1000));
+                info!("net: eth{index}: e1000 at {}, MAC {mac}", dev.addr);
+            },
+            Err(e) => warning!("net: e1000 at {}: {e}", dev.addr),
+        }
+    }
+}
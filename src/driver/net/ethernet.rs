@@ -0,0 +1,145 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Ethernet II framing: a 14-byte header of the destination and source MAC
+//! addresses, then the EtherType telling the protocol of the payload. Frames
+//! are handed to the devices without their frame check sequence, which the
+//! hardware computes and strips.
+
+use crate::driver::net::{self, MacAddress, NetError};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// The EtherType reserved by IEEE 802 for local experiments, e.g. the kernel
+/// log stream.
+pub const ETHERTYPE_LOCAL_EXPERIMENTAL: u16 = 0x88b5;
+
+pub const HEADER_LEN: usize = 14;
+pub const MAX_PAYLOAD_LEN: usize = 1500;
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN;
+/// Shorter frames are padded with zeros up to this length.
+pub const MIN_FRAME_LEN: usize = 60;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EthernetHeader {
+    pub dst: MacAddress,
+    pub src: MacAddress,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    /// Split `frame` into its header and payload; `None` if it is too short.
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let mac = |offset: usize| {
+            MacAddress(frame[offset..(offset + 6)].try_into().unwrap())
+        };
+
+        let header = Self {
+            dst: mac(0),
+            src: mac(6),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        };
+
+        Some((header, &frame[HEADER_LEN..]))
+    }
+
+    /// Write the frame of this header and `payload` into `buf`, padded to
+    /// `MIN_FRAME_LEN`; return the frame's length.
+    pub fn build(
+        &self,
+        payload: &[u8],
+        buf: &mut [u8; MAX_FRAME_LEN],
+    ) -> Result<usize, NetError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(NetError::InvalidLength(payload.len()));
+        }
+        let len = (HEADER_LEN + payload.len()).max(MIN_FRAME_LEN);
+
+        buf[0..6].copy_from_slice(&self.dst.0);
+        buf[6..12].copy_from_slice(&self.src.0);
+        buf[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+        buf[HEADER_LEN..(HEADER_LEN + payload.len())].copy_from_slice(payload);
+        buf[(HEADER_LEN + payload.len())..len].fill(0);
+
+        Ok(len)
+    }
+}
+
+/// Send `payload` as a frame of type `ethertype` to `dst`, through the network
+/// device `index`.
+pub fn send(
+    index: usize,
+    dst: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let src = net::with_device(index, |dev| dev.mac_address())?;
+    let mut buf = [0; MAX_FRAME_LEN];
+    let len = EthernetHeader { dst, src, ethertype }.build(payload, &mut buf)?;
+
+    net::transmit(index, &buf[..len])
+}
+
+/// Wait for a frame on the network device `index`, and read it into `buf`;
+/// return its header and the length of its payload, at `HEADER_LEN` in `buf`.
+/// Runt frames, shorter than a header, are dropped.
+pub fn receive(
+    index: usize,
+    buf: &mut [u8; MAX_FRAME_LEN],
+) -> Result<(EthernetHeader, usize), NetError> {
+    loop {
+        let len = net::receive(index, buf)?;
+        if let Some((header, payload)) = EthernetHeader::parse(&buf[..len]) {
+            return Ok((header, payload.len()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_and_parses_frames() {
+        let header = EthernetHeader {
+            dst: MacAddress::BROADCAST,
+            src: MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            ethertype: ETHERTYPE_ARP,
+        };
+        let mut buf = [0xaa; MAX_FRAME_LEN];
+
+        let len = header.build(b"hello", &mut buf).unwrap();
+        assert_eq!(len, MIN_FRAME_LEN);
+        assert_eq!(&buf[12..19], b"\x08\x06hello");
+        assert!(buf[19..len].iter().all(|&byte| byte == 0));
+
+        let (parsed, payload) = EthernetHeader::parse(&buf[..len]).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(&payload[..5], b"hello");
+    }
+
+    #[test]
+    fn it_rejects_invalid_lengths() {
+        let header = EthernetHeader {
+            dst: MacAddress::BROADCAST,
+            src: MacAddress::BROADCAST,
+            ethertype: ETHERTYPE_IPV4,
+        };
+        let mut buf = [0; MAX_FRAME_LEN];
+
+        assert!(matches!(header.build(&[0; MAX_PAYLOAD_LEN + 1], &mut buf),
+                         Err(NetError::InvalidLength(1501))));
+        assert_eq!(EthernetHeader::parse(&buf[..13]), None);
+    }
+}
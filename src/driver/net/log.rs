@@ -0,0 +1,74 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Streaming of the kernel log over ethernet: every log message is broadcast
+//! as the payload of a frame of type `ETHERTYPE_LOCAL_EXPERIMENTAL`, as the
+//! text `<severity>: <message>`. It can be watched from another machine on the
+//! same link, e.g. with `tcpdump -A ether proto 0x88b5`.
+//!
+//! Messages are best-effort: they are dropped rather than waited for when the
+//! transmit ring is full, or when they are logged by the network code itself.
+
+use alloc::boxed::Box;
+use arrayvec::ArrayString;
+use core::fmt::{self, Write};
+
+use crate::driver::net::ethernet::{
+    EthernetHeader, ETHERTYPE_LOCAL_EXPERIMENTAL, MAX_FRAME_LEN, MAX_PAYLOAD_LEN,
+};
+use crate::driver::net::{self, MacAddress, NetError};
+use crate::logging::{reset_logger, Logger, Severity, DEFAULT_LOGGER};
+
+pub struct NetLogger {
+    inner: &'static mut (dyn Logger + Send),
+    device: usize,
+    mac: MacAddress,
+}
+
+impl Logger for NetLogger {
+    fn log(&mut self, severity: Severity, args: fmt::Arguments) {
+        self.inner.log(severity, args);
+
+        // Messages longer than a frame are truncated.
+        let mut line = ArrayString::<MAX_PAYLOAD_LEN>::new();
+        let _ = write!(line, "{}: {}", severity.label(), args);
+
+        let header = EthernetHeader {
+            dst: MacAddress::BROADCAST,
+            src: self.mac,
+            ethertype: ETHERTYPE_LOCAL_EXPERIMENTAL,
+        };
+        let mut buf = [0; MAX_FRAME_LEN];
+        let Ok(len) = header.build(line.as_bytes(), &mut buf) else {
+            return;
+        };
+        let _ = net::try_with_device(self.device, |dev| {
+            dev.transmit(&buf[..len])
+        });
+    }
+}
+
+/// Stream the kernel log through the network device `index`, on top of the
+/// current logger.
+///
+/// # Safety #
+///
+/// Must be called during the boot process, with only one CPU running.
+pub unsafe fn start(index: usize) -> Result<(), NetError> {
+    let mac = net::with_device(index, |dev| dev.mac_address())?;
+    let logger = Box::leak(Box::new(NetLogger {
+        inner: unsafe { reset_logger() },
+        device: index,
+        mac,
+    }));
+    *DEFAULT_LOGGER.lock() = logger;
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Network devices. A driver registers each network interface card it starts
+//! as a `NetDevice`, which sends and receives raw ethernet frames; interfaces
+//! are named after their index in registration order, `eth0` being the first.
+//!
+//! Drivers don't block: a full transmit ring or an empty receive ring is
+//! reported to the caller. Waiting is done here, on queues notified by the
+//! devices' interrupts.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use thiserror_no_std::Error;
+
+use crate::mem::iomap::IomapError;
+use crate::mem::resource::ResourceError;
+use crate::sync::{Spinlock, WaitQueue};
+
+pub mod e1000;
+pub mod ethernet;
+pub mod log;
+
+static DEVICES: Spinlock<Vec<Box<dyn NetDevice>>> = Spinlock::new(Vec::new());
+static RX_WAITERS: WaitQueue = WaitQueue::new();
+static TX_WAITERS: WaitQueue = WaitQueue::new();
+
+/// A 48-bit IEEE 802 MAC address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == Self::BROADCAST
+    }
+
+    /// Whether the address is a group address, i.e. multicast or broadcast.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum NetError {
+    #[error("no network device {0}")]
+    NoDevice(usize),
+
+    #[error("the device has no registers")]
+    NoRegisters,
+
+    #[error("couldn't map the device's registers: {0}")]
+    Iomap(#[source] IomapError),
+
+    #[error("couldn't request the device's registers: {0}")]
+    Resource(#[source] ResourceError),
+
+    #[error("out of memory")]
+    NoMemory,
+
+    #[error("the device didn't answer in time")]
+    Timeout,
+
+    #[error("the transmit ring is full")]
+    QueueFull,
+
+    #[error("invalid frame length {0}")]
+    InvalidLength(usize),
+}
+
+/// A network interface card, sending and receiving ethernet frames, without
+/// their frame check sequence.
+pub trait NetDevice: Send {
+    /// The name of the driver, e.g. `e1000`.
+    fn driver(&self) -> &'static str;
+
+    fn mac_address(&self) -> MacAddress;
+
+    /// Whether the link is established, e.g. a cable is plugged in.
+    fn link_up(&self) -> bool;
+
+    /// The legacy interrupt line of the device.
+    fn irq(&self) -> u8;
+
+    /// Queue `frame` for transmission; `NetError::QueueFull` if the device
+    /// has no room for it until previous frames are sent.
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Pop the oldest received frame into `buf`, returning its length; `None`
+    /// if no frame is waiting. Frames longer than `buf` are truncated.
+    fn receive(&mut self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Acknowledge the device's interrupt; return whether it was raised by
+    /// the device, the line being possibly shared.
+    fn handle_irq(&mut self) -> bool;
+}
+
+/// A summary of a registered network device, as listed by `devices()`.
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub driver: &'static str,
+    pub mac_address: MacAddress,
+    pub link_up: bool,
+}
+
+/// Start the drivers of all the network devices found.
+pub fn init() {
+    e1000::init();
+}
+
+/// Register `device`, started by its driver; return its index.
+pub fn register(device: Box<dyn NetDevice>) -> usize {
+    let mut devices = DEVICES.lock();
+    devices.push(device);
+    devices.len() - 1
+}
+
+/// The registered network devices.
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES.lock().iter()
+        .enumerate()
+        .map(|(index, dev)| DeviceInfo {
+            index,
+            driver: dev.driver(),
+            mac_address: dev.mac_address(),
+            link_up: dev.link_up(),
+        })
+        .collect()
+}
+
+/// Run `f` on the device `index`.
+pub fn with_device<R>(
+    index: usize,
+    f: impl FnOnce(&mut dyn NetDevice) -> R,
+) -> Result<R, NetError> {
+    let mut devices = DEVICES.lock();
+    let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+    Ok(f(dev.as_mut()))
+}
+
+/// Run `f` on the device `index`, unless the devices are in use, e.g. by an
+/// interrupted caller; this is meant for contexts that must not wait.
+pub fn try_with_device<R>(
+    index: usize,
+    f: impl FnOnce(&mut dyn NetDevice) -> R,
+) -> Option<Result<R, NetError>> {
+    let mut devices = DEVICES.try_lock()?;
+    Some(match devices.get_mut(index) {
+        Some(dev) => Ok(f(dev.as_mut())),
+        None => Err(NetError::NoDevice(index)),
+    })
+}
+
+/// Send `frame` through the device `index`, waiting for room in its transmit
+/// ring if needed.
+pub fn transmit(index: usize, frame: &[u8]) -> Result<(), NetError> {
+    TX_WAITERS.wait_until(|| {
+        match with_device(index, |dev| dev.transmit(frame)) {
+            Ok(Err(NetError::QueueFull)) => None,
+            Ok(result) => Some(result),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+/// Receive a frame from the device `index` into `buf`, waiting for one to
+/// arrive; return its length.
+pub fn receive(index: usize, buf: &mut [u8]) -> Result<usize, NetError> {
+    RX_WAITERS.wait_until(|| {
+        with_device(index, |dev| dev.receive(buf)).transpose()
+    })
+}
+
+/// Handle the interrupt `irq` for the network devices on that line; return
+/// whether one of them raised it.
+pub fn on_irq(irq: usize) -> bool {
+    let mut handled = false;
+
+    for dev in DEVICES.lock().iter_mut() {
+        if dev.irq() as usize == irq && dev.handle_irq() {
+            handled = true;
+        }
+    }
+
+    if handled {
+        RX_WAITERS.notify_all();
+        TX_WAITERS.notify_all();
+    }
+
+    handled
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use super::*;
+
+    #[test]
+    fn it_formats_mac_addresses() {
+        let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+        assert_eq!(mac.to_string(), "52:54:00:12:34:56");
+        assert!(!mac.is_multicast());
+        assert!(MacAddress::BROADCAST.is_multicast());
+    }
+}
@@ -0,0 +1,280 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! PCI devices. The configuration space of every function is reachable through
+//! `arch::pci`; devices are found by scanning all buses. A driver claims the
+//! device it drives before touching it, so that two drivers never fight over
+//! the same function; the claims are listed by the `lspci` shell command.
+
+use alloc::vec::Vec;
+use core::{fmt, iter};
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
+
+use crate::arch::pci::{config_read32, config_write32};
+use crate::mem::PAddr;
+use crate::sync::Spinlock;
+
+const MAX_CLAIMS: usize = 32;
+
+const REG_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_STATUS: u8 = 0x06;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0c;
+const REG_BAR0: u8 = 0x10;
+const REG_CAPABILITIES: u8 = 0x34;
+const REG_INTERRUPT_LINE: u8 = 0x3c;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// The most capabilities fitting in the configuration space, after which a
+/// malformed list is considered looping.
+const MAX_CAPABILITIES: usize = 48;
+
+const HEADER_TYPE_MULTIFUNCTION: u8 = 1 << 7;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+static CLAIMS: Spinlock<ArrayVec<PciClaim, MAX_CLAIMS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+/// The location of a PCI function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PciDevice {
+    pub addr: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+/// A base address register, i.e. a window of the device's registers.
+#[derive(Debug, Copy, Clone)]
+pub enum Bar {
+    Memory { paddr: PAddr, bsize: u64, prefetchable: bool },
+    Io { port: u16, len: u16 },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PciClaim {
+    pub addr: PciAddress,
+    pub owner: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum PciError {
+    #[error("PCI device {addr} is already claimed by {owner}")]
+    Conflict { addr: PciAddress, owner: &'static str },
+
+    #[error("too many PCI device claims")]
+    TooManyClaims,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+impl PciAddress {
+    pub fn read32(&self, offset: u8) -> u32 {
+        config_read32(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write32(&self, offset: u8, value: u32) {
+        config_write32(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn read16(&self, offset: u8) -> u16 {
+        (self.read32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read32(offset) & !(0xffff << shift);
+        self.write32(offset, dword | (value as u32) << shift);
+    }
+
+    pub fn read8(&self, offset: u8) -> u8 {
+        (self.read32(offset) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+impl PciDevice {
+    /// Read the function at `addr`, if present.
+    pub fn probe(addr: PciAddress) -> Option<Self> {
+        let id = addr.read32(REG_ID);
+        if id & 0xffff == 0xffff {
+            return None;
+        }
+        let class = addr.read32(REG_CLASS);
+
+        Some(Self {
+            addr,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+        })
+    }
+
+    /// Let the device decode its memory and I/O windows, and master the bus,
+    /// i.e. perform DMA.
+    pub fn enable(&self) {
+        let command = self.addr.read16(REG_COMMAND);
+        self.addr.write16(REG_COMMAND, command
+            | COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    /// The legacy interrupt line, as routed by the firmware.
+    pub fn interrupt_line(&self) -> u8 {
+        self.addr.read8(REG_INTERRUPT_LINE)
+    }
+
+    /// The capabilities of the device, as pairs of their ID and offset in the
+    /// configuration space.
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> {
+        let addr = self.addr;
+        let first = if addr.read16(REG_STATUS) & STATUS_CAPABILITIES != 0 {
+            addr.read8(REG_CAPABILITIES) & !0b11
+        } else {
+            0
+        };
+
+        iter::successors(Some(first), move |&offset| {
+            Some(addr.read8(offset + 1) & !0b11)
+        })
+            .take_while(|&offset| offset != 0)
+            .take(MAX_CAPABILITIES)
+            .map(move |offset| (addr.read8(offset), offset))
+    }
+
+    /// Decode the base address register `index`; `None` if it is unused, or is
+    /// the upper half of a 64-bit one.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= 6 {
+            return None;
+        }
+        let offset = REG_BAR0 + index * 4;
+        let low = self.addr.read32(offset);
+        let is_64 = low & BAR_IO == 0 && low & BAR_TYPE_MASK == BAR_TYPE_64;
+
+        // The size is found by writing all ones and reading back which bits
+        // stuck, with decoding disabled meanwhile.
+        let command = self.addr.read16(REG_COMMAND);
+        self.addr.write16(REG_COMMAND,
+                          command & !(COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE));
+        let size_mask = |offset: u8| {
+            let value = self.addr.read32(offset);
+            self.addr.write32(offset, u32::MAX);
+            let mask = self.addr.read32(offset);
+            self.addr.write32(offset, value);
+            mask
+        };
+        let low_mask = size_mask(offset);
+        let high = if is_64 { self.addr.read32(offset + 4) } else { 0 };
+        let high_mask = if is_64 { size_mask(offset + 4) } else { u32::MAX };
+        self.addr.write16(REG_COMMAND, command);
+
+        if low & BAR_IO != 0 {
+            let mask = low_mask & !0b11;
+            if mask == 0 {
+                return None;
+            }
+            return Some(Bar::Io {
+                port: (low & !0b11) as u16,
+                len: (!mask).wrapping_add(1) as u16,
+            });
+        }
+
+        let mask = (high_mask as u64) << 32 | (low_mask & !0b1111) as u64;
+        if mask & 0xffff_ffff == 0 {
+            return None;
+        }
+
+        Some(Bar::Memory {
+            paddr: PAddr((high as u64) << 32 | (low & !0b1111) as u64),
+            bsize: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+        })
+    }
+
+    /// Claim the device on behalf of the driver `owner`.
+    pub fn claim(&self, owner: &'static str) -> Result<(), PciError> {
+        let mut claims = CLAIMS.lock();
+
+        if let Some(other) = claims.iter().find(|claim| claim.addr == self.addr) {
+            return Err(PciError::Conflict {
+                addr: self.addr,
+                owner: other.owner,
+            });
+        }
+
+        claims.try_push(PciClaim { addr: self.addr, owner })
+            .map_err(|_| PciError::TooManyClaims)
+    }
+
+    /// The driver that claimed the device, if any.
+    pub fn owner(&self) -> Option<&'static str> {
+        CLAIMS.lock().iter()
+            .find(|claim| claim.addr == self.addr)
+            .map(|claim| claim.owner)
+    }
+}
+
+/// Scan all buses for PCI functions.
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let addr = PciAddress { bus, device, function: 0 };
+            let Some(dev) = PciDevice::probe(addr) else {
+                continue;
+            };
+            devices.push(dev);
+
+            if addr.read8(REG_HEADER_TYPE + 2) & HEADER_TYPE_MULTIFUNCTION != 0 {
+                devices.extend((1..8).filter_map(|function| {
+                    PciDevice::probe(PciAddress { bus, device, function })
+                }));
+            }
+        }
+    }
+
+    devices
+}
+
+/// Find the functions of class `class`, `subclass` and programming interface
+/// `prog_if`.
+pub fn find_by_class(class: u8, subclass: u8, prog_if: u8) -> Vec<PciDevice> {
+    devices().into_iter()
+        .filter(|dev| {
+            (dev.class, dev.subclass, dev.prog_if) == (class, subclass, prog_if)
+        })
+        .collect()
+}
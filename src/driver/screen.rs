@@ -8,9 +8,14 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::str::FromStr;
 
-#[derive(Debug, Copy, Clone)]
+use crate::ui::font8x8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -26,6 +31,11 @@ impl Color {
         }
     }
 
+    /// The color as a 0x00RRGGBB pixel, as taken by `FramebufferScreen::copy()`.
+    pub fn to_rgb(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
     fn blend_channel(fg: u8, alpha: u8, bg: u8) -> u8 {
         let fg = (alpha as u16 * fg as u16 / 255) as u8;
         let bg = ((255 - alpha) as u16 * bg as u16 / 255) as u8;
@@ -58,21 +68,277 @@ impl FromStr for Color {
     }
 }
 
+/// The position and size in bits of a color channel within a pixel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChannelField {
+    pub shift: u8,
+    pub size: u8,
+}
+
+impl ChannelField {
+    /// Encode the 8-bit channel `value`, keeping its most significant bits.
+    fn encode(&self, value: u8) -> u32 {
+        match self.size {
+            0 => 0,
+            size @ 1..=7 => ((value >> (8 - size)) as u32) << self.shift,
+            _ => (value as u32) << self.shift,
+        }
+    }
+}
+
+/// How the colors are encoded into a framebuffer's pixels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PixelFormat {
+    /// The number of bits per pixel: 16, 24 or 32.
+    pub bpp: u8,
+    pub red: ChannelField,
+    pub green: ChannelField,
+    pub blue: ChannelField,
+}
+
+impl PixelFormat {
+    /// The 0x00RRGGBB pixels of the data given to `FramebufferScreen::copy()`.
+    pub const XRGB8888: Self = Self {
+        bpp: 32,
+        red: ChannelField { shift: 16, size: 8 },
+        green: ChannelField { shift: 8, size: 8 },
+        blue: ChannelField { shift: 0, size: 8 },
+    };
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize + 7) / 8
+    }
+
+    pub fn encode(&self, color: Color) -> u32 {
+        self.red.encode(color.r)
+            | self.green.encode(color.g)
+            | self.blue.encode(color.b)
+    }
+}
+
+/// The video mode of a framebuffer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FramebufferMode {
+    pub width: usize,
+    pub height: usize,
+    /// The number of bytes between the starts of two rows.
+    pub pitch: usize,
+    pub format: PixelFormat,
+}
+
+/// The number of pixels `FramebufferScreen`'s drawing primitives copy at once;
+/// they use a buffer on the stack so that they can be used without a heap,
+/// e.g. by the panic handler.
+const ROW_CHUNK: usize = 64;
+
+/// A linear framebuffer. Implementations only have to provide pixel and row
+/// accesses: the drawing primitives are built upon `copy()`, which is expected
+/// to be a plain memory copy.
 pub trait FramebufferScreen {
-    fn dimensions(&self) -> (usize, usize);
+    fn mode(&self) -> FramebufferMode;
+
+    fn dimensions(&self) -> (usize, usize) {
+        let mode = self.mode();
+        (mode.width, mode.height)
+    }
 
     fn put(&mut self, x: usize, y: usize, color: Color);
 
+    /// Copy a row of 0x00RRGGBB pixels at `x`, `y`.
     fn copy(&mut self, x: usize, y: usize, data: &[u32]);
 
     fn clear(&mut self);
+
+    /// Make what was drawn visible, for framebuffers that are not scanned out
+    /// directly from memory.
+    fn flush(&mut self) {}
+
+    /// A second handle onto this framebuffer, for the panic handler to draw
+    /// onto when the kernel terminal can't be used; it must neither allocate
+    /// nor wait on any lock. It must not be used once this framebuffer is
+    /// dropped.
+    fn panic_handle(&self) -> Option<Box<dyn FramebufferScreen + Send>> {
+        None
+    }
+
+    /// Fill the rectangle of `width` × `height` pixels at `x`, `y` with
+    /// `color`, clipped to the screen.
+    fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+    ) {
+        let (screen_width, screen_height) = self.dimensions();
+        let width = width.min(screen_width.saturating_sub(x));
+        let height = height.min(screen_height.saturating_sub(y));
+        let row = [color.to_rgb(); ROW_CHUNK];
+
+        for y in y..(y + height) {
+            for dx in (0..width).step_by(ROW_CHUNK) {
+                self.copy(x + dx, y, &row[..(width - dx).min(ROW_CHUNK)]);
+            }
+        }
+    }
+
+    /// Copy the image `pixels`, made of rows of `width` 0x00RRGGBB pixels, at
+    /// `x`, `y`, clipped to the screen.
+    fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[u32]) {
+        let (screen_width, screen_height) = self.dimensions();
+        let visible = width.min(screen_width.saturating_sub(x));
+        if visible == 0 {
+            return;
+        }
+
+        for (dy, row) in pixels.chunks(width)
+            .take(screen_height.saturating_sub(y))
+            .enumerate() {
+            self.copy(x, y + dy, &row[..visible.min(row.len())]);
+        }
+    }
+
+    /// Draw a one-pixel wide line from `x0`, `y0` to `x1`, `y1` inclusive,
+    /// clipped to the screen.
+    fn draw_line(
+        &mut self,
+        (x0, y0): (usize, usize),
+        (x1, y1): (usize, usize),
+        color: Color,
+    ) {
+        let (screen_width, screen_height) = self.dimensions();
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (step_x, step_y) = ((x1 - x).signum(), (y1 - y).signum());
+        let mut err = dx + dy;
+
+        // Bresenham's algorithm, for all octants.
+        loop {
+            if (x as usize) < screen_width && (y as usize) < screen_height {
+                self.put(x as usize, y as usize, color);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draw the 1-bit `bitmap`, one byte per row of 8 pixels, least
+    /// significant bit leftmost, at `x`, `y`: set bits in `fg`, the others in
+    /// `bg`. Each bit takes `scale` × `scale` pixels, up to 8 × 8.
+    fn draw_bitmap(
+        &mut self,
+        x: usize,
+        y: usize,
+        bitmap: &[u8],
+        scale: usize,
+        fg: Color,
+        bg: Color,
+    ) {
+        let scale = scale.clamp(1, ROW_CHUNK / 8);
+        let (screen_width, screen_height) = self.dimensions();
+        let visible = (8 * scale).min(screen_width.saturating_sub(x));
+        let mut row = [0; ROW_CHUNK];
+
+        for (dy, bits) in bitmap.iter().enumerate() {
+            for (dx, px) in row[..(8 * scale)].iter_mut().enumerate() {
+                let color = if bits & (1 << (dx / scale)) != 0 { fg } else { bg };
+                *px = color.to_rgb();
+            }
+            for sy in 0..scale {
+                let y = y + dy * scale + sy;
+                if y < screen_height && visible > 0 {
+                    self.copy(x, y, &row[..visible]);
+                }
+            }
+        }
+    }
 }
 
+impl<F: FramebufferScreen + ?Sized> FramebufferScreen for Box<F> {
+    fn mode(&self) -> FramebufferMode {
+        (**self).mode()
+    }
+
+    fn put(&mut self, x: usize, y: usize, color: Color) {
+        (**self).put(x, y, color)
+    }
+
+    fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
+        (**self).copy(x, y, data)
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+
+    fn panic_handle(&self) -> Option<Box<dyn FramebufferScreen + Send>> {
+        (**self).panic_handle()
+    }
+
+    fn fill_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+    ) {
+        (**self).fill_rect(x, y, width, height, color)
+    }
+
+    fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[u32]) {
+        (**self).blit(x, y, width, pixels)
+    }
+
+    fn draw_line(
+        &mut self,
+        from: (usize, usize),
+        to: (usize, usize),
+        color: Color,
+    ) {
+        (**self).draw_line(from, to, color)
+    }
+
+    fn draw_bitmap(
+        &mut self,
+        x: usize,
+        y: usize,
+        bitmap: &[u8],
+        scale: usize,
+        fg: Color,
+        bg: Color,
+    ) {
+        (**self).draw_bitmap(x, y, bitmap, scale, fg, bg)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct CharAttrs {
     pub color: Color,
+    pub bg_color: Color,
 }
 
 pub trait TextScreen {
+    /// The number of columns and rows.
+    fn dimensions(&self) -> (usize, usize);
+
     fn put(&mut self, x: usize, y: usize, c: char, attrs: CharAttrs);
 
     fn scroll_up(&mut self, lines: u8);
@@ -80,20 +346,196 @@ pub trait TextScreen {
     fn clear(&mut self);
 }
 
+impl<S: TextScreen + ?Sized> TextScreen for Box<S> {
+    fn dimensions(&self) -> (usize, usize) {
+        (**self).dimensions()
+    }
+
+    fn put(&mut self, x: usize, y: usize, c: char, attrs: CharAttrs) {
+        (**self).put(x, y, c, attrs)
+    }
+
+    fn scroll_up(&mut self, lines: u8) {
+        (**self).scroll_up(lines)
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+}
+
+impl<S: TextScreen + ?Sized> TextScreen for &mut S {
+    fn dimensions(&self) -> (usize, usize) {
+        (**self).dimensions()
+    }
+
+    fn put(&mut self, x: usize, y: usize, c: char, attrs: CharAttrs) {
+        (**self).put(x, y, c, attrs)
+    }
+
+    fn scroll_up(&mut self, lines: u8) {
+        (**self).scroll_up(lines)
+    }
+
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+}
+
+/// A text screen drawn onto a framebuffer with the built-in 8×8 font.
 pub struct FramebufferTextScreen<F: FramebufferScreen> {
     fb: F,
+    columns: usize,
+    rows: usize,
+    /// The characters on screen, drawn again when scrolling since the
+    /// framebuffer can't be read back.
+    cells: Vec<(char, CharAttrs)>,
+}
+
+impl<F: FramebufferScreen> FramebufferTextScreen<F> {
+    pub fn new(fb: F) -> Self {
+        let (width, height) = fb.dimensions();
+        let columns = width / font8x8::GLYPH_WIDTH as usize;
+        let rows = height / font8x8::GLYPH_HEIGHT as usize;
+        let mut screen = Self {
+            fb,
+            columns,
+            rows,
+            cells: vec![(' ', BLANK_ATTRS); columns * rows],
+        };
+        screen.clear();
+
+        screen
+    }
+
+    fn draw(&mut self, x: usize, y: usize) {
+        let (c, attrs) = self.cells[y * self.columns + x];
+
+        self.fb.draw_bitmap(x * font8x8::GLYPH_WIDTH as usize,
+                            y * font8x8::GLYPH_HEIGHT as usize,
+                            font8x8::glyph(c), 1,
+                            attrs.color, attrs.bg_color);
+    }
 }
 
+const BLANK_ATTRS: CharAttrs = CharAttrs {
+    color: Color { r: 0, g: 0, b: 0 },
+    bg_color: Color { r: 0, g: 0, b: 0 },
+};
+
 impl<F: FramebufferScreen> TextScreen for FramebufferTextScreen<F> {
-    fn put(&mut self, _x: usize, _y: usize, _c: char, _attrs: CharAttrs) {
-        todo!()
+    fn dimensions(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    fn put(&mut self, x: usize, y: usize, c: char, attrs: CharAttrs) {
+        self.cells[y * self.columns + x] = (c, attrs);
+        self.draw(x, y);
+        self.fb.flush();
     }
 
-    fn scroll_up(&mut self, _lines: u8) {
-        todo!()
+    fn scroll_up(&mut self, lines: u8) {
+        let shift = (lines as usize).min(self.rows) * self.columns;
+        self.cells.drain(..shift);
+        self.cells.resize(self.columns * self.rows, (' ', BLANK_ATTRS));
+
+        for y in 0..self.rows {
+            for x in 0..self.columns {
+                self.draw(x, y);
+            }
+        }
+        self.fb.flush();
     }
 
     fn clear(&mut self) {
-        todo!()
+        self.cells.fill((' ', BLANK_ATTRS));
+
+        for y in 0..self.rows {
+            for x in 0..self.columns {
+                self.draw(x, y);
+            }
+        }
+        self.fb.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockFramebuffer {
+        width: usize,
+        height: usize,
+        pixels: Vec<u32>,
+    }
+
+    impl MockFramebuffer {
+        fn new(width: usize, height: usize) -> Self {
+            Self { width, height, pixels: vec![0; width * height] }
+        }
+    }
+
+    impl FramebufferScreen for MockFramebuffer {
+        fn mode(&self) -> FramebufferMode {
+            FramebufferMode {
+                width: self.width,
+                height: self.height,
+                pitch: self.width * 4,
+                format: PixelFormat::XRGB8888,
+            }
+        }
+
+        fn put(&mut self, x: usize, y: usize, color: Color) {
+            self.pixels[y * self.width + x] = color.to_rgb();
+        }
+
+        fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
+            let start = y * self.width + x;
+            self.pixels[start..(start + data.len())].copy_from_slice(data);
+        }
+
+        fn clear(&mut self) {
+            self.pixels.fill(0);
+        }
+    }
+
+    const WHITE: Color = Color { r: 0xff, g: 0xff, b: 0xff };
+
+    #[test]
+    fn it_clips_rectangles_and_images() {
+        let mut fb = MockFramebuffer::new(4, 3);
+
+        fb.fill_rect(2, 1, 10, 10, WHITE);
+        assert_eq!(fb.pixels, [0, 0, 0, 0,
+                               0, 0, 0xffffff, 0xffffff,
+                               0, 0, 0xffffff, 0xffffff]);
+
+        fb.blit(3, 1, 2, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(fb.pixels[4..], [0, 0, 0xffffff, 1, 0, 0, 0xffffff, 3]);
+        fb.fill_rect(4, 0, 1, 1, WHITE);
+    }
+
+    #[test]
+    fn it_draws_lines() {
+        let mut fb = MockFramebuffer::new(4, 3);
+
+        fb.draw_line((3, 2), (0, 0), WHITE);
+        let lit: Vec<_> = (0..12).filter(|&i| fb.pixels[i] != 0).collect();
+        assert_eq!(lit, [0, 5, 6, 11]);
+    }
+
+    #[test]
+    fn it_encodes_pixels() {
+        let color = Color { r: 0xff, g: 0x80, b: 0x10 };
+        let bgr565 = PixelFormat {
+            bpp: 16,
+            red: ChannelField { shift: 0, size: 5 },
+            green: ChannelField { shift: 5, size: 6 },
+            blue: ChannelField { shift: 11, size: 5 },
+        };
+
+        assert_eq!(PixelFormat::XRGB8888.encode(color), 0x00ff8010);
+        assert_eq!(bgr565.encode(color), 0b00010_100000_11111);
+        assert_eq!(bgr565.bytes_per_pixel(), 2);
     }
 }
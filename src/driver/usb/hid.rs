@@ -0,0 +1,207 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The HID class driver for keyboards speaking the boot protocol: an 8-byte
+//! report of the modifiers and of up to 6 keys held down. Key presses and
+//! releases are found by comparing each report with the previous one, and fed
+//! to `driver::keyboard` just like the PS/2 keyboard's.
+
+use crate::driver::keyboard::{Key, KeyEvent};
+use crate::driver::usb::{
+    InterfaceDescriptor, SetupPacket, REQ_TYPE_CLASS, REQ_TYPE_INTERFACE,
+};
+
+pub const CLASS_HID: u8 = 0x03;
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+pub const REPORT_LEN: usize = 8;
+
+const REQ_SET_IDLE: u8 = 0x0a;
+const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+const BOOT_PROTOCOL: u16 = 0;
+
+/// The usage reported in every key slot when too many keys are held down.
+const USAGE_ROLLOVER_ERROR: u8 = 0x01;
+
+/// The modifiers, in the order of the bits of the report's first byte.
+const MODIFIERS: [Key; 8] = [
+    Key::LeftCtrl, Key::LeftShift, Key::Alt, Key::LeftMeta,
+    Key::RightCtrl, Key::RightShift, Key::AltGr, Key::RightMeta,
+];
+
+/// Whether the interface is a keyboard speaking the boot protocol.
+pub fn is_boot_keyboard(interface: &InterfaceDescriptor) -> bool {
+    (interface.class, interface.subclass, interface.protocol)
+        == (CLASS_HID, SUBCLASS_BOOT, PROTOCOL_KEYBOARD)
+}
+
+/// The request switching `interface` to the boot protocol.
+pub fn set_boot_protocol(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: REQ_TYPE_CLASS | REQ_TYPE_INTERFACE,
+        request: REQ_SET_PROTOCOL,
+        value: BOOT_PROTOCOL,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+/// The request making `interface` report only on changes, rather than
+/// periodically.
+pub fn set_idle_infinite(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: REQ_TYPE_CLASS | REQ_TYPE_INTERFACE,
+        request: REQ_SET_IDLE,
+        value: 0,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+pub struct BootKeyboard {
+    last_report: [u8; REPORT_LEN],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self {
+            last_report: [0; REPORT_LEN],
+        }
+    }
+
+    /// Process a report, calling `on_event` for every key pressed or released
+    /// since the previous one.
+    pub fn on_report(
+        &mut self,
+        report: &[u8; REPORT_LEN],
+        mut on_event: impl FnMut(KeyEvent),
+    ) {
+        if report[2..].contains(&USAGE_ROLLOVER_ERROR) {
+            return;
+        }
+        let last = &self.last_report;
+
+        for (bit, &key) in MODIFIERS.iter().enumerate() {
+            let (was, is) = (last[0] & 1 << bit != 0, report[0] & 1 << bit != 0);
+            if was && !is {
+                on_event(KeyEvent::Released(key));
+            } else if !was && is {
+                on_event(KeyEvent::Pressed(key));
+            }
+        }
+
+        for &usage in last[2..].iter().filter(|u| !report[2..].contains(u)) {
+            if let Some(key) = usage_to_key(usage) {
+                on_event(KeyEvent::Released(key));
+            }
+        }
+        for &usage in report[2..].iter().filter(|u| !last[2..].contains(u)) {
+            if let Some(key) = usage_to_key(usage) {
+                on_event(KeyEvent::Pressed(key));
+            }
+        }
+
+        self.last_report = *report;
+    }
+}
+
+/// Translate a usage of the keyboard usage page into a key.
+fn usage_to_key(usage: u8) -> Option<Key> {
+    Some(match usage {
+        0x04..=0x1d => Key::Letter((b'A' + usage - 0x04) as char),
+        0x1e..=0x26 => Key::Digit(usage - 0x1e + 1),
+        0x27 => Key::Digit(0),
+        0x28 => Key::Enter,
+        0x29 => Key::Escape,
+        0x2a => Key::Backspace,
+        0x2b => Key::Tab,
+        0x2c => Key::Space,
+        0x2d => Key::Dash,
+        0x2e => Key::Equal,
+        0x2f => Key::LeftBracket,
+        0x30 => Key::RightBracket,
+        // The non-US '#' key sits where the US backslash is.
+        0x31 | 0x32 => Key::Backslash,
+        0x33 => Key::Semicolon,
+        0x34 => Key::SingleQuote,
+        0x35 => Key::Backquote,
+        0x36 => Key::Comma,
+        0x37 => Key::Period,
+        0x38 => Key::Slash,
+        0x39 => Key::CapsLock,
+        0x3a..=0x45 => Key::F(usage - 0x3a + 1),
+        0x47 => Key::ScrollLock,
+        0x49 => Key::Insert,
+        0x4a => Key::Home,
+        0x4b => Key::PgUp,
+        0x4c => Key::Del,
+        0x4d => Key::End,
+        0x4e => Key::PgDown,
+        0x4f => Key::Right,
+        0x50 => Key::Left,
+        0x51 => Key::Down,
+        0x52 => Key::Up,
+        0x53 => Key::KeypadNumLock,
+        0x54 => Key::KeypadDiv,
+        0x55 => Key::KeypadMul,
+        0x56 => Key::KeypadMinus,
+        0x57 => Key::KeypadPlus,
+        0x58 => Key::KeypadEnter,
+        0x59..=0x61 => Key::KeypadDigit(usage - 0x59 + 1),
+        0x62 => Key::KeypadDigit(0),
+        0x63 => Key::KeypadPeriod,
+        0x64 => Key::Iso,
+        0x65 => Key::Menu,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    fn events(kb: &mut BootKeyboard, report: [u8; REPORT_LEN]) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        kb.on_report(&report, |ev| events.push(ev));
+        events
+    }
+
+    #[test]
+    fn it_reports_presses_and_releases() {
+        let mut kb = BootKeyboard::new();
+
+        let evs = events(&mut kb, [0b10, 0, 0x04, 0, 0, 0, 0, 0]);
+        assert!(matches!(evs[..], [
+            KeyEvent::Pressed(Key::LeftShift),
+            KeyEvent::Pressed(Key::Letter('A')),
+        ]));
+
+        let evs = events(&mut kb, [0b10, 0, 0x04, 0x27, 0, 0, 0, 0]);
+        assert!(matches!(evs[..], [KeyEvent::Pressed(Key::Digit(0))]));
+
+        let evs = events(&mut kb, [0, 0, 0x27, 0, 0, 0, 0, 0]);
+        assert!(matches!(evs[..], [
+            KeyEvent::Released(Key::LeftShift),
+            KeyEvent::Released(Key::Letter('A')),
+        ]));
+    }
+
+    #[test]
+    fn it_ignores_rollover_errors() {
+        let mut kb = BootKeyboard::new();
+
+        events(&mut kb, [0, 0, 0x04, 0, 0, 0, 0, 0]);
+        assert!(events(&mut kb, [0, 0, 1, 1, 1, 1, 1, 1]).is_empty());
+        assert!(events(&mut kb, [0, 0, 0x04, 0, 0, 0, 0, 0]).is_empty());
+    }
+}
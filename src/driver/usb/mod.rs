@@ -0,0 +1,267 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The USB subsystem. Host controllers are found on the PCI bus; the devices
+//! plugged into their root ports are enumerated at initialization, and those
+//! with a class driver are configured. Only xHCI controllers and HID boot
+//! keyboards are supported, and hubs are not.
+//!
+//! There is no interrupt routing for PCI devices yet: the controllers' event
+//! rings are polled on every timer tick by `poll()`.
+
+use alloc::vec::Vec;
+use thiserror_no_std::Error;
+
+use crate::driver::pci;
+use crate::mem::iomap::IomapError;
+use crate::mem::resource::ResourceError;
+use crate::sync::Spinlock;
+use crate::{info, initcall, warning};
+
+pub mod hid;
+pub mod xhci;
+
+const PCI_CLASS_SERIAL_BUS: u8 = 0x0c;
+const PCI_SUBCLASS_USB: u8 = 0x03;
+const PCI_PROG_IF_XHCI: u8 = 0x30;
+
+pub const REQ_GET_DESCRIPTOR: u8 = 0x06;
+pub const REQ_SET_CONFIGURATION: u8 = 0x09;
+
+pub const DESC_DEVICE: u8 = 0x01;
+pub const DESC_CONFIGURATION: u8 = 0x02;
+pub const DESC_INTERFACE: u8 = 0x04;
+pub const DESC_ENDPOINT: u8 = 0x05;
+
+/// `bmRequestType`: the data stage, if any, is from the device to the host.
+pub const REQ_TYPE_IN: u8 = 1 << 7;
+pub const REQ_TYPE_CLASS: u8 = 1 << 5;
+pub const REQ_TYPE_INTERFACE: u8 = 1;
+
+static CONTROLLERS: Spinlock<Vec<xhci::Xhci>> = Spinlock::new(Vec::new());
+
+#[derive(Error, Debug)]
+pub enum UsbError {
+    #[error("couldn't map the controller's registers: {0}")]
+    Iomap(#[source] IomapError),
+
+    #[error("couldn't request the controller's registers: {0}")]
+    Resource(#[source] ResourceError),
+
+    #[error("the controller has no usable register window")]
+    NoRegisters,
+
+    #[error("out of memory")]
+    NoMemory,
+
+    #[error("timed out")]
+    Timeout,
+
+    #[error("transfer or command failed with completion code {0}")]
+    Failed(u8),
+
+    #[error("invalid descriptor")]
+    InvalidDescriptor,
+}
+
+/// The 8-byte packet starting a control transfer.
+#[derive(Debug, Copy, Clone)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn get_descriptor(desc_type: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: REQ_TYPE_IN,
+            request: REQ_GET_DESCRIPTOR,
+            value: (desc_type as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: REQ_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQ_TYPE_IN != 0
+    }
+
+    /// The packet as sent on the bus, little-endian.
+    pub fn to_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+/// The fields of an interface descriptor relevant to class drivers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// The fields of an endpoint descriptor relevant to class drivers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// The endpoint number, with bit 7 set for IN endpoints.
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.attributes & 0b11 == 0b11
+    }
+}
+
+/// A descriptor within a configuration descriptor's hierarchy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    Other(u8),
+}
+
+/// Iterate over the descriptors following a configuration descriptor, as read
+/// in full with its total length.
+pub fn parse_configuration(
+    data: &[u8],
+) -> impl Iterator<Item = Result<Descriptor, UsbError>> + '_ {
+    let mut offset = 0;
+
+    core::iter::from_fn(move || {
+        let desc = data.get(offset..)?;
+        let len = *desc.first()? as usize;
+        if len < 2 || desc.len() < len {
+            offset = data.len();
+            return Some(Err(UsbError::InvalidDescriptor));
+        }
+        offset += len;
+
+        Some(Ok(match desc[1] {
+            DESC_INTERFACE if len >= 9 => Descriptor::Interface(
+                InterfaceDescriptor {
+                    number: desc[2],
+                    class: desc[5],
+                    subclass: desc[6],
+                    protocol: desc[7],
+                }
+            ),
+            DESC_ENDPOINT if len >= 7 => Descriptor::Endpoint(
+                EndpointDescriptor {
+                    address: desc[2],
+                    attributes: desc[3],
+                    max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                    interval: desc[6],
+                }
+            ),
+            desc_type => Descriptor::Other(desc_type),
+        }))
+    })
+}
+
+/// Start all the xHCI controllers found on the PCI bus, and the devices
+/// plugged into them.
+pub fn init() {
+    let controllers = pci::find_by_class(PCI_CLASS_SERIAL_BUS, PCI_SUBCLASS_USB,
+                                         PCI_PROG_IF_XHCI);
+
+    for dev in controllers {
+        if let Err(e) = dev.claim("xhci") {
+            warning!("usb: {e}");
+            continue;
+        }
+
+        match xhci::Xhci::new(dev) {
+            Ok(controller) => {
+                info!("usb: xHCI controller at {} started", dev.addr);
+                CONTROLLERS.lock().push(controller);
+            },
+            Err(e) => warning!("usb: xHCI controller at {}: {e}", dev.addr),
+        }
+    }
+}
+
+initcall!(device, init, "Starting USB controllers...");
+
+/// Process the events of all controllers, e.g. completed transfers; this is
+/// meant to be called on every timer tick.
+pub fn poll() {
+    for controller in CONTROLLERS.lock().iter_mut() {
+        controller.poll();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_configuration_descriptors() {
+        let data = [
+            9, DESC_CONFIGURATION, 34, 0, 1, 1, 0, 0xa0, 50,
+            9, DESC_INTERFACE, 0, 0, 1, 3, 1, 1, 0,
+            9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+            7, DESC_ENDPOINT, 0x81, 0x03, 8, 0, 10,
+        ];
+        let descs: Vec<_> = parse_configuration(&data)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(descs, [
+            Descriptor::Other(DESC_CONFIGURATION),
+            Descriptor::Interface(InterfaceDescriptor {
+                number: 0,
+                class: 3,
+                subclass: 1,
+                protocol: 1,
+            }),
+            Descriptor::Other(0x21),
+            Descriptor::Endpoint(EndpointDescriptor {
+                address: 0x81,
+                attributes: 0x03,
+                max_packet_size: 8,
+                interval: 10,
+            }),
+        ]);
+    }
+
+    #[test]
+    fn it_rejects_truncated_descriptors() {
+        let data = [9, DESC_CONFIGURATION, 34, 0, 1, 1, 0, 0xa0, 50, 9, 4, 0];
+
+        assert!(matches!(parse_configuration(&data).last(),
+                         Some(Err(UsbError::InvalidDescriptor))));
+    }
+}
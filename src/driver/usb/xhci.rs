@@ -0,0 +1,767 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The xHCI (USB 3) host controller driver. The controller is driven through
+//! rings of 16-byte TRBs shared in memory: the command ring and one transfer
+//! ring per endpoint are produced by the driver, which rings a doorbell once
+//! TRBs are queued; the event ring is produced by the controller, to report
+//! completions.
+//!
+//! Commands and control transfers are only issued during initialization, and
+//! are waited for synchronously. Afterwards, only interrupt transfers of the
+//! keyboards are in flight; their completions are processed by `poll()`.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::arch::time::delay_us;
+use crate::driver::keyboard::on_key_event;
+use crate::driver::pci::{Bar, PciDevice};
+use crate::driver::usb::hid::{self, BootKeyboard};
+use crate::driver::usb::{
+    parse_configuration, Descriptor, EndpointDescriptor, SetupPacket, UsbError,
+    DESC_CONFIGURATION, DESC_DEVICE,
+};
+use crate::mem::dma::DmaPages;
+use crate::mem::iomap::iomap;
+use crate::mem::{resource, CacheMode, MmioRegion};
+use crate::{debug, warning};
+
+const CAP_CAPLENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+const HCC_CONTEXT_64: u32 = 1 << 2;
+
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC: usize = 0x400;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+
+const CRCR_RING_CYCLE: u64 = 1 << 0;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+/// The bits cleared by writing 1, which must be written as 0 to be preserved.
+const PORTSC_RW1C: u32 = PORTSC_ENABLED | 0b111_1111 << 17;
+
+/// The registers of the first interrupter, in the runtime registers.
+const IR0: usize = 0x20;
+const IR_ERSTSZ: usize = 0x08;
+const IR_ERSTBA: usize = 0x10;
+const IR_ERDP: usize = 0x18;
+
+const ERDP_BUSY: u64 = 1 << 3;
+
+const XECP_LEGACY_SUPPORT: u32 = 1;
+const LEGSUP_BIOS_OWNED: u32 = 1 << 16;
+const LEGSUP_OS_OWNED: u32 = 1 << 24;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_SHORT: u32 = 1 << 2;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+/// In data and status TRBs: the stage is from the device to the host.
+const TRB_DIR_IN: u32 = 1 << 16;
+
+const TRANSFER_TYPE_NO_DATA: u32 = 0;
+const TRANSFER_TYPE_OUT: u32 = 2;
+const TRANSFER_TYPE_IN: u32 = 3;
+
+const CC_SUCCESS: u8 = 1;
+const CC_SHORT_PACKET: u8 = 13;
+
+const SPEED_FULL: u32 = 1;
+const SPEED_LOW: u32 = 2;
+const SPEED_HIGH: u32 = 3;
+const SPEED_SUPER: u32 = 4;
+
+const EP_TYPE_CONTROL: u32 = 4;
+const EP_TYPE_INTERRUPT_IN: u32 = 7;
+/// The number of retries on transaction errors.
+const EP_ERROR_COUNT: u32 = 3;
+
+/// The device context index of the default control endpoint.
+const DCI_CONTROL: u8 = 1;
+
+/// The maximum number of device slots enabled.
+const MAX_SLOTS: u32 = 16;
+
+const RING_LEN: usize = PAGE_SIZE / core::mem::size_of::<Trb>();
+
+const TIMEOUT_US: u64 = 1_000_000;
+const POLL_PERIOD_US: u64 = 10;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Trb {
+    param: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(trb_type: u32, param: u64, status: u32, flags: u32) -> Self {
+        Self {
+            param,
+            status,
+            control: trb_type << 10 | flags,
+        }
+    }
+
+    fn trb_type(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+}
+
+/// A ring of TRBs produced by the driver; its last TRB links back to the
+/// first one.
+struct Ring {
+    mem: DmaPages,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Result<Self, UsbError> {
+        let mem = dma_page()?;
+        let ring = Self { mem, enqueue: 0, cycle: true };
+        ring.write_trb(RING_LEN - 1, Trb::new(
+            TRB_LINK, ring.mem.paddr().0, 0, TRB_TOGGLE_CYCLE,
+        ));
+
+        Ok(ring)
+    }
+
+    fn paddr(&self) -> u64 {
+        self.mem.paddr().0
+    }
+
+    /// Queue `trb`, handing it over to the controller; return its physical
+    /// address, as reported by the completion events.
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        let paddr = self.paddr() + (self.enqueue * 16) as u64;
+        trb.control = trb.control & !TRB_CYCLE | self.cycle as u32;
+        self.write_trb(self.enqueue, trb);
+
+        self.enqueue += 1;
+        if self.enqueue == RING_LEN - 1 {
+            let mut link = self.read_trb(RING_LEN - 1);
+            link.control = link.control & !TRB_CYCLE | self.cycle as u32;
+            self.write_trb(RING_LEN - 1, link);
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        paddr
+    }
+
+    fn read_trb(&self, index: usize) -> Trb {
+        unsafe { ptr::read_volatile(self.mem.vaddr().as_ptr::<Trb>().add(index)) }
+    }
+
+    /// Write a TRB, its control word last: its cycle bit hands the TRB over.
+    fn write_trb(&self, index: usize, trb: Trb) {
+        let offset = index * 16;
+        self.mem.write64(offset, trb.param);
+        self.mem.write32(offset + 8, trb.status);
+        fence(Ordering::Release);
+        self.mem.write32(offset + 12, trb.control);
+    }
+}
+
+/// The ring of events produced by the controller, made of a single segment.
+struct EventRing {
+    segment: DmaPages,
+    /// The event ring segment table, listing the single segment.
+    table: DmaPages,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn new() -> Result<Self, UsbError> {
+        let segment = dma_page()?;
+        let table = dma_page()?;
+        table.write64(0, segment.paddr().0);
+        table.write32(8, RING_LEN as u32);
+
+        Ok(Self { segment, table, dequeue: 0, cycle: true })
+    }
+
+    /// Pop the next event, if the controller produced one.
+    fn pop(&mut self) -> Option<Trb> {
+        let trb: Trb = unsafe {
+            ptr::read_volatile(
+                self.segment.vaddr().as_ptr::<Trb>().add(self.dequeue)
+            )
+        };
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        fence(Ordering::Acquire);
+
+        self.dequeue += 1;
+        if self.dequeue == RING_LEN {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        Some(trb)
+    }
+
+    fn dequeue_paddr(&self) -> u64 {
+        self.segment.paddr().0 + (self.dequeue * 16) as u64
+    }
+}
+
+/// The interrupt IN endpoint of a boot keyboard, with its single transfer
+/// always in flight.
+struct KeyboardEndpoint {
+    dci: u8,
+    ring: Ring,
+    buffer: DmaPages,
+    hid: BootKeyboard,
+}
+
+impl KeyboardEndpoint {
+    /// Queue the transfer of the next report; the endpoint's doorbell must be
+    /// rung afterwards.
+    fn queue_report(&mut self) {
+        self.ring.push(Trb::new(
+            TRB_NORMAL, self.buffer.paddr().0, hid::REPORT_LEN as u32,
+            TRB_INTERRUPT_ON_SHORT | TRB_INTERRUPT_ON_COMPLETION,
+        ));
+    }
+}
+
+struct Device {
+    slot: u8,
+    _context: DmaPages,
+    control: Ring,
+    keyboard: Option<KeyboardEndpoint>,
+}
+
+pub struct Xhci {
+    regs: MmioRegion,
+    /// The offsets of the operational and runtime registers, and of the
+    /// doorbells, in `regs`.
+    op: usize,
+    runtime: usize,
+    doorbells: usize,
+    /// The size of the contexts' entries: 32 or 64 bytes.
+    context_size: usize,
+    max_ports: u8,
+    dcbaa: DmaPages,
+    _scratchpads: Vec<DmaPages>,
+    commands: Ring,
+    events: EventRing,
+    devices: Vec<Device>,
+}
+
+impl Xhci {
+    /// Reset and start the controller `dev`, and enumerate the devices plugged
+    /// into its root ports.
+    pub fn new(dev: PciDevice) -> Result<Self, UsbError> {
+        let Some(Bar::Memory { paddr, bsize, .. }) = dev.bar(0) else {
+            return Err(UsbError::NoRegisters);
+        };
+        resource::request_region(paddr, bsize, "xhci")
+            .map_err(UsbError::Resource)?;
+        let regs = iomap(paddr, bsize as usize, CacheMode::Uncached)
+            .map_err(UsbError::Iomap)?;
+        dev.enable();
+
+        let hcs1 = regs.read32(CAP_HCSPARAMS1);
+        let hcs2 = regs.read32(CAP_HCSPARAMS2);
+        let hcc1 = regs.read32(CAP_HCCPARAMS1);
+        let nr_scratchpads = ((hcs2 >> 21) & 0x1f) << 5 | (hcs2 >> 27) & 0x1f;
+
+        let mut xhci = Self {
+            op: regs.read8(CAP_CAPLENGTH) as usize,
+            runtime: (regs.read32(CAP_RTSOFF) & !0x1f) as usize,
+            doorbells: (regs.read32(CAP_DBOFF) & !0b11) as usize,
+            context_size: if hcc1 & HCC_CONTEXT_64 != 0 { 64 } else { 32 },
+            max_ports: (hcs1 >> 24) as u8,
+            regs,
+            dcbaa: dma_page()?,
+            _scratchpads: Vec::new(),
+            commands: Ring::new()?,
+            events: EventRing::new()?,
+            devices: Vec::new(),
+        };
+
+        xhci.take_ownership(((hcc1 >> 16) as usize) << 2);
+        xhci.reset()?;
+        xhci.allocate_scratchpads(nr_scratchpads as usize)?;
+        xhci.start((hcs1 & 0xff).min(MAX_SLOTS))?;
+
+        for port in 1..=xhci.max_ports {
+            if let Err(e) = xhci.attach_port(port) {
+                warning!("usb: port {port}: {e}");
+            }
+        }
+
+        Ok(xhci)
+    }
+
+    /// Process the completed transfers.
+    pub fn poll(&mut self) {
+        while let Some(event) = self.events.pop() {
+            self.handle_event(event);
+        }
+        self.acknowledge_events();
+    }
+
+    /// Request the controller from the firmware, which may drive it to emulate
+    /// a PS/2 keyboard; `xecp` is the offset of the extended capabilities.
+    fn take_ownership(&self, mut xecp: usize) {
+        while xecp != 0 {
+            let cap = self.regs.read32(xecp);
+
+            if cap & 0xff == XECP_LEGACY_SUPPORT {
+                self.regs.write32(xecp, cap | LEGSUP_OS_OWNED);
+                if wait_until(|| {
+                    self.regs.read32(xecp) & LEGSUP_BIOS_OWNED == 0
+                }).is_err() {
+                    warning!("usb: firmware didn't release the xHCI controller");
+                }
+            }
+
+            match (cap >> 8) & 0xff {
+                0 => break,
+                next => xecp += (next as usize) << 2,
+            }
+        }
+    }
+
+    fn reset(&self) -> Result<(), UsbError> {
+        let cmd = self.op_read32(OP_USBCMD);
+        self.op_write32(OP_USBCMD, cmd & !USBCMD_RUN);
+        wait_until(|| self.op_read32(OP_USBSTS) & USBSTS_HALTED != 0)?;
+
+        self.op_write32(OP_USBCMD, USBCMD_RESET);
+        wait_until(|| {
+            self.op_read32(OP_USBCMD) & USBCMD_RESET == 0
+                && self.op_read32(OP_USBSTS) & USBSTS_NOT_READY == 0
+        })
+    }
+
+    /// Give the controller the scratchpad buffers it asked for, for its
+    /// private use.
+    fn allocate_scratchpads(&mut self, count: usize) -> Result<(), UsbError> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        let array = dma_page()?;
+        for i in 0..count {
+            let buffer = dma_page()?;
+            array.write64(i * 8, buffer.paddr().0);
+            self._scratchpads.push(buffer);
+        }
+        self.dcbaa.write64(0, array.paddr().0);
+        self._scratchpads.push(array);
+
+        Ok(())
+    }
+
+    fn start(&mut self, nr_slots: u32) -> Result<(), UsbError> {
+        self.op_write32(OP_CONFIG, nr_slots);
+        self.op_write64(OP_DCBAAP, self.dcbaa.paddr().0);
+        self.op_write64(OP_CRCR, self.commands.paddr() | CRCR_RING_CYCLE);
+
+        let ir0 = self.runtime + IR0;
+        self.regs.write32(ir0 + IR_ERSTSZ, 1);
+        self.regs.write64(ir0 + IR_ERDP, self.events.dequeue_paddr());
+        self.regs.write64(ir0 + IR_ERSTBA, self.events.table.paddr().0);
+
+        self.op_write32(OP_USBCMD, USBCMD_RUN);
+        wait_until(|| self.op_read32(OP_USBSTS) & USBSTS_HALTED == 0)
+    }
+
+    /// Reset the root port `port`, counted from 1, and set up the device
+    /// plugged into it, if any.
+    fn attach_port(&mut self, port: u8) -> Result<(), UsbError> {
+        let portsc_offset = OP_PORTSC + 0x10 * (port as usize - 1);
+        let portsc = self.op_read32(portsc_offset);
+        if portsc & PORTSC_CONNECTED == 0 {
+            return Ok(());
+        }
+
+        self.op_write32(portsc_offset, portsc & !PORTSC_RW1C | PORTSC_RESET);
+        wait_until(|| {
+            self.op_read32(portsc_offset) & PORTSC_RESET_CHANGE != 0
+        })?;
+        let portsc = self.op_read32(portsc_offset);
+        self.op_write32(portsc_offset,
+                        portsc & !PORTSC_RW1C | PORTSC_RESET_CHANGE);
+        if portsc & PORTSC_ENABLED == 0 {
+            return Ok(());
+        }
+
+        let speed = (portsc >> 10) & 0xf;
+        let device = self.address_device(port, speed)?;
+        self.devices.push(device);
+
+        Ok(())
+    }
+
+    /// Assign an address to the device plugged into `port`, and configure it
+    /// if it is a boot keyboard.
+    fn address_device(&mut self, port: u8, speed: u32) -> Result<Device, UsbError> {
+        let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot();
+        let context = dma_page()?;
+        self.dcbaa.write64(slot as usize * 8, context.paddr().0);
+
+        let mut control = Ring::new()?;
+        let input = dma_page()?;
+        let max_packet = match speed {
+            SPEED_LOW | SPEED_FULL => 8,
+            SPEED_HIGH => 64,
+            _ => 512,
+        };
+
+        input.write32(4, 1 << 0 | 1 << DCI_CONTROL);
+        self.write_slot_context(&input, speed, port, DCI_CONTROL);
+        self.write_endpoint_context(&input, DCI_CONTROL, EP_TYPE_CONTROL,
+                                    max_packet, 0, control.paddr());
+        self.command(Trb::new(TRB_ADDRESS_DEVICE, input.paddr().0, 0,
+                              (slot as u32) << 24))?;
+
+        let buffer = dma_page()?;
+
+        // Full-speed devices may have a larger control endpoint than assumed.
+        self.control_transfer(slot, &mut control,
+                              SetupPacket::get_descriptor(DESC_DEVICE, 0, 8),
+                              Some(&buffer))?;
+        let actual_max_packet = buffer.read32(4) >> 24;
+        if speed == SPEED_FULL && actual_max_packet != max_packet {
+            input.zero();
+            input.write32(4, 1 << DCI_CONTROL);
+            self.write_endpoint_context(&input, DCI_CONTROL, EP_TYPE_CONTROL,
+                                        actual_max_packet, 0, control.paddr());
+            self.command(Trb::new(TRB_EVALUATE_CONTEXT, input.paddr().0, 0,
+                                  (slot as u32) << 24))?;
+        }
+
+        self.control_transfer(
+            slot, &mut control,
+            SetupPacket::get_descriptor(DESC_CONFIGURATION, 0, 9),
+            Some(&buffer),
+        )?;
+        let total_len = (buffer.read32(0) >> 16).min(PAGE_SIZE as u32) as u16;
+        if total_len < 9 {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        self.control_transfer(
+            slot, &mut control,
+            SetupPacket::get_descriptor(DESC_CONFIGURATION, 0, total_len),
+            Some(&buffer),
+        )?;
+
+        let config = unsafe {
+            core::slice::from_raw_parts(buffer.vaddr().as_ptr::<u8>(),
+                                        total_len as usize)
+        };
+        let config_value = config[5];
+        let keyboard = find_boot_keyboard(config)?;
+
+        let mut device = Device {
+            slot,
+            _context: context,
+            control,
+            keyboard: None,
+        };
+
+        let Some((interface, endpoint)) = keyboard else {
+            debug!("usb: port {port}: no driver for device in slot {slot}");
+            return Ok(device);
+        };
+
+        self.control_transfer(slot, &mut device.control,
+                              SetupPacket::set_configuration(config_value),
+                              None)?;
+        self.control_transfer(slot, &mut device.control,
+                              hid::set_boot_protocol(interface), None)?;
+        self.control_transfer(slot, &mut device.control,
+                              hid::set_idle_infinite(interface), None)?;
+
+        let dci = (endpoint.address & 0xf) * 2 + 1;
+        let ring = Ring::new()?;
+        input.zero();
+        input.write32(4, 1 << 0 | 1 << dci);
+        self.write_slot_context(&input, speed, port, dci);
+        self.write_endpoint_context(
+            &input, dci, EP_TYPE_INTERRUPT_IN,
+            endpoint.max_packet_size as u32,
+            endpoint_interval(speed, endpoint.interval),
+            ring.paddr(),
+        );
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT, input.paddr().0, 0,
+                              (slot as u32) << 24))?;
+
+        let mut keyboard = KeyboardEndpoint {
+            dci,
+            ring,
+            buffer,
+            hid: BootKeyboard::new(),
+        };
+        keyboard.queue_report();
+        self.ring_doorbell(slot, dci);
+        device.keyboard = Some(keyboard);
+        debug!("usb: port {port}: boot keyboard in slot {slot}");
+
+        Ok(device)
+    }
+
+    fn write_slot_context(&self, input: &DmaPages, speed: u32, port: u8,
+                          last_dci: u8) {
+        let slot = self.context_size;
+        input.write32(slot, speed << 20 | (last_dci as u32) << 27);
+        input.write32(slot + 4, (port as u32) << 16);
+    }
+
+    fn write_endpoint_context(
+        &self,
+        input: &DmaPages,
+        dci: u8,
+        ep_type: u32,
+        max_packet: u32,
+        interval: u32,
+        ring: u64,
+    ) {
+        // The input control context comes first, then the slot context.
+        let ep = self.context_size * (1 + dci as usize);
+        input.write32(ep, interval << 16);
+        input.write32(ep + 4, EP_ERROR_COUNT << 1 | ep_type << 3
+                              | max_packet << 16);
+        input.write64(ep + 8, ring | 1);
+        input.write32(ep + 16, max_packet << 16 | 8);
+    }
+
+    fn control_transfer(
+        &mut self,
+        slot: u8,
+        ring: &mut Ring,
+        setup: SetupPacket,
+        data: Option<&DmaPages>,
+    ) -> Result<(), UsbError> {
+        let transfer_type = match (setup.length, setup.is_in()) {
+            (0, _) => TRANSFER_TYPE_NO_DATA,
+            (_, true) => TRANSFER_TYPE_IN,
+            (_, false) => TRANSFER_TYPE_OUT,
+        };
+        ring.push(Trb::new(TRB_SETUP, setup.to_u64(), 8,
+                           TRB_IMMEDIATE_DATA | transfer_type << 16));
+
+        if let (Some(data), true) = (data, setup.length > 0) {
+            let dir = if setup.is_in() { TRB_DIR_IN } else { 0 };
+            ring.push(Trb::new(TRB_DATA, data.paddr().0, setup.length as u32,
+                               dir));
+        }
+
+        // The status stage goes the other way than the data stage.
+        let dir = if setup.length > 0 && setup.is_in() { 0 } else { TRB_DIR_IN };
+        ring.push(Trb::new(TRB_STATUS, 0, 0,
+                           dir | TRB_INTERRUPT_ON_COMPLETION));
+        self.ring_doorbell(slot, DCI_CONTROL);
+
+        let event = self.wait_event(|event| {
+            event.trb_type() == TRB_TRANSFER_EVENT
+                && event.slot() == slot
+                && event.endpoint() == DCI_CONTROL
+        })?;
+        match event.completion_code() {
+            CC_SUCCESS | CC_SHORT_PACKET => Ok(()),
+            code => Err(UsbError::Failed(code)),
+        }
+    }
+
+    fn command(&mut self, trb: Trb) -> Result<Trb, UsbError> {
+        let paddr = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+
+        let event = self.wait_event(|event| {
+            event.trb_type() == TRB_COMMAND_COMPLETION && event.param == paddr
+        })?;
+        match event.completion_code() {
+            CC_SUCCESS => Ok(event),
+            code => Err(UsbError::Failed(code)),
+        }
+    }
+
+    /// Wait for an event matching `is_awaited`, handling the others.
+    fn wait_event(
+        &mut self,
+        is_awaited: impl Fn(&Trb) -> bool,
+    ) -> Result<Trb, UsbError> {
+        for _ in 0..(TIMEOUT_US / POLL_PERIOD_US) {
+            while let Some(event) = self.events.pop() {
+                if is_awaited(&event) {
+                    self.acknowledge_events();
+                    return Ok(event);
+                }
+                self.handle_event(event);
+            }
+            self.acknowledge_events();
+            delay_us(POLL_PERIOD_US);
+        }
+
+        Err(UsbError::Timeout)
+    }
+
+    fn handle_event(&mut self, event: Trb) {
+        if event.trb_type() != TRB_TRANSFER_EVENT {
+            return;
+        }
+
+        let Some(device) = self.devices.iter_mut()
+            .find(|device| device.slot == event.slot()) else {
+            return;
+        };
+        let slot = device.slot;
+        let Some(keyboard) = device.keyboard.as_mut()
+            .filter(|keyboard| keyboard.dci == event.endpoint()) else {
+            return;
+        };
+
+        match event.completion_code() {
+            CC_SUCCESS | CC_SHORT_PACKET => {
+                let mut report = [0; hid::REPORT_LEN];
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        keyboard.buffer.vaddr().as_ptr::<u8>(),
+                        report.as_mut_ptr(),
+                        report.len(),
+                    );
+                }
+                keyboard.hid.on_report(&report, on_key_event);
+
+                keyboard.queue_report();
+                let dci = keyboard.dci;
+                self.ring_doorbell(slot, dci);
+            },
+            code => warning!("usb: keyboard in slot {slot}: transfer failed \
+                              with completion code {code}"),
+        }
+    }
+
+    /// Tell the controller the events up to the dequeue pointer were handled.
+    fn acknowledge_events(&self) {
+        self.regs.write64(self.runtime + IR0 + IR_ERDP,
+                          self.events.dequeue_paddr() | ERDP_BUSY);
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        self.regs.write32(self.doorbells + 4 * slot as usize, target as u32);
+    }
+
+    fn op_read32(&self, offset: usize) -> u32 {
+        self.regs.read32(self.op + offset)
+    }
+
+    fn op_write32(&self, offset: usize, value: u32) {
+        self.regs.write32(self.op + offset, value)
+    }
+
+    fn op_write64(&self, offset: usize, value: u64) {
+        self.regs.write64(self.op + offset, value)
+    }
+}
+
+/// Find the first boot keyboard interface of a configuration, along with its
+/// interrupt IN endpoint.
+fn find_boot_keyboard(
+    config: &[u8],
+) -> Result<Option<(u8, EndpointDescriptor)>, UsbError> {
+    let mut interface = None;
+
+    for desc in parse_configuration(config) {
+        match desc? {
+            Descriptor::Interface(iface) => {
+                interface = hid::is_boot_keyboard(&iface).then_some(iface.number);
+            },
+            Descriptor::Endpoint(ep) if ep.is_in() && ep.is_interrupt() => {
+                if let Some(interface) = interface {
+                    return Ok(Some((interface, ep)));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Ok(None)
+}
+
+/// The endpoint context's interval, in 2^n × 125 µs, for an endpoint
+/// descriptor's `bInterval`: a number of frames (milliseconds) at low and full
+/// speed, or an exponent of microframes at higher speeds.
+fn endpoint_interval(speed: u32, interval: u8) -> u32 {
+    match speed {
+        SPEED_HIGH | SPEED_SUPER => interval.clamp(1, 16) as u32 - 1,
+        _ => (interval.max(1) as u32 * 8).ilog2().clamp(3, 10),
+    }
+}
+
+fn dma_page() -> Result<DmaPages, UsbError> {
+    DmaPages::new(1).ok_or(UsbError::NoMemory)
+}
+
+fn wait_until(mut cond: impl FnMut() -> bool) -> Result<(), UsbError> {
+    for _ in 0..(TIMEOUT_US / POLL_PERIOD_US) {
+        if cond() {
+            return Ok(());
+        }
+        delay_us(POLL_PERIOD_US);
+    }
+
+    Err(UsbError::Timeout)
+}
@@ -0,0 +1,403 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The virtio-gpu device, as found in virtual machines, driven in 2D mode: the
+//! framebuffer is a resource in guest memory, which the host copies to its
+//! display when flushed. Unlike the framebuffer set up by the firmware, its
+//! resolution can be changed at any time.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use thiserror_no_std::Error;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::driver::pci::{self, PciDevice};
+use crate::driver::screen::{Color, FramebufferMode, FramebufferScreen,
+                            PixelFormat};
+use crate::driver::virtio::{
+    Buffer, VirtioError, VirtioPci, Virtqueue, FEATURE_VERSION_1,
+    PCI_DEVICE_MODERN_BASE, PCI_VENDOR_VIRTIO,
+};
+use crate::mem::dma::DmaPages;
+use crate::misc::align_up;
+use crate::sync::Spinlock;
+use crate::{info, initcall, warning};
+
+pub const DEVICE_TYPE_GPU: u16 = 16;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Pixels of bytes B, G, R then unused: 0x00RRGGBB in little-endian.
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const CONTROL_QUEUE: u16 = 0;
+
+/// The size of the header of every request and response.
+const HEADER_LEN: usize = 24;
+const MAX_SCANOUTS: usize = 16;
+/// The size of the response to `CMD_GET_DISPLAY_INFO`: the header, then the
+/// rectangle, enabled and flags fields of every scanout.
+const DISPLAY_INFO_LEN: usize = HEADER_LEN + MAX_SCANOUTS * 24;
+
+/// The offset of the response within the command buffer.
+const RESPONSE_OFFSET: usize = 2048;
+
+/// The scanout, i.e. display output, the framebuffers are shown on.
+const SCANOUT: u32 = 0;
+
+const MAX_WIDTH: usize = 7680;
+const MAX_HEIGHT: usize = 4320;
+
+static GPU: Spinlock<Option<VirtioGpu>> = Spinlock::new(None);
+
+#[derive(Error, Debug)]
+pub enum GpuError {
+    #[error("no virtio-gpu device")]
+    NoDevice,
+
+    #[error("{0}")]
+    Virtio(#[from] VirtioError),
+
+    #[error("command {cmd:#06x} failed with response {resp:#06x}")]
+    Command { cmd: u32, resp: u32 },
+
+    #[error("invalid resolution {0}×{1}")]
+    InvalidResolution(usize, usize),
+
+    #[error("out of memory")]
+    NoMemory,
+}
+
+struct VirtioGpu {
+    transport: VirtioPci,
+    control: Virtqueue,
+    /// The buffer of the command being sent, with its response at
+    /// `RESPONSE_OFFSET`.
+    command: DmaPages,
+    next_resource_id: u32,
+}
+
+impl VirtioGpu {
+    fn new(dev: &PciDevice) -> Result<Self, GpuError> {
+        let transport = VirtioPci::new(dev, "virtio-gpu")?;
+        transport.negotiate(FEATURE_VERSION_1)?;
+        let control = transport.setup_queue(CONTROL_QUEUE)?;
+        transport.driver_ok();
+
+        Ok(Self {
+            transport,
+            control,
+            command: DmaPages::new(1).ok_or(GpuError::NoMemory)?,
+            next_resource_id: 1,
+        })
+    }
+
+    /// Send the command `cmd` with the dwords `args` after its header, and
+    /// check the device answered with `resp` in `resp_len` bytes.
+    fn command(
+        &mut self,
+        cmd: u32,
+        args: &[u32],
+        resp: u32,
+        resp_len: usize,
+    ) -> Result<(), GpuError> {
+        self.command.zero();
+        self.command.write32(0, cmd);
+        for (i, &arg) in args.iter().enumerate() {
+            self.command.write32(HEADER_LEN + 4 * i, arg);
+        }
+
+        let paddr = self.command.paddr();
+        self.transport.request(&mut self.control, &[
+            Buffer {
+                paddr,
+                len: (HEADER_LEN + 4 * args.len()) as u32,
+                writable: false,
+            },
+            Buffer {
+                paddr: paddr + RESPONSE_OFFSET as u64,
+                len: resp_len as u32,
+                writable: true,
+            },
+        ])?;
+
+        match self.command.read32(RESPONSE_OFFSET) {
+            r if r == resp => Ok(()),
+            r => Err(GpuError::Command { cmd, resp: r }),
+        }
+    }
+
+    /// The resolutions of the enabled displays, as preferred by the host.
+    fn display_modes(&mut self) -> Result<Vec<(usize, usize)>, GpuError> {
+        self.command(CMD_GET_DISPLAY_INFO, &[], RESP_OK_DISPLAY_INFO,
+                     DISPLAY_INFO_LEN)?;
+
+        Ok((0..MAX_SCANOUTS)
+            .map(|i| RESPONSE_OFFSET + HEADER_LEN + i * 24)
+            .filter(|&mode| self.command.read32(mode + 16) != 0)
+            .map(|mode| (self.command.read32(mode + 8) as usize,
+                         self.command.read32(mode + 12) as usize))
+            .collect())
+    }
+
+    /// Create a resource of `width` × `height` pixels backed by `mem`, and
+    /// show it on the display.
+    fn create_scanout(
+        &mut self,
+        mem: &DmaPages,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuError> {
+        let id = self.next_resource_id;
+        self.next_resource_id += 1;
+
+        self.command(CMD_RESOURCE_CREATE_2D,
+                     &[id, FORMAT_B8G8R8X8_UNORM, width, height],
+                     RESP_OK_NODATA, HEADER_LEN)?;
+        let paddr = mem.paddr().0;
+        self.command(CMD_RESOURCE_ATTACH_BACKING,
+                     &[id, 1, paddr as u32, (paddr >> 32) as u32,
+                       mem.bsize() as u32, 0],
+                     RESP_OK_NODATA, HEADER_LEN)?;
+        self.command(CMD_SET_SCANOUT, &[0, 0, width, height, SCANOUT, id],
+                     RESP_OK_NODATA, HEADER_LEN)?;
+
+        Ok(id)
+    }
+
+    /// Copy the rows `y..y + height` of a resource from guest memory, and
+    /// update the display with them.
+    fn flush(&mut self, id: u32, width: u32, y: u32, height: u32)
+             -> Result<(), GpuError> {
+        let offset = y as u64 * width as u64 * 4;
+        self.command(CMD_TRANSFER_TO_HOST_2D,
+                     &[0, y, width, height,
+                       offset as u32, (offset >> 32) as u32, id, 0],
+                     RESP_OK_NODATA, HEADER_LEN)?;
+        self.command(CMD_RESOURCE_FLUSH, &[0, y, width, height, id, 0],
+                     RESP_OK_NODATA, HEADER_LEN)
+    }
+
+    fn destroy(&mut self, id: u32) -> Result<(), GpuError> {
+        self.command(CMD_RESOURCE_DETACH_BACKING, &[id, 0],
+                     RESP_OK_NODATA, HEADER_LEN)?;
+        self.command(CMD_RESOURCE_UNREF, &[id, 0], RESP_OK_NODATA, HEADER_LEN)
+    }
+}
+
+/// A framebuffer shown on the display of the virtio-gpu device; the
+/// resource is destroyed on drop.
+pub struct GpuFramebuffer {
+    resource_id: u32,
+    mem: DmaPages,
+    width: usize,
+    height: usize,
+    /// The rows written to since the last flush.
+    dirty: Option<(usize, usize)>,
+}
+
+impl GpuFramebuffer {
+    fn mark_dirty(&mut self, y: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((start, end)) => (start.min(y), end.max(y + 1)),
+            None => (y, y + 1),
+        });
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.mem.vaddr().as_mut_ptr(),
+                                            self.width * self.height)
+        }
+    }
+}
+
+impl FramebufferScreen for GpuFramebuffer {
+    fn mode(&self) -> FramebufferMode {
+        FramebufferMode {
+            width: self.width,
+            height: self.height,
+            pitch: self.width * 4,
+            format: PixelFormat::XRGB8888,
+        }
+    }
+
+    fn put(&mut self, x: usize, y: usize, color: Color) {
+        let width = self.width;
+        self.pixels()[y * width + x] = PixelFormat::XRGB8888.encode(color);
+        self.mark_dirty(y);
+    }
+
+    fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
+        let start = y * self.width + x;
+        self.pixels()[start..(start + data.len())].copy_from_slice(data);
+        self.mark_dirty(y);
+    }
+
+    fn clear(&mut self) {
+        self.pixels().fill(0);
+        self.dirty = Some((0, self.height));
+    }
+
+    fn flush(&mut self) {
+        let Some((start, end)) = self.dirty.take() else {
+            return;
+        };
+        let Some(ref mut gpu) = *GPU.lock() else {
+            return;
+        };
+
+        if let Err(e) = gpu.flush(self.resource_id, self.width as u32,
+                                  start as u32, (end - start) as u32) {
+            warning!("virtio-gpu: flush failed: {e}");
+        }
+    }
+
+    fn panic_handle(&self) -> Option<Box<dyn FramebufferScreen + Send>> {
+        Some(Box::new(PanicFramebuffer {
+            resource_id: self.resource_id,
+            pixels: self.mem.vaddr().as_mut_ptr(),
+            width: self.width,
+            height: self.height,
+        }))
+    }
+}
+
+/// A second handle onto the pixels of a `GpuFramebuffer`, for the panic
+/// handler: the whole screen is flushed, only if the device isn't in use, and
+/// the resource isn't destroyed on drop.
+struct PanicFramebuffer {
+    resource_id: u32,
+    pixels: *mut u32,
+    width: usize,
+    height: usize,
+}
+
+unsafe impl Send for PanicFramebuffer {}
+
+impl PanicFramebuffer {
+    fn pixels(&mut self) -> &mut [u32] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.pixels,
+                                            self.width * self.height)
+        }
+    }
+}
+
+impl FramebufferScreen for PanicFramebuffer {
+    fn mode(&self) -> FramebufferMode {
+        FramebufferMode {
+            width: self.width,
+            height: self.height,
+            pitch: self.width * 4,
+            format: PixelFormat::XRGB8888,
+        }
+    }
+
+    fn put(&mut self, x: usize, y: usize, color: Color) {
+        let width = self.width;
+        self.pixels()[y * width + x] = PixelFormat::XRGB8888.encode(color);
+    }
+
+    fn copy(&mut self, x: usize, y: usize, data: &[u32]) {
+        let start = y * self.width + x;
+        self.pixels()[start..(start + data.len())].copy_from_slice(data);
+    }
+
+    fn clear(&mut self) {
+        self.pixels().fill(0);
+    }
+
+    fn flush(&mut self) {
+        if let Some(mut gpu) = GPU.try_lock() {
+            if let Some(ref mut gpu) = *gpu {
+                let _ = gpu.flush(self.resource_id, self.width as u32,
+                                  0, self.height as u32);
+            }
+        }
+    }
+}
+
+impl Drop for GpuFramebuffer {
+    fn drop(&mut self) {
+        if let Some(ref mut gpu) = *GPU.lock() {
+            if let Err(e) = gpu.destroy(self.resource_id) {
+                warning!("virtio-gpu: couldn't destroy resource: {e}");
+            }
+        }
+    }
+}
+
+/// Start the first virtio-gpu device found on the PCI bus, if any.
+pub fn init() {
+    let Some(dev) = pci::devices().into_iter().find(|dev| {
+        dev.vendor_id == PCI_VENDOR_VIRTIO
+            && dev.device_id == PCI_DEVICE_MODERN_BASE + DEVICE_TYPE_GPU
+    }) else {
+        return;
+    };
+
+    if let Err(e) = dev.claim("virtio-gpu") {
+        warning!("virtio-gpu: {e}");
+        return;
+    }
+
+    match VirtioGpu::new(&dev) {
+        Ok(gpu) => {
+            info!("virtio-gpu: device at {} started", dev.addr);
+            *GPU.lock() = Some(gpu);
+        },
+        Err(e) => warning!("virtio-gpu: device at {}: {e}", dev.addr),
+    }
+}
+
+initcall!(device, init, "Starting the virtio GPU...");
+
+pub fn is_present() -> bool {
+    GPU.lock().is_some()
+}
+
+/// The resolutions preferred by the host for its enabled displays.
+pub fn display_modes() -> Result<Vec<(usize, usize)>, GpuError> {
+    GPU.lock().as_mut().ok_or(GpuError::NoDevice)?.display_modes()
+}
+
+/// Create a framebuffer of `width` × `height` pixels and show it on the
+/// display, replacing the previous one.
+pub fn framebuffer(width: usize, height: usize)
+                   -> Result<GpuFramebuffer, GpuError> {
+    if !(1..=MAX_WIDTH).contains(&width) || !(1..=MAX_HEIGHT).contains(&height) {
+        return Err(GpuError::InvalidResolution(width, height));
+    }
+
+    let nr_frames = align_up(width * height * 4, PAGE_SIZE) / PAGE_SIZE;
+    let mem = DmaPages::new(nr_frames).ok_or(GpuError::NoMemory)?;
+    let resource_id = GPU.lock().as_mut()
+        .ok_or(GpuError::NoDevice)?
+        .create_scanout(&mem, width as u32, height as u32)?;
+
+    Ok(GpuFramebuffer {
+        resource_id,
+        mem,
+        width,
+        height,
+        dirty: Some((0, height)),
+    })
+}
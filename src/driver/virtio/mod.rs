@@ -0,0 +1,316 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Virtio devices, over the PCI transport of virtio 1.0: the device's
+//! registers are split into configuration structures, located by vendor PCI
+//! capabilities. Requests go through split virtqueues: rings of descriptors of
+//! guest memory buffers, shared with the device.
+//!
+//! Requests are synchronous: the driver posts one chain of buffers, notifies
+//! the device, then polls until the device returns it.
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{fence, Ordering};
+use thiserror_no_std::Error;
+
+use crate::arch::time::delay_us;
+use crate::driver::pci::{Bar, PciDevice};
+use crate::mem::dma::DmaPages;
+use crate::mem::iomap::IomapError;
+use crate::mem::resource::{self, ResourceError};
+use crate::mem::{iomap, CacheMode, MmioRegion, PAddr};
+
+#[cfg(feature = "fb-terminal")]
+pub mod gpu;
+
+pub const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+
+/// The PCI device ID of modern devices is this plus the virtio device type.
+pub const PCI_DEVICE_MODERN_BASE: u16 = 0x1040;
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+/// The device complies with virtio 1.0, rather than the legacy interface.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// The largest queue size used, so that a whole queue fits in a page.
+const MAX_QUEUE_SIZE: u16 = 64;
+
+/// The offsets of the driver and device areas within the queue's page, after
+/// the descriptor table.
+const QUEUE_AVAIL_OFFSET: usize = 16 * MAX_QUEUE_SIZE as usize;
+const QUEUE_USED_OFFSET: usize = 2048;
+
+const TIMEOUT_US: u64 = 1_000_000;
+const POLL_PERIOD_US: u64 = 10;
+
+#[derive(Error, Debug)]
+pub enum VirtioError {
+    #[error("couldn't map the device's registers: {0}")]
+    Iomap(#[source] IomapError),
+
+    #[error("couldn't request the device's registers: {0}")]
+    Resource(#[source] ResourceError),
+
+    #[error("missing {0} configuration structure")]
+    MissingStructure(&'static str),
+
+    #[error("the device rejected the features")]
+    FeaturesRejected,
+
+    #[error("queue {0} is not available")]
+    NoQueue(u16),
+
+    #[error("out of memory")]
+    NoMemory,
+
+    #[error("the device didn't answer in time")]
+    Timeout,
+}
+
+/// A buffer of a request, in guest memory.
+#[derive(Debug, Copy, Clone)]
+pub struct Buffer {
+    pub paddr: PAddr,
+    pub len: u32,
+    /// Whether the device writes to the buffer, rather than reads from it.
+    pub writable: bool,
+}
+
+/// The PCI transport of a virtio device.
+pub struct VirtioPci {
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_multiplier: u32,
+    device: Option<MmioRegion>,
+}
+
+impl VirtioPci {
+    /// Map the configuration structures of `dev` on behalf of the driver
+    /// `owner`, and reset the device.
+    pub fn new(dev: &PciDevice, owner: &'static str) -> Result<Self, VirtioError> {
+        let mut common = None;
+        let mut notify = None;
+        let mut device = None;
+        let mut requested = ArrayVec::<u8, 6>::new();
+
+        for (_, cap) in dev.capabilities().filter(|&(id, _)| id == PCI_CAP_VENDOR) {
+            let cfg_type = dev.addr.read8(cap + 3);
+            if !matches!(cfg_type, CFG_TYPE_COMMON | CFG_TYPE_NOTIFY
+                                   | CFG_TYPE_DEVICE) {
+                continue;
+            }
+
+            let bar_index = dev.addr.read8(cap + 4);
+            let offset = dev.addr.read32(cap + 8) as u64;
+            let len = dev.addr.read32(cap + 12) as usize;
+            let Some(Bar::Memory { paddr, bsize, .. }) = dev.bar(bar_index) else {
+                continue;
+            };
+
+            if !requested.contains(&bar_index) {
+                resource::request_region(paddr, bsize, owner)
+                    .map_err(VirtioError::Resource)?;
+                requested.push(bar_index);
+            }
+            let regs = iomap(paddr + offset, len, CacheMode::Uncached)
+                .map_err(VirtioError::Iomap)?;
+
+            match cfg_type {
+                CFG_TYPE_COMMON => common = Some(regs),
+                CFG_TYPE_NOTIFY => {
+                    notify = Some((regs, dev.addr.read32(cap + 16)));
+                },
+                _ => device = Some(regs),
+            }
+        }
+
+        let common = common.ok_or(VirtioError::MissingStructure("common"))?;
+        let (notify, notify_multiplier) = notify
+            .ok_or(VirtioError::MissingStructure("notification"))?;
+        dev.enable();
+
+        let transport = Self { common, notify, notify_multiplier, device };
+        transport.reset()?;
+
+        Ok(transport)
+    }
+
+    /// The device-specific configuration structure, if the device has one.
+    pub fn device_config(&self) -> Option<&MmioRegion> {
+        self.device.as_ref()
+    }
+
+    /// Negotiate the features the driver supports among `features`; return
+    /// those the device offers too.
+    pub fn negotiate(&self, features: u64) -> Result<u64, VirtioError> {
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+
+        let mut offered = 0;
+        for half in 0..2 {
+            self.common.write32(COMMON_DEVICE_FEATURE_SELECT, half);
+            offered |= (self.common.read32(COMMON_DEVICE_FEATURE) as u64)
+                << (32 * half);
+        }
+
+        let accepted = offered & features;
+        for half in 0..2 {
+            self.common.write32(COMMON_DRIVER_FEATURE_SELECT, half);
+            self.common.write32(COMMON_DRIVER_FEATURE,
+                                (accepted >> (32 * half)) as u32);
+        }
+
+        self.set_status(STATUS_FEATURES_OK);
+        if self.common.read8(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        Ok(accepted)
+    }
+
+    /// Set up the virtqueue `index`.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.common.write16(COMMON_QUEUE_SELECT, index);
+        let size = self.common.read16(COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        let mem = DmaPages::new(1).ok_or(VirtioError::NoMemory)?;
+        self.common.write16(COMMON_QUEUE_SIZE, size);
+        self.common.write64(COMMON_QUEUE_DESC, mem.paddr().0);
+        self.common.write64(COMMON_QUEUE_DRIVER,
+                            mem.paddr().0 + QUEUE_AVAIL_OFFSET as u64);
+        self.common.write64(COMMON_QUEUE_DEVICE,
+                            mem.paddr().0 + QUEUE_USED_OFFSET as u64);
+        let notify_offset = self.common.read16(COMMON_QUEUE_NOTIFY_OFF) as usize
+            * self.notify_multiplier as usize;
+        self.common.write16(COMMON_QUEUE_ENABLE, 1);
+
+        Ok(Virtqueue {
+            index,
+            size,
+            mem,
+            notify_offset,
+            avail_idx: 0,
+            used_idx: 0,
+        })
+    }
+
+    /// Tell the device the driver is ready, once the queues are set up.
+    pub fn driver_ok(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Post the chain of `buffers` to `queue`, and wait for the device to be
+    /// done with them; return the number of bytes the device wrote.
+    pub fn request(
+        &self,
+        queue: &mut Virtqueue,
+        buffers: &[Buffer],
+    ) -> Result<u32, VirtioError> {
+        assert!(!buffers.is_empty() && buffers.len() <= queue.size as usize);
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = i * 16;
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            queue.mem.write64(desc, buffer.paddr.0);
+            queue.mem.write32(desc + 8, buffer.len);
+            queue.mem.write16(desc + 12, flags);
+            queue.mem.write16(desc + 14, i as u16 + 1);
+        }
+
+        let slot = queue.avail_idx % queue.size;
+        queue.mem.write16(QUEUE_AVAIL_OFFSET + 4 + 2 * slot as usize, 0);
+        queue.avail_idx = queue.avail_idx.wrapping_add(1);
+        fence(Ordering::Release);
+        queue.mem.write16(QUEUE_AVAIL_OFFSET + 2, queue.avail_idx);
+        fence(Ordering::SeqCst);
+        self.notify.write16(queue.notify_offset, queue.index);
+
+        for _ in 0..(TIMEOUT_US / POLL_PERIOD_US) {
+            if queue.mem.read16(QUEUE_USED_OFFSET + 2) != queue.used_idx {
+                fence(Ordering::Acquire);
+                let slot = queue.used_idx % queue.size;
+                let len = queue.mem.read32(
+                    QUEUE_USED_OFFSET + 4 + 8 * slot as usize + 4
+                );
+                queue.used_idx = queue.used_idx.wrapping_add(1);
+                return Ok(len);
+            }
+            delay_us(POLL_PERIOD_US);
+        }
+
+        Err(VirtioError::Timeout)
+    }
+
+    fn reset(&self) -> Result<(), VirtioError> {
+        self.common.write8(COMMON_DEVICE_STATUS, 0);
+
+        for _ in 0..(TIMEOUT_US / POLL_PERIOD_US) {
+            if self.common.read8(COMMON_DEVICE_STATUS) == 0 {
+                return Ok(());
+            }
+            delay_us(POLL_PERIOD_US);
+        }
+
+        Err(VirtioError::Timeout)
+    }
+
+    /// Add `status` to the device status bits.
+    fn set_status(&self, status: u8) {
+        let current = self.common.read8(COMMON_DEVICE_STATUS);
+        self.common.write8(COMMON_DEVICE_STATUS, current | status);
+    }
+}
+
+/// A split virtqueue, fitting in a single page.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// The descriptor table, then the driver area at `QUEUE_AVAIL_OFFSET` and
+    /// the device area at `QUEUE_USED_OFFSET`.
+    mem: DmaPages,
+    /// The offset of the queue's doorbell in the notification structure.
+    notify_offset: usize,
+    avail_idx: u16,
+    used_idx: u16,
+}
@@ -0,0 +1,184 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Block devices: stores of fixed-size blocks, on which the disk file systems
+//! live. There is no disk driver yet; the RAM disk serves file system images
+//! kept in memory, e.g. loaded as boot modules.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::ops::Range;
+
+use crate::fs::FsError;
+use crate::sync::Spinlock;
+
+pub trait BlockDevice: Send + Sync {
+    /// The size of a block in bytes, a power of two of at least 512.
+    fn block_size(&self) -> usize;
+
+    fn nr_blocks(&self) -> u64;
+
+    /// Read the blocks from `first` into `buf`, whose length is a multiple of
+    /// the block size.
+    fn read_blocks(&self, first: u64, buf: &mut [u8]) -> Result<(), FsError>;
+
+    /// Write `buf`, whose length is a multiple of the block size, to the
+    /// blocks from `first`.
+    fn write_blocks(&self, first: u64, buf: &[u8]) -> Result<(), FsError>;
+}
+
+/// Read `buf.len()` bytes of `dev` from the byte `offset`, which needn't be
+/// aligned on a block.
+pub fn read_at(
+    dev: &dyn BlockDevice,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), FsError> {
+    let bsize = dev.block_size();
+    let mut block = Vec::new();
+    let mut done = 0;
+
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let in_block = (pos % bsize as u64) as usize;
+        let left = buf.len() - done;
+
+        if in_block == 0 && left >= bsize {
+            let len = left - left % bsize;
+            dev.read_blocks(pos / bsize as u64, &mut buf[done..][..len])?;
+            done += len;
+        } else {
+            let len = min(bsize - in_block, left);
+            block.resize(bsize, 0);
+            dev.read_blocks(pos / bsize as u64, &mut block)?;
+            buf[done..][..len].copy_from_slice(&block[in_block..][..len]);
+            done += len;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `data` to `dev` from the byte `offset`, which needn't be aligned on a
+/// block; the blocks only partly written to are read first.
+pub fn write_at(
+    dev: &dyn BlockDevice,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), FsError> {
+    let bsize = dev.block_size();
+    let mut block = Vec::new();
+    let mut done = 0;
+
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let in_block = (pos % bsize as u64) as usize;
+        let left = data.len() - done;
+
+        if in_block == 0 && left >= bsize {
+            let len = left - left % bsize;
+            dev.write_blocks(pos / bsize as u64, &data[done..][..len])?;
+            done += len;
+        } else {
+            let len = min(bsize - in_block, left);
+            block.resize(bsize, 0);
+            dev.read_blocks(pos / bsize as u64, &mut block)?;
+            block[in_block..][..len].copy_from_slice(&data[done..][..len]);
+            dev.write_blocks(pos / bsize as u64, &block)?;
+            done += len;
+        }
+    }
+
+    Ok(())
+}
+
+/// A block device in memory.
+pub struct RamDisk {
+    block_size: usize,
+    data: Spinlock<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// A RAM disk of `block_size` blocks holding `data`, whose size is rounded
+    /// down to a whole number of blocks.
+    pub fn new(mut data: Vec<u8>, block_size: usize) -> Self {
+        assert!(block_size.is_power_of_two() && block_size >= 512);
+        data.truncate(data.len() - data.len() % block_size);
+        Self {
+            block_size,
+            data: Spinlock::new(data),
+        }
+    }
+
+    /// An empty RAM disk of `nr_blocks` blocks.
+    pub fn zeroed(nr_blocks: usize, block_size: usize) -> Self {
+        Self::new(vec![0; nr_blocks * block_size], block_size)
+    }
+
+    /// The bytes of the `len` bytes from the block `first`, if they are whole
+    /// blocks within the disk.
+    fn range(&self, first: u64, len: usize) -> Result<Range<usize>, FsError> {
+        let start = usize::try_from(first).ok()
+            .and_then(|first| first.checked_mul(self.block_size))
+            .ok_or(FsError::Io)?;
+        let end = start.checked_add(len).ok_or(FsError::Io)?;
+
+        if len % self.block_size != 0 || end > self.data.lock().len() {
+            return Err(FsError::Io);
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn nr_blocks(&self) -> u64 {
+        (self.data.lock().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, first: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let range = self.range(first, buf.len())?;
+        buf.copy_from_slice(&self.data.lock()[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, first: u64, buf: &[u8]) -> Result<(), FsError> {
+        let range = self.range(first, buf.len())?;
+        self.data.lock()[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_and_writes_unaligned_bytes() {
+        let disk = RamDisk::zeroed(4, 512);
+
+        write_at(&disk, 500, &[0xaa; 600]).unwrap();
+        let mut buf = [0; 1024];
+        disk.read_blocks(0, &mut buf).unwrap();
+        assert!(buf[..500].iter().all(|&byte| byte == 0));
+        assert!(buf[500..].iter().all(|&byte| byte == 0xaa));
+
+        let mut buf = [0; 602];
+        read_at(&disk, 499, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1], buf[600], buf[601]), (0, 0xaa, 0xaa, 0));
+
+        assert!(read_at(&disk, 2000, &mut buf).is_err());
+        assert!(write_at(&disk, 4 * 512, &[0]).is_err());
+    }
+}
@@ -0,0 +1,642 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The page cache, between the file systems and their backing store: the
+//! pages of file content read from the store are kept in memory, keyed by
+//! inode and page index, so that later reads don't hit the store again.
+//!
+//! Reading a file sequentially makes the following pages be read ahead, over
+//! a window that doubles with each sequential read up to `MAX_READAHEAD`; a
+//! random read resets it. There is no asynchronous I/O yet: pages are read
+//! ahead right after the read that triggered it, in larger batches.
+//!
+//! Writes go to the cached pages, which are marked dirty until `sync()` writes
+//! them back to the store; the file system allocates the room for the data on
+//! the store beforehand, so that writing back can't run out of space.
+//!
+//! The clean pages are evicted in least recently used order when the frame
+//! allocator runs out of free frames; dirty pages stay until written back.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::ops::RangeInclusive;
+use core::slice;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::fs::FsError;
+use crate::initcall;
+use crate::mem::frame::{self, allocate_frames, free_frames};
+use crate::mem::PAddr;
+use crate::sync::Spinlock;
+
+/// The number of pages read ahead once a sequential read is detected.
+const MIN_READAHEAD: u64 = 4;
+
+/// The most pages read ahead past a sequential read.
+pub const MAX_READAHEAD: u64 = 32;
+
+static CACHE: Spinlock<PageCache> = Spinlock::new(PageCache::new());
+static NEXT_FS_ID: AtomicU32 = AtomicU32::new(1);
+
+/// The number of a file, unique across file systems: a mounted file system
+/// numbers its files `fs_id << 32 | ino`, see `new_fs_id()`.
+pub type InodeId = u64;
+
+/// The backing store of a file system, from which it reads pages of file
+/// content.
+pub trait PageSource {
+    /// Read the page `index` of the file `inode` into `page`, `PAGE_SIZE`
+    /// bytes long; return the number of bytes of the file in it, fewer than
+    /// `PAGE_SIZE` in the last page and 0 past the end of the file.
+    fn read_page(
+        &self,
+        inode: InodeId,
+        index: u64,
+        page: &mut [u8],
+    ) -> Result<usize, FsError>;
+
+    /// Write the first `len` bytes of the page `index` of the file `inode`
+    /// back to the store. Read-only stores keep the default, which fails.
+    fn write_page(
+        &self,
+        _inode: InodeId,
+        _index: u64,
+        _page: &[u8],
+        _len: usize,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// The figures of the page cache, for monitoring.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub nr_pages: usize,
+    /// The pages written to and not written back yet.
+    pub nr_dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// The pages read ahead of the reads.
+    pub read_ahead: u64,
+    pub evicted: u64,
+    pub written_back: u64,
+}
+
+struct CachedPage {
+    frame: PAddr,
+    /// The number of bytes of the file in the page.
+    len: usize,
+    /// Whether the page was written to since it was read or written back.
+    dirty: bool,
+    /// When the page was last used, the key in `PageCache::lru`.
+    last_used: u64,
+}
+
+#[derive(Copy, Clone)]
+struct ReadAhead {
+    /// The index of the page a sequential read would start from.
+    next: u64,
+    /// The number of pages to read ahead.
+    window: u64,
+}
+
+struct PageCache {
+    pages: BTreeMap<(InodeId, u64), CachedPage>,
+    /// The cached pages, by time of last use.
+    lru: BTreeMap<u64, (InodeId, u64)>,
+    clock: u64,
+    read_ahead: BTreeMap<InodeId, ReadAhead>,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            read_ahead: BTreeMap::new(),
+            stats: CacheStats {
+                nr_pages: 0,
+                nr_dirty: 0,
+                hits: 0,
+                misses: 0,
+                read_ahead: 0,
+                evicted: 0,
+                written_back: 0,
+            },
+        }
+    }
+
+    /// Copy from the offset `in_page` of the cached page `index` of `inode`
+    /// into `buf`; return the number of bytes copied, or `None` if the page
+    /// isn't cached.
+    fn copy_from(
+        &mut self,
+        inode: InodeId,
+        index: u64,
+        in_page: usize,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let page = self.touch(inode, index)?;
+
+        let len = min(page.len.saturating_sub(in_page), buf.len());
+        buf[..len].copy_from_slice(&page_bytes(page.frame)[in_page..][..len]);
+        Some(len)
+    }
+
+    /// Copy `data` to the offset `in_page` of the cached page `index` of
+    /// `inode`, marking it dirty; return the number of bytes copied, or `None`
+    /// if the page isn't cached.
+    fn copy_to(
+        &mut self,
+        inode: InodeId,
+        index: u64,
+        in_page: usize,
+        data: &[u8],
+    ) -> Option<usize> {
+        let page = self.touch(inode, index)?;
+
+        let len = min(PAGE_SIZE - in_page, data.len());
+        page_bytes(page.frame)[in_page..][..len]
+            .copy_from_slice(&data[..len]);
+        page.len = max(page.len, in_page + len);
+        let was_clean = !page.dirty;
+        page.dirty = true;
+        if was_clean {
+            self.stats.nr_dirty += 1;
+        }
+        Some(len)
+    }
+
+    /// Mark the cached page `index` of `inode` as used just now.
+    fn touch(&mut self, inode: InodeId, index: u64) -> Option<&mut CachedPage> {
+        let page = self.pages.get_mut(&(inode, index))?;
+
+        self.lru.remove(&page.last_used);
+        self.clock += 1;
+        page.last_used = self.clock;
+        self.lru.insert(page.last_used, (inode, index));
+        self.stats.hits += 1;
+        Some(page)
+    }
+
+    /// Add the page `index` of `inode`, read into `frame`; return `false` if
+    /// it was cached in the meantime, in which case `frame` is left to the
+    /// caller.
+    fn insert(
+        &mut self,
+        inode: InodeId,
+        index: u64,
+        frame: PAddr,
+        len: usize,
+    ) -> bool {
+        if self.pages.contains_key(&(inode, index)) {
+            return false;
+        }
+
+        self.clock += 1;
+        self.pages.insert((inode, index), CachedPage {
+            frame,
+            len,
+            dirty: false,
+            last_used: self.clock,
+        });
+        self.lru.insert(self.clock, (inode, index));
+        self.stats.nr_pages += 1;
+        true
+    }
+
+    /// Remove the least recently used clean page; return its frame.
+    fn evict_oldest(&mut self) -> Option<PAddr> {
+        let (&last_used, &key) = self.lru.iter()
+            .find(|(_, key)| !self.pages[key].dirty)?;
+        self.lru.remove(&last_used);
+        let page = self.pages.remove(&key)?;
+
+        self.stats.nr_pages -= 1;
+        self.stats.evicted += 1;
+        Some(page.frame)
+    }
+
+    /// Record a read of the pages `first..=last` of `inode`; return the pages
+    /// to read ahead of it.
+    fn advance(&mut self, inode: InodeId, first: u64, last: u64) -> u64 {
+        let state = self.read_ahead.entry(inode).or_insert(ReadAhead {
+            next: 0,
+            window: 0,
+        });
+
+        state.window = if first == state.next || first + 1 == state.next {
+            (state.window * 2).clamp(MIN_READAHEAD, MAX_READAHEAD)
+        } else {
+            0
+        };
+        state.next = last + 1;
+        state.window
+    }
+
+    /// Mark the cached page `index` of `inode` as clean, copying it into
+    /// `buf` to be written back; return its length, or `None` if it isn't
+    /// cached or is clean.
+    fn take_dirty(
+        &mut self,
+        inode: InodeId,
+        index: u64,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let page = self.pages.get_mut(&(inode, index))
+            .filter(|page| page.dirty)?;
+
+        page.dirty = false;
+        self.stats.nr_dirty -= 1;
+        buf.copy_from_slice(page_bytes(page.frame));
+        Some(page.len)
+    }
+
+    /// Mark the cached page `index` of `inode` as dirty again, after it
+    /// failed to be written back.
+    fn redirty(&mut self, inode: InodeId, index: u64) {
+        if let Some(page) = self.pages.get_mut(&(inode, index)) {
+            if !page.dirty {
+                page.dirty = true;
+                self.stats.nr_dirty += 1;
+            }
+        }
+    }
+}
+
+/// Read the file `inode` from `source` at `offset` into `buf`, through the
+/// cache; return the number of bytes read, fewer than `buf.len()` at the end
+/// of the file.
+pub fn read(
+    source: &dyn PageSource,
+    inode: InodeId,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize, FsError> {
+    let first = offset / PAGE_SIZE as u64;
+    let mut done = 0;
+
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let index = pos / PAGE_SIZE as u64;
+        let in_page = (pos % PAGE_SIZE as u64) as usize;
+
+        let copied = CACHE.lock()
+            .copy_from(inode, index, in_page, &mut buf[done..]);
+        match copied {
+            Some(0) => break,
+            Some(len) => done += len,
+            None => {
+                CACHE.lock().stats.misses += 1;
+                if !fetch(source, inode, index)? {
+                    break;
+                }
+            },
+        }
+    }
+
+    if done > 0 {
+        let last = (offset + done as u64 - 1) / PAGE_SIZE as u64;
+        let window = CACHE.lock().advance(inode, first, last);
+        read_ahead(source, inode, last + 1, window);
+    }
+
+    Ok(done)
+}
+
+/// Write `data` to the file `inode` at `offset`, through the cache; return
+/// the number of bytes written. The file system must have grown the file to
+/// at least `offset + data.len()` bytes beforehand, see `resize()`.
+pub fn write(
+    source: &dyn PageSource,
+    inode: InodeId,
+    offset: u64,
+    data: &[u8],
+) -> Result<usize, FsError> {
+    let mut done = 0;
+
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let index = pos / PAGE_SIZE as u64;
+        let in_page = (pos % PAGE_SIZE as u64) as usize;
+
+        let copied = CACHE.lock()
+            .copy_to(inode, index, in_page, &data[done..]);
+        match copied {
+            Some(len) => done += len,
+            None => {
+                CACHE.lock().stats.misses += 1;
+                if !fetch(source, inode, index)? {
+                    break;
+                }
+            },
+        }
+    }
+
+    Ok(done)
+}
+
+/// Set the size of the cached pages of `inode` for a file now `bsize` bytes
+/// long; the bytes it grew by read as zeros.
+pub fn resize(inode: InodeId, bsize: u64) {
+    let mut cache = CACHE.lock();
+
+    for (&(_, index), page) in cache.pages
+        .range_mut((inode, 0)..=(inode, u64::MAX)) {
+        let start = index * PAGE_SIZE as u64;
+        let len = bsize.saturating_sub(start).min(PAGE_SIZE as u64) as usize;
+        if len > page.len {
+            page_bytes(page.frame)[page.len..len].fill(0);
+        }
+        page.len = len;
+    }
+}
+
+/// Write the dirty pages of the files `inodes` back to `source`. Stop at the
+/// first error, leaving the page that failed dirty.
+pub fn sync(
+    source: &dyn PageSource,
+    inodes: RangeInclusive<InodeId>,
+) -> Result<(), FsError> {
+    let dirty: Vec<_> = CACHE.lock().pages
+        .range((*inodes.start(), 0)..=(*inodes.end(), u64::MAX))
+        .filter(|(_, page)| page.dirty)
+        .map(|(&key, _)| key)
+        .collect();
+    let mut buf = vec![0; PAGE_SIZE];
+
+    for (inode, index) in dirty {
+        let Some(len) = CACHE.lock().take_dirty(inode, index, &mut buf) else {
+            continue;
+        };
+        if let Err(e) = source.write_page(inode, index, &buf, len) {
+            CACHE.lock().redirty(inode, index);
+            return Err(e);
+        }
+        CACHE.lock().stats.written_back += 1;
+    }
+
+    Ok(())
+}
+
+/// Drop the cached pages of `inode`, e.g. once the file changed on the store.
+/// Dirty pages are dropped without being written back.
+pub fn invalidate(inode: InodeId) {
+    invalidate_range(inode..=inode);
+}
+
+/// Drop the cached pages of the files `inodes`, e.g. those of a file system
+/// being unmounted.
+pub fn invalidate_range(inodes: RangeInclusive<InodeId>) {
+    let mut cache = CACHE.lock();
+    let keys: Vec<_> = cache.pages
+        .range((*inodes.start(), 0)..=(*inodes.end(), u64::MAX))
+        .map(|(&key, page)| (key, page.last_used))
+        .collect();
+
+    for (key, last_used) in keys {
+        let page = cache.pages.remove(&key).unwrap();
+        cache.lru.remove(&last_used);
+        cache.stats.nr_pages -= 1;
+        if page.dirty {
+            cache.stats.nr_dirty -= 1;
+        }
+        unsafe { free_frames(page.frame, 1); }
+    }
+    cache.read_ahead.retain(|inode, _| !inodes.contains(inode));
+}
+
+/// Allocate the number of a newly mounted file system, the upper half of the
+/// `InodeId`s of its files.
+pub fn new_fs_id() -> u32 {
+    NEXT_FS_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The range of the `InodeId`s of the files of the file system `fs_id`.
+pub fn fs_inodes(fs_id: u32) -> RangeInclusive<InodeId> {
+    let base = (fs_id as InodeId) << 32;
+    base..=(base | u32::MAX as InodeId)
+}
+
+pub fn stats() -> CacheStats {
+    CACHE.lock().stats
+}
+
+/// Register the page cache's shrinker with the frame allocator.
+pub fn init() {
+    if !frame::register_shrinker(shrink) {
+        panic!("too many shrinkers");
+    }
+}
+
+initcall!(early, init, "Starting the page cache...");
+
+/// Evict up to `nr_frames` clean pages, least recently used first; return how
+/// many were. Nothing is evicted if the cache is in use by the allocating
+/// code.
+fn shrink(nr_frames: usize) -> usize {
+    let mut nr_evicted = 0;
+
+    while nr_evicted < nr_frames {
+        let Some(frame) = CACHE.try_lock()
+            .and_then(|mut cache| cache.evict_oldest()) else {
+            break;
+        };
+        unsafe { free_frames(frame, 1); }
+        nr_evicted += 1;
+    }
+
+    nr_evicted
+}
+
+/// Read the page `index` of `inode` from `source` and cache it; return
+/// `false` if it is past the end of the file.
+fn fetch(
+    source: &dyn PageSource,
+    inode: InodeId,
+    index: u64,
+) -> Result<bool, FsError> {
+    let frame = allocate_frames().allocate().ok_or(FsError::NoMemory)?;
+
+    let len = match source.read_page(inode, index, page_bytes(frame)) {
+        Ok(len) if len > 0 => min(len, PAGE_SIZE),
+        result => {
+            unsafe { free_frames(frame, 1); }
+            return result.map(|_| false);
+        },
+    };
+
+    if !CACHE.lock().insert(inode, index, frame, len) {
+        unsafe { free_frames(frame, 1); }
+    }
+    Ok(true)
+}
+
+/// Cache the `window` pages of `inode` from `first` that aren't yet, stopping
+/// at the end of the file or on the first error.
+fn read_ahead(
+    source: &dyn PageSource,
+    inode: InodeId,
+    first: u64,
+    window: u64,
+) {
+    for index in first..(first + window) {
+        if CACHE.lock().pages.contains_key(&(inode, index)) {
+            continue;
+        }
+        if !matches!(fetch(source, inode, index), Ok(true)) {
+            break;
+        }
+        CACHE.lock().stats.read_ahead += 1;
+    }
+}
+
+fn page_bytes(frame: PAddr) -> &'static mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(frame.into_vaddr().as_mut_ptr(), PAGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::{Cell, RefCell};
+    use crate::arch::mem::{reset_memory, MEMORY_MUTEX, NR_PHYS_FRAMES};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use super::*;
+
+    /// A file of `bsize` bytes, counting the pages read and recording those
+    /// written back.
+    struct TestFile {
+        bsize: u64,
+        nr_reads: Cell<u64>,
+        written: RefCell<Vec<(u64, Vec<u8>)>>,
+    }
+
+    impl TestFile {
+        fn new(bsize: u64) -> Self {
+            Self {
+                bsize,
+                nr_reads: Cell::new(0),
+                written: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl PageSource for TestFile {
+        fn read_page(
+            &self,
+            _inode: InodeId,
+            index: u64,
+            page: &mut [u8],
+        ) -> Result<usize, FsError> {
+            self.nr_reads.set(self.nr_reads.get() + 1);
+            let start = index * PAGE_SIZE as u64;
+            let len = self.bsize.saturating_sub(start).min(PAGE_SIZE as u64);
+
+            for (i, byte) in page[..len as usize].iter_mut().enumerate() {
+                *byte = ((start + i as u64) % 251) as u8;
+            }
+            Ok(len as usize)
+        }
+
+        fn write_page(
+            &self,
+            _inode: InodeId,
+            index: u64,
+            page: &[u8],
+            len: usize,
+        ) -> Result<(), FsError> {
+            self.written.borrow_mut().push((index, page[..len].to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_reads_ahead_of_sequential_reads() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+        let file = TestFile::new(10 * PAGE_SIZE as u64 - 100);
+        let before = stats();
+
+        let mut buf = [0; 16];
+        assert_eq!(read(&file, 1, 4000, &mut buf).unwrap(), 16);
+        assert_eq!(buf[0], (4000 % 251) as u8);
+        assert_eq!(buf[15], (4015 % 251) as u8);
+        assert_eq!(file.nr_reads.get(), 1 + MIN_READAHEAD);
+        assert_eq!(stats().read_ahead - before.read_ahead, MIN_READAHEAD);
+
+        // The read ahead past the end of the file stops at the first page.
+        let mut buf = [0; 10 * PAGE_SIZE];
+        assert_eq!(read(&file, 1, 0, &mut buf).unwrap(), 10 * PAGE_SIZE - 100);
+        assert_eq!(file.nr_reads.get(), 11);
+        assert_eq!(buf[PAGE_SIZE], (PAGE_SIZE % 251) as u8);
+
+        invalidate(1);
+        assert_eq!(read(&file, 1, 0, &mut buf[..1]).unwrap(), 1);
+        assert_eq!(file.nr_reads.get(), 12 + MIN_READAHEAD);
+        invalidate(1);
+    }
+
+    #[test]
+    fn it_evicts_pages_when_memory_runs_out() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+        assert!(frame::register_shrinker(shrink));
+        let file = TestFile::new(2 * NR_PHYS_FRAMES as u64 * PAGE_SIZE as u64);
+        let before = stats();
+
+        let mut buf = [0; PAGE_SIZE];
+        for offset in (0..file.bsize).step_by(PAGE_SIZE) {
+            assert_eq!(read(&file, 2, offset, &mut buf).unwrap(), PAGE_SIZE);
+            assert_eq!(buf[0], (offset % 251) as u8);
+        }
+
+        assert!(stats().nr_pages <= NR_PHYS_FRAMES);
+        assert!(stats().evicted - before.evicted >= NR_PHYS_FRAMES as u64);
+        invalidate(2);
+    }
+    #[test]
+    fn it_writes_dirty_pages_back_on_sync() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+        let file = TestFile::new(PAGE_SIZE as u64 + 10);
+        let before = stats();
+
+        let offset = PAGE_SIZE as u64 + 5;
+        assert_eq!(write(&file, 3, offset, b"hello").unwrap(), 5);
+        assert_eq!(stats().nr_dirty - before.nr_dirty, 1);
+
+        // Dirty pages survive memory pressure.
+        shrink(NR_PHYS_FRAMES);
+        let mut buf = [0; 5];
+        assert_eq!(read(&file, 3, offset, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+
+        sync(&file, 3..=3).unwrap();
+        let written = file.written.take();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].0, 1);
+        assert_eq!(written[0].1.len(), 10);
+        assert_eq!(&written[0].1[5..], b"hello");
+        assert_eq!(stats().nr_dirty, before.nr_dirty);
+
+        sync(&file, 3..=3).unwrap();
+        assert!(file.written.take().is_empty());
+        invalidate(3);
+    }
+}
@@ -0,0 +1,393 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The device file system, mounted on `/dev`: each registered device is a
+//! file whose reads, writes and `ioctl()` requests go to the device's own
+//! handlers, so that hardware is accessed like any other file. The generic
+//! devices are registered by `init()`; the architecture registers its serial
+//! ports, and the boot modules are served as RAM disks.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::arch::cpu::random_u64;
+use crate::boot;
+use crate::fs::block::{self, BlockDevice, RamDisk};
+use crate::fs::FsError;
+use crate::sync::Spinlock;
+use crate::ui::kterm;
+use crate::{initcall, warning};
+
+/// Where the device file system is mounted.
+pub const MOUNT_POINT: &str = "/dev";
+
+/// The block size of the RAM disks holding the boot modules.
+const RAMDISK_BLOCK_SIZE: usize = 512;
+
+/// `ioctl()` request of framebuffers: write the width, height, pitch and bits
+/// per pixel of the video mode into the argument, as `u32`s.
+pub const FB_GET_MODE: u32 = 0x4600;
+
+/// `ioctl()` request of block devices: write the block size into the
+/// argument, as a `u32`.
+pub const BLK_GET_BLOCK_SIZE: u32 = 0x1268;
+
+/// `ioctl()` request of block devices: write the size in bytes into the
+/// argument, as a `u64`.
+pub const BLK_GET_SIZE: u32 = 0x1272;
+
+static DEVICES: Spinlock<BTreeMap<String, Node>>
+    = Spinlock::new(BTreeMap::new());
+
+/// The handlers of a device file. The operations a device doesn't support
+/// fail by default.
+pub trait Device: Send + Sync {
+    /// Read from the device at `offset` into `buf`; return the number of bytes
+    /// read, 0 at the end of the device. Stream devices ignore `offset`, and
+    /// fail with `WouldBlock` when no data is available yet.
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Write `data` to the device at `offset`; return the number of bytes
+    /// written.
+    fn write(&self, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Perform the device-specific `request`, whose argument is read from and
+    /// written to `arg`; return a request-specific value.
+    fn ioctl(&self, _request: u32, _arg: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::BadIoctl)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// A stream of bytes, or a device with its own addressing.
+    Char,
+    /// A store of fixed-size blocks, on which a file system can be mounted.
+    Block,
+}
+
+#[derive(Clone)]
+struct Node {
+    device: Arc<dyn Device>,
+    block: Option<Arc<dyn BlockDevice>>,
+}
+
+/// Register the generic devices: `null`, `zero`, `random`, the TTY of the
+/// kernel terminal as `tty0` and `console` if it takes input, `fb0` if it has
+/// a framebuffer, and `ram0`, `ram1`, etc. for the boot modules.
+pub fn init() {
+    let devices: [(&str, Arc<dyn Device>); 3] = [
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+        ("random", Arc::new(Random)),
+    ];
+    for (name, device) in devices {
+        register_or_warn(name, device);
+    }
+
+    if let Some(tty) = kterm::tty() {
+        register_or_warn("tty0", tty.clone());
+        register_or_warn("console", tty);
+    }
+
+    if kterm::with_framebuffer(|_| ()).is_some() {
+        register_or_warn("fb0", Arc::new(Framebuffer));
+    }
+
+    let modules = &boot::info().modules;
+    for (i, data) in modules.iter().filter_map(|module| module.data())
+        .enumerate() {
+        let disk = RamDisk::new(data.to_vec(), RAMDISK_BLOCK_SIZE);
+        if let Err(e) = register_block(&format!("ram{i}"), Arc::new(disk)) {
+            warning!("devfs: ram{i}: {e}");
+        }
+    }
+}
+
+initcall!(late, init, "Creating the device files...");
+
+/// Register `device` as the file `/dev/{name}`.
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), FsError> {
+    insert(name, Node { device, block: None })
+}
+
+/// Register the block device `dev` as the file `/dev/{name}`.
+pub fn register_block(
+    name: &str,
+    dev: Arc<dyn BlockDevice>,
+) -> Result<(), FsError> {
+    insert(name, Node {
+        device: Arc::new(BlockFile(dev.clone())),
+        block: Some(dev),
+    })
+}
+
+/// Remove the file `/dev/{name}`; the device stays usable by those who opened
+/// it.
+pub fn unregister(name: &str) -> Result<(), FsError> {
+    DEVICES.lock().remove(name).map(|_| ()).ok_or(FsError::NotFound)
+}
+
+/// The device of the file at the absolute `path`, e.g. `/dev/console`.
+pub fn open(path: &str) -> Result<Arc<dyn Device>, FsError> {
+    lookup(path).map(|node| node.device)
+}
+
+/// The block device of the file at the absolute `path`, e.g. to mount the
+/// file system on it.
+pub fn open_block(path: &str) -> Result<Arc<dyn BlockDevice>, FsError> {
+    lookup(path)?.block.ok_or(FsError::NotSupported)
+}
+
+/// The names of the registered devices, in alphabetical order.
+pub fn devices() -> Vec<(String, DeviceKind)> {
+    DEVICES.lock().iter()
+        .map(|(name, node)| {
+            let kind = match node.block {
+                Some(_) => DeviceKind::Block,
+                None => DeviceKind::Char,
+            };
+            (name.clone(), kind)
+        })
+        .collect()
+}
+
+fn insert(name: &str, node: Node) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(FsError::InvalidName);
+    }
+
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(FsError::Exists);
+    }
+    devices.insert(String::from(name), node);
+    Ok(())
+}
+
+fn lookup(path: &str) -> Result<Node, FsError> {
+    let name = path.strip_prefix(MOUNT_POINT)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .ok_or(FsError::NotFound)?;
+
+    DEVICES.lock().get(name).cloned().ok_or(FsError::NotFound)
+}
+
+fn register_or_warn(name: &str, device: Arc<dyn Device>) {
+    if let Err(e) = register(name, device) {
+        warning!("devfs: {name}: {e}");
+    }
+}
+
+/// Discards what is written, reads as empty.
+struct Null;
+
+impl Device for Null {
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Discards what is written, reads as an endless stream of zeros.
+struct Zero;
+
+impl Device for Zero {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// Reads as random bytes, from the architecture's RNG, which is not
+/// cryptographically secure everywhere; what is written is discarded.
+struct Random;
+
+impl Device for Random {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_ne_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// The framebuffer of the kernel terminal, as 0x00RRGGBB pixels row after
+/// row; the terminal draws over what is written at its next update. The
+/// pixels can't be read back.
+struct Framebuffer;
+
+impl Device for Framebuffer {
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if offset % 4 != 0 || data.len() % 4 != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        kterm::with_framebuffer(|fb| {
+            let (width, height) = fb.dimensions();
+            let first = usize::try_from(offset / 4).unwrap_or(usize::MAX);
+            let nr_pixels = min(data.len() / 4,
+                                (width * height).saturating_sub(first));
+            if nr_pixels == 0 && !data.is_empty() {
+                return Err(FsError::NoSpace);
+            }
+
+            let pixels: Vec<u32> = data[..(4 * nr_pixels)].chunks_exact(4)
+                .map(|pixel| u32::from_ne_bytes(pixel.try_into().unwrap()))
+                .collect();
+            let mut done = 0;
+            while done < nr_pixels {
+                let (x, y) = ((first + done) % width, (first + done) / width);
+                let len = min(width - x, nr_pixels - done);
+                fb.copy(x, y, &pixels[done..][..len]);
+                done += len;
+            }
+            fb.flush();
+
+            Ok(4 * nr_pixels)
+        }).unwrap_or(Err(FsError::Io))
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> Result<usize, FsError> {
+        if request != FB_GET_MODE {
+            return Err(FsError::BadIoctl);
+        }
+
+        let mode = kterm::with_framebuffer(|fb| fb.mode()).ok_or(FsError::Io)?;
+        let bpp = 8 * mode.format.bytes_per_pixel();
+        put_values(arg, &[mode.width, mode.height, mode.pitch, bpp]
+            .map(|value| value as u32))
+    }
+}
+
+/// The file of a block device, read and written at any byte offset.
+struct BlockFile(Arc<dyn BlockDevice>);
+
+impl BlockFile {
+    fn bsize(&self) -> u64 {
+        self.0.nr_blocks() * self.0.block_size() as u64
+    }
+
+    /// The part of a transfer of `len` bytes at `offset` within the device.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        min(len as u64, self.bsize().saturating_sub(offset)) as usize
+    }
+}
+
+impl Device for BlockFile {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = self.clamp(offset, buf.len());
+        block::read_at(&*self.0, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let len = self.clamp(offset, data.len());
+        if len == 0 && !data.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        block::write_at(&*self.0, offset, &data[..len])?;
+        Ok(len)
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> Result<usize, FsError> {
+        match request {
+            BLK_GET_BLOCK_SIZE => {
+                put_values(arg, &[self.0.block_size() as u32])
+            },
+            BLK_GET_SIZE => {
+                let bytes = self.bsize().to_ne_bytes();
+                arg.get_mut(..bytes.len())
+                    .ok_or(FsError::InvalidArgument)?
+                    .copy_from_slice(&bytes);
+                Ok(0)
+            },
+            _ => Err(FsError::BadIoctl),
+        }
+    }
+}
+
+/// Write `values` into the `ioctl()` argument `arg`.
+pub(crate) fn put_values(
+    arg: &mut [u8],
+    values: &[u32],
+) -> Result<usize, FsError> {
+    let arg = arg.get_mut(..(4 * values.len()))
+        .ok_or(FsError::InvalidArgument)?;
+
+    for (bytes, value) in arg.chunks_exact_mut(4).zip(values) {
+        bytes.copy_from_slice(&value.to_ne_bytes());
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_opens_registered_devices() {
+        register("test-zero", Arc::new(Zero)).unwrap();
+        assert!(matches!(register("test-zero", Arc::new(Null)),
+                         Err(FsError::Exists)));
+        assert!(matches!(register("a/b", Arc::new(Null)),
+                         Err(FsError::InvalidName)));
+
+        let zero = open("/dev/test-zero").unwrap();
+        let mut buf = [0xff; 16];
+        assert_eq!(zero.read(0, &mut buf).unwrap(), 16);
+        assert_eq!(buf, [0; 16]);
+        assert!(matches!(zero.ioctl(0, &mut buf), Err(FsError::BadIoctl)));
+        assert!(devices().contains(&(String::from("test-zero"),
+                                     DeviceKind::Char)));
+
+        unregister("test-zero").unwrap();
+        assert!(open("/dev/test-zero").is_err());
+        assert!(open("test-zero").is_err());
+    }
+
+    #[test]
+    fn it_serves_block_devices() {
+        register_block("test-ram", Arc::new(RamDisk::zeroed(4, 512)))
+            .unwrap();
+
+        let file = open("/dev/test-ram").unwrap();
+        assert_eq!(file.write(2045, b"hello").unwrap(), 3);
+        assert!(matches!(file.write(2048, b"x"), Err(FsError::NoSpace)));
+
+        let mut buf = [0; 8];
+        assert_eq!(file.read(2040, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0\0\0hel");
+        file.ioctl(BLK_GET_SIZE, &mut buf).unwrap();
+        assert_eq!(u64::from_ne_bytes(buf), 2048);
+
+        assert_eq!(open_block("/dev/test-ram").unwrap().nr_blocks(), 4);
+        unregister("test-ram").unwrap();
+    }
+}
@@ -0,0 +1,1100 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The ext2 file system, read and written on a block device.
+//!
+//! File content goes through the page cache. Writing to a file allocates its
+//! blocks on the device right away, so that writing back can't run out of
+//! space, but the data only reaches the device when the dirty pages are
+//! written back by `sync()`. The metadata, i.e. the superblock, the group
+//! descriptors, the bitmaps, the inodes and the directories, is read and
+//! written straight on the device.
+//!
+//! Only the features of the original ext2 are supported, plus the file types
+//! in directory entries; a file system with unknown read-only compatible
+//! features is mounted read-only. There is no wall clock yet, so timestamps are
+//! left at zero.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use arrayvec::ArrayVec;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::fs::block::{self, BlockDevice};
+use crate::fs::cache::{self, InodeId, PageSource};
+use crate::fs::FsError;
+use crate::sync::Spinlock;
+
+/// The inode of the root directory.
+pub const ROOT_INO: u32 = 2;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_BSIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+
+/// The first non-reserved inode and the inode size of revision 0 file
+/// systems.
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+const GROUP_DESC_BSIZE: usize = 32;
+
+/// The number of direct block pointers of an inode, followed by the single,
+/// double and triple indirect ones.
+const NR_DIRECT_BLOCKS: usize = 12;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+
+const MAX_NAME_LEN: usize = 255;
+
+/// The size of a directory entry's header, before its name.
+const DIR_ENTRY_HEADER: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    /// Symbolic links, device files, etc., which are not supported yet.
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: String,
+    pub kind: FileKind,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub bsize: u64,
+    pub nr_links: u16,
+}
+
+/// Where the metadata of a block group lies.
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+}
+
+/// The free counts of a block group, as in its descriptor.
+struct GroupCounts {
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// The allocation state, mirrored by the superblock and group descriptors on
+/// the device.
+struct Allocation {
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: Vec<GroupCounts>,
+}
+
+pub struct Ext2 {
+    dev: Arc<dyn BlockDevice>,
+    /// The number of the file system in the page cache.
+    fs_id: u32,
+    block_size: usize,
+    nr_blocks: u32,
+    nr_inodes: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    first_ino: u32,
+    inode_size: usize,
+    /// Whether directory entries hold the type of their file.
+    filetype: bool,
+    /// Whether regular files may be larger than 2 GiB.
+    large_file: bool,
+    read_only: bool,
+    groups: Vec<Group>,
+    /// Held across every modification of the file system, which are thus
+    /// serialized.
+    alloc: Spinlock<Allocation>,
+}
+
+impl Ext2 {
+    /// Mount the ext2 file system on `dev`.
+    pub fn mount(dev: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut sb = [0; SUPERBLOCK_BSIZE];
+        block::read_at(&*dev, SUPERBLOCK_OFFSET, &mut sb)?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(FsError::Corrupted);
+        }
+
+        // Blocks larger than a page would need several pages in the cache.
+        let log_block_size = u32_at(&sb, 24);
+        if log_block_size > 2 || 1024 << log_block_size > PAGE_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let block_size = 1024 << log_block_size;
+        if dev.block_size() > block_size {
+            return Err(FsError::Unsupported);
+        }
+
+        let (first_ino, inode_size, incompat, ro_compat) =
+            if u32_at(&sb, 76) == 0 {
+                (GOOD_OLD_FIRST_INO, GOOD_OLD_INODE_SIZE, 0, 0)
+            } else {
+                (u32_at(&sb, 84), u16_at(&sb, 88) as usize,
+                 u32_at(&sb, 96), u32_at(&sb, 100))
+            };
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::Unsupported);
+        }
+
+        let nr_inodes = u32_at(&sb, 0);
+        let nr_blocks = u32_at(&sb, 4);
+        let first_data_block = u32_at(&sb, 20);
+        let blocks_per_group = u32_at(&sb, 32);
+        let inodes_per_group = u32_at(&sb, 40);
+        let bits_per_block = 8 * block_size as u32;
+        let dev_bsize = dev.nr_blocks() * dev.block_size() as u64;
+
+        if inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || !(1..=bits_per_block).contains(&blocks_per_group)
+            || !(1..=bits_per_block).contains(&inodes_per_group)
+            || first_data_block >= nr_blocks
+            || nr_blocks as u64 * block_size as u64 > dev_bsize {
+            return Err(FsError::Corrupted);
+        }
+
+        let nr_groups = (nr_blocks - first_data_block)
+            .div_ceil(blocks_per_group) as usize;
+        if nr_inodes as u64 > nr_groups as u64 * inodes_per_group as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut descs = vec![0; nr_groups * GROUP_DESC_BSIZE];
+        let gdt_offset = (first_data_block as u64 + 1) * block_size as u64;
+        block::read_at(&*dev, gdt_offset, &mut descs)?;
+
+        let mut groups = Vec::with_capacity(nr_groups);
+        let mut counts = Vec::with_capacity(nr_groups);
+        for desc in descs.chunks_exact(GROUP_DESC_BSIZE) {
+            let group = Group {
+                block_bitmap: u32_at(desc, 0),
+                inode_bitmap: u32_at(desc, 4),
+                inode_table: u32_at(desc, 8),
+            };
+            if [group.block_bitmap, group.inode_bitmap, group.inode_table]
+                .iter().any(|&block| block >= nr_blocks) {
+                return Err(FsError::Corrupted);
+            }
+            groups.push(group);
+            counts.push(GroupCounts {
+                free_blocks: u16_at(desc, 12),
+                free_inodes: u16_at(desc, 14),
+                used_dirs: u16_at(desc, 16),
+            });
+        }
+
+        let known_ro_compat = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+
+        Ok(Self {
+            dev,
+            fs_id: cache::new_fs_id(),
+            block_size,
+            nr_blocks,
+            nr_inodes,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            first_ino,
+            inode_size,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !known_ro_compat != 0,
+            groups,
+            alloc: Spinlock::new(Allocation {
+                free_blocks: u32_at(&sb, 12),
+                free_inodes: u32_at(&sb, 16),
+                groups: counts,
+            }),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Write the dirty pages of the files back to the device.
+    pub fn sync(&self) -> Result<(), FsError> {
+        cache::sync(self, cache::fs_inodes(self.fs_id))
+    }
+
+    /// Write the dirty pages of the files back to the device, then drop all of
+    /// them from the page cache, even those that failed to be written back.
+    pub fn unmount(self) -> Result<(), FsError> {
+        let result = self.sync();
+        cache::invalidate_range(cache::fs_inodes(self.fs_id));
+        result
+    }
+
+    /// The inode of the file at `path`, relative to the root directory.
+    pub fn lookup(&self, path: &str) -> Result<u32, FsError> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(ROOT_INO, |dir, name| {
+                let dir = self.read_dir_inode(dir)?;
+                self.find_entry(&dir, name)?.ok_or(FsError::NotFound)
+            })
+    }
+
+    pub fn metadata(&self, ino: u32) -> Result<Metadata, FsError> {
+        let inode = self.read_inode(ino)?;
+        Ok(Metadata {
+            kind: inode.kind(),
+            bsize: inode.size(),
+            nr_links: inode.links(),
+        })
+    }
+
+    /// The entries of the directory `ino`, including `.` and `..`.
+    pub fn read_dir(&self, ino: u32) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.read_dir_inode(ino)?;
+        let mut entries = Vec::new();
+
+        for index in 0..self.nr_dir_blocks(&dir) {
+            let (_, block) = self.read_dir_block(&dir, index)?;
+            for entry in parse_entries(&block)? {
+                if entry.ino == 0 {
+                    continue;
+                }
+                let kind = match (self.filetype, entry.file_type) {
+                    (true, FT_REG_FILE) => FileKind::Regular,
+                    (true, FT_DIR) => FileKind::Directory,
+                    (true, _) => FileKind::Other,
+                    (false, _) => self.read_inode(entry.ino)?.kind(),
+                };
+                entries.push(DirEntry {
+                    ino: entry.ino,
+                    name: String::from_utf8_lossy(entry.name(&block))
+                        .into_owned(),
+                    kind,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Create the empty regular file `name` in the directory `dir`; return
+    /// its inode.
+    pub fn create(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        self.create_inode(dir, name, FileKind::Regular)
+    }
+
+    /// Create the empty directory `name` in the directory `dir`; return its
+    /// inode.
+    pub fn mkdir(&self, dir: u32, name: &str) -> Result<u32, FsError> {
+        self.create_inode(dir, name, FileKind::Directory)
+    }
+
+    /// Read the file `ino` at `offset` into `buf`; return the number of bytes
+    /// read, fewer than `buf.len()` at the end of the file.
+    pub fn read(
+        &self,
+        ino: u32,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, FsError> {
+        if self.read_inode(ino)?.kind() == FileKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        cache::read(self, self.inode_id(ino), offset, buf)
+    }
+
+    /// Write `data` to the file `ino` at `offset`, growing it as needed;
+    /// return the number of bytes written. The data reaches the device on the
+    /// next `sync()`.
+    pub fn write(
+        &self,
+        ino: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FsError> {
+        self.check_writable()?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let max_bsize = if self.large_file {
+            u64::MAX
+        } else {
+            i32::MAX as u64
+        };
+        let end = offset.checked_add(data.len() as u64)
+            .filter(|&end| end <= max_bsize)
+            .ok_or(FsError::TooBig)?;
+
+        let bsize = {
+            let mut alloc = self.alloc.lock();
+            let mut inode = self.read_inode(ino)?;
+            if inode.kind() == FileKind::Directory {
+                return Err(FsError::IsADirectory);
+            }
+
+            let block_size = self.block_size as u64;
+            let mapped = (offset / block_size..=(end - 1) / block_size)
+                .try_for_each(|index| {
+                    self.map_block(&mut alloc, ino, &mut inode, index)
+                        .map(|_| ())
+                });
+            if mapped.is_ok() && end > inode.size() {
+                inode.set_size(end);
+            }
+            // The blocks allocated before a failure are kept.
+            self.write_inode(ino, &inode)?;
+            mapped?;
+            inode.size()
+        };
+
+        let id = self.inode_id(ino);
+        cache::resize(id, bsize);
+        cache::write(self, id, offset, data)
+    }
+
+    fn create_inode(
+        &self,
+        dir_ino: u32,
+        name: &str,
+        kind: FileKind,
+    ) -> Result<u32, FsError> {
+        self.check_writable()?;
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/')
+            || name == "." || name == ".." {
+            return Err(FsError::InvalidName);
+        }
+
+        let mut alloc = self.alloc.lock();
+        let mut dir = self.read_dir_inode(dir_ino)?;
+        if self.find_entry(&dir, name)?.is_some() {
+            return Err(FsError::Exists);
+        }
+
+        let is_dir = kind == FileKind::Directory;
+        let group = self.group_of(dir_ino);
+        let ino = self.alloc_inode(&mut alloc, group, is_dir)?;
+        let mut inode = Inode { raw: vec![0; self.inode_size] };
+
+        let file_type = if is_dir {
+            inode.set_mode(S_IFDIR | 0o755);
+            inode.set_links(2);
+
+            let block = self.map_block(&mut alloc, ino, &mut inode, 0)?;
+            let mut buf = vec![0; self.block_size];
+            self.put_entry(&mut buf, 0, 12, ino, b".", FT_DIR);
+            self.put_entry(&mut buf, 12, self.block_size - 12, dir_ino, b"..",
+                           FT_DIR);
+            self.write_block(block, &buf)?;
+            inode.set_size(self.block_size as u64);
+            dir.set_links(dir.links() + 1);
+            FT_DIR
+        } else {
+            inode.set_mode(S_IFREG | 0o644);
+            inode.set_links(1);
+            FT_REG_FILE
+        };
+
+        self.write_inode(ino, &inode)?;
+        self.add_entry(&mut alloc, dir_ino, &mut dir, name.as_bytes(), ino,
+                       file_type)?;
+        self.write_inode(dir_ino, &dir)?;
+        Ok(ino)
+    }
+
+    /// Add the entry `name` for `ino` to the directory `dir_ino`, in the first
+    /// gap large enough, or else in a new block.
+    fn add_entry(
+        &self,
+        alloc: &mut Allocation,
+        dir_ino: u32,
+        dir: &mut Inode,
+        name: &[u8],
+        ino: u32,
+        file_type: u8,
+    ) -> Result<(), FsError> {
+        let needed = entry_bsize(name.len());
+        let nr_blocks = self.nr_dir_blocks(dir);
+
+        for index in 0..nr_blocks {
+            let (block, mut buf) = self.read_dir_block(dir, index)?;
+            for entry in parse_entries(&buf)? {
+                let used = match entry.ino {
+                    0 => 0,
+                    _ => entry_bsize(entry.name_len),
+                };
+                if entry.rec_len - used < needed {
+                    continue;
+                }
+                if used > 0 {
+                    put_u16(&mut buf, entry.offset + 4, used as u16);
+                }
+                self.put_entry(&mut buf, entry.offset + used,
+                               entry.rec_len - used, ino, name, file_type);
+                return self.write_block(block, &buf);
+            }
+        }
+
+        let block = self.map_block(alloc, dir_ino, dir, nr_blocks)?;
+        let mut buf = vec![0; self.block_size];
+        self.put_entry(&mut buf, 0, self.block_size, ino, name, file_type);
+        self.write_block(block, &buf)?;
+        dir.set_size((nr_blocks + 1) * self.block_size as u64);
+        Ok(())
+    }
+
+    /// The inode of the entry `name` of the directory `dir`, if any.
+    fn find_entry(
+        &self,
+        dir: &Inode,
+        name: &str,
+    ) -> Result<Option<u32>, FsError> {
+        for index in 0..self.nr_dir_blocks(dir) {
+            let (_, block) = self.read_dir_block(dir, index)?;
+            let found = parse_entries(&block)?.iter().find(|entry| {
+                entry.ino != 0 && entry.name(&block) == name.as_bytes()
+            }).map(|entry| entry.ino);
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    fn put_entry(
+        &self,
+        block: &mut [u8],
+        offset: usize,
+        rec_len: usize,
+        ino: u32,
+        name: &[u8],
+        file_type: u8,
+    ) {
+        put_u32(block, offset, ino);
+        put_u16(block, offset + 4, rec_len as u16);
+        block[offset + 6] = name.len() as u8;
+        block[offset + 7] = if self.filetype { file_type } else { 0 };
+        block[offset + DIR_ENTRY_HEADER..][..name.len()].copy_from_slice(name);
+    }
+
+    fn nr_dir_blocks(&self, dir: &Inode) -> u64 {
+        dir.size() / self.block_size as u64
+    }
+
+    /// Read the block `index` of the directory `dir`; return its number along
+    /// with its content.
+    fn read_dir_block(
+        &self,
+        dir: &Inode,
+        index: u64,
+    ) -> Result<(u32, Vec<u8>), FsError> {
+        // Directories have no holes.
+        let block = match self.file_block(dir, index)? {
+            0 => return Err(FsError::Corrupted),
+            block => block,
+        };
+        let mut buf = vec![0; self.block_size];
+        self.read_block(block, &mut buf)?;
+        Ok((block, buf))
+    }
+
+    fn read_dir_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let inode = self.read_inode(ino)?;
+        match inode.kind() {
+            FileKind::Directory => Ok(inode),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let mut raw = vec![0; self.inode_size];
+        block::read_at(&*self.dev, self.inode_offset(ino)?, &mut raw)?;
+        Ok(Inode { raw })
+    }
+
+    fn write_inode(&self, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        block::write_at(&*self.dev, self.inode_offset(ino)?, &inode.raw)
+    }
+
+    /// The byte offset of the inode `ino` on the device.
+    fn inode_offset(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.nr_inodes {
+            return Err(FsError::Corrupted);
+        }
+        let group = &self.groups[self.group_of(ino)];
+        let index = (ino - 1) % self.inodes_per_group;
+
+        Ok(group.inode_table as u64 * self.block_size as u64
+            + index as u64 * self.inode_size as u64)
+    }
+
+    fn group_of(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    fn inode_id(&self, ino: u32) -> InodeId {
+        cache::fs_inodes(self.fs_id).start() | ino as InodeId
+    }
+
+    /// The block `index` of the file `inode`, or 0 if it is a hole.
+    fn file_block(&self, inode: &Inode, index: u64) -> Result<u32, FsError> {
+        let (slot, path) = self.block_path(index)?;
+        let mut block = inode.block(slot);
+
+        for index in path {
+            if block == 0 {
+                break;
+            }
+            block = self.read_pointer(block, index)?;
+        }
+        Ok(block)
+    }
+
+    /// The block `index` of the file `ino`, allocated if it isn't yet, along
+    /// with the indirect blocks leading to it.
+    fn map_block(
+        &self,
+        alloc: &mut Allocation,
+        ino: u32,
+        inode: &mut Inode,
+        index: u64,
+    ) -> Result<u32, FsError> {
+        let group = self.group_of(ino);
+        let (slot, path) = self.block_path(index)?;
+
+        if inode.block(slot) == 0 {
+            let block = self.alloc_block(alloc, group)?;
+            inode.set_block(slot, block);
+            inode.add_blocks(self.block_size);
+        }
+
+        let mut block = inode.block(slot);
+        for index in path {
+            let mut next = self.read_pointer(block, index)?;
+            if next == 0 {
+                next = self.alloc_block(alloc, group)?;
+                self.write_pointer(block, index, next)?;
+                inode.add_blocks(self.block_size);
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// The way to the block `index` of a file: the slot of the inode's block
+    /// pointers, then the index in each indirect block.
+    fn block_path(
+        &self,
+        index: u64,
+    ) -> Result<(usize, ArrayVec<usize, 3>), FsError> {
+        let per_block = (self.block_size / 4) as u64;
+        let mut path = ArrayVec::new();
+
+        if index < NR_DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, path));
+        }
+
+        let mut index = index - NR_DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for depth in 1..=3 {
+            if index < span {
+                for level in (0..depth).rev() {
+                    path.push((index / per_block.pow(level) % per_block)
+                        as usize);
+                }
+                return Ok((NR_DIRECT_BLOCKS - 1 + depth as usize, path));
+            }
+            index -= span;
+            span *= per_block;
+        }
+
+        Err(FsError::TooBig)
+    }
+
+    fn read_pointer(&self, block: u32, index: usize) -> Result<u32, FsError> {
+        let offset = self.block_offset(block)? + 4 * index as u64;
+        let mut pointer = [0; 4];
+        block::read_at(&*self.dev, offset, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn write_pointer(
+        &self,
+        block: u32,
+        index: usize,
+        pointer: u32,
+    ) -> Result<(), FsError> {
+        let offset = self.block_offset(block)? + 4 * index as u64;
+        block::write_at(&*self.dev, offset, &pointer.to_le_bytes())
+    }
+
+    /// Allocate a zeroed block, in the group `preferred` if it has room.
+    fn alloc_block(
+        &self,
+        alloc: &mut Allocation,
+        preferred: usize,
+    ) -> Result<u32, FsError> {
+        let nr_groups = self.groups.len();
+
+        for group in (0..nr_groups).map(|i| (preferred + i) % nr_groups) {
+            if alloc.groups[group].free_blocks == 0 {
+                continue;
+            }
+
+            let start = self.first_data_block
+                + group as u32 * self.blocks_per_group;
+            let nr_bits = min(self.blocks_per_group, self.nr_blocks - start);
+            let bitmap = self.groups[group].block_bitmap;
+            let Some(bit) = self.take_bit(bitmap, 0, nr_bits)? else {
+                continue;
+            };
+
+            alloc.free_blocks = alloc.free_blocks.saturating_sub(1);
+            alloc.groups[group].free_blocks -= 1;
+            self.write_counts(alloc, group)?;
+
+            let block = start + bit;
+            self.write_block(block, &vec![0; self.block_size])?;
+            return Ok(block);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    /// Allocate an inode, in the group `preferred` if it has room.
+    fn alloc_inode(
+        &self,
+        alloc: &mut Allocation,
+        preferred: usize,
+        is_dir: bool,
+    ) -> Result<u32, FsError> {
+        let nr_groups = self.groups.len();
+
+        for group in (0..nr_groups).map(|i| (preferred + i) % nr_groups) {
+            if alloc.groups[group].free_inodes == 0 {
+                continue;
+            }
+
+            // The inodes below `first_ino` are reserved.
+            let base = group as u32 * self.inodes_per_group;
+            let nr_bits = min(self.inodes_per_group,
+                              self.nr_inodes.saturating_sub(base));
+            let first = min(self.first_ino.saturating_sub(base + 1), nr_bits);
+            let bitmap = self.groups[group].inode_bitmap;
+            let Some(bit) = self.take_bit(bitmap, first, nr_bits)? else {
+                continue;
+            };
+
+            alloc.free_inodes = alloc.free_inodes.saturating_sub(1);
+            alloc.groups[group].free_inodes -= 1;
+            if is_dir {
+                alloc.groups[group].used_dirs += 1;
+            }
+            self.write_counts(alloc, group)?;
+            return Ok(base + bit + 1);
+        }
+
+        Err(FsError::NoSpace)
+    }
+
+    /// Set the first clear bit in `first..nr_bits` of the bitmap in `block`;
+    /// return its index, or `None` if they are all set.
+    fn take_bit(
+        &self,
+        block: u32,
+        first: u32,
+        nr_bits: u32,
+    ) -> Result<Option<u32>, FsError> {
+        let mut bitmap = vec![0; self.block_size];
+        self.read_block(block, &mut bitmap)?;
+
+        let is_set = |bit: u32| {
+            bitmap[bit as usize / 8] & (1 << (bit % 8)) != 0
+        };
+        let Some(bit) = (first..nr_bits).find(|&bit| !is_set(bit)) else {
+            return Ok(None);
+        };
+
+        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+        self.write_block(block, &bitmap)?;
+        Ok(Some(bit))
+    }
+
+    /// Write the free counts of the file system and of `group` back to the
+    /// superblock and the group descriptor.
+    fn write_counts(
+        &self,
+        alloc: &Allocation,
+        group: usize,
+    ) -> Result<(), FsError> {
+        let counts = &alloc.groups[group];
+        let mut desc = [0; 6];
+        put_u16(&mut desc, 0, counts.free_blocks);
+        put_u16(&mut desc, 2, counts.free_inodes);
+        put_u16(&mut desc, 4, counts.used_dirs);
+        let gdt_offset = self.block_offset(self.first_data_block + 1)?;
+        block::write_at(&*self.dev,
+                        gdt_offset + (group * GROUP_DESC_BSIZE) as u64 + 12,
+                        &desc)?;
+
+        let mut sb = [0; 8];
+        put_u32(&mut sb, 0, alloc.free_blocks);
+        put_u32(&mut sb, 4, alloc.free_inodes);
+        block::write_at(&*self.dev, SUPERBLOCK_OFFSET + 12, &sb)
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), FsError> {
+        block::read_at(&*self.dev, self.block_offset(block)?, buf)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), FsError> {
+        block::write_at(&*self.dev, self.block_offset(block)?, buf)
+    }
+
+    /// The byte offset of `block` on the device.
+    fn block_offset(&self, block: u32) -> Result<u64, FsError> {
+        match block {
+            0 => Err(FsError::Corrupted),
+            block if block >= self.nr_blocks => Err(FsError::Corrupted),
+            block => Ok(block as u64 * self.block_size as u64),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        match self.read_only {
+            true => Err(FsError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+}
+
+impl PageSource for Ext2 {
+    fn read_page(
+        &self,
+        inode: InodeId,
+        index: u64,
+        page: &mut [u8],
+    ) -> Result<usize, FsError> {
+        let inode = self.read_inode(inode as u32)?;
+        let start = index * PAGE_SIZE as u64;
+        let len = inode.size().saturating_sub(start).min(PAGE_SIZE as u64)
+            as usize;
+        let first = start / self.block_size as u64;
+
+        let chunks = page.chunks_exact_mut(self.block_size)
+            .take(len.div_ceil(self.block_size));
+        for (i, chunk) in chunks.enumerate() {
+            match self.file_block(&inode, first + i as u64)? {
+                0 => chunk.fill(0),
+                block => self.read_block(block, chunk)?,
+            }
+        }
+        page[len..].fill(0);
+
+        Ok(len)
+    }
+
+    fn write_page(
+        &self,
+        inode: InodeId,
+        index: u64,
+        page: &[u8],
+        len: usize,
+    ) -> Result<(), FsError> {
+        let inode = self.read_inode(inode as u32)?;
+        let first = index * (PAGE_SIZE / self.block_size) as u64;
+
+        let chunks = page.chunks_exact(self.block_size)
+            .take(len.div_ceil(self.block_size));
+        for (i, chunk) in chunks.enumerate() {
+            match self.file_block(&inode, first + i as u64)? {
+                // Never written to, so still zeros: left as a hole.
+                0 => {},
+                block => self.write_block(block, chunk)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An inode, as laid out on the device.
+struct Inode {
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    fn kind(&self) -> FileKind {
+        match u16_at(&self.raw, 0) & S_IFMT {
+            S_IFREG => FileKind::Regular,
+            S_IFDIR => FileKind::Directory,
+            _ => FileKind::Other,
+        }
+    }
+
+    fn set_mode(&mut self, mode: u16) {
+        put_u16(&mut self.raw, 0, mode);
+    }
+
+    /// The size of the file; that of a regular file has its upper half where
+    /// older revisions had `i_dir_acl`.
+    fn size(&self) -> u64 {
+        let high = match self.kind() {
+            FileKind::Regular => u32_at(&self.raw, 108),
+            _ => 0,
+        };
+        ((high as u64) << 32) | u32_at(&self.raw, 4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        put_u32(&mut self.raw, 4, size as u32);
+        if self.kind() == FileKind::Regular {
+            put_u32(&mut self.raw, 108, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        u16_at(&self.raw, 26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        put_u16(&mut self.raw, 26, links);
+    }
+
+    /// Count `bsize` more bytes of blocks, in `i_blocks`' 512-byte units.
+    fn add_blocks(&mut self, bsize: usize) {
+        let sectors = u32_at(&self.raw, 28) + (bsize / 512) as u32;
+        put_u32(&mut self.raw, 28, sectors);
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        u32_at(&self.raw, 40 + 4 * slot)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        put_u32(&mut self.raw, 40 + 4 * slot, block);
+    }
+}
+
+/// A directory entry, as laid out in a directory block.
+struct RawEntry {
+    offset: usize,
+    /// The inode of the entry, or 0 if the entry is unused.
+    ino: u32,
+    rec_len: usize,
+    name_len: usize,
+    file_type: u8,
+}
+
+impl RawEntry {
+    fn name<'a>(&self, block: &'a [u8]) -> &'a [u8] {
+        &block[(self.offset + DIR_ENTRY_HEADER)..][..self.name_len]
+    }
+}
+
+/// Parse the entries of a directory block, which span all of it.
+fn parse_entries(block: &[u8]) -> Result<Vec<RawEntry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset < block.len() {
+        if block.len() - offset < DIR_ENTRY_HEADER {
+            return Err(FsError::Corrupted);
+        }
+
+        let rec_len = u16_at(block, offset + 4) as usize;
+        let name_len = block[offset + 6] as usize;
+        if rec_len < DIR_ENTRY_HEADER || rec_len % 4 != 0
+            || rec_len > block.len() - offset
+            || DIR_ENTRY_HEADER + name_len > rec_len {
+            return Err(FsError::Corrupted);
+        }
+
+        entries.push(RawEntry {
+            offset,
+            ino: u32_at(block, offset),
+            rec_len,
+            name_len,
+            file_type: block[offset + 7],
+        });
+        offset += rec_len;
+    }
+
+    Ok(entries)
+}
+
+/// The room taken by a directory entry with a name of `name_len` bytes.
+fn entry_bsize(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..][..2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..][..4].try_into().unwrap())
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..][..2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..][..4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::mem::{reset_memory, MEMORY_MUTEX};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use crate::fs::block::RamDisk;
+    use super::*;
+
+    const NR_BLOCKS: u32 = 512;
+    const NR_INODES: u32 = 32;
+    const ROOT_DIR_BLOCK: usize = 9;
+
+    /// A freshly formatted file system of 1 KiB blocks in a single group: the
+    /// superblock is in block 1, the group descriptor in 2, the bitmaps in 3
+    /// and 4, the inode table in 5 to 8 and the root directory in 9.
+    fn mkfs() -> Arc<RamDisk> {
+        let mut image = vec![0; NR_BLOCKS as usize * 1024];
+
+        let sb = &mut image[1024..2048];
+        put_u32(sb, 0, NR_INODES);
+        put_u32(sb, 4, NR_BLOCKS);
+        put_u32(sb, 12, NR_BLOCKS - 10);
+        put_u32(sb, 16, NR_INODES - 11);
+        put_u32(sb, 20, 1);
+        put_u32(sb, 32, 8192);
+        put_u32(sb, 40, NR_INODES);
+        put_u16(sb, 56, MAGIC);
+        put_u32(sb, 76, 1);
+        put_u32(sb, 84, GOOD_OLD_FIRST_INO);
+        put_u16(sb, 88, 128);
+        put_u32(sb, 96, INCOMPAT_FILETYPE);
+
+        let desc = &mut image[2048..][..GROUP_DESC_BSIZE];
+        put_u32(desc, 0, 3);
+        put_u32(desc, 4, 4);
+        put_u32(desc, 8, 5);
+        put_u16(desc, 12, NR_BLOCKS as u16 - 10);
+        put_u16(desc, 14, NR_INODES as u16 - 11);
+        put_u16(desc, 16, 1);
+
+        // The blocks 1 to 9 and the inodes 1 to 11 are in use.
+        image[3 * 1024..][..2].copy_from_slice(&[0xff, 0x01]);
+        image[4 * 1024..][..2].copy_from_slice(&[0xff, 0x07]);
+
+        let root = &mut image[5 * 1024 + 128..][..128];
+        put_u16(root, 0, S_IFDIR | 0o755);
+        put_u32(root, 4, 1024);
+        put_u16(root, 26, 2);
+        put_u32(root, 28, 2);
+        put_u32(root, 40, ROOT_DIR_BLOCK as u32);
+
+        let dir = &mut image[ROOT_DIR_BLOCK * 1024..][..1024];
+        put_u32(dir, 0, ROOT_INO);
+        put_u16(dir, 4, 12);
+        dir[6..9].copy_from_slice(&[1, FT_DIR, b'.']);
+        put_u32(dir, 12, ROOT_INO);
+        put_u16(dir, 16, 1012);
+        dir[18..22].copy_from_slice(&[2, FT_DIR, b'.', b'.']);
+
+        Arc::new(RamDisk::new(image, 512))
+    }
+
+    #[test]
+    fn it_creates_and_writes_files() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+        let disk = mkfs();
+
+        let fs = Ext2::mount(disk.clone()).unwrap();
+        let dir = fs.mkdir(ROOT_INO, "etc").unwrap();
+        let file = fs.create(dir, "motd").unwrap();
+        assert!(matches!(fs.create(dir, "motd"), Err(FsError::Exists)));
+        assert!(matches!(fs.create(file, "x"), Err(FsError::NotADirectory)));
+        assert_eq!(fs.write(file, 0, b"hello, world").unwrap(), 12);
+        assert_eq!(fs.lookup("/etc/motd").unwrap(), file);
+
+        let mut buf = [0; 32];
+        assert_eq!(fs.read(file, 7, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        fs.unmount().unwrap();
+
+        // Everything reached the disk.
+        let fs = Ext2::mount(disk).unwrap();
+        let file = fs.lookup("etc/motd").unwrap();
+        assert_eq!(fs.read(file, 0, &mut buf).unwrap(), 12);
+        assert_eq!(&buf[..12], b"hello, world");
+
+        let root = fs.read_dir(ROOT_INO).unwrap();
+        let names: Vec<_> = root.iter().map(|entry| &entry.name).collect();
+        assert_eq!(names, [".", "..", "etc"]);
+        assert_eq!(root[2].kind, FileKind::Directory);
+        assert_eq!(fs.metadata(ROOT_INO).unwrap().nr_links, 3);
+        assert_eq!(fs.alloc.lock().free_inodes, NR_INODES - 13);
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn it_maps_files_through_indirect_blocks() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+        let disk = mkfs();
+        let data: Vec<u8> = (0..20 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let fs = Ext2::mount(disk.clone()).unwrap();
+        let file = fs.create(ROOT_INO, "data").unwrap();
+        assert_eq!(fs.write(file, 0, &data).unwrap(), data.len());
+        // Block 292, past the single indirect blocks.
+        assert_eq!(fs.write(file, 300_000, b"tail").unwrap(), 4);
+        fs.unmount().unwrap();
+
+        let fs = Ext2::mount(disk).unwrap();
+        assert_eq!(fs.metadata(file).unwrap().bsize, 300_004);
+        // 20 data blocks and an indirect one, then a data block and two
+        // indirect ones.
+        assert_eq!(fs.alloc.lock().free_blocks, NR_BLOCKS - 10 - 24);
+
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.read(file, 0, &mut buf).unwrap(), data.len());
+        assert!(buf == data);
+
+        let mut buf = [0xff; 8];
+        assert_eq!(fs.read(file, 299_996, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0\0tail");
+        fs.unmount().unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Open files, which file descriptors refer to. An open file has its own
+//! offset and access mode, and is shared by the descriptors duplicated from
+//! it, including those inherited by forked children. Device files go to their
+//! device; the files of the initfs and of the procfs are read from their
+//! content at the time they were opened. Pipes have no offset.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::fs::devfs::{self, Device};
+use crate::fs::pipe::{self, PipeEnd};
+use crate::fs::{self, procfs, FsError};
+use crate::sync::Spinlock;
+
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+/// The bits of the `open()` flags holding the access mode; the other flags
+/// are accepted but ignored.
+pub const O_ACCMODE: usize = 3;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+enum Content {
+    Device(Arc<dyn Device>),
+    Static(&'static [u8]),
+    Generated(Vec<u8>),
+    Pipe(PipeEnd),
+}
+
+pub struct OpenFile {
+    content: Content,
+    readable: bool,
+    writable: bool,
+    offset: Spinlock<u64>,
+}
+
+/// Open the file at the absolute `path` with the access mode of `flags`; only
+/// device files can be opened for writing.
+pub fn open(path: &str, flags: usize) -> Result<Arc<OpenFile>, FsError> {
+    let (readable, writable) = match flags & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(FsError::InvalidArgument),
+    };
+
+    let content = if path.starts_with(devfs::MOUNT_POINT) {
+        Content::Device(devfs::open(path)?)
+    } else if path.starts_with(procfs::MOUNT_POINT) {
+        Content::Generated(procfs::read(path)?.into_bytes())
+    } else {
+        Content::Static(fs::read(path)?)
+    };
+    if writable && !matches!(content, Content::Device(_)) {
+        return Err(FsError::ReadOnly);
+    }
+
+    Ok(Arc::new(OpenFile {
+        content,
+        readable,
+        writable,
+        offset: Spinlock::new(0),
+    }))
+}
+
+/// Create a pipe; return the open files of its read end and its write end.
+pub fn pipe() -> (Arc<OpenFile>, Arc<OpenFile>) {
+    let (reader, writer) = pipe::new();
+    let open = |end: PipeEnd| Arc::new(OpenFile {
+        readable: !end.is_writer(),
+        writable: end.is_writer(),
+        content: Content::Pipe(end),
+        offset: Spinlock::new(0),
+    });
+    (open(reader), open(writer))
+}
+
+impl OpenFile {
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Read into `buf` from the current offset, and advance it past the bytes
+    /// read; return their number, 0 at the end of the file.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        // Not with the offset locked: reading a pipe may wait.
+        if let Content::Pipe(end) = &self.content {
+            return end.read(buf);
+        }
+
+        let mut offset = self.offset.lock();
+        let len = match &self.content {
+            Content::Device(device) => device.read(*offset, buf)?,
+            Content::Static(data) => copy_at(data, *offset, buf),
+            Content::Generated(data) => copy_at(data, *offset, buf),
+            Content::Pipe(_) => unreachable!(),
+        };
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Write `data` at the current offset, and advance it past the bytes
+    /// written; return their number.
+    pub fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        if let Content::Pipe(end) = &self.content {
+            return end.write(data);
+        }
+
+        let mut offset = self.offset.lock();
+        let len = match &self.content {
+            Content::Device(device) => device.write(*offset, data)?,
+            _ => return Err(FsError::ReadOnly),
+        };
+        *offset += len as u64;
+        Ok(len)
+    }
+
+    /// Move the offset to `offset` bytes from the start, the current offset or
+    /// the end of the file, as `whence` says; return the new offset. Devices
+    /// have no end to seek from, and pipes can't seek.
+    pub fn seek(&self, offset: i64, whence: usize) -> Result<u64, FsError> {
+        let mut current = self.offset.lock();
+        let base = match (whence, &self.content) {
+            (_, Content::Pipe(_)) => return Err(FsError::NotSeekable),
+            (SEEK_SET, _) => 0,
+            (SEEK_CUR, _) => *current,
+            (SEEK_END, Content::Static(data)) => data.len() as u64,
+            (SEEK_END, Content::Generated(data)) => data.len() as u64,
+            _ => return Err(FsError::InvalidArgument),
+        };
+
+        *current = base.checked_add_signed(offset)
+            .ok_or(FsError::InvalidArgument)?;
+        Ok(*current)
+    }
+}
+
+/// Copy the bytes of `data` from `offset` into `buf`; return their number.
+fn copy_at(data: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let start = min(offset, data.len() as u64) as usize;
+    let len = min(data.len() - start, buf.len());
+    buf[..len].copy_from_slice(&data[start..][..len]);
+    len
+}
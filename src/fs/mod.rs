@@ -0,0 +1,120 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel's virtual file system. There is no storage driver yet, so the
+//! main backing store is the _initfs_: a read-only set of files embedded in the
+//! kernel image at build time, mostly made of the `media/` directory. The file
+//! systems of block devices, such as ext2, read and write their files through
+//! the page cache. The devices are files of the devfs, under `/dev`, and the
+//! kernel's state is exposed as generated files under `/proc`.
+
+use thiserror_no_std::Error;
+
+pub mod block;
+pub mod cache;
+pub mod devfs;
+pub mod ext2;
+pub mod file;
+pub mod pipe;
+pub mod procfs;
+
+#[derive(Error, Debug)]
+pub enum FsError {
+    #[error("no such file or directory")]
+    NotFound,
+
+    #[error("input/output error")]
+    Io,
+
+    #[error("out of memory")]
+    NoMemory,
+
+    #[error("file exists")]
+    Exists,
+
+    #[error("not a directory")]
+    NotADirectory,
+
+    #[error("is a directory")]
+    IsADirectory,
+
+    #[error("invalid file name")]
+    InvalidName,
+
+    #[error("file too large")]
+    TooBig,
+
+    #[error("no space left on device")]
+    NoSpace,
+
+    #[error("read-only file system")]
+    ReadOnly,
+
+    #[error("corrupted file system")]
+    Corrupted,
+
+    #[error("unsupported file system features")]
+    Unsupported,
+
+    #[error("operation not supported")]
+    NotSupported,
+
+    #[error("inappropriate ioctl for device")]
+    BadIoctl,
+
+    #[error("invalid argument")]
+    InvalidArgument,
+
+    #[error("resource temporarily unavailable")]
+    WouldBlock,
+
+    #[error("illegal seek")]
+    NotSeekable,
+
+    #[error("broken pipe")]
+    BrokenPipe,
+}
+
+struct InitFsFile {
+    path: &'static str,
+    data: &'static [u8],
+}
+
+macro_rules! initfs_file {
+    ($path:literal, $media:literal) => {
+        InitFsFile {
+            path: $path,
+            data: include_bytes!(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/media/", $media)
+            ),
+        }
+    };
+}
+
+static INITFS: &[InitFsFile] = &[
+    #[cfg(feature = "fb-terminal")]
+    initfs_file!("/fonts/iosevka.pxfont", "iosevka.pxfont"),
+    initfs_file!("/keymaps/us.keymap", "us.keymap"),
+    initfs_file!("/keymaps/fr.keymap", "fr.keymap"),
+    initfs_file!("/etc/kshellrc", "kshellrc"),
+];
+
+/// Return the whole content of the file at the absolute `path`.
+pub fn read(path: &str) -> Result<&'static [u8], FsError> {
+    INITFS.iter()
+        .find(|file| file.path == path)
+        .map(|file| file.data)
+        .ok_or(FsError::NotFound)
+}
+
+/// Iterate over the absolute paths of all files present in the file system.
+pub fn files() -> impl Iterator<Item = &'static str> {
+    INITFS.iter().map(|file| file.path)
+}
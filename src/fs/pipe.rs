@@ -0,0 +1,196 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Anonymous pipes: a ring buffer written at one end and read from at the
+//! other, to pass data between tasks. Reads wait for data, and return the end
+//! of file once every write end is closed; writes wait for room, and fail with
+//! `BrokenPipe` once every read end is closed.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cmp::min;
+
+use crate::fs::FsError;
+use crate::sync::{Spinlock, WaitQueue};
+
+/// The capacity of the ring buffer of a pipe, in bytes.
+pub const PIPE_BSIZE: usize = 4096;
+
+struct Ring {
+    data: Box<[u8; PIPE_BSIZE]>,
+    /// The index of the oldest byte in `data`.
+    start: usize,
+    len: usize,
+    /// The number of read ends still open.
+    readers: usize,
+    /// The number of write ends still open.
+    writers: usize,
+}
+
+impl Ring {
+    /// Append as much of `data` as fits; return the number of bytes appended.
+    fn push(&mut self, data: &[u8]) -> usize {
+        let len = min(data.len(), PIPE_BSIZE - self.len);
+        for (i, &byte) in data[..len].iter().enumerate() {
+            self.data[(self.start + self.len + i) % PIPE_BSIZE] = byte;
+        }
+        self.len += len;
+        len
+    }
+
+    /// Remove the oldest bytes into `buf`; return their number.
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let len = min(buf.len(), self.len);
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[(self.start + i) % PIPE_BSIZE];
+        }
+        self.start = (self.start + len) % PIPE_BSIZE;
+        self.len -= len;
+        len
+    }
+}
+
+struct Pipe {
+    ring: Spinlock<Ring>,
+    /// Notified when data is written or the last write end is closed.
+    readable: WaitQueue,
+    /// Notified when data is read or the last read end is closed.
+    writable: WaitQueue,
+}
+
+/// One end of a pipe, either the read end or the write end; the end is
+/// closed when it is dropped.
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    is_writer: bool,
+}
+
+/// Create a pipe; return its read end and its write end.
+pub fn new() -> (PipeEnd, PipeEnd) {
+    let pipe = Arc::new(Pipe {
+        ring: Spinlock::new(Ring {
+            data: Box::new([0; PIPE_BSIZE]),
+            start: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+
+    let reader = PipeEnd { pipe: pipe.clone(), is_writer: false };
+    let writer = PipeEnd { pipe, is_writer: true };
+    (reader, writer)
+}
+
+impl PipeEnd {
+    pub fn is_writer(&self) -> bool {
+        self.is_writer
+    }
+
+    /// Read into `buf` from the read end, waiting for data if the pipe is
+    /// empty; return the number of bytes read, 0 once the pipe is empty and
+    /// every write end is closed.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.is_writer {
+            return Err(FsError::NotSupported);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = self.pipe.readable.wait_until(|| {
+            let mut ring = self.pipe.ring.lock();
+            match ring.pop(buf) {
+                0 if ring.writers > 0 => None,
+                len => Some(len),
+            }
+        });
+        self.pipe.writable.notify_all();
+        Ok(len)
+    }
+
+    /// Write all of `data` to the write end, waiting for room whenever the
+    /// pipe is full; return the number of bytes written, which is less than
+    /// `data.len()` only if every read end was closed meanwhile.
+    pub fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        if !self.is_writer {
+            return Err(FsError::NotSupported);
+        }
+
+        let mut written = 0;
+        while written < data.len() {
+            let pushed = self.pipe.writable.wait_until(|| {
+                let mut ring = self.pipe.ring.lock();
+                if ring.readers == 0 {
+                    return Some(None);
+                }
+                match ring.push(&data[written..]) {
+                    0 => None,
+                    len => Some(Some(len)),
+                }
+            });
+
+            match pushed {
+                Some(len) => written += len,
+                None if written == 0 => return Err(FsError::BrokenPipe),
+                None => break,
+            }
+            self.pipe.readable.notify_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut ring = self.pipe.ring.lock();
+        if self.is_writer {
+            ring.writers -= 1;
+        } else {
+            ring.readers -= 1;
+        }
+        drop(ring);
+
+        self.pipe.readable.notify_all();
+        self.pipe.writable.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn it_passes_data_through_the_ring_buffer() {
+        let (reader, writer) = new();
+        let data: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+
+        assert_eq!(writer.write(&data[..3000]).unwrap(), 3000);
+        let mut buf = vec![0; 5000];
+        assert_eq!(reader.read(&mut buf[..2000]).unwrap(), 2000);
+        assert_eq!(writer.write(&data[3000..]).unwrap(), 3000);
+        assert_eq!(reader.read(&mut buf).unwrap(), 4000);
+        assert_eq!(&buf[..4000], &data[2000..]);
+        assert!(matches!(reader.write(b"x"), Err(FsError::NotSupported)));
+
+        writer.write(b"end").unwrap();
+        drop(writer);
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let (reader, writer) = new();
+        drop(reader);
+        assert!(matches!(writer.write(b"x"), Err(FsError::BrokenPipe)));
+    }
+}
@@ -0,0 +1,200 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The process file system, mounted on `/proc`: read-only files whose content
+//! is generated from the kernel's state each time they are read. The generic
+//! files are registered by `init()`; the architecture registers `cpuinfo` and
+//! `interrupts`. Besides, `/proc/PID/maps` lists the regions of the address
+//! space of the process `PID`, and `/proc/self/maps` those of the current one.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::fs::{cache, FsError};
+use crate::logging::with_log_ring;
+use crate::mem;
+use crate::misc::BinSize;
+use crate::sync::Spinlock;
+use crate::task::process;
+use crate::task::vm::{VirtualMemory, VmBacking};
+use crate::{initcall, warning};
+
+/// Where the process file system is mounted.
+pub const MOUNT_POINT: &str = "/proc";
+
+/// Write the content of a file.
+pub type Generator = fn(&mut String) -> fmt::Result;
+
+static FILES: Spinlock<BTreeMap<&'static str, Generator>>
+    = Spinlock::new(BTreeMap::new());
+
+/// Register the generic files: `meminfo`, `tasks` and `dmesg`.
+pub fn init() {
+    let files: [(&str, Generator); 3] = [
+        ("meminfo", write_meminfo),
+        ("tasks", write_tasks),
+        ("dmesg", write_dmesg),
+    ];
+
+    for (name, generate) in files {
+        if let Err(e) = register(name, generate) {
+            warning!("procfs: {name}: {e}");
+        }
+    }
+}
+
+initcall!(late, init, "Creating the kernel files...");
+
+/// Register the file `/proc/{name}`, whose content `generate` writes.
+pub fn register(
+    name: &'static str,
+    generate: Generator,
+) -> Result<(), FsError> {
+    if name.is_empty() || name.contains('/') || name == "self"
+        || name.parse::<u32>().is_ok() {
+        return Err(FsError::InvalidName);
+    }
+
+    let mut files = FILES.lock();
+    if files.contains_key(name) {
+        return Err(FsError::Exists);
+    }
+    files.insert(name, generate);
+    Ok(())
+}
+
+/// Generate the content of the file at the absolute `path`, e.g.
+/// `/proc/meminfo`.
+pub fn read(path: &str) -> Result<String, FsError> {
+    let name = path.strip_prefix(MOUNT_POINT)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .ok_or(FsError::NotFound)?;
+    let mut out = String::new();
+
+    if let Some(process) = name.strip_suffix("/maps") {
+        let pid = match process {
+            "self" => process::current().ok_or(FsError::NotFound)?,
+            pid => pid.parse().map_err(|_| FsError::NotFound)?,
+        };
+        process::with_vm(pid, |vm| Ok(write_maps(&mut out, vm)))
+            .map_err(|_| FsError::NotFound)?
+            .map_err(|_| FsError::Io)?;
+        return Ok(out);
+    }
+
+    // Not called with the lock held: generators may read other files.
+    let generate = *FILES.lock().get(name).ok_or(FsError::NotFound)?;
+    generate(&mut out).map_err(|_| FsError::Io)?;
+    Ok(out)
+}
+
+/// The absolute paths of the files, besides those of each process.
+pub fn files() -> Vec<String> {
+    FILES.lock().keys()
+        .map(|name| format!("{MOUNT_POINT}/{name}"))
+        .chain([format!("{MOUNT_POINT}/self/maps")])
+        .collect()
+}
+
+fn write_meminfo(out: &mut String) -> fmt::Result {
+    if let Some(stats) = mem::stats() {
+        let rows = [
+            ("total", stats.total),
+            ("free", stats.free),
+            ("zeroed", stats.zeroed),
+            ("allocated", stats.allocated),
+            ("reserved", stats.reserved()),
+            ("unusable", stats.unusable),
+        ];
+        for (name, nr_frames) in rows {
+            writeln!(out, "{name:<10} {nr_frames:>10} frames  {}",
+                     BinSize(mem::frames_bsize(nr_frames)))?;
+        }
+    }
+
+    let cache = cache::stats();
+    writeln!(out, "{:<10} {:>10} pages", "cached", cache.nr_pages)?;
+    writeln!(out, "{:<10} {:>10} pages", "dirty", cache.nr_dirty)
+}
+
+fn write_tasks(out: &mut String) -> fmt::Result {
+    writeln!(out, "  TID   PID  PPID STATE      PRI         CYCLES NAME")?;
+    for task in process::stats() {
+        writeln!(out, "{:>5} {:>5} {:>5} {:<10} {:>3} {:>14} {}",
+                 task.tid, task.pid, task.parent_pid, task.state.as_str(),
+                 task.priority, task.runtime, task.name)?;
+    }
+    Ok(())
+}
+
+fn write_dmesg(out: &mut String) -> fmt::Result {
+    let (text, wrapped) = with_log_ring(|ring| {
+        let (first, second) = ring.as_slices();
+        ([first, second].concat(), ring.is_wrapped())
+    });
+    let text = String::from_utf8_lossy(&text);
+
+    // The first line may have been partly overwritten.
+    let skip = if wrapped { 1 } else { 0 };
+    for line in text.lines().skip(skip) {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+fn write_maps(out: &mut String, vm: &VirtualMemory) -> fmt::Result {
+    for region in vm.regions() {
+        let perms = region.permissions;
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        let range = region.range();
+
+        write!(out, "{:016x}-{:016x} {}{}{}{} ",
+               range.start.0, range.end.0,
+               flag(perms.readable, 'r'), flag(perms.writable, 'w'),
+               flag(perms.executable, 'x'), flag(region.copy_on_write, 'c'))?;
+        match region.backing {
+            VmBacking::Anonymous => writeln!(out, "anonymous")?,
+            VmBacking::File { offset, .. } => {
+                writeln!(out, "file @ {offset:#x}")?
+            },
+            VmBacking::Device { paddr } => {
+                writeln!(out, "device @ {:#x}", paddr.0)?
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_test(out: &mut String) -> fmt::Result {
+        write!(out, "hello")
+    }
+
+    #[test]
+    fn it_generates_registered_files() {
+        register("test-hello", write_test).unwrap();
+        assert!(matches!(register("test-hello", write_test),
+                         Err(FsError::Exists)));
+        assert!(matches!(register("42", write_test),
+                         Err(FsError::InvalidName)));
+
+        assert_eq!(read("/proc/test-hello").unwrap(), "hello");
+        assert!(files().iter().any(|path| path == "/proc/test-hello"));
+        assert!(matches!(read("/proc/nope"), Err(FsError::NotFound)));
+        assert!(matches!(read("/proc/4242/maps"), Err(FsError::NotFound)));
+        assert!(matches!(read("test-hello"), Err(FsError::NotFound)));
+    }
+}
@@ -0,0 +1,154 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Initcalls: the drivers and subsystems declare their own initialization
+//! with the `initcall!()` macro, which places it into the `.initcalls`
+//! sections, rather than being called one by one by the architecture's boot
+//! process. The boot process runs each level in turn, at the point where what
+//! the level depends on is ready; a driver left out of the build is then left
+//! out of the boot process as well.
+//!
+//! The linker sorts the sections by level; within a level, the order is
+//! unspecified, so initcalls of the same level must not depend on each other.
+
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::slice;
+
+use crate::time;
+#[cfg(feature = "fb-terminal")]
+use crate::ui::splash;
+
+/// When an initcall runs during the boot process, in this order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    /// Once the memory management is set up, with interrupts still disabled.
+    Early,
+    /// Once interrupts are enabled and the screen is set up: subsystems and
+    /// buses, for the devices to be found on.
+    Core,
+    /// Device drivers, once the buses are enumerated.
+    Device,
+    /// Once the kernel terminal is created, right before the kernel's main
+    /// function.
+    Late,
+}
+
+/// An initialization function, as declared by `initcall!()`.
+pub struct Initcall {
+    pub name: &'static str,
+    pub level: InitLevel,
+    /// What the splash screen shows while the initcall runs.
+    pub status: &'static str,
+    pub init: fn(),
+}
+
+extern "C" {
+    static __kernel_initcalls: u8;
+    static __kernel_initcalls_end: u8;
+}
+
+/// All the initcalls declared with `initcall!()`, sorted by level.
+pub fn initcalls() -> &'static [Initcall] {
+    let start = unsafe { addr_of!(__kernel_initcalls) };
+    let end = unsafe { addr_of!(__kernel_initcalls_end) };
+    let len = (end as usize - start as usize) / size_of::<Initcall>();
+
+    unsafe { slice::from_raw_parts(start as *const Initcall, len) }
+}
+
+/// Run the initcalls of `level`, each in its own timing scope, reporting its
+/// status onto the splash screen if shown.
+pub fn run(level: InitLevel) {
+    for initcall in of_level(initcalls(), level) {
+        time::scope!(initcall.name);
+        #[cfg(feature = "fb-terminal")]
+        splash::step(initcall.status);
+        (initcall.init)();
+    }
+}
+
+/// The number of initcalls of `level`, e.g. to count the boot steps.
+pub fn count(level: InitLevel) -> usize {
+    of_level(initcalls(), level).count()
+}
+
+fn of_level(
+    initcalls: &[Initcall],
+    level: InitLevel,
+) -> impl Iterator<Item = &Initcall> {
+    initcalls.iter().filter(move |initcall| initcall.level == level)
+}
+
+/// Declare the function `$init`, with no argument, as an initcall of the level
+/// `early`, `core`, `device` or `late`; `$status` is shown on the splash
+/// screen while it runs:
+///
+/// ```ignore
+/// initcall!(device, init, "Probing PS/2 devices...");
+/// ```
+#[macro_export]
+macro_rules! initcall {
+    (early, $init:path, $status:literal) => {
+        $crate::initcall!(@section ".initcalls.0", Early, $init, $status);
+    };
+    (core, $init:path, $status:literal) => {
+        $crate::initcall!(@section ".initcalls.1", Core, $init, $status);
+    };
+    (device, $init:path, $status:literal) => {
+        $crate::initcall!(@section ".initcalls.2", Device, $init, $status);
+    };
+    (late, $init:path, $status:literal) => {
+        $crate::initcall!(@section ".initcalls.3", Late, $init, $status);
+    };
+    (@section $section:literal, $level:ident, $init:path, $status:literal) => {
+        const _: () = {
+            #[used]
+            #[link_section = $section]
+            static INITCALL: $crate::initcall::Initcall
+                = $crate::initcall::Initcall {
+                    name: concat!(module_path!(), "::", stringify!($init)),
+                    level: $crate::initcall::InitLevel::$level,
+                    status: $status,
+                    init: $init,
+                };
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop() {}
+
+    #[test]
+    fn it_selects_the_initcalls_of_a_level() {
+        let initcall = |name, level| Initcall {
+            name,
+            level,
+            status: "",
+            init: nop,
+        };
+        let initcalls = [
+            initcall("cache", InitLevel::Early),
+            initcall("pci", InitLevel::Core),
+            initcall("usb", InitLevel::Device),
+            initcall("net", InitLevel::Device),
+        ];
+
+        let names: Vec<_> = of_level(&initcalls, InitLevel::Device)
+            .map(|initcall| initcall.name)
+            .collect();
+        assert_eq!(names, ["usb", "net"]);
+        assert_eq!(of_level(&initcalls, InitLevel::Late).count(), 0);
+        assert!(InitLevel::Core < InitLevel::Device);
+    }
+}
@@ -0,0 +1,233 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel's symbol table, naming the functions of backtraces and profiles.
+//! The bootloader loads the `.symtab` and `.strtab` sections of the kernel
+//! image along with it, see `boot::BootSymbols`; the functions are read from
+//! them once during boot, with their names demangled, into a table sorted by
+//! address that is never changed afterwards: lookups neither lock nor
+//! allocate, and work from the panic handler.
+//!
+//! Without a symbol table, e.g. with a stripped kernel or on aarch64 where the
+//! bootloader gives none, nothing is named.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::addr_of;
+
+use crate::mem::VAddr;
+use crate::{boot, info, initcall, warning};
+
+const SYM_ENTRY_LEN: usize = 24;
+const STT_FUNC: u8 = 2;
+
+/// The hash suffix of legacy mangled names, e.g. `h0123456789abcdef`.
+const HASH_LEN: usize = 17;
+
+/// The escapes of legacy mangled names.
+const ESCAPES: [(&str, &str); 13] = [
+    ("$SP$", "@"), ("$BP$", "*"), ("$RF$", "&"), ("$LT$", "<"),
+    ("$GT$", ">"), ("$LP$", "("), ("$RP$", ")"), ("$C$", ","),
+    ("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"),
+    ("$u7e$", "~"),
+];
+
+static mut SYMBOLS: &[Symbol] = &[];
+
+/// A function of the kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub addr: VAddr,
+    pub bsize: usize,
+    pub name: &'static str,
+}
+
+/// Read the functions of the symbol table given by the bootloader.
+fn init() {
+    let Some(symbols) = &boot::info().symbols else {
+        info!("ksyms: no symbol table, functions will not be named");
+        return;
+    };
+    let Some((symtab, strtab)) = symbols.data() else {
+        warning!("ksyms: the symbol table lies outside low memory");
+        return;
+    };
+
+    let symbols = parse(symtab, strtab);
+    info!("ksyms: {} functions", symbols.len());
+
+    // Only the bootstrap CPU runs, and no lookup can happen concurrently.
+    unsafe { SYMBOLS = symbols.leak() };
+}
+
+initcall!(early, init, "Loading the kernel symbols...");
+
+/// The function containing `addr`, with the offset of `addr` within it.
+pub fn lookup(addr: VAddr) -> Option<(&'static str, usize)> {
+    let symbol = find(unsafe { *addr_of!(SYMBOLS) }, addr)?;
+    Some((symbol.name, addr.0 - symbol.addr.0))
+}
+
+fn find(symbols: &[Symbol], addr: VAddr) -> Option<&Symbol> {
+    let index = symbols.partition_point(|symbol| symbol.addr <= addr);
+    let symbol = &symbols[index.checked_sub(1)?];
+
+    (addr.0 < symbol.addr.0 + symbol.bsize.max(1)).then_some(symbol)
+}
+
+/// Read the functions of `symtab`, an array of ELF64 symbol entries whose
+/// names are in `strtab`; they are returned sorted by address.
+fn parse(symtab: &[u8], strtab: &[u8]) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = symtab.chunks_exact(SYM_ENTRY_LEN)
+        .filter_map(|entry| {
+            let name = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+            let info = entry[4];
+            let value = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let size = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+            if info & 0xf != STT_FUNC || value == 0 {
+                return None;
+            }
+
+            let name = strtab.get(name as usize..)?;
+            let name = &name[..name.iter().position(|&b| b == 0)?];
+            let name = demangle(core::str::from_utf8(name).ok()?);
+
+            Some(Symbol {
+                addr: VAddr(value as usize),
+                bsize: size as usize,
+                name: Box::leak(name.into_boxed_str()),
+            })
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| symbol.addr.0);
+
+    symbols
+}
+
+/// Demangle the legacy mangled Rust `name`, without its hash, e.g.
+/// `_ZN4core3fmt5write17h0123456789abcdefE` into `core::fmt::write`. Any other
+/// name, e.g. of an assembly routine, is kept as is.
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return name.into();
+    };
+
+    let mut demangled = String::new();
+    while let Some(tail) = rest.strip_prefix(|c: char| c.is_ascii_digit()) {
+        let nr_digits = tail.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(tail.len()) + 1;
+        let Ok(len) = rest[..nr_digits].parse::<usize>() else {
+            return name.into();
+        };
+        let Some(ident) = rest.get(nr_digits..nr_digits + len) else {
+            return name.into();
+        };
+        rest = &rest[nr_digits + len..];
+
+        if rest == "E" && is_hash(ident) {
+            break;
+        }
+        if !demangled.is_empty() {
+            demangled.push_str("::");
+        }
+        // Identifiers starting with an escape are prefixed with `_`.
+        let ident = ident.strip_prefix('_')
+            .filter(|ident| ident.starts_with('$'))
+            .unwrap_or(ident);
+        unescape(ident, &mut demangled);
+    }
+
+    if rest != "E" {
+        return name.into();
+    }
+
+    demangled
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == HASH_LEN && ident.starts_with('h')
+        && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Append the `ident` of a mangled name to `out`, with its escapes replaced.
+fn unescape(mut ident: &str, out: &mut String) {
+    while !ident.is_empty() {
+        if let Some(rest) = ident.strip_prefix("..") {
+            out.push_str("::");
+            ident = rest;
+        } else if let Some((escape, c)) = ESCAPES.iter()
+            .find(|(escape, _)| ident.starts_with(escape)) {
+            out.push_str(c);
+            ident = &ident[escape.len()..];
+        } else {
+            let c = ident.chars().next().unwrap();
+            out.push(c);
+            ident = &ident[c.len_utf8()..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym_entry(name: u32, info: u8, value: u64, size: u64) -> Vec<u8> {
+        let mut entry = Vec::new();
+        entry.extend_from_slice(&name.to_le_bytes());
+        entry.extend_from_slice(&[info, 0, 1, 0]);
+        entry.extend_from_slice(&value.to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn it_reads_the_functions_sorted_by_address() {
+        let strtab = b"\0_ZN7nucloid4main17h0123456789abcdefE\0data\0isr\0";
+        let symtab = [
+            sym_entry(0, 0, 0, 0),
+            sym_entry(1, STT_FUNC | 0x10, 0x2000, 0x40),
+            sym_entry(38, 1, 0x3000, 8),
+            sym_entry(43, STT_FUNC, 0x1000, 0),
+        ].concat();
+
+        assert_eq!(parse(&symtab, strtab), [
+            Symbol { addr: VAddr(0x1000), bsize: 0, name: "isr" },
+            Symbol { addr: VAddr(0x2000), bsize: 0x40, name: "nucloid::main" },
+        ]);
+    }
+
+    #[test]
+    fn it_finds_the_function_containing_an_address() {
+        let symbols = [
+            Symbol { addr: VAddr(0x1000), bsize: 0, name: "isr" },
+            Symbol { addr: VAddr(0x2000), bsize: 0x40, name: "main" },
+        ];
+
+        assert_eq!(find(&symbols, VAddr(0xfff)), None);
+        assert_eq!(find(&symbols, VAddr(0x1000)).unwrap().name, "isr");
+        assert_eq!(find(&symbols, VAddr(0x1001)), None);
+        assert_eq!(find(&symbols, VAddr(0x203f)).unwrap().name, "main");
+        assert_eq!(find(&symbols, VAddr(0x2040)), None);
+    }
+
+    #[test]
+    fn it_demangles_legacy_names() {
+        assert_eq!(demangle("_ZN4core3fmt5write17h0123456789abcdefE"),
+                   "core::fmt::write");
+        let name = "_ZN64_$LT$nucloid..ui..term..Terminal$u20$as$u20$\
+                    core..fmt..Write$GT$9write_str17h0123456789abcdefE";
+        assert_eq!(demangle(name),
+                   "<nucloid::ui::term::Terminal as core::fmt::Write>\
+                    ::write_str");
+        assert_eq!(demangle("rust_begin_unwind"), "rust_begin_unwind");
+        assert_eq!(demangle("_ZN4core3fm"), "_ZN4core3fm");
+    }
+}
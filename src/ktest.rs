@@ -0,0 +1,142 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The in-kernel test runner, built with the `ktest` feature. Unlike the unit
+//! tests, which run on the host against mocks, kernel tests run at the end of
+//! the boot process under QEMU, with real paging, frames and interrupts; `make
+//! ktest` builds and runs them.
+//!
+//! Tests are declared with the `ktest!()` macro, which places them into the
+//! `.ktests` section; they fail by panicking, e.g. with `assert!()`. Results
+//! are reported onto the serial line, then QEMU is terminated through its
+//! `isa-debug-exit` device, its exit status telling whether all tests passed.
+//! The first failing test ends the run, as the kernel can't recover from a
+//! panic.
+
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::arch;
+use crate::arch::ioport::Port;
+use crate::arch::logging::LOGGER_SERIAL;
+
+/// The I/O port of QEMU's `isa-debug-exit` device, as set on its command line.
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// The codes written to `DEBUG_EXIT_PORT`; QEMU exits with `(code << 1) | 1`,
+/// i.e. 33 for success and 35 for failure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// A kernel test, as declared by `ktest!()`.
+pub struct KernelTest {
+    pub name: &'static str,
+    pub run: fn(),
+}
+
+extern "C" {
+    static __kernel_ktests: u8;
+    static __kernel_ktests_end: u8;
+}
+
+/// The test being run, for the panic handler to report it; null if none.
+static CURRENT_TEST: AtomicPtr<KernelTest>
+    = AtomicPtr::new(core::ptr::null_mut());
+
+/// All the tests declared with `ktest!()`.
+pub fn tests() -> &'static [KernelTest] {
+    let start = unsafe { addr_of!(__kernel_ktests) };
+    let end = unsafe { addr_of!(__kernel_ktests_end) };
+    let len = (end as usize - start as usize) / size_of::<KernelTest>();
+
+    unsafe { slice::from_raw_parts(start as *const KernelTest, len) }
+}
+
+/// Run all the tests, then terminate QEMU.
+pub fn run() -> ! {
+    let tests = tests();
+    serial_write(format_args!("ktest: running {} tests\n", tests.len()));
+
+    for test in tests {
+        serial_write(format_args!("ktest: {} ... ", test.name));
+        CURRENT_TEST.store(test as *const _ as *mut _, Ordering::SeqCst);
+        (test.run)();
+        CURRENT_TEST.store(core::ptr::null_mut(), Ordering::SeqCst);
+        serial_write(format_args!("ok\n"));
+    }
+
+    serial_write(format_args!("ktest: all {} tests passed\n", tests.len()));
+    exit_qemu(ExitCode::Success);
+}
+
+/// Report the failure of the running test, if any, and terminate QEMU; this is
+/// called by the panic handler, once the panic was reported.
+pub fn on_panic() -> ! {
+    let test = CURRENT_TEST.load(Ordering::SeqCst);
+
+    if let Some(test) = unsafe { test.as_ref() } {
+        serial_write(format_args!("ktest: {} FAILED\n", test.name));
+    } else {
+        serial_write(format_args!("ktest: panicked outside of any test\n"));
+    }
+
+    exit_qemu(ExitCode::Failure);
+}
+
+/// Terminate QEMU with `code`; halt if the kernel doesn't run on QEMU with an
+/// `isa-debug-exit` device.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    unsafe { Port::<u32>::new(DEBUG_EXIT_PORT) }.write(code as u32);
+    arch::cpu::perm_halt();
+}
+
+fn serial_write(args: core::fmt::Arguments) {
+    if let Some(serial) = unsafe { LOGGER_SERIAL.as_mut() } {
+        let _ = serial.write_fmt(args);
+    }
+}
+
+/// Declare kernel tests, as functions with no argument. They go in a `ktests`
+/// module next to the `tests` one, only built with the `ktest` feature:
+///
+/// ```ignore
+/// #[cfg(all(feature = "ktest", not(test)))]
+/// mod ktests {
+///     use crate::ktest;
+///
+///     ktest! {
+///         fn it_allocates_frames() {
+///             assert!(allocate_frames().allocate().is_some());
+///         }
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! ktest {
+    ($(fn $name:ident() $body:block)*) => {$(
+        const _: () = {
+            fn $name() $body
+
+            #[used]
+            #[link_section = ".ktests"]
+            static TEST: $crate::ktest::KernelTest = $crate::ktest::KernelTest {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                run: $name,
+            };
+        };
+    )*};
+}
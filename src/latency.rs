@@ -0,0 +1,169 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The latency watchdog. It measures how long interrupts stay disabled, i.e.
+//! the outermost critical regions, and how long spinlocks are held; a section
+//! lasting longer than the threshold is logged as a warning along with a
+//! backtrace of the code that ended it, which is usually the offender.
+//!
+//! The watchdog is disabled by default; the `latency=<us>` boot option enables
+//! it with the given threshold. To avoid flooding the log, at most one section
+//! is reported per `REPORT_PERIOD_S` seconds, the others being counted only.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::warning;
+
+/// The minimum period, in seconds, between two reports.
+const REPORT_PERIOD_S: u64 = 1;
+
+/// The number of backtrace frames logged per report.
+const REPORT_DEPTH: usize = 8;
+
+/// The threshold in timestamp cycles; zero if the watchdog is disabled.
+static THRESHOLD: AtomicU64 = AtomicU64::new(0);
+
+/// Set while a section is being reported: the log and the unwinder have
+/// critical regions and spinlocks of their own, which are not watched.
+static IN_REPORT: AtomicBool = AtomicBool::new(false);
+
+static LAST_REPORT: AtomicU64 = AtomicU64::new(0);
+
+/// The number of sections over the threshold that weren't reported since the
+/// last report.
+static SUPPRESSED: AtomicUsize = AtomicUsize::new(0);
+
+/// A kind of section during which the CPU can't respond promptly.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Section {
+    IrqsDisabled,
+    LockHeld,
+}
+
+impl Section {
+    fn description(self) -> &'static str {
+        match self {
+            Section::IrqsDisabled => "interrupts were disabled",
+            Section::LockHeld => "a spinlock was held",
+        }
+    }
+}
+
+/// Enable the watchdog with a threshold of `threshold_us` microseconds, or
+/// disable it with `None`.
+pub fn set_threshold_us(threshold_us: Option<u64>) {
+    let cycles = match threshold_us {
+        Some(us) => us_to_cycles(us, timestamp_frequency_or_default()).max(1),
+        None => 0,
+    };
+    THRESHOLD.store(cycles, Ordering::SeqCst);
+}
+
+/// The threshold in microseconds; `None` if the watchdog is disabled.
+pub fn threshold_us() -> Option<u64> {
+    match THRESHOLD.load(Ordering::Relaxed) {
+        0 => None,
+        cycles => Some(cycles_to_us(cycles, timestamp_frequency_or_default())),
+    }
+}
+
+/// The start timestamp of a watched section, to be passed to `check()` when
+/// it ends; zero if the watchdog is disabled.
+#[inline]
+pub fn start() -> u64 {
+    if THRESHOLD.load(Ordering::Relaxed) == 0 {
+        0
+    } else {
+        timestamp()
+    }
+}
+
+/// End the watched section `section`, which started at `start`, and report it
+/// if it lasted longer than the threshold.
+#[inline]
+pub fn check(section: Section, start: u64) {
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    if start == 0 || threshold == 0 || IN_REPORT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let elapsed = timestamp().saturating_sub(start);
+    if elapsed >= threshold {
+        report(section, elapsed);
+    }
+}
+
+#[cold]
+fn report(section: Section, elapsed: u64) {
+    if IN_REPORT.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let freq = timestamp_frequency_or_default();
+    let now = timestamp();
+    let last = LAST_REPORT.load(Ordering::SeqCst);
+
+    if last != 0 && now.saturating_sub(last) < REPORT_PERIOD_S * freq {
+        SUPPRESSED.fetch_add(1, Ordering::SeqCst);
+    } else {
+        LAST_REPORT.store(now, Ordering::SeqCst);
+
+        warning!("latency: {} for {} us",
+                 section.description(), cycles_to_us(elapsed, freq));
+        let suppressed = SUPPRESSED.swap(0, Ordering::SeqCst);
+        if suppressed > 0 {
+            warning!("latency: {suppressed} more sections were not reported");
+        }
+        log_backtrace();
+    }
+
+    IN_REPORT.store(false, Ordering::SeqCst);
+}
+
+#[cfg(not(test))]
+fn log_backtrace() {
+    use crate::arch::cpu::MachineState;
+    use crate::backtrace::Backtrace;
+
+    // Skip this function, `report()` and `check()`.
+    let machine = MachineState::here();
+    let frames = Backtrace::from_machine_state(&machine)
+        .skip(3)
+        .take(REPORT_DEPTH);
+    for frame in frames {
+        warning!("latency:   at 0x{:?}", frame.pc);
+    }
+}
+
+#[cfg(test)]
+fn log_backtrace() {
+}
+
+fn us_to_cycles(us: u64, freq: u64) -> u64 {
+    (us as u128 * freq as u128 / 1_000_000) as u64
+}
+
+fn cycles_to_us(cycles: u64, freq: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / freq as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_converts_between_cycles_and_microseconds() {
+        assert_eq!(us_to_cycles(1500, 2_000_000_000), 3_000_000);
+        assert_eq!(cycles_to_us(3_000_000, 2_000_000_000), 1500);
+        assert_eq!(cycles_to_us(u64::MAX, 1_000_000), u64::MAX);
+    }
+}
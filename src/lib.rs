@@ -0,0 +1,83 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The Nucloid kernel, as a library: all the subsystems, the architecture
+//! support and the boot process, linked into a kernel image by a binary crate.
+//! The binary only provides `nucloid_main()`, which the architecture's entry
+//! point calls once the machine is set up; see `src/main.rs` for the kernel's
+//! own. Experiments, such as fuzzers or alternative user interfaces, can link
+//! this crate with their own `nucloid_main()` rather than patching the kernel.
+//!
+//! On the host, e.g. for tests, the crate builds against the emulated `test`
+//! architecture instead, with the standard library.
+
+#![cfg_attr(not(test), no_std)]
+
+#![feature(panic_info_message)]
+#![feature(const_mut_refs)]
+#![feature(alloc_error_handler)]
+#![feature(const_trait_impl)]
+#![feature(inline_const)]
+#![feature(const_for)]
+#![feature(const_maybe_uninit_as_mut_ptr)]
+#![feature(iter_advance_by)]
+
+#![allow(unused_unsafe)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+pub mod arch;
+pub mod boot;
+pub mod collections;
+pub mod driver;
+pub mod mem;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod logging;
+pub mod sync;
+pub mod screen;
+pub mod panic;
+
+#[macro_use]
+pub mod misc;
+
+pub mod task;
+pub mod ui;
+pub mod time;
+pub mod crashdump;
+pub mod trace;
+#[cfg(feature = "profiler")]
+pub mod profile;
+pub mod latency;
+pub mod watchdog;
+pub mod lockdep;
+#[cfg(all(feature = "ktest", not(test)))]
+pub mod ktest;
+pub mod buildinfo;
+pub mod initcall;
+#[cfg(not(test))]
+pub mod stack_protector;
+pub mod fs;
+pub mod ksyms;
+mod backtrace;
+
+#[cfg(not(test))]
+extern "C" {
+    /// The kernel's main function, called once the architecture-specific
+    /// initialization is done; it is defined by the binary linking this crate.
+    fn nucloid_main() -> !;
+}
+
+/// Hand over to the binary's `nucloid_main()`.
+#[cfg(not(test))]
+fn main() -> ! {
+    unsafe { nucloid_main() }
+}
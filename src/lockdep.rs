@@ -0,0 +1,350 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The lock dependency validator, in the spirit of Linux's lockdep. When
+//! enabled with the `lockdep=on` boot option, in debug builds only, every CPU
+//! records the spinlocks it holds; acquiring a lock while holding another one
+//! records a dependency between the two, along with the stack of the
+//! acquisition. The kernel panics as soon as:
+//!
+//!   * a CPU acquires a lock it already holds, which would spin forever;
+//!   * a new dependency closes a cycle, i.e. two code paths acquire the same
+//!     locks in different orders, which deadlocks if they ever run at the same
+//!     time; the stack of the conflicting acquisition is logged before the
+//!     panic report, which shows the current stack.
+//!
+//! Locks are identified by their address, hence a lock in freed memory shares
+//! its dependencies with whatever lock later reuses the address. `try_lock()`
+//! can't deadlock: it records the lock as held, but no dependency. There is no
+//! scheduler yet, the held locks are tracked per CPU.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::{error, warning};
+
+/// The maximum number of distinct locks.
+const MAX_LOCKS: usize = 256;
+
+/// The maximum number of dependencies between locks.
+const MAX_DEPENDENCIES: usize = 1024;
+
+/// The maximum number of locks held at the same time by a CPU.
+const MAX_HELD: usize = 16;
+
+/// The number of frames recorded in the stack of a dependency.
+const STACK_DEPTH: usize = 8;
+
+static LOCKDEP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a CPU is validating an acquisition: the locks taken by the
+/// validator itself and by the unwinder are not tracked.
+static IN_LOCKDEP: [AtomicBool; MAX_CPUS]
+    = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+static STATE: Spinlock<LockState> = Spinlock::new(LockState::new());
+
+/// The return addresses of the innermost frames of an acquisition; unused
+/// entries are zero.
+type Stack = [u64; STACK_DEPTH];
+
+struct Dependency {
+    /// The index of the lock that was held.
+    before: usize,
+    /// The index of the lock that was acquired while holding it.
+    after: usize,
+    /// The stack of the first acquisition that recorded the dependency.
+    stack: Stack,
+}
+
+/// The locks seen so far and the dependencies between them.
+struct Graph {
+    /// The addresses of the locks, indexed by lock index.
+    locks: ArrayVec<usize, MAX_LOCKS>,
+    dependencies: ArrayVec<Dependency, MAX_DEPENDENCIES>,
+}
+
+struct LockState {
+    graph: Graph,
+    /// The indices of the locks held by each CPU, in acquisition order.
+    held: [ArrayVec<usize, MAX_HELD>; MAX_CPUS],
+}
+
+/// An acquisition that could deadlock, or that can't be validated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Violation {
+    /// The lock at `lock` is already held by the CPU.
+    Recursive { lock: usize },
+
+    /// Acquiring the lock at `after` while holding the one at `before` closes
+    /// a cycle; `stack` is the first dependency of the opposite order.
+    Cycle { before: usize, after: usize, stack: Stack },
+
+    /// The given table is full.
+    Overflow(&'static str),
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Self {
+            locks: ArrayVec::new_const(),
+            dependencies: ArrayVec::new_const(),
+        }
+    }
+
+    /// The index of the lock at `addr`, which is registered if it wasn't
+    /// seen before.
+    fn lock_index(&mut self, addr: usize) -> Result<usize, Violation> {
+        if let Some(index) = self.locks.iter().position(|&a| a == addr) {
+            return Ok(index);
+        }
+
+        self.locks.try_push(addr)
+            .map_err(|_| Violation::Overflow("lock table"))?;
+        Ok(self.locks.len() - 1)
+    }
+
+    /// Record that the lock `after` was acquired while holding `before`,
+    /// unless the opposite order was already seen.
+    fn add_dependency(
+        &mut self,
+        before: usize,
+        after: usize,
+        stack: impl FnOnce() -> Stack,
+    ) -> Result<(), Violation> {
+        if self.dependencies.iter()
+            .any(|dep| dep.before == before && dep.after == after) {
+            return Ok(());
+        }
+
+        if let Some(dep) = self.dependencies.iter()
+            .filter(|dep| dep.before == after)
+            .find(|dep| dep.after == before || self.reaches(dep.after, before))
+        {
+            return Err(Violation::Cycle {
+                before: self.locks[before],
+                after: self.locks[after],
+                stack: dep.stack,
+            });
+        }
+
+        self.dependencies
+            .try_push(Dependency { before, after, stack: stack() })
+            .map_err(|_| Violation::Overflow("dependency table"))
+    }
+
+    /// Whether there is a chain of dependencies from the lock `from` to the
+    /// lock `to`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = [false; MAX_LOCKS];
+        let mut pending: ArrayVec<usize, MAX_LOCKS> = ArrayVec::new();
+        visited[from] = true;
+        pending.push(from);
+
+        while let Some(lock) = pending.pop() {
+            if lock == to {
+                return true;
+            }
+
+            for dep in self.dependencies.iter().filter(|d| d.before == lock) {
+                if !visited[dep.after] {
+                    visited[dep.after] = true;
+                    pending.push(dep.after);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+impl LockState {
+    const fn new() -> Self {
+        Self {
+            graph: Graph::new(),
+            held: [const { ArrayVec::new_const() }; MAX_CPUS],
+        }
+    }
+
+    fn acquire(
+        &mut self,
+        cpu: usize,
+        addr: usize,
+        trylock: bool,
+    ) -> Result<(), Violation> {
+        let lock = self.graph.lock_index(addr)?;
+
+        if !trylock {
+            if self.held[cpu].contains(&lock) {
+                return Err(Violation::Recursive { lock: addr });
+            }
+
+            let mut stack = None;
+            for &before in &self.held[cpu] {
+                self.graph.add_dependency(before, lock, || {
+                    *stack.get_or_insert_with(capture_stack)
+                })?;
+            }
+        }
+
+        self.held[cpu].try_push(lock)
+            .map_err(|_| Violation::Overflow("held lock stack"))
+    }
+
+    fn release(&mut self, cpu: usize, addr: usize) {
+        let Some(lock) = self.graph.locks.iter().position(|&a| a == addr)
+        else {
+            return;
+        };
+
+        if let Some(pos) = self.held[cpu].iter().rposition(|&l| l == lock) {
+            self.held[cpu].remove(pos);
+        }
+    }
+}
+
+/// Enable or disable the validator; it can't be enabled in release builds.
+pub fn set_enabled(enabled: bool) {
+    if enabled && !cfg!(debug_assertions) {
+        warning!("lockdep: not available in release builds");
+        return;
+    }
+    LOCKDEP_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    cfg!(debug_assertions) && LOCKDEP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Validate the acquisition of the lock at `addr` by the current CPU, which
+/// must be in a critical region; `trylock` is set if it doesn't wait for the
+/// lock, which is then already acquired.
+#[inline]
+pub fn acquire(addr: usize, trylock: bool) {
+    if !is_enabled() {
+        return;
+    }
+
+    let cpu = current_cpu_index().get();
+    if IN_LOCKDEP[cpu].swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let result = STATE.lock().acquire(cpu, addr, trylock);
+    IN_LOCKDEP[cpu].store(false, Ordering::SeqCst);
+
+    if let Err(violation) = result {
+        report(violation);
+    }
+}
+
+/// Record the release of the lock at `addr` by the current CPU.
+#[inline]
+pub fn release(addr: usize) {
+    if !is_enabled() {
+        return;
+    }
+
+    let cpu = current_cpu_index().get();
+    if IN_LOCKDEP[cpu].swap(true, Ordering::SeqCst) {
+        return;
+    }
+    STATE.lock().release(cpu, addr);
+    IN_LOCKDEP[cpu].store(false, Ordering::SeqCst);
+}
+
+#[cold]
+fn report(violation: Violation) {
+    // The panic handler takes locks of its own, possibly in unusual orders.
+    set_enabled(false);
+
+    match violation {
+        Violation::Recursive { lock } => {
+            panic!("lockdep: recursive acquisition of the lock at {lock:#x}");
+        },
+        Violation::Cycle { before, after, stack } => {
+            error!("lockdep: the lock at {after:#x} was previously acquired \
+                    before the one at {before:#x}, at:");
+            for pc in stack.iter().take_while(|&&pc| pc != 0) {
+                error!("lockdep:   at {pc:#x}");
+            }
+            panic!("lockdep: possible deadlock, acquiring the lock at \
+                    {after:#x} while holding the one at {before:#x}");
+        },
+        Violation::Overflow(table) => {
+            warning!("lockdep: the {table} is full, validation is disabled");
+        },
+    }
+}
+
+#[cfg(not(test))]
+fn capture_stack() -> Stack {
+    use crate::arch::cpu::MachineState;
+    use crate::backtrace::Backtrace;
+
+    let mut stack = [0; STACK_DEPTH];
+
+    // Skip this function and the validator's own frames.
+    let machine = MachineState::here();
+    let frames = Backtrace::from_machine_state(&machine).skip(5);
+    for (pc, frame) in stack.iter_mut().zip(frames) {
+        *pc = frame.pc.0 as u64;
+    }
+
+    stack
+}
+
+#[cfg(test)]
+fn capture_stack() -> Stack {
+    [0; STACK_DEPTH]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_detects_lock_order_inversions() {
+        let mut state = LockState::new();
+
+        state.acquire(0, 0xa000, false).unwrap();
+        state.acquire(0, 0xb000, false).unwrap();
+        state.acquire(0, 0xc000, false).unwrap();
+        state.release(0, 0xc000);
+        state.release(0, 0xb000);
+        state.release(0, 0xa000);
+
+        state.acquire(1, 0xb000, false).unwrap();
+        state.acquire(1, 0xc000, false).unwrap();
+        state.release(1, 0xb000);
+        state.release(1, 0xc000);
+
+        state.acquire(1, 0xc000, false).unwrap();
+        assert_eq!(state.acquire(1, 0xa000, false),
+                   Err(Violation::Cycle {
+                       before: 0xc000,
+                       after: 0xa000,
+                       stack: [0; STACK_DEPTH],
+                   }));
+        assert_eq!(state.acquire(1, 0xa000, true), Ok(()));
+    }
+
+    #[test]
+    fn it_detects_recursive_acquisitions() {
+        let mut state = LockState::new();
+
+        state.acquire(0, 0xa000, false).unwrap();
+        assert_eq!(state.acquire(0, 0xa000, false),
+                   Err(Violation::Recursive { lock: 0xa000 }));
+        assert_eq!(state.acquire(1, 0xa000, false), Ok(()));
+    }
+}
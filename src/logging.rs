@@ -8,10 +8,13 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use crate::arch::logging::LOGGER_SERIAL;
 use crate::sync::Spinlock;
 
 use core::{fmt, mem};
+use core::fmt::Write;
 use core::ops::DerefMut;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 pub static DEFAULT_LOGGER: Spinlock<&'static mut (dyn Logger + Send)>
     = Spinlock::new(unsafe { &mut NULL_LOGGER });
@@ -20,7 +23,7 @@ pub trait Logger {
     fn log(&mut self, severity: Severity, args: fmt::Arguments);
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Severity {
     Debug,
     Info,
@@ -47,69 +50,187 @@ impl Severity {
             Emergency   => "emerg",
         }
     }
+
+    /// The severity whose label is `label`, as returned by `label()`.
+    pub fn from_label(label: &str) -> Option<Severity> {
+        use Severity::*;
+
+        [Debug, Info, Notice, Warning, Error, Critical, Alert, Emergency]
+            .into_iter()
+            .find(|severity| severity.label() == label)
+    }
+}
+
+/// The number of bytes of the most recent log messages kept in memory.
+const LOG_RING_SIZE: usize = 16384;
+
+static LOG_RING: Spinlock<LogRing> = Spinlock::new(LogRing::new());
+
+/// The least severe messages passed on to the default logger; the log ring
+/// keeps all of them regardless.
+static MIN_SEVERITY: AtomicU8 = AtomicU8::new(Severity::Debug as u8);
+
+/// A circular buffer retaining the text of the latest log messages, oldest
+/// bytes being overwritten first. It allows the crash dump to include what was
+/// logged before a panic, regardless of the logger in use.
+pub struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Return the buffered log text as two slices, the oldest first. Since the
+    /// oldest bytes may have been overwritten, the first slice may start in
+    /// the middle of a line, or even of a UTF-8 sequence.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let start = (self.head + LOG_RING_SIZE - self.len) % LOG_RING_SIZE;
+
+        if start + self.len <= LOG_RING_SIZE {
+            (&self.buf[start..(start + self.len)], &[])
+        } else {
+            (&self.buf[start..], &self.buf[..self.head])
+        }
+    }
+
+    /// Whether the oldest log text was overwritten.
+    pub fn is_wrapped(&self) -> bool {
+        self.len == LOG_RING_SIZE
+    }
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.head] = byte;
+            self.head = (self.head + 1) % LOG_RING_SIZE;
+            self.len = (self.len + 1).min(LOG_RING_SIZE);
+        }
+
+        Ok(())
+    }
+}
+
+/// Log a message with the default logger, and keep a copy of it in the log
+/// ring. This is what the logging macros expand to.
+pub fn log(severity: Severity, args: fmt::Arguments) {
+    {
+        let mut ring = LOG_RING.lock();
+        let _ = writeln!(ring, "{}: {}", severity.label(), args);
+    }
+
+    if severity as u8 >= MIN_SEVERITY.load(Ordering::Relaxed) {
+        DEFAULT_LOGGER.lock().log(severity, args);
+    }
+}
+
+/// Log a message like `log()`, from a context that may have interrupted the
+/// holder of the logging locks on the same CPU, such as the NMI and
+/// machine-check handlers: the locks are only tried, the message is not kept in
+/// a busy log ring, and it goes raw onto the serial line if the default logger
+/// is busy.
+pub fn log_lockless(severity: Severity, args: fmt::Arguments) {
+    if let Some(mut ring) = LOG_RING.try_lock() {
+        let _ = writeln!(ring, "{}: {}", severity.label(), args);
+    }
+
+    if severity as u8 >= MIN_SEVERITY.load(Ordering::Relaxed) {
+        match DEFAULT_LOGGER.try_lock() {
+            Some(mut logger) => logger.log(severity, args),
+            None => {
+                // SAFETY: the serial line is only ever written to, a message
+                // interleaved with another one is the worst that can happen.
+                if let Some(serial) = unsafe { LOGGER_SERIAL.as_mut() } {
+                    serial.log(severity, args);
+                }
+            },
+        }
+    }
+}
+
+/// Only pass on the messages at least as severe as `severity` to the default
+/// logger.
+pub fn set_min_severity(severity: Severity) {
+    MIN_SEVERITY.store(severity as u8, Ordering::Relaxed);
+}
+
+/// Run `f` on the log ring, e.g. to read what was logged so far.
+pub fn with_log_ring<R>(f: impl FnOnce(&LogRing) -> R) -> R {
+    f(&LOG_RING.lock())
+}
+
+/// Access the log ring without locking; this is meant for the panic handler
+/// only, which must not wait for a lock that may never be released.
+///
+/// # Safety #
+///
+/// No other CPU may be running, nor can the log ring be written to while the
+/// returned reference is alive.
+pub unsafe fn log_ring_unlocked() -> &'static LogRing {
+    unsafe { &*LOG_RING.bypass_lock() }
 }
 
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Debug, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Debug, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Info, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Info, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! notice {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Notice, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Notice, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! warning {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Warning, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Warning, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Error, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Error, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! critical {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Critical, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Critical, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! alert {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Alert, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Alert, format_args!($($arg)*));
     });
 }
 
 #[macro_export]
 macro_rules! emergency {
     ($($arg:tt)*) => ({
-        let mut logger = $crate::logging::DEFAULT_LOGGER.lock();
-        logger.log($crate::logging::Severity::Emergency, format_args!($($arg)*));
+        $crate::logging::log($crate::logging::Severity::Emergency, format_args!($($arg)*));
     });
 }
 
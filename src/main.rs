@@ -8,40 +8,14 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-#![cfg_attr(not(test), no_std)]
-#![cfg_attr(not(test), no_main)]
+#![no_std]
+#![no_main]
 
-#![feature(panic_info_message)]
-#![feature(const_mut_refs)]
-#![feature(alloc_error_handler)]
-#![feature(const_trait_impl)]
-#![feature(inline_const)]
-#![feature(const_for)]
-#![feature(const_maybe_uninit_as_mut_ptr)]
-#![feature(iter_advance_by)]
+use nucloid::{critical, debug, error, info, notice, println, task, warning};
 
-#![allow(unused_unsafe)]
-#![allow(dead_code)]
-
-#[cfg(not(test))]
-extern crate alloc;
-
-pub mod arch;
-pub mod driver;
-pub mod mem;
-pub mod logging;
-pub mod sync;
-pub mod screen;
-pub mod panic;
-
-#[macro_use]
-pub mod misc;
-
-pub mod task;
-pub mod ui;
-mod backtrace;
-
-fn main() -> ! {
+/// Called by the kernel once the machine is set up.
+#[no_mangle]
+extern "C" fn nucloid_main() -> ! {
     println!("Nucloid v{}", env!("CARGO_PKG_VERSION"));
     println!();
     println!("\x1b<fg=cc7832>impl\x1b<!fg> PxFont {{");
@@ -67,7 +41,5 @@ fn main() -> ! {
     error!("Oops, un erreur s'est produite...");
     critical!("Aïe ! C'est sérieux !");
 
-    loop {
-        arch::cpu::halt();
-    }
+    task::init::start();
 }
@@ -0,0 +1,270 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The early-boot memory allocator. Before the frame allocator exists, the
+//! memory needed by the boot process, e.g. the page tables and the frame
+//! allocator's own array of frames, is taken from the RAM described by the
+//! bootloader's memory map. Every range handed out or set aside is recorded as
+//! a reservation along with its owner, so that nothing overlaps by accident.
+//!
+//! Once the boot allocations are done, `BootMem::transfer()` declares the
+//! memory map and the reservations to the frame allocator's builder: the
+//! reserved RAM stays allocated, the rest of the RAM becomes free.
+
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
+
+use crate::arch::mem::FRAME_SIZE;
+use crate::mem::PAddr;
+use crate::mem::frame::AllocatorBuilder;
+use crate::misc::align_up;
+
+/// The maximum number of areas of the memory map; UEFI firmwares report many
+/// more than the BIOS does.
+pub const MAX_REGIONS: usize = 128;
+
+/// The maximum number of reservations; adjacent ones of the same owner are
+/// merged.
+pub const MAX_RESERVATIONS: usize = 32;
+
+/// What an area of the memory map holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+    /// General-purpose RAM.
+    Ram,
+    /// Memory the firmware uses, or device memory.
+    Reserved,
+    /// Memory that must not be touched at all.
+    Unusable,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Region {
+    start: u64,
+    end: u64,
+    kind: RegionKind,
+}
+
+/// A physical range set aside during the boot.
+#[derive(Debug, Copy, Clone)]
+pub struct Reservation {
+    pub start: PAddr,
+    pub bsize: u64,
+    pub owner: &'static str,
+}
+
+impl Reservation {
+    pub fn end(&self) -> PAddr {
+        self.start + self.bsize
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end().0 && self.start.0 < end
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BootMemError {
+    #[error("too many memory areas")]
+    TooManyRegions,
+
+    #[error("too many reservations")]
+    TooManyReservations,
+
+    #[error("overlaps memory reserved by {0}")]
+    Overlap(&'static str),
+}
+
+pub struct BootMem {
+    regions: ArrayVec<Region, MAX_REGIONS>,
+    reservations: ArrayVec<Reservation, MAX_RESERVATIONS>,
+}
+
+impl BootMem {
+    pub const fn new() -> Self {
+        Self {
+            regions: ArrayVec::new_const(),
+            reservations: ArrayVec::new_const(),
+        }
+    }
+
+    /// Add an area of the memory map.
+    pub fn add_region(
+        &mut self,
+        start: PAddr,
+        bsize: u64,
+        kind: RegionKind,
+    ) -> Result<(), BootMemError> {
+        self.regions
+            .try_push(Region { start: start.0, end: start.0 + bsize, kind })
+            .map_err(|_| BootMemError::TooManyRegions)
+    }
+
+    /// Set aside the frames spanned by `bsize` bytes at `start` for `owner`;
+    /// they are not necessarily RAM, e.g. the low memory used by the BIOS.
+    pub fn reserve(
+        &mut self,
+        start: PAddr,
+        bsize: u64,
+        owner: &'static str,
+    ) -> Result<(), BootMemError> {
+        let end = align_up(start.0 + bsize, FRAME_SIZE as u64);
+        let start = start.0 & !(FRAME_SIZE as u64 - 1);
+
+        if let Some(other) = self.reservations.iter()
+            .find(|r| r.overlaps(start, end)) {
+            return Err(BootMemError::Overlap(other.owner));
+        }
+
+        // Merge with a reservation of the same owner ending right there, as
+        // done by the page tables allocated one by one.
+        if let Some(last) = self.reservations.iter_mut()
+            .find(|r| r.owner == owner && r.end().0 == start) {
+            last.bsize += end - start;
+            return Ok(());
+        }
+
+        self.reservations
+            .try_push(Reservation {
+                start: PAddr(start),
+                bsize: end - start,
+                owner,
+            })
+            .map_err(|_| BootMemError::TooManyReservations)
+    }
+
+    /// Allocate `bsize` bytes of RAM for `owner`, ending below `limit`, e.g.
+    /// so that the memory is already mapped; the lowest free range is taken.
+    pub fn alloc(
+        &mut self,
+        bsize: u64,
+        limit: PAddr,
+        owner: &'static str,
+    ) -> Option<PAddr> {
+        let bsize = align_up(bsize, FRAME_SIZE as u64);
+        let start = self.find_free(bsize, limit.0)?;
+
+        self.reserve(PAddr(start), bsize, owner).ok()?;
+        Some(PAddr(start))
+    }
+
+    /// The ranges reserved so far, in the order they were first reserved.
+    pub fn reservations(&self) -> &[Reservation] {
+        &self.reservations
+    }
+
+    /// Declare the memory map then the reservations to the frame allocator
+    /// being built; the reservations take precedence.
+    ///
+    /// # Safety #
+    ///
+    /// All the memory in use must have been reserved, see
+    /// `AllocatorBuilder::declare_allocated_ram()`.
+    pub unsafe fn transfer(&self, builder: &mut AllocatorBuilder) {
+        let frame_mask = FRAME_SIZE as u64 - 1;
+
+        // Partial frames of RAM are left out, and those of the other areas
+        // are included: their non-RAM part must not be handed out.
+        for region in self.ram() {
+            builder.declare_unused_ram(PAddr(region.start),
+                                       region.end - region.start);
+        }
+        for region in self.regions.iter()
+            .filter(|region| region.kind != RegionKind::Ram) {
+            let start = PAddr(region.start & !frame_mask);
+            let bsize = align_up(region.end, FRAME_SIZE as u64) - start.0;
+            match region.kind {
+                RegionKind::Reserved => builder.declare_reserved(start, bsize),
+                _ => builder.declare_unusable(start, bsize),
+            }
+        }
+
+        for reservation in &self.reservations {
+            builder.declare_allocated_ram(reservation.start, reservation.bsize);
+        }
+    }
+
+    /// The lowest free range of RAM of `bsize` bytes ending below `limit`.
+    fn find_free(&self, bsize: u64, limit: u64) -> Option<u64> {
+        for region in self.ram() {
+            let mut start = region.start;
+            while start + bsize <= region.end.min(limit) {
+                let end = start + bsize;
+                match self.reservations.iter()
+                    .find(|r| r.overlaps(start, end)) {
+                    Some(other) => start = other.end().0,
+                    None => return Some(start),
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The RAM areas, shrunk to whole frames.
+    fn ram(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter()
+            .filter(|region| region.kind == RegionKind::Ram)
+            .map(|region| Region {
+                start: align_up(region.start, FRAME_SIZE as u64),
+                end: region.end & !(FRAME_SIZE as u64 - 1),
+                kind: region.kind,
+            })
+            .filter(|region| region.start < region.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    fn bootmem() -> BootMem {
+        let mut bootmem = BootMem::new();
+        bootmem.add_region(PAddr(0), 0x9fc00, RegionKind::Ram).unwrap();
+        bootmem.add_region(PAddr(0x9fc00), 0x400, RegionKind::Reserved)
+            .unwrap();
+        bootmem.add_region(PAddr(0x100000), 0x700000, RegionKind::Ram)
+            .unwrap();
+        bootmem
+    }
+
+    fn alloc(bootmem: &mut BootMem, bsize: u64, limit: u64) -> Option<u64> {
+        bootmem.alloc(bsize, PAddr(limit), "test").map(|paddr| paddr.0)
+    }
+
+    #[test]
+    fn it_allocates_around_reservations() {
+        let mut bootmem = bootmem();
+        bootmem.reserve(PAddr(0), 0x200123, "kernel").unwrap();
+
+        assert_eq!(alloc(&mut bootmem, 0x1000, 0x800000), Some(0x201000));
+        assert_eq!(alloc(&mut bootmem, 0x1000, 0x800000), Some(0x202000));
+
+        let reservations: Vec<_> = bootmem.reservations().iter()
+            .map(|r| (r.start.0, r.bsize, r.owner))
+            .collect();
+        assert_eq!(reservations, [(0, 0x201000, "kernel"),
+                                  (0x201000, 0x2000, "test")]);
+
+        assert_eq!(bootmem.reserve(PAddr(0x202800), 16, "other").unwrap_err(),
+                   BootMemError::Overlap("test"));
+    }
+
+    #[test]
+    fn it_allocates_below_the_limit() {
+        let mut bootmem = bootmem();
+        bootmem.reserve(PAddr(0), 0x100000, "low memory").unwrap();
+
+        assert_eq!(alloc(&mut bootmem, 0x300000, 0x400000), Some(0x100000));
+        assert_eq!(alloc(&mut bootmem, 0x200000, 0x400000), None);
+        assert_eq!(alloc(&mut bootmem, 0x200000, 0x800000), Some(0x400000));
+    }
+}
@@ -0,0 +1,296 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Memory shared with devices performing DMA. It is plain RAM, cached: the
+//! devices' accesses are coherent with the CPU caches on x86.
+//!
+//! Devices may not address all of the physical memory, e.g. those limited to
+//! 32-bit addresses: the buffers they access must lie below their limit. Those
+//! allocated with `alloc_coherent()` do; others are mapped with `map_single()`,
+//! which goes through a bounce buffer when they don't.
+//!
+//! With DMA remapping on, devices only reach the buffers allocated here: the
+//! others are always bounced, since the pages they lie in may hold other data.
+
+use core::ptr;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::driver::iommu;
+use crate::mem::frame::{allocate_frames, free_frames};
+use crate::mem::{PAddr, VAddr};
+use crate::misc::align_up;
+
+/// The highest physical address of devices addressing 32 bits.
+pub const DMA_32BIT: PAddr = PAddr(0xffff_ffff);
+
+/// The highest physical address of devices addressing all of the memory.
+pub const DMA_64BIT: PAddr = PAddr(u64::MAX);
+
+/// Zeroed, physically contiguous frames, freed on drop.
+pub struct DmaPages {
+    paddr: PAddr,
+    nr_frames: usize,
+}
+
+/// Which way the data of a buffer mapped with `map_single()` flows.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DmaDirection {
+    /// The device reads the buffer.
+    ToDevice,
+    /// The device writes the buffer.
+    FromDevice,
+    /// The device both reads and writes the buffer.
+    Bidirectional,
+}
+
+/// A buffer made accessible to a device with `map_single()`. The device
+/// accesses `paddr()`, which is either the buffer itself or a bounce buffer;
+/// what the device wrote is copied back into the buffer on drop.
+pub struct DmaMapping<'a> {
+    buf: &'a mut [u8],
+    direction: DmaDirection,
+    bounce: Option<DmaPages>,
+}
+
+impl DmaPages {
+    /// Allocate `nr_frames` zeroed frames; `None` if out of memory.
+    pub fn new(nr_frames: usize) -> Option<Self> {
+        Self::new_below(nr_frames, DMA_64BIT)
+    }
+
+    /// Allocate `nr_frames` zeroed frames, the last byte of which is at or
+    /// below `max_paddr`; `None` if there aren't enough of them.
+    pub fn new_below(nr_frames: usize, max_paddr: PAddr) -> Option<Self> {
+        let paddr = allocate_frames()
+            .nr_frames(nr_frames)
+            .zero_mem()
+            .max_paddr(max_paddr)
+            .allocate()?;
+        let pages = Self { paddr, nr_frames };
+
+        iommu::map_dma(paddr, pages.bsize()).ok()?;
+        Some(pages)
+    }
+
+    pub fn paddr(&self) -> PAddr {
+        self.paddr
+    }
+
+    pub fn vaddr(&self) -> VAddr {
+        self.paddr.into_vaddr()
+    }
+
+    pub fn bsize(&self) -> usize {
+        self.nr_frames * PAGE_SIZE
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        self.check::<u16>(offset);
+        unsafe { ptr::read_volatile((self.vaddr() + offset).as_ptr()) }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        self.check::<u16>(offset);
+        unsafe { ptr::write_volatile((self.vaddr() + offset).as_mut_ptr(), value) }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.check::<u32>(offset);
+        unsafe { ptr::read_volatile((self.vaddr() + offset).as_ptr()) }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.check::<u32>(offset);
+        unsafe { ptr::write_volatile((self.vaddr() + offset).as_mut_ptr(), value) }
+    }
+
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.check::<u64>(offset);
+        unsafe { ptr::write_volatile((self.vaddr() + offset).as_mut_ptr(), value) }
+    }
+
+    /// Copy the bytes at `offset` into `buf`.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        self.check_range(offset, buf.len());
+        unsafe {
+            ptr::copy_nonoverlapping((self.vaddr() + offset).as_ptr(),
+                                     buf.as_mut_ptr(), buf.len());
+        }
+    }
+
+    /// Copy `data` to `offset`.
+    pub fn write_bytes(&self, offset: usize, data: &[u8]) {
+        self.check_range(offset, data.len());
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(),
+                                     (self.vaddr() + offset).as_mut_ptr(),
+                                     data.len());
+        }
+    }
+
+    pub fn zero(&self) {
+        unsafe {
+            self.vaddr().as_mut_ptr::<u8>().write_bytes(0, self.bsize());
+        }
+    }
+
+    fn check<T>(&self, offset: usize) {
+        self.check_range(offset, core::mem::size_of::<T>());
+    }
+
+    fn check_range(&self, offset: usize, len: usize) {
+        assert!(offset + len <= self.bsize(),
+                "DMA access at {offset:#x} out of bounds ({:#x})", self.bsize());
+    }
+}
+
+impl Drop for DmaPages {
+    fn drop(&mut self) {
+        iommu::unmap_dma(self.paddr, self.bsize());
+        unsafe { free_frames(self.paddr, self.nr_frames); }
+    }
+}
+
+impl DmaMapping<'_> {
+    /// The physical address for the device to access.
+    pub fn paddr(&self) -> PAddr {
+        match &self.bounce {
+            Some(bounce) => bounce.paddr(),
+            None => PAddr::from_lowmem_vaddr(VAddr::from(self.buf.as_ptr()))
+                .unwrap(),
+        }
+    }
+
+    pub fn bsize(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+
+    /// Make what the device wrote so far visible in the buffer.
+    pub fn sync_for_cpu(&mut self) {
+        if let Some(bounce) = &self.bounce {
+            if self.direction != DmaDirection::ToDevice {
+                bounce.read_bytes(0, self.buf);
+            }
+        }
+    }
+
+    /// Make what the CPU wrote into the buffer visible to the device, before
+    /// it accesses it again.
+    pub fn sync_for_device(&mut self) {
+        if let Some(bounce) = &self.bounce {
+            if self.direction != DmaDirection::FromDevice {
+                bounce.write_bytes(0, self.buf);
+            }
+        }
+    }
+}
+
+impl Drop for DmaMapping<'_> {
+    fn drop(&mut self) {
+        self.sync_for_cpu();
+    }
+}
+
+/// Allocate a zeroed buffer of at least `len` bytes for a device that
+/// addresses physical memory up to `max_paddr`, e.g. `DMA_32BIT`; it is
+/// physically contiguous and accessed by the CPU through `vaddr()`. `None` if
+/// there isn't enough memory below the limit.
+pub fn alloc_coherent(len: usize, max_paddr: PAddr) -> Option<DmaPages> {
+    let nr_frames = align_up(len.max(1), PAGE_SIZE) / PAGE_SIZE;
+    DmaPages::new_below(nr_frames, max_paddr)
+}
+
+/// Make `buf` accessible to a device that addresses physical memory up to
+/// `max_paddr`, for the duration of the mapping. The buffer is used as is if
+/// it is physically contiguous and below the limit, otherwise a bounce buffer
+/// is allocated and synchronized with it; `None` if that fails.
+pub fn map_single(
+    buf: &mut [u8],
+    max_paddr: PAddr,
+    direction: DmaDirection,
+) -> Option<DmaMapping<'_>> {
+    let start = VAddr::from(buf.as_ptr());
+    let last = start + buf.len().saturating_sub(1);
+
+    // The low memory maps the physical memory linearly.
+    let is_usable = match (PAddr::from_lowmem_vaddr(start),
+                           PAddr::from_lowmem_vaddr(last)) {
+        (Some(_), Some(last)) => {
+            last.0 <= max_paddr.0 && !iommu::is_enabled()
+        },
+        _ => false,
+    };
+
+    let bounce = if is_usable {
+        None
+    } else {
+        Some(alloc_coherent(buf.len(), max_paddr)?)
+    };
+    let mut mapping = DmaMapping { buf, direction, bounce };
+    mapping.sync_for_device();
+
+    Some(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use core::slice;
+    use crate::arch::mem::{reset_memory, MEMORY_MUTEX};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use super::*;
+
+    #[test]
+    fn it_allocates_below_the_device_limit() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let _low = DmaPages::new(2).unwrap();
+        assert!(alloc_coherent(3 * PAGE_SIZE, PAddr(0x3fff)).is_none());
+
+        let pages = alloc_coherent(PAGE_SIZE + 1, PAddr(0x3fff)).unwrap();
+        assert_eq!(pages.paddr().0, 0x2000);
+        assert_eq!(pages.bsize(), 2 * PAGE_SIZE);
+        assert_eq!(pages.read32(PAGE_SIZE), 0);
+    }
+
+    #[test]
+    fn it_bounces_buffers_the_device_cant_reach() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut buf = vec![1u8; 16];
+        let mapping = map_single(&mut buf, DMA_32BIT,
+                                 DmaDirection::Bidirectional).unwrap();
+        assert!(mapping.is_bounced());
+
+        // The device reads and writes the bounce buffer.
+        let device_view = mapping.paddr().into_vaddr();
+        assert_eq!(unsafe { *device_view.as_ptr::<u8>() }, 1);
+        unsafe { *device_view.as_mut_ptr::<u8>() = 7; }
+        drop(mapping);
+        assert_eq!(&buf[..2], &[7, 1]);
+
+        let pages = DmaPages::new(1).unwrap();
+        let buf = unsafe {
+            slice::from_raw_parts_mut(pages.vaddr().as_mut_ptr::<u8>(), 16)
+        };
+        let mapping = map_single(buf, DMA_32BIT, DmaDirection::ToDevice)
+            .unwrap();
+        assert!(!mapping.is_bounced());
+        assert_eq!(mapping.paddr().0, pages.paddr().0);
+    }
+}
@@ -16,18 +16,28 @@
 ///! which manages a global array of frames mapping the entire physical address
 ///! space.
 
+use core::ops::{AddAssign, Range};
 use core::slice;
 use core::mem::size_of;
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
 
 use crate::sync::Spinlock;
 use crate::mem::{PAddr, get_lowmem_va_end, VAddr};
 use crate::arch::mem::{FRAME_SIZE, FRAME_SIZE_BITS};
 use crate::debug;
+use crate::mem::hotplug::HotAddError;
+use crate::mem::resource::{self, ResourceError};
+use crate::mem::numa::{self, NodeId};
 use crate::misc::align_up;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Frame {
     state: FrameState,
+
+    /// Whether the free frame is known to be filled with zeros, as done ahead
+    /// of time by `prezero_frames()`.
+    zeroed: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -47,7 +57,7 @@ enum FrameState {
     /// Special memory area but currently unused. Those are generally MMIO
     /// devices like PCI BARs, framebuffers, etc. Frames marked as reserved
     /// won't be returned by a general-purpose allocation and must be
-    /// specifically claimed through a `claim()` call on the frame allocator.
+    /// specifically claimed with `mem::resource::request_region()`.
     /// At boot, the kernel will mark as reserved all frames as stated by
     /// the bootloader.
     UnclaimedReserved,
@@ -73,16 +83,122 @@ impl Default for Frame {
     fn default() -> Self {
         Frame {
             state: FrameState::Unusable,
+            zeroed: false,
         }
     }
 }
 
+/// A snapshot of the frame allocator's counters, in number of frames for each
+/// frame state.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub allocated: usize,
+    pub unclaimed_reserved: usize,
+    pub claimed_reserved: usize,
+    pub unusable: usize,
+    /// The free frames already zeroed, handed out right away to the
+    /// allocations asking for zeroed memory.
+    pub zeroed: usize,
+}
+
+impl FrameStats {
+    pub fn reserved(&self) -> usize {
+        self.unclaimed_reserved + self.claimed_reserved
+    }
+}
+
+impl AddAssign for FrameStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.total += rhs.total;
+        self.free += rhs.free;
+        self.allocated += rhs.allocated;
+        self.unclaimed_reserved += rhs.unclaimed_reserved;
+        self.claimed_reserved += rhs.claimed_reserved;
+        self.unusable += rhs.unusable;
+        self.zeroed += rhs.zeroed;
+    }
+}
+
 //----------------------------------------------------------------------------//
 
 pub static FRAME_ALLOCATOR: Spinlock<Option<FrameAllocator>> = Spinlock::new(None);
 
+/// A callback giving back memory when the frame allocator runs low: it is
+/// passed the number of frames wanted, and returns how many it freed. It is
+/// called with no frame allocator lock held, but possibly with any other lock
+/// held by the allocating code: it must only try to take its own locks.
+pub type Shrinker = fn(usize) -> usize;
+
+static SHRINKERS: Spinlock<ArrayVec<Shrinker, MAX_SHRINKERS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+const MAX_CLAIMS: usize = 32;
+
+const MAX_SHRINKERS: usize = 8;
+
+/// The maximum number of frame ranges attributed to NUMA nodes.
+const MAX_NODE_SPANS: usize = 32;
+
+/// The maximum number of frames `prezero_frames()` zeroes per call.
+const PREZERO_BATCH: usize = 16;
+
 pub struct FrameAllocator {
     frames: &'static mut [Frame],
+
+    /// The reserved regions claimed by drivers, with their owner.
+    claims: ArrayVec<Claim, MAX_CLAIMS>,
+
+    /// The frame index from which to look for a free frame to zero.
+    prezero_cursor: usize,
+
+    /// Whether all the free frames are zeroed, until some are freed.
+    all_zeroed: bool,
+
+    /// The frames of each NUMA node, making up its pool; empty when all the
+    /// frames are on node 0.
+    node_spans: ArrayVec<NodeSpan, MAX_NODE_SPANS>,
+}
+
+/// A range of frames, by index, belonging to a NUMA node.
+#[derive(Debug, Clone)]
+struct NodeSpan {
+    frames: Range<usize>,
+    node: NodeId,
+}
+
+/// A physical region claimed by a driver through `claim_region()`.
+#[derive(Debug, Copy, Clone)]
+pub struct Claim {
+    pub paddr: PAddr,
+    pub nr_frames: usize,
+    pub owner: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum ClaimError {
+    #[error("frame {paddr:?} is already claimed by {owner}")]
+    Conflict { paddr: PAddr, owner: &'static str },
+
+    #[error("frame {paddr:?} is general-purpose RAM")]
+    OverlapsRam { paddr: PAddr },
+
+    #[error("empty or misaligned region")]
+    InvalidRegion,
+
+    #[error("too many claims")]
+    TooManyClaims,
+
+    #[error("no region at {paddr:?} is claimed by {owner}")]
+    NotClaimed { paddr: PAddr, owner: &'static str },
+}
+
+impl Claim {
+    fn frame_range(&self) -> core::ops::Range<usize> {
+        let first = FrameAllocator::index_from_paddr(self.paddr);
+        first..(first + self.nr_frames)
+    }
 }
 
 impl FrameAllocator {
@@ -102,15 +218,93 @@ impl FrameAllocator {
         &mut self,
         nr_frames: usize,
     ) -> Option<PAddr> {
+        self.allocate_below(nr_frames, PAddr(u64::MAX), false)
+    }
+
+    /// Allocate `nr_frames` contiguous frames, like `allocate()`, but only
+    /// among the free frames already zeroed.
+    pub fn allocate_zeroed(&mut self, nr_frames: usize) -> Option<PAddr> {
+        self.allocate_below(nr_frames, PAddr(u64::MAX), true)
+    }
+
+    /// Allocate `nr_frames` contiguous frames whose last byte is at or below
+    /// `max_paddr`, as required by devices that can't address all of the
+    /// physical memory; only among the frames already zeroed if `zeroed`.
+    pub fn allocate_below(
+        &mut self,
+        nr_frames: usize,
+        max_paddr: PAddr,
+        zeroed: bool,
+    ) -> Option<PAddr> {
+        self.allocate_in(0..self.frames.len(), nr_frames, max_paddr, zeroed)
+    }
+
+    /// Allocate frames like `allocate_below()`, but only from the pool of the
+    /// NUMA node `node`.
+    pub fn allocate_on_node(
+        &mut self,
+        node: NodeId,
+        nr_frames: usize,
+        max_paddr: PAddr,
+        zeroed: bool,
+    ) -> Option<PAddr> {
+        self.node_frames(node)
+            .into_iter()
+            .find_map(|frames| {
+                self.allocate_in(frames, nr_frames, max_paddr, zeroed)
+            })
+    }
+
+    /// Attribute the frames spanned by `bsize` bytes at `paddr` to the NUMA
+    /// node `node`; return `false` if there are too many ranges already.
+    pub fn set_node(&mut self, paddr: PAddr, bsize: u64, node: NodeId) -> bool {
+        let first = Self::index_from_paddr(PAddr(align_up(paddr.0,
+                                                          FRAME_SIZE as u64)));
+        let end = Self::index_from_paddr(paddr + bsize).min(self.frames.len());
+        if first >= end {
+            return true;
+        }
+
+        self.node_spans.try_push(NodeSpan { frames: first..end, node }).is_ok()
+    }
+
+    /// The frame ranges making up the pool of `node`.
+    fn node_frames(
+        &self,
+        node: NodeId,
+    ) -> ArrayVec<Range<usize>, MAX_NODE_SPANS> {
+        if self.node_spans.is_empty() {
+            let all = 0..self.frames.len();
+            return (node == 0).then_some(all).into_iter().collect();
+        }
+
+        self.node_spans.iter()
+            .filter(|span| span.node == node)
+            .map(|span| span.frames.clone())
+            .collect()
+    }
+
+    fn allocate_in(
+        &mut self,
+        frames: Range<usize>,
+        nr_frames: usize,
+        max_paddr: PAddr,
+        zeroed: bool,
+    ) -> Option<PAddr> {
+        let end = (max_paddr.0.saturating_add(1) >> FRAME_SIZE_BITS)
+            .min(frames.end as u64) as usize;
+        let start = frames.start.min(end);
         let mut nr_free = 0;
         let mut free_index = None;
 
-        for (i, frame) in self.frames.iter_mut().enumerate() {
-            if frame.is_free_ram() {
+        for (i, frame) in self.frames[start..end].iter_mut().enumerate() {
+            let is_candidate = frame.is_free_ram() && (frame.zeroed || !zeroed);
+
+            if is_candidate {
                 nr_free += 1;
 
                 if nr_free == nr_frames {
-                    free_index = Some(i - (nr_free - 1));
+                    free_index = Some(start + i - (nr_free - 1));
                     break;
                 }
             } else {
@@ -124,6 +318,7 @@ impl FrameAllocator {
                 .skip(free_index)
                 .take(nr_frames) {
                 frame.state = FrameState::AllocatedRAM;
+                frame.zeroed = false;
             }
 
             Some(Self::frame_paddr(free_index))
@@ -132,20 +327,281 @@ impl FrameAllocator {
         }
     }
 
+    /// Take a free frame not zeroed yet, for the caller to zero it outside of
+    /// the allocator's lock then give it back with `put_zeroed()`; in the
+    /// meantime, it is accounted as allocated.
+    pub fn take_dirty(&mut self) -> Option<PAddr> {
+        if self.all_zeroed {
+            return None;
+        }
+        let len = self.frames.len();
+
+        for offset in 0..len {
+            let index = (self.prezero_cursor + offset) % len;
+            let frame = &mut self.frames[index];
+
+            if frame.is_free_ram() && !frame.zeroed {
+                frame.state = FrameState::AllocatedRAM;
+                self.prezero_cursor = (index + 1) % len;
+                return Some(Self::frame_paddr(index));
+            }
+        }
+
+        self.all_zeroed = true;
+        None
+    }
+
+    /// Give back the frame at `frame_addr` obtained with `take_dirty()`, now
+    /// filled with zeros.
+    ///
+    /// # Safety #
+    ///
+    /// The frame must be zeroed, and not used anymore.
+    pub unsafe fn put_zeroed(&mut self, frame_addr: PAddr) {
+        let frame = &mut self.frames[Self::index_from_paddr(frame_addr)];
+        assert!(frame.is_allocated(), "trying to free unallocated frame");
+
+        frame.state = FrameState::FreeRAM;
+        frame.zeroed = true;
+    }
+
     pub unsafe fn free(&mut self, frame_addr: PAddr, nr_frames: usize) {
-        assert_eq!(nr_frames, 1, "unimplemented");
-        let index = Self::index_from_paddr(frame_addr);
+        let first = Self::index_from_paddr(frame_addr);
 
-        if index >= self.frames.len() {
+        if first + nr_frames > self.frames.len() {
             panic!("Free of out of bound frame at {}", frame_addr.0);
         }
 
-        let new_state = match self.frames[index].state {
-            FrameState::AllocatedRAM => FrameState::FreeRAM,
-            FrameState::ClaimedReserved => FrameState::UnclaimedReserved,
-            _ => panic!("trying to free unallocated frame"),
+        for frame in &mut self.frames[first..(first + nr_frames)] {
+            let new_state = match frame.state {
+                FrameState::AllocatedRAM => FrameState::FreeRAM,
+                FrameState::ClaimedReserved => FrameState::UnclaimedReserved,
+                _ => panic!("trying to free unallocated frame"),
+            };
+            frame.state = new_state;
+            frame.zeroed = false;
+        }
+        self.all_zeroed = false;
+    }
+
+    /// Claim the reserved frames spanning `paddr..paddr+bsize` for the driver
+    /// named `owner`, e.g. for MMIO registers or a framebuffer. The region must
+    /// be frame-aligned and must not include any general-purpose RAM nor any
+    /// frame already claimed.
+    ///
+    /// Reserved frames become claimed; frames beyond the physical memory size
+    /// or unusable ones, such as holes in the memory map, are only accounted
+    /// in the list of claims.
+    pub fn claim_region(
+        &mut self,
+        paddr: PAddr,
+        bsize: u64,
+        owner: &'static str,
+    ) -> Result<(), ClaimError> {
+        if bsize == 0 || paddr.0 & (FRAME_SIZE as u64 - 1) != 0 {
+            return Err(ClaimError::InvalidRegion);
+        }
+
+        let claim = Claim {
+            paddr,
+            nr_frames: (align_up(bsize, FRAME_SIZE as u64) >> FRAME_SIZE_BITS)
+                as usize,
+            owner,
         };
-        self.frames[index].state = new_state;
+        let range = claim.frame_range();
+
+        if let Some(other) = self.claims.iter()
+            .find(|other| {
+                let other = other.frame_range();
+                range.start < other.end && other.start < range.end
+            }) {
+            return Err(ClaimError::Conflict {
+                paddr: other.paddr,
+                owner: other.owner,
+            });
+        }
+
+        let in_array = range.start.min(self.frames.len())
+            ..range.end.min(self.frames.len());
+        for index in in_array.clone() {
+            if matches!(self.frames[index].state,
+                        FrameState::FreeRAM | FrameState::AllocatedRAM) {
+                return Err(ClaimError::OverlapsRam {
+                    paddr: Self::frame_paddr(index),
+                });
+            }
+        }
+
+        self.claims.try_push(claim).map_err(|_| ClaimError::TooManyClaims)?;
+
+        for frame in self.frames[in_array].iter_mut() {
+            if matches!(frame.state, FrameState::UnclaimedReserved) {
+                frame.state = FrameState::ClaimedReserved;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release a region previously obtained by `owner` with `claim_region()`;
+    /// its frames become reserved again.
+    pub fn release_region(
+        &mut self,
+        paddr: PAddr,
+        owner: &'static str,
+    ) -> Result<(), ClaimError> {
+        let index = self.claims.iter()
+            .position(|claim| claim.paddr.0 == paddr.0 && claim.owner == owner)
+            .ok_or(ClaimError::NotClaimed { paddr, owner })?;
+        let range = self.claims.swap_remove(index).frame_range();
+
+        let in_array = range.start.min(self.frames.len())
+            ..range.end.min(self.frames.len());
+        for frame in self.frames[in_array].iter_mut() {
+            if matches!(frame.state, FrameState::ClaimedReserved) {
+                frame.state = FrameState::UnclaimedReserved;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The regions currently claimed by drivers.
+    pub fn claims(&self) -> &[Claim] {
+        &self.claims
+    }
+
+    /// The number of frames in the array, covering the physical address space
+    /// from 0.
+    pub fn nr_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Replace the array of frames with the one at `frame_array`, covering
+    /// `phys_mem_bsize` bytes of physical memory; the frames beyond the former
+    /// array are unusable, and the RAM of the former array is freed. Return
+    /// `false`, leaving the allocator untouched, if the current array already
+    /// covers that much memory.
+    ///
+    /// # Safety #
+    ///
+    /// `frame_array` must point to `AllocatorBuilder::array_bsize()` bytes of
+    /// allocated RAM, which the allocator takes ownership of.
+    pub unsafe fn grow(
+        &mut self,
+        frame_array: VAddr,
+        phys_mem_bsize: u64,
+    ) -> bool {
+        let nr_frames = (align_up(phys_mem_bsize, FRAME_SIZE as u64)
+            >> FRAME_SIZE_BITS) as usize;
+        if nr_frames <= self.frames.len() {
+            return false;
+        }
+
+        let frames = slice::from_raw_parts_mut(
+            frame_array.as_mut_ptr::<Frame>(),
+            nr_frames,
+        );
+        let (known, new) = frames.split_at_mut(self.frames.len());
+        known.copy_from_slice(self.frames);
+        new.fill(Default::default());
+
+        let former = core::mem::replace(&mut self.frames, frames);
+        let former_bsize = former.len() * size_of::<Frame>();
+        if let Some(paddr) = PAddr::from_lowmem_vaddr(former.as_ptr().into()) {
+            self.free(paddr, align_up(former_bsize, FRAME_SIZE) / FRAME_SIZE);
+        }
+
+        true
+    }
+
+    /// Check that the frames spanned by `bsize` bytes at `paddr` can be added
+    /// as RAM with `add_ram()`, i.e. that they are unusable so far, or beyond
+    /// the array of frames.
+    pub fn check_hot_add(
+        &self,
+        paddr: PAddr,
+        bsize: u64,
+    ) -> Result<(), HotAddError> {
+        let first = Self::index_from_paddr(paddr).min(self.frames.len());
+        let end = Self::index_from_paddr(paddr + bsize).min(self.frames.len());
+
+        match self.frames[first..end].iter().position(|f| !f.is_unusable()) {
+            Some(i) => Err(HotAddError::AlreadyKnown {
+                paddr: Self::frame_paddr(first + i),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Add the frames spanned by `bsize` bytes at `paddr`, on the NUMA node
+    /// `node`, to the general-purpose RAM; the array of frames must cover
+    /// them, see `grow()`, and they must pass `check_hot_add()`.
+    pub fn add_ram(
+        &mut self,
+        paddr: PAddr,
+        bsize: u64,
+        node: NodeId,
+    ) -> Result<(), HotAddError> {
+        let first = Self::index_from_paddr(paddr);
+        let end = Self::index_from_paddr(paddr + bsize);
+        if end > self.frames.len() {
+            return Err(HotAddError::BeyondFrameArray);
+        }
+        self.check_hot_add(paddr, bsize)?;
+
+        if node != 0 && self.node_spans.is_empty() {
+            // All the frames were on node 0 until now.
+            self.node_spans.push(NodeSpan { frames: 0..first, node: 0 });
+        }
+        if !self.node_spans.is_empty() && !self.set_node(paddr, bsize, node) {
+            return Err(HotAddError::TooManyNodeSpans);
+        }
+
+        for frame in &mut self.frames[first..end] {
+            frame.state = FrameState::FreeRAM;
+            frame.zeroed = false;
+        }
+        self.all_zeroed = false;
+
+        Ok(())
+    }
+
+    /// Count the frames in each state.
+    pub fn stats(&self) -> FrameStats {
+        Self::count(&self.frames)
+    }
+
+    /// Count the frames in each state within the pool of the NUMA node `node`.
+    pub fn node_stats(&self, node: NodeId) -> FrameStats {
+        let mut stats = FrameStats::default();
+        for frames in self.node_frames(node) {
+            stats += Self::count(&self.frames[frames]);
+        }
+
+        stats
+    }
+
+    fn count(frames: &[Frame]) -> FrameStats {
+        let mut stats = FrameStats {
+            total: frames.len(),
+            ..Default::default()
+        };
+
+        for frame in frames.iter() {
+            if frame.is_free_ram() && frame.zeroed {
+                stats.zeroed += 1;
+            }
+            *match frame.state {
+                FrameState::Unusable => &mut stats.unusable,
+                FrameState::FreeRAM => &mut stats.free,
+                FrameState::AllocatedRAM => &mut stats.allocated,
+                FrameState::UnclaimedReserved => &mut stats.unclaimed_reserved,
+                FrameState::ClaimedReserved => &mut stats.claimed_reserved,
+            } += 1;
+        }
+
+        stats
     }
 
     fn frame_paddr(frame_index: usize) -> PAddr {
@@ -157,16 +613,101 @@ impl FrameAllocator {
     }
 }
 
+/// Claim a reserved physical region for the driver named `owner`. This is
+/// `mem::resource::request_region()`: the resource map owns the claims, and
+/// accounts them in the frame allocator with `FrameAllocator::claim_region()`.
+pub fn claim_region(
+    paddr: PAddr,
+    bsize: u64,
+    owner: &'static str,
+) -> Result<(), ResourceError> {
+    resource::request_region(paddr, bsize, owner)
+}
+
+/// Release a physical region previously claimed with `claim_region()`.
+pub fn release_region(
+    paddr: PAddr,
+    owner: &'static str,
+) -> Result<(), ResourceError> {
+    resource::release_region(paddr, owner)
+}
+
+/// Give back `nr_frames` contiguous frames starting at `paddr`, previously
+/// obtained from `allocate_frames()`.
+///
+/// # Safety #
+///
+/// The frames must not be in use anymore, nor mapped anywhere but in low
+/// memory.
+pub unsafe fn free_frames(paddr: PAddr, nr_frames: usize) {
+    FRAME_ALLOCATOR.lock()
+        .as_mut()
+        .expect("no frame allocator configured")
+        .free(paddr, nr_frames)
+}
+
+/// Register `shrinker` to be called when an allocation fails for lack of free
+/// frames; return `false` if there are too many of them.
+pub fn register_shrinker(shrinker: Shrinker) -> bool {
+    SHRINKERS.lock().try_push(shrinker).is_ok()
+}
+
+/// Have the shrinkers free up to `nr_frames` frames; return how many they
+/// freed.
+pub fn shrink(nr_frames: usize) -> usize {
+    let shrinkers = SHRINKERS.lock().clone();
+    let mut nr_freed = 0;
+
+    for shrinker in shrinkers {
+        if nr_freed >= nr_frames {
+            break;
+        }
+        nr_freed += shrinker(nr_frames - nr_freed);
+    }
+
+    nr_freed
+}
+
+/// Zero up to `PREZERO_BATCH` free frames ahead of time, so that allocations
+/// asking for zeroed memory get them right away; return how many were zeroed.
+/// This is meant to be called from idle loops.
+pub fn prezero_frames() -> usize {
+    let mut nr_zeroed = 0;
+
+    while nr_zeroed < PREZERO_BATCH {
+        let Some(paddr) = FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .and_then(|allocator| allocator.take_dirty()) else {
+            break;
+        };
+
+        unsafe {
+            paddr.into_vaddr().as_mut_ptr::<u8>().write_bytes(0, FRAME_SIZE);
+            FRAME_ALLOCATOR.lock()
+                .as_mut()
+                .expect("no frame allocator configured")
+                .put_zeroed(paddr);
+        }
+        nr_zeroed += 1;
+    }
+
+    nr_zeroed
+}
+
 pub fn allocate_frames() -> AllocationBuilder {
     AllocationBuilder {
         nr_frames: 1,
         zero: false,
+        max_paddr: PAddr(u64::MAX),
+        node: None,
     }
 }
 
 pub struct AllocationBuilder {
     nr_frames: usize,
     zero: bool,
+    max_paddr: PAddr,
+    node: Option<NodeId>,
 }
 
 impl AllocationBuilder {
@@ -180,15 +721,47 @@ impl AllocationBuilder {
         self
     }
 
+    /// Only allocate frames whose last byte is at or below `max_paddr`.
+    pub fn max_paddr(&mut self, max_paddr: PAddr) -> &mut Self {
+        self.max_paddr = max_paddr;
+        self
+    }
+
+    /// Only allocate frames from the NUMA node `node`. Otherwise, the node of
+    /// the calling CPU is preferred, then the nearest ones, then any frame.
+    pub fn on_node(&mut self, node: NodeId) -> &mut Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Allocate the frames; with `zero_mem()`, frames zeroed ahead of time
+    /// are preferred, otherwise they are zeroed now. If there aren't enough
+    /// free frames, the shrinkers are asked to free some before trying again.
     pub fn allocate(&mut self) -> Option<PAddr> {
-        let mut allocator = FRAME_ALLOCATOR.lock();
+        self.try_allocate().or_else(|| {
+            (shrink(self.nr_frames) > 0).then(|| self.try_allocate())?
+        })
+    }
+
+    fn try_allocate(&self) -> Option<PAddr> {
+        let nodes = match self.node {
+            Some(node) => [node].into_iter().collect(),
+            None => numa::nodes_by_distance(numa::current_node()),
+        };
 
-        let paddr = allocator
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator
             .as_mut()
-            .expect("no frame allocator configured")
-            .allocate(self.nr_frames)?;
+            .expect("no frame allocator configured");
 
-        if self.zero {
+        let (paddr, needs_zeroing) = nodes.into_iter()
+            .find_map(|node| self.allocate_from(allocator, Some(node)))
+            .or_else(|| match self.node {
+                Some(_) => None,
+                None => self.allocate_from(allocator, None),
+            })?;
+
+        if needs_zeroing {
             unsafe {
                 paddr.into_vaddr().as_mut_ptr::<u8>()
                     .write_bytes(0, self.nr_frames * 4096);
@@ -198,22 +771,32 @@ impl AllocationBuilder {
         Some(paddr)
     }
 
-    pub fn map_lowmem(&mut self) -> Option<VAddr> {
-        let mut allocator = FRAME_ALLOCATOR.lock();
-
-        let vaddr = allocator
-            .as_mut()
-            .expect("no frame allocator configured")
-            .allocate(self.nr_frames)?
-            .into_vaddr();
+    /// Allocate from the pool of `node`, or from anywhere if `None`, preferring
+    /// the frames zeroed ahead of time if zeroed memory is wanted; return the
+    /// frames' address, and whether they still need zeroing.
+    fn allocate_from(
+        &self,
+        allocator: &mut FrameAllocator,
+        node: Option<NodeId>,
+    ) -> Option<(PAddr, bool)> {
+        let mut allocate = |zeroed| match node {
+            Some(node) => allocator.allocate_on_node(node, self.nr_frames,
+                                                     self.max_paddr, zeroed),
+            None => allocator.allocate_below(self.nr_frames, self.max_paddr,
+                                             zeroed),
+        };
 
         if self.zero {
-            unsafe {
-                vaddr.as_mut_ptr::<u8>().write_bytes(0, self.nr_frames * 4096);
+            if let Some(paddr) = allocate(true) {
+                return Some((paddr, false));
             }
         }
 
-        Some(vaddr)
+        allocate(false).map(|paddr| (paddr, self.zero))
+    }
+
+    pub fn map_lowmem(&mut self) -> Option<VAddr> {
+        self.allocate().map(PAddr::into_vaddr)
     }
 }
 
@@ -255,7 +838,7 @@ impl AllocatorBuilder {
         phys_mem_bsize: u64,
     ) -> AllocatorBuilder {
         let nr_frames = (align_up(phys_mem_bsize, 4096) >> 12) as usize;
-        let array_bsize = nr_frames * size_of::<Frame>();
+        let array_bsize = Self::array_bsize(phys_mem_bsize);
 
         assert!(frame_array + array_bsize < get_lowmem_va_end());
         let frames = unsafe {
@@ -268,6 +851,12 @@ impl AllocatorBuilder {
         }
     }
 
+    /// The size in bytes of the array of frames for `phys_mem_bsize` bytes of
+    /// physical memory, i.e. of the buffer `new()` needs.
+    pub fn array_bsize(phys_mem_bsize: u64) -> usize {
+        (align_up(phys_mem_bsize, 4096) >> 12) as usize * size_of::<Frame>()
+    }
+
     /// Declare some physical memory area as already allocated and in use for
     /// general purpose allocations. This function is used when creating the
     /// allocator service to declare which memory areas were already in use for
@@ -317,17 +906,24 @@ impl AllocatorBuilder {
     /// Failure to do so will either hand already-allocated frames to other
     /// users, or allocate reserved memory areas for general purpose.
     pub unsafe fn build(mut self) -> FrameAllocator {
-        let frames_paddr = PAddr::from_lowmem_vaddr(VAddr::from(self.frames.as_ptr())).unwrap();
+        let frames_vaddr = VAddr::from(self.frames.as_ptr());
         let frames_bsize = self.frames.len() * size_of::<Frame>();
 
-        // Let's not forget to mark as used the RAM for the frame descriptors.
-        self.declare_allocated_ram(
-            frames_paddr,
-            align_up(frames_bsize as u64, 4096)
-        );
+        // Let's not forget to mark as used the RAM for the frame descriptors;
+        // the host tests keep them outside of the managed memory.
+        if let Some(frames_paddr) = PAddr::from_lowmem_vaddr(frames_vaddr) {
+            self.declare_allocated_ram(
+                frames_paddr,
+                align_up(frames_bsize as u64, 4096)
+            );
+        }
 
         FrameAllocator {
             frames: self.frames,
+            claims: ArrayVec::new(),
+            prezero_cursor: 0,
+            all_zeroed: false,
+            node_spans: ArrayVec::new(),
         }
     }
 
@@ -348,4 +944,264 @@ impl AllocatorBuilder {
 
 #[cfg(test)]
 mod test {
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use crate::arch::mem::{reset_memory, MEMORY_MUTEX, NR_PHYS_FRAMES};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use super::*;
+
+    fn allocator_with(states: &[FrameState]) -> FrameAllocator {
+        let frames = states.iter()
+            .map(|&state| Frame { state, zeroed: false })
+            .collect::<Vec<_>>();
+
+        FrameAllocator {
+            frames: Box::leak(frames.into_boxed_slice()),
+            claims: ArrayVec::new(),
+            prezero_cursor: 0,
+            all_zeroed: false,
+            node_spans: ArrayVec::new(),
+        }
+    }
+
+    #[test]
+    fn it_counts_frames_by_state() {
+        let allocator = allocator_with(&[
+            FrameState::Unusable,
+            FrameState::FreeRAM,
+            FrameState::FreeRAM,
+            FrameState::AllocatedRAM,
+            FrameState::UnclaimedReserved,
+            FrameState::ClaimedReserved,
+            FrameState::ClaimedReserved,
+        ]);
+
+        let stats = allocator.stats();
+
+        assert_eq!(stats, FrameStats {
+            total: 7,
+            free: 2,
+            allocated: 1,
+            unclaimed_reserved: 1,
+            claimed_reserved: 2,
+            unusable: 1,
+            zeroed: 0,
+        });
+        assert_eq!(stats.reserved(), 3);
+    }
+
+    #[test]
+    fn it_claims_reserved_frames() {
+        let mut allocator = allocator_with(&[
+            FrameState::FreeRAM,
+            FrameState::UnclaimedReserved,
+            FrameState::UnclaimedReserved,
+        ]);
+
+        allocator.claim_region(PAddr(0x1000), 0x2000, "test").unwrap();
+
+        assert_eq!(allocator.stats().claimed_reserved, 2);
+        assert_eq!(allocator.claims().len(), 1);
+        assert_eq!(allocator.claims()[0].owner, "test");
+    }
+
+    #[test]
+    fn it_doesnt_claim_ram() {
+        let mut allocator = allocator_with(&[
+            FrameState::UnclaimedReserved,
+            FrameState::FreeRAM,
+        ]);
+
+        let res = allocator.claim_region(PAddr(0), 0x2000, "test");
+
+        assert!(matches!(res, Err(ClaimError::OverlapsRam { paddr })
+                              if paddr.0 == 0x1000));
+        assert_eq!(allocator.stats().claimed_reserved, 0);
+        assert!(allocator.claims().is_empty());
+    }
+
+    #[test]
+    fn it_doesnt_claim_twice() {
+        let mut allocator = allocator_with(&[FrameState::UnclaimedReserved; 4]);
+        allocator.claim_region(PAddr(0x1000), 0x2000, "first").unwrap();
+
+        let res = allocator.claim_region(PAddr(0x2000), 0x2000, "second");
+
+        assert!(matches!(res,
+                         Err(ClaimError::Conflict { owner: "first", .. })));
+    }
+
+    #[test]
+    fn it_claims_beyond_physical_memory() {
+        let mut allocator = allocator_with(&[FrameState::UnclaimedReserved]);
+
+        allocator.claim_region(PAddr(0x10000), 0x1000, "test").unwrap();
+        let res = allocator.claim_region(PAddr(0x10000), 0x1000, "other");
+
+        assert!(matches!(res, Err(ClaimError::Conflict { owner: "test", .. })));
+        assert_eq!(allocator.stats().claimed_reserved, 0);
+    }
+
+    #[test]
+    fn it_releases_claims() {
+        let mut allocator = allocator_with(&[FrameState::UnclaimedReserved; 2]);
+        allocator.claim_region(PAddr(0), 0x2000, "test").unwrap();
+
+        assert!(allocator.release_region(PAddr(0), "other").is_err());
+        allocator.release_region(PAddr(0), "test").unwrap();
+
+        assert_eq!(allocator.stats().unclaimed_reserved, 2);
+        assert!(allocator.claims().is_empty());
+    }
+
+    #[test]
+    fn it_updates_stats_on_allocation() {
+        let mut allocator = allocator_with(&vec![FrameState::FreeRAM; 4]);
+
+        allocator.allocate(3).unwrap();
+
+        let stats = allocator.stats();
+        assert_eq!(stats.free, 1);
+        assert_eq!(stats.allocated, 3);
+    }
+
+    #[test]
+    fn it_allocates_below_a_physical_limit() {
+        let mut allocator = allocator_with(&[
+            FrameState::FreeRAM,
+            FrameState::AllocatedRAM,
+            FrameState::FreeRAM,
+            FrameState::FreeRAM,
+        ]);
+
+        assert!(allocator.allocate_below(2, PAddr(0x3ffe), false).is_none());
+        assert_eq!(allocator.allocate_below(2, PAddr(0x3fff), false)
+                       .unwrap().0, 0x2000);
+        assert_eq!(allocator.allocate_below(1, PAddr(0xfff), false)
+                       .unwrap().0, 0);
+    }
+
+    #[test]
+    fn it_allocates_from_node_pools() {
+        let mut allocator = allocator_with(&vec![FrameState::FreeRAM; 4]);
+        assert_eq!(allocator.allocate_on_node(0, 1, PAddr(u64::MAX), false)
+                       .unwrap().0, 0);
+        assert!(allocator.allocate_on_node(1, 1, PAddr(u64::MAX), false)
+                    .is_none());
+
+        assert!(allocator.set_node(PAddr(0), 0x2000, 0));
+        assert!(allocator.set_node(PAddr(0x2000), 0x2000, 1));
+
+        assert_eq!(allocator.allocate_on_node(1, 2, PAddr(u64::MAX), false)
+                       .unwrap().0, 0x2000);
+        assert!(allocator.allocate_on_node(0, 2, PAddr(u64::MAX), false)
+                    .is_none());
+        assert_eq!(allocator.node_stats(0).free, 1);
+        assert_eq!(allocator.node_stats(1).allocated, 2);
+    }
+
+    #[test]
+    fn it_grows_the_frame_array() {
+        let mut allocator = allocator_with(&[FrameState::AllocatedRAM]);
+        let array = Box::leak(vec![Frame::default(); 4].into_boxed_slice());
+
+        unsafe {
+            assert!(!allocator.grow(VAddr::from(array.as_ptr()), 0x1000));
+            assert!(allocator.grow(VAddr::from(array.as_ptr()), 0x4000));
+        }
+
+        assert_eq!(allocator.nr_frames(), 4);
+        assert_eq!(allocator.stats().allocated, 1);
+        assert_eq!(allocator.stats().unusable, 3);
+    }
+
+    #[test]
+    fn it_adds_hot_plugged_ram() {
+        let mut allocator = allocator_with(&[
+            FrameState::FreeRAM,
+            FrameState::Unusable,
+            FrameState::Unusable,
+            FrameState::UnclaimedReserved,
+        ]);
+
+        assert!(matches!(allocator.add_ram(PAddr(0x1000), 0x3000, 0),
+                         Err(HotAddError::AlreadyKnown { paddr })
+                         if paddr.0 == 0x3000));
+        assert!(matches!(allocator.add_ram(PAddr(0x3000), 0x2000, 0),
+                         Err(HotAddError::BeyondFrameArray)));
+        allocator.add_ram(PAddr(0x1000), 0x2000, 1).unwrap();
+
+        assert_eq!(allocator.stats().free, 3);
+        assert_eq!(allocator.node_stats(0).free, 1);
+        assert_eq!(allocator.allocate_on_node(1, 2, PAddr(u64::MAX), false)
+                       .unwrap().0, 0x1000);
+    }
+
+    #[test]
+    fn it_hands_out_prezeroed_frames_first() {
+        let mut allocator = allocator_with(&vec![FrameState::FreeRAM; 4]);
+
+        let dirty = allocator.take_dirty().unwrap();
+        assert_eq!(dirty.0, 0);
+        assert_eq!(allocator.stats().allocated, 1);
+        unsafe { allocator.put_zeroed(dirty); }
+        assert_eq!(allocator.take_dirty().unwrap().0, 0x1000);
+        assert_eq!(allocator.stats().zeroed, 1);
+
+        assert!(allocator.allocate_zeroed(2).is_none());
+        assert_eq!(allocator.allocate_zeroed(1).unwrap().0, 0);
+        assert_eq!(allocator.stats().zeroed, 0);
+
+        unsafe { allocator.free(PAddr(0), 1); }
+        assert_eq!(allocator.stats().zeroed, 0);
+    }
+
+    #[test]
+    fn it_allocates_zeroed_frames_from_memory() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let paddr = allocate_frames().nr_frames(2).zero_mem().allocate()
+            .unwrap();
+        let mem = unsafe {
+            slice::from_raw_parts(paddr.into_vaddr().as_ptr::<u8>(),
+                                  2 * FRAME_SIZE)
+        };
+        assert!(mem.iter().all(|&b| b == 0));
+
+        let stats = || FRAME_ALLOCATOR.lock().as_ref().unwrap().stats();
+        assert_eq!(stats().allocated, 2);
+        assert_eq!(stats().free, NR_PHYS_FRAMES - 2);
+
+        unsafe { free_frames(paddr, 2); }
+        assert_eq!(stats().allocated, 0);
+    }
+}
+
+#[cfg(all(feature = "ktest", not(test)))]
+mod ktests {
+    use crate::ktest;
+    use crate::mem::stats;
+    use super::*;
+
+    ktest! {
+        fn it_allocates_and_frees_frames() {
+            let allocated = stats().unwrap().allocated;
+
+            let paddr = allocate_frames().nr_frames(4).zero_mem().allocate()
+                .unwrap();
+            let mem = unsafe {
+                slice::from_raw_parts_mut(paddr.into_vaddr().as_mut_ptr::<u8>(),
+                                          4 * FRAME_SIZE)
+            };
+            assert!(mem.iter().all(|&b| b == 0));
+            mem.fill(0xa5);
+            assert_eq!(stats().unwrap().allocated, allocated + 4);
+
+            unsafe { free_frames(paddr, 4); }
+            assert_eq!(stats().unwrap().allocated, allocated);
+        }
+    }
 }
@@ -0,0 +1,150 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Memory hot-add. RAM showing up after boot, e.g. plugged by virtio-mem or
+//! found by a late ACPI parsing, is mapped in low memory then handed to the
+//! frame allocator, whose array of frames is moved to a bigger one if it
+//! doesn't cover the new RAM; `PHYS_MEM_SIZE` and the end of low memory follow.
+
+use thiserror_no_std::Error;
+
+use crate::arch::mem::{map_page, unmap_page, FRAME_SIZE, LOWMEM_SIZE};
+use crate::mem::frame::{allocate_frames, AllocatorBuilder, FRAME_ALLOCATOR};
+use crate::mem::numa::NodeId;
+use crate::mem::resource::{self, ResourceKind};
+use crate::mem::{get_lowmem_va_end, CacheMode, PAddr, LOWMEM_VA_END,
+                 PHYS_MEM_SIZE};
+use crate::misc::{align_up, BinSize};
+use crate::sync::Spinlock;
+use crate::info;
+
+/// Serializes the hot-adds, which map, allocate and grow the frame array in
+/// several steps.
+static HOTPLUG: Spinlock<()> = Spinlock::new(());
+
+#[derive(Error, Debug)]
+pub enum HotAddError {
+    #[error("empty or misaligned range")]
+    InvalidRange,
+
+    #[error("range goes beyond the low memory")]
+    BeyondLowmem,
+
+    #[error("range goes beyond the array of frames")]
+    BeyondFrameArray,
+
+    #[error("frame {paddr:?} is already known to the frame allocator")]
+    AlreadyKnown { paddr: PAddr },
+
+    #[error("too many NUMA node ranges")]
+    TooManyNodeSpans,
+
+    #[error("no memory left for the array of frames")]
+    NoMemory,
+
+    #[error("couldn't map {paddr:?} in low memory")]
+    MapFailed { paddr: PAddr },
+}
+
+/// Add the `bsize` bytes of RAM at `paddr`, on the NUMA node `node`, to the
+/// frame allocator. The range must be frame-aligned, and not be known as RAM
+/// nor reserved memory already.
+pub fn add_memory(
+    paddr: PAddr,
+    bsize: u64,
+    node: NodeId,
+) -> Result<(), HotAddError> {
+    let end = paddr.0.checked_add(bsize)
+        .filter(|_| bsize > 0 && (paddr.0 | bsize) % FRAME_SIZE as u64 == 0)
+        .ok_or(HotAddError::InvalidRange)?;
+    if end > LOWMEM_SIZE as u64 {
+        return Err(HotAddError::BeyondLowmem);
+    }
+
+    let _hotplug = HOTPLUG.lock();
+
+    FRAME_ALLOCATOR.lock()
+        .as_ref()
+        .expect("no frame allocator configured")
+        .check_hot_add(paddr, bsize)?;
+
+    grow_frame_array(end)?;
+    unsafe { map_lowmem(paddr, end)?; }
+
+    FRAME_ALLOCATOR.lock()
+        .as_mut()
+        .expect("no frame allocator configured")
+        .add_ram(paddr, bsize, node)?;
+
+    unsafe {
+        PHYS_MEM_SIZE = PHYS_MEM_SIZE.max(end);
+        if PAddr(end).into_vaddr() > get_lowmem_va_end() {
+            LOWMEM_VA_END = PAddr(end).into_vaddr();
+        }
+    }
+    resource::declare_boot_region(paddr, bsize, ResourceKind::SystemRam);
+
+    info!("memory: added {paddr:?} -> {:?} ({}) on node {node}",
+          PAddr(end), BinSize(bsize));
+
+    Ok(())
+}
+
+/// Move the frame allocator to a bigger array of frames if its current one
+/// doesn't cover the physical memory up to `phys_mem_end`.
+fn grow_frame_array(phys_mem_end: u64) -> Result<(), HotAddError> {
+    let nr_frames = FRAME_ALLOCATOR.lock()
+        .as_ref()
+        .expect("no frame allocator configured")
+        .nr_frames();
+    if phys_mem_end <= nr_frames as u64 * FRAME_SIZE as u64 {
+        return Ok(());
+    }
+
+    let array_bsize = AllocatorBuilder::array_bsize(phys_mem_end);
+    let array = allocate_frames()
+        .nr_frames(align_up(array_bsize, FRAME_SIZE) / FRAME_SIZE)
+        .map_lowmem()
+        .ok_or(HotAddError::NoMemory)?;
+
+    unsafe {
+        FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("no frame allocator configured")
+            .grow(array, phys_mem_end);
+    }
+
+    Ok(())
+}
+
+/// Map the pages of `start..end` beyond the end of low memory at their low
+/// memory address; the pages below are mapped since boot.
+///
+/// # Safety #
+///
+/// The range must be RAM unknown to the frame allocator.
+unsafe fn map_lowmem(start: PAddr, end: u64) -> Result<(), HotAddError> {
+    let lowmem_end = get_lowmem_va_end();
+    let pages = (start.0..end)
+        .step_by(FRAME_SIZE)
+        .map(PAddr)
+        .filter(|paddr| paddr.into_vaddr() >= lowmem_end);
+
+    for (i, paddr) in pages.clone().enumerate() {
+        if !map_page(paddr.into_vaddr(), paddr, CacheMode::WriteBack) {
+            for mapped in pages.take(i) {
+                unmap_page(mapped.into_vaddr());
+            }
+            return Err(HotAddError::MapFailed { paddr });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,219 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Mappings of device memory. The low-memory area maps physical memory as
+//! regular cached RAM, which is wrong for device registers and framebuffers:
+//! `iomap()` maps them instead into a dedicated virtual window, with the
+//! caching mode they need set in the page table entries: uncached for
+//! registers, write-combining for framebuffers.
+//!
+//! Virtual addresses of the window are never reused: unmapping a region only
+//! removes its pages.
+
+use thiserror_no_std::Error;
+
+use crate::arch::mem::{map_page, unmap_page, IOMAP_VA_SIZE, IOMAP_VA_START,
+                       PAGE_SIZE};
+use crate::driver::mmio::RegBlock;
+use crate::mem::{CacheMode, PAddr, VAddr};
+use crate::misc::align_up;
+use crate::sync::Spinlock;
+
+/// The next free virtual address of the window.
+static NEXT_VADDR: Spinlock<VAddr> = Spinlock::new(IOMAP_VA_START);
+
+#[derive(Error, Debug)]
+pub enum IomapError {
+    #[error("empty or overflowing range")]
+    InvalidRange,
+
+    #[error("no more virtual addresses to map device memory")]
+    OutOfAddressSpace,
+
+    #[error("couldn't map {paddr:?}")]
+    MapFailed { paddr: PAddr },
+}
+
+/// A mapping of device memory, unmapped on drop. Every access is volatile and
+/// bound-checked.
+pub struct MmioRegion {
+    /// The first mapped page.
+    base: VAddr,
+    nr_pages: usize,
+    /// The offset of the device memory in the first page.
+    offset: usize,
+    regs: RegBlock,
+}
+
+/// Map the `bsize` bytes of device memory at `paddr` with the caching mode
+/// `cache`, which should be `CacheMode::Uncached` for device registers.
+pub fn iomap(
+    paddr: PAddr,
+    bsize: usize,
+    cache: CacheMode,
+) -> Result<MmioRegion, IomapError> {
+    let offset = paddr.0 as usize % PAGE_SIZE;
+    let first_frame = PAddr(paddr.0 - offset as u64);
+    let map_bsize = offset.checked_add(bsize)
+        .filter(|_| bsize > 0)
+        .ok_or(IomapError::InvalidRange)?;
+    let nr_pages = align_up(map_bsize, PAGE_SIZE) / PAGE_SIZE;
+
+    let base = {
+        let mut next = NEXT_VADDR.lock();
+        let base = *next;
+        if (base - IOMAP_VA_START).0 + nr_pages * PAGE_SIZE > IOMAP_VA_SIZE {
+            return Err(IomapError::OutOfAddressSpace);
+        }
+        *next += nr_pages * PAGE_SIZE;
+        base
+    };
+
+    for i in 0..nr_pages {
+        let vaddr = base + i * PAGE_SIZE;
+        let frame = PAddr(first_frame.0 + (i * PAGE_SIZE) as u64);
+
+        if !unsafe { map_page(vaddr, frame, cache) } {
+            for mapped in 0..i {
+                unsafe { unmap_page(base + mapped * PAGE_SIZE); }
+            }
+            return Err(IomapError::MapFailed { paddr: frame });
+        }
+    }
+
+    Ok(MmioRegion {
+        base,
+        nr_pages,
+        offset,
+        regs: unsafe { RegBlock::new((base + offset).as_mut_ptr(), bsize) },
+    })
+}
+
+impl MmioRegion {
+    #[inline]
+    pub fn bsize(&self) -> usize {
+        self.regs.bsize()
+    }
+
+    /// The registers of the region, e.g. to log accesses or to access them in
+    /// another byte order.
+    #[inline]
+    pub fn regs(&self) -> &RegBlock {
+        &self.regs
+    }
+
+    #[inline]
+    pub fn read8(&self, offset: usize) -> u8 {
+        self.regs.read(offset)
+    }
+
+    #[inline]
+    pub fn read16(&self, offset: usize) -> u16 {
+        self.regs.read(offset)
+    }
+
+    #[inline]
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.regs.read(offset)
+    }
+
+    #[inline]
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.regs.read(offset)
+    }
+
+    #[inline]
+    pub fn write8(&self, offset: usize, value: u8) {
+        self.regs.write(offset, value)
+    }
+
+    #[inline]
+    pub fn write16(&self, offset: usize, value: u16) {
+        self.regs.write(offset, value)
+    }
+
+    #[inline]
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.regs.write(offset, value)
+    }
+
+    #[inline]
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.regs.write(offset, value)
+    }
+
+    /// Keep the region mapped forever and return its virtual address, for
+    /// memory accessed through raw pointers such as framebuffers.
+    pub fn leak(self) -> VAddr {
+        let vaddr = self.base + self.offset;
+        core::mem::forget(self);
+        vaddr
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        for i in 0..self.nr_pages {
+            unsafe { unmap_page(self.base + i * PAGE_SIZE); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use crate::arch::mem::{reset_memory, walk_mappings, MEMORY_MUTEX};
+    use super::*;
+
+    fn mapped_frames() -> Vec<u64> {
+        let mut frames = Vec::new();
+        walk_mappings(IOMAP_VA_START..(IOMAP_VA_START + IOMAP_VA_SIZE),
+                      |mapping| frames.push(mapping.paddr.0));
+        frames
+    }
+
+    #[test]
+    fn it_maps_and_unmaps_device_memory() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+
+        let region = iomap(PAddr(0x1010), 0x2000, CacheMode::Uncached).unwrap();
+        assert_eq!(region.bsize(), 0x2000);
+        assert_eq!(mapped_frames(), [0x1000, 0x2000, 0x3000]);
+
+        drop(region);
+        assert!(mapped_frames().is_empty());
+        assert!(matches!(iomap(PAddr(0x1000), 0, CacheMode::Uncached),
+                         Err(IomapError::InvalidRange)));
+    }
+}
+
+#[cfg(all(feature = "ktest", not(test)))]
+mod ktests {
+    use crate::ktest;
+    use crate::mem::frame::{allocate_frames, free_frames};
+    use super::*;
+
+    ktest! {
+        fn it_maps_frames_into_the_window() {
+            let paddr = allocate_frames().zero_mem().allocate().unwrap();
+            let lowmem = paddr.into_vaddr().as_mut_ptr::<u32>();
+
+            let region = iomap(paddr, PAGE_SIZE, CacheMode::WriteBack).unwrap();
+            unsafe { lowmem.write_volatile(0xdeadbeef); }
+            assert_eq!(region.read32(0), 0xdeadbeef);
+            region.write32(4, 0x12345678);
+            assert_eq!(unsafe { lowmem.add(1).read_volatile() }, 0x12345678);
+
+            drop(region);
+            unsafe { free_frames(paddr, 1); }
+        }
+    }
+}
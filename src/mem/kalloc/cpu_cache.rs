@@ -0,0 +1,164 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Per-CPU caches of small free blocks, sitting in front of the global
+//! allocator. Small allocations are rounded up to a size class; a freed block
+//! is kept in the current CPU's list for its class, to be handed out again
+//! without taking the global allocator's lock.
+
+use core::cell::RefCell;
+use core::ptr::NonNull;
+
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::task::cpu_local::CpuLocal;
+
+/// The size of each class, in bytes; allocations larger than the last class
+/// bypass the caches.
+const SIZE_CLASSES: [usize; NR_SIZE_CLASSES] = [16, 32, 64, 128, 256];
+const NR_SIZE_CLASSES: usize = 5;
+
+/// The maximum number of blocks each list holds; past that, freed blocks go
+/// back to the global allocator.
+const MAX_CACHED_BLOCKS: usize = 32;
+
+static CACHES: CpuLocal<RefCell<CpuCache>>
+    = CpuLocal::new([const { RefCell::new(CpuCache::new()) }; MAX_CPUS]);
+
+pub struct CpuCache {
+    lists: [FreeList; NR_SIZE_CLASSES],
+}
+
+#[derive(Copy, Clone)]
+struct FreeList {
+    head: Option<NonNull<FreeBlock>>,
+    len: usize,
+}
+
+/// A cached block, whose first word links to the next one in its list.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// The size class for an allocation of `bsize` bytes, `None` if it is too
+/// large to be cached.
+pub fn size_class(bsize: usize) -> Option<usize> {
+    SIZE_CLASSES.iter().position(|&class_bsize| bsize <= class_bsize)
+}
+
+/// The number of bytes to actually allocate for blocks of `class`.
+pub fn class_bsize(class: usize) -> usize {
+    SIZE_CLASSES[class]
+}
+
+/// Take a block of `class` from the current CPU's cache, `None` if it is empty
+/// or already in use on this CPU.
+pub fn pop(class: usize) -> Option<NonNull<u8>> {
+    let cpu = current_cpu_index();
+    let mut cache = CACHES.get(&cpu).try_borrow_mut().ok()?;
+
+    cache.pop(class)
+}
+
+/// Put the free block `ptr` of `class` in the current CPU's cache; return
+/// `false` if it is full, in which case the caller must free the block.
+///
+/// # Safety #
+///
+/// `ptr` must point to a block allocated for `class` which is not in use
+/// anymore.
+pub unsafe fn push(class: usize, ptr: NonNull<u8>) -> bool {
+    let cpu = current_cpu_index();
+    let Ok(mut cache) = CACHES.get(&cpu).try_borrow_mut() else {
+        return false;
+    };
+
+    cache.push(class, ptr)
+}
+
+impl CpuCache {
+    pub const fn new() -> Self {
+        Self {
+            lists: [FreeList { head: None, len: 0 }; NR_SIZE_CLASSES],
+        }
+    }
+
+    fn pop(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let list = &mut self.lists[class];
+        let block = list.head?;
+
+        list.head = unsafe { block.as_ref() }.next;
+        list.len -= 1;
+
+        Some(block.cast())
+    }
+
+    unsafe fn push(&mut self, class: usize, ptr: NonNull<u8>) -> bool {
+        let list = &mut self.lists[class];
+        if list.len >= MAX_CACHED_BLOCKS {
+            return false;
+        }
+
+        let block = ptr.cast::<FreeBlock>();
+        block.as_ptr().write(FreeBlock { next: list.head });
+        list.head = Some(block);
+        list.len += 1;
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(16))]
+    struct Blocks([[u8; 16]; MAX_CACHED_BLOCKS + 1]);
+
+    #[test]
+    fn it_rounds_up_to_size_classes() {
+        assert_eq!(size_class(1), Some(0));
+        assert_eq!(size_class(16), Some(0));
+        assert_eq!(size_class(17), Some(1));
+        assert_eq!(size_class(256), Some(4));
+        assert_eq!(size_class(257), None);
+    }
+
+    #[test]
+    fn it_hands_out_the_last_freed_block_first() {
+        let mut blocks = Blocks([[0; 16]; MAX_CACHED_BLOCKS + 1]);
+        let mut cache = CpuCache::new();
+        let a = NonNull::from(&mut blocks.0[0]).cast();
+        let b = NonNull::from(&mut blocks.0[1]).cast();
+
+        assert_eq!(cache.pop(0), None);
+        unsafe {
+            assert!(cache.push(0, a));
+            assert!(cache.push(0, b));
+        }
+
+        assert_eq!(cache.pop(1), None);
+        assert_eq!(cache.pop(0), Some(b));
+        assert_eq!(cache.pop(0), Some(a));
+        assert_eq!(cache.pop(0), None);
+    }
+
+    #[test]
+    fn it_refuses_blocks_when_full() {
+        let mut blocks = Blocks([[0; 16]; MAX_CACHED_BLOCKS + 1]);
+        let mut cache = CpuCache::new();
+
+        for block in &mut blocks.0[..MAX_CACHED_BLOCKS] {
+            assert!(unsafe { cache.push(0, NonNull::from(block).cast()) });
+        }
+
+        let last = NonNull::from(&mut blocks.0[MAX_CACHED_BLOCKS]).cast();
+        assert!(!unsafe { cache.push(0, last) });
+    }
+}
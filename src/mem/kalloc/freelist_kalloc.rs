@@ -19,14 +19,45 @@ use crate::misc::align_up;
 const MIN_BLOCK_SIZE: usize = 8;
 const BLOCK_MAGIC: u16 = 0xcafe;
 
+/// The size of the guard areas placed before and after user data in debug
+/// mode; it keeps the user pointer aligned like the block header.
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+
+/// The pattern freed memory is filled with in debug mode, `0xdead` in memory
+/// order.
+const POISON_PATTERN: [u8; 2] = [0xde, 0xad];
+
+/// A free span of whole pages is only given back to the backend if it is at
+/// least this many pages long; smaller spans are kept for future allocations.
+const TRIM_THRESHOLD_PAGES: usize = 16;
+
+/// The number of free pages kept at the start of a span being given back, so
+/// that allocating right after a trim doesn't immediately request pages again.
+const TRIM_SLACK_PAGES: usize = 4;
+
 pub struct FreelistAllocator<Backend: AllocatorBackend> {
     free_list: Option<NonNull<Block>>,
     last_block: Option<NonNull<Block>>,
+
+    /// In debug mode, each user block is surrounded by red zones, checked on
+    /// deallocation to detect overflows; and free memory is poisoned, checked
+    /// on allocation to detect writes after free.
+    debug: bool,
+
     _marker: PhantomData<Backend>,
 }
 
 pub trait AllocatorBackend {
     fn new_pages(nr_pages: usize) -> Option<NonNull<()>>;
+
+    /// Give back `nr_pages` pages starting at `ptr`, within a run previously
+    /// obtained from `new_pages()`.
+    ///
+    /// # Safety #
+    ///
+    /// The pages must not be in use anymore.
+    unsafe fn free_pages(ptr: NonNull<()>, nr_pages: usize);
 }
 
 unsafe impl<B: AllocatorBackend> Send for FreelistAllocator<B> {}
@@ -61,6 +92,10 @@ struct Block {
     /// `BLOCK_MAGIC`.
     magic: u16,
 
+    /// The number of bytes the user requested; only valid for allocated blocks
+    /// in debug mode.
+    req_bsize: usize,
+
     _phantom: PhantomData<Block>,
 }
 
@@ -97,23 +132,53 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
         FreelistAllocator {
             free_list: None,
             last_block: None,
+            debug: false,
             _marker: PhantomData,
         }
     }
 
+    /// Create an allocator in debug mode, detecting heap corruptions at the
+    /// cost of speed and memory.
+    pub const fn new_debug() -> Self {
+        FreelistAllocator {
+            debug: true,
+            ..Self::new()
+        }
+    }
+
     pub unsafe fn alloc(&mut self, bsize: usize) -> Option<NonNull<u8>> {
         if bsize == 0 {
             return None;
         }
 
-        let block = self.get_free_block(bsize)?;
-        self.cut_free_block(block, bsize);
+        let block_bsize = if self.debug {
+            bsize + 2 * REDZONE_SIZE
+        } else {
+            bsize
+        };
+
+        let mut block = self.get_free_block(block_bsize)?;
+        if self.debug {
+            check_poison(unsafe { block.as_ref() }, block_bsize);
+        }
+        self.cut_free_block(block, block_bsize);
         self.mark_free_block_allocated(block);
 
-        Some(unsafe { block.as_ref() }.as_user_ptr())
+        let block = unsafe { block.as_mut() };
+        if self.debug {
+            block.req_bsize = bsize;
+            fill_redzones(block);
+            let user_ptr = block.as_user_ptr().as_ptr().add(REDZONE_SIZE);
+            return Some(NonNull::new_unchecked(user_ptr));
+        }
+
+        Some(block.as_user_ptr())
     }
 
-    // TODO: do something smarter
+    /// Resize the block at `ptr` to `bsize` bytes. The block is shrunk in
+    /// place, giving its tail back as a free block; it is grown in place if the
+    /// block right after it is free and large enough. Otherwise, a new block is
+    /// allocated, the data copied, and the old block freed.
     pub unsafe fn realloc(
         &mut self,
         ptr: *mut u8,
@@ -123,15 +188,42 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
             return self.alloc(bsize);
         }
 
-        let block = unsafe { &mut *(ptr as *mut Block).sub(1) };
+        let block = unsafe { &mut *self.block_of(ptr) };
         assert_eq!(block.magic, BLOCK_MAGIC,
                    "kalloc: realloc(): invalid block magic, tried to realloc an invalid address");
         assert!(!block.is_free(), "kalloc: realloc(): use-after-free");
 
+        let old_bsize = if self.debug {
+            check_redzones(block);
+            block.req_bsize
+        } else {
+            block.bsize
+        };
+
+        if bsize == 0 {
+            return None;
+        }
+
+        let block_bsize = if self.debug {
+            bsize + 2 * REDZONE_SIZE
+        } else {
+            bsize
+        };
+
+        if block_bsize <= block.bsize || self.grow_block(block, block_bsize) {
+            self.shrink_block(block, block_bsize);
+            if self.debug {
+                block.req_bsize = bsize;
+                fill_redzones(block);
+            }
+            return Some(NonNull::new_unchecked(ptr));
+        }
+
         let new = self.alloc(bsize)?;
-        let copy_size = min(block.bsize, bsize);
+        let copy_size = min(old_bsize, bsize);
 
         copy_nonoverlapping(ptr, new.as_ptr(), copy_size);
+        self.dealloc(ptr);
 
         Some(new)
     }
@@ -141,11 +233,22 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
             return;
         }
 
-        let block = unsafe { &mut *(ptr as *mut Block).sub(1) };
+        let block = unsafe { &mut *self.block_of(ptr) };
         assert_eq!(block.magic, BLOCK_MAGIC,
                    "kalloc: dealloc(): invalid block magic, tried to free an invalid address");
         assert!(!block.is_free(), "kalloc: dealloc(): double-free");
 
+        if self.debug {
+            check_redzones(block);
+        }
+
+        let free = self.release_block(block);
+        self.trim_free_block(free);
+    }
+
+    /// Give an allocated block back to the free list, merging it with its free
+    /// neighbors; return the resulting free block.
+    fn release_block(&mut self, block: &mut Block) -> NonNull<Block> {
         let mut has_merged = false;
 
         // First, try to find a free block immediately after to extend into.
@@ -156,8 +259,11 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
             has_merged = true;
         }
 
-        // Try to find a free block immediately before to extend.
-        if let Some(mut prev) = block.prev {
+        // Try to find a free block immediately before to extend; the previous
+        // block may be from another run of pages, after a hole.
+        let block_addr = block as *const Block as *const u8;
+        if let Some(mut prev) = block.prev
+            .filter(|prev| unsafe { prev.as_ref() }.end_addr() == block_addr) {
             let prev = unsafe { prev.as_mut() };
             if prev.is_free() {
                 if let Some(mut next) = block.next {
@@ -165,24 +271,148 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
                 }
                 prev.bsize += size_of::<Block>() + block.bsize;
                 prev.next = block.next;
-                prev.next_free = block.next_free;
+                // Otherwise, `prev` already links to the next free block.
+                if has_merged {
+                    prev.next_free = block.next_free;
+                }
+                if self.last_block == Some(block.into()) {
+                    self.last_block = Some(prev.into());
+                }
                 block.magic = 0xdead;
-                return;
+                if self.debug {
+                    poison(prev);
+                }
+                return prev.into();
             }
         }
 
+        // Merging with the next block already linked `block` into the free
+        // list, in place of the next block.
+        if has_merged {
+            return block.into();
+        }
+
         if let Some(mut prev_free) = self.prev_free_block(block.into()) {
             let prev_free = unsafe { prev_free.as_mut() };
             block.next_free = prev_free.next_free;
             prev_free.next_free = Some(block.into());
         } else {
-            if !has_merged {
-                block.next_free = self.free_list;
-            }
+            block.next_free = self.free_list;
             self.free_list = Some(block.into());
         }
 
         block.flags &= !BLOCK_ALLOCATED_BIT;
+        if self.debug {
+            poison(block);
+        }
+
+        block.into()
+    }
+
+    /// Give the whole pages inside the free `block` back to the backend if
+    /// there are enough of them. The block keeps its header and a few pages of
+    /// slack; what remains past the returned pages becomes a new free block,
+    /// starting a new run.
+    fn trim_free_block(&mut self, mut block: NonNull<Block>) {
+        let block = unsafe { block.as_mut() };
+        let start = block as *mut Block as usize;
+        let end = block.end_addr() as usize;
+
+        let span_start = align_up(start + size_of::<Block>() + MIN_BLOCK_SIZE,
+                                  4096)
+            + TRIM_SLACK_PAGES * 4096;
+        let mut span_end = end & !4095;
+        if span_end != end && end - span_end < size_of::<Block>() + MIN_BLOCK_SIZE {
+            span_end = span_end.saturating_sub(4096);
+        }
+        if span_end < span_start
+            || (span_end - span_start) >> 12 < TRIM_THRESHOLD_PAGES {
+            return;
+        }
+
+        if span_end != end {
+            let tail = unsafe { &mut *(span_end as *mut Block) };
+            tail.prev = Some(block.into());
+            tail.next = block.next;
+            tail.next_free = block.next_free;
+            tail.bsize = end - span_end - size_of::<Block>();
+            tail.flags = 0;
+            tail.magic = BLOCK_MAGIC;
+
+            if let Some(mut next) = block.next {
+                unsafe { next.as_mut() }.prev = Some(tail.into());
+            }
+            block.next = Some(tail.into());
+            block.next_free = Some(tail.into());
+            if self.last_block == Some(block.into()) {
+                self.last_block = Some(tail.into());
+            }
+        }
+
+        block.bsize = span_start - start - size_of::<Block>();
+
+        unsafe {
+            Backend::free_pages(
+                NonNull::new_unchecked(span_start as *mut ()),
+                (span_end - span_start) >> 12,
+            );
+        }
+    }
+
+    /// Try to extend the allocated `block` to at least `bsize` bytes by
+    /// absorbing the free block right after it; returns `false` if there is no
+    /// such block or if it is too small.
+    fn grow_block(&mut self, block: &mut Block, bsize: usize) -> bool {
+        let Some(mut next) = self.direct_next_free_block(block.into()) else {
+            return false;
+        };
+        let next_bsize = unsafe { next.as_ref() }.bsize;
+        if block.bsize + size_of::<Block>() + next_bsize < bsize {
+            return false;
+        }
+
+        if self.debug {
+            check_poison(unsafe { next.as_ref() }, next_bsize);
+        }
+        self.mark_free_block_allocated(next);
+
+        let next = unsafe { next.as_mut() };
+        block.bsize += size_of::<Block>() + next.bsize;
+        block.next = next.next;
+        if let Some(mut next_next) = next.next {
+            unsafe { next_next.as_mut() }.prev = Some(block.into());
+        }
+        if self.last_block == Some(next.into()) {
+            self.last_block = Some(block.into());
+        }
+        next.magic = 0xdead;
+
+        true
+    }
+
+    /// Shrink the allocated `block` to `bsize` bytes, giving the tail back as a
+    /// free block if it is large enough to make one.
+    fn shrink_block(&mut self, block: &mut Block, bsize: usize) {
+        let old_next = block.next;
+        self.cut_free_block(block.into(), bsize);
+
+        if block.next != old_next {
+            let tail = unsafe { block.next.unwrap().as_mut() };
+            tail.flags |= BLOCK_ALLOCATED_BIT;
+            let free = self.release_block(tail);
+            self.trim_free_block(free);
+        }
+    }
+
+    /// Return the header of the block whose user pointer is `ptr`.
+    fn block_of(&self, ptr: *mut u8) -> *mut Block {
+        let ptr = if self.debug {
+            ptr.wrapping_sub(REDZONE_SIZE)
+        } else {
+            ptr
+        };
+
+        (ptr as *mut Block).wrapping_sub(1)
     }
 
     /// Perform sanity check to ensure verifiable invariants are still valid.
@@ -255,6 +485,9 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
         }
 
         left.flags &= !BLOCK_ALLOCATED_BIT;
+        if self.debug {
+            poison(left);
+        }
         info!("leaving");
         //self.self_check();
         info!("left");
@@ -309,17 +542,26 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
 
             if last_free.end_addr() == block as *const u8 {
                 last_free.bsize += ext_bsize;
+                if self.debug {
+                    poison(last_free);
+                }
                 return Some(last_free.into());
             }
         }
 
+        // Blocks are linked in the order their runs were obtained, which is not
+        // necessarily the order of their addresses: the new run goes last, and
+        // it is only merged with the blocks it is adjacent to in memory.
         let block = unsafe { &mut *block };
-        block.prev = self.last_block; // FIXME: not always true
+        block.prev = self.last_block;
         block.next = None;
         block.next_free = None;
         block.bsize = ext_bsize - size_of::<Block>();
         block.flags = 0;
         block.magic = BLOCK_MAGIC;
+        if self.debug {
+            poison(block);
+        }
 
         if let Some(mut prev) = block.prev {
             unsafe { prev.as_mut() }.next = Some(block.into());
@@ -335,7 +577,6 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
             self.free_list = Some(block_ptr);
         }
 
-        // FIXME: just because we allocated a frame doesn't mean it's the last one
         self.last_block = Some(block_ptr);
 
         Some(block_ptr)
@@ -466,6 +707,64 @@ impl<Backend: AllocatorBackend> FreelistAllocator<Backend> {
     }
 }
 
+/// Fill the whole user area of a free block with the poison pattern.
+fn poison(block: &mut Block) {
+    let area = block.as_user_ptr().as_ptr();
+
+    for i in 0..block.bsize {
+        unsafe { area.add(i).write(POISON_PATTERN[i % 2]); }
+    }
+}
+
+/// Check that the first `bsize` bytes of a free block, that are about to be
+/// allocated, are still poisoned: they must not have been written since freed.
+fn check_poison(block: &Block, bsize: usize) {
+    let area = block.as_user_ptr().as_ptr();
+
+    for i in 0..min(bsize, block.bsize) {
+        let byte = unsafe { area.add(i).read() };
+        if byte != POISON_PATTERN[i % 2] {
+            panic!("kalloc: heap corruption: free block at {:p} was written \
+                    at offset {} (found {:#04x}), use-after-free?",
+                   area, i, byte);
+        }
+    }
+}
+
+/// Fill the red zones of an allocated block, before and after the user data.
+fn fill_redzones(block: &mut Block) {
+    let area = block.as_user_ptr().as_ptr();
+
+    for i in redzone_offsets(block) {
+        unsafe { area.add(i).write(REDZONE_BYTE); }
+    }
+}
+
+fn check_redzones(block: &Block) {
+    let area = block.as_user_ptr().as_ptr();
+
+    for i in redzone_offsets(block) {
+        let byte = unsafe { area.add(i).read() };
+        if byte != REDZONE_BYTE {
+            let (zone, offset) = if i < REDZONE_SIZE {
+                ("underflow", i as isize - REDZONE_SIZE as isize)
+            } else {
+                ("overflow", (i - REDZONE_SIZE) as isize)
+            };
+            panic!("kalloc: heap corruption: red zone of block at {:p} \
+                    ({} bytes) overwritten at offset {} (found {:#04x}), \
+                    buffer {}?",
+                   unsafe { area.add(REDZONE_SIZE) }, block.req_bsize,
+                   offset, byte, zone);
+        }
+    }
+}
+
+/// The offsets of the red zones' bytes from the start of a block's user area.
+fn redzone_offsets(block: &Block) -> impl Iterator<Item = usize> {
+    (0..REDZONE_SIZE).chain((REDZONE_SIZE + block.req_bsize)..block.bsize)
+}
+
 struct FreeBlockIter<'a> {
     curr_block: Option<ptr::NonNull<Block>>,
     _phantom: PhantomData<&'a Block>,
@@ -534,6 +833,8 @@ mod tests {
     use core::slice;
     use crate::arch::test::export::mem::{MEMORY, MEMORY_MUTEX, reset_memory};
     use crate::arch::test::frame::reset_frame_allocator;
+    use crate::arch::test::export::mem::NR_PHYS_FRAMES;
+    use crate::mem::frame::{allocate_frames, FRAME_ALLOCATOR};
     use crate::mem::kalloc::FrameAllocatorBackend;
     use crate::mem::kalloc::freelist_kalloc::{Block};
     use crate::mem::kalloc::freelist_kalloc::FreelistAllocator;
@@ -598,7 +899,17 @@ mod tests {
 
     #[test]
     fn it_doesnt_extend_trailing_free_blocks_across_page_holes() {
-        todo!()
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            do_alloc(&mut alloc, 3000, BSZ);
+            make_page_hole();
+            do_alloc(&mut alloc, 3000, 2*4096 + BSZ);
+            assert_eq!(alloc.count_blocks(), 4);
+        }
     }
 
     #[test]
@@ -731,6 +1042,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_deallocates_and_merge_with_next_after_a_free_block() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let first = do_alloc(&mut alloc, 256, 1*BSZ + 0*256);
+            do_alloc(&mut alloc, 256, 2*BSZ + 1*256);
+            let last = do_alloc(&mut alloc, 256, 3*BSZ + 2*256);
+            assert_eq!(alloc.count_blocks(), 4);
+
+            // [free][used][last][free]
+            alloc.dealloc(first.as_ptr());
+            alloc.self_check();
+            alloc.dealloc(last.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 3);
+
+            do_alloc(&mut alloc, 256, 1*BSZ + 0*256);
+            do_alloc(&mut alloc, 256, 3*BSZ + 2*256);
+        }
+    }
+
     #[test]
     fn it_allocates_after_a_free() {
         let _lock = MEMORY_MUTEX.lock();
@@ -791,14 +1127,295 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_poisons_freed_memory_in_debug_mode() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new_debug();
+        unsafe {
+            let addr = alloc.alloc(100).unwrap();
+            slice::from_raw_parts_mut(addr.as_ptr(), 100).fill(0x42);
+            alloc.dealloc(addr.as_ptr());
+            alloc.self_check();
+
+            let slice = slice::from_raw_parts(addr.as_ptr(), 100);
+            assert!(slice.chunks(2).all(|w| w == [0xde, 0xad]));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer overflow")]
+    fn it_detects_overflows_in_debug_mode() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new_debug();
+        unsafe {
+            let addr = alloc.alloc(10).unwrap();
+            addr.as_ptr().add(10).write(0);
+            alloc.dealloc(addr.as_ptr());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "use-after-free")]
+    fn it_detects_writes_after_free_in_debug_mode() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new_debug();
+        unsafe {
+            let addr = alloc.alloc(64).unwrap();
+            alloc.dealloc(addr.as_ptr());
+            addr.as_ptr().write(0);
+            alloc.alloc(64).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_shrinks_in_place() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 1024, BSZ);
+            do_alloc(&mut alloc, 256, 2*BSZ + 1024);
+
+            let new = alloc.realloc(addr.as_ptr(), 256).unwrap();
+            alloc.self_check();
+            assert_eq!(new, addr);
+            assert_eq!(alloc.count_blocks(), 4);
+
+            do_alloc(&mut alloc, 256, 2*BSZ + 256);
+        }
+    }
+
+    #[test]
+    fn it_shrinks_in_place_after_a_free_block() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let first = do_alloc(&mut alloc, 256, BSZ);
+            do_alloc(&mut alloc, 256, 2*BSZ + 256);
+            let addr = do_alloc(&mut alloc, 1024, 3*BSZ + 2*256);
+            alloc.dealloc(first.as_ptr());
+            alloc.self_check();
+
+            // The tail given back merges with the trailing free block.
+            let new = alloc.realloc(addr.as_ptr(), 256).unwrap();
+            alloc.self_check();
+            assert_eq!(new, addr);
+            assert_eq!(alloc.count_blocks(), 4);
+
+            do_alloc(&mut alloc, 256, BSZ);
+            do_alloc(&mut alloc, 256, 4*BSZ + 3*256);
+        }
+    }
+
+    #[test]
+    fn it_grows_in_place_into_the_next_free_block() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 256, BSZ);
+            slice::from_raw_parts_mut(addr.as_ptr(), 256).fill(0x42);
+
+            let new = alloc.realloc(addr.as_ptr(), 1024).unwrap();
+            alloc.self_check();
+            assert_eq!(new, addr);
+            assert!(slice::from_raw_parts(new.as_ptr(), 256)
+                .iter().all(|&b| b == 0x42));
+            assert_eq!(alloc.count_blocks(), 2);
+
+            do_alloc(&mut alloc, 256, 2*BSZ + 1024);
+        }
+    }
+
+    #[test]
+    fn it_moves_blocks_that_cant_grow_in_place() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 256, BSZ);
+            do_alloc(&mut alloc, 256, 2*BSZ + 256);
+            slice::from_raw_parts_mut(addr.as_ptr(), 256).fill(0x42);
+
+            let new = alloc.realloc(addr.as_ptr(), 1024).unwrap();
+            alloc.self_check();
+            assert_eq!(new.as_ptr(), MEMORY.0.as_mut_ptr().add(3*BSZ + 512));
+            assert!(slice::from_raw_parts(new.as_ptr(), 256)
+                .iter().all(|&b| b == 0x42));
+
+            do_alloc(&mut alloc, 256, BSZ);
+        }
+    }
+
+    #[test]
+    fn it_keeps_red_zones_when_reallocating_in_debug_mode() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new_debug();
+        unsafe {
+            let addr = alloc.alloc(100).unwrap();
+            let addr = alloc.realloc(addr.as_ptr(), 500).unwrap();
+            slice::from_raw_parts_mut(addr.as_ptr(), 500).fill(0x42);
+            let addr = alloc.realloc(addr.as_ptr(), 50).unwrap();
+            slice::from_raw_parts_mut(addr.as_ptr(), 50).fill(0x42);
+            alloc.dealloc(addr.as_ptr());
+            alloc.self_check();
+        }
+    }
+
     #[test]
     fn it_doesnt_merge_with_prev_across_page_holes() {
-        unimplemented!()
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let first = do_alloc(&mut alloc, 4096 - BSZ, BSZ);
+            make_page_hole();
+            let second = do_alloc(&mut alloc, 4096 - BSZ, 2*4096 + BSZ);
+            assert_eq!(alloc.count_blocks(), 2);
+
+            alloc.dealloc(first.as_ptr());
+            alloc.self_check();
+            alloc.dealloc(second.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 2);
+
+            do_alloc(&mut alloc, 4096 - BSZ, BSZ);
+            do_alloc(&mut alloc, 4096 - BSZ, 2*4096 + BSZ);
+        }
     }
 
     #[test]
     fn it_doesnt_merge_with_next_across_page_holes() {
-        unimplemented!()
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let first = do_alloc(&mut alloc, 4096 - BSZ, BSZ);
+            make_page_hole();
+            let second = do_alloc(&mut alloc, 4096 - BSZ, 2*4096 + BSZ);
+
+            alloc.dealloc(second.as_ptr());
+            alloc.self_check();
+            alloc.dealloc(first.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 2);
+
+            do_alloc(&mut alloc, 4096 - BSZ, BSZ);
+            do_alloc(&mut alloc, 4096 - BSZ, 2*4096 + BSZ);
+        }
+    }
+
+    #[test]
+    fn it_merges_contiguous_page_runs() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let first = do_alloc(&mut alloc, 4096 - BSZ, BSZ);
+            let second = do_alloc(&mut alloc, 4096 - BSZ, 4096 + BSZ);
+            let third = do_alloc(&mut alloc, 4096 - BSZ, 2*4096 + BSZ);
+            assert_eq!(alloc.count_blocks(), 3);
+
+            alloc.dealloc(third.as_ptr());
+            alloc.dealloc(first.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 3);
+
+            alloc.dealloc(second.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 1);
+        }
+    }
+
+    #[test]
+    fn it_gives_large_free_spans_back_to_the_backend() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 24*4096 - BSZ, BSZ);
+            assert_eq!(nr_free_frames(), NR_PHYS_FRAMES - 24);
+
+            alloc.dealloc(addr.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 1);
+            assert_eq!(nr_free_frames(), NR_PHYS_FRAMES - 5);
+
+            do_alloc(&mut alloc, 5*4096 - BSZ, BSZ);
+            assert_eq!(nr_free_frames(), NR_PHYS_FRAMES - 5);
+        }
+    }
+
+    #[test]
+    fn it_keeps_small_free_spans() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 8*4096 - BSZ, BSZ);
+            alloc.dealloc(addr.as_ptr());
+            alloc.self_check();
+            assert_eq!(nr_free_frames(), NR_PHYS_FRAMES - 8);
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_tail_of_a_trimmed_block() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let mut alloc = KernelAllocator::new();
+        unsafe {
+            let addr = do_alloc(&mut alloc, 24*4096 - BSZ - 512, BSZ);
+            do_alloc(&mut alloc, 256, 24*4096 - 512 + BSZ);
+
+            alloc.dealloc(addr.as_ptr());
+            alloc.self_check();
+            assert_eq!(alloc.count_blocks(), 4);
+            assert_eq!(nr_free_frames(), NR_PHYS_FRAMES - 6);
+        }
+    }
+
+    fn nr_free_frames() -> usize {
+        FRAME_ALLOCATOR.lock().as_ref().unwrap().stats().free
+    }
+
+    /// Take the next physical frame away from the heap, so that the next pages
+    /// the backend hands out are not contiguous with the previous ones.
+    fn make_page_hole() {
+        allocate_frames().allocate().expect("couldn't allocate a frame");
     }
 
     fn do_alloc(
@@ -0,0 +1,138 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The guard page allocator, a debug mode of the kernel allocator enabled by
+//! the `guard-alloc` feature. Every allocation of at least `MIN_BSIZE` bytes
+//! gets pages of its own in a dedicated virtual window, ending right before an
+//! unmapped guard page, and is unmapped when freed. Since the window's
+//! addresses are never reused, overflows past the end of a block and uses
+//! after free fault right away, on the exact address.
+
+use crate::arch::mem::{map_page, unmap_page, GUARD_VA_SIZE, GUARD_VA_START,
+                       PAGE_SIZE};
+use crate::mem::frame::{allocate_frames, free_frames};
+use crate::mem::{CacheMode, VAddr};
+use crate::misc::align_up;
+use crate::sync::Spinlock;
+
+/// The size from which allocations are guarded; smaller ones go to the
+/// regular allocator.
+pub const MIN_BSIZE: usize = 128;
+
+/// The alignment of the guarded blocks, the one the kernel allocator
+/// guarantees.
+const ALIGN: usize = 16;
+
+/// The next free virtual address of the window.
+static NEXT_VADDR: Spinlock<VAddr> = Spinlock::new(GUARD_VA_START);
+
+/// Whether `ptr` was allocated by the guard page allocator.
+pub fn contains(ptr: *const u8) -> bool {
+    let vaddr = VAddr::from(ptr);
+    vaddr >= GUARD_VA_START && vaddr < GUARD_VA_START + GUARD_VA_SIZE
+}
+
+/// Allocate a block of `bsize` bytes on pages of its own, followed by a guard
+/// page; return null if there is no memory or virtual address left.
+pub fn alloc(bsize: usize) -> *mut u8 {
+    let nr_pages = nr_pages(bsize);
+
+    let base = {
+        let mut next = NEXT_VADDR.lock();
+        let base = *next;
+        let window_end = (base - GUARD_VA_START).0 + (nr_pages + 1) * PAGE_SIZE;
+        if window_end > GUARD_VA_SIZE {
+            return core::ptr::null_mut();
+        }
+        *next += (nr_pages + 1) * PAGE_SIZE;
+        base
+    };
+
+    let Some(frames) = allocate_frames().nr_frames(nr_pages).allocate() else {
+        return core::ptr::null_mut();
+    };
+
+    for i in 0..nr_pages {
+        let vaddr = base + i * PAGE_SIZE;
+        let frame = frames + (i * PAGE_SIZE) as u64;
+        if !unsafe { map_page(vaddr, frame, CacheMode::WriteBack) } {
+            unsafe {
+                for mapped in 0..i {
+                    unmap_page(base + mapped * PAGE_SIZE);
+                }
+                free_frames(frames, nr_pages);
+            }
+            return core::ptr::null_mut();
+        }
+    }
+
+    // The block ends where the guard page starts.
+    (base + (nr_pages * PAGE_SIZE - align_up(bsize, ALIGN))).as_mut_ptr()
+}
+
+/// Unmap the block of `bsize` bytes at `ptr` and free its frames; its virtual
+/// addresses are never used again.
+///
+/// # Safety #
+///
+/// `ptr` must have been returned by `alloc()` for `bsize` bytes, and not be
+/// freed already.
+pub unsafe fn dealloc(ptr: *mut u8, bsize: usize) {
+    let nr_pages = nr_pages(bsize);
+    let base = VAddr(ptr as usize & !(PAGE_SIZE - 1));
+    let frames = base.to_paddr()
+        .expect("kalloc: freeing an unmapped guarded block");
+
+    for i in 0..nr_pages {
+        unmap_page(base + i * PAGE_SIZE);
+    }
+    free_frames(frames, nr_pages);
+}
+
+fn nr_pages(bsize: usize) -> usize {
+    align_up(align_up(bsize, ALIGN), PAGE_SIZE) / PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::mem::{reset_memory, walk_mappings, MEMORY_MUTEX};
+    use crate::arch::test::frame::reset_frame_allocator;
+    use super::*;
+
+    fn nr_mapped_pages() -> usize {
+        let mut nr_pages = 0;
+        walk_mappings(GUARD_VA_START..(GUARD_VA_START + GUARD_VA_SIZE),
+                      |_| nr_pages += 1);
+        nr_pages
+    }
+
+    #[test]
+    fn it_ends_blocks_on_a_guard_page() {
+        let _lock = MEMORY_MUTEX.lock();
+        reset_memory();
+        reset_frame_allocator();
+
+        let a = alloc(5000);
+        let b = alloc(200);
+        assert!(contains(a) && contains(b));
+        assert_eq!(nr_mapped_pages(), 3);
+
+        let a_end = VAddr::from(a) + align_up(5000, ALIGN);
+        assert_eq!(a_end.0 % PAGE_SIZE, 0);
+        assert_eq!(VAddr::from(b).0 % PAGE_SIZE, PAGE_SIZE - 208);
+
+        unsafe {
+            dealloc(a, 5000);
+            dealloc(b, 200);
+        }
+        assert_eq!(nr_mapped_pages(), 0);
+        assert!(alloc(200) > b);
+    }
+}
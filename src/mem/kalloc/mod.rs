@@ -11,16 +11,32 @@
 mod freelist_kalloc;
 mod mimalloc;
 mod bump_kalloc;
+mod cpu_cache;
+#[cfg(feature = "guard-alloc")]
+mod guard;
+pub mod quarantine;
+pub mod tracker;
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::min;
 use core::ptr;
 use core::ptr::NonNull;
-use crate::error;
-use crate::mem::frame::allocate_frames;
+use crate::{error, trace_alloc, trace_dealloc};
+use crate::mem::{PAddr, VAddr};
+use crate::mem::frame::{allocate_frames, free_frames};
 use crate::mem::kalloc::bump_kalloc::BumpAllocator;
 use crate::mem::kalloc::freelist_kalloc::{AllocatorBackend};
+#[cfg(feature = "kasan")]
+use crate::mem::kasan;
 use crate::sync::Spinlock;
 
+/// The number of bytes appended to each allocation, as a red zone for the
+/// address sanitizer.
+#[cfg(feature = "kasan")]
+const REDZONE_BSIZE: usize = kasan::REDZONE_BSIZE;
+#[cfg(not(feature = "kasan"))]
+const REDZONE_BSIZE: usize = 0;
+
 pub struct KernelAllocatorWrapper(
     Spinlock<BumpAllocator<FrameAllocatorBackend>>
 );
@@ -34,6 +50,72 @@ impl AllocatorBackend for FrameAllocatorBackend {
             .map_lowmem()
             .map(|vaddr| NonNull::new(vaddr.as_mut_ptr()).unwrap())
     }
+
+    unsafe fn free_pages(ptr: NonNull<()>, nr_pages: usize) {
+        let paddr = PAddr::from_lowmem_vaddr(VAddr::from(ptr.as_ptr()))
+            .expect("heap pages must be in low memory");
+        free_frames(paddr, nr_pages);
+    }
+}
+
+impl KernelAllocatorWrapper {
+    /// Allocate from the global allocator, taking its lock.
+    fn global_alloc(&self, bsize: usize) -> *mut u8 {
+        self.0.lock().alloc(bsize)
+            .map(|p| p.as_ptr() as *mut u8)
+            .unwrap_or(ptr::null_mut())
+    }
+
+    /// Allocate a block of `bsize` bytes from the guard page allocator if it is
+    /// big enough, from the current CPU's cache if it is small enough, from
+    /// the global allocator otherwise.
+    fn alloc_block(&self, bsize: usize) -> *mut u8 {
+        #[cfg(feature = "guard-alloc")]
+        if bsize >= guard::MIN_BSIZE {
+            return guard::alloc(bsize);
+        }
+
+        let block_bsize = bsize + REDZONE_BSIZE;
+        let ptr = match cpu_cache::size_class(block_bsize) {
+            Some(class) => match cpu_cache::pop(class) {
+                Some(ptr) => ptr.as_ptr(),
+                None => self.global_alloc(cpu_cache::class_bsize(class)),
+            },
+            None => self.global_alloc(block_bsize),
+        };
+        #[cfg(feature = "kasan")]
+        if !ptr.is_null() {
+            kasan::on_alloc(ptr, bsize, Self::block_bsize(block_bsize));
+        }
+
+        ptr
+    }
+
+    /// The number of bytes actually taken by a block of `bsize` bytes, red zone
+    /// included, once rounded up to its size class.
+    fn block_bsize(bsize: usize) -> usize {
+        cpu_cache::size_class(bsize).map_or(bsize, cpu_cache::class_bsize)
+    }
+
+    /// Give the block of `bsize` bytes at `ptr` back to the current CPU's
+    /// cache, or to the global allocator.
+    unsafe fn release(&self, ptr: *mut u8, bsize: usize) {
+        let block_bsize = bsize + REDZONE_BSIZE;
+        if let (Some(class), Some(block)) = (cpu_cache::size_class(block_bsize),
+                                             NonNull::new(ptr)) {
+            if cpu_cache::push(class, block) {
+                return;
+            }
+        }
+
+        self.0.lock().dealloc(ptr as *mut ())
+    }
+
+    /// Whether the allocator is currently in use; allocating from a panic
+    /// handler in that case would either deadlock or corrupt the heap.
+    pub fn is_busy(&self) -> bool {
+        self.0.is_locked()
+    }
 }
 
 #[cfg_attr(not(test), global_allocator)]
@@ -47,26 +129,82 @@ unsafe impl GlobalAlloc for KernelAllocatorWrapper {
             return ptr::null_mut();
         }
 
-        self.0.lock().alloc(layout.size())
-            .map(|p| p.as_ptr() as *mut u8)
-            .unwrap_or(ptr::null_mut())
+        let ptr = self.alloc_block(layout.size());
+        tracker::track(ptr, layout.size());
+        trace_alloc!(ptr, layout.size());
+
+        ptr
     }
 
     #[inline]
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        self.0.lock().dealloc(ptr as *mut ())
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        tracker::untrack(ptr);
+        trace_dealloc!(ptr, layout.size());
+
+        #[cfg(feature = "guard-alloc")]
+        if guard::contains(ptr) {
+            guard::dealloc(ptr, layout.size());
+            return;
+        }
+
+        #[cfg(feature = "kasan")]
+        kasan::on_free(ptr,
+                       Self::block_bsize(layout.size() + REDZONE_BSIZE));
+
+        for (block, bsize) in quarantine::quarantine(ptr, layout.size()) {
+            self.release(block, bsize);
+        }
     }
 
     #[inline]
     unsafe fn realloc(
         &self,
         ptr: *mut u8,
-        _layout: Layout,
+        layout: Layout,
         new_size: usize
     ) -> *mut u8 {
-        self.0.lock().realloc(ptr as *mut (), new_size)
+        // Cached blocks must have been allocated with their class's size, and
+        // guarded blocks have their own pages: they can't be resized by the
+        // global allocator.
+        let old_bsize = layout.size() + REDZONE_BSIZE;
+        let new_bsize = new_size + REDZONE_BSIZE;
+        let moves = cpu_cache::size_class(old_bsize).is_some()
+            || cpu_cache::size_class(new_bsize).is_some();
+        #[cfg(feature = "guard-alloc")]
+        let moves = moves || guard::contains(ptr)
+            || new_size >= guard::MIN_BSIZE;
+
+        if moves {
+            let new_layout = Layout::from_size_align_unchecked(new_size,
+                                                               layout.align());
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                let copy_bsize = min(layout.size(), new_size);
+                #[cfg(feature = "kasan")]
+                kasan::check_read(ptr, copy_bsize);
+                ptr::copy_nonoverlapping(ptr, new_ptr, copy_bsize);
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+
+        let new_ptr = self.0.lock().realloc(ptr as *mut (), new_bsize)
             .map(|p| p.as_ptr() as *mut u8)
-            .unwrap_or(ptr::null_mut())
+            .unwrap_or(ptr::null_mut());
+        if !new_ptr.is_null() {
+            tracker::untrack(ptr);
+            tracker::track(new_ptr, new_size);
+
+            #[cfg(feature = "kasan")]
+            {
+                if new_ptr != ptr {
+                    kasan::on_free(ptr, old_bsize);
+                }
+                kasan::on_alloc(new_ptr, new_size, new_bsize);
+            }
+        }
+
+        new_ptr
     }
 }
 
@@ -75,3 +213,32 @@ unsafe impl GlobalAlloc for KernelAllocatorWrapper {
 fn allocator_error(layout: Layout) -> ! {
     panic!("Kernel allocator failed: {:?}", layout)
 }
+
+#[cfg(all(feature = "ktest", not(test)))]
+mod ktests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use crate::ktest;
+
+    ktest! {
+        fn it_keeps_blocks_intact_across_frees() {
+            let sizes = [8, 24, 100, 1000, 4096, 5000, 12_000];
+            let mut blocks: Vec<Vec<u8>> = (0..64)
+                .map(|i| vec![i as u8; sizes[i % sizes.len()]])
+                .collect();
+
+            // Free every other block, then fill the holes with new ones.
+            for i in (0..blocks.len()).step_by(2) {
+                blocks[i] = Vec::new();
+            }
+            for i in (0..blocks.len()).step_by(2) {
+                blocks[i] = vec![i as u8; sizes[(i + 3) % sizes.len()]];
+            }
+
+            for (i, block) in blocks.iter().enumerate() {
+                assert!(block.iter().all(|&b| b == i as u8),
+                        "block {i} was corrupted");
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The quarantine of freed heap blocks. When enabled with the `quarantine=KiB`
+//! boot option, freed blocks are filled with poison and held back for a while
+//! instead of being reused right away, up to that many KiB: a use after free
+//! then reads poison rather than another allocation's data, and a write after
+//! free is caught when the block leaves the quarantine with its poison
+//! altered. With the `kasan` feature, the quarantined blocks stay poisoned in
+//! the shadow map, so that checked accesses to them are reported.
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::mem::VAddr;
+use crate::sync::Spinlock;
+
+/// The maximum number of blocks held in quarantine, whatever their size.
+const MAX_BLOCKS: usize = 256;
+
+/// The maximum number of blocks released from quarantine at once, to make room
+/// for a new one.
+pub const MAX_RELEASED: usize = 8;
+
+/// The byte quarantined blocks are filled with.
+const POISON_BYTE: u8 = 0x6b;
+
+/// The maximum number of bytes held in quarantine, 0 if disabled.
+static LIMIT_BSIZE: AtomicUsize = AtomicUsize::new(0);
+
+static QUARANTINE: Spinlock<Quarantine> = Spinlock::new(Quarantine::new());
+
+/// A quarantined block: its address and size.
+type Block = (usize, usize);
+
+struct Quarantine {
+    /// A ring of the quarantined blocks, the oldest at `head`.
+    blocks: [Block; MAX_BLOCKS],
+    head: usize,
+    len: usize,
+    /// The total size of the quarantined blocks.
+    bsize: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            blocks: [(0, 0); MAX_BLOCKS],
+            head: 0,
+            len: 0,
+            bsize: 0,
+        }
+    }
+
+    /// Add `block`, releasing the oldest blocks to stay under `limit_bsize`
+    /// bytes; return the released blocks, or `block` itself if it is too big.
+    fn push(
+        &mut self,
+        block: Block,
+        limit_bsize: usize,
+    ) -> ArrayVec<Block, MAX_RELEASED> {
+        let mut released = ArrayVec::new();
+        if block.1 > limit_bsize {
+            released.push(block);
+            return released;
+        }
+
+        while self.len > 0 && !released.is_full()
+            && (self.len == MAX_BLOCKS || self.bsize + block.1 > limit_bsize) {
+            released.push(self.pop());
+        }
+
+        self.blocks[(self.head + self.len) % MAX_BLOCKS] = block;
+        self.len += 1;
+        self.bsize += block.1;
+
+        released
+    }
+
+    fn pop(&mut self) -> Block {
+        let block = self.blocks[self.head];
+        self.head = (self.head + 1) % MAX_BLOCKS;
+        self.len -= 1;
+        self.bsize -= block.1;
+        block
+    }
+}
+
+/// Set the maximum number of bytes held in quarantine, 0 to disable it; the
+/// blocks in excess are released on the next frees.
+pub fn set_limit_bsize(bsize: usize) {
+    LIMIT_BSIZE.store(bsize, Ordering::SeqCst);
+}
+
+/// Put the freed block of `bsize` bytes at `ptr` in quarantine, filling it
+/// with poison; return the blocks to actually free: the oldest ones released
+/// to make room, checked for writes after free, or `ptr` itself if the
+/// quarantine is disabled or the block is too big.
+///
+/// # Safety #
+///
+/// The block must be freed, and not used anymore.
+pub unsafe fn quarantine(
+    ptr: *mut u8,
+    bsize: usize,
+) -> ArrayVec<(*mut u8, usize), MAX_RELEASED> {
+    let limit_bsize = LIMIT_BSIZE.load(Ordering::Relaxed);
+    if limit_bsize == 0 || bsize > limit_bsize {
+        return [(ptr, bsize)].into_iter().collect();
+    }
+
+    ptr.write_bytes(POISON_BYTE, bsize);
+    let released = QUARANTINE.lock().push((ptr as usize, bsize), limit_bsize);
+
+    released.into_iter()
+        .map(|(addr, bsize)| {
+            let block = addr as *mut u8;
+            check_poison(slice::from_raw_parts(block, bsize), block);
+            (block, bsize)
+        })
+        .collect()
+}
+
+fn check_poison(block: &[u8], ptr: *const u8) {
+    if let Some(offset) = block.iter().position(|&b| b != POISON_BYTE) {
+        panic!("kalloc: block {:?} of {} bytes was written to after free, at \
+                offset {offset}", VAddr::from(ptr), block.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_releases_the_oldest_blocks_past_its_limit() {
+        let mut quarantine = Quarantine::new();
+
+        assert!(quarantine.push((0x1000, 100), 256).is_empty());
+        assert!(quarantine.push((0x2000, 100), 256).is_empty());
+        assert_eq!(quarantine.push((0x3000, 100), 256).as_slice(),
+                   [(0x1000, 100)]);
+        assert_eq!(quarantine.push((0x4000, 300), 256).as_slice(),
+                   [(0x4000, 300)]);
+        assert_eq!(quarantine.push((0x5000, 250), 256).as_slice(),
+                   [(0x2000, 100), (0x3000, 100)]);
+        assert_eq!((quarantine.len, quarantine.bsize), (1, 250));
+    }
+
+    #[test]
+    fn it_caps_the_number_of_blocks() {
+        let mut quarantine = Quarantine::new();
+
+        for i in 0..MAX_BLOCKS {
+            assert!(quarantine.push((i, 1), usize::MAX).is_empty());
+        }
+        assert_eq!(quarantine.push((MAX_BLOCKS, 1), usize::MAX).as_slice(),
+                   [(0, 1)]);
+        assert_eq!(quarantine.len, MAX_BLOCKS);
+    }
+
+    #[test]
+    #[should_panic(expected = "written to after free, at offset 3")]
+    fn it_detects_writes_after_free() {
+        let mut block = [POISON_BYTE; 16];
+        check_poison(&block, block.as_ptr());
+
+        block[3] = 0;
+        check_poison(&block, block.as_ptr());
+    }
+}
@@ -0,0 +1,317 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Allocation tracking and leak detection, in the spirit of Linux's kmemleak.
+//! When enabled with the `kmemleak=on` boot option, every live allocation is
+//! recorded along with its size, its timestamp and its call site, as given by
+//! the backtrace unwinder.
+//!
+//! A scan looks for the recorded blocks that are unreachable: the kernel's
+//! .data and .bss sections (which include the boot stack) are the roots, and
+//! every word that points inside a block makes it reachable, as well as the
+//! blocks it points to in turn. Pointers hidden in memory that is neither a
+//! root nor a tracked block are not seen, which is why unreachable blocks are
+//! only *suspected* leaks.
+
+use core::cell::Cell;
+use core::mem::{size_of, size_of_val};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::arch::time::timestamp;
+use crate::mem::load::kernel_data_segment;
+use crate::mem::VAddr;
+use crate::misc::align_up;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, CpuIndex, MAX_CPUS};
+use crate::task::cpu_local::CpuLocal;
+
+const MAX_TRACKED: usize = 1024;
+const CALL_SITE_DEPTH: usize = 4;
+
+static TRACKER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set while the CPU is recording an allocation or scanning for leaks: its
+/// nested allocations, e.g. those of the scan's callback, are not tracked.
+/// Other CPUs keep tracking their allocations meanwhile.
+static IN_TRACKER: CpuLocal<Cell<bool>>
+    = CpuLocal::new([const { Cell::new(false) }; MAX_CPUS]);
+
+/// The number of allocations that couldn't be tracked because the table was
+/// full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+static RECORDS: Spinlock<ArrayVec<AllocRecord, MAX_TRACKED>>
+    = Spinlock::new(ArrayVec::new_const());
+
+#[derive(Copy, Clone, Debug)]
+pub struct AllocRecord {
+    pub addr: VAddr,
+    pub bsize: usize,
+    pub timestamp: u64,
+
+    /// The return addresses of the innermost frames of the allocation, past
+    /// the allocator's own; unused entries are null.
+    pub call_site: [VAddr; CALL_SITE_DEPTH],
+}
+
+impl AllocRecord {
+    fn range(&self) -> Range<usize> {
+        self.addr.0..(self.addr.0 + self.bsize)
+    }
+}
+
+pub fn set_enabled(enabled: bool) {
+    TRACKER_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    TRACKER_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The number of allocations that were not tracked since the table was full.
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::SeqCst)
+}
+
+/// Record a new allocation of `bsize` bytes at `ptr`.
+pub fn track(ptr: *mut u8, bsize: usize) {
+    if ptr.is_null() || !is_enabled() {
+        return;
+    }
+    let Some(_guard) = enter() else {
+        return;
+    };
+
+    let record = AllocRecord {
+        addr: VAddr::from(ptr),
+        bsize,
+        timestamp: timestamp(),
+        call_site: call_site(),
+    };
+
+    if RECORDS.lock().try_push(record).is_err() {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Forget about the allocation at `ptr`, which is being freed; untracked
+/// pointers are ignored.
+pub fn untrack(ptr: *mut u8) {
+    if ptr.is_null() || !is_enabled() {
+        return;
+    }
+    let Some(_guard) = enter() else {
+        return;
+    };
+
+    let mut records = RECORDS.lock();
+    if let Some(index) = records.iter()
+        .position(|record| record.addr == VAddr::from(ptr)) {
+        records.swap_remove(index);
+    }
+}
+
+/// Scan memory for unreachable blocks and call `f` for each of them; return
+/// the number of suspected leaks. Allocations made by `f` are not tracked.
+pub fn scan_leaks(mut f: impl FnMut(&AllocRecord)) -> usize {
+    let Some(_guard) = enter() else {
+        return 0;
+    };
+
+    let records = RECORDS.lock();
+    let table = &*records as *const _ as usize;
+    let table = table..(table + size_of_val(&*records));
+    let data = kernel_data_segment();
+
+    // The table itself points to every block, it must not act as a root.
+    let roots = [data.start.0..table.start, table.end..data.end.0];
+
+    let mut marked = [false; MAX_TRACKED];
+    mark_reachable(&records, &roots, &mut marked);
+
+    let mut count = 0;
+    for (record, _) in records.iter().zip(marked).filter(|(_, m)| !m) {
+        f(record);
+        count += 1;
+    }
+    count
+}
+
+/// Keeps the current CPU in the tracker until dropped; being a critical
+/// region, the current task can't migrate to another CPU meanwhile.
+struct TrackerGuard(CpuIndex);
+
+impl Drop for TrackerGuard {
+    fn drop(&mut self) {
+        IN_TRACKER.get(&self.0).set(false);
+    }
+}
+
+/// Enter the tracker on the current CPU; `None` if it is already in it.
+fn enter() -> Option<TrackerGuard> {
+    let cpu = current_cpu_index();
+    if IN_TRACKER.get(&cpu).replace(true) {
+        return None;
+    }
+
+    Some(TrackerGuard(cpu))
+}
+
+#[cfg(not(test))]
+fn call_site() -> [VAddr; CALL_SITE_DEPTH] {
+    use crate::arch::cpu::MachineState;
+    use crate::backtrace::Backtrace;
+
+    let mut call_site = [VAddr(0); CALL_SITE_DEPTH];
+
+    // Skip this function, `track()` and the allocator's `alloc()`.
+    let machine = MachineState::here();
+    let frames = Backtrace::from_machine_state(&machine).skip(3);
+    for (pc, frame) in call_site.iter_mut().zip(frames) {
+        *pc = frame.pc;
+    }
+
+    call_site
+}
+
+#[cfg(test)]
+fn call_site() -> [VAddr; CALL_SITE_DEPTH] {
+    [VAddr(0); CALL_SITE_DEPTH]
+}
+
+/// Mark the records whose block is reachable from the `roots` memory ranges,
+/// either directly or through other reachable blocks.
+fn mark_reachable(
+    records: &[AllocRecord],
+    roots: &[Range<usize>],
+    marked: &mut [bool],
+) {
+    let mut scanned = [false; MAX_TRACKED];
+
+    for root in roots {
+        mark_pointed(root.clone(), records, marked);
+    }
+
+    loop {
+        let mut has_scanned = false;
+
+        for i in 0..records.len() {
+            if marked[i] && !scanned[i] {
+                scanned[i] = true;
+                has_scanned = true;
+                mark_pointed(records[i].range(), records, marked);
+            }
+        }
+
+        if !has_scanned {
+            break;
+        }
+    }
+}
+
+/// Mark the records of every block pointed to by an aligned word of `area`.
+fn mark_pointed(
+    area: Range<usize>,
+    records: &[AllocRecord],
+    marked: &mut [bool],
+) {
+    let word_size = size_of::<usize>();
+    let Some(lowest) = records.iter().map(|r| r.addr.0).min() else {
+        return;
+    };
+    let highest = records.iter().map(|r| r.range().end).max().unwrap();
+
+    let mut addr = align_up(area.start, word_size);
+    while addr + word_size <= area.end {
+        let value = unsafe { (addr as *const usize).read_volatile() };
+        addr += word_size;
+
+        if value < lowest || value >= highest {
+            continue;
+        }
+
+        if let Some(index) = records.iter()
+            .position(|record| record.range().contains(&value)) {
+            marked[index] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_of(block: &[usize]) -> AllocRecord {
+        AllocRecord {
+            addr: VAddr::from(block.as_ptr()),
+            bsize: block.len() * size_of::<usize>(),
+            timestamp: 0,
+            call_site: [VAddr(0); CALL_SITE_DEPTH],
+        }
+    }
+
+    fn range_of(block: &[usize]) -> Range<usize> {
+        record_of(block).range()
+    }
+
+    #[test]
+    fn it_marks_blocks_pointed_by_roots() {
+        let block = [0usize; 4];
+        let root = [0, block.as_ptr() as usize];
+        let records = [record_of(&block)];
+        let mut marked = [false; 1];
+
+        mark_reachable(&records, &[range_of(&root)], &mut marked);
+
+        assert_eq!(marked, [true]);
+    }
+
+    #[test]
+    fn it_marks_blocks_pointed_inside() {
+        let block = [0usize; 4];
+        let root = [&block[2] as *const usize as usize];
+        let records = [record_of(&block)];
+        let mut marked = [false; 1];
+
+        mark_reachable(&records, &[range_of(&root)], &mut marked);
+
+        assert_eq!(marked, [true]);
+    }
+
+    #[test]
+    fn it_marks_blocks_transitively() {
+        let leaf = [0usize; 2];
+        let middle = [0, leaf.as_ptr() as usize];
+        let root = [middle.as_ptr() as usize];
+        let records = [record_of(&leaf), record_of(&middle)];
+        let mut marked = [false; 2];
+
+        mark_reachable(&records, &[range_of(&root)], &mut marked);
+
+        assert_eq!(marked, [true, true]);
+    }
+
+    #[test]
+    fn it_doesnt_mark_unreachable_cycles() {
+        let mut a = [0usize; 2];
+        let b = [a.as_ptr() as usize, 0];
+        a[0] = b.as_ptr() as usize;
+        let reachable = [0usize; 2];
+        let root = [reachable.as_ptr() as usize];
+        let records = [record_of(&a), record_of(&b), record_of(&reachable)];
+        let mut marked = [false; 3];
+
+        mark_reachable(&records, &[range_of(&root)], &mut marked);
+
+        assert_eq!(marked, [false, false, true]);
+    }
+}
@@ -0,0 +1,214 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A lite kernel address sanitizer, for debug builds with the `kasan` feature.
+//! A shadow map, one byte per `GRANULE` bytes of low memory, tells which bytes
+//! of the kernel heap may be accessed: the allocator unpoisons the blocks it
+//! hands out, poisons the red zone following each of them and the blocks freed.
+//! A few hot helpers, such as the user-space copies or the reallocation copy,
+//! check their accesses against the shadow map, catching the out-of-bounds
+//! accesses and uses after free that the heap's magic values miss.
+//!
+//! A shadow byte of 0 means that the whole granule is accessible, 1 to 7 that
+//! only that many first bytes are, and a poison value that none are.
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::{ptr, slice};
+
+use crate::mem::VAddr;
+use crate::misc::align_up;
+
+/// The number of bytes of memory each shadow byte describes.
+const GRANULE: usize = 8;
+
+/// The number of bytes appended to each heap allocation as a red zone.
+pub const REDZONE_BSIZE: usize = 16;
+
+/// The shadow value of the red zones following heap blocks.
+const POISON_REDZONE: u8 = 0xfc;
+
+/// The shadow value of freed heap blocks.
+const POISON_FREED: u8 = 0xfb;
+
+/// The shadow map, null until `init()`; its first byte describes the granule
+/// at `COVERED_START`.
+static SHADOW: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+static SHADOW_BSIZE: AtomicUsize = AtomicUsize::new(0);
+static COVERED_START: AtomicUsize = AtomicUsize::new(0);
+
+/// The size in bytes of the shadow map describing `bsize` bytes of memory.
+pub fn shadow_bsize(bsize: u64) -> u64 {
+    align_up(bsize, GRANULE as u64) / GRANULE as u64
+}
+
+/// Start sanitizing the low memory from `covered_start` with the shadow map of
+/// `bsize` bytes at `shadow`, reserved during boot; all the memory starts as
+/// accessible.
+///
+/// # Safety #
+///
+/// The shadow map must be writable memory, not used for anything else.
+pub unsafe fn init(covered_start: VAddr, shadow: VAddr, bsize: usize) {
+    shadow.as_mut_ptr::<u8>().write_bytes(0, bsize);
+    COVERED_START.store(covered_start.0, Ordering::SeqCst);
+    SHADOW_BSIZE.store(bsize, Ordering::SeqCst);
+    SHADOW.store(shadow.as_mut_ptr(), Ordering::SeqCst);
+}
+
+/// Mark the first `bsize` bytes of the heap block at `ptr` as accessible, and
+/// the rest of its `block_bsize` bytes as its red zone.
+pub fn on_alloc(ptr: *mut u8, bsize: usize, block_bsize: usize) {
+    let Some((shadow, offset)) = shadow_of(ptr) else { return };
+
+    unpoison(shadow, offset, bsize);
+    let redzone = align_up(offset + bsize, GRANULE);
+    poison(shadow, redzone, offset + block_bsize, POISON_REDZONE);
+}
+
+/// Mark the `block_bsize` bytes of the heap block at `ptr` as freed; panic if
+/// it is not allocated.
+pub fn on_free(ptr: *mut u8, block_bsize: usize) {
+    let Some((shadow, offset)) = shadow_of(ptr) else { return };
+
+    match shadow.get(offset / GRANULE) {
+        Some(&POISON_FREED) => report_free(ptr, "double free"),
+        Some(&POISON_REDZONE) => report_free(ptr, "invalid free"),
+        _ => poison(shadow, offset, offset + block_bsize, POISON_FREED),
+    }
+}
+
+/// Check that the `bsize` bytes at `ptr` may be read; panic if they may not.
+pub fn check_read(ptr: *const u8, bsize: usize) {
+    check(ptr, bsize, false);
+}
+
+/// Check that the `bsize` bytes at `ptr` may be written; panic if they may
+/// not.
+pub fn check_write(ptr: *mut u8, bsize: usize) {
+    check(ptr, bsize, true);
+}
+
+fn check(ptr: *const u8, bsize: usize, write: bool) {
+    let Some((shadow, offset)) = shadow_of(ptr) else { return };
+
+    if let Some((bad_offset, value)) = first_bad_byte(shadow, offset, bsize) {
+        let kind = match value {
+            POISON_FREED => "use after free",
+            _ => "heap out-of-bounds access",
+        };
+        panic!("kasan: {kind}: {} of {bsize} bytes at {:?}, invalid from \
+                {:?} (shadow {value:#04x})",
+               if write { "write" } else { "read" },
+               VAddr::from(ptr),
+               VAddr::from(ptr) + (bad_offset - offset));
+    }
+}
+
+fn report_free(ptr: *mut u8, kind: &str) -> ! {
+    panic!("kasan: {kind} of {:?}", VAddr::from(ptr));
+}
+
+/// The shadow map, with the offset of `ptr` within the covered memory; `None`
+/// if the sanitizer is not running, or `ptr` is not covered.
+fn shadow_of(ptr: *const u8) -> Option<(&'static mut [u8], usize)> {
+    let shadow = SHADOW.load(Ordering::SeqCst);
+    if shadow.is_null() {
+        return None;
+    }
+
+    let bsize = SHADOW_BSIZE.load(Ordering::SeqCst);
+    let offset = (ptr as usize)
+        .checked_sub(COVERED_START.load(Ordering::SeqCst))?;
+    (offset / GRANULE < bsize).then(|| {
+        (unsafe { slice::from_raw_parts_mut(shadow, bsize) }, offset)
+    })
+}
+
+/// Mark the `bsize` bytes from `offset`, which is granule-aligned, as
+/// accessible.
+fn unpoison(shadow: &mut [u8], offset: usize, bsize: usize) {
+    let first = offset / GRANULE;
+    let end = (first + bsize / GRANULE).min(shadow.len());
+
+    shadow[first..end].fill(0);
+    if bsize % GRANULE != 0 {
+        if let Some(partial) = shadow.get_mut(end) {
+            *partial = (bsize % GRANULE) as u8;
+        }
+    }
+}
+
+/// Mark the granules from the one at `start`, which is granule-aligned, up to
+/// the one containing `end` as poisoned with `value`.
+fn poison(shadow: &mut [u8], start: usize, end: usize, value: u8) {
+    let first = (start / GRANULE).min(shadow.len());
+    let end = (align_up(end, GRANULE) / GRANULE).min(shadow.len());
+
+    if first < end {
+        shadow[first..end].fill(value);
+    }
+}
+
+/// The offset of the first byte that may not be accessed among the `bsize`
+/// bytes from `offset`, with its shadow value.
+fn first_bad_byte(
+    shadow: &[u8],
+    offset: usize,
+    bsize: usize,
+) -> Option<(usize, u8)> {
+    let end = offset.checked_add(bsize)?;
+    let first = offset / GRANULE;
+    let last = (align_up(end, GRANULE) / GRANULE).min(shadow.len());
+
+    for (i, &value) in shadow.iter().enumerate().take(last).skip(first) {
+        let granule = i * GRANULE;
+        let accessible = match value {
+            0 => GRANULE,
+            1..=7 => value as usize,
+            _ => 0,
+        };
+
+        if end.min(granule + GRANULE) > granule + accessible {
+            return Some((offset.max(granule + accessible), value));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_poisons_red_zones_and_freed_blocks() {
+        let mut shadow = [0xff; 8];
+
+        unpoison(&mut shadow, 8, 13);
+        poison(&mut shadow, 24, 8 + 13 + REDZONE_BSIZE, POISON_REDZONE);
+        assert_eq!(shadow, [0xff, 0, 5, POISON_REDZONE, POISON_REDZONE, 0xff,
+                            0xff, 0xff]);
+
+        poison(&mut shadow, 8, 8 + 13 + REDZONE_BSIZE, POISON_FREED);
+        assert_eq!(shadow[1..5], [POISON_FREED; 4]);
+    }
+
+    #[test]
+    fn it_finds_the_first_invalid_byte() {
+        let shadow = [0, 5, POISON_REDZONE, POISON_FREED];
+
+        assert_eq!(first_bad_byte(&shadow, 0, 13), None);
+        assert_eq!(first_bad_byte(&shadow, 4, 10), Some((13, 5)));
+        assert_eq!(first_bad_byte(&shadow, 16, 1), Some((16, POISON_REDZONE)));
+        assert_eq!(first_bad_byte(&shadow, 26, 2), Some((26, POISON_FREED)));
+        assert_eq!(first_bad_byte(&shadow, 30, 4), Some((30, POISON_FREED)));
+        assert_eq!(first_bad_byte(&shadow, 40, 4), None);
+    }
+}
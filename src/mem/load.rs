@@ -41,6 +41,10 @@ extern "C" {
     static __kernel_rodata_start: u8;
 
     static __kernel_rodata_end: u8;
+
+    static __kernel_data_start: u8;
+
+    static __kernel_data_end: u8;
 }
 
 #[inline]
@@ -66,3 +70,12 @@ pub fn kernel_rodata_segment() -> Range<VAddr> {
             ..VAddr(&__kernel_rodata_end as *const u8 as usize)
     }
 }
+
+/// The kernel's .data and .bss sections, which include the boot stack.
+#[inline]
+pub fn kernel_data_segment() -> Range<VAddr> {
+    unsafe {
+        VAddr(&__kernel_data_start as *const u8 as usize)
+            ..VAddr(&__kernel_data_end as *const u8 as usize)
+    }
+}
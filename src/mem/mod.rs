@@ -9,17 +9,38 @@
  ******************************************************************************/
 
 use core::cmp::Ordering;
+use core::fmt;
 use core::fmt::{Debug, Formatter, LowerHex};
 use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use crate::arch;
 use crate::arch::cpu::MachineState;
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::mem::frame::{FrameStats, FRAME_ALLOCATOR};
+use crate::misc::BinSize;
 use crate::panic::panic_at_state;
+use crate::task::process;
+use crate::task::signal::{self, SIGSEGV};
+use crate::{debug, notice};
 
+pub mod bootmem;
+pub mod dma;
 pub mod frame;
+pub mod hotplug;
+pub mod iomap;
 pub mod kalloc;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod load;
+pub mod numa;
+pub mod paging;
+pub mod protect;
+pub mod resource;
+pub mod uaccess;
 
 pub use arch::mem::PAddr;
+pub use iomap::{iomap, MmioRegion};
 
 use crate::arch::mem::page_permissions;
 use crate::screen::R;
@@ -27,6 +48,12 @@ use crate::screen::R;
 pub static mut PHYS_MEM_SIZE: u64 = 0;
 pub static mut LOWMEM_VA_END: VAddr = VAddr(0);
 
+/// The period, in seconds, at which `log_stats_if_due()` logs the memory
+/// statistics.
+const STATS_LOG_PERIOD_S: u64 = 60;
+
+static LAST_STATS_LOG: AtomicU64 = AtomicU64::new(0);
+
 impl const Add<u64> for PAddr {
     type Output = Self;
 
@@ -108,6 +135,44 @@ pub fn get_lowmem_va_end() -> VAddr {
     unsafe { LOWMEM_VA_END }
 }
 
+/// The physical memory statistics, as counted by the frame allocator; `None` if
+/// the frame allocator is not configured yet.
+pub fn stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(|allocator| allocator.stats())
+}
+
+/// The physical memory statistics of the NUMA node `node`, like `stats()`.
+pub fn node_stats(node: numa::NodeId) -> Option<FrameStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(|allocator| allocator.node_stats(node))
+}
+
+/// Log the memory statistics at debug level if they were not logged for the
+/// last `STATS_LOG_PERIOD_S` seconds; this is meant to be called from idle
+/// loops, so that memory pressure can be observed.
+pub fn log_stats_if_due() {
+    let now = timestamp();
+    let freq = timestamp_frequency_or_default();
+    let last = LAST_STATS_LOG.load(AtomicOrdering::SeqCst);
+
+    if now.saturating_sub(last) < STATS_LOG_PERIOD_S * freq {
+        return;
+    }
+    LAST_STATS_LOG.store(now, AtomicOrdering::SeqCst);
+
+    if let Some(stats) = stats() {
+        debug!("memory: {} free, {} allocated, {} reserved, {} total",
+               BinSize(frames_bsize(stats.free)),
+               BinSize(frames_bsize(stats.allocated)),
+               BinSize(frames_bsize(stats.reserved())),
+               BinSize(frames_bsize(stats.total)));
+    }
+}
+
+/// The size in bytes of `nr_frames` frames.
+pub fn frames_bsize(nr_frames: usize) -> u64 {
+    nr_frames as u64 * arch::mem::FRAME_SIZE as u64
+}
+
 pub struct PagePermissions {
     pub accessible: bool,
     pub readable: bool,
@@ -115,14 +180,47 @@ pub struct PagePermissions {
     pub executable: bool,
 }
 
+/// A page mapped in the address space, with its effective permissions.
+#[derive(Copy, Clone)]
+pub struct Mapping {
+    pub vaddr: VAddr,
+    pub paddr: PAddr,
+    pub bsize: usize,
+    pub writable: bool,
+    pub executable: bool,
+    /// Whether user space can access the page.
+    pub user: bool,
+    /// Whether the page's TLB entry survives address space switches.
+    pub global: bool,
+}
+
+/// The memory type of a mapping, i.e. how the CPU caches accesses to it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CacheMode {
+    /// Regular RAM: reads and writes are cached.
+    WriteBack,
+    /// Reads are cached, writes go straight to memory.
+    WriteThrough,
+    /// No caching nor speculative access at all, as required by device
+    /// registers.
+    Uncached,
+    /// Writes are buffered and combined into bursts, reads are uncached; meant
+    /// for framebuffers. Falls back to `Uncached` if the CPU can't do it.
+    WriteCombining,
+}
+
 pub enum AccessAttempt {
     Read,
     Write,
     Execute,
 }
 
+/// Handle a page fault at `fault_addr`; `details` is the arch-specific
+/// description of the fault, as reported by the CPU. A fault in user mode
+/// sends `SIGSEGV` to the faulting process; one in the kernel is fatal.
 pub fn handle_pagefault(fault_addr: VAddr,
                         access: AccessAttempt,
+                        details: fmt::Arguments,
                         machine_state: &MachineState) {
     let op_str = match access {
         AccessAttempt::Read => "Invalid read",
@@ -141,9 +239,18 @@ pub fn handle_pagefault(fault_addr: VAddr,
         "unknown error"
     };
 
+    if machine_state.is_user() {
+        if let Some(pid) = process::current() {
+            notice!("pid {pid}: {op_str} at {fault_addr:?}: {reason} \
+                     ({details}), sending SIGSEGV");
+            signal::force(pid, SIGSEGV);
+            return;
+        }
+    }
+
     panic_at_state(
-        format_args!("{} at {:?}: {}",
-                     op_str, fault_addr, reason),
+        format_args!("{} at {:?}: {} ({})",
+                     op_str, fault_addr, reason, details),
         Some(machine_state),
         0,
     );
@@ -0,0 +1,310 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The NUMA topology of the machine, as described by the ACPI SRAT, which
+//! tells the proximity domain of each CPU and memory range, and the SLIT, which
+//! tells the relative distances between domains. Proximity domains are
+//! numbered as nodes in their order of appearance; without an SRAT, the whole
+//! machine is node 0.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::driver::acpi::{self, read_u32, read_u64, SDT_HEADER_BSIZE};
+use crate::mem::frame::FRAME_ALLOCATOR;
+use crate::mem::PAddr;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::{debug, info, warning};
+
+/// The maximum number of NUMA nodes; the domains beyond are ignored.
+pub const MAX_NODES: usize = 8;
+
+/// The maximum number of memory ranges described by the SRAT.
+const MAX_MEMORY_RANGES: usize = 32;
+
+/// The maximum number of CPUs described by the SRAT.
+const MAX_CPU_AFFINITIES: usize = 64;
+
+/// The distance of a node to itself, and to the nodes the SLIT doesn't tell
+/// the distance of.
+const LOCAL_DISTANCE: u8 = 10;
+const REMOTE_DISTANCE: u8 = 20;
+
+/// The SRAT entries follow the header and 12 reserved bytes.
+const SRAT_ENTRIES_OFFSET: usize = SDT_HEADER_BSIZE + 12;
+const SRAT_LAPIC_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+/// The SLIT's distance matrix follows the header and the number of domains.
+const SLIT_ENTRIES_OFFSET: usize = SDT_HEADER_BSIZE + 8;
+
+pub type NodeId = usize;
+
+static TOPOLOGY: Spinlock<Topology> = Spinlock::new(Topology::new());
+static CPU_NODES: [AtomicUsize; MAX_CPUS] = [NODE_ZERO; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const NODE_ZERO: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Copy, Clone)]
+struct MemoryAffinity {
+    paddr: PAddr,
+    bsize: u64,
+    node: NodeId,
+}
+
+struct Topology {
+    /// The proximity domain of each node, by node ID.
+    domains: ArrayVec<u32, MAX_NODES>,
+    memory: ArrayVec<MemoryAffinity, MAX_MEMORY_RANGES>,
+    /// The hardware ID of CPUs, i.e. their APIC ID on x86, with their node.
+    cpus: ArrayVec<(u32, NodeId), MAX_CPU_AFFINITIES>,
+    /// The distances between nodes, 0 if unknown.
+    distances: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+impl Topology {
+    const fn new() -> Self {
+        Self {
+            domains: ArrayVec::new_const(),
+            memory: ArrayVec::new_const(),
+            cpus: ArrayVec::new_const(),
+            distances: [[0; MAX_NODES]; MAX_NODES],
+        }
+    }
+
+    fn nr_nodes(&self) -> usize {
+        self.domains.len().max(1)
+    }
+
+    fn distance(&self, a: NodeId, b: NodeId) -> u8 {
+        match self.distances[a][b] {
+            _ if a == b => LOCAL_DISTANCE,
+            0 => REMOTE_DISTANCE,
+            distance => distance,
+        }
+    }
+
+    /// The node of the proximity domain `domain`, numbering it if it is new;
+    /// `None` if there are too many nodes.
+    fn node_of(&mut self, domain: u32) -> Option<NodeId> {
+        if let Some(node) = self.domains.iter().position(|&d| d == domain) {
+            return Some(node);
+        }
+
+        self.domains.try_push(domain).ok()?;
+        Some(self.domains.len() - 1)
+    }
+
+    fn parse_srat(&mut self, srat: &[u8]) {
+        let mut offset = SRAT_ENTRIES_OFFSET;
+
+        while let (Some(&kind), Some(&len)) = (srat.get(offset),
+                                               srat.get(offset + 1)) {
+            let len = len as usize;
+            let Some(entry) = srat.get(offset..(offset + len)) else {
+                break;
+            };
+            if len < 2 {
+                break;
+            }
+
+            if self.parse_srat_entry(kind, entry).is_none() {
+                warning!("numa: ignoring invalid or excess SRAT entry type \
+                          {kind}");
+            }
+            offset += len;
+        }
+    }
+
+    fn parse_srat_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            SRAT_LAPIC_AFFINITY => {
+                if read_u32(entry, 4)? & SRAT_ENABLED == 0 {
+                    return Some(());
+                }
+                let domain_hi = read_u32(entry, 8)? >> 8;
+                let domain = *entry.get(2)? as u32 | domain_hi << 8;
+                let node = self.node_of(domain)?;
+                self.cpus.try_push((*entry.get(3)? as u32, node)).ok()
+            },
+            SRAT_MEMORY_AFFINITY => {
+                if read_u32(entry, 28)? & SRAT_ENABLED == 0 {
+                    return Some(());
+                }
+                let node = self.node_of(read_u32(entry, 2)?)?;
+                self.memory.try_push(MemoryAffinity {
+                    paddr: PAddr(read_u64(entry, 8)?),
+                    bsize: read_u64(entry, 16)?,
+                    node,
+                }).ok()
+            },
+            SRAT_X2APIC_AFFINITY => {
+                if read_u32(entry, 12)? & SRAT_ENABLED == 0 {
+                    return Some(());
+                }
+                let node = self.node_of(read_u32(entry, 4)?)?;
+                self.cpus.try_push((read_u32(entry, 8)?, node)).ok()
+            },
+            _ => Some(()),
+        }
+    }
+
+    fn parse_slit(&mut self, slit: &[u8]) {
+        let Some(nr_domains) = read_u64(slit, SDT_HEADER_BSIZE) else {
+            return;
+        };
+        let nr_domains = nr_domains as usize;
+
+        for (a, &domain_a) in self.domains.iter().enumerate() {
+            for (b, &domain_b) in self.domains.iter().enumerate() {
+                let (i, j) = (domain_a as usize, domain_b as usize);
+                if i >= nr_domains || j >= nr_domains {
+                    continue;
+                }
+                if let Some(&distance) = slit.get(SLIT_ENTRIES_OFFSET
+                                                  + i * nr_domains + j) {
+                    self.distances[a][b] = distance;
+                }
+            }
+        }
+    }
+}
+
+/// Learn the NUMA topology from the ACPI tables, then attribute the memory
+/// ranges to their node in the frame allocator, and the CPUs to theirs;
+/// `hw_id` gives the hardware ID of a CPU by index, as used in the SRAT.
+pub fn init(hw_id: impl Fn(usize) -> Option<u32>) {
+    let Some(srat) = acpi::find(b"SRAT") else {
+        debug!("numa: no SRAT, assuming a single node");
+        return;
+    };
+
+    let mut topology = TOPOLOGY.lock();
+    topology.parse_srat(srat);
+    if let Some(slit) = acpi::find(b"SLIT") {
+        topology.parse_slit(slit);
+    }
+
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        for range in &topology.memory {
+            if !allocator.set_node(range.paddr, range.bsize, range.node) {
+                warning!("numa: too many memory ranges, ignoring {:?} \
+                          (+{:#x}) on node {}",
+                         range.paddr, range.bsize, range.node);
+            }
+        }
+    }
+
+    for (cpu, cpu_node) in CPU_NODES.iter().enumerate() {
+        let node = hw_id(cpu)
+            .and_then(|id| topology.cpus.iter().find(|(i, _)| *i == id))
+            .map_or(0, |&(_, node)| node);
+        cpu_node.store(node, Ordering::Relaxed);
+    }
+
+    info!("numa: {} node(s), {} memory range(s)",
+          topology.nr_nodes(), topology.memory.len());
+}
+
+/// The number of NUMA nodes, at least 1.
+pub fn nr_nodes() -> usize {
+    TOPOLOGY.lock().nr_nodes()
+}
+
+/// The NUMA node of the CPU running this code.
+pub fn current_node() -> NodeId {
+    let cpu = current_cpu_index();
+    CPU_NODES[cpu.get()].load(Ordering::Relaxed)
+}
+
+/// The relative distance between the nodes `a` and `b`, as given by the SLIT:
+/// 10 is local, 20 is twice as far.
+pub fn distance(a: NodeId, b: NodeId) -> u8 {
+    TOPOLOGY.lock().distance(a, b)
+}
+
+/// All the nodes, from the nearest to `from`, which comes first, to the
+/// farthest.
+pub fn nodes_by_distance(from: NodeId) -> ArrayVec<NodeId, MAX_NODES> {
+    let topology = TOPOLOGY.lock();
+    let mut nodes: ArrayVec<NodeId, MAX_NODES> =
+        (0..topology.nr_nodes()).collect();
+    let from = from.min(topology.nr_nodes() - 1);
+
+    nodes.sort_unstable_by_key(|&node| (topology.distance(from, node), node));
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: &[&[u8]]) -> Vec<u8> {
+        let mut table = vec![0; SRAT_ENTRIES_OFFSET];
+        entries.iter().for_each(|entry| table.extend_from_slice(entry));
+        table
+    }
+
+    fn memory_affinity(domain: u32, base: u64, len: u64) -> Vec<u8> {
+        let mut entry = vec![0; 40];
+        entry[0] = SRAT_MEMORY_AFFINITY;
+        entry[1] = 40;
+        entry[2..6].copy_from_slice(&domain.to_le_bytes());
+        entry[8..16].copy_from_slice(&base.to_le_bytes());
+        entry[16..24].copy_from_slice(&len.to_le_bytes());
+        entry[28] = SRAT_ENABLED as u8;
+        entry
+    }
+
+    #[test]
+    fn it_parses_the_srat() {
+        let lapic = [SRAT_LAPIC_AFFINITY, 16, 7, 3, 1, 0, 0, 0,
+                     0, 0, 0, 0, 0, 0, 0, 0];
+        let x2apic_disabled = [SRAT_X2APIC_AFFINITY, 24, 0, 0, 9, 0, 0, 0,
+                               42, 0, 0, 0, 0, 0, 0, 0,
+                               0, 0, 0, 0, 0, 0, 0, 0];
+        let srat = table(&[
+            &memory_affinity(7, 0, 0x8000_0000),
+            &lapic,
+            &memory_affinity(2, 0x1_0000_0000, 0x8000_0000),
+            &x2apic_disabled,
+        ]);
+
+        let mut topology = Topology::new();
+        topology.parse_srat(&srat);
+
+        assert_eq!(topology.domains.as_slice(), [7, 2]);
+        assert_eq!(topology.cpus.as_slice(), [(3, 0)]);
+        let memory: Vec<_> = topology.memory.iter()
+            .map(|r| (r.paddr.0, r.bsize, r.node))
+            .collect();
+        assert_eq!(memory, [(0, 0x8000_0000, 0),
+                            (0x1_0000_0000, 0x8000_0000, 1)]);
+    }
+
+    #[test]
+    fn it_parses_the_slit() {
+        let mut topology = Topology::new();
+        topology.domains.extend([1, 0]);
+
+        let mut slit = vec![0; SLIT_ENTRIES_OFFSET];
+        slit[SDT_HEADER_BSIZE] = 2;
+        slit.extend_from_slice(&[10, 21, 21, 10]);
+        topology.parse_slit(&slit);
+
+        assert_eq!(topology.distance(0, 0), LOCAL_DISTANCE);
+        assert_eq!(topology.distance(0, 1), 21);
+        assert_eq!(topology.distance(1, 0), 21);
+        assert_eq!(topology.distance(1, 7), REMOTE_DISTANCE);
+    }
+}
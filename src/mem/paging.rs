@@ -0,0 +1,62 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Inspection of the page tables, for debugging mapping issues.
+
+use core::ops::Range;
+
+use crate::arch::mem::walk_mappings;
+use crate::mem::{Mapping, VAddr};
+use crate::println;
+
+/// Call `f` for each region of `range` made of contiguous pages, both
+/// virtually and physically, with the same permissions; the region is given
+/// as a `Mapping` spanning all its pages.
+pub fn regions(range: Range<VAddr>, mut f: impl FnMut(&Mapping)) {
+    let mut current: Option<Mapping> = None;
+
+    walk_mappings(range, |mapping| {
+        if let Some(region) = current.as_mut() {
+            if extends(region, &mapping) {
+                region.bsize += mapping.bsize;
+                return;
+            }
+            f(region);
+        }
+        current = Some(mapping);
+    });
+
+    if let Some(region) = current {
+        f(&region);
+    }
+}
+
+/// Print the mapped regions of `range`, one per line.
+pub fn dump(range: Range<VAddr>) {
+    regions(range, |region| {
+        println!("{:016x}-{:016x} -> {:012x} {:>10} KiB  {} {} {} {}",
+                 region.vaddr.0, region.vaddr.0 + (region.bsize - 1),
+                 region.paddr.0, region.bsize / 1024,
+                 if region.writable { "RW" } else { "RO" },
+                 if region.executable { "X " } else { "NX" },
+                 if region.user { "US" } else { "--" },
+                 if region.global { "G" } else { "-" });
+    });
+}
+
+/// Whether `next` directly follows `region`, with the same permissions.
+fn extends(region: &Mapping, next: &Mapping) -> bool {
+    region.vaddr.0.checked_add(region.bsize) == Some(next.vaddr.0)
+        && region.paddr.0 + region.bsize as u64 == next.paddr.0
+        && region.writable == next.writable
+        && region.executable == next.executable
+        && region.user == next.user
+        && region.global == next.global
+}
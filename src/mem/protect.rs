@@ -0,0 +1,101 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Tightening of the kernel's own mappings once it no longer needs to write to
+//! some of its structures, and auditing of the W^X policy: no kernel page
+//! should ever be both writable and executable.
+//!
+//! The page tables themselves are left writable, since changing a mapping
+//! requires writing to them.
+
+use crate::arch::mem::{set_page_permissions, walk_kernel_mappings,
+                       PAGE_SIZE};
+use crate::mem::VAddr;
+use crate::warning;
+
+/// Mark the pages spanning `bsize` bytes from `start` read-only and
+/// non-executable. The range is extended to page boundaries: the structure
+/// should thus sit on pages of its own.
+///
+/// # Safety #
+///
+/// Any later write to the range will fault.
+pub unsafe fn set_read_only(start: VAddr, bsize: usize) {
+    for_each_page(start, bsize, |vaddr| {
+        set_page_permissions(vaddr, false, false)
+    });
+}
+
+/// Mark the pages spanning `bsize` bytes from `start` non-executable, leaving
+/// them writable.
+///
+/// # Safety #
+///
+/// Any code still running from the range will fault.
+pub unsafe fn set_non_executable(start: VAddr, bsize: usize) {
+    for_each_page(start, bsize, |vaddr| {
+        set_page_permissions(vaddr, true, false)
+    });
+}
+
+fn for_each_page(start: VAddr, bsize: usize, mut f: impl FnMut(VAddr) -> bool) {
+    let first = start.0 & !(PAGE_SIZE - 1);
+    let end = (start.0 + bsize + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    for vaddr in (first..end).step_by(PAGE_SIZE) {
+        if !f(VAddr(vaddr)) {
+            warning!("protect: page {:?} is not mapped", VAddr(vaddr));
+        }
+    }
+}
+
+/// Walk the kernel's page tables and call `report` for every range of
+/// contiguous pages that are both writable and executable, with the range's
+/// start and size in bytes. Returns the number of such ranges.
+pub fn audit_wx(mut report: impl FnMut(VAddr, usize)) -> usize {
+    let mut count = 0;
+    let mut current: Option<(VAddr, usize)> = None;
+
+    walk_kernel_mappings(|vaddr, bsize, perms| {
+        let is_wx = perms.writable && perms.executable;
+
+        match current {
+            Some((start, len)) if is_wx && start + len == vaddr => {
+                current = Some((start, len + bsize));
+                return;
+            },
+            Some((start, len)) => {
+                report(start, len);
+                count += 1;
+                current = None;
+            },
+            None => (),
+        }
+
+        if is_wx {
+            current = Some((vaddr, bsize));
+        }
+    });
+
+    if let Some((start, len)) = current {
+        report(start, len);
+        count += 1;
+    }
+
+    count
+}
+
+/// Warn about every writable and executable kernel mapping.
+pub fn warn_wx_mappings() -> usize {
+    audit_wx(|start, bsize| {
+        warning!("W^X: {:?}..{:?} ({bsize} bytes) is writable and executable",
+                 start, start + bsize);
+    })
+}
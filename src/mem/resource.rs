@@ -0,0 +1,268 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The physical address space resource map, similar to Linux's `/proc/iomem`.
+//! The boot memory map declares the RAM and reserved regions; then, drivers
+//! request the physical ranges they drive (framebuffers, PCI BARs, LAPIC, ...)
+//! so that two drivers can't map the same device, and that no device range can
+//! be mapped over usable RAM.
+
+use arrayvec::ArrayVec;
+use thiserror_no_std::Error;
+
+use crate::arch::mem::FRAME_SIZE;
+use crate::mem::frame::{ClaimError, FrameAllocator, FRAME_ALLOCATOR};
+use crate::mem::PAddr;
+use crate::misc::align_up;
+use crate::sync::Spinlock;
+use crate::warning;
+
+const MAX_RESOURCES: usize = 64;
+
+static RESOURCES: Spinlock<ArrayVec<Resource, MAX_RESOURCES>>
+    = Spinlock::new(ArrayVec::new_const());
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ResourceKind {
+    /// Usable RAM, as declared by the boot memory map.
+    SystemRam,
+    /// Reserved by the firmware, as declared by the boot memory map.
+    Reserved,
+    /// ACPI tables or NVS, as declared by the boot memory map.
+    Acpi,
+    /// Defective RAM, as declared by the boot memory map.
+    Unusable,
+    /// A range requested by a driver.
+    Device,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Resource {
+    pub start: PAddr,
+    /// The first address after the end of the range.
+    pub end: PAddr,
+    pub kind: ResourceKind,
+    pub name: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum ResourceError {
+    #[error("range {start:?}..{end:?} is already owned by {owner}")]
+    Conflict { start: PAddr, end: PAddr, owner: &'static str },
+
+    #[error("range overlaps system RAM at {start:?}..{end:?}")]
+    OverlapsRam { start: PAddr, end: PAddr },
+
+    #[error("empty or overflowing range")]
+    InvalidRange,
+
+    #[error("too many resources")]
+    TooManyResources,
+
+    #[error("no range at {start:?} is owned by {owner}")]
+    NotRequested { start: PAddr, owner: &'static str },
+
+    #[error("{0}")]
+    Frames(#[from] ClaimError),
+}
+
+impl Resource {
+    #[inline]
+    pub fn bsize(&self) -> u64 {
+        self.end.0 - self.start.0
+    }
+
+    #[inline]
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start < self.end.0 && self.start.0 < end
+    }
+
+    #[inline]
+    pub fn contains(&self, other: &Resource) -> bool {
+        self.start.0 <= other.start.0 && other.end.0 <= self.end.0
+    }
+
+    /// The frames spanning the range, as claimed in the frame allocator.
+    fn frames(&self) -> (PAddr, u64) {
+        let first = self.start.0 & !(FRAME_SIZE as u64 - 1);
+        (PAddr(first), align_up(self.end.0, FRAME_SIZE as u64) - first)
+    }
+}
+
+/// Declare a region of the boot memory map, or RAM hot-added since. Boot
+/// regions are trusted and never checked for conflicts.
+pub fn declare_boot_region(start: PAddr, bsize: u64, kind: ResourceKind) {
+    let mut resources = RESOURCES.lock();
+
+    if bsize == 0 || resources.is_full() {
+        return;
+    }
+
+    let name = match kind {
+        ResourceKind::SystemRam => "System RAM",
+        ResourceKind::Reserved => "reserved",
+        ResourceKind::Acpi => "ACPI",
+        ResourceKind::Unusable => "unusable",
+        ResourceKind::Device => "device",
+    };
+
+    resources.push(Resource {
+        start,
+        end: PAddr(start.0.saturating_add(bsize)),
+        kind,
+        name,
+    });
+}
+
+/// Request ownership of the physical range `start..start+bsize` for the device
+/// named `owner`. The range must not overlap system RAM nor any range already
+/// requested. The frames of the range are claimed in the frame allocator, see
+/// `FrameAllocator::claim_region()`.
+pub fn request_region(
+    start: PAddr,
+    bsize: u64,
+    owner: &'static str,
+) -> Result<(), ResourceError> {
+    let end = start.0.checked_add(bsize)
+        .filter(|_| bsize > 0)
+        .ok_or(ResourceError::InvalidRange)?;
+    let mut resources = RESOURCES.lock();
+
+    for res in resources.iter().filter(|res| res.overlaps(start.0, end)) {
+        match res.kind {
+            ResourceKind::SystemRam => return Err(ResourceError::OverlapsRam {
+                start: res.start,
+                end: res.end,
+            }),
+            ResourceKind::Device => return Err(ResourceError::Conflict {
+                start: res.start,
+                end: res.end,
+                owner: res.name,
+            }),
+            _ => (),
+        }
+    }
+
+    if resources.is_full() {
+        return Err(ResourceError::TooManyResources);
+    }
+
+    let res = Resource {
+        start,
+        end: PAddr(end),
+        kind: ResourceKind::Device,
+        name: owner,
+    };
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        let (paddr, bsize) = res.frames();
+        allocator.claim_region(paddr, bsize, owner)?;
+    }
+    resources.push(res);
+
+    Ok(())
+}
+
+/// Release a range previously obtained with `request_region()`.
+pub fn release_region(
+    start: PAddr,
+    owner: &'static str,
+) -> Result<(), ResourceError> {
+    let mut resources = RESOURCES.lock();
+    let index = resources.iter()
+        .position(|res| {
+            res.kind == ResourceKind::Device
+                && res.start.0 == start.0
+                && res.name == owner
+        })
+        .ok_or(ResourceError::NotRequested { start, owner })?;
+    let released = resources.remove(index);
+
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.release_region(released.frames().0, owner)?;
+    }
+
+    Ok(())
+}
+
+/// Claim the frames of the device ranges requested so far; this is called once
+/// the frame allocator is created, later requests claiming them themselves.
+pub fn mark_claimed_frames(allocator: &mut FrameAllocator) {
+    let resources = RESOURCES.lock();
+    for res in resources.iter().filter(|res| res.kind == ResourceKind::Device) {
+        let (paddr, bsize) = res.frames();
+        if let Err(e) = allocator.claim_region(paddr, bsize, res.name) {
+            warning!("resource: {}: {e}", res.name);
+        }
+    }
+}
+
+/// Return all the resources sorted by address, each boot region being followed
+/// by the device ranges it contains.
+pub fn resources() -> ArrayVec<Resource, MAX_RESOURCES> {
+    let mut resources = RESOURCES.lock().clone();
+    resources.sort_unstable_by_key(|res| {
+        (res.start.0, res.kind == ResourceKind::Device)
+    });
+    resources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The resource map is global: each test works on its own ranges, far above
+    // the physical memory of the test frame allocator.
+
+    #[test]
+    fn it_refuses_conflicting_requests() {
+        request_region(PAddr(0x1_0000_0000), 0x2000, "first").unwrap();
+
+        let res = request_region(PAddr(0x1_0000_1000), 0x2000, "second");
+
+        assert!(matches!(res, Err(ResourceError::Conflict { start, owner, .. })
+                              if start.0 == 0x1_0000_0000 && owner == "first"));
+        request_region(PAddr(0x1_0000_2000), 0x1000, "second").unwrap();
+    }
+
+    #[test]
+    fn it_refuses_requests_over_ram() {
+        declare_boot_region(PAddr(0x1_0010_0000), 0x10000,
+                            ResourceKind::SystemRam);
+
+        let res = request_region(PAddr(0x1_000f_f000), 0x2000, "test");
+
+        assert!(matches!(res, Err(ResourceError::OverlapsRam { start, end })
+                              if start.0 == 0x1_0010_0000
+                                 && end.0 == 0x1_0011_0000));
+    }
+
+    #[test]
+    fn it_refuses_invalid_ranges() {
+        assert!(matches!(request_region(PAddr(0x1_0020_0000), 0, "test"),
+                         Err(ResourceError::InvalidRange)));
+        let res = request_region(PAddr(u64::MAX - 0xfff), 0x2000, "test");
+        assert!(matches!(res, Err(ResourceError::InvalidRange)));
+    }
+
+    #[test]
+    fn it_releases_and_requests_again() {
+        let start = PAddr(0x1_0030_0000);
+        request_region(start, 0x1000, "first").unwrap();
+
+        let res = release_region(start, "second");
+        assert!(matches!(res, Err(ResourceError::NotRequested { owner, .. })
+                              if owner == "second"));
+        release_region(start, "first").unwrap();
+        assert!(matches!(release_region(start, "first"),
+                         Err(ResourceError::NotRequested { .. })));
+
+        request_region(start, 0x1000, "second").unwrap();
+    }
+}
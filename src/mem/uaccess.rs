@@ -0,0 +1,163 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Copies between kernel memory and the memory of a user task. User pointers
+//! are never trusted: the range must lie within the task's regions with the
+//! right permissions, and a page fault during the copy, e.g. on a page that
+//! can't be populated, is reported as `EFAULT` rather than panicking.
+
+use core::ops::Range;
+use core::str;
+use arrayvec::{ArrayString, ArrayVec};
+
+use crate::arch::mem::copy_user;
+#[cfg(feature = "kasan")]
+use crate::mem::kasan;
+use crate::mem::VAddr;
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+
+/// Copy `dst.len()` bytes from the user address `src` of the address space
+/// `vm` into `dst`.
+pub fn copy_from_user(
+    vm: &VirtualMemory,
+    dst: &mut [u8],
+    src: VAddr,
+) -> Result<(), Errno> {
+    let range = user_range(src, dst.len())?;
+    if !vm.check_access(range, false) {
+        return Err(Errno::EFAULT);
+    }
+
+    #[cfg(feature = "kasan")]
+    kasan::check_write(dst.as_mut_ptr(), dst.len());
+    match unsafe { copy_user(dst.as_mut_ptr(), src.as_ptr(), dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copy `src` to the user address `dst` of the address space `vm`.
+pub fn copy_to_user(
+    vm: &VirtualMemory,
+    dst: VAddr,
+    src: &[u8],
+) -> Result<(), Errno> {
+    let range = user_range(dst, src.len())?;
+    if !vm.check_access(range, true) {
+        return Err(Errno::EFAULT);
+    }
+
+    #[cfg(feature = "kasan")]
+    kasan::check_read(src.as_ptr(), src.len());
+    match unsafe { copy_user(dst.as_mut_ptr(), src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copy the NUL-terminated string at the user address `src` of the address
+/// space `vm`; fail with `ENAMETOOLONG` if it is longer than `N` bytes.
+pub fn copy_str_from_user<const N: usize>(
+    vm: &VirtualMemory,
+    src: VAddr,
+) -> Result<ArrayString<N>, Errno> {
+    let mut bytes = ArrayVec::<u8, N>::new();
+
+    loop {
+        let mut byte = [0];
+        let addr = src.0.checked_add(bytes.len()).ok_or(Errno::EFAULT)?;
+        copy_from_user(vm, &mut byte, VAddr(addr))?;
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.try_push(byte[0]).map_err(|_| Errno::ENAMETOOLONG)?;
+    }
+
+    let string = str::from_utf8(&bytes).map_err(|_| Errno::EINVAL)?;
+    Ok(ArrayString::from(string).unwrap())
+}
+
+fn user_range(vaddr: VAddr, bsize: usize) -> Result<Range<VAddr>, Errno> {
+    let end = vaddr.0.checked_add(bsize).ok_or(Errno::EFAULT)?;
+    Ok(vaddr..VAddr(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::mem::PAGE_SIZE;
+    use crate::task::vm::{VmBacking, VmPermissions};
+    use super::*;
+
+    #[repr(align(4096))]
+    struct Pages([u8; 2 * PAGE_SIZE]);
+
+    const RO: VmPermissions = VmPermissions {
+        readable: true,
+        writable: false,
+        executable: false,
+    };
+    const RW: VmPermissions = VmPermissions {
+        readable: true,
+        writable: true,
+        executable: false,
+    };
+
+    fn user_vm(pages: &mut Pages) -> (VirtualMemory, VAddr) {
+        let base = VAddr::from(pages.0.as_mut_ptr());
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(!(PAGE_SIZE - 1)));
+        vm.insert(base..(base + PAGE_SIZE), RW, VmBacking::Anonymous).unwrap();
+        vm.insert((base + PAGE_SIZE)..(base + 2 * PAGE_SIZE), RO,
+                  VmBacking::Anonymous).unwrap();
+        (vm, base)
+    }
+
+    #[test]
+    fn it_copies_within_user_regions() {
+        let mut pages = Pages([0; 2 * PAGE_SIZE]);
+        let (vm, base) = user_vm(&mut pages);
+
+        copy_to_user(&vm, base + 10, b"hello").unwrap();
+        let mut buf = [0; 5];
+        copy_from_user(&vm, &mut buf, base + 10).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut buf = [0; 16];
+        copy_from_user(&vm, &mut buf, base + (PAGE_SIZE - 8)).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_invalid_user_ranges() {
+        let mut pages = Pages([0; 2 * PAGE_SIZE]);
+        let (vm, base) = user_vm(&mut pages);
+        let mut buf = [0; 16];
+
+        assert_eq!(copy_to_user(&vm, base + PAGE_SIZE, b"hello"),
+                   Err(Errno::EFAULT));
+        assert_eq!(copy_from_user(&vm, &mut buf, base + (2 * PAGE_SIZE - 8)),
+                   Err(Errno::EFAULT));
+        assert_eq!(copy_from_user(&vm, &mut buf, VAddr(usize::MAX - 8)),
+                   Err(Errno::EFAULT));
+    }
+
+    #[test]
+    fn it_copies_user_strings() {
+        let mut pages = Pages([0; 2 * PAGE_SIZE]);
+        let (vm, base) = user_vm(&mut pages);
+        copy_to_user(&vm, base, b"/sbin/init\0").unwrap();
+
+        assert_eq!(copy_str_from_user::<16>(&vm, base).unwrap().as_str(),
+                   "/sbin/init");
+        assert_eq!(copy_str_from_user::<4>(&vm, base),
+                   Err(Errno::ENAMETOOLONG));
+        assert_eq!(copy_str_from_user::<16>(&vm, base + 2 * PAGE_SIZE),
+                   Err(Errno::EFAULT));
+    }
+}
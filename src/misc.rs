@@ -9,7 +9,7 @@
  ******************************************************************************/
 
 use core::fmt;
-use core::fmt::Formatter;
+use core::fmt::{Formatter, Write};
 use core::ops::{BitAnd, Not};
 use num_integer::Integer;
 
@@ -63,9 +63,84 @@ pub fn first_bit_pos(n: usize) -> u8 {
     0
 }
 
+/// A 32-bit FNV-1a hasher; it can be fed with formatted text, which doesn't
+/// require any memory allocation.
+#[derive(Copy, Clone)]
+pub struct Fnv1a(u32);
+
+impl Fnv1a {
+    pub const fn new() -> Self {
+        Self(0x811c9dc5)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u32;
+            self.0 = self.0.wrapping_mul(0x01000193);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The canonical hex+ASCII dump of `bytes`, as laid out by `hexdump -C`: each
+/// line shows the address of its first byte, counting from `addr`, then up to
+/// 16 bytes in hexadecimal and as printable ASCII characters.
+pub fn hexdump(addr: usize, bytes: &[u8]) -> HexDump<'_> {
+    HexDump { addr, bytes }
+}
+
+pub struct HexDump<'a> {
+    addr: usize,
+    bytes: &'a [u8],
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.bytes.chunks(16).enumerate() {
+            write!(f, "{:08x} ", self.addr.wrapping_add(i * 16))?;
+            for col in 0..16 {
+                if col % 8 == 0 {
+                    f.write_char(' ')?;
+                }
+                match line.get(col) {
+                    Some(byte) => write!(f, "{byte:02x} ")?,
+                    None => f.write_str("   ")?,
+                }
+            }
+
+            f.write_str(" |")?;
+            for &byte in line {
+                let printable = byte.is_ascii_graphic() || byte == b' ';
+                f.write_char(if printable { byte as char } else { '.' })?;
+            }
+            f.write_str("|\n")?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::misc::first_bit_pos;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use crate::misc::{first_bit_pos, hexdump, Fnv1a};
 
     #[test]
     fn test_first_bit_pos() {
@@ -74,6 +149,32 @@ mod test {
         assert_eq!(first_bit_pos(0b11100000_10010101), 15);
         assert_eq!(first_bit_pos(0), 0);
     }
+
+    #[test]
+    fn test_fnv1a() {
+        let mut hasher = Fnv1a::new();
+        assert_eq!(hasher.finish(), 0x811c9dc5);
+        hasher.write_bytes(b"a");
+        assert_eq!(hasher.finish(), 0xe40c292c);
+
+        let mut hasher = Fnv1a::new();
+        hasher.write_bytes(b"foobar");
+        assert_eq!(hasher.finish(), 0xbf9cf968);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let dump = format!("{}", hexdump(0x1000, b"Hello, world!\n\0\xff\x7f"));
+        let lines: Vec<_> = dump.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], concat!("00001000  48 65 6c 6c 6f 2c 20 77  ",
+                                     "6f 72 6c 64 21 0a 00 ff  ",
+                                     "|Hello, world!...|"));
+        assert_eq!(lines[1], format!("00001010  7f{:48}|.|", ""));
+        assert!(dump.ends_with('\n'));
+        assert_eq!(format!("{}", hexdump(0, b"")), "");
+    }
 }
 
 #[macro_use]
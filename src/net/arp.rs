@@ -0,0 +1,219 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The Address Resolution Protocol, RFC 826: finding the MAC address of an IPv4
+//! address on the local link. Resolved addresses are kept in a small cache,
+//! the oldest entry being evicted when it is full; the cache also learns from
+//! the requests of other hosts asking for our address.
+
+use arrayvec::ArrayVec;
+
+use crate::driver::net::ethernet::ETHERTYPE_ARP;
+use crate::driver::net::{MacAddress, NetError};
+use crate::net::ipv4::Ipv4Address;
+use crate::net::{send_frame, Interface};
+use crate::sync::Spinlock;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+
+const PACKET_LEN: usize = 28;
+const CACHE_LEN: usize = 32;
+
+static CACHE: Spinlock<ArpCache> = Spinlock::new(ArpCache::new());
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub oper: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    /// Parse an ARP packet for IPv4 over ethernet; `None` if it is malformed,
+    /// or for other protocols.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < PACKET_LEN
+            || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+            || u16::from_be_bytes([packet[2], packet[3]]) != PTYPE_IPV4
+            || packet[4] != 6 || packet[5] != 4 {
+            return None;
+        }
+        let mac = |offset: usize| {
+            MacAddress(packet[offset..(offset + 6)].try_into().unwrap())
+        };
+        let ip = |offset: usize| {
+            Ipv4Address(packet[offset..(offset + 4)].try_into().unwrap())
+        };
+
+        Some(Self {
+            oper: u16::from_be_bytes([packet[6], packet[7]]),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn build(&self) -> [u8; PACKET_LEN] {
+        let mut packet = [0; PACKET_LEN];
+
+        packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        packet[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        packet[4] = 6;
+        packet[5] = 4;
+        packet[6..8].copy_from_slice(&self.oper.to_be_bytes());
+        packet[8..14].copy_from_slice(&self.sender_mac.0);
+        packet[14..18].copy_from_slice(&self.sender_ip.0);
+        packet[18..24].copy_from_slice(&self.target_mac.0);
+        packet[24..28].copy_from_slice(&self.target_ip.0);
+
+        packet
+    }
+}
+
+/// The resolved addresses, the oldest first.
+pub struct ArpCache {
+    entries: ArrayVec<(Ipv4Address, MacAddress), CACHE_LEN>,
+}
+
+impl ArpCache {
+    const fn new() -> Self {
+        Self {
+            entries: ArrayVec::new_const(),
+        }
+    }
+
+    pub fn lookup(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        self.entries.iter()
+            .find(|&&(entry_ip, _)| entry_ip == ip)
+            .map(|&(_, mac)| mac)
+    }
+
+    /// Update the entry of `ip`, if any; return whether there was one.
+    fn update(&mut self, ip: Ipv4Address, mac: MacAddress) -> bool {
+        match self.entries.iter_mut().find(|(entry_ip, _)| *entry_ip == ip) {
+            Some(entry) => {
+                entry.1 = mac;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, ip: Ipv4Address, mac: MacAddress) {
+        if self.update(ip, mac) {
+            return;
+        }
+        if self.entries.is_full() {
+            self.entries.remove(0);
+        }
+        self.entries.push((ip, mac));
+    }
+
+    /// The cached entries, the oldest first.
+    pub fn entries(&self) -> &[(Ipv4Address, MacAddress)] {
+        &self.entries
+    }
+}
+
+/// The MAC address of `ip`, if cached.
+pub fn lookup(ip: Ipv4Address) -> Option<MacAddress> {
+    CACHE.lock().lookup(ip)
+}
+
+/// Run `f` on the cache, e.g. to list its entries.
+pub fn with_cache<R>(f: impl FnOnce(&ArpCache) -> R) -> R {
+    f(&CACHE.lock())
+}
+
+/// Broadcast a request for the MAC address of `ip` on `iface`.
+pub fn request(iface: &Interface, ip: Ipv4Address) -> Result<(), NetError> {
+    let packet = ArpPacket {
+        oper: OPER_REQUEST,
+        sender_mac: iface.mac,
+        sender_ip: iface.cidr.addr,
+        target_mac: MacAddress([0; 6]),
+        target_ip: ip,
+    };
+
+    send_frame(iface, MacAddress::BROADCAST, ETHERTYPE_ARP, &packet.build())
+}
+
+/// Handle the ARP packet `packet`, received on `iface`: learn the sender's
+/// address, and answer requests for ours.
+pub fn handle(iface: &Interface, packet: &[u8]) {
+    let Some(packet) = ArpPacket::parse(packet) else {
+        return;
+    };
+    let for_us = packet.target_ip == iface.cidr.addr;
+
+    {
+        let mut cache = CACHE.lock();
+        if !cache.update(packet.sender_ip, packet.sender_mac) && for_us {
+            cache.insert(packet.sender_ip, packet.sender_mac);
+        }
+    }
+
+    if for_us && packet.oper == OPER_REQUEST {
+        let reply = ArpPacket {
+            oper: OPER_REPLY,
+            sender_mac: iface.mac,
+            sender_ip: iface.cidr.addr,
+            target_mac: packet.sender_mac,
+            target_ip: packet.sender_ip,
+        };
+        let _ = send_frame(iface, packet.sender_mac, ETHERTYPE_ARP,
+                           &reply.build());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_and_parses_packets() {
+        let packet = ArpPacket {
+            oper: OPER_REQUEST,
+            sender_mac: MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            sender_ip: Ipv4Address([10, 0, 2, 15]),
+            target_mac: MacAddress([0; 6]),
+            target_ip: Ipv4Address([10, 0, 2, 2]),
+        };
+        let data = packet.build();
+
+        assert_eq!(&data[..8], &[0, 1, 8, 0, 6, 4, 0, 1]);
+        assert_eq!(ArpPacket::parse(&data), Some(packet));
+        assert_eq!(ArpPacket::parse(&data[..27]), None);
+    }
+
+    #[test]
+    fn it_evicts_the_oldest_entries() {
+        let mut cache = ArpCache::new();
+        let ip = |i: usize| Ipv4Address([10, 0, 0, i as u8]);
+        let mac = |i: usize| MacAddress([2, 0, 0, 0, 0, i as u8]);
+
+        for i in 0..=CACHE_LEN {
+            cache.insert(ip(i), mac(i));
+        }
+        cache.insert(ip(5), mac(42));
+
+        assert_eq!(cache.lookup(ip(0)), None);
+        assert_eq!(cache.lookup(ip(1)), Some(mac(1)));
+        assert_eq!(cache.lookup(ip(5)), Some(mac(42)));
+        assert_eq!(cache.entries().len(), CACHE_LEN);
+    }
+}
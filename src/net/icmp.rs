@@ -0,0 +1,77 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! ICMP, RFC 792; only echo requests are handled, answered with an echo reply
+//! carrying the same identifier, sequence number and data, so that the kernel
+//! answers `ping`.
+
+use crate::net::ipv4::{self, checksum, Ipv4Header, MAX_PAYLOAD, PROTOCOL_ICMP};
+use crate::net::Interface;
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+const HEADER_LEN: usize = 8;
+
+/// Build the echo reply to the ICMP message `request` into `buf`; return its
+/// length, `None` if `request` is not a valid echo request.
+pub fn echo_reply(request: &[u8], buf: &mut [u8]) -> Option<usize> {
+    if request.len() < HEADER_LEN || request.len() > buf.len()
+        || request[0] != TYPE_ECHO_REQUEST || request[1] != 0
+        || checksum(request) != 0 {
+        return None;
+    }
+
+    let reply = &mut buf[..request.len()];
+    reply.copy_from_slice(request);
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = checksum(reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    Some(request.len())
+}
+
+/// Handle the ICMP message `message`, received on `iface` in the datagram of
+/// header `header`.
+pub fn handle(iface: &Interface, header: &Ipv4Header, message: &[u8]) {
+    // Broadcast pings are not answered, as most hosts do.
+    if header.dst != iface.cidr.addr {
+        return;
+    }
+
+    let mut reply = [0; MAX_PAYLOAD];
+    if let Some(len) = echo_reply(message, &mut reply) {
+        let _ = ipv4::send(iface, header.src, PROTOCOL_ICMP, &reply[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_answers_echo_requests() {
+        let mut request = [TYPE_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1,
+                           b'a', b'b', b'c'];
+        let sum = checksum(&request);
+        request[2..4].copy_from_slice(&sum.to_be_bytes());
+        let mut buf = [0; 64];
+
+        let len = echo_reply(&request, &mut buf).unwrap();
+        assert_eq!(len, request.len());
+        assert_eq!(buf[0], TYPE_ECHO_REPLY);
+        assert_eq!(&buf[4..len], &request[4..]);
+        assert_eq!(checksum(&buf[..len]), 0);
+
+        request[8] = b'x';
+        assert_eq!(echo_reply(&request, &mut buf), None);
+    }
+}
@@ -0,0 +1,372 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! IPv4: addresses, and the header of the datagrams carried over ethernet.
+//! Fragmented datagrams are not reassembled, but dropped; IP options are
+//! skipped.
+
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+use thiserror_no_std::Error;
+
+use crate::driver::net::ethernet::{ETHERTYPE_IPV4, MAX_PAYLOAD_LEN};
+use crate::driver::net::{MacAddress, NetError};
+use crate::net::{arp, icmp, send_frame, tcp, udp, Interface};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_LEN: usize = 20;
+pub const MAX_PAYLOAD: usize = MAX_PAYLOAD_LEN - HEADER_LEN;
+
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+impl FromStr for Ipv4Address {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+
+        for octet in octets.iter_mut() {
+            *octet = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        }
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(Self(octets))
+    }
+}
+
+/// An address with the length of its network prefix, e.g. `10.0.2.15/24`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Address,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn netmask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0)
+    }
+
+    /// Whether `addr` is on the same network.
+    pub fn contains(&self, addr: Ipv4Address) -> bool {
+        (addr.to_u32() ^ self.addr.to_u32()) & self.netmask() == 0
+    }
+
+    /// The directed broadcast address of the network.
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address((self.addr.to_u32() | !self.netmask()).to_be_bytes())
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').ok_or(())?;
+        let prefix_len = prefix_len.parse().map_err(|_| ())?;
+        if prefix_len > 32 {
+            return Err(());
+        }
+
+        Ok(Self {
+            addr: addr.parse()?,
+            prefix_len,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum IpError {
+    #[error("no route to {0}")]
+    NoRoute(Ipv4Address),
+
+    #[error("the link-layer address of {0} is being resolved")]
+    Unresolved(Ipv4Address),
+
+    #[error("payload of {0} bytes too long")]
+    TooLong(usize),
+
+    #[error("{0}")]
+    Device(#[from] NetError),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub src: Ipv4Address,
+    pub dst: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+impl Ipv4Header {
+    /// Split the datagram `packet` into its header and payload; `None` if it
+    /// is malformed, its checksum is wrong, or it is a fragment.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_LEN || total_len < header_len
+            || total_len > packet.len() {
+            return None;
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+            return None;
+        }
+
+        let addr = |offset: usize| {
+            Ipv4Address(packet[offset..(offset + 4)].try_into().unwrap())
+        };
+        let header = Self {
+            src: addr(12),
+            dst: addr(16),
+            protocol: packet[9],
+            ttl: packet[8],
+        };
+
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Write the header of a datagram of `payload_len` bytes into `buf`.
+    pub fn build(&self, payload_len: usize, buf: &mut [u8; HEADER_LEN]) {
+        let total_len = (HEADER_LEN + payload_len) as u16;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        buf[0] = 0x45;
+        buf[1] = 0;
+        buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+        buf[4..6].copy_from_slice(&id.to_be_bytes());
+        buf[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        buf[8] = self.ttl;
+        buf[9] = self.protocol;
+        buf[10..12].fill(0);
+        buf[12..16].copy_from_slice(&self.src.0);
+        buf[16..20].copy_from_slice(&self.dst.0);
+
+        let sum = checksum(buf);
+        buf[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+/// The Internet checksum of `data`, as defined by RFC 1071: the one's
+/// complement of the one's complement sum of its 16-bit words. Checking data
+/// that includes its checksum yields zero.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add(data);
+    sum.finish()
+}
+
+/// An Internet checksum computed over several pieces of data, e.g. a
+/// pseudo-header then a datagram; all the pieces but the last must be of even
+/// length.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Checksum(u32);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        for word in data.chunks(2) {
+            self.0 += match *word {
+                [high, low] => u16::from_be_bytes([high, low]) as u32,
+                [high] => (high as u32) << 8,
+                _ => unreachable!(),
+            };
+            self.0 = (self.0 & 0xffff) + (self.0 >> 16);
+        }
+    }
+
+    pub fn finish(self) -> u16 {
+        !(self.0 as u16)
+    }
+}
+
+/// The checksum of `data`, carried by `protocol` from `src` to `dst`, preceded
+/// by the IPv4 pseudo-header, as used by TCP and UDP.
+pub fn pseudo_header_checksum(
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    protocol: u8,
+    data: &[u8],
+) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add(&src.0);
+    sum.add(&dst.0);
+    sum.add(&[0, protocol]);
+    sum.add(&(data.len() as u16).to_be_bytes());
+    sum.add(data);
+    sum.finish()
+}
+
+/// Send `payload` as a datagram of `protocol` to `dst` on the interface
+/// `iface`. If the link-layer address of the next hop is not known yet, it is
+/// asked for and the datagram is dropped.
+pub fn send(
+    iface: &Interface,
+    dst: Ipv4Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), IpError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(IpError::TooLong(payload.len()));
+    }
+    let dst_mac = resolve(iface, dst)?;
+
+    let mut packet = [0; MAX_PAYLOAD_LEN];
+    let header = Ipv4Header {
+        src: iface.cidr.addr,
+        dst,
+        protocol,
+        ttl: DEFAULT_TTL,
+    };
+    header.build(payload.len(),
+                 (&mut packet[..HEADER_LEN]).try_into().unwrap());
+    packet[HEADER_LEN..(HEADER_LEN + payload.len())].copy_from_slice(payload);
+
+    send_frame(iface, dst_mac, ETHERTYPE_IPV4,
+               &packet[..(HEADER_LEN + payload.len())])?;
+    Ok(())
+}
+
+/// The link-layer address to send datagrams for `dst` to: `dst`'s own if it
+/// is on the interface's network, the gateway's otherwise.
+fn resolve(iface: &Interface, dst: Ipv4Address) -> Result<MacAddress, IpError> {
+    if dst == Ipv4Address::BROADCAST || dst == iface.cidr.broadcast() {
+        return Ok(MacAddress::BROADCAST);
+    }
+
+    let next_hop = if iface.cidr.contains(dst) {
+        dst
+    } else {
+        iface.gateway.ok_or(IpError::NoRoute(dst))?
+    };
+
+    match arp::lookup(next_hop) {
+        Some(mac) => Ok(mac),
+        None => {
+            arp::request(iface, next_hop)?;
+            Err(IpError::Unresolved(next_hop))
+        },
+    }
+}
+
+/// Handle the datagram `packet`, received on `iface`.
+pub fn handle(iface: &Interface, packet: &[u8]) {
+    let Some((header, payload)) = Ipv4Header::parse(packet) else {
+        return;
+    };
+    if header.dst != iface.cidr.addr && header.dst != Ipv4Address::BROADCAST
+        && header.dst != iface.cidr.broadcast() {
+        return;
+    }
+
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(iface, &header, payload),
+        PROTOCOL_TCP => tcp::handle(iface, &header, payload),
+        PROTOCOL_UDP => udp::handle(iface, &header, payload),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_checksums() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+
+        assert_eq!(checksum(&header), 0xb861);
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&header), 0);
+        assert_eq!(checksum(&[0x01]), !0x0100);
+    }
+
+    #[test]
+    fn it_builds_and_parses_headers() {
+        let header = Ipv4Header {
+            src: Ipv4Address([10, 0, 2, 15]),
+            dst: Ipv4Address([10, 0, 2, 2]),
+            protocol: PROTOCOL_ICMP,
+            ttl: 64,
+        };
+        let mut packet = [0; HEADER_LEN + 4];
+        header.build(4, (&mut packet[..HEADER_LEN]).try_into().unwrap());
+        packet[HEADER_LEN..].copy_from_slice(b"ping");
+
+        let (parsed, payload) = Ipv4Header::parse(&packet).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, b"ping");
+
+        packet[8] -= 1;
+        assert_eq!(Ipv4Header::parse(&packet), None);
+    }
+
+    #[test]
+    fn it_parses_addresses() {
+        let cidr: Ipv4Cidr = "10.0.2.15/24".parse().unwrap();
+
+        assert_eq!(cidr.addr, Ipv4Address([10, 0, 2, 15]));
+        assert_eq!(cidr.broadcast(), Ipv4Address([10, 0, 2, 255]));
+        assert!(cidr.contains(Ipv4Address([10, 0, 2, 2])));
+        assert!(!cidr.contains(Ipv4Address([10, 0, 3, 2])));
+        assert!("10.0.2/24".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.2.256/24".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.2.15/33".parse::<Ipv4Cidr>().is_err());
+    }
+}
@@ -0,0 +1,141 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The remote kernel shell: a TCP port through which the kernel shell can be
+//! used over the network, e.g. with `nc`. The lines received from the session
+//! go into the console input queue, like those typed on the keyboard, and the
+//! output of `print!()` is copied to the session.
+//!
+//! A single session is served at a time; further connections are told so and
+//! closed. Nothing is echoed back: the client is expected to edit its lines
+//! locally, as `nc` and `telnet` do.
+
+use alloc::string::String;
+use core::fmt::{self, Arguments, Write};
+
+use crate::net::tcp::{TcpError, TcpListener, TcpStream};
+use crate::sync::Spinlock;
+use crate::ui::console::LineDiscipline;
+use crate::ui::kterm;
+use crate::ui::shell::PROMPT;
+use crate::info;
+
+static KSHELL: Spinlock<Option<KShell>> = Spinlock::new(None);
+
+struct KShell {
+    listener: TcpListener,
+    session: Option<Session>,
+}
+
+struct Session {
+    stream: TcpStream,
+    ldisc: LineDiscipline,
+}
+
+/// Writes to the session's stream, dropping what doesn't fit in its send
+/// buffer rather than waiting.
+struct SessionWriter<'a>(&'a TcpStream);
+
+impl Write for SessionWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes()).map_err(|_| fmt::Error)?;
+        Ok(())
+    }
+}
+
+/// Discards the echo of the line discipline.
+struct NoEcho;
+
+impl Write for NoEcho {
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Serve the kernel shell on the TCP port `port`.
+pub fn start(port: u16) -> Result<(), TcpError> {
+    let listener = TcpListener::bind(port)?;
+    *KSHELL.lock() = Some(KShell { listener, session: None });
+    kterm::set_output_mirror(Some(mirror));
+
+    info!("kshell: listening on TCP port {port}");
+    Ok(())
+}
+
+/// Accept the incoming sessions and push the lines received into the console
+/// input queue; this is meant to be called from the shell's loop.
+pub fn poll() {
+    let mut opened = None;
+    let mut closed = None;
+
+    {
+        let mut kshell = KSHELL.lock();
+        let Some(kshell) = kshell.as_mut() else {
+            return;
+        };
+
+        while let Some(stream) = kshell.listener.try_accept() {
+            if kshell.session.is_some() {
+                let _ = stream.write(b"kshell: another session is open\n");
+                continue;
+            }
+
+            let _ = write!(SessionWriter(&stream),
+                           "Nucloid kernel shell\n{PROMPT}");
+            opened = Some(stream.remote());
+            kshell.session = Some(Session {
+                stream,
+                ldisc: LineDiscipline::new(),
+            });
+        }
+
+        if let Some(session) = kshell.session.as_mut() {
+            let mut buf = [0; 256];
+            loop {
+                match session.stream.try_read(&mut buf) {
+                    Err(TcpError::WouldBlock) => break,
+                    Ok(0) | Err(_) => {
+                        closed = Some(session.stream.remote());
+                        break;
+                    },
+                    Ok(len) => {
+                        let input = String::from_utf8_lossy(&buf[..len]);
+                        for c in input.chars() {
+                            session.ldisc.input_and_push(c, &mut NoEcho);
+                        }
+                    },
+                }
+            }
+        }
+        if closed.is_some() {
+            kshell.session = None;
+        }
+    }
+
+    if let Some(remote) = opened {
+        info!("kshell: session opened from {remote}");
+    }
+    if let Some(remote) = closed {
+        info!("kshell: session from {remote} closed");
+    }
+}
+
+/// Copy the output `args` of `print!()` to the session, if any.
+fn mirror(args: Arguments) {
+    // The shell may print while `poll()` holds the lock, e.g. when logging;
+    // the output is then lost for the session rather than deadlocking.
+    let Some(kshell) = KSHELL.try_lock() else {
+        return;
+    };
+
+    if let Some(session) = kshell.as_ref().and_then(|k| k.session.as_ref()) {
+        let _ = SessionWriter(&session.stream).write_fmt(args);
+    }
+}
@@ -0,0 +1,147 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The network stack, on top of the network devices of `driver::net`. A single
+//! interface is configured statically, from the `ip=` and `gateway=` boot
+//! options; there is no DHCP.
+//!
+//! There are no tasks to run the stack in yet: received frames are processed by
+//! `poll()`, on every timer tick. Once an interface is configured, the stack
+//! consumes all the frames received by its device.
+
+use crate::boot::cmdline;
+use crate::driver::net::ethernet::{
+    EthernetHeader, ETHERTYPE_ARP, ETHERTYPE_IPV4, MAX_FRAME_LEN,
+};
+use crate::driver::net::{self, MacAddress, NetError};
+use crate::net::ipv4::{Ipv4Address, Ipv4Cidr};
+use crate::sync::Spinlock;
+use crate::{info, initcall, warning};
+
+pub mod arp;
+pub mod icmp;
+pub mod ipv4;
+pub mod kshell;
+pub mod syslog;
+pub mod tcp;
+pub mod udp;
+
+/// The most frames processed by a single `poll()`, so that a flood doesn't
+/// hold the CPU within the timer interrupt.
+const MAX_FRAMES_PER_POLL: usize = 16;
+
+static INTERFACE: Spinlock<Option<Interface>> = Spinlock::new(None);
+
+/// The IPv4 configuration of a network device.
+#[derive(Debug, Copy, Clone)]
+pub struct Interface {
+    /// The index of the network device.
+    pub device: usize,
+    pub mac: MacAddress,
+    pub cidr: Ipv4Cidr,
+    /// Where to send datagrams for other networks.
+    pub gateway: Option<Ipv4Address>,
+}
+
+/// Start the network devices, then configure the interface and start the
+/// services set by the boot options.
+fn start() {
+    net::init();
+    let cmdline = cmdline::get();
+
+    if let Some(cidr) = cmdline.ip() {
+        match configure(0, cidr, cmdline.gateway()) {
+            Ok(()) => info!("net: eth0 configured as {cidr}"),
+            Err(e) => warning!("net: {e}"),
+        }
+    }
+    // SAFETY: the device initcalls run during the boot process, before the
+    // other CPUs could be brought up; this is the only one to start loggers.
+    if let Some(collector) = cmdline.syslog() {
+        if let Err(e) = unsafe { syslog::start(collector) } {
+            warning!("syslog: {e}");
+        }
+    }
+    if let Some(port) = cmdline.kshell() {
+        if let Err(e) = kshell::start(port) {
+            warning!("kshell: {e}");
+        }
+    }
+    if cmdline.netlog() {
+        // SAFETY: as for the syslog, only the bootstrap CPU runs.
+        if let Err(e) = unsafe { net::log::start(0) } {
+            warning!("netlog: {e}");
+        }
+    }
+}
+
+initcall!(device, start, "Starting network devices...");
+
+/// Configure the network device `device` with the address `cidr`.
+pub fn configure(
+    device: usize,
+    cidr: Ipv4Cidr,
+    gateway: Option<Ipv4Address>,
+) -> Result<(), NetError> {
+    let mac = net::with_device(device, |dev| dev.mac_address())?;
+    *INTERFACE.lock() = Some(Interface { device, mac, cidr, gateway });
+
+    Ok(())
+}
+
+/// The configured interface, if any.
+pub fn interface() -> Option<Interface> {
+    *INTERFACE.lock()
+}
+
+/// Send `payload` in a frame of type `ethertype` to `dst`, through the device
+/// of `iface`. This doesn't wait: the frame is dropped if the device is busy.
+pub fn send_frame(
+    iface: &Interface,
+    dst: MacAddress,
+    ethertype: u16,
+    payload: &[u8],
+) -> Result<(), NetError> {
+    let header = EthernetHeader { dst, src: iface.mac, ethertype };
+    let mut buf = [0; MAX_FRAME_LEN];
+    let len = header.build(payload, &mut buf)?;
+
+    match net::try_with_device(iface.device, |dev| dev.transmit(&buf[..len])) {
+        Some(result) => result?,
+        None => Err(NetError::QueueFull),
+    }
+}
+
+/// Process the frames received on the configured interface; this is meant to
+/// be called on every timer tick.
+pub fn poll() {
+    let Some(iface) = interface() else {
+        return;
+    };
+    let mut buf = [0; MAX_FRAME_LEN];
+
+    for _ in 0..MAX_FRAMES_PER_POLL {
+        let received = net::try_with_device(iface.device, |dev| {
+            dev.receive(&mut buf)
+        });
+        let Some(Ok(Some(len))) = received else {
+            break;
+        };
+        let Some((header, payload)) = EthernetHeader::parse(&buf[..len]) else {
+            continue;
+        };
+
+        match header.ethertype {
+            ETHERTYPE_ARP => arp::handle(&iface, payload),
+            ETHERTYPE_IPV4 => ipv4::handle(&iface, payload),
+            _ => (),
+        }
+    }
+}
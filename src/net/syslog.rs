@@ -0,0 +1,101 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A log sink shipping the kernel log to a syslog collector over UDP, in the
+//! format of RFC 5424, with the kernel facility; it is set up by the
+//! `syslog=<address>[:<port>]` boot option.
+//!
+//! Records are best-effort: they are dropped rather than waited for, e.g. the
+//! first ones while the collector's address is being resolved.
+
+use alloc::boxed::Box;
+use arrayvec::ArrayString;
+use core::fmt::{self, Write};
+
+use crate::logging::{reset_logger, Logger, Severity, DEFAULT_LOGGER};
+use crate::net::interface;
+use crate::net::udp::{Endpoint, UdpError, UdpSocket, MAX_DATAGRAM_LEN};
+
+/// The syslog port, when the boot option doesn't give one.
+pub const SYSLOG_PORT: u16 = 514;
+
+const FACILITY_KERNEL: u8 = 0;
+
+pub struct SyslogLogger {
+    inner: &'static mut (dyn Logger + Send),
+    socket: UdpSocket,
+    collector: Endpoint,
+}
+
+impl Logger for SyslogLogger {
+    fn log(&mut self, severity: Severity, args: fmt::Arguments) {
+        self.inner.log(severity, args);
+
+        let Some(iface) = interface() else {
+            return;
+        };
+        // Records longer than a datagram are truncated.
+        let mut record = ArrayString::<MAX_DATAGRAM_LEN>::new();
+        let _ = write!(record, "<{}>1 - {} nucloid - - - {}",
+                       priority(severity), iface.cidr.addr, args);
+        let _ = self.socket.send_to(record.as_bytes(), self.collector);
+    }
+}
+
+/// The PRI field of a record of `severity`, combining the facility and the
+/// syslog severity code, 0 being the most severe.
+fn priority(severity: Severity) -> u8 {
+    let code = match severity {
+        Severity::Emergency => 0,
+        Severity::Alert => 1,
+        Severity::Critical => 2,
+        Severity::Error => 3,
+        Severity::Warning => 4,
+        Severity::Notice => 5,
+        Severity::Info => 6,
+        Severity::Debug => 7,
+    };
+
+    FACILITY_KERNEL * 8 + code
+}
+
+/// Ship the kernel log to the syslog collector at `collector`, on top of the
+/// current logger.
+///
+/// # Safety #
+///
+/// Must be called during the boot process, with only one CPU running.
+pub unsafe fn start(collector: Endpoint) -> Result<(), UdpError> {
+    if interface().is_none() {
+        return Err(UdpError::NoInterface);
+    }
+    let socket = UdpSocket::bind(0)?;
+
+    let logger = Box::leak(Box::new(SyslogLogger {
+        inner: unsafe { reset_logger() },
+        socket,
+        collector,
+    }));
+    *DEFAULT_LOGGER.lock() = logger;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_maps_severities_to_priorities() {
+        assert_eq!(priority(Severity::Emergency), 0);
+        assert_eq!(priority(Severity::Warning), 4);
+        assert_eq!(priority(Severity::Debug), 7);
+    }
+}
@@ -0,0 +1,957 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! TCP, RFC 793, reduced to what a server needs: connections are only opened
+//! passively, by a `TcpListener`, then read from and written to through a
+//! `TcpStream`; dropping the stream closes the connection.
+//!
+//! Segments must arrive in order: those beyond the next expected byte are
+//! dropped and acknowledged again, so that the peer retransmits them. Only the
+//! oldest unacknowledged segment is retransmitted, when the retransmission
+//! timer expires, with an exponential backoff; the connection is reset after
+//! `MAX_RETRIES` retransmissions. There is no congestion control, nor any
+//! persist timer: a connection whose peer closed its window waits for the
+//! peer to open it again.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::time::Duration;
+use thiserror_no_std::Error;
+
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::net::ipv4::{
+    self, pseudo_header_checksum, Ipv4Address, Ipv4Header, MAX_PAYLOAD,
+    PROTOCOL_TCP,
+};
+use crate::net::udp::Endpoint;
+use crate::net::{interface, Interface};
+use crate::sync::{Spinlock, WaitQueue};
+use crate::task::timer::Timer;
+
+pub const HEADER_LEN: usize = 20;
+/// The largest segment payload fitting in an ethernet frame, which is the
+/// maximum segment size we announce.
+pub const MAX_SEGMENT_LEN: usize = MAX_PAYLOAD - HEADER_LEN;
+
+/// The maximum segment size of peers that don't announce one.
+const DEFAULT_MSS: usize = 536;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The size of the receive buffer of a connection, i.e. its largest window.
+const RECV_BUFFER_LEN: usize = 8192;
+/// The most bytes queued for sending on a connection.
+const SEND_BUFFER_LEN: usize = 16384;
+
+const INITIAL_RTO_MS: u64 = 1000;
+const MAX_RTO_MS: u64 = 30_000;
+const MAX_RETRIES: u32 = 8;
+/// How long a closed connection lingers to acknowledge a retransmitted FIN;
+/// far shorter than the 2 MSL of the RFC, which we can't afford to wait.
+const TIME_WAIT_MS: u64 = 2000;
+/// How long to wait for the peer to close its side once we closed ours.
+const FIN_WAIT_2_TIMEOUT_MS: u64 = 60_000;
+
+/// The most connections waiting to be accepted on a listener; further SYNs are
+/// ignored until some are.
+const MAX_BACKLOG: usize = 4;
+
+static TCP: Spinlock<Tcp> = Spinlock::new(Tcp::new());
+static WAITERS: WaitQueue = WaitQueue::new();
+
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcpError {
+    #[error("no network interface is configured")]
+    NoInterface,
+
+    #[error("port {0} is already bound")]
+    PortInUse(u16),
+
+    #[error("the operation would block")]
+    WouldBlock,
+
+    #[error("the connection is closed")]
+    Closed,
+
+    #[error("the connection was reset")]
+    Reset,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcpState {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// The maximum segment size option, only sent along with SYN.
+    pub mss: Option<u16>,
+}
+
+impl TcpHeader {
+    /// Split the segment `segment`, sent from `src` to `dst`, into its header
+    /// and payload; `None` if it is malformed or its checksum is wrong.
+    pub fn parse(
+        segment: &[u8],
+        src: Ipv4Address,
+        dst: Ipv4Address,
+    ) -> Option<(Self, &[u8])> {
+        if segment.len() < HEADER_LEN {
+            return None;
+        }
+        let header_len = (segment[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > segment.len()
+            || pseudo_header_checksum(src, dst, PROTOCOL_TCP, segment) != 0 {
+            return None;
+        }
+
+        let u16_at = |offset: usize| {
+            u16::from_be_bytes([segment[offset], segment[offset + 1]])
+        };
+        let u32_at = |offset: usize| {
+            let bytes = segment[offset..(offset + 4)].try_into().unwrap();
+            u32::from_be_bytes(bytes)
+        };
+        let header = Self {
+            src_port: u16_at(0),
+            dst_port: u16_at(2),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: segment[13],
+            window: u16_at(14),
+            mss: parse_mss(&segment[HEADER_LEN..header_len]),
+        };
+
+        Some((header, &segment[header_len..]))
+    }
+
+    /// Write the segment of this header and `payload`, from `src` to `dst`,
+    /// into `buf`; return its length.
+    pub fn build(
+        &self,
+        payload: &[u8],
+        src: Ipv4Address,
+        dst: Ipv4Address,
+        buf: &mut [u8; MAX_PAYLOAD],
+    ) -> usize {
+        let options_len = if self.mss.is_some() { 4 } else { 0 };
+        let header_len = HEADER_LEN + options_len;
+        let len = header_len + payload.len();
+        assert!(len <= MAX_PAYLOAD, "TCP segment of {len} bytes too long");
+
+        buf[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        buf[12] = ((header_len / 4) as u8) << 4;
+        buf[13] = self.flags;
+        buf[14..16].copy_from_slice(&self.window.to_be_bytes());
+        buf[16..20].fill(0);
+        if let Some(mss) = self.mss {
+            buf[20..22].copy_from_slice(&[OPTION_MSS, 4]);
+            buf[22..24].copy_from_slice(&mss.to_be_bytes());
+        }
+        buf[header_len..len].copy_from_slice(payload);
+
+        let sum = pseudo_header_checksum(src, dst, PROTOCOL_TCP, &buf[..len]);
+        buf[16..18].copy_from_slice(&sum.to_be_bytes());
+
+        len
+    }
+
+    /// The length of the segment in sequence space: its payload, plus one for
+    /// each of SYN and FIN.
+    fn seq_len(&self, payload_len: usize) -> u32 {
+        let flags = (self.flags & FLAG_SYN != 0) as u32
+            + (self.flags & FLAG_FIN != 0) as u32;
+        payload_len as u32 + flags
+    }
+}
+
+/// The value of the maximum segment size option among `options`, if any.
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    return Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            },
+        }
+    }
+
+    None
+}
+
+/// Whether the sequence number `a` comes before `b`, modulo 2^32.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn now_ms() -> u64 {
+    let freq = timestamp_frequency_or_default();
+    timestamp() / (freq / 1000).max(1)
+}
+
+/// An initial sequence number, from a clock ticking every 4 µs as suggested
+/// by the RFC, so that a new incarnation of a connection doesn't reuse the
+/// sequence numbers of the previous one.
+fn initial_sequence_number() -> u32 {
+    let freq = timestamp_frequency_or_default();
+    (timestamp() / (freq / 250_000).max(1)) as u32
+}
+
+struct Tcp {
+    listeners: Vec<u16>,
+    connections: Vec<Connection>,
+    next_id: u64,
+    /// The timer armed for the earliest deadline of the connections, along
+    /// with that deadline.
+    timer: Option<(u64, Timer)>,
+}
+
+impl Tcp {
+    const fn new() -> Self {
+        Self {
+            listeners: Vec::new(),
+            connections: Vec::new(),
+            next_id: 0,
+            timer: None,
+        }
+    }
+
+    /// Forget the closed connections nobody can see anymore, and arm the timer
+    /// for the earliest deadline of the others.
+    fn update_timer(&mut self) {
+        self.connections.retain(|conn| {
+            conn.state != TcpState::Closed || (conn.accepted && !conn.orphaned)
+        });
+
+        let deadline = self.connections.iter()
+            .filter_map(|conn| conn.deadline)
+            .min();
+        if self.timer.map(|(deadline, _)| deadline) == deadline {
+            return;
+        }
+
+        if let Some((_, timer)) = self.timer.take() {
+            timer.cancel();
+        }
+        self.timer = deadline.map(|deadline| {
+            let delay = deadline.saturating_sub(now_ms());
+            (deadline, Timer::after(Duration::from_millis(delay), run_timers))
+        });
+    }
+
+    fn connection(&mut self, id: u64) -> Option<&mut Connection> {
+        self.connections.iter_mut().find(|conn| conn.id == id)
+    }
+}
+
+struct Connection {
+    id: u64,
+    local_port: u16,
+    remote: Endpoint,
+    state: TcpState,
+    /// Whether the connection was returned by its listener.
+    accepted: bool,
+    /// Whether its stream was dropped: it is forgotten once closed.
+    orphaned: bool,
+    /// Whether the connection was reset, by the peer or on timeout.
+    reset: bool,
+
+    /// The oldest unacknowledged sequence number, and the next one to send.
+    snd_una: u32,
+    snd_nxt: u32,
+    /// The peer's receive window, from `snd_una`.
+    snd_wnd: u32,
+    /// The peer's maximum segment size.
+    snd_mss: usize,
+    /// The data to send from `snd_una` on; its first `sent` bytes were sent.
+    send_buf: VecDeque<u8>,
+    sent: usize,
+    /// Whether the stream was dropped, and a FIN is to be sent after the data.
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+
+    /// The next sequence number expected from the peer.
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+
+    /// When the retransmission timer expires, in milliseconds; in the
+    /// `FinWait2` and `TimeWait` states, when the connection closes.
+    deadline: Option<u64>,
+    rto_ms: u64,
+    retries: u32,
+}
+
+impl Connection {
+    /// A connection opened by the SYN `syn` from `remote` to `local_port`; the
+    /// SYN-ACK is still to be sent.
+    fn new(
+        id: u64,
+        local_port: u16,
+        remote: Endpoint,
+        syn: &TcpHeader,
+    ) -> Self {
+        let iss = initial_sequence_number();
+
+        Self {
+            id,
+            local_port,
+            remote,
+            state: TcpState::SynReceived,
+            accepted: false,
+            orphaned: false,
+            reset: false,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: syn.window as u32,
+            snd_mss: syn.mss.map_or(DEFAULT_MSS, |mss| mss as usize)
+                .clamp(1, MAX_SEGMENT_LEN),
+            send_buf: VecDeque::new(),
+            sent: 0,
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            rcv_nxt: syn.seq.wrapping_add(1),
+            recv_buf: VecDeque::new(),
+            deadline: None,
+            rto_ms: INITIAL_RTO_MS,
+            retries: 0,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUFFER_LEN - self.recv_buf.len()) as u16
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+
+    fn send(&self, iface: &Interface, seq: u32, flags: u8, payload: &[u8]) {
+        let header = TcpHeader {
+            src_port: self.local_port,
+            dst_port: self.remote.port,
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.window(),
+            mss: (flags & FLAG_SYN != 0).then_some(MAX_SEGMENT_LEN as u16),
+        };
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = header.build(payload, iface.cidr.addr, self.remote.addr,
+                               &mut buf);
+
+        // Lost segments are recovered by retransmission.
+        let _ = ipv4::send(iface, self.remote.addr, PROTOCOL_TCP, &buf[..len]);
+    }
+
+    fn send_ack(&self, iface: &Interface) {
+        self.send(iface, self.snd_nxt, FLAG_ACK, &[]);
+    }
+
+    /// Send the `len` bytes of `send_buf` from `offset`, at their sequence
+    /// number.
+    fn send_data(&self, iface: &Interface, offset: usize, len: usize) {
+        let mut payload = [0; MAX_SEGMENT_LEN];
+        for (dst, &src) in payload.iter_mut()
+            .zip(self.send_buf.range(offset..(offset + len))) {
+            *dst = src;
+        }
+
+        let seq = self.snd_una.wrapping_add(offset as u32);
+        self.send(iface, seq, FLAG_ACK | FLAG_PSH, &payload[..len]);
+    }
+
+    /// Send the queued data the peer's window has room for, then the FIN once
+    /// all data is sent.
+    fn transmit(&mut self, iface: &Interface, now: u64) {
+        if !matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            return;
+        }
+
+        while self.sent < self.send_buf.len() {
+            let window = (self.snd_wnd as usize).saturating_sub(self.sent);
+            let len = (self.send_buf.len() - self.sent)
+                .min(self.snd_mss)
+                .min(window);
+            if len == 0 {
+                break;
+            }
+
+            self.send_data(iface, self.sent, len);
+            self.sent += len;
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        if self.fin_queued && !self.fin_sent
+            && self.sent == self.send_buf.len() {
+            self.send(iface, self.snd_nxt, FLAG_FIN | FLAG_ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                TcpState::Established => TcpState::FinWait1,
+                _ => TcpState::LastAck,
+            };
+        }
+
+        if self.in_flight() > 0 && self.deadline.is_none() {
+            self.deadline = Some(now + self.rto_ms);
+        }
+    }
+
+    /// Handle the acknowledgment of `acked` more sequence numbers.
+    fn on_ack(&mut self, acked: u32, now: u64) {
+        let mut acked = acked as usize;
+        self.snd_una = self.snd_una.wrapping_add(acked as u32);
+
+        if self.state == TcpState::SynReceived {
+            acked -= 1;
+            self.state = TcpState::Established;
+        }
+
+        let data = acked.min(self.sent);
+        self.send_buf.drain(..data);
+        self.sent -= data;
+        acked -= data;
+
+        if acked > 0 && self.fin_sent {
+            self.fin_acked = true;
+            self.state = match self.state {
+                TcpState::FinWait1 => TcpState::FinWait2,
+                TcpState::Closing => TcpState::TimeWait,
+                TcpState::LastAck => TcpState::Closed,
+                state => state,
+            };
+        }
+
+        self.retries = 0;
+        self.rto_ms = INITIAL_RTO_MS;
+        self.deadline = match self.state {
+            TcpState::FinWait2 => Some(now + FIN_WAIT_2_TIMEOUT_MS),
+            TcpState::TimeWait => Some(now + TIME_WAIT_MS),
+            _ if self.in_flight() > 0 => Some(now + self.rto_ms),
+            _ => None,
+        };
+    }
+
+    /// Handle the segment `seg` of payload `payload`, received from the peer.
+    fn on_segment(
+        &mut self,
+        iface: &Interface,
+        now: u64,
+        seg: &TcpHeader,
+        payload: &[u8],
+    ) {
+        if seg.flags & FLAG_RST != 0 {
+            let offset = seg.seq.wrapping_sub(self.rcv_nxt);
+            if offset <= self.window() as u32 {
+                self.state = TcpState::Closed;
+                self.reset = true;
+                self.deadline = None;
+            }
+            return;
+        }
+
+        if seg.flags & FLAG_SYN != 0 {
+            // The peer didn't get our SYN-ACK, or is confused: answer it with
+            // the same SYN-ACK, or with where we stand.
+            if self.state == TcpState::SynReceived {
+                self.send(iface, self.snd_una, FLAG_SYN | FLAG_ACK, &[]);
+            } else {
+                self.send_ack(iface);
+            }
+            return;
+        }
+
+        if seg.flags & FLAG_ACK == 0 {
+            return;
+        }
+        let acked = seg.ack.wrapping_sub(self.snd_una);
+        if acked > self.in_flight() {
+            // An acknowledgment of something not sent yet, or a duplicate.
+            if seq_lt(self.snd_nxt, seg.ack) {
+                self.send_ack(iface);
+                return;
+            }
+        } else if acked > 0 {
+            self.on_ack(acked, now);
+        }
+        if self.state == TcpState::SynReceived {
+            return;
+        }
+        self.snd_wnd = seg.window as u32;
+
+        let is_fin = seg.flags & FLAG_FIN != 0;
+        let mut need_ack = false;
+        if !payload.is_empty() || is_fin {
+            if seg.seq != self.rcv_nxt {
+                // Out of order, or already received: tell where we stand.
+                self.send_ack(iface);
+                return;
+            }
+            need_ack = true;
+
+            if matches!(self.state, TcpState::Established | TcpState::FinWait1
+                                    | TcpState::FinWait2) {
+                let room = RECV_BUFFER_LEN - self.recv_buf.len();
+                let len = payload.len().min(room);
+                self.recv_buf.extend(&payload[..len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+
+                // The FIN is only accepted along with all the data before it.
+                if is_fin && len == payload.len() {
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                    self.state = match self.state {
+                        TcpState::Established => TcpState::CloseWait,
+                        TcpState::FinWait1 if !self.fin_acked => {
+                            TcpState::Closing
+                        },
+                        _ => TcpState::TimeWait,
+                    };
+                    if self.state == TcpState::TimeWait {
+                        self.deadline = Some(now + TIME_WAIT_MS);
+                    }
+                }
+            }
+        }
+
+        let snd_nxt = self.snd_nxt;
+        self.transmit(iface, now);
+        if need_ack && self.snd_nxt == snd_nxt {
+            self.send_ack(iface);
+        }
+    }
+
+    /// Handle the expiry of the connection's timer, if due.
+    fn on_timer(&mut self, iface: &Interface, now: u64) {
+        match self.deadline {
+            Some(deadline) if now >= deadline => (),
+            _ => return,
+        }
+
+        if matches!(self.state, TcpState::FinWait2 | TcpState::TimeWait) {
+            self.state = TcpState::Closed;
+            self.deadline = None;
+            return;
+        }
+
+        if self.retries >= MAX_RETRIES {
+            self.abort(iface);
+            return;
+        }
+        self.retries += 1;
+        self.rto_ms = (self.rto_ms * 2).min(MAX_RTO_MS);
+        self.deadline = Some(now + self.rto_ms);
+
+        if self.state == TcpState::SynReceived {
+            self.send(iface, self.snd_una, FLAG_SYN | FLAG_ACK, &[]);
+        } else if self.sent > 0 {
+            self.send_data(iface, 0, self.sent.min(self.snd_mss));
+        } else if self.fin_sent && !self.fin_acked {
+            self.send(iface, self.snd_nxt.wrapping_sub(1),
+                      FLAG_FIN | FLAG_ACK, &[]);
+        }
+    }
+
+    /// Reset the connection.
+    fn abort(&mut self, iface: &Interface) {
+        if self.state != TcpState::Closed {
+            self.send(iface, self.snd_nxt, FLAG_RST, &[]);
+        }
+        self.state = TcpState::Closed;
+        self.reset = true;
+        self.deadline = None;
+    }
+}
+
+/// Answer the segment `seg`, for which there is no connection, with a reset.
+fn send_reset(
+    iface: &Interface,
+    ip: &Ipv4Header,
+    seg: &TcpHeader,
+    payload_len: usize,
+) {
+    let (seq, ack, flags) = if seg.flags & FLAG_ACK != 0 {
+        (seg.ack, 0, FLAG_RST)
+    } else {
+        (0, seg.seq.wrapping_add(seg.seq_len(payload_len)), FLAG_RST | FLAG_ACK)
+    };
+    let header = TcpHeader {
+        src_port: seg.dst_port,
+        dst_port: seg.src_port,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+    };
+    let mut buf = [0; MAX_PAYLOAD];
+    let len = header.build(&[], iface.cidr.addr, ip.src, &mut buf);
+
+    let _ = ipv4::send(iface, ip.src, PROTOCOL_TCP, &buf[..len]);
+}
+
+/// Handle the TCP segment `segment`, received on `iface` in the IPv4 datagram
+/// of header `header`.
+pub fn handle(iface: &Interface, header: &Ipv4Header, segment: &[u8]) {
+    if header.dst != iface.cidr.addr {
+        return;
+    }
+    let Some((seg, payload)) = TcpHeader::parse(segment, header.src,
+                                                header.dst) else {
+        return;
+    };
+    let remote = Endpoint { addr: header.src, port: seg.src_port };
+    let now = now_ms();
+
+    {
+        let mut tcp = TCP.lock();
+        let existing = tcp.connections.iter_mut().find(|conn| {
+            conn.local_port == seg.dst_port && conn.remote == remote
+                && conn.state != TcpState::Closed
+        });
+
+        if let Some(conn) = existing {
+            conn.on_segment(iface, now, &seg, payload);
+        } else if seg.flags & FLAG_RST != 0 {
+            return;
+        } else if seg.flags & (FLAG_SYN | FLAG_ACK) == FLAG_SYN
+            && tcp.listeners.contains(&seg.dst_port) {
+            let backlog = tcp.connections.iter()
+                .filter(|conn| {
+                    conn.local_port == seg.dst_port && !conn.accepted
+                })
+                .count();
+            if backlog >= MAX_BACKLOG {
+                return;
+            }
+
+            let id = tcp.next_id;
+            tcp.next_id += 1;
+            let mut conn = Connection::new(id, seg.dst_port, remote, &seg);
+            conn.send(iface, conn.snd_una, FLAG_SYN | FLAG_ACK, &[]);
+            conn.deadline = Some(now + conn.rto_ms);
+            tcp.connections.push(conn);
+        } else {
+            send_reset(iface, header, &seg, payload.len());
+            return;
+        }
+
+        tcp.update_timer();
+    }
+
+    WAITERS.notify_all();
+}
+
+/// Run the timers of the connections that are due; this is the callback of
+/// the TCP timer.
+fn run_timers() {
+    let Some(iface) = interface() else {
+        return;
+    };
+    let now = now_ms();
+
+    {
+        let mut tcp = TCP.lock();
+        tcp.timer = None;
+        for conn in tcp.connections.iter_mut() {
+            conn.on_timer(&iface, now);
+        }
+        tcp.update_timer();
+    }
+
+    WAITERS.notify_all();
+}
+
+/// A socket accepting the connections to a local port, until dropped.
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<Self, TcpError> {
+        let mut tcp = TCP.lock();
+        if tcp.listeners.contains(&port) {
+            return Err(TcpError::PortInUse(port));
+        }
+
+        tcp.listeners.push(port);
+        Ok(Self { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Return the oldest established connection not accepted yet, if any.
+    pub fn try_accept(&self) -> Option<TcpStream> {
+        let mut tcp = TCP.lock();
+        let conn = tcp.connections.iter_mut().find(|conn| {
+            conn.local_port == self.port && !conn.accepted
+                && matches!(conn.state, TcpState::Established
+                                        | TcpState::CloseWait)
+        })?;
+
+        conn.accepted = true;
+        Some(TcpStream { id: conn.id, remote: conn.remote })
+    }
+
+    /// Wait for a connection, and return it.
+    pub fn accept(&self) -> TcpStream {
+        WAITERS.wait_until(|| self.try_accept())
+    }
+}
+
+impl Drop for TcpListener {
+    /// Stop listening, and reset the connections not accepted yet.
+    fn drop(&mut self) {
+        let iface = interface();
+        let mut tcp = TCP.lock();
+
+        tcp.listeners.retain(|&port| port != self.port);
+        for conn in tcp.connections.iter_mut() {
+            if conn.local_port == self.port && !conn.accepted {
+                if let Some(iface) = &iface {
+                    conn.abort(iface);
+                }
+                conn.state = TcpState::Closed;
+            }
+        }
+        tcp.update_timer();
+    }
+}
+
+/// An established connection, closed when dropped.
+pub struct TcpStream {
+    id: u64,
+    remote: Endpoint,
+}
+
+impl TcpStream {
+    pub fn remote(&self) -> Endpoint {
+        self.remote
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.with_connection(|conn, _| conn.state)
+            .unwrap_or(TcpState::Closed)
+    }
+
+    fn with_connection<R>(
+        &self,
+        f: impl FnOnce(&mut Connection, &Interface) -> R,
+    ) -> Result<R, TcpError> {
+        let iface = interface().ok_or(TcpError::NoInterface)?;
+        let mut tcp = TCP.lock();
+        let conn = tcp.connection(self.id).ok_or(TcpError::Closed)?;
+        let result = f(conn, &iface);
+        tcp.update_timer();
+
+        Ok(result)
+    }
+
+    /// Read the received data into `buf`, returning its length; 0 once the
+    /// peer closed the connection and all its data was read.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        self.with_connection(|conn, iface| {
+            if conn.reset {
+                return Err(TcpError::Reset);
+            }
+            if conn.recv_buf.is_empty() {
+                return match conn.state {
+                    TcpState::Established | TcpState::FinWait1
+                    | TcpState::FinWait2 => Err(TcpError::WouldBlock),
+                    _ => Ok(0),
+                };
+            }
+
+            let was_full = (conn.window() as usize) < conn.snd_mss;
+            let len = conn.recv_buf.len().min(buf.len());
+            for (dst, src) in buf.iter_mut().zip(conn.recv_buf.drain(..len)) {
+                *dst = src;
+            }
+            // Tell the peer its window reopened.
+            if was_full {
+                conn.send_ack(iface);
+            }
+
+            Ok(len)
+        })?
+    }
+
+    /// Read the received data into `buf`, waiting for some to arrive; return
+    /// its length, 0 once the peer closed the connection.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        WAITERS.wait_until(|| match self.try_read(buf) {
+            Err(TcpError::WouldBlock) => None,
+            result => Some(result),
+        })
+    }
+
+    /// Queue as much of `data` as fits in the send buffer for sending; return
+    /// the number of bytes queued, possibly 0.
+    pub fn write(&self, data: &[u8]) -> Result<usize, TcpError> {
+        self.with_connection(|conn, iface| {
+            if conn.reset {
+                return Err(TcpError::Reset);
+            }
+            if !matches!(conn.state, TcpState::Established
+                                     | TcpState::CloseWait) {
+                return Err(TcpError::Closed);
+            }
+
+            let len = data.len().min(SEND_BUFFER_LEN - conn.send_buf.len());
+            conn.send_buf.extend(&data[..len]);
+            conn.transmit(iface, now_ms());
+            Ok(len)
+        })?
+    }
+
+    /// Queue all of `data` for sending, waiting for room in the send buffer.
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), TcpError> {
+        while !data.is_empty() {
+            let len = WAITERS.wait_until(|| match self.write(data) {
+                Ok(0) => None,
+                result => Some(result),
+            })?;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    /// Close the connection once the queued data is sent.
+    fn drop(&mut self) {
+        let _ = self.with_connection(|conn, iface| {
+            conn.orphaned = true;
+            conn.fin_queued = true;
+            conn.transmit(iface, now_ms());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::net::MacAddress;
+    use crate::net::ipv4::Ipv4Cidr;
+    use super::*;
+
+    const LOCAL: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+    const REMOTE: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+
+    fn segment(seq: u32, ack: u32, flags: u8) -> TcpHeader {
+        TcpHeader {
+            src_port: 40000,
+            dst_port: 23,
+            seq,
+            ack,
+            flags,
+            window: 1000,
+            mss: None,
+        }
+    }
+
+    #[test]
+    fn it_builds_and_parses_segments() {
+        let header = TcpHeader {
+            mss: Some(1460),
+            ..segment(1, 2, FLAG_SYN | FLAG_ACK)
+        };
+        let mut buf = [0; MAX_PAYLOAD];
+
+        let len = header.build(b"hi", LOCAL, REMOTE, &mut buf);
+        assert_eq!(len, HEADER_LEN + 4 + 2);
+
+        let (parsed, payload) = TcpHeader::parse(&buf[..len], LOCAL, REMOTE)
+            .unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, b"hi");
+        assert_eq!(TcpHeader::parse(&buf[..len], REMOTE, REMOTE), None);
+        assert_eq!(parse_mss(&[OPTION_NOP, OPTION_MSS, 4, 0x02, 0x18]),
+                   Some(536));
+    }
+
+    #[test]
+    fn it_runs_a_connection_through_its_states() {
+        let iface = Interface {
+            device: usize::MAX,
+            mac: MacAddress([2, 0, 0, 0, 0, 1]),
+            cidr: Ipv4Cidr { addr: LOCAL, prefix_len: 24 },
+            gateway: None,
+        };
+        let remote = Endpoint { addr: REMOTE, port: 40000 };
+        let syn = segment(100, 0, FLAG_SYN);
+        let mut conn = Connection::new(0, 23, remote, &syn);
+        let iss = conn.snd_una;
+
+        conn.on_segment(&iface, 0, &segment(101, iss + 1, FLAG_ACK), b"ls\n");
+        assert_eq!(conn.state, TcpState::Established);
+        assert_eq!(conn.recv_buf.iter().copied().collect::<Vec<_>>(), b"ls\n");
+        assert_eq!(conn.rcv_nxt, 104);
+
+        conn.send_buf.extend(b"hello");
+        conn.transmit(&iface, 0);
+        assert_eq!((conn.sent, conn.in_flight()), (5, 5));
+        assert!(conn.deadline.is_some());
+
+        conn.on_timer(&iface, INITIAL_RTO_MS);
+        assert_eq!(conn.retries, 1);
+
+        conn.on_segment(&iface, 0, &segment(104, iss + 6, FLAG_ACK), &[]);
+        assert!(conn.send_buf.is_empty());
+        assert_eq!(conn.deadline, None);
+
+        conn.on_segment(&iface, 0,
+                        &segment(104, iss + 6, FLAG_ACK | FLAG_FIN), &[]);
+        assert_eq!(conn.state, TcpState::CloseWait);
+
+        conn.fin_queued = true;
+        conn.transmit(&iface, 0);
+        assert_eq!(conn.state, TcpState::LastAck);
+        conn.on_segment(&iface, 0, &segment(105, iss + 7, FLAG_ACK), &[]);
+        assert_eq!(conn.state, TcpState::Closed);
+    }
+}
@@ -0,0 +1,304 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! UDP, RFC 768, with sockets for kernel code. A socket is bound to a local
+//! port, and receives the datagrams sent to it on the configured interface,
+//! queued until read; datagrams to unbound ports, or overflowing a socket's
+//! queue, are dropped.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::str::FromStr;
+use thiserror_no_std::Error;
+
+use crate::net::ipv4::{
+    self, pseudo_header_checksum, IpError, Ipv4Address, Ipv4Header, MAX_PAYLOAD,
+    PROTOCOL_UDP,
+};
+use crate::net::{interface, Interface};
+use crate::sync::{Spinlock, WaitQueue};
+
+pub const HEADER_LEN: usize = 8;
+pub const MAX_DATAGRAM_LEN: usize = MAX_PAYLOAD - HEADER_LEN;
+
+/// The range of the ports allocated to sockets bound to port 0.
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// The most datagrams queued on a socket.
+const QUEUE_LEN: usize = 16;
+
+static SOCKETS: Spinlock<Vec<Binding>> = Spinlock::new(Vec::new());
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// An IPv4 address and a UDP port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub addr: Ipv4Address,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, port) = s.split_once(':').ok_or(())?;
+
+        Ok(Self {
+            addr: addr.parse()?,
+            port: port.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UdpError {
+    #[error("no network interface is configured")]
+    NoInterface,
+
+    #[error("port {0} is already bound")]
+    PortInUse(u16),
+
+    #[error("no free port")]
+    NoFreePort,
+
+    #[error("datagram of {0} bytes too long")]
+    TooLong(usize),
+
+    #[error("{0}")]
+    Ip(#[from] IpError),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl UdpHeader {
+    /// Split the datagram `datagram`, sent from `src` to `dst`, into its header
+    /// and payload; `None` if it is malformed or its checksum is wrong.
+    pub fn parse(
+        datagram: &[u8],
+        src: Ipv4Address,
+        dst: Ipv4Address,
+    ) -> Option<(Self, &[u8])> {
+        if datagram.len() < HEADER_LEN {
+            return None;
+        }
+        let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        if len < HEADER_LEN || len > datagram.len() {
+            return None;
+        }
+        let datagram = &datagram[..len];
+
+        // A zero checksum means the sender didn't compute one.
+        let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+        if sum != 0
+            && pseudo_header_checksum(src, dst, PROTOCOL_UDP, datagram) != 0 {
+            return None;
+        }
+
+        let header = Self {
+            src_port: u16::from_be_bytes([datagram[0], datagram[1]]),
+            dst_port: u16::from_be_bytes([datagram[2], datagram[3]]),
+        };
+
+        Some((header, &datagram[HEADER_LEN..]))
+    }
+
+    /// Write the datagram of this header and `payload`, from `src` to `dst`,
+    /// into `buf`; return its length.
+    pub fn build(
+        &self,
+        payload: &[u8],
+        src: Ipv4Address,
+        dst: Ipv4Address,
+        buf: &mut [u8; MAX_PAYLOAD],
+    ) -> Result<usize, UdpError> {
+        if payload.len() > MAX_DATAGRAM_LEN {
+            return Err(UdpError::TooLong(payload.len()));
+        }
+        let len = HEADER_LEN + payload.len();
+
+        buf[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        buf[6..8].fill(0);
+        buf[HEADER_LEN..len].copy_from_slice(payload);
+
+        // A computed checksum of zero is sent as all ones, zero meaning none.
+        let sum = pseudo_header_checksum(src, dst, PROTOCOL_UDP, &buf[..len]);
+        let sum = match sum {
+            0 => 0xffff,
+            sum => sum,
+        };
+        buf[6..8].copy_from_slice(&sum.to_be_bytes());
+
+        Ok(len)
+    }
+}
+
+struct Datagram {
+    src: Endpoint,
+    data: Vec<u8>,
+}
+
+struct Binding {
+    port: u16,
+    queue: VecDeque<Datagram>,
+}
+
+/// A UDP socket, bound to a local port until dropped.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind a socket to `port`, or to a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<Self, UdpError> {
+        let mut sockets = SOCKETS.lock();
+        let is_free = |port| sockets.iter().all(|b: &Binding| b.port != port);
+
+        let port = if port == 0 {
+            EPHEMERAL_PORTS.into_iter()
+                .find(|&port| is_free(port))
+                .ok_or(UdpError::NoFreePort)?
+        } else if is_free(port) {
+            port
+        } else {
+            return Err(UdpError::PortInUse(port));
+        };
+
+        sockets.push(Binding { port, queue: VecDeque::new() });
+        Ok(Self { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send `payload` in a datagram to `dst`. This doesn't wait: the datagram
+    /// is dropped if `dst`'s link-layer address isn't resolved yet, or if the
+    /// device is busy.
+    pub fn send_to(&self, payload: &[u8], dst: Endpoint) -> Result<(), UdpError> {
+        let iface = interface().ok_or(UdpError::NoInterface)?;
+        let header = UdpHeader { src_port: self.port, dst_port: dst.port };
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = header.build(payload, iface.cidr.addr, dst.addr, &mut buf)?;
+
+        ipv4::send(&iface, dst.addr, PROTOCOL_UDP, &buf[..len])?;
+        Ok(())
+    }
+
+    /// Pop the oldest datagram received into `buf`, returning its length and
+    /// sender; `None` if there is none. Datagrams longer than `buf` are
+    /// truncated.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Endpoint)> {
+        let datagram = SOCKETS.lock().iter_mut()
+            .find(|binding| binding.port == self.port)?
+            .queue
+            .pop_front()?;
+
+        let len = datagram.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Some((len, datagram.src))
+    }
+
+    /// Receive a datagram into `buf`, waiting for one to arrive; return its
+    /// length and sender.
+    pub fn recv_from(&self, buf: &mut [u8]) -> (usize, Endpoint) {
+        WAITERS.wait_until(|| self.try_recv_from(buf))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().retain(|binding| binding.port != self.port);
+    }
+}
+
+/// Handle the UDP datagram `datagram`, received on `iface` in the IPv4
+/// datagram of header `header`.
+pub fn handle(_iface: &Interface, header: &Ipv4Header, datagram: &[u8]) {
+    let Some((udp, payload)) = UdpHeader::parse(datagram, header.src,
+                                                header.dst) else {
+        return;
+    };
+
+    {
+        let mut sockets = SOCKETS.lock();
+        let Some(binding) = sockets.iter_mut()
+            .find(|binding| binding.port == udp.dst_port) else {
+            return;
+        };
+        if binding.queue.len() >= QUEUE_LEN {
+            return;
+        }
+
+        binding.queue.push_back(Datagram {
+            src: Endpoint { addr: header.src, port: udp.src_port },
+            data: payload.to_vec(),
+        });
+    }
+
+    WAITERS.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_and_parses_datagrams() {
+        let src = Ipv4Address([10, 0, 2, 15]);
+        let dst = Ipv4Address([10, 0, 2, 2]);
+        let header = UdpHeader { src_port: 49152, dst_port: 514 };
+        let mut buf = [0; MAX_PAYLOAD];
+
+        let len = header.build(b"hello", src, dst, &mut buf).unwrap();
+        assert_eq!(len, HEADER_LEN + 5);
+
+        let (parsed, payload) = UdpHeader::parse(&buf[..len], src, dst)
+            .unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, b"hello");
+        assert_eq!(UdpHeader::parse(&buf[..len], dst, dst), None);
+    }
+
+    #[test]
+    fn it_allocates_ports() {
+        let a = UdpSocket::bind(0).unwrap();
+        let b = UdpSocket::bind(0).unwrap();
+        assert_ne!(a.local_port(), b.local_port());
+
+        assert!(matches!(UdpSocket::bind(a.local_port()),
+                         Err(UdpError::PortInUse(_))));
+        let port = a.local_port();
+        drop(a);
+        assert!(UdpSocket::bind(port).is_ok());
+    }
+
+    #[test]
+    fn it_parses_endpoints() {
+        assert_eq!("10.0.2.2:514".parse(), Ok(Endpoint {
+            addr: Ipv4Address([10, 0, 2, 2]),
+            port: 514,
+        }));
+        assert!("10.0.2.2".parse::<Endpoint>().is_err());
+    }
+}
@@ -8,8 +8,11 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use arrayvec::ArrayVec;
 use core::fmt;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 #[cfg(not(test))]
 use core::panic::PanicInfo;
@@ -18,10 +21,147 @@ use crate::arch::cpu::MachineState;
 use crate::{arch, print, println};
 use crate::arch::logging::LOGGER_SERIAL;
 use crate::backtrace::Backtrace;
+use crate::buildinfo::BUILD_ID;
+use crate::crashdump;
+#[cfg(feature = "fb-terminal")]
+use crate::driver::screen::Color;
 use crate::driver::vga::VgaScreen;
+use crate::mem::kalloc::KERNEL_ALLOCATOR;
+use crate::misc::Fnv1a;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::task::idle::nr_cpus;
+use crate::ui::kterm::{self, PANIC_TEXT_SCREEN};
+#[cfg(feature = "fb-terminal")]
+use crate::ui::kterm::PANIC_FRAMEBUFFER;
+#[cfg(feature = "fb-terminal")]
+use crate::ui::rawterm::RawTerminal;
+use crate::ui::textterm::TextTerminal;
 
 static PANIC_ENTERED: AtomicBool = AtomicBool::new(false);
 
+/// Set once a panic was reported with the raw renderer; this lets a panic
+/// triggered from within the panic handler be reported once, without recursing.
+static PANIC_RAW_ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// The index of the panicking CPU.
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(0);
+
+/// The states of the other CPUs, with their index, saved as they were stopped
+/// by the panicking one.
+static STOPPED_CPUS: Spinlock<ArrayVec<(usize, MachineState), MAX_CPUS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+/// How long to wait for the other CPUs to stop, in microseconds.
+const STOP_TIMEOUT_US: u64 = 100_000;
+
+static mut PANIC_POLICY: PanicPolicy = PanicPolicy::Halt;
+
+/// Set to `false` from an attached debugger to let a panicking kernel waiting
+/// with `PanicPolicy::Debugger` proceed to halting.
+#[no_mangle]
+static PANIC_WAIT_FOR_DEBUGGER: AtomicBool = AtomicBool::new(true);
+
+/// What to do once a kernel panic was reported, as selected by the `panic=`
+/// boot option.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PanicPolicy {
+    /// Permanently halt the CPU (`panic=halt`); this is the default.
+    Halt,
+
+    /// Reboot the machine after the given number of seconds (`panic=reboot` or
+    /// `panic=reboot:<secs>`); meant for unattended test rigs.
+    Reboot { timeout_secs: u32 },
+
+    /// Wait for a debugger, such as QEMU's GDB stub, to attach and inspect the
+    /// machine (`panic=debug`). Clearing `PANIC_WAIT_FOR_DEBUGGER` from the
+    /// debugger lets the kernel halt.
+    Debugger,
+}
+
+const DEFAULT_REBOOT_TIMEOUT_SECS: u32 = 10;
+
+/// The number of backtrace frames, after the skipped ones, that are hashed into
+/// the panic fingerprint.
+const FINGERPRINT_FRAMES: usize = 3;
+
+/// A short hash of the panic message and of the top frames of the backtrace,
+/// displayed as `XXXX-XXXX`. The same bug gives the same fingerprint across
+/// machines, and across builds as long as the involved functions don't change,
+/// which lets testers' reports be matched with known issues.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PanicFingerprint(pub u32);
+
+impl PanicFingerprint {
+    pub fn compute(
+        message: fmt::Arguments,
+        machine: Option<&MachineState>,
+        skip_frames: usize,
+    ) -> Self {
+        let mut hasher = Fnv1a::new();
+        let _ = write!(hasher, "{message}");
+
+        if let Some(machine) = machine {
+            let frames = Backtrace::from_machine_state(machine)
+                .skip(skip_frames)
+                .take(FINGERPRINT_FRAMES);
+
+            for frame in frames {
+                // Symbol names rather than addresses, which change with every
+                // build.
+                match frame.symbol {
+                    Some(sym) => hasher.write_bytes(sym.as_bytes()),
+                    None => { let _ = write!(hasher, "{:x}", frame.pc.0); },
+                }
+            }
+        }
+
+        Self(hasher.finish())
+    }
+}
+
+impl fmt::Display for PanicFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04X}-{:04X}", self.0 >> 16, self.0 & 0xffff)
+    }
+}
+
+impl FromStr for PanicPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, arg) = s.split_once(':')
+            .map(|(k, v)| (k, Some(v)))
+            .unwrap_or((s, None));
+
+        Ok(match (policy, arg) {
+            ("halt", None) => PanicPolicy::Halt,
+            ("reboot", None) => PanicPolicy::Reboot {
+                timeout_secs: DEFAULT_REBOOT_TIMEOUT_SECS,
+            },
+            ("reboot", Some(secs)) => PanicPolicy::Reboot {
+                timeout_secs: secs.parse().map_err(|_| ())?,
+            },
+            ("debug", None) => PanicPolicy::Debugger,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Select what the kernel does after a panic was reported.
+///
+/// # Safety #
+///
+/// This function must only be called during the early boot process, when only
+/// one CPU is running.
+pub unsafe fn set_panic_policy(policy: PanicPolicy) {
+    PANIC_POLICY = policy;
+}
+
+pub fn panic_policy() -> PanicPolicy {
+    unsafe { PANIC_POLICY }
+}
+
 pub fn panic_at_state(
     message: fmt::Arguments,
     machine: Option<&MachineState>,
@@ -33,22 +173,127 @@ pub fn panic_at_state(
     if PANIC_ENTERED.compare_exchange(false, true,
                                       Ordering::SeqCst, Ordering::SeqCst)
         .is_err() {
+        // We most likely panicked while reporting a panic, probably because
+        // the terminal or the heap is in an inconsistent state; try to tell so
+        // with the raw renderer, only once. The fingerprint isn't computed
+        // here: the unwinding it needs may well be what panicked.
+        if !PANIC_RAW_ENTERED.swap(true, Ordering::SeqCst) {
+            print_raw(format_args!("panic while panicking: {message}"),
+                      None, machine, skip_frames);
+        }
         arch::cpu::perm_halt();
     }
 
-    let logger = unsafe { LOGGER_SERIAL.as_mut() };
+    stop_other_cpus();
 
     // We do nothing if the screen was not initialized, meaning we panicked very
     // early in the boot process and aren't able to print anything.
-    if let Some(logger) = logger {
+    if let Some(logger) = unsafe { LOGGER_SERIAL.as_mut() } {
         print_panic(logger, message, machine);
     }
 
-    print_terminal(message, machine, skip_frames);
+    // Computing the fingerprint unwinds the stack, which may be corrupt: this
+    // is only done once the primary report is out, as a best effort.
+    let fingerprint = PanicFingerprint::compute(message, machine, skip_frames);
+    if let Some(logger) = unsafe { LOGGER_SERIAL.as_mut() } {
+        let _ = writeln!(logger, "Fingerprint {fingerprint}, build {BUILD_ID}");
+    }
+
+    // The dump goes out before the terminal report, which may hang while
+    // rendering onto a framebuffer in an inconsistent state.
+    if crashdump::is_enabled() {
+        if let Some(logger) = unsafe { LOGGER_SERIAL.as_mut() } {
+            let _ = crashdump::write_crash_dump(logger, message, fingerprint,
+                                                machine, skip_frames);
+        }
+    }
+
+    #[cfg(all(feature = "ktest", not(test)))]
+    crate::ktest::on_panic();
+
+    // The regular terminal allocates memory; it can't be used if we panicked
+    // within the allocator, or before it was even created.
+    let terminal_usable = !KERNEL_ALLOCATOR.is_busy() && kterm::is_available();
+
+    if terminal_usable {
+        print_terminal(message, fingerprint, machine, skip_frames);
+    } else {
+        PANIC_RAW_ENTERED.store(true, Ordering::SeqCst);
+        print_raw(message, Some(fingerprint), machine, skip_frames);
+    }
+
+    apply_panic_policy(terminal_usable);
+}
+
+/// Stop the other CPUs, so that they don't keep running on a kernel in an
+/// unknown state, and wait a little for them to save their states. There are
+/// none until the application processors are brought up, see `task::smp`.
+fn stop_other_cpus() {
+    PANIC_CPU.store(current_cpu_index().get(), Ordering::SeqCst);
+    if nr_cpus() == 1 {
+        return;
+    }
+
+    arch::smp::stop_others();
+
+    for _ in 0..(STOP_TIMEOUT_US / 1000) {
+        if STOPPED_CPUS.lock().len() == nr_cpus() - 1 {
+            break;
+        }
+        arch::time::delay_us(1000);
+    }
+}
+
+/// Stop the current CPU for good if another one panicked, saving its state
+/// `machine` for the panic report; this is meant to be called by the handler
+/// of non-maskable interrupts, before anything else.
+pub fn stop_if_panicking(machine: &MachineState) {
+    let cpu = current_cpu_index().get();
+    if !PANIC_ENTERED.load(Ordering::SeqCst)
+        || PANIC_CPU.load(Ordering::SeqCst) == cpu {
+        return;
+    }
+
+    let _ = STOPPED_CPUS.lock().try_push((cpu, machine.clone()));
+    arch::cpu::perm_halt();
+}
+
+/// Apply the panic policy; its messages only go to the terminal if
+/// `terminal_usable`, since its locks may be held by the panicking code.
+fn apply_panic_policy(terminal_usable: bool) -> ! {
+    match panic_policy() {
+        PanicPolicy::Halt => (),
+        PanicPolicy::Reboot { timeout_secs } => {
+            tell_policy(terminal_usable, format_args!(
+                "Rebooting in {timeout_secs} seconds..."
+            ));
+            arch::time::delay_us(timeout_secs as u64 * 1_000_000);
+            arch::cpu::reset();
+        },
+        PanicPolicy::Debugger => {
+            tell_policy(terminal_usable,
+                        format_args!("Waiting for a debugger to attach..."));
+
+            while PANIC_WAIT_FOR_DEBUGGER.load(Ordering::SeqCst) {
+                core::hint::spin_loop();
+            }
+        },
+    }
 
     arch::cpu::perm_halt();
 }
 
+/// Print the message of the panic policy onto the serial line, and onto the
+/// terminal if `terminal_usable`.
+fn tell_policy(terminal_usable: bool, message: fmt::Arguments) {
+    if terminal_usable {
+        println!("{message}");
+    }
+    if let Some(logger) = unsafe { LOGGER_SERIAL.as_mut() } {
+        let _ = writeln!(logger, "{message}");
+    }
+}
+
 #[allow(unused_must_use)]
 fn print_panic_screen(
     vga: &mut impl VgaScreen,
@@ -99,33 +344,138 @@ fn print_panic(
     if let Some(machine) = machine {
         writeln!(w, "{}", machine);
     }
+    for_each_stopped_cpu(|cpu, machine| {
+        writeln!(w, "CPU {cpu}:\n{machine}");
+    });
 }
 
 fn print_terminal(
     message: fmt::Arguments,
+    fingerprint: PanicFingerprint,
     machine: Option<&MachineState>,
     skip_frames: usize,
 ) {
-    println!("\x1b<nl;fg=f00;bg=ff0>KERNEL PANIC!\x1b<!bg> {message}");
+    print!("\x1b<nl;fg=f00;bg=ff0>");
+    kterm::print_banner("KERNEL PANIC!");
+    println!("\x1b<!bg>{message}");
+    println!("Fingerprint \x1b<fg=fff>{fingerprint}\x1b<!fg>, build {BUILD_ID}");
 
     if let Some(machine) = machine {
-        machine.print_term();
+        print_terminal_state(machine, skip_frames);
+    }
+    for_each_stopped_cpu(|cpu, machine| {
+        println!("\n\x1b<fg=fff>CPU {cpu}:\x1b<!fg>");
+        print_terminal_state(machine, 0);
+    });
+}
 
-        for frame in Backtrace::from_machine_state(machine).skip(skip_frames) {
-            if let Some(sym) = frame.symbol {
-                println!("  > \x1b<fg=fff>{sym}\x1b<!fg>");
-            } else {
-                println!("  > ???");
-            }
-            print!("      ");
-            if let Some((file, line)) = frame.file_line {
-                print!("{file}:{line}    ");
-            }
-            if let Some(sym_off) = frame.sym_off {
-                println!("<0x{:?} + {sym_off:#x}>", frame.pc);
-            } else {
-                println!("<0x{:?}>", frame.pc);
-            }
+fn print_terminal_state(machine: &MachineState, skip_frames: usize) {
+    machine.print_term();
+
+    for frame in Backtrace::from_machine_state(machine).skip(skip_frames) {
+        if let Some(sym) = frame.symbol {
+            println!("  > \x1b<fg=fff>{sym}\x1b<!fg>");
+        } else {
+            println!("  > ???");
+        }
+        print!("      ");
+        if let Some((file, line)) = frame.file_line {
+            print!("{file}:{line}    ");
+        }
+        if let Some(sym_off) = frame.sym_off {
+            println!("<0x{:?} + {sym_off:#x}>", frame.pc);
+        } else {
+            println!("<0x{:?}>", frame.pc);
+        }
+    }
+}
+
+/// Report the panic directly onto the framebuffer, or the text screen in text
+/// mode, without allocating any memory nor taking any lock.
+#[allow(unused_must_use)]
+fn print_raw(
+    message: fmt::Arguments,
+    fingerprint: Option<PanicFingerprint>,
+    machine: Option<&MachineState>,
+    skip_frames: usize,
+) {
+    #[cfg(feature = "fb-terminal")]
+    if let Some(fb) = unsafe { PANIC_FRAMEBUFFER.as_mut() } {
+        let mut term = RawTerminal::new(&mut *fb);
+        let white = Color { r: 0xff, g: 0xff, b: 0xff };
+        let red = Color { r: 0xaa, g: 0x00, b: 0x00 };
+        let black = Color { r: 0x00, g: 0x00, b: 0x00 };
+
+        term.set_colors(white, black);
+        term.clear();
+        term.set_colors(white, red);
+        write!(term, "KERNEL PANIC!");
+        term.set_colors(white, black);
+        writeln!(term, " {message}");
+        print_raw_fingerprint(&mut term, fingerprint);
+        print_raw_details(&mut term, machine, skip_frames);
+        print_raw_stopped_cpus(&mut term);
+        fb.flush();
+        return;
+    }
+
+    if let Some(screen) = unsafe { PANIC_TEXT_SCREEN.as_mut() } {
+        let mut term = TextTerminal::new(&mut **screen);
+
+        writeln!(term, "\x1b<fg=fff;bg=a00>KERNEL PANIC!\x1b<!bg> {message}");
+        print_raw_fingerprint(&mut term, fingerprint);
+        print_raw_details(&mut term, machine, skip_frames);
+        print_raw_stopped_cpus(&mut term);
+    }
+}
+
+#[allow(unused_must_use)]
+fn print_raw_fingerprint(
+    term: &mut impl fmt::Write,
+    fingerprint: Option<PanicFingerprint>,
+) {
+    match fingerprint {
+        Some(fingerprint) => {
+            writeln!(term, "Fingerprint {fingerprint}, build {BUILD_ID}\n")
+        },
+        None => writeln!(term, "Build {BUILD_ID}\n"),
+    };
+}
+
+#[allow(unused_must_use)]
+fn print_raw_details(
+    term: &mut impl fmt::Write,
+    machine: Option<&MachineState>,
+    skip_frames: usize,
+) {
+    let Some(machine) = machine else {
+        return;
+    };
+    writeln!(term, "{machine}");
+
+    for frame in Backtrace::from_machine_state(machine).skip(skip_frames) {
+        write!(term, "  > {:?}", frame.pc);
+        if let (Some(sym), Some(off)) = (frame.symbol, frame.sym_off) {
+            write!(term, " {sym}+{off:#x}");
+        }
+        writeln!(term);
+    }
+}
+
+#[allow(unused_must_use)]
+fn print_raw_stopped_cpus(term: &mut impl fmt::Write) {
+    for_each_stopped_cpu(|cpu, machine| {
+        writeln!(term, "\nCPU {cpu}:");
+        print_raw_details(term, Some(machine), 0);
+    });
+}
+
+/// Call `f` with the index and the saved state of each CPU stopped by the
+/// panic; nothing is called if the states are still being saved.
+fn for_each_stopped_cpu(mut f: impl FnMut(usize, &MachineState)) {
+    if let Some(stopped) = STOPPED_CPUS.try_lock() {
+        for (cpu, machine) in stopped.iter() {
+            f(*cpu, machine);
         }
     }
 }
@@ -149,3 +499,31 @@ fn panic_handler(panic_info: &PanicInfo) -> ! {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_panic_policies() {
+        assert_eq!("halt".parse(), Ok(PanicPolicy::Halt));
+        assert_eq!("reboot".parse(), Ok(PanicPolicy::Reboot {
+            timeout_secs: DEFAULT_REBOOT_TIMEOUT_SECS,
+        }));
+        assert_eq!("reboot:0".parse(), Ok(PanicPolicy::Reboot {
+            timeout_secs: 0,
+        }));
+        assert_eq!("reboot:30".parse(), Ok(PanicPolicy::Reboot {
+            timeout_secs: 30,
+        }));
+        assert_eq!("debug".parse(), Ok(PanicPolicy::Debugger));
+    }
+
+    #[test]
+    fn it_rejects_invalid_panic_policies() {
+        for invalid in ["", "poweroff", "Halt", "halt:5", "debug:1", "reboot:",
+                        "reboot:-1", "reboot:soon", "reboot:1:2"] {
+            assert_eq!(invalid.parse::<PanicPolicy>(), Err(()), "{invalid}");
+        }
+    }
+}
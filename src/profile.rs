@@ -0,0 +1,314 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A sampling profiler. While it is enabled, a timer of its own, the LAPIC
+//! timer on x86, interrupts the CPU `SAMPLE_HZ` times a second, recording
+//! where the CPU was interrupted into a fixed-size table of sample counts,
+//! keyed by instruction pointer; recording never waits nor allocates, a sample
+//! is dropped when the table is busy or full. The timer is only started on the
+//! CPU enabling the profiler.
+//!
+//! The samples are only aggregated by function when a report is made, the
+//! function of a sample being found from the kernel's symbol table, see
+//! `ksyms`, or else from the unwind information, in which case it is only
+//! reported by its start address. Samples outside of any known function are
+//! reported by their own address.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use hashbrown::HashMap;
+use thiserror_no_std::Error;
+
+use crate::arch::time::{start_sample_timer, stop_sample_timer};
+use crate::backtrace;
+use crate::ksyms;
+use crate::mem::VAddr;
+use crate::sync::Spinlock;
+
+/// The number of samples taken per second.
+pub const SAMPLE_HZ: u32 = 1000;
+
+/// The number of distinct instruction pointers the table can count.
+const TABLE_LEN: usize = 1024;
+
+/// The number of functions reported by default.
+pub const DEFAULT_REPORT_LEN: usize = 20;
+
+static PROFILE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static SAMPLES: Spinlock<SampleTable> = Spinlock::new(SampleTable::new());
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("no timer to sample from on this machine")]
+    NoTimer,
+}
+
+#[derive(Copy, Clone)]
+struct Sample {
+    pc: u64,
+    count: u32,
+}
+
+/// An open-addressing hash table of sample counts.
+struct SampleTable {
+    samples: [Option<Sample>; TABLE_LEN],
+    nr_samples: u64,
+    /// The number of samples that couldn't be counted, the table being full.
+    nr_dropped: u64,
+}
+
+impl SampleTable {
+    const fn new() -> Self {
+        Self {
+            samples: [None; TABLE_LEN],
+            nr_samples: 0,
+            nr_dropped: 0,
+        }
+    }
+
+    fn add(&mut self, pc: u64) {
+        let start = (pc.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize;
+
+        for i in 0..TABLE_LEN {
+            let slot = &mut self.samples[(start + i) % TABLE_LEN];
+            match slot {
+                Some(sample) if sample.pc == pc => {
+                    sample.count += 1;
+                    self.nr_samples += 1;
+                    return;
+                },
+                Some(_) => (),
+                None => {
+                    *slot = Some(Sample { pc, count: 1 });
+                    self.nr_samples += 1;
+                    return;
+                },
+            }
+        }
+
+        self.nr_dropped += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter().flatten()
+    }
+
+    fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+/// The samples attributed to a function.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProfileEntry {
+    /// The function's start address, or the address sampled if it is not
+    /// within a known function.
+    pub addr: VAddr,
+    /// Whether `addr` is the start of a known function.
+    pub resolved: bool,
+    /// The function's name, if it is in the symbol table.
+    pub name: Option<&'static str>,
+    pub samples: u64,
+}
+
+/// The functions sampled most, the most sampled first.
+pub struct Report {
+    pub entries: Vec<ProfileEntry>,
+    pub nr_samples: u64,
+    pub nr_dropped: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} samples, {} dropped", self.nr_samples, self.nr_dropped)?;
+
+        for entry in &self.entries {
+            let percent = entry.samples as f64 * 100.0
+                / self.nr_samples.max(1) as f64;
+            write!(f, "{percent:5.1}% {:>8}  {:#018x}",
+                   entry.samples, entry.addr.0)?;
+            match (entry.name, entry.resolved) {
+                (Some(name), _) => writeln!(f, "  {name}")?,
+                (None, true) => writeln!(f)?,
+                (None, false) => writeln!(f, " (unknown function)")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Start or stop sampling; the samples taken so far are kept.
+pub fn set_enabled(enabled: bool) -> Result<(), ProfileError> {
+    if enabled {
+        PROFILE_ENABLED.store(true, Ordering::SeqCst);
+        if !start_sample_timer(SAMPLE_HZ) {
+            PROFILE_ENABLED.store(false, Ordering::SeqCst);
+            return Err(ProfileError::NoTimer);
+        }
+    } else {
+        stop_sample_timer();
+        PROFILE_ENABLED.store(false, Ordering::SeqCst);
+    }
+
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    PROFILE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Count a sample at the interrupted instruction `pc`, if profiling is
+/// enabled; this is meant to be called from the sample timer's interrupt
+/// handler, see `arch::time::start_sample_timer()`.
+#[inline]
+pub fn sample(pc: VAddr) {
+    if !is_enabled() {
+        return;
+    }
+
+    if let Some(mut samples) = SAMPLES.try_lock() {
+        samples.add(pc.0 as u64);
+    }
+}
+
+/// Discard all the samples.
+pub fn clear() {
+    SAMPLES.lock().clear();
+}
+
+/// Aggregate the samples by function and report the `nr_entries` functions
+/// sampled most.
+pub fn report(nr_entries: usize) -> Report {
+    let mut samples = Vec::with_capacity(TABLE_LEN);
+    let (nr_samples, nr_dropped) = {
+        // Allocating while the table is locked would deadlock if the heap is
+        // itself sampled; the vector is allocated beforehand.
+        let table = SAMPLES.lock();
+        samples.extend(table.iter().copied());
+        (table.nr_samples, table.nr_dropped)
+    };
+
+    Report {
+        entries: aggregate(&samples, nr_entries, function_of),
+        nr_samples,
+        nr_dropped,
+    }
+}
+
+/// The start address of the function containing `pc`, with its name if it is
+/// in the symbol table.
+fn function_of(pc: VAddr) -> Option<(VAddr, Option<&'static str>)> {
+    match ksyms::lookup(pc) {
+        Some((name, offset)) => Some((VAddr(pc.0 - offset), Some(name))),
+        None => backtrace::function_start(pc).map(|start| (start, None)),
+    }
+}
+
+/// Sum the `samples` of each function, as found by `function_of`, and return
+/// the `nr_entries` functions with the most samples.
+fn aggregate(
+    samples: &[Sample],
+    nr_entries: usize,
+    function_of: impl Fn(VAddr) -> Option<(VAddr, Option<&'static str>)>,
+) -> Vec<ProfileEntry> {
+    let mut functions: HashMap<(usize, bool, Option<&'static str>), u64>
+        = HashMap::new();
+
+    for sample in samples {
+        let pc = VAddr(sample.pc as usize);
+        let key = match function_of(pc) {
+            Some((start, name)) => (start.0, true, name),
+            None => (pc.0, false, None),
+        };
+        *functions.entry(key).or_default() += sample.count as u64;
+    }
+
+    let mut entries: Vec<ProfileEntry> = functions.into_iter()
+        .map(|((addr, resolved, name), samples)| ProfileEntry {
+            addr: VAddr(addr),
+            resolved,
+            name,
+            samples,
+        })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.samples.cmp(&a.samples).then(a.addr.0.cmp(&b.addr.0))
+    });
+    entries.truncate(nr_entries);
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_counts_samples_until_the_table_is_full() {
+        let mut table = SampleTable::new();
+        table.add(0x1000);
+        table.add(0x1000);
+        table.add(0x2000);
+
+        let mut samples: Vec<_> = table.iter()
+            .map(|sample| (sample.pc, sample.count))
+            .collect();
+        samples.sort();
+        assert_eq!(samples, [(0x1000, 2), (0x2000, 1)]);
+
+        for pc in 0..(TABLE_LEN as u64) {
+            table.add(0x10000 + pc);
+        }
+        assert_eq!(table.nr_samples, TABLE_LEN as u64 + 1);
+        assert_eq!(table.nr_dropped, 2);
+    }
+
+    #[test]
+    fn it_aggregates_samples_by_function() {
+        let samples = [
+            Sample { pc: 0x1004, count: 3 },
+            Sample { pc: 0x1010, count: 2 },
+            Sample { pc: 0x2008, count: 4 },
+            Sample { pc: 0x9000, count: 1 },
+        ];
+        let function_of = |pc: VAddr| match pc.0 {
+            0x1000..=0x1fff => Some((VAddr(0x1000), Some("nucloid::main"))),
+            0x2000..=0x2fff => Some((VAddr(0x2000), None)),
+            _ => None,
+        };
+
+        let entries = aggregate(&samples, 2, function_of);
+        assert_eq!(entries, [
+            ProfileEntry {
+                addr: VAddr(0x1000),
+                resolved: true,
+                name: Some("nucloid::main"),
+                samples: 5,
+            },
+            ProfileEntry {
+                addr: VAddr(0x2000),
+                resolved: true,
+                name: None,
+                samples: 4,
+            },
+        ]);
+
+        let entries = aggregate(&samples, 10, function_of);
+        assert_eq!(entries[2], ProfileEntry {
+            addr: VAddr(0x9000),
+            resolved: false,
+            name: None,
+            samples: 1,
+        });
+    }
+}
@@ -0,0 +1,47 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Support for the compiler's stack-smashing protector, enabled with
+//! `-Z stack-protector=strong`: functions with local buffers store the
+//! `__stack_chk_guard` canary between their locals and their return address,
+//! and call `__stack_chk_fail` if it changed when they return.
+
+use core::ptr;
+
+use crate::arch;
+use crate::arch::cpu::MachineState;
+use crate::panic::panic_at_state;
+
+/// The canary used until `init()` is called. Its low byte is zero, like the
+/// random one, so that an overflowing string copy can't write it back.
+const BOOT_CANARY: usize = 0x595e9fbd_94fda700;
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static mut __stack_chk_guard: usize = BOOT_CANARY;
+
+/// Replace the boot canary with a random one.
+///
+/// # Safety #
+///
+/// Every function on the call stack that checks the canary will fail this
+/// check when it returns: this must be called from a function that never
+/// returns, such as `arch_init`, into which it is inlined.
+#[inline(always)]
+pub unsafe fn init() {
+    let canary = arch::cpu::random_u64() as usize & !0xff;
+    ptr::write_volatile(ptr::addr_of_mut!(__stack_chk_guard), canary);
+}
+
+#[no_mangle]
+extern "C" fn __stack_chk_fail() -> ! {
+    let machine = MachineState::here();
+    panic_at_state(format_args!("stack smashing detected"), Some(&machine), 1);
+}
@@ -8,11 +8,17 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::sync::Arc;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::cell::UnsafeCell;
+use core::time::Duration;
 
 use crate::arch::sync::{push_critical_region, pop_critical_region};
+use crate::latency::{self, Section};
+use crate::lockdep;
+use crate::task::idle;
+use crate::task::timer::Timer;
 
 pub struct Spinlock<T> {
     lock: AtomicBool,
@@ -32,6 +38,7 @@ impl<T> Spinlock<T> {
 
     pub fn lock(&self) -> SpinlockGuard<T> {
         push_critical_region();
+        lockdep::acquire(self.addr(), false);
         while self.lock.compare_exchange_weak(false, true,
                                               Ordering::Acquire,
                                               Ordering::Relaxed).is_err() {
@@ -50,9 +57,31 @@ impl<T> Spinlock<T> {
         SpinlockGuard {
             lock: &self.lock,
             data,
+            start: latency::start(),
         }
     }
 
+    /// Acquire the lock if it is free, without waiting.
+    pub fn try_lock(&self) -> Option<SpinlockGuard<T>> {
+        push_critical_region();
+        if self.lock.compare_exchange(false, true,
+                                      Ordering::Acquire,
+                                      Ordering::Relaxed).is_err() {
+            pop_critical_region();
+            return None;
+        }
+        lockdep::acquire(self.addr(), true);
+
+        // Safety: see `lock()`.
+        let data = unsafe { &mut *self.data.get() };
+
+        Some(SpinlockGuard {
+            lock: &self.lock,
+            data,
+            start: latency::start(),
+        })
+    }
+
     /// Checks whether the lock is held right now, without any lock or
     /// synchronization.
     pub fn is_locked(&self) -> bool {
@@ -62,11 +91,18 @@ impl<T> Spinlock<T> {
     pub unsafe fn bypass_lock(&self) -> *mut T {
         self.data.get()
     }
+
+    /// The address identifying the lock for the lock dependency validator.
+    fn addr(&self) -> usize {
+        &self.lock as *const AtomicBool as usize
+    }
 }
 
 pub struct SpinlockGuard<'a, T> {
     lock: &'a AtomicBool,
     data: &'a mut T,
+    /// When the lock was acquired, for the latency watchdog.
+    start: u64,
 }
 
 impl<T> Deref for SpinlockGuard<'_, T> {
@@ -85,7 +121,76 @@ impl<T> DerefMut for SpinlockGuard<'_, T> {
 
 impl<T> Drop for SpinlockGuard<'_, T> {
     fn drop(&mut self) {
+        lockdep::release(self.lock as *const AtomicBool as usize);
         self.lock.store(false, Ordering::Release);
         pop_critical_region();
+        latency::check(Section::LockHeld, self.start);
+    }
+}
+
+/// A queue of waiters for an event signaled by an interrupt handler.
+///
+/// There is no scheduler yet, hence nothing to put to sleep: waiting idles the
+/// CPU until the next interrupt, which may be the one signaling the event, and
+/// checks again. An event signaled between the check and the halt is only seen
+/// at the following interrupt, at worst the next timer tick.
+pub struct WaitQueue {
+    generation: AtomicUsize,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until `f` returns a value, calling it again each time the waiter
+    /// is woken up.
+    pub fn wait_until<R>(&self, mut f: impl FnMut() -> Option<R>) -> R {
+        loop {
+            if let Some(value) = f() {
+                return value;
+            }
+            idle::idle();
+        }
+    }
+
+    /// Wait until `f` returns a value, like `wait_until()`, but for at most
+    /// `timeout`; return `None` if it elapsed first.
+    pub fn wait_timeout<R>(
+        &self,
+        timeout: Duration,
+        mut f: impl FnMut() -> Option<R>,
+    ) -> Option<R> {
+        let expired = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let expired = expired.clone();
+            Timer::after(timeout, move || {
+                expired.store(true, Ordering::SeqCst);
+                idle::wake_all();
+            })
+        };
+
+        let value = self.wait_until(|| match f() {
+            Some(value) => Some(Some(value)),
+            None if expired.load(Ordering::SeqCst) => Some(None),
+            None => None,
+        });
+
+        timer.cancel();
+        value
+    }
+
+    /// Wake up all the waiters of the queue.
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        idle::wake_all();
+    }
+
+    /// The number of times the queue was notified, for waiters that need to
+    /// tell whether they missed a notification.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
     }
 }
@@ -8,11 +8,50 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::arch::sync::{push_critical_region, pop_critical_region};
 
+/// The number of CPUs the per-CPU state is sized for; without the `smp`
+/// feature, only the bootstrap CPU is.
+#[cfg(feature = "smp")]
 pub const MAX_CPUS: usize = 32;
-pub static NR_CPUS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(feature = "smp"))]
+pub const MAX_CPUS: usize = 1;
+
+/// The number of CPUs up and running. The application processors are not
+/// brought up yet, so this is only ever the bootstrap CPU.
+pub static NR_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// A set of CPUs, by index.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CpuMask(pub u64);
+
+impl CpuMask {
+    /// All the CPUs, including those not online yet.
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub const fn of(cpu: usize) -> Self {
+        Self(1 << cpu)
+    }
+
+    /// The CPUs that are up and running.
+    pub fn online() -> Self {
+        let nr_cpus = NR_CPUS.load(Ordering::Relaxed).clamp(1, MAX_CPUS);
+        Self(u64::MAX >> (u64::BITS as usize - nr_cpus))
+    }
+
+    pub fn contains(self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & Self::of(cpu).0 != 0
+    }
+
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |&cpu| self.contains(cpu))
+    }
+}
 
 pub struct CpuIndex(usize);
 
@@ -34,10 +73,16 @@ impl Drop for CpuIndex {
     }
 }
 
+/// The index of the CPU running this code, for the per-CPU state of the
+/// critical regions themselves: unlike `current_cpu_index()`, it doesn't enter
+/// one, so the value may be stale unless the caller already is in one. Only
+/// the bootstrap CPU is brought up for now, see `task::smp`.
+pub fn raw_cpu_index() -> usize {
+    0
+}
+
 pub fn current_cpu_index() -> CpuIndex {
     push_critical_region();
 
-    let curr_cpu = 0; // TODO
-
-    CpuIndex(curr_cpu)
+    CpuIndex(raw_cpu_index())
 }
@@ -0,0 +1,254 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The ELF loader, for the statically linked executables of user space. The
+//! loadable segments become regions of the address space, backed by the image
+//! itself: their pages are read from it on demand, and the part of a segment
+//! past its file content, e.g. `.bss`, reads as zeros.
+
+use thiserror_no_std::Error;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::arch::task::ELF_MACHINE;
+use crate::mem::VAddr;
+use crate::misc::align_up;
+use crate::task::vm::{VirtualMemory, VmBacking, VmError, VmPermissions};
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+
+const HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[derive(Error, Debug)]
+pub enum ElfError {
+    #[error("not an ELF image")]
+    NotElf,
+
+    #[error("truncated ELF image")]
+    Truncated,
+
+    #[error("not a 64-bit little-endian static executable")]
+    Unsupported,
+
+    #[error("built for another machine ({0})")]
+    WrongMachine(u16),
+
+    #[error("invalid loadable segment at {0:?}")]
+    InvalidSegment(VAddr),
+
+    #[error("couldn't map a segment: {0}")]
+    Map(#[from] VmError),
+}
+
+/// A loadable segment, as described by its program header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Segment {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_bsize: usize,
+    mem_bsize: usize,
+}
+
+/// Map the loadable segments of the executable `image` into `vm`; return its
+/// entry point.
+pub fn load(
+    image: &'static [u8],
+    vm: &mut VirtualMemory,
+) -> Result<VAddr, ElfError> {
+    let (entry, segments) = parse(image)?;
+
+    for segment in segments {
+        let segment = segment?;
+        let invalid = || ElfError::InvalidSegment(VAddr(segment.vaddr));
+        let file_end = segment.offset.checked_add(segment.file_bsize)
+            .filter(|&end| end <= image.len())
+            .ok_or(ElfError::Truncated)?;
+        let mem_end = segment.vaddr.checked_add(segment.mem_bsize)
+            .ok_or_else(invalid)?;
+        if segment.file_bsize > segment.mem_bsize
+            || segment.vaddr % PAGE_SIZE != segment.offset % PAGE_SIZE {
+            return Err(invalid());
+        }
+
+        let start = segment.vaddr - segment.vaddr % PAGE_SIZE;
+        let backing = match segment.file_bsize {
+            0 => VmBacking::Anonymous,
+            _ => VmBacking::File {
+                data: &image[..file_end],
+                offset: segment.offset - segment.offset % PAGE_SIZE,
+            },
+        };
+        let permissions = VmPermissions {
+            readable: segment.flags & PF_R != 0,
+            writable: segment.flags & PF_W != 0,
+            executable: segment.flags & PF_X != 0,
+        };
+
+        vm.insert(VAddr(start)..VAddr(align_up(mem_end, PAGE_SIZE)),
+                  permissions, backing)?;
+    }
+
+    Ok(entry)
+}
+
+/// Parse the header of `image`; return its entry point and its loadable
+/// segments.
+fn parse(
+    image: &[u8],
+) -> Result<(VAddr, impl Iterator<Item = Result<Segment, ElfError>> + '_),
+            ElfError> {
+    if !image.starts_with(MAGIC) {
+        return Err(ElfError::NotElf);
+    }
+    if image.len() < HEADER_LEN {
+        return Err(ElfError::Truncated);
+    }
+    if image[4] != CLASS_64 || image[5] != DATA_LSB
+        || read_u16(image, 16)? != TYPE_EXEC {
+        return Err(ElfError::Unsupported);
+    }
+    let machine = read_u16(image, 18)?;
+    if machine != ELF_MACHINE {
+        return Err(ElfError::WrongMachine(machine));
+    }
+
+    let entry = VAddr(read_u64(image, 24)? as usize);
+    let phoff = read_u64(image, 32)? as usize;
+    let phentsize = read_u16(image, 54)? as usize;
+    let phnum = read_u16(image, 56)? as usize;
+    if phentsize < PROGRAM_HEADER_LEN {
+        return Err(ElfError::Unsupported);
+    }
+
+    let segments = (0..phnum)
+        .map(move |i| -> Result<(u32, usize), ElfError> {
+            let off = phoff.checked_add(i * phentsize)
+                .ok_or(ElfError::Truncated)?;
+            Ok((read_u32(image, off)?, off))
+        })
+        .filter(|header| !matches!(header, Ok((kind, _)) if *kind != PT_LOAD))
+        .map(|header| {
+            let (_, off) = header?;
+            Ok(Segment {
+                flags: read_u32(image, off + 4)?,
+                offset: read_u64(image, off + 8)? as usize,
+                vaddr: read_u64(image, off + 16)? as usize,
+                file_bsize: read_u64(image, off + 32)? as usize,
+                mem_bsize: read_u64(image, off + 40)? as usize,
+            })
+        });
+
+    Ok((entry, segments))
+}
+
+fn read_bytes<const N: usize>(
+    image: &[u8],
+    offset: usize,
+) -> Result<[u8; N], ElfError> {
+    image.get(offset..)
+        .and_then(|data| data.get(..N))
+        .map(|data| data.try_into().unwrap())
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read_bytes(image, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read_bytes(image, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+    read_bytes(image, offset).map(u64::from_le_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    /// An executable with a text segment and a data segment followed by its
+    /// bss, at 0x400000.
+    fn executable() -> &'static [u8] {
+        let mut image = std::vec![0u8; 3 * PAGE_SIZE];
+        image[..4].copy_from_slice(MAGIC);
+        image[4] = CLASS_64;
+        image[5] = DATA_LSB;
+        image[16..18].copy_from_slice(&TYPE_EXEC.to_le_bytes());
+        image[18..20].copy_from_slice(&ELF_MACHINE.to_le_bytes());
+        image[24..32].copy_from_slice(&0x401000u64.to_le_bytes());
+        image[32..40].copy_from_slice(&(HEADER_LEN as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(PROGRAM_HEADER_LEN as u16)
+            .to_le_bytes());
+        image[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+        let segments = [
+            (PF_R | PF_X, PAGE_SIZE, 0x401000, 100, 100),
+            (PF_R | PF_W, 2 * PAGE_SIZE, 0x402000, 200, 3 * PAGE_SIZE),
+        ];
+        for (i, (flags, offset, vaddr, filesz, memsz)) in
+            segments.into_iter().enumerate() {
+            let ph = &mut image[(HEADER_LEN + i * PROGRAM_HEADER_LEN)..];
+            ph[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            ph[8..16].copy_from_slice(&(offset as u64).to_le_bytes());
+            ph[16..24].copy_from_slice(&(vaddr as u64).to_le_bytes());
+            ph[32..40].copy_from_slice(&(filesz as u64).to_le_bytes());
+            ph[40..48].copy_from_slice(&(memsz as u64).to_le_bytes());
+        }
+
+        image.leak()
+    }
+
+    #[test]
+    fn it_maps_the_loadable_segments() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(0x1000_0000));
+        let entry = load(executable(), &mut vm).unwrap();
+
+        assert_eq!(entry, VAddr(0x401000));
+        let regions: Vec<_> = vm.regions()
+            .map(|r| (r.range().start.0, r.range().end.0,
+                      r.permissions.writable, r.permissions.executable))
+            .collect();
+        assert_eq!(regions, [(0x401000, 0x402000, false, true),
+                             (0x402000, 0x405000, true, false)]);
+        let VmBacking::File { data, offset } = vm.find(VAddr(0x402000))
+            .unwrap().backing else {
+            panic!("the data segment isn't backed by the image");
+        };
+        assert_eq!((data.len(), offset), (2 * PAGE_SIZE + 200, 2 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn it_rejects_invalid_images() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(0x1000_0000));
+        let image = executable();
+
+        assert!(matches!(load(b"#!/bin/sh\n", &mut vm),
+                         Err(ElfError::NotElf)));
+        assert!(matches!(load(&image[..32], &mut vm),
+                         Err(ElfError::Truncated)));
+
+        let mut other = image.to_vec();
+        other[18..20].copy_from_slice(&183u16.to_le_bytes());
+        assert!(matches!(load(other.leak(), &mut vm),
+                         Err(ElfError::WrongMachine(183))));
+    }
+}
@@ -0,0 +1,135 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The file descriptor tables of processes. A file descriptor is an index in
+//! the table of its process, referring to an open file; descriptors are
+//! allocated lowest first. Forked children get a copy of the table, whose
+//! descriptors share the open files, and their offsets, with the parent's.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::file::{self, OpenFile, O_RDWR};
+use crate::task::syscall::Errno;
+
+/// The number of file descriptors a process may have open.
+pub const MAX_FDS: usize = 256;
+
+pub const STDIN_FILENO: usize = 0;
+pub const STDOUT_FILENO: usize = 1;
+pub const STDERR_FILENO: usize = 2;
+
+/// The path of the device the standard file descriptors are opened on.
+const CONSOLE_PATH: &str = "/dev/console";
+
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FileTable {
+    pub const fn new() -> Self {
+        Self {
+            files: Vec::new(),
+        }
+    }
+
+    /// A table with the standard input, output and error sharing one open
+    /// file of the console device; empty if there is no console in the devfs.
+    pub fn with_console() -> Self {
+        let mut table = Self::new();
+        if let Ok(console) = file::open(CONSOLE_PATH, O_RDWR) {
+            table.files = vec![Some(console); STDERR_FILENO + 1];
+        }
+        table
+    }
+
+    /// The open file of the descriptor `fd`.
+    pub fn get(&self, fd: usize) -> Result<Arc<OpenFile>, Errno> {
+        self.files.get(fd)
+            .and_then(Option::clone)
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Allocate the lowest free descriptor for `file`, and return it.
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> Result<usize, Errno> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(None);
+                self.files.len() - 1
+            },
+            None => return Err(Errno::EMFILE),
+        };
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    /// Release the descriptor `fd`; the open file is closed once no other
+    /// descriptor refers to it.
+    pub fn close(&mut self, fd: usize) -> Result<(), Errno> {
+        self.files.get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(Errno::EBADF)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(())
+    }
+
+    /// Make `new_fd` refer to the open file of `old_fd`, closing the file it
+    /// referred to if any; return `new_fd`.
+    pub fn dup2(
+        &mut self,
+        old_fd: usize,
+        new_fd: usize,
+    ) -> Result<usize, Errno> {
+        let file = self.get(old_fd)?;
+        if new_fd >= MAX_FDS {
+            return Err(Errno::EBADF);
+        }
+
+        if self.files.len() <= new_fd {
+            self.files.resize(new_fd + 1, None);
+        }
+        self.files[new_fd] = Some(file);
+        Ok(new_fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs;
+    use crate::fs::file::{O_RDONLY, SEEK_CUR};
+    use super::*;
+
+    #[test]
+    fn it_shares_open_files_between_descriptors() {
+        let mut table = FileTable::new();
+        let file = file::open("/etc/kshellrc", O_RDONLY).unwrap();
+        let fd = table.insert(file).unwrap();
+        assert_eq!(fd, 0);
+        assert_eq!(table.dup2(fd, 5), Ok(5));
+        assert_eq!(table.insert(table.get(fd).unwrap()), Ok(1));
+
+        let forked = table.clone();
+        let mut buf = [0; 4];
+        assert_eq!(table.get(5).unwrap().read(&mut buf).unwrap(), 4);
+        assert_eq!(forked.get(fd).unwrap().seek(0, SEEK_CUR).unwrap(), 4);
+        assert_eq!(&buf, &fs::read("/etc/kshellrc").unwrap()[..4]);
+
+        assert_eq!(table.close(5), Ok(()));
+        assert!(matches!(table.get(5), Err(Errno::EBADF)));
+        assert_eq!(table.close(5), Err(Errno::EBADF));
+        assert_eq!(table.dup2(fd, MAX_FDS), Err(Errno::EBADF));
+        assert!(forked.get(5).is_ok());
+    }
+}
@@ -0,0 +1,219 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Futexes, the wait primitive of user-space locks. A task waits on a 32-bit
+//! word of its address space, provided it still holds the value the task last
+//! saw, until another task wakes the waiters of that word; the lock itself
+//! lives in user space, the kernel is only involved under contention.
+//!
+//! The waiters are kept in a table keyed by the address space and the address
+//! of the word, hence futexes are private to an address space. There is no
+//! scheduler yet: a waiting task idles its CPU.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use hashbrown::HashMap;
+
+use crate::mem::uaccess::copy_from_user;
+use crate::mem::VAddr;
+use crate::sync::{Spinlock, WaitQueue};
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+
+static FUTEXES: Spinlock<Option<FutexTable>> = Spinlock::new(None);
+
+/// The waiters of all the futexes, told apart by their own woken flag.
+static WAITERS: WaitQueue = WaitQueue::new();
+
+/// The identity of a futex: the address of its address space, and the user
+/// address of its word.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct FutexKey {
+    vm: usize,
+    addr: usize,
+}
+
+impl FutexKey {
+    fn new(vm: &VirtualMemory, addr: VAddr) -> Result<Self, Errno> {
+        if addr.0 % size_of::<u32>() != 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        Ok(Self {
+            vm: vm as *const VirtualMemory as usize,
+            addr: addr.0,
+        })
+    }
+}
+
+/// A task waiting on a futex; set once woken.
+type Waiter = Arc<AtomicBool>;
+
+/// The waiters of each futex with any, in the order they started waiting.
+#[derive(Default)]
+struct FutexTable {
+    queues: HashMap<FutexKey, VecDeque<Waiter>>,
+}
+
+impl FutexTable {
+    fn enqueue(&mut self, key: FutexKey) -> Waiter {
+        let waiter = Arc::new(AtomicBool::new(false));
+        self.queues.entry(key).or_default().push_back(waiter.clone());
+        waiter
+    }
+
+    /// Remove `waiter` from the queue of `key`; return false if it was not
+    /// there anymore, i.e. it was woken.
+    fn dequeue(&mut self, key: FutexKey, waiter: &Waiter) -> bool {
+        let Some(queue) = self.queues.get_mut(&key) else {
+            return false;
+        };
+        let Some(pos) = queue.iter().position(|w| Arc::ptr_eq(w, waiter))
+        else {
+            return false;
+        };
+
+        queue.remove(pos);
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        }
+        true
+    }
+
+    /// Wake up to `count` waiters of `key`, the oldest first; return how many
+    /// were.
+    fn wake(&mut self, key: FutexKey, count: usize) -> usize {
+        let Some(queue) = self.queues.get_mut(&key) else {
+            return 0;
+        };
+
+        let count = count.min(queue.len());
+        for waiter in queue.drain(..count) {
+            waiter.store(true, Ordering::SeqCst);
+        }
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        }
+        count
+    }
+}
+
+fn with_table<R>(f: impl FnOnce(&mut FutexTable) -> R) -> R {
+    f(FUTEXES.lock().get_or_insert_with(FutexTable::default))
+}
+
+/// Wait on the futex at `addr` of the address space `vm` if it holds `value`,
+/// until woken by `futex_wake()` or, if any, until `timeout` elapsed; fail
+/// with `EAGAIN` if it doesn't hold `value`, or `ETIMEDOUT` on timeout.
+pub fn futex_wait(
+    vm: &VirtualMemory,
+    addr: VAddr,
+    value: u32,
+    timeout: Option<Duration>,
+) -> Result<(), Errno> {
+    futex_prepare_wait(vm, addr, value)?.wait(timeout)
+}
+
+/// Start waiting on the futex at `addr` of the address space `vm` if it holds
+/// `value`, like `futex_wait()`; the wait itself is left to the returned
+/// `FutexWait`, so that the caller can release the address space first.
+pub fn futex_prepare_wait(
+    vm: &VirtualMemory,
+    addr: VAddr,
+    value: u32,
+) -> Result<FutexWait, Errno> {
+    let key = FutexKey::new(vm, addr)?;
+
+    // The value is checked with the table locked, so that a wake-up between
+    // the check and the enqueuing can't be missed.
+    let waiter = with_table(|table| {
+        let mut word = [0; size_of::<u32>()];
+        copy_from_user(vm, &mut word, addr)?;
+        if u32::from_ne_bytes(word) != value {
+            return Err(Errno::EAGAIN);
+        }
+
+        Ok(table.enqueue(key))
+    })?;
+
+    Ok(FutexWait { key, waiter })
+}
+
+/// A task enqueued on a futex, not waiting yet.
+#[must_use]
+pub struct FutexWait {
+    key: FutexKey,
+    waiter: Waiter,
+}
+
+impl FutexWait {
+    /// Wait until woken by `futex_wake()` or, if any, until `timeout` elapsed;
+    /// fail with `ETIMEDOUT` on timeout.
+    pub fn wait(self, timeout: Option<Duration>) -> Result<(), Errno> {
+        let woken = || self.waiter.load(Ordering::SeqCst).then_some(());
+        let Some(timeout) = timeout else {
+            WAITERS.wait_until(woken);
+            return Ok(());
+        };
+
+        if WAITERS.wait_timeout(timeout, woken).is_some()
+            || !with_table(|table| table.dequeue(self.key, &self.waiter)) {
+            Ok(())
+        } else {
+            Err(Errno::ETIMEDOUT)
+        }
+    }
+}
+
+/// Wake up to `count` tasks waiting on the futex at `addr` of the address
+/// space `vm`; return how many were.
+pub fn futex_wake(
+    vm: &VirtualMemory,
+    addr: VAddr,
+    count: usize,
+) -> Result<usize, Errno> {
+    let key = FutexKey::new(vm, addr)?;
+    let woken = with_table(|table| table.wake(key, count));
+
+    if woken > 0 {
+        WAITERS.notify_all();
+    }
+    Ok(woken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_wakes_the_oldest_waiters() {
+        let mut table = FutexTable::default();
+        let key = FutexKey { vm: 0x1000, addr: 0x2000 };
+        let other = FutexKey { vm: 0x1000, addr: 0x2004 };
+
+        let first = table.enqueue(key);
+        let second = table.enqueue(key);
+        let third = table.enqueue(other);
+
+        assert_eq!(table.wake(key, 1), 1);
+        assert!(first.load(Ordering::SeqCst));
+        assert!(!second.load(Ordering::SeqCst));
+
+        assert!(!table.dequeue(key, &first));
+        assert!(table.dequeue(key, &second));
+        assert_eq!(table.wake(key, 10), 0);
+        assert_eq!(table.wake(other, 10), 1);
+        assert!(third.load(Ordering::SeqCst));
+        assert!(table.queues.is_empty());
+    }
+}
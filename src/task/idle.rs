@@ -0,0 +1,115 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! CPU idle management. When a CPU has nothing to do, it enters a low-power
+//! state through `idle()`, with `MWAIT` where supported and `HLT` otherwise,
+//! and the time spent there is accounted per CPU.
+//!
+//! The timer still ticks at a fixed rate while idle: there is no one-shot
+//! timer nor run queue yet to tell how long the CPU could sleep. Once there
+//! are, `idle()` is where the next timer deadline is to be pushed back.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::arch::cpu::idle_wait;
+use crate::arch::time::timestamp;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS, NR_CPUS};
+
+static CPUS: [CpuIdle; MAX_CPUS] = [CpuIdle::NEW; MAX_CPUS];
+
+struct CpuIdle {
+    /// Bumped to wake the CPU up from `MWAIT`.
+    wakeup: AtomicU64,
+    /// The total time spent idle, in timestamp cycles.
+    idle_cycles: AtomicU64,
+    nr_entries: AtomicU64,
+}
+
+impl CpuIdle {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        wakeup: AtomicU64::new(0),
+        idle_cycles: AtomicU64::new(0),
+        nr_entries: AtomicU64::new(0),
+    };
+}
+
+/// The idle time of a CPU since boot.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IdleStats {
+    /// The time spent idle, in timestamp cycles.
+    pub idle_cycles: u64,
+    /// The number of times the CPU went idle.
+    pub nr_entries: u64,
+}
+
+impl IdleStats {
+    /// The share of the `elapsed` cycles since `prev` was taken that the CPU
+    /// spent idle, in percent.
+    pub fn idle_percent_since(&self, prev: &IdleStats, elapsed: u64) -> u64 {
+        if elapsed == 0 {
+            return 0;
+        }
+
+        let idle = self.idle_cycles.saturating_sub(prev.idle_cycles);
+        (idle.min(elapsed) as u128 * 100 / elapsed as u128) as u64
+    }
+}
+
+/// Idle the current CPU until the next interrupt, or until `wake()` is called
+/// for it. Interrupts must be enabled.
+pub fn idle() {
+    // The idle loop of a CPU never migrates, the index stays valid.
+    let cpu = &CPUS[current_cpu_index().get()];
+    let seen = cpu.wakeup.load(Ordering::SeqCst);
+
+    let start = timestamp();
+    idle_wait(&cpu.wakeup, seen);
+    let end = timestamp();
+
+    cpu.idle_cycles.fetch_add(end.saturating_sub(start), Ordering::Relaxed);
+    cpu.nr_entries.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Wake up all idle CPUs that can be without an interrupt, so that they check
+/// again for work.
+pub fn wake_all() {
+    for cpu in &CPUS[..nr_cpus()] {
+        cpu.wakeup.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// The idle time of the CPU `cpu`.
+pub fn stats(cpu: usize) -> IdleStats {
+    IdleStats {
+        idle_cycles: CPUS[cpu].idle_cycles.load(Ordering::Relaxed),
+        nr_entries: CPUS[cpu].nr_entries.load(Ordering::Relaxed),
+    }
+}
+
+/// The number of CPUs to account, at least the boot one.
+pub fn nr_cpus() -> usize {
+    NR_CPUS.load(Ordering::Relaxed).clamp(1, MAX_CPUS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_the_idle_share() {
+        let prev = IdleStats { idle_cycles: 1000, nr_entries: 1 };
+        let now = IdleStats { idle_cycles: 1750, nr_entries: 4 };
+
+        assert_eq!(now.idle_percent_since(&prev, 1000), 75);
+        assert_eq!(now.idle_percent_since(&prev, 500), 100);
+        assert_eq!(now.idle_percent_since(&prev, 0), 0);
+    }
+}
@@ -0,0 +1,121 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The boundary between the kernel and user space. Once initialized, the kernel
+//! hands over to the userland `init` program, read from the root file system
+//! and run as pid 1: init sets up user space, supervises services, and adopts
+//! the processes whose parent exited to reap them once they complete.
+//!
+//! When no userland init can be started, the built-in init takes its place: it
+//! runs the kernel shell startup script then the shell on the console, and the
+//! kernel itself reaps the orphaned processes.
+
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicBool, Ordering};
+use arrayvec::ArrayString;
+use thiserror_no_std::Error;
+
+use crate::fs::{self, FsError};
+use crate::notice;
+use crate::task::{Task, TaskState};
+use crate::ui;
+
+pub const INIT_PID: u32 = 1;
+pub const DEFAULT_INIT_PATH: &str = "/sbin/init";
+
+const MAX_INIT_PATH_LEN: usize = 64;
+
+static mut INIT_PATH: ArrayString<MAX_INIT_PATH_LEN> = ArrayString::new_const();
+
+/// Whether pid 1 is a userland program rather than the built-in init.
+static USERLAND_INIT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Error, Debug)]
+pub enum InitError {
+    #[error("{0}")]
+    Read(#[from] FsError),
+
+    #[error("user-space programs can't be executed yet")]
+    ExecUnsupported,
+}
+
+/// What to do with a process whose parent exited.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Orphan {
+    /// The process was adopted by the userland init.
+    Adopted,
+    /// The process has no parent anymore; the kernel will reap it when it
+    /// completes.
+    Detached,
+    /// The process is a zombie nobody will wait for: it must be reaped now.
+    Reap,
+}
+
+/// Select the userland init program with the `init=` boot option.
+///
+/// # Safety #
+///
+/// Must be called during the early boot process, with only one CPU running.
+pub unsafe fn set_init_path(path: &str) -> bool {
+    INIT_PATH.clear();
+    INIT_PATH.try_push_str(path).is_ok()
+}
+
+pub fn init_path() -> &'static str {
+    match unsafe { INIT_PATH.as_str() } {
+        "" => DEFAULT_INIT_PATH,
+        path => path,
+    }
+}
+
+pub fn has_userland_init() -> bool {
+    USERLAND_INIT.load(Ordering::SeqCst)
+}
+
+/// Hand over to user space, or run the built-in init if that isn't possible.
+pub fn start() -> ! {
+    let path = init_path();
+
+    match spawn_userland_init(path) {
+        Ok(never) => match never {},
+        Err(e) => {
+            notice!("{path}: {e}; starting the built-in init");
+            builtin_init()
+        },
+    }
+}
+
+/// Load the init program and run it as pid 1. The root file system is the
+/// initfs, which is always mounted.
+fn spawn_userland_init(path: &str) -> Result<Infallible, InitError> {
+    let _image = fs::read(path)?;
+
+    // TODO: create the pid 1 process with `process::spawn()` once tasks can
+    //       enter user space, set `USERLAND_INIT` and enter the scheduler.
+    Err(InitError::ExecUnsupported)
+}
+
+fn builtin_init() -> ! {
+    ui::script::run_startup();
+    ui::shell::run();
+}
+
+/// Handle `task`, whose parent process exited.
+pub fn handle_orphan(task: &mut Task) -> Orphan {
+    if has_userland_init() {
+        task.parent_pid = INIT_PID;
+        Orphan::Adopted
+    } else if let TaskState::Zombie = task.state {
+        Orphan::Reap
+    } else {
+        task.parent_pid = 0;
+        Orphan::Detached
+    }
+}
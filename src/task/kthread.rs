@@ -0,0 +1,243 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Kernel threads: tasks running a kernel function rather than a program,
+//! with their own kernel stack and no address space of their own. They are
+//! listed along with the processes, under PID 0, and ignore all signals.
+//!
+//! A thread is controlled through the `KThread` handle `spawn()` returns:
+//! `park()` has it pause at its next `parkme()`, until `unpark()`, and
+//! `stop()` asks it to return, then waits for it. The thread polls
+//! `should_stop()` and `should_park()` from its main loop to cooperate.
+//!
+//! There is no scheduler switching to tasks yet: a spawned thread is
+//! enqueued but doesn't run until there is one.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::task::TaskMachineContext;
+use crate::mem::VAddr;
+use crate::sync::{Spinlock, WaitQueue};
+use crate::task::idle;
+use crate::task::process;
+use crate::task::syscall::Errno;
+
+/// The size of a kernel thread's stack.
+pub const KTHREAD_STACK_BSIZE: usize = 16 << 10;
+
+/// The kernel threads alive.
+static THREADS: Spinlock<Vec<Arc<ThreadState>>> = Spinlock::new(Vec::new());
+
+/// The kernel threads that completed, whose stack is freed once no CPU runs
+/// on it anymore.
+static DEAD: Spinlock<Vec<Arc<ThreadState>>> = Spinlock::new(Vec::new());
+
+/// Notified each time a thread is asked to stop, park or unpark, parks, or
+/// completes.
+static EVENTS: WaitQueue = WaitQueue::new();
+
+type ThreadFn = Box<dyn FnOnce() + Send>;
+
+struct ThreadState {
+    tid: u32,
+    should_stop: AtomicBool,
+    should_park: AtomicBool,
+    parked: AtomicBool,
+    exited: AtomicBool,
+    stack: Box<[u8]>,
+}
+
+/// The handle of a kernel thread. Dropping it detaches the thread, which keeps
+/// running until it returns.
+pub struct KThread(Arc<ThreadState>);
+
+impl KThread {
+    pub fn tid(&self) -> u32 {
+        self.0.tid
+    }
+
+    /// Ask the thread to park, and wait until it does, or completes.
+    pub fn park(&self) {
+        self.0.should_park.store(true, Ordering::SeqCst);
+        EVENTS.notify_all();
+        EVENTS.wait_until(|| {
+            let state = &self.0;
+            (state.parked.load(Ordering::SeqCst)
+                || state.exited.load(Ordering::SeqCst)).then_some(())
+        });
+    }
+
+    /// Let the thread resume if it is parked, or cancel a request to park.
+    pub fn unpark(&self) {
+        self.0.should_park.store(false, Ordering::SeqCst);
+        EVENTS.notify_all();
+    }
+
+    /// Ask the thread to stop, unparking it if need be, and wait until it
+    /// returns.
+    pub fn stop(self) {
+        self.0.should_stop.store(true, Ordering::SeqCst);
+        self.unpark();
+        EVENTS.wait_until(|| {
+            self.0.exited.load(Ordering::SeqCst).then_some(())
+        });
+    }
+}
+
+/// Create a kernel thread named `name` running `f`, and enqueue it for the
+/// scheduler.
+pub fn spawn(
+    name: &str,
+    f: impl FnOnce() + Send + 'static,
+) -> Result<KThread, Errno> {
+    reap();
+
+    let stack = vec![0; KTHREAD_STACK_BSIZE].into_boxed_slice();
+    let stack_start = VAddr::from(stack.as_ptr());
+    let stack_range = stack_start..(stack_start + stack.len());
+
+    // The entry point takes a thin pointer to the boxed closure.
+    let f: ThreadFn = Box::new(f);
+    let f = Box::into_raw(Box::new(f));
+    let machine_ctx = TaskMachineContext::kernel(entry, f as usize,
+                                                 stack_range.end);
+
+    // The registry lock is held across the insertion so that the thread
+    // can't look itself up before it is registered.
+    let mut threads = THREADS.lock();
+    let tid = match process::insert_kthread(name, machine_ctx, stack_range) {
+        Ok(tid) => tid,
+        Err(e) => {
+            drop(unsafe { Box::from_raw(f) });
+            return Err(e);
+        },
+    };
+    let state = Arc::new(ThreadState {
+        tid,
+        should_stop: AtomicBool::new(false),
+        should_park: AtomicBool::new(false),
+        parked: AtomicBool::new(false),
+        exited: AtomicBool::new(false),
+        stack,
+    });
+    threads.push(state.clone());
+
+    Ok(KThread(state))
+}
+
+/// Whether the calling kernel thread was asked to stop: it should return as
+/// soon as possible.
+pub fn should_stop() -> bool {
+    current().is_some_and(|state| state.should_stop.load(Ordering::SeqCst))
+}
+
+/// Whether the calling kernel thread was asked to park: it should call
+/// `parkme()` once in a state it can be paused in.
+pub fn should_park() -> bool {
+    current().is_some_and(|state| state.should_park.load(Ordering::SeqCst))
+}
+
+/// Park the calling kernel thread if it was asked to, until it is unparked
+/// or asked to stop.
+pub fn parkme() {
+    let Some(state) = current() else {
+        return;
+    };
+
+    if !state.should_park.load(Ordering::SeqCst) {
+        return;
+    }
+    state.parked.store(true, Ordering::SeqCst);
+    EVENTS.notify_all();
+
+    EVENTS.wait_until(|| {
+        (!state.should_park.load(Ordering::SeqCst)
+            || state.should_stop.load(Ordering::SeqCst)).then_some(())
+    });
+    state.parked.store(false, Ordering::SeqCst);
+}
+
+/// The kernel thread running on this CPU, if any.
+fn current() -> Option<Arc<ThreadState>> {
+    let tid = process::current()?;
+    THREADS.lock().iter().find(|state| state.tid == tid).cloned()
+}
+
+extern "C" fn entry(f: usize) -> ! {
+    let f = unsafe { Box::from_raw(f as *mut ThreadFn) };
+    f();
+
+    if let Some(state) = current() {
+        finish(state);
+    }
+
+    // Wait for the scheduler to switch away for good: the task is gone from
+    // the run queues.
+    loop {
+        idle::idle();
+    }
+}
+
+/// Remove the completed thread `state`, waking up whoever stops it.
+fn finish(state: Arc<ThreadState>) {
+    THREADS.lock().retain(|thread| !Arc::ptr_eq(thread, &state));
+    process::remove_kthread(state.tid);
+    state.exited.store(true, Ordering::SeqCst);
+    DEAD.lock().push(state);
+    EVENTS.notify_all();
+}
+
+/// Free the stacks of the completed threads no CPU runs on anymore.
+fn reap() {
+    DEAD.lock().retain(|state| process::running_cpu(state.tid).is_some());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(tid: u32) -> Option<process::ProcessStats> {
+        process::stats().into_iter().find(|stats| stats.tid == tid)
+    }
+
+    #[test]
+    fn it_lists_threads_by_name_until_they_complete() {
+        let thread = spawn("kflushd", || {}).unwrap();
+        let stats = stats_of(thread.tid()).unwrap();
+        assert_eq!((stats.pid, stats.parent_pid), (0, 0));
+        assert_eq!(stats.name, "kflushd");
+
+        let state = thread.0.clone();
+        finish(state);
+        assert!(stats_of(thread.tid()).is_none());
+        thread.stop();
+    }
+
+    #[test]
+    fn it_hands_over_park_and_stop_requests() {
+        let thread = spawn("kparkd", || {}).unwrap();
+        let state = thread.0.clone();
+
+        // The thread acknowledged a former request.
+        state.parked.store(true, Ordering::SeqCst);
+        thread.park();
+        assert!(state.should_park.load(Ordering::SeqCst));
+        thread.unpark();
+        assert!(!state.should_park.load(Ordering::SeqCst));
+
+        finish(state.clone());
+        thread.stop();
+        assert!(state.should_stop.load(Ordering::SeqCst));
+    }
+}
@@ -11,10 +11,28 @@
 pub mod vm;
 pub mod cpu;
 pub mod cpu_local;
+pub mod elf;
+pub mod fd;
+pub mod futex;
+pub mod idle;
+pub mod init;
+pub mod kthread;
+pub mod process;
+pub mod sched;
+pub mod signal;
+#[cfg(feature = "smp")]
+pub mod smp;
+pub mod syscall;
+pub mod timer;
 
-use crate::arch::task::TaskMachineContext;
+use alloc::string::String;
+use core::ops::Range;
 
-//use alloc::string::String;
+use crate::arch::task::TaskMachineContext;
+use crate::mem::VAddr;
+use crate::task::cpu::CpuMask;
+use crate::task::sched::DEFAULT_PRIORITY;
+use crate::task::syscall::Errno;
 
 #[allow(unused)]
 pub struct Task {
@@ -31,28 +49,123 @@ pub struct Task {
     pid: u32,
 
     /// The parent PID of the process this task belongs to. Zero means there is
-    /// no parent; only kernel threads and `userd` (pid=1) are allowed to not
-    /// have a parent.
+    /// no parent; only kernel threads, `init` (pid=1), and orphans when there
+    /// is no userland init, are allowed to not have a parent.
     parent_pid: u32,
 
     /// A descriptive name for the task, this is usually the program's name.
-    //name: String,
+    name: String,
 
     /// The current state of the state, whether it is running, waiting to be
     /// scheduled, waiting for an external event, suspended, etc.
     state: TaskState,
 
+    /// The status the process exited with, reported to its parent once it is a
+    /// zombie, as encoded by `wait()`.
+    exit_status: i32,
+
+    /// The signals blocked, pending, and how they are handled.
+    signals: signal::SignalState,
+
+    /// The file descriptors of the process.
+    files: fd::FileTable,
+
     /// The saved execution machine context used for context switching; it
     /// contains an exhaustive description of all the states to be saved and
     /// restored when switching between tasks, typically CPU registers. The
     /// exact content of this struct is arch-specific, and only arch-specific
     /// code is allowed to handle its internals.
     machine_ctx: TaskMachineContext,
+
+    /// The task's own kernel stack, on which it runs in kernel mode; it holds
+    /// the task's call frames while it is switched out.
+    kernel_stack: Range<VAddr>,
     vm: vm::VirtualMemory,
+
+    /// The scheduling priority, a nice value from `sched::PRIORITY_HIGHEST` to
+    /// `sched::PRIORITY_LOWEST`.
     priority: i32,
+
+    /// The time the task spent running on a CPU, in timestamp cycles.
+    runtime: u64,
+
+    /// The CPUs the task may run on.
+    affinity: CpuMask,
+}
+
+impl Task {
+    /// A runnable task that may run on any CPU, at the default priority, with
+    /// the default signal dispositions and no open files. Its kernel stack is
+    /// left empty.
+    fn new(
+        tid: u32,
+        pid: u32,
+        parent_pid: u32,
+        name: String,
+        vm: vm::VirtualMemory,
+        machine_ctx: TaskMachineContext,
+    ) -> Self {
+        Self {
+            tid,
+            pid,
+            parent_pid,
+            name,
+            state: TaskState::Runnable,
+            exit_status: 0,
+            signals: signal::SignalState::new(),
+            files: fd::FileTable::new(),
+            machine_ctx,
+            kernel_stack: VAddr(0)..VAddr(0),
+            vm,
+            priority: DEFAULT_PRIORITY,
+            runtime: 0,
+            affinity: CpuMask::ALL,
+        }
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_kernel_thread(&self) -> bool {
+        self.pid == 0
+    }
+
+    /// The machine context saved when the task was switched out; `None` if it
+    /// is running, or dead and its stack possibly gone.
+    pub fn saved_context(&self) -> Option<&TaskMachineContext> {
+        match self.state {
+            TaskState::Running | TaskState::Zombie => None,
+            _ => Some(&self.machine_ctx),
+        }
+    }
+
+    pub fn kernel_stack(&self) -> Range<VAddr> {
+        self.kernel_stack.clone()
+    }
+
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    /// Restrict the task to the CPUs of `affinity`, at least one of which must
+    /// be online. The scheduler is to be told with `sched::migrate()`.
+    pub fn set_affinity(&mut self, affinity: CpuMask) -> Result<(), Errno> {
+        if !affinity.intersects(CpuMask::online()) {
+            return Err(Errno::EINVAL);
+        }
+
+        self.affinity = affinity;
+        Ok(())
+    }
 }
 
 #[allow(unused)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TaskState {
     /// This task is currently running on a CPU.
     Running,
@@ -80,3 +193,15 @@ pub enum TaskState {
     /// parent has not read the completion status.
     Zombie,
 }
+
+impl TaskState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Runnable => "runnable",
+            Self::Waiting => "waiting",
+            Self::Suspended => "suspended",
+            Self::Zombie => "zombie",
+        }
+    }
+}
@@ -0,0 +1,550 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The lifecycle of user processes: `fork()` duplicates a process, `execve()`
+//! replaces its program, `exit()` turns it into a zombie until its parent
+//! collects its status with `waitpid()`. The children of an exiting process
+//! are handed over to `init::handle_orphan()`, which reparents them to pid 1,
+//! or detaches them so that the kernel reaps them when there is no userland
+//! init. The open files are shared with forked children, and kept across
+//! `execve()`.
+//!
+//! Processes are single-threaded, their TID is their PID. This is bookkeeping
+//! only, hence `fork()`, `execve()` and `waitpid()` being behind the
+//! `userland` feature: nothing enters user mode yet, and there are no
+//! per-process page tables. Copy-on-write is only recorded in the regions of
+//! the forked address spaces, no fault enforces it, and `fork()` duplicates
+//! the machine context last saved for the caller, which the arch-specific
+//! system call entry point will have to keep up to date.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "userland")]
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::arch::task::TaskMachineContext;
+#[cfg(feature = "userland")]
+use crate::arch::task::SIGRETURN_TRAMPOLINE;
+#[cfg(feature = "userland")]
+use crate::fs;
+use crate::mem::VAddr;
+use crate::sync::{Spinlock, WaitQueue};
+use crate::task::cpu::{current_cpu_index, CpuMask, MAX_CPUS};
+#[cfg(feature = "userland")]
+use crate::task::elf;
+use crate::task::fd::FileTable;
+use crate::task::init::{handle_orphan, has_userland_init, Orphan, INIT_PID};
+use crate::task::sched::{self, PRIORITY_HIGHEST, PRIORITY_LOWEST};
+use crate::task::signal::SIGCHLD;
+use crate::task::syscall::Errno;
+use crate::task::vm::VirtualMemory;
+#[cfg(feature = "userland")]
+use crate::task::vm::{VmBacking, VmPermissions};
+use crate::task::{Task, TaskState};
+
+/// The largest PID; PIDs wrap around to 1 past it.
+pub const MAX_PID: u32 = 32768;
+
+/// `waitpid()` option: return right away if no child exited yet.
+pub const WNOHANG: usize = 1;
+
+/// The end of the user half of the address space, on 48-bit address spaces.
+const USER_SPACE_END: usize = 0x8000_0000_0000;
+
+/// The addresses user regions may be placed at; the first page is left
+/// unmapped to catch null pointers.
+#[cfg(feature = "userland")]
+const USER_SPACE: Range<VAddr> = VAddr(PAGE_SIZE)..VAddr(USER_SPACE_END);
+
+const USER_STACK_BSIZE: usize = 8 << 20;
+
+/// The address of the signal trampoline, below the stack and its guard page.
+pub const SIGNAL_TRAMPOLINE: VAddr =
+    VAddr(USER_SPACE_END - USER_STACK_BSIZE - 2 * PAGE_SIZE);
+
+static PROCESSES: Spinlock<ProcessTable> = Spinlock::new(ProcessTable::new());
+
+/// The figures of a task, for monitoring.
+#[derive(Debug, Clone)]
+pub struct ProcessStats {
+    pub tid: u32,
+    /// The PID of the task's process, 0 for kernel threads.
+    pub pid: u32,
+    pub parent_pid: u32,
+    pub name: String,
+    pub state: TaskState,
+    pub priority: i32,
+    /// The time spent running on a CPU, in timestamp cycles.
+    pub runtime: u64,
+}
+
+/// Notified each time a process exits, for the parents waiting for it.
+static CHILD_EXIT: WaitQueue = WaitQueue::new();
+
+/// The TID of the task running on each CPU, 0 if none; for processes, it is
+/// their PID.
+static CURRENT: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+struct ProcessTable {
+    /// The tasks of all processes and the kernel threads, boxed so that their
+    /// address space doesn't move: futexes are keyed by its address.
+    tasks: Vec<Box<Task>>,
+    next_pid: u32,
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            next_pid: INIT_PID,
+        }
+    }
+
+    /// The task `tid`; a process' single task has its PID as TID.
+    fn get_mut(&mut self, tid: u32) -> Option<&mut Task> {
+        self.tasks.iter_mut()
+            .find(|task| task.tid == tid)
+            .map(|task| &mut **task)
+    }
+
+    fn remove(&mut self, tid: u32) -> Option<Box<Task>> {
+        let pos = self.tasks.iter().position(|task| task.tid == tid)?;
+        Some(self.tasks.remove(pos))
+    }
+
+    fn alloc_pid(&mut self) -> Option<u32> {
+        for _ in 0..MAX_PID {
+            let pid = self.next_pid;
+            self.next_pid = pid % MAX_PID + 1;
+            if self.get_mut(pid).is_none() {
+                return Some(pid);
+            }
+        }
+
+        None
+    }
+
+    /// Allocate the TID of a kernel thread, keeping `INIT_PID` for init.
+    fn alloc_tid(&mut self) -> Option<u32> {
+        match self.alloc_pid()? {
+            INIT_PID => self.alloc_pid(),
+            tid => Some(tid),
+        }
+    }
+
+    /// Add a runnable process, child of `parent_pid`. It is up to the caller
+    /// to enqueue it for the scheduler.
+    fn insert(
+        &mut self,
+        parent_pid: u32,
+        name: String,
+        vm: VirtualMemory,
+        machine_ctx: TaskMachineContext,
+    ) -> Result<&mut Task, Errno> {
+        let pid = self.alloc_pid().ok_or(Errno::EAGAIN)?;
+
+        // TODO: allocate the kernel stack once there is a scheduler to switch
+        //       to the task.
+        self.tasks.push(Box::new(
+            Task::new(pid, pid, parent_pid, name, vm, machine_ctx),
+        ));
+        Ok(&mut **self.tasks.last_mut().unwrap())
+    }
+}
+
+/// Run `f` on the address space of the process `pid`.
+pub fn with_vm<R>(
+    pid: u32,
+    f: impl FnOnce(&mut VirtualMemory) -> Result<R, Errno>,
+) -> Result<R, Errno> {
+    with_task(pid, |task| f(&mut task.vm))
+}
+
+/// Run `f` on the file descriptor table of the process `pid`.
+pub fn with_files<R>(
+    pid: u32,
+    f: impl FnOnce(&mut FileTable) -> Result<R, Errno>,
+) -> Result<R, Errno> {
+    with_task(pid, |task| f(&mut task.files))
+}
+
+/// Run `f` on the task of the process `pid`, or on the kernel thread of that
+/// TID.
+pub(super) fn with_task<R>(
+    pid: u32,
+    f: impl FnOnce(&mut Task) -> Result<R, Errno>,
+) -> Result<R, Errno> {
+    let mut table = PROCESSES.lock();
+    f(table.get_mut(pid).ok_or(Errno::ESRCH)?)
+}
+
+/// The process running on this CPU, if any, or the TID of the kernel thread
+/// running.
+pub fn current() -> Option<u32> {
+    let pid = CURRENT[current_cpu_index().get()].load(Ordering::SeqCst);
+    (pid != 0).then_some(pid)
+}
+
+/// Record that the task `tid` runs on this CPU from now on, 0 for none; this
+/// is meant to be called when switching tasks.
+pub fn set_current(tid: u32) {
+    CURRENT[current_cpu_index().get()].store(tid, Ordering::SeqCst);
+}
+
+/// Create a process with no parent, running the program at `path`; return its
+/// PID.
+#[cfg(feature = "userland")]
+pub fn spawn(path: &str) -> Result<u32, Errno> {
+    let (vm, machine_ctx) = load_program(path)?;
+    create(program_name(path), vm, machine_ctx)
+}
+
+/// Create a process named `name` with no parent, with the address space `vm`,
+/// and starting from `machine_ctx`; return its PID. Its standard file
+/// descriptors are opened on the console.
+pub fn create(
+    name: &str,
+    vm: VirtualMemory,
+    machine_ctx: TaskMachineContext,
+) -> Result<u32, Errno> {
+    let files = FileTable::with_console();
+    let mut table = PROCESSES.lock();
+    let task = table.insert(0, name.to_string(), vm, machine_ctx)?;
+    task.files = files;
+    let pid = task.pid;
+    drop(table);
+
+    sched::enqueue(pid);
+    Ok(pid)
+}
+
+/// Duplicate the process `pid`; return the PID of the child, to which the
+/// system call returns 0.
+#[cfg(feature = "userland")]
+pub fn fork(pid: u32) -> Result<u32, Errno> {
+    let mut table = PROCESSES.lock();
+    let parent = table.get_mut(pid).ok_or(Errno::ESRCH)?;
+
+    let mut machine_ctx = parent.machine_ctx.clone();
+    machine_ctx.set_return_value(0);
+    let name = parent.name.clone();
+    let vm = parent.vm.fork();
+    let signals = parent.signals.fork();
+    let files = parent.files.clone();
+    let priority = parent.priority;
+    let affinity = parent.affinity;
+
+    let child = table.insert(pid, name, vm, machine_ctx)?;
+    child.signals = signals;
+    child.files = files;
+    child.priority = priority;
+    child.affinity = affinity;
+    let child = child.pid;
+    drop(table);
+
+    sched::enqueue(child);
+    Ok(child)
+}
+
+/// Replace the program of the process `pid` with the one at `path`.
+#[cfg(feature = "userland")]
+pub fn execve(pid: u32, path: &str) -> Result<(), Errno> {
+    let (vm, machine_ctx) = load_program(path)?;
+
+    let mut table = PROCESSES.lock();
+    let task = table.get_mut(pid).ok_or(Errno::ESRCH)?;
+    task.name = program_name(path).to_string();
+    task.vm = vm;
+    task.machine_ctx = machine_ctx;
+    task.signals.exec();
+    Ok(())
+}
+
+/// Add a runnable kernel thread named `name`, starting from `machine_ctx` on
+/// the kernel stack `stack`, and enqueue it; return its TID.
+pub(super) fn insert_kthread(
+    name: &str,
+    machine_ctx: TaskMachineContext,
+    stack: Range<VAddr>,
+) -> Result<u32, Errno> {
+    let mut table = PROCESSES.lock();
+    let tid = table.alloc_tid().ok_or(Errno::EAGAIN)?;
+
+    let vm = VirtualMemory::new(VAddr(0)..VAddr(0));
+    let mut task = Task::new(tid, 0, 0, name.to_string(), vm, machine_ctx);
+    task.kernel_stack = stack;
+    table.tasks.push(Box::new(task));
+    drop(table);
+
+    sched::enqueue(tid);
+    Ok(tid)
+}
+
+/// Remove the kernel thread `tid`, which completed.
+pub(super) fn remove_kthread(tid: u32) {
+    PROCESSES.lock().remove(tid);
+    sched::dequeue(tid);
+}
+
+/// Terminate the process `pid` with `status`: it releases its address space
+/// and remains as a zombie until its parent waits for it, or is reaped right
+/// away if it has none. The caller must not resume the task afterwards.
+pub fn exit(pid: u32, status: i32) {
+    terminate(pid, (status & 0xff) << 8);
+}
+
+/// Terminate the process `pid` because of `signal`, like `exit()`.
+pub fn exit_by_signal(pid: u32, signal: usize) {
+    terminate(pid, signal as i32);
+}
+
+/// Terminate the process `pid`, reporting `wstatus` to its parent.
+fn terminate(pid: u32, wstatus: i32) {
+    if pid == INIT_PID && has_userland_init() {
+        panic!("init terminated with status {wstatus:#x}");
+    }
+
+    let mut table = PROCESSES.lock();
+    let Some(task) = table.get_mut(pid) else {
+        return;
+    };
+    task.state = TaskState::Zombie;
+    task.exit_status = wstatus;
+    task.vm = VirtualMemory::new(VAddr(0)..VAddr(0));
+    task.files = FileTable::new();
+    let parent_pid = task.parent_pid;
+
+    if let Some(parent) = table.get_mut(parent_pid) {
+        parent.signals.raise(SIGCHLD);
+    }
+
+    table.tasks.retain_mut(|task| {
+        task.parent_pid != pid || handle_orphan(task) != Orphan::Reap
+    });
+    if parent_pid == 0 {
+        table.remove(pid);
+    }
+    drop(table);
+
+    sched::dequeue(pid);
+    CHILD_EXIT.notify_all();
+}
+
+/// Wait for a child of the process `pid` to exit and reap it; return its PID
+/// and its status, as encoded by `wait()`. `target` is either the PID of the
+/// child, or -1 for any child. With `WNOHANG` in `options`, `None` is returned
+/// if no child exited yet rather than waiting.
+#[cfg(feature = "userland")]
+pub fn waitpid(
+    pid: u32,
+    target: i32,
+    options: usize,
+) -> Result<Option<(u32, i32)>, Errno> {
+    if options & !WNOHANG != 0 || target == 0 || target < -1 {
+        return Err(Errno::EINVAL);
+    }
+    let is_target = |task: &Task| {
+        task.parent_pid == pid && (target == -1 || task.pid == target as u32)
+    };
+
+    CHILD_EXIT.wait_until(|| {
+        let mut table = PROCESSES.lock();
+        if !table.tasks.iter().any(|task| is_target(task)) {
+            return Some(Err(Errno::ECHILD));
+        }
+
+        let zombie = table.tasks.iter().position(|task| {
+            is_target(task) && matches!(task.state, TaskState::Zombie)
+        });
+        match zombie {
+            Some(pos) => {
+                let child = table.tasks.remove(pos);
+                Some(Ok(Some((child.pid, child.exit_status))))
+            },
+            None if options & WNOHANG != 0 => Some(Ok(None)),
+            None => None,
+        }
+    })
+}
+
+/// The scheduling priority of the process `pid`.
+pub fn priority(pid: u32) -> Result<i32, Errno> {
+    with_task(pid, |task| Ok(task.priority))
+}
+
+/// Set the scheduling priority of the process `pid`, taking effect from its
+/// next time slice.
+pub fn set_priority(pid: u32, priority: i32) -> Result<(), Errno> {
+    if !(PRIORITY_HIGHEST..=PRIORITY_LOWEST).contains(&priority) {
+        return Err(Errno::EINVAL);
+    }
+    with_task(pid, |task| {
+        task.priority = priority;
+        Ok(())
+    })
+}
+
+/// The CPUs the process `pid` may run on.
+pub fn affinity(pid: u32) -> Result<CpuMask, Errno> {
+    with_task(pid, |task| Ok(task.affinity()))
+}
+
+/// Restrict the process `pid` to the CPUs of `affinity`, migrating it if need
+/// be.
+pub fn set_affinity(pid: u32, affinity: CpuMask) -> Result<(), Errno> {
+    with_task(pid, |task| task.set_affinity(affinity))?;
+    sched::migrate(pid, affinity);
+    Ok(())
+}
+
+/// The CPU the process `pid` is running on, if any.
+pub fn running_cpu(pid: u32) -> Option<usize> {
+    CURRENT.iter().position(|current| current.load(Ordering::SeqCst) == pid)
+}
+
+/// Whether the process `pid` can run, i.e. it exists and isn't waiting for
+/// anything.
+pub fn is_runnable(pid: u32) -> bool {
+    with_task(pid, |task| {
+        Ok(matches!(task.state, TaskState::Running | TaskState::Runnable))
+    }).unwrap_or(false)
+}
+
+/// Add `cycles` to the CPU time of the process `pid`.
+pub(super) fn charge(pid: u32, cycles: u64) {
+    let _ = with_task(pid, |task| {
+        task.runtime = task.runtime.saturating_add(cycles);
+        Ok(())
+    });
+}
+
+/// Record the switch of this CPU from the process `prev` to `next`.
+pub(super) fn switch(prev: Option<u32>, next: Option<u32>) {
+    let mut table = PROCESSES.lock();
+
+    if let Some(task) = prev.and_then(|pid| table.get_mut(pid)) {
+        if task.state == TaskState::Running {
+            task.state = TaskState::Runnable;
+        }
+    }
+    if let Some(task) = next.and_then(|pid| table.get_mut(pid)) {
+        task.state = TaskState::Running;
+    }
+    drop(table);
+
+    set_current(next.unwrap_or(0));
+}
+
+/// The figures of all the processes and kernel threads, by increasing TID.
+pub fn stats() -> Vec<ProcessStats> {
+    let mut stats: Vec<_> = PROCESSES.lock().tasks.iter()
+        .map(|task| ProcessStats {
+            tid: task.tid,
+            pid: task.pid,
+            parent_pid: task.parent_pid,
+            name: task.name.clone(),
+            state: task.state,
+            priority: task.priority,
+            runtime: task.runtime,
+        })
+        .collect();
+
+    stats.sort_unstable_by_key(|stats| stats.tid);
+    stats
+}
+
+/// The name of the program at `path`, i.e. its file name.
+#[cfg(feature = "userland")]
+fn program_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Load the program at `path` into a new address space, with a stack; return
+/// it along with the machine context entering the program.
+#[cfg(feature = "userland")]
+fn load_program(
+    path: &str,
+) -> Result<(VirtualMemory, TaskMachineContext), Errno> {
+    let image = fs::read(path)?;
+    let mut vm = VirtualMemory::new(USER_SPACE);
+    let entry = elf::load(image, &mut vm)?;
+
+    let stack_rw = VmPermissions {
+        readable: true,
+        writable: true,
+        executable: false,
+    };
+    vm.insert(VAddr(USER_SPACE_END - USER_STACK_BSIZE)..USER_SPACE.end,
+              stack_rw, VmBacking::Anonymous)?;
+
+    let trampoline_rx = VmPermissions {
+        readable: true,
+        writable: false,
+        executable: true,
+    };
+    vm.insert(SIGNAL_TRAMPOLINE..(SIGNAL_TRAMPOLINE + PAGE_SIZE),
+              trampoline_rx,
+              VmBacking::File { data: SIGRETURN_TRAMPOLINE, offset: 0 })?;
+
+    // The stack pages start zeroed, which reads as an empty argv, envp and
+    // auxiliary vector above the stack pointer.
+    let stack = VAddr(USER_SPACE_END - 8 * size_of::<usize>());
+
+    Ok((vm, TaskMachineContext::user(entry, stack)))
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    fn create_process() -> u32 {
+        let machine_ctx = TaskMachineContext::user(VAddr(0), VAddr(0));
+        create("test", VirtualMemory::new(USER_SPACE), machine_ctx).unwrap()
+    }
+
+    fn parent_of(pid: u32) -> Option<u32> {
+        PROCESSES.lock().get_mut(pid).map(|task| task.parent_pid)
+    }
+
+    #[test]
+    fn it_reaps_exited_children() {
+        let parent = create_process();
+        let child = fork(parent).unwrap();
+        assert_eq!(parent_of(child), Some(parent));
+
+        assert_eq!(waitpid(parent, child as i32, WNOHANG), Ok(None));
+        exit(child, 3);
+        assert_eq!(waitpid(parent, -1, 0), Ok(Some((child, 3 << 8))));
+        assert_eq!(waitpid(parent, -1, WNOHANG), Err(Errno::ECHILD));
+
+        exit(parent, 0);
+        assert_eq!(parent_of(parent), None);
+        assert_eq!(with_vm(parent, |_| Ok(())), Err(Errno::ESRCH));
+    }
+
+    #[test]
+    fn it_hands_over_orphans() {
+        let parent = create_process();
+        let zombie = fork(parent).unwrap();
+        let running = fork(parent).unwrap();
+
+        exit(zombie, 1);
+        exit(parent, 0);
+        assert_eq!(parent_of(zombie), None);
+        assert_eq!(parent_of(running), Some(0));
+
+        exit(running, 0);
+        assert_eq!(parent_of(running), None);
+    }
+}
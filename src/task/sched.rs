@@ -0,0 +1,368 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The scheduling policy, a weighted round-robin over the runnable processes.
+//! Each CPU has its own run queue, listing the tasks waiting for it; the task
+//! at its head gets the CPU for a time slice proportional to the weight of its
+//! priority, then goes back at its end if still runnable. Priorities are nice
+//! values, from `PRIORITY_HIGHEST` to `PRIORITY_LOWEST`: each step of priority
+//! is worth about 10% of CPU time against a competing task.
+//!
+//! A task only runs on the CPUs of its affinity mask. New tasks go to the
+//! least loaded of them; a CPU whose queue is empty steals a task from the
+//! busiest queue, and every `BALANCE_PERIOD_MS` each CPU pulls a task from
+//! the busiest queue if it is longer than its own by more than one. Queues
+//! are only ever locked one at a time. Until the application processors are
+//! brought up, see `task::smp`, the bootstrap CPU's queue is the only one
+//! used.
+//!
+//! The time each process spends on a CPU is accounted in timestamp cycles, on
+//! every timer tick and on every switch. There is no context switching yet:
+//! `schedule()` tells which task is to run next, once `need_resched()` says
+//! the slice of the current one ended; the arch-specific switch acts upon it.
+
+use alloc::collections::VecDeque;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, CpuMask, MAX_CPUS};
+use crate::task::process;
+
+pub const PRIORITY_HIGHEST: i32 = -20;
+pub const PRIORITY_LOWEST: i32 = 19;
+pub const DEFAULT_PRIORITY: i32 = 0;
+
+/// The weight of each priority, from the highest; each step is a ratio of
+/// about 1.25.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// The weight of `DEFAULT_PRIORITY`.
+const DEFAULT_WEIGHT: u64 = 1024;
+
+/// The time slice of a task of `DEFAULT_PRIORITY`, in milliseconds.
+const BASE_SLICE_MS: u64 = 10;
+
+/// The period at which each CPU balances the load of the run queues.
+const BALANCE_PERIOD_MS: u64 = 100;
+
+static RUN_QUEUES: [Spinlock<RunQueue>; MAX_CPUS] =
+    [const { Spinlock::new(RunQueue::new()) }; MAX_CPUS];
+
+static CPUS: [CpuSched; MAX_CPUS] = [CpuSched::NEW; MAX_CPUS];
+
+struct CpuSched {
+    /// The timestamp up to which the CPU time was accounted.
+    accounted: AtomicU64,
+    /// The timestamp at which the slice of the current task ends.
+    slice_end: AtomicU64,
+    need_resched: AtomicBool,
+    /// The timestamp of the last load balancing.
+    balanced: AtomicU64,
+    /// The length of the run queue, read without locking it.
+    nr_queued: AtomicUsize,
+}
+
+impl CpuSched {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        accounted: AtomicU64::new(0),
+        slice_end: AtomicU64::new(u64::MAX),
+        need_resched: AtomicBool::new(false),
+        balanced: AtomicU64::new(0),
+        nr_queued: AtomicUsize::new(0),
+    };
+}
+
+/// A task in a run queue, with its affinity so that other CPUs can tell
+/// whether they may steal it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Queued {
+    pid: u32,
+    affinity: CpuMask,
+}
+
+struct RunQueue {
+    /// The runnable processes waiting for the CPU, the next to run first.
+    queue: VecDeque<Queued>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, task: Queued) {
+        if !self.queue.iter().any(|queued| queued.pid == task.pid) {
+            self.queue.push_back(task);
+        }
+    }
+
+    fn remove(&mut self, pid: u32) -> Option<Queued> {
+        let pos = self.queue.iter().position(|queued| queued.pid == pid)?;
+        self.queue.remove(pos)
+    }
+
+    /// Take the task that waited the least among those allowed on `cpu`, for
+    /// that CPU to steal it.
+    fn steal(&mut self, cpu: usize) -> Option<Queued> {
+        let pos = self.queue.iter()
+            .rposition(|queued| queued.affinity.contains(cpu))?;
+        self.queue.remove(pos)
+    }
+}
+
+/// Make the process `pid` wait for a CPU, on the least loaded one it may run
+/// on.
+pub fn enqueue(pid: u32) {
+    let Ok(affinity) = process::affinity(pid) else {
+        return;
+    };
+
+    if let Some(cpu) = least_loaded(affinity) {
+        with_queue(cpu, |queue| queue.push(Queued { pid, affinity }));
+    }
+}
+
+/// Remove the process `pid` from the run queues, as it stopped being
+/// runnable.
+pub fn dequeue(pid: u32) {
+    for cpu in CpuMask::online().iter() {
+        with_queue(cpu, |queue| queue.remove(pid));
+    }
+}
+
+/// Apply the new `affinity` of the process `pid`: move it off the run queue of
+/// a CPU it may not run on anymore, and have such a CPU running it reschedule.
+pub fn migrate(pid: u32, affinity: CpuMask) {
+    for cpu in CpuMask::online().iter() {
+        if affinity.contains(cpu) {
+            continue;
+        }
+        if with_queue(cpu, |queue| queue.remove(pid)).is_some() {
+            enqueue(pid);
+        }
+    }
+
+    if let Some(cpu) = process::running_cpu(pid) {
+        if !affinity.contains(cpu) {
+            CPUS[cpu].need_resched.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Account the CPU time of this CPU, request a reschedule if the slice of the
+/// current task ended, and balance the load periodically; this is meant to be
+/// called on every timer tick.
+pub fn tick() {
+    let now = timestamp();
+    account(now);
+
+    let cpu_index = current_cpu_index();
+    let this = cpu_index.get();
+    let cpu = &CPUS[this];
+    if now >= cpu.slice_end.load(Ordering::Relaxed) {
+        cpu.need_resched.store(true, Ordering::Relaxed);
+    }
+
+    let period = BALANCE_PERIOD_MS * timestamp_frequency_or_default() / 1000;
+    if now.saturating_sub(cpu.balanced.load(Ordering::Relaxed)) >= period {
+        cpu.balanced.store(now, Ordering::Relaxed);
+        balance(this);
+    }
+}
+
+/// Whether the task running on this CPU should make way for `schedule()`.
+pub fn need_resched() -> bool {
+    CPUS[current_cpu_index().get()].need_resched.load(Ordering::Relaxed)
+}
+
+/// Pick the process to run next on this CPU, in place of the current one;
+/// return `None` if there is none, for the CPU to idle.
+pub fn schedule() -> Option<u32> {
+    let now = timestamp();
+    account(now);
+
+    let cpu_index = current_cpu_index();
+    let this = cpu_index.get();
+
+    let prev = process::current()
+        .filter(|&pid| process::is_runnable(pid));
+    if let Some(pid) = prev {
+        match process::affinity(pid) {
+            Ok(affinity) if affinity.contains(this) => {
+                with_queue(this, |queue| {
+                    queue.push(Queued { pid, affinity });
+                });
+            },
+            _ => enqueue(pid),
+        }
+    }
+
+    let next = with_queue(this, |queue| queue.queue.pop_front())
+        .or_else(|| steal(this))
+        .map(|queued| queued.pid);
+
+    let cpu = &CPUS[this];
+    let slice_end = match next {
+        Some(pid) => {
+            let priority = process::priority(pid).unwrap_or(DEFAULT_PRIORITY);
+            let freq = timestamp_frequency_or_default();
+            now.saturating_add(slice_cycles(priority, freq))
+        },
+        None => u64::MAX,
+    };
+    cpu.slice_end.store(slice_end, Ordering::Relaxed);
+    cpu.need_resched.store(false, Ordering::Relaxed);
+
+    process::switch(prev, next);
+    next
+}
+
+/// The number of tasks waiting in the run queue of the CPU `cpu`.
+pub fn nr_queued(cpu: usize) -> usize {
+    CPUS[cpu].nr_queued.load(Ordering::Relaxed)
+}
+
+fn with_queue<R>(cpu: usize, f: impl FnOnce(&mut RunQueue) -> R) -> R {
+    let mut queue = RUN_QUEUES[cpu].lock();
+    let value = f(&mut queue);
+    CPUS[cpu].nr_queued.store(queue.queue.len(), Ordering::Relaxed);
+    value
+}
+
+/// Steal a task for the CPU `this`, from the busiest run queue holding one
+/// allowed on it.
+fn steal(this: usize) -> Option<Queued> {
+    let loads = loads();
+    let mut victims: ArrayVec<usize, MAX_CPUS> = CpuMask::online().iter()
+        .filter(|&cpu| cpu != this && loads[cpu] > 0)
+        .collect();
+    victims.sort_unstable_by_key(|&cpu| Reverse(loads[cpu]));
+
+    victims.into_iter()
+        .find_map(|cpu| with_queue(cpu, |queue| queue.steal(this)))
+}
+
+/// Pull a task to the CPU `this` from the busiest run queue, if it is longer
+/// than the CPU's own by more than one.
+fn balance(this: usize) {
+    let loads = loads();
+    let Some(busiest) = busiest(&loads, this) else {
+        return;
+    };
+
+    if let Some(task) = with_queue(busiest, |queue| queue.steal(this)) {
+        with_queue(this, |queue| queue.push(task));
+    }
+}
+
+/// The length of the run queue of each CPU.
+fn loads() -> [usize; MAX_CPUS] {
+    let mut loads = [0; MAX_CPUS];
+    for cpu in CpuMask::online().iter() {
+        loads[cpu] = nr_queued(cpu);
+    }
+    loads
+}
+
+/// The online CPU of `affinity` with the shortest run queue.
+fn least_loaded(affinity: CpuMask) -> Option<usize> {
+    let loads = loads();
+    affinity.iter()
+        .filter(|&cpu| CpuMask::online().contains(cpu))
+        .min_by_key(|&cpu| loads[cpu])
+}
+
+/// The CPU whose run queue is longer than the one of `this` by more than one,
+/// the longest if several are.
+fn busiest(loads: &[usize; MAX_CPUS], this: usize) -> Option<usize> {
+    (0..MAX_CPUS)
+        .filter(|&cpu| loads[cpu] > loads[this] + 1)
+        .max_by_key(|&cpu| loads[cpu])
+}
+
+/// Charge the cycles elapsed since the last accounting on this CPU to the
+/// process running on it, if any.
+fn account(now: u64) {
+    let cpu = &CPUS[current_cpu_index().get()];
+    let last = cpu.accounted.swap(now, Ordering::Relaxed);
+
+    if let Some(pid) = process::current() {
+        process::charge(pid, now.saturating_sub(last));
+    }
+}
+
+/// The length of the time slice of a task of `priority`, in timestamp cycles
+/// at `freq` Hz.
+fn slice_cycles(priority: i32, freq: u64) -> u64 {
+    let index = (priority.clamp(PRIORITY_HIGHEST, PRIORITY_LOWEST)
+        - PRIORITY_HIGHEST) as usize;
+    let base = BASE_SLICE_MS * freq / 1000;
+
+    (base as u128 * WEIGHTS[index] as u128 / DEFAULT_WEIGHT as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_weights_slices_by_priority() {
+        let freq = 1_000_000;
+
+        assert_eq!(slice_cycles(DEFAULT_PRIORITY, freq), 10_000);
+        assert_eq!(slice_cycles(1, freq), 8_007);
+        assert!(slice_cycles(PRIORITY_HIGHEST, freq) > 80 * 10_000);
+        assert_eq!(slice_cycles(100, freq),
+                   slice_cycles(PRIORITY_LOWEST, freq));
+    }
+
+    #[test]
+    #[cfg(feature = "smp")]
+    fn it_steals_tasks_allowed_on_the_thief() {
+        let mut queue = RunQueue::new();
+        let pinned = Queued { pid: 1, affinity: CpuMask::of(0) };
+        let free = Queued { pid: 2, affinity: CpuMask::ALL };
+        queue.push(free);
+        queue.push(pinned);
+        queue.push(free);
+
+        assert_eq!(queue.queue.len(), 2);
+        assert_eq!(queue.steal(1), Some(free));
+        assert_eq!(queue.steal(1), None);
+        assert_eq!(queue.steal(0), Some(pinned));
+    }
+
+    #[test]
+    #[cfg(feature = "smp")]
+    fn it_balances_from_the_busiest_queue() {
+        let mut loads = [0; MAX_CPUS];
+        loads[..4].copy_from_slice(&[2, 4, 3, 1]);
+
+        assert_eq!(busiest(&loads, 3), Some(1));
+        assert_eq!(busiest(&loads, 0), Some(1));
+        assert_eq!(busiest(&loads, 2), None);
+    }
+}
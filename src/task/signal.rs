@@ -0,0 +1,432 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Signals, the asynchronous notifications of processes: sent by other
+//! processes with `kill()`, or by the kernel as the consequence of what a task
+//! did, e.g. `SIGSEGV` on an invalid access. A signal stays pending while the
+//! task's mask blocks it, and is delivered by `deliver()`, which the
+//! arch-specific code calls before returning to user space: either its
+//! default disposition applies, terminating the process or ignoring the
+//! signal, or the task calls the handler installed with `sigaction()`.
+//!
+//! Handlers return to the signal trampoline, mapped in every address space,
+//! which calls `rt_sigreturn()` to resume the interrupted context. That
+//! context is kept by the kernel rather than on the user stack: a handler
+//! leaving with `longjmp()` leaves it behind, only the last `MAX_FRAMES` are
+//! kept.
+//!
+//! There is no job control yet: the stop and continue signals are ignored.
+
+use alloc::vec::Vec;
+
+use crate::arch::task::TaskMachineContext;
+use crate::mem::VAddr;
+use crate::task::process::{self, with_task, SIGNAL_TRAMPOLINE};
+use crate::task::syscall::Errno;
+use crate::task::Task;
+
+pub const NSIG: usize = 64;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// The handler of the default disposition.
+pub const SIG_DFL: usize = 0;
+/// The handler ignoring the signal.
+pub const SIG_IGN: usize = 1;
+
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// The number of interrupted contexts kept for the handlers running.
+const MAX_FRAMES: usize = 16;
+
+/// The signals that can't be caught, blocked nor ignored.
+const UNCATCHABLE: SigSet = SigSet(SigSet::of(SIGKILL).0
+                                   | SigSet::of(SIGSTOP).0);
+
+/// A set of signals, with signal `n` as bit `n - 1`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SigSet(pub u64);
+
+impl SigSet {
+    pub const fn of(signal: usize) -> Self {
+        Self(1 << (signal - 1))
+    }
+
+    pub fn contains(self, signal: usize) -> bool {
+        self.0 & Self::of(signal).0 != 0
+    }
+
+    pub fn insert(&mut self, signal: usize) {
+        self.0 |= Self::of(signal).0;
+    }
+
+    pub fn remove(&mut self, signal: usize) {
+        self.0 &= !Self::of(signal).0;
+    }
+
+    /// The lowest signal of the set, delivered first.
+    fn first(self) -> Option<usize> {
+        (self.0 != 0).then(|| self.0.trailing_zeros() as usize + 1)
+    }
+}
+
+/// How a process handles a signal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SigAction {
+    /// The address of the handler, or `SIG_DFL` or `SIG_IGN`.
+    pub handler: usize,
+    /// The signals blocked while the handler runs, besides the signal itself.
+    pub mask: SigSet,
+}
+
+impl SigAction {
+    pub const DEFAULT: Self = Self {
+        handler: SIG_DFL,
+        mask: SigSet(0),
+    };
+}
+
+/// The context interrupted by a signal handler.
+struct SignalFrame {
+    machine_ctx: TaskMachineContext,
+    mask: SigSet,
+}
+
+/// The signals of a process: those blocked, those pending, and how each is
+/// handled.
+pub struct SignalState {
+    mask: SigSet,
+    pending: SigSet,
+    actions: [SigAction; NSIG],
+    /// The contexts interrupted by the handlers running, the last one for the
+    /// innermost handler.
+    frames: Vec<SignalFrame>,
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            mask: SigSet(0),
+            pending: SigSet(0),
+            actions: [SigAction::DEFAULT; NSIG],
+            frames: Vec::new(),
+        }
+    }
+
+    /// The state of a forked child, which inherits the mask and the actions
+    /// but none of the pending signals.
+    pub fn fork(&self) -> Self {
+        Self {
+            mask: self.mask,
+            actions: self.actions,
+            ..Self::new()
+        }
+    }
+
+    /// Reset the handlers, whose code is gone with the program replaced by
+    /// `execve()`; ignored signals remain ignored.
+    pub fn exec(&mut self) {
+        for action in &mut self.actions {
+            if action.handler != SIG_IGN {
+                *action = SigAction::DEFAULT;
+            }
+        }
+        self.frames.clear();
+    }
+
+    /// Make a signal pending, unless it would be ignored anyway.
+    pub(super) fn raise(&mut self, signal: usize) {
+        if !self.is_ignored(signal) {
+            self.pending.insert(signal);
+        }
+    }
+
+    fn is_ignored(&self, signal: usize) -> bool {
+        match self.actions[signal - 1].handler {
+            SIG_IGN => true,
+            SIG_DFL => is_ignored_by_default(signal),
+            _ => false,
+        }
+    }
+}
+
+/// Send `signal` to the process `pid`; the null signal only checks that the
+/// process exists. Kernel threads ignore all signals.
+pub fn send(pid: u32, signal: usize) -> Result<(), Errno> {
+    if signal != 0 {
+        check_signal(signal)?;
+    }
+
+    with_task(pid, |task| {
+        if signal != 0 && !task.is_kernel_thread() {
+            task.signals.raise(signal);
+        }
+        Ok(())
+    })
+}
+
+/// Send `signal` to the process `pid` on behalf of the kernel, for what its
+/// task did, e.g. `SIGSEGV` on an invalid access: if the signal is blocked or
+/// ignored, its default disposition is restored rather than letting the task
+/// run into the same fault again.
+pub fn force(pid: u32, signal: usize) {
+    let _ = with_task(pid, |task| {
+        let signals = &mut task.signals;
+        let action = &mut signals.actions[signal - 1];
+
+        if signals.mask.contains(signal) || action.handler == SIG_IGN {
+            signals.mask.remove(signal);
+            *action = SigAction::DEFAULT;
+        }
+        signals.pending.insert(signal);
+        Ok(())
+    });
+}
+
+/// Deliver the pending signals of the process `pid` that aren't blocked,
+/// before it returns to user space: either its task is made to call a
+/// handler, or the process is terminated.
+pub fn deliver(pid: u32) {
+    if let Ok(Some(signal)) = with_task(pid, |task| Ok(deliver_next(task))) {
+        process::exit_by_signal(pid, signal);
+    }
+}
+
+/// Deliver the pending signals of `task` up to the first one not ignored;
+/// return it if it terminates the process.
+fn deliver_next(task: &mut Task) -> Option<usize> {
+    loop {
+        let signals = &mut task.signals;
+        let signal = SigSet(signals.pending.0 & !signals.mask.0).first()?;
+        signals.pending.remove(signal);
+
+        let action = signals.actions[signal - 1];
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL if is_ignored_by_default(signal) => continue,
+            SIG_DFL => return Some(signal),
+            handler => {
+                if signals.frames.len() == MAX_FRAMES {
+                    signals.frames.remove(0);
+                }
+                signals.frames.push(SignalFrame {
+                    machine_ctx: task.machine_ctx.clone(),
+                    mask: signals.mask,
+                });
+
+                let entered = task.machine_ctx.enter_signal_handler(
+                    &task.vm,
+                    VAddr(handler),
+                    signal,
+                    SIGNAL_TRAMPOLINE,
+                );
+                if entered.is_err() {
+                    // The stack can't hold the call, e.g. it overflowed.
+                    let frame = signals.frames.pop().unwrap();
+                    task.machine_ctx = frame.machine_ctx;
+                    return Some(SIGSEGV);
+                }
+
+                signals.mask.0 |= action.mask.0 | SigSet::of(signal).0;
+                signals.mask.0 &= !UNCATCHABLE.0;
+                return None;
+            },
+        }
+    }
+}
+
+/// Resume the context interrupted by the innermost signal handler of the
+/// process `pid`, once it returned; return the value of the context's return
+/// register, for the system call to return it unchanged.
+pub fn sigreturn(pid: u32) -> Result<usize, Errno> {
+    with_task(pid, |task| {
+        let frame = task.signals.frames.pop().ok_or(Errno::EINVAL)?;
+        task.signals.mask = frame.mask;
+        task.machine_ctx = frame.machine_ctx;
+        Ok(task.machine_ctx.return_value())
+    })
+}
+
+/// Set how the process `pid` handles `signal` if `action` is given; return
+/// the previous action.
+pub fn sigaction(
+    pid: u32,
+    signal: usize,
+    action: Option<SigAction>,
+) -> Result<SigAction, Errno> {
+    check_signal(signal)?;
+    if action.is_some() && UNCATCHABLE.contains(signal) {
+        return Err(Errno::EINVAL);
+    }
+
+    with_task(pid, |task| {
+        let signals = &mut task.signals;
+        let old = signals.actions[signal - 1];
+
+        if let Some(mut action) = action {
+            action.mask.0 &= !UNCATCHABLE.0;
+            signals.actions[signal - 1] = action;
+            if signals.is_ignored(signal) {
+                signals.pending.remove(signal);
+            }
+        }
+        Ok(old)
+    })
+}
+
+/// Change the signal mask of the process `pid` with `set` as `how` says, if
+/// given: `SIG_BLOCK`, `SIG_UNBLOCK` or `SIG_SETMASK`; return the previous
+/// mask.
+pub fn sigprocmask(
+    pid: u32,
+    how: usize,
+    set: Option<SigSet>,
+) -> Result<SigSet, Errno> {
+    with_task(pid, |task| {
+        let mask = &mut task.signals.mask;
+        let old = *mask;
+
+        if let Some(set) = set {
+            mask.0 = match how {
+                SIG_BLOCK => mask.0 | set.0,
+                SIG_UNBLOCK => mask.0 & !set.0,
+                SIG_SETMASK => set.0,
+                _ => return Err(Errno::EINVAL),
+            } & !UNCATCHABLE.0;
+        }
+        Ok(old)
+    })
+}
+
+fn check_signal(signal: usize) -> Result<(), Errno> {
+    match signal {
+        1..=NSIG => Ok(()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Whether the default disposition of `signal` is to ignore it rather than
+/// terminating the process.
+fn is_ignored_by_default(signal: usize) -> bool {
+    matches!(signal, SIGCHLD | SIGURG | SIGWINCH | SIGCONT | SIGSTOP
+        | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::mem::PAGE_SIZE;
+    use crate::mem::uaccess::copy_from_user;
+    use crate::task::process::{create, exit, with_vm};
+    use crate::task::vm::{VirtualMemory, VmBacking, VmPermissions};
+    use super::*;
+
+    #[repr(align(4096))]
+    struct Stack([u8; PAGE_SIZE]);
+
+    #[test]
+    fn it_keeps_blocked_signals_pending() {
+        let mut signals = SignalState::new();
+        signals.mask.insert(SIGUSR1);
+        signals.raise(SIGUSR1);
+        signals.raise(SIGCHLD);
+
+        assert_eq!(signals.pending, SigSet::of(SIGUSR1));
+        assert_eq!(SigSet(signals.pending.0 & !signals.mask.0).first(), None);
+        assert_eq!(signals.fork().pending, SigSet(0));
+        assert_eq!(signals.fork().mask, SigSet::of(SIGUSR1));
+    }
+
+    #[test]
+    fn it_calls_handlers_and_returns_from_them() {
+        let mut stack = Stack([0; PAGE_SIZE]);
+        let base = stack.0.as_mut_ptr() as usize;
+        let rw = VmPermissions {
+            readable: true,
+            writable: true,
+            executable: false,
+        };
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(!(PAGE_SIZE - 1)));
+        vm.insert(VAddr(base)..VAddr(base + PAGE_SIZE), rw,
+                  VmBacking::Anonymous).unwrap();
+        let ctx = TaskMachineContext::user(VAddr(0x1000),
+                                           VAddr(base + PAGE_SIZE));
+        let pid = create("test", vm, ctx).unwrap();
+
+        let handler = SigAction { handler: 0x2000, mask: SigSet(0) };
+        sigaction(pid, SIGUSR1, Some(handler)).unwrap();
+        send(pid, SIGUSR1).unwrap();
+        deliver(pid);
+
+        let (rip, rsp, rdi, mask) = with_task(pid, |task| {
+            let ctx = &task.machine_ctx;
+            Ok((ctx.rip, ctx.rsp, ctx.rdi, task.signals.mask))
+        }).unwrap();
+        assert_eq!((rip, rdi, mask), (0x2000, SIGUSR1 as u64,
+                                      SigSet::of(SIGUSR1)));
+        let mut ret = [0; 8];
+        with_vm(pid, |vm| {
+            copy_from_user(vm, &mut ret, VAddr(rsp as usize))
+        }).unwrap();
+        assert_eq!(u64::from_ne_bytes(ret), SIGNAL_TRAMPOLINE.0 as u64);
+
+        sigreturn(pid).unwrap();
+        let (rip, mask) = with_task(pid, |task| {
+            Ok((task.machine_ctx.rip, task.signals.mask))
+        }).unwrap();
+        assert_eq!((rip, mask), (0x1000, SigSet(0)));
+        assert_eq!(sigreturn(pid), Err(Errno::EINVAL));
+
+        exit(pid, 0);
+    }
+
+    #[test]
+    fn it_terminates_on_default_dispositions() {
+        let ctx = TaskMachineContext::user(VAddr(0), VAddr(0));
+        let vm = VirtualMemory::new(VAddr(0)..VAddr(0));
+        let pid = create("test", vm, ctx).unwrap();
+
+        assert_eq!(sigaction(pid, SIGKILL, Some(SigAction::DEFAULT)),
+                   Err(Errno::EINVAL));
+        sigprocmask(pid, SIG_BLOCK, Some(SigSet::of(SIGTERM))).unwrap();
+        send(pid, SIGCHLD).unwrap();
+        send(pid, SIGTERM).unwrap();
+        deliver(pid);
+        assert_eq!(send(pid, 0), Ok(()));
+
+        sigprocmask(pid, SIG_SETMASK, Some(SigSet(0))).unwrap();
+        deliver(pid);
+        assert_eq!(send(pid, 0), Err(Errno::ESRCH));
+    }
+}
@@ -0,0 +1,168 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Cross-CPU function calls. `call_on()` queues a function into the mailbox of
+//! a CPU and interrupts it with an IPI; that CPU runs the functions of its
+//! mailbox from the interrupt handler, `handle_call_ipi()`, while the caller
+//! waits for them to complete; TLB shootdowns are to be built on it.
+//!
+//! The functions run in interrupt context: they must be short, and must not
+//! wait for anything the caller may hold. A CPU calling itself runs the
+//! function directly. While waiting, a caller runs the calls queued for its
+//! own CPU, so that two CPUs calling each other don't deadlock.
+//!
+//! The application processors are not brought up yet: `nr_cpus()` is 1, so
+//! every call only ever runs on the bootstrap CPU, directly.
+
+use arrayvec::ArrayVec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use thiserror_no_std::Error;
+
+use crate::arch;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::task::idle::nr_cpus;
+
+/// The most calls pending for a single CPU; further callers wait for room.
+const MAILBOX_LEN: usize = 16;
+
+static MAILBOXES: [Mailbox; MAX_CPUS] = [Mailbox::NEW; MAX_CPUS];
+
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SmpError {
+    #[error("there is no CPU {0}")]
+    NoSuchCpu(usize),
+}
+
+struct Mailbox {
+    calls: Spinlock<ArrayVec<Call, MAILBOX_LEN>>,
+}
+
+impl Mailbox {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self = Self {
+        calls: Spinlock::new(ArrayVec::new_const()),
+    };
+}
+
+#[derive(Copy, Clone)]
+struct Call {
+    f: &'static (dyn Fn() + Sync),
+    /// The number of CPUs yet to complete the call, decremented once `f`
+    /// returns.
+    pending: &'static AtomicUsize,
+}
+
+/// Run `f` on the CPU of index `cpu`, and wait for it to return.
+pub fn call_on(cpu: usize, f: impl Fn() + Sync) -> Result<(), SmpError> {
+    if cpu >= nr_cpus() {
+        return Err(SmpError::NoSuchCpu(cpu));
+    }
+
+    call(cpu..(cpu + 1), &f);
+    Ok(())
+}
+
+/// Run `f` on every CPU, the current one included, and wait for all of them to
+/// return.
+pub fn call_all(f: impl Fn() + Sync) {
+    call(0..nr_cpus(), &f);
+}
+
+/// Run the calls pending for the current CPU; this is meant to be called by
+/// the handler of the IPI sent by `arch::smp::send_call_ipi()`.
+pub fn handle_call_ipi() {
+    let cpu = current_cpu_index();
+    run_pending(cpu.get());
+}
+
+fn call(cpus: impl Iterator<Item = usize>, f: &(dyn Fn() + Sync)) {
+    // The current CPU can't change while we hold its index.
+    let this_cpu = current_cpu_index();
+    let pending = AtomicUsize::new(0);
+
+    // SAFETY: the call is only reachable from the mailboxes until `pending`
+    // drops to zero, which we wait for before returning.
+    let call = unsafe {
+        Call {
+            f: core::mem::transmute(f),
+            pending: core::mem::transmute(&pending),
+        }
+    };
+
+    let mut run_here = false;
+    for cpu in cpus {
+        if cpu == this_cpu.get() {
+            run_here = true;
+            continue;
+        }
+
+        pending.fetch_add(1, Ordering::SeqCst);
+        while MAILBOXES[cpu].calls.lock().try_push(call).is_err() {
+            run_pending(this_cpu.get());
+            spin_loop();
+        }
+        arch::smp::send_call_ipi(cpu);
+    }
+
+    if run_here {
+        f();
+    }
+    while pending.load(Ordering::SeqCst) > 0 {
+        run_pending(this_cpu.get());
+        spin_loop();
+    }
+}
+
+/// Run the calls in the mailbox of `cpu`, which must be the current CPU.
+fn run_pending(cpu: usize) {
+    loop {
+        let call = MAILBOXES[cpu].calls.lock().pop_at(0);
+        let Some(call) = call else {
+            break;
+        };
+
+        (call.f)();
+        call.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_runs_calls_on_the_current_cpu() {
+        let count = AtomicUsize::new(0);
+
+        call_on(0, || { count.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        call_all(|| { count.fetch_add(1, Ordering::SeqCst); });
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(call_on(MAX_CPUS, || ()),
+                   Err(SmpError::NoSuchCpu(MAX_CPUS)));
+    }
+
+    #[test]
+    fn it_runs_the_calls_of_the_mailbox() {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        static PENDING: AtomicUsize = AtomicUsize::new(2);
+        fn f() {
+            COUNT.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let call = Call { f: &f, pending: &PENDING };
+        MAILBOXES[0].calls.lock().extend([call, call]);
+        handle_call_ipi();
+
+        assert_eq!(COUNT.load(Ordering::SeqCst), 2);
+        assert_eq!(PENDING.load(Ordering::SeqCst), 0);
+    }
+}
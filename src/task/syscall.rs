@@ -0,0 +1,749 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The architecture-independent side of system calls. There is no user mode
+//! yet: the arch-specific entry point will call `dispatch()` with the PID of
+//! the calling process once there is one.
+//!
+//! Memory-management calls only update the task's `VirtualMemory`. There are
+//! no per-process page tables yet: user pages are never mapped, the page fault
+//! handler doesn't populate the regions, so no page table is ever changed
+//! here.
+//!
+//! `futex()` only supports `FUTEX_WAIT` and `FUTEX_WAKE`, see `task::futex`.
+//! The process lifecycle calls are implemented by `task::process`; `fork()`,
+//! `execve()` and `wait4()` need the `userland` feature, `ENOSYS` is returned
+//! otherwise. `execve()` doesn't pass `argv` nor `envp` to the program yet.
+//! The signal calls are implemented by `task::signal`; `rt_sigaction()`
+//! ignores the flags and the restorer, handlers always return to the kernel's
+//! signal trampoline.
+//!
+//! The file calls go through the file descriptor table of the process, see
+//! `task::fd`; reads and writes transfer at most `MAX_IO_BSIZE` bytes at once.
+//! Reads of TTYs wait for input, see `ui::tty`. Reads and writes of pipes wait
+//! for data or room, see `fs::pipe`; writing to a pipe with no reader left
+//! sends `SIGPIPE`.
+
+use alloc::vec;
+use core::cmp::min;
+use core::mem::size_of;
+use core::ops::Range;
+use core::time::Duration;
+use thiserror_no_std::Error;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::fs::{file, FsError};
+use crate::mem::uaccess::{copy_from_user, copy_str_from_user, copy_to_user};
+use crate::mem::VAddr;
+use crate::misc::align_up;
+use crate::task::elf::ElfError;
+use crate::task::futex::{futex_prepare_wait, futex_wake};
+use crate::task::process::{exit, with_files, with_vm};
+#[cfg(feature = "userland")]
+use crate::task::process::{execve, fork, waitpid};
+use crate::task::signal::{self, SigAction, SigSet, SIGPIPE};
+use crate::task::vm::{VirtualMemory, VmBacking, VmError, VmPermissions};
+
+pub const SYS_READ: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MPROTECT: usize = 10;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_RT_SIGACTION: usize = 13;
+pub const SYS_RT_SIGPROCMASK: usize = 14;
+pub const SYS_RT_SIGRETURN: usize = 15;
+pub const SYS_PIPE: usize = 22;
+pub const SYS_DUP2: usize = 33;
+pub const SYS_FORK: usize = 57;
+pub const SYS_EXECVE: usize = 59;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_WAIT4: usize = 61;
+pub const SYS_KILL: usize = 62;
+pub const SYS_FUTEX: usize = 202;
+
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+/// Futexes are always private to their address space, the flag is accepted
+/// but ignored.
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+/// The longest path accepted from user space, in bytes.
+pub const MAX_PATH_LEN: usize = 256;
+
+/// The most bytes a single `read()` or `write()` transfers.
+pub const MAX_IO_BSIZE: usize = 64 << 10;
+
+/// The error codes returned to user space, negated, by failing system calls.
+#[derive(Error, Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(isize)]
+pub enum Errno {
+    #[error("no such file or directory")]
+    ENOENT = 2,
+
+    #[error("no such process")]
+    ESRCH = 3,
+
+    #[error("input/output error")]
+    EIO = 5,
+
+    #[error("exec format error")]
+    ENOEXEC = 8,
+
+    #[error("bad file descriptor")]
+    EBADF = 9,
+
+    #[error("no child processes")]
+    ECHILD = 10,
+
+    #[error("resource temporarily unavailable")]
+    EAGAIN = 11,
+
+    #[error("out of memory")]
+    ENOMEM = 12,
+
+    #[error("bad address")]
+    EFAULT = 14,
+
+    #[error("file exists")]
+    EEXIST = 17,
+
+    #[error("not a directory")]
+    ENOTDIR = 20,
+
+    #[error("is a directory")]
+    EISDIR = 21,
+
+    #[error("invalid argument")]
+    EINVAL = 22,
+
+    #[error("too many open files")]
+    EMFILE = 24,
+
+    #[error("inappropriate ioctl for device")]
+    ENOTTY = 25,
+
+    #[error("file too large")]
+    EFBIG = 27,
+
+    #[error("no space left on device")]
+    ENOSPC = 28,
+
+    #[error("illegal seek")]
+    ESPIPE = 29,
+
+    #[error("read-only file system")]
+    EROFS = 30,
+
+    #[error("broken pipe")]
+    EPIPE = 32,
+
+    #[error("file name too long")]
+    ENAMETOOLONG = 36,
+
+    #[error("function not implemented")]
+    ENOSYS = 38,
+
+    #[error("connection timed out")]
+    ETIMEDOUT = 110,
+}
+
+impl From<VmError> for Errno {
+    fn from(e: VmError) -> Self {
+        match e {
+            VmError::InvalidRange | VmError::Overlap => Errno::EINVAL,
+            VmError::NoSpace(_) => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::Io => Errno::EIO,
+            FsError::NoMemory => Errno::ENOMEM,
+            FsError::Exists => Errno::EEXIST,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::InvalidName
+            | FsError::Unsupported
+            | FsError::NotSupported
+            | FsError::InvalidArgument => Errno::EINVAL,
+            FsError::BadIoctl => Errno::ENOTTY,
+            FsError::WouldBlock => Errno::EAGAIN,
+            FsError::NotSeekable => Errno::ESPIPE,
+            FsError::BrokenPipe => Errno::EPIPE,
+            FsError::TooBig => Errno::EFBIG,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::Corrupted => Errno::EIO,
+        }
+    }
+}
+
+impl From<ElfError> for Errno {
+    fn from(e: ElfError) -> Self {
+        match e {
+            ElfError::Map(e) => e.into(),
+            _ => Errno::ENOEXEC,
+        }
+    }
+}
+
+/// Run the system call `nr` with its arguments `args` on behalf of the process
+/// `pid`; return the value for user space, a negated `Errno` on failure.
+pub fn dispatch(pid: u32, nr: usize, args: [usize; 6]) -> isize {
+    let result = match nr {
+        SYS_READ => sys_read(pid, args[0], args[1], args[2]),
+        SYS_WRITE => sys_write(pid, args[0], args[1], args[2]),
+        SYS_OPEN => sys_open(pid, args[0], args[1]),
+        SYS_CLOSE => with_files(pid, |files| files.close(args[0])).map(|_| 0),
+        SYS_LSEEK => sys_lseek(pid, args[0], args[1] as i64, args[2]),
+        SYS_MMAP => with_vm(pid, |vm| {
+            sys_mmap(vm, args[0], args[1], args[2], args[3])
+        }),
+        SYS_MPROTECT => with_vm(pid, |vm| {
+            sys_mprotect(vm, args[0], args[1], args[2])
+        }).map(|_| 0),
+        SYS_MUNMAP => with_vm(pid, |vm| sys_munmap(vm, args[0], args[1]))
+            .map(|_| 0),
+        SYS_RT_SIGACTION => {
+            sys_rt_sigaction(pid, args[0], args[1], args[2], args[3])
+                .map(|_| 0)
+        },
+        SYS_RT_SIGPROCMASK => {
+            sys_rt_sigprocmask(pid, args[0], args[1], args[2], args[3])
+                .map(|_| 0)
+        },
+        SYS_RT_SIGRETURN => signal::sigreturn(pid),
+        SYS_PIPE => sys_pipe(pid, args[0]).map(|_| 0),
+        SYS_DUP2 => with_files(pid, |files| files.dup2(args[0], args[1])),
+        #[cfg(feature = "userland")]
+        SYS_FORK => fork(pid).map(|child| child as usize),
+        #[cfg(feature = "userland")]
+        SYS_EXECVE => sys_execve(pid, args[0]).map(|_| 0),
+        SYS_EXIT => {
+            exit(pid, args[0] as i32);
+            Ok(0)
+        },
+        #[cfg(feature = "userland")]
+        SYS_WAIT4 => sys_wait4(pid, args[0], args[1], args[2]),
+        SYS_KILL => sys_kill(args[0], args[1]).map(|_| 0),
+        SYS_FUTEX => sys_futex(pid, args[0], args[1], args[2], args[3]),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    }
+}
+
+/// Create an anonymous private mapping of `len` bytes, at `addr` if `flags`
+/// has `MAP_FIXED`, replacing what was there, or at the lowest free address
+/// otherwise; return its address.
+pub fn sys_mmap(
+    vm: &mut VirtualMemory,
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+) -> Result<usize, Errno> {
+    if flags & !(MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0
+        || flags & (MAP_PRIVATE | MAP_ANONYMOUS) != MAP_PRIVATE | MAP_ANONYMOUS {
+        return Err(Errno::EINVAL);
+    }
+    let permissions = permissions_from_prot(prot)?;
+
+    if flags & MAP_FIXED != 0 {
+        let range = page_range(addr, len)?;
+        vm.replace(range, permissions, VmBacking::Anonymous)?;
+        Ok(addr)
+    } else {
+        if len == 0 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(vm.mmap(len, permissions, VmBacking::Anonymous)?.0)
+    }
+}
+
+/// Remove the mappings within `len` bytes from `addr`; unmapping a range with
+/// no mapping is not an error.
+pub fn sys_munmap(
+    vm: &mut VirtualMemory,
+    addr: usize,
+    len: usize,
+) -> Result<(), Errno> {
+    vm.unmap(page_range(addr, len)?)?;
+    Ok(())
+}
+
+/// Change the protection of the mappings within `len` bytes from `addr`, which
+/// must be entirely mapped; `PROT_NONE` makes them inaccessible.
+pub fn sys_mprotect(
+    vm: &mut VirtualMemory,
+    addr: usize,
+    len: usize,
+    prot: usize,
+) -> Result<(), Errno> {
+    let range = page_range(addr, len)?;
+    let permissions = permissions_from_prot(prot)?;
+
+    if pages(&range).any(|vaddr| vm.find(vaddr).is_none()) {
+        return Err(Errno::ENOMEM);
+    }
+    vm.protect(range, permissions)?;
+    Ok(())
+}
+
+/// Read at most `count` bytes from the file descriptor `fd` of the process
+/// `pid` into the user buffer at `buf`; return the number of bytes read.
+pub fn sys_read(
+    pid: u32,
+    fd: usize,
+    buf: usize,
+    count: usize,
+) -> Result<usize, Errno> {
+    let file = with_files(pid, |files| files.get(fd))?;
+    if !file.is_readable() {
+        return Err(Errno::EBADF);
+    }
+
+    let mut data = vec![0; min(count, MAX_IO_BSIZE)];
+    let len = file.read(&mut data)?;
+    with_vm(pid, |vm| copy_to_user(vm, VAddr(buf), &data[..len]))?;
+    Ok(len)
+}
+
+/// Write at most `count` bytes of the user buffer at `buf` to the file
+/// descriptor `fd` of the process `pid`; return the number of bytes written.
+pub fn sys_write(
+    pid: u32,
+    fd: usize,
+    buf: usize,
+    count: usize,
+) -> Result<usize, Errno> {
+    let file = with_files(pid, |files| files.get(fd))?;
+    if !file.is_writable() {
+        return Err(Errno::EBADF);
+    }
+
+    let mut data = vec![0; min(count, MAX_IO_BSIZE)];
+    with_vm(pid, |vm| copy_from_user(vm, &mut data, VAddr(buf)))?;
+    match file.write(&data) {
+        Err(FsError::BrokenPipe) => {
+            signal::send(pid, SIGPIPE)?;
+            Err(Errno::EPIPE)
+        },
+        result => Ok(result?),
+    }
+}
+
+/// Open the file at the path pointed to by `path` for the process `pid`, with
+/// the access mode of `flags`; return the new file descriptor.
+pub fn sys_open(pid: u32, path: usize, flags: usize) -> Result<usize, Errno> {
+    let path = with_vm(pid, |vm| {
+        copy_str_from_user::<MAX_PATH_LEN>(vm, VAddr(path))
+    })?;
+    let file = file::open(&path, flags)?;
+    with_files(pid, |files| files.insert(file))
+}
+
+/// Create a pipe for the process `pid`; store the file descriptors of its read
+/// end and of its write end as two `int`s at the user address `fds`.
+pub fn sys_pipe(pid: u32, fds: usize) -> Result<(), Errno> {
+    let (reader, writer) = file::pipe();
+    let (read_fd, write_fd) = with_files(pid, |files| {
+        let read_fd = files.insert(reader)?;
+        match files.insert(writer) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                files.close(read_fd)?;
+                Err(e)
+            },
+        }
+    })?;
+
+    let mut raw = [0; 2 * size_of::<i32>()];
+    raw[..4].copy_from_slice(&(read_fd as i32).to_ne_bytes());
+    raw[4..].copy_from_slice(&(write_fd as i32).to_ne_bytes());
+    if let Err(e) = with_vm(pid, |vm| copy_to_user(vm, VAddr(fds), &raw)) {
+        with_files(pid, |files| {
+            files.close(read_fd)?;
+            files.close(write_fd)
+        })?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Move the offset of the file descriptor `fd` of the process `pid`, see
+/// `OpenFile::seek()`; return the new offset.
+pub fn sys_lseek(
+    pid: u32,
+    fd: usize,
+    offset: i64,
+    whence: usize,
+) -> Result<usize, Errno> {
+    let file = with_files(pid, |files| files.get(fd))?;
+    Ok(file.seek(offset, whence)? as usize)
+}
+
+/// Replace the program of the process `pid` with the one at the path pointed
+/// to by `path`.
+#[cfg(feature = "userland")]
+pub fn sys_execve(pid: u32, path: usize) -> Result<(), Errno> {
+    let path = with_vm(pid, |vm| {
+        copy_str_from_user::<MAX_PATH_LEN>(vm, VAddr(path))
+    })?;
+    execve(pid, &path)
+}
+
+/// Wait for a child of the process `pid` to exit, see `process::waitpid()`;
+/// return its PID, and store its status at the user address `status` if not
+/// null.
+#[cfg(feature = "userland")]
+pub fn sys_wait4(
+    pid: u32,
+    target: usize,
+    status: usize,
+    options: usize,
+) -> Result<usize, Errno> {
+    let Some((child, wstatus)) = waitpid(pid, target as i32, options)? else {
+        return Ok(0);
+    };
+
+    if status != 0 {
+        with_vm(pid, |vm| {
+            copy_to_user(vm, VAddr(status), &wstatus.to_ne_bytes())
+        })?;
+    }
+    Ok(child as usize)
+}
+
+/// Set how the process `pid` handles `signal` to the `struct sigaction` at
+/// the user address `act` if not null, and store the previous action at the
+/// user address `oldact` if not null.
+pub fn sys_rt_sigaction(
+    pid: u32,
+    signal: usize,
+    act: usize,
+    oldact: usize,
+    sigsetsize: usize,
+) -> Result<(), Errno> {
+    if sigsetsize != size_of::<SigSet>() {
+        return Err(Errno::EINVAL);
+    }
+
+    // The fields of the structure are the handler, the flags, the restorer
+    // and the mask.
+    const FIELD: usize = size_of::<u64>();
+    let action = match act {
+        0 => None,
+        ptr => {
+            let mut raw = [0; 4 * FIELD];
+            with_vm(pid, |vm| copy_from_user(vm, &mut raw, VAddr(ptr)))?;
+            let field = |i: usize| {
+                u64::from_ne_bytes(raw[(i * FIELD)..][..FIELD]
+                    .try_into().unwrap())
+            };
+            Some(SigAction {
+                handler: field(0) as usize,
+                mask: SigSet(field(3)),
+            })
+        },
+    };
+
+    let old = signal::sigaction(pid, signal, action)?;
+    if oldact != 0 {
+        let mut raw = [0; 4 * FIELD];
+        raw[..FIELD].copy_from_slice(&(old.handler as u64).to_ne_bytes());
+        raw[(3 * FIELD)..].copy_from_slice(&old.mask.0.to_ne_bytes());
+        with_vm(pid, |vm| copy_to_user(vm, VAddr(oldact), &raw))?;
+    }
+    Ok(())
+}
+
+/// Change the signal mask of the process `pid` with the set at the user
+/// address `set` if not null, as `how` says; store the previous mask at the
+/// user address `oldset` if not null.
+pub fn sys_rt_sigprocmask(
+    pid: u32,
+    how: usize,
+    set: usize,
+    oldset: usize,
+    sigsetsize: usize,
+) -> Result<(), Errno> {
+    if sigsetsize != size_of::<SigSet>() {
+        return Err(Errno::EINVAL);
+    }
+
+    let set = match set {
+        0 => None,
+        ptr => {
+            let mut raw = [0; size_of::<u64>()];
+            with_vm(pid, |vm| copy_from_user(vm, &mut raw, VAddr(ptr)))?;
+            Some(SigSet(u64::from_ne_bytes(raw)))
+        },
+    };
+
+    let old = signal::sigprocmask(pid, how, set)?;
+    if oldset != 0 {
+        with_vm(pid, |vm| {
+            copy_to_user(vm, VAddr(oldset), &old.0.to_ne_bytes())
+        })?;
+    }
+    Ok(())
+}
+
+/// Send `signal` to the process `target`; there are no process groups, so
+/// `target` must be a PID.
+pub fn sys_kill(target: usize, signal: usize) -> Result<(), Errno> {
+    match target as i32 {
+        pid if pid > 0 => signal::send(pid as u32, signal),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Wait on, or wake the waiters of, the futex at `addr` of the process `pid`,
+/// depending on `op`. `FUTEX_WAIT` waits if the futex holds `value`, for at
+/// most the duration of the `struct timespec` at the user address `timeout` if
+/// not null; `FUTEX_WAKE` wakes up to `value` waiters and returns how many
+/// were.
+pub fn sys_futex(
+    pid: u32,
+    addr: usize,
+    op: usize,
+    value: usize,
+    timeout: usize,
+) -> Result<usize, Errno> {
+    match op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => {
+            // The wait happens once the address space is released.
+            let (wait, timeout) = with_vm(pid, |vm| {
+                let timeout = match timeout {
+                    0 => None,
+                    ptr => Some(read_timespec(vm, VAddr(ptr))?),
+                };
+                let wait = futex_prepare_wait(vm, VAddr(addr), value as u32)?;
+                Ok((wait, timeout))
+            })?;
+            wait.wait(timeout).map(|_| 0)
+        },
+        FUTEX_WAKE => with_vm(pid, |vm| futex_wake(vm, VAddr(addr), value)),
+        _ => Err(Errno::ENOSYS),
+    }
+}
+
+/// Read the `struct timespec` at the user address `ptr` as a duration.
+fn read_timespec(vm: &VirtualMemory, ptr: VAddr) -> Result<Duration, Errno> {
+    let mut buf = [0; 2 * size_of::<i64>()];
+    copy_from_user(vm, &mut buf, ptr)?;
+
+    let (sec, nsec) = buf.split_at(size_of::<i64>());
+    let sec = i64::from_ne_bytes(sec.try_into().unwrap());
+    let nsec = i64::from_ne_bytes(nsec.try_into().unwrap());
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(Duration::new(sec as u64, nsec as u32))
+}
+
+fn permissions_from_prot(prot: usize) -> Result<VmPermissions, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(VmPermissions {
+        readable: prot & PROT_READ != 0,
+        writable: prot & PROT_WRITE != 0,
+        executable: prot & PROT_EXEC != 0,
+    })
+}
+
+/// The pages spanning `len` bytes from `addr`, which must be page-aligned.
+fn page_range(addr: usize, len: usize) -> Result<Range<VAddr>, Errno> {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let end = addr.checked_add(align_up(len, PAGE_SIZE))
+        .ok_or(Errno::EINVAL)?;
+
+    Ok(VAddr(addr)..VAddr(end))
+}
+
+fn pages(range: &Range<VAddr>) -> impl Iterator<Item = VAddr> {
+    (range.start.0..range.end.0).step_by(PAGE_SIZE).map(VAddr)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::task::TaskMachineContext;
+    use crate::task::process;
+    use super::*;
+
+    #[test]
+    fn it_rejects_invalid_mmap_arguments() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(100 * PAGE_SIZE));
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+
+        assert_eq!(sys_mmap(&mut vm, 0, 0, PROT_READ, anon), Err(Errno::EINVAL));
+        assert_eq!(sys_mmap(&mut vm, 0, 4096, 0x80, anon), Err(Errno::EINVAL));
+        assert_eq!(sys_mmap(&mut vm, 0, 4096, PROT_READ, MAP_PRIVATE),
+                   Err(Errno::EINVAL));
+        assert_eq!(sys_mmap(&mut vm, 12, 4096, PROT_READ, anon | MAP_FIXED),
+                   Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn it_maps_anonymous_memory() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(100 * PAGE_SIZE));
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+
+        let addr = sys_mmap(&mut vm, 0, 5000, PROT_READ | PROT_WRITE, anon)
+            .unwrap();
+        assert_eq!(addr, 0);
+        assert!(vm.check_access(VAddr(0)..VAddr(2 * PAGE_SIZE), true));
+        assert!(!vm.check_access(VAddr(0)..VAddr(3 * PAGE_SIZE), false));
+
+        assert_eq!(dispatch(0, 4242, [0; 6]), -(Errno::ENOSYS as isize));
+    }
+
+    #[test]
+    fn it_keeps_mappings_when_map_fixed_fails() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(100 * PAGE_SIZE));
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+        let rw = PROT_READ | PROT_WRITE;
+
+        assert_eq!(sys_mmap(&mut vm, 0, 10 * PAGE_SIZE, rw, anon), Ok(0));
+        assert_eq!(sys_mmap(&mut vm, 5 * PAGE_SIZE, 100 * PAGE_SIZE, rw,
+                            anon | MAP_FIXED),
+                   Err(Errno::EINVAL));
+        assert!(vm.check_access(VAddr(0)..VAddr(10 * PAGE_SIZE), true));
+
+        assert_eq!(sys_mmap(&mut vm, 5 * PAGE_SIZE, PAGE_SIZE, PROT_READ,
+                            anon | MAP_FIXED),
+                   Ok(5 * PAGE_SIZE));
+        assert!(!vm.check_access(VAddr(0)..VAddr(10 * PAGE_SIZE), true));
+        assert!(vm.check_access(VAddr(0)..VAddr(10 * PAGE_SIZE), false));
+    }
+
+    #[test]
+    fn it_protects_and_unmaps_anonymous_memory() {
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(100 * PAGE_SIZE));
+        let anon = MAP_PRIVATE | MAP_ANONYMOUS;
+        let rw = PROT_READ | PROT_WRITE;
+        assert_eq!(sys_mmap(&mut vm, 0, 4 * PAGE_SIZE, rw, anon), Ok(0));
+
+        assert_eq!(sys_mprotect(&mut vm, PAGE_SIZE, PAGE_SIZE, 0), Ok(()));
+        assert!(!vm.check_access(VAddr(PAGE_SIZE)..VAddr(PAGE_SIZE + 1),
+                                 false));
+        assert!(vm.check_access(VAddr(2 * PAGE_SIZE)..VAddr(4 * PAGE_SIZE),
+                                true));
+        assert_eq!(sys_mprotect(&mut vm, 0, 5 * PAGE_SIZE, PROT_READ),
+                   Err(Errno::ENOMEM));
+
+        assert_eq!(sys_munmap(&mut vm, 0, 2 * PAGE_SIZE), Ok(()));
+        assert!(vm.find(VAddr(PAGE_SIZE)).is_none());
+        assert_eq!(sys_munmap(&mut vm, 50 * PAGE_SIZE, PAGE_SIZE), Ok(()));
+    }
+
+    #[test]
+    fn it_handles_futex_calls_that_dont_wait() {
+        #[repr(align(4096))]
+        struct Page([u8; PAGE_SIZE]);
+
+        let mut page = Page([0; PAGE_SIZE]);
+        page.0[..4].copy_from_slice(&42u32.to_ne_bytes());
+        let base = page.0.as_mut_ptr() as usize;
+        let rw = VmPermissions {
+            readable: true,
+            writable: true,
+            executable: false,
+        };
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(!(PAGE_SIZE - 1)));
+        vm.insert(VAddr(base)..VAddr(base + PAGE_SIZE), rw,
+                  VmBacking::Anonymous).unwrap();
+        let ctx = TaskMachineContext::user(VAddr(0), VAddr(0));
+        let pid = process::create("test", vm, ctx).unwrap();
+
+        assert_eq!(sys_futex(pid, base, FUTEX_WAIT, 41, 0),
+                   Err(Errno::EAGAIN));
+        assert_eq!(sys_futex(pid, base + 2, FUTEX_WAIT, 42, 0),
+                   Err(Errno::EINVAL));
+        assert_eq!(sys_futex(pid, base + PAGE_SIZE, FUTEX_WAIT, 0, 0),
+                   Err(Errno::EFAULT));
+        assert_eq!(sys_futex(pid, base, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 1, 0),
+                   Ok(0));
+        assert_eq!(sys_futex(pid, base, 9, 0, 0), Err(Errno::ENOSYS));
+        process::exit(pid, 0);
+    }
+
+    #[test]
+    fn it_reads_files_through_file_descriptors() {
+        #[repr(align(4096))]
+        struct Page([u8; PAGE_SIZE]);
+
+        let mut page = Page([0; PAGE_SIZE]);
+        page.0[..14].copy_from_slice(b"/etc/kshellrc\0");
+        let base = page.0.as_mut_ptr() as usize;
+        let rw = VmPermissions {
+            readable: true,
+            writable: true,
+            executable: false,
+        };
+        let mut vm = VirtualMemory::new(VAddr(0)..VAddr(!(PAGE_SIZE - 1)));
+        vm.insert(VAddr(base)..VAddr(base + PAGE_SIZE), rw,
+                  VmBacking::Anonymous).unwrap();
+        let ctx = TaskMachineContext::user(VAddr(0), VAddr(0));
+        let pid = process::create("test", vm, ctx).unwrap();
+        let content = crate::fs::read("/etc/kshellrc").unwrap();
+
+        let fd = sys_open(pid, base, file::O_RDONLY).unwrap();
+        assert_eq!(sys_read(pid, fd, base + 16, 8), Ok(8));
+        assert_eq!(&page.0[16..24], &content[..8]);
+        assert_eq!(sys_write(pid, fd, base, 1), Err(Errno::EBADF));
+        assert_eq!(sys_open(pid, base, file::O_WRONLY), Err(Errno::EROFS));
+
+        assert_eq!(sys_lseek(pid, fd, -2, file::SEEK_END),
+                   Ok(content.len() - 2));
+        assert_eq!(sys_read(pid, fd, base + 16, 8), Ok(2));
+        assert_eq!(sys_read(pid, fd, base + 16, 8), Ok(0));
+        assert_eq!(sys_lseek(pid, fd, -1, file::SEEK_SET),
+                   Err(Errno::EINVAL));
+
+        assert_eq!(dispatch(pid, SYS_DUP2, [fd, 42, 0, 0, 0, 0]), 42);
+        assert_eq!(dispatch(pid, SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(sys_read(pid, fd, base + 16, 8), Err(Errno::EBADF));
+        assert_eq!(sys_lseek(pid, 42, 0, file::SEEK_SET), Ok(0));
+
+        assert_eq!(sys_pipe(pid, base + 32), Ok(()));
+        let read_fd = u32::from_ne_bytes(page.0[32..36].try_into().unwrap());
+        let write_fd = u32::from_ne_bytes(page.0[36..40].try_into().unwrap());
+        assert_eq!(sys_write(pid, write_fd as usize, base, 4), Ok(4));
+        assert_eq!(sys_read(pid, read_fd as usize, base + 48, 8), Ok(4));
+        assert_eq!(&page.0[48..52], b"/etc");
+        assert_eq!(sys_lseek(pid, read_fd as usize, 0, file::SEEK_SET),
+                   Err(Errno::ESPIPE));
+        process::exit(pid, 0);
+    }
+}
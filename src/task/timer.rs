@@ -0,0 +1,298 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Software timers, run from the timer interrupt. They are kept in a
+//! hierarchical timer wheel with a resolution of a millisecond: level 0 has a
+//! slot per millisecond for the next `SLOTS` milliseconds, and each slot of
+//! the level above spans all the slots of the level below. As time advances,
+//! the slot of each level that becomes current is cascaded down to the lower
+//! levels, so that arming, canceling and expiring a timer is constant time.
+//!
+//! The wheel advances on every timer tick, by the milliseconds elapsed since
+//! the previous one; a timer thus expires on the first tick past its delay.
+//! Callbacks run in interrupt context, outside the wheel's lock, so that they
+//! can arm timers again.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::time::Duration;
+
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::sync::{Spinlock, WaitQueue};
+
+/// The number of bits of the slot index within a level.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+/// The longest delay the wheel holds, in milliseconds, about 4.6 hours; later
+/// timers are kept in the last level until they come within range.
+const MAX_DELAY_MS: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+static WHEEL: Spinlock<Wheel> = Spinlock::new(Wheel::new());
+
+/// The tasks in `sleep()`, which nothing but their timeout wakes up.
+static SLEEPERS: WaitQueue = WaitQueue::new();
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// A handle onto an armed timer. Dropping it doesn't cancel the timer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timer {
+    index: usize,
+    generation: u64,
+}
+
+struct Entry {
+    /// When the timer expires, in milliseconds.
+    expires: u64,
+    generation: u64,
+    callback: Callback,
+}
+
+struct Wheel {
+    /// The time up to which the wheel advanced, in milliseconds; zero until
+    /// the first timer is armed.
+    now: u64,
+    /// The timers of each slot of each level, as indices into `entries` along
+    /// with their generation; those canceled are skipped.
+    slots: [[Vec<(usize, u64)>; SLOTS]; LEVELS],
+    /// The armed timers; unused entries are `None` and listed in `free`.
+    entries: Vec<Option<Entry>>,
+    free: Vec<usize>,
+    next_generation: u64,
+    nr_armed: usize,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            now: 0,
+            slots: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+            entries: Vec::new(),
+            free: Vec::new(),
+            next_generation: 0,
+            nr_armed: 0,
+        }
+    }
+
+    fn insert(&mut self, expires: u64, callback: Callback) -> Timer {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+
+        let entry = Entry { expires, generation, callback };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index] = Some(entry);
+                index
+            },
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            },
+        };
+
+        self.nr_armed += 1;
+        self.place(index);
+        Timer { index, generation }
+    }
+
+    /// Put the entry at `index` in the slot for its remaining delay.
+    fn place(&mut self, index: usize) {
+        let entry = self.entries[index].as_ref().unwrap();
+        let expires = entry.expires.max(self.now + 1)
+            .min(self.now + MAX_DELAY_MS);
+        let delay = expires - self.now;
+
+        let level = (0..LEVELS)
+            .find(|&level| delay >> (SLOT_BITS * (level as u32 + 1)) == 0)
+            .unwrap_or(LEVELS - 1);
+        let slot = slot_index(expires, level);
+        self.slots[level][slot].push((index, entry.generation));
+    }
+
+    fn is_armed(&self, timer: &Timer) -> bool {
+        matches!(&self.entries.get(timer.index),
+                 Some(Some(entry)) if entry.generation == timer.generation)
+    }
+
+    fn remove(&mut self, index: usize) -> Option<Entry> {
+        let entry = self.entries[index].take()?;
+        self.free.push(index);
+        self.nr_armed -= 1;
+        Some(entry)
+    }
+
+    /// Advance the wheel up to `to` milliseconds, and return the callbacks of
+    /// the timers that expired.
+    fn advance(&mut self, to: u64) -> Vec<Callback> {
+        let mut expired = Vec::new();
+
+        if self.nr_armed == 0 {
+            self.now = self.now.max(to);
+            return expired;
+        }
+
+        while self.now < to {
+            self.now += 1;
+
+            for level in 1..LEVELS {
+                let mask = (1 << (SLOT_BITS * level as u32)) - 1;
+                if self.now & mask != 0 {
+                    break;
+                }
+                self.cascade(level, slot_index(self.now, level));
+            }
+
+            let slot = slot_index(self.now, 0);
+            for (index, generation) in mem::take(&mut self.slots[0][slot]) {
+                if !self.is_armed(&Timer { index, generation }) {
+                    continue;
+                }
+                if self.entries[index].as_ref().unwrap().expires <= self.now {
+                    expired.push(self.remove(index).unwrap().callback);
+                } else {
+                    self.place(index);
+                }
+            }
+        }
+
+        expired
+    }
+
+    /// Put the timers of the slot `slot` of the level `level` back in the
+    /// slots for their remaining delay, i.e. in lower levels.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        for (index, generation) in mem::take(&mut self.slots[level][slot]) {
+            if self.is_armed(&Timer { index, generation }) {
+                self.place(index);
+            }
+        }
+    }
+}
+
+impl Timer {
+    /// Arm a timer calling `callback`, from the timer interrupt, once `delay`
+    /// elapsed.
+    pub fn after(
+        delay: Duration,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> Timer {
+        let now = now_ms();
+        let delay = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+        let mut wheel = WHEEL.lock();
+
+        // An idle wheel doesn't advance, start it from the current time.
+        if wheel.nr_armed == 0 {
+            wheel.now = wheel.now.max(now);
+        }
+        let expires = now.saturating_add(delay);
+
+        wheel.insert(expires, Box::new(callback))
+    }
+
+    /// Cancel the timer, and return whether it was still armed.
+    pub fn cancel(&self) -> bool {
+        let mut wheel = WHEEL.lock();
+        if !wheel.is_armed(self) {
+            return false;
+        }
+
+        // The timer stays listed in its slot, which skips it once removed.
+        wheel.remove(self.index).is_some()
+    }
+
+    /// Whether the timer is still armed, i.e. neither expired nor canceled.
+    pub fn is_armed(&self) -> bool {
+        WHEEL.lock().is_armed(self)
+    }
+}
+
+/// Wait for `duration` to elapse.
+pub fn sleep(duration: Duration) {
+    SLEEPERS.wait_timeout(duration, || None::<()>);
+}
+
+/// Run the timers that expired; this is meant to be called on every timer
+/// tick.
+pub fn on_tick() {
+    let expired = WHEEL.lock().advance(now_ms());
+
+    for callback in expired {
+        callback();
+    }
+}
+
+fn slot_index(time: u64, level: usize) -> usize {
+    (time >> (SLOT_BITS * level as u32)) as usize & (SLOTS - 1)
+}
+
+fn now_ms() -> u64 {
+    let freq = timestamp_frequency_or_default();
+    timestamp() / (freq / 1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    fn counter(count: &Arc<AtomicUsize>) -> Callback {
+        let count = count.clone();
+        Box::new(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn it_expires_timers_at_their_deadline() {
+        let mut wheel = Wheel::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        wheel.now = 1000;
+
+        for delay in [1, 63, 64, 65, 5000, 300_000] {
+            wheel.insert(1000 + delay, counter(&count));
+        }
+
+        let mut expired = 0;
+        for (to, nr_expired) in [(1000, 0), (1001, 1), (1063, 1), (1065, 2),
+                                 (5999, 0), (6000, 1), (300_999, 0),
+                                 (301_000, 1)] {
+            let callbacks = wheel.advance(to);
+            assert_eq!(callbacks.len(), nr_expired, "at {to}");
+            expired += nr_expired;
+        }
+        assert_eq!(expired, 6);
+        assert_eq!(wheel.nr_armed, 0);
+    }
+
+    #[test]
+    fn it_cancels_timers() {
+        let mut wheel = Wheel::new();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let timer = wheel.insert(100, counter(&count));
+        assert!(wheel.is_armed(&timer));
+        assert!(wheel.remove(timer.index).is_some());
+        assert!(!wheel.is_armed(&timer));
+
+        // The new timer reuses the entry, the stale slot must not fire it.
+        let other = wheel.insert(200, counter(&count));
+        assert_eq!(other.index, timer.index);
+        assert!(wheel.advance(199).is_empty());
+        for callback in wheel.advance(200) {
+            callback();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
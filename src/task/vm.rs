@@ -8,17 +8,600 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-#[allow(unused)]
+//! The virtual memory layout of an address space: a set of non-overlapping,
+//! page-aligned regions, each with its permissions and what backs its
+//! content. This is the bookkeeping demand paging will rely on; there are no
+//! per-process page tables yet, so nothing maps the regions.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::ptr::{self, addr_of_mut, NonNull};
+use thiserror_no_std::Error;
+
+use crate::arch::mem::PAGE_SIZE;
+use crate::collections::{RbTree, TreeAdapter, TreeLink};
+use crate::mem::{PAddr, VAddr};
+use crate::misc::align_up;
+
+#[derive(Error, Debug)]
+pub enum VmError {
+    #[error("empty, unaligned or out of bounds range")]
+    InvalidRange,
+
+    #[error("the range overlaps an existing region")]
+    Overlap,
+
+    #[error("no free range of {0} bytes")]
+    NoSpace(usize),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VmPermissions {
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// What provides the content of a region's pages.
+#[derive(Copy, Clone)]
+pub enum VmBacking {
+    /// Zero-filled memory, allocated on first access.
+    Anonymous,
+
+    /// The content of a file, from its byte `offset`.
+    File { data: &'static [u8], offset: usize },
+
+    /// Device memory at a fixed physical address, e.g. a framebuffer.
+    Device { paddr: PAddr },
+}
+
+impl VmBacking {
+    /// The backing of the part of a region starting `offset` bytes into it.
+    fn advance(&self, offset: usize) -> Self {
+        match *self {
+            Self::Anonymous => Self::Anonymous,
+            Self::File { data, offset: start } => Self::File {
+                data,
+                offset: start + offset,
+            },
+            Self::Device { paddr } => Self::Device {
+                paddr: paddr + offset as u64,
+            },
+        }
+    }
+
+    /// Whether `next` is this backing continued `bsize` bytes further, so that
+    /// the two regions can be merged.
+    fn is_continued_by(&self, bsize: usize, next: &VmBacking) -> bool {
+        match (self.advance(bsize), next) {
+            (Self::Anonymous, Self::Anonymous) => true,
+            (Self::File { data: a, offset: a_off },
+             Self::File { data: b, offset: b_off }) => {
+                ptr::eq(a, *b) && a_off == *b_off
+            },
+            (Self::Device { paddr: a }, Self::Device { paddr: b }) => {
+                a.0 == b.0
+            },
+            _ => false,
+        }
+    }
+}
+
+pub struct VmRegion {
+    range: Range<VAddr>,
+    pub permissions: VmPermissions,
+    pub backing: VmBacking,
+    /// Whether the populated pages are shared with another address space
+    /// since a fork: the first write to one must map a copy of its own.
+    pub copy_on_write: bool,
+    link: TreeLink<VmRegion>,
+}
+
+impl VmRegion {
+    pub fn range(&self) -> Range<VAddr> {
+        self.range.clone()
+    }
+
+    pub fn bsize(&self) -> usize {
+        (self.range.end - self.range.start).0
+    }
+
+    pub fn contains(&self, vaddr: VAddr) -> bool {
+        vaddr >= self.range.start && vaddr < self.range.end
+    }
+}
+
+struct RegionAdapter;
+
+impl TreeAdapter for RegionAdapter {
+    type Node = VmRegion;
+    type Key = usize;
+
+    fn link(node: NonNull<VmRegion>) -> NonNull<TreeLink<VmRegion>> {
+        unsafe { NonNull::new_unchecked(addr_of_mut!((*node.as_ptr()).link)) }
+    }
+
+    fn key(node: &VmRegion) -> &usize {
+        &node.range.start.0
+    }
+}
+
+/// The regions of an address space, ordered by address. Adjacent regions with
+/// the same permissions and a continuous backing are merged.
 pub struct VirtualMemory {
-    //areas: Vec<VMArea>,
+    regions: RbTree<RegionAdapter>,
+
+    /// The addresses regions may be placed at.
+    bounds: Range<VAddr>,
+}
+
+unsafe impl Send for VirtualMemory {}
+
+impl VirtualMemory {
+    pub const fn new(bounds: Range<VAddr>) -> Self {
+        Self {
+            regions: RbTree::new(),
+            bounds,
+        }
+    }
+
+    /// Return the region containing `vaddr`, if any.
+    pub fn find(&self, vaddr: VAddr) -> Option<&VmRegion> {
+        let node = self.regions.floor(&vaddr.0)?;
+        let region = unsafe { node.as_ref() };
+
+        region.contains(vaddr).then_some(region)
+    }
+
+    /// Whether `range` is entirely covered by regions that allow reading, and
+    /// writing as well if `write` is set; this is how user pointers are
+    /// validated.
+    pub fn check_access(&self, range: Range<VAddr>, write: bool) -> bool {
+        let mut vaddr = range.start;
+
+        while vaddr < range.end {
+            let Some(region) = self.find(vaddr) else {
+                return false;
+            };
+            if !region.permissions.readable
+                || (write && !region.permissions.writable) {
+                return false;
+            }
+            vaddr = region.range.end;
+        }
+
+        true
+    }
+
+    /// Iterate over the regions, in address order.
+    pub fn regions(&self) -> impl Iterator<Item = &VmRegion> {
+        self.regions.iter().map(|node| unsafe { node.as_ref() })
+    }
+
+    /// Add a region at the fixed address `range`, which must be page-aligned
+    /// and free.
+    pub fn insert(
+        &mut self,
+        range: Range<VAddr>,
+        permissions: VmPermissions,
+        backing: VmBacking,
+    ) -> Result<(), VmError> {
+        self.check_range(&range)?;
+        if self.overlaps(&range) {
+            return Err(VmError::Overlap);
+        }
+
+        let node = NonNull::from(Box::leak(Box::new(VmRegion {
+            range,
+            permissions,
+            backing,
+            copy_on_write: false,
+            link: TreeLink::new(),
+        })));
+        unsafe { self.regions.insert(node); }
+        self.merge_around(node);
+
+        Ok(())
+    }
+
+    /// Add a region at the fixed address `range` like `insert()`, replacing the
+    /// parts of the regions it overlaps. Nothing is changed if `range` is
+    /// invalid.
+    pub fn replace(
+        &mut self,
+        range: Range<VAddr>,
+        permissions: VmPermissions,
+        backing: VmBacking,
+    ) -> Result<(), VmError> {
+        self.check_range(&range)?;
+        self.unmap(range.clone())?;
+        self.insert(range, permissions, backing)
+    }
+
+    /// Add a region of `bsize` bytes, rounded up to whole pages, at the lowest
+    /// free address; return its start address.
+    pub fn mmap(
+        &mut self,
+        bsize: usize,
+        permissions: VmPermissions,
+        backing: VmBacking,
+    ) -> Result<VAddr, VmError> {
+        if bsize == 0 {
+            return Err(VmError::InvalidRange);
+        }
+
+        let bsize = align_up(bsize, PAGE_SIZE);
+        let vaddr = self.find_gap(bsize).ok_or(VmError::NoSpace(bsize))?;
+        self.insert(vaddr..(vaddr + bsize), permissions, backing)?;
+
+        Ok(vaddr)
+    }
+
+    /// Remove every part of the regions within `range`, splitting the regions
+    /// straddling its ends.
+    pub fn unmap(&mut self, range: Range<VAddr>) -> Result<(), VmError> {
+        for node in self.isolate(&range)? {
+            unsafe {
+                self.regions.remove(node);
+                drop(Box::from_raw(node.as_ptr()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Change the permissions of every part of the regions within `range`,
+    /// splitting the regions straddling its ends.
+    pub fn protect(
+        &mut self,
+        range: Range<VAddr>,
+        permissions: VmPermissions,
+    ) -> Result<(), VmError> {
+        let nodes = self.isolate(&range)?;
+
+        for &node in &nodes {
+            unsafe { (*node.as_ptr()).permissions = permissions; }
+        }
+        for node in nodes {
+            // A previous merge may have absorbed this region already.
+            if self.regions.iter().any(|n| n == node) {
+                self.merge_around(node);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Duplicate the address space, for a child process. The writable regions
+    /// of memory, rather than devices, become copy-on-write in both address
+    /// spaces: their populated pages are shared until either one writes to
+    /// them.
+    pub fn fork(&mut self) -> Self {
+        let mut child = Self::new(self.bounds.clone());
+
+        for node in self.regions.iter() {
+            let region = unsafe { &mut *node.as_ptr() };
+            if region.permissions.writable
+                && !matches!(region.backing, VmBacking::Device { .. }) {
+                region.copy_on_write = true;
+            }
+
+            let copy = NonNull::from(Box::leak(Box::new(VmRegion {
+                range: region.range(),
+                permissions: region.permissions,
+                backing: region.backing,
+                copy_on_write: region.copy_on_write,
+                link: TreeLink::new(),
+            })));
+            unsafe { child.regions.insert(copy); }
+        }
+
+        child
+    }
+
+    /// Split the region containing `vaddr` in two, the second part starting at
+    /// `vaddr`. Nothing is done if `vaddr` is not strictly inside a region.
+    pub fn split(&mut self, vaddr: VAddr) -> Result<(), VmError> {
+        if vaddr.0 % PAGE_SIZE != 0 {
+            return Err(VmError::InvalidRange);
+        }
+
+        let Some(node) = self.regions.floor(&vaddr.0) else {
+            return Ok(());
+        };
+        let region = unsafe { &mut *node.as_ptr() };
+        if !region.contains(vaddr) || region.range.start == vaddr {
+            return Ok(());
+        }
+
+        let tail = NonNull::from(Box::leak(Box::new(VmRegion {
+            range: vaddr..region.range.end,
+            permissions: region.permissions,
+            backing: region.backing.advance((vaddr - region.range.start).0),
+            copy_on_write: region.copy_on_write,
+            link: TreeLink::new(),
+        })));
+        region.range.end = vaddr;
+        unsafe { self.regions.insert(tail); }
+
+        Ok(())
+    }
+
+    /// Check that `range` is non-empty, page-aligned and within the bounds.
+    fn check_range(&self, range: &Range<VAddr>) -> Result<(), VmError> {
+        if range.is_empty()
+            || range.start.0 % PAGE_SIZE != 0
+            || range.end.0 % PAGE_SIZE != 0
+            || range.start < self.bounds.start
+            || range.end > self.bounds.end {
+            return Err(VmError::InvalidRange);
+        }
+
+        Ok(())
+    }
+
+    fn overlaps(&self, range: &Range<VAddr>) -> bool {
+        let first = match self.regions.floor(&range.start.0) {
+            Some(node) => Some(node),
+            None => self.regions.first(),
+        };
+
+        let mut curr = first;
+        while let Some(node) = curr {
+            let region = unsafe { node.as_ref() };
+            if region.range.start >= range.end {
+                break;
+            }
+            if region.range.end > range.start {
+                return true;
+            }
+            curr = self.regions.next(node);
+        }
+
+        false
+    }
+
+    fn find_gap(&self, bsize: usize) -> Option<VAddr> {
+        let mut start = self.bounds.start;
+
+        for region in self.regions() {
+            if (region.range.start - start).0 >= bsize {
+                return Some(start);
+            }
+            start = region.range.end;
+        }
+
+        let end = self.bounds.end;
+        (end >= start && (end - start).0 >= bsize).then_some(start)
+    }
+
+    /// Split the regions at both ends of `range` and return the ones inside.
+    fn isolate(
+        &mut self,
+        range: &Range<VAddr>,
+    ) -> Result<Vec<NonNull<VmRegion>>, VmError> {
+        if range.is_empty() {
+            return Err(VmError::InvalidRange);
+        }
+
+        self.split(range.start)?;
+        self.split(range.end)?;
+
+        Ok(self.regions.iter()
+            .filter(|node| {
+                let region = unsafe { node.as_ref() };
+                region.range.start >= range.start && region.range.end <= range.end
+            })
+            .collect())
+    }
+
+    /// Merge the region `node` with its neighbors when they are compatible.
+    fn merge_around(&mut self, node: NonNull<VmRegion>) {
+        let mut node = node;
+
+        if let Some(prev) = self.regions.prev(node) {
+            if Self::can_merge(prev, node) {
+                self.merge(prev, node);
+                node = prev;
+            }
+        }
+        if let Some(next) = self.regions.next(node) {
+            if Self::can_merge(node, next) {
+                self.merge(node, next);
+            }
+        }
+    }
+
+    fn can_merge(left: NonNull<VmRegion>, right: NonNull<VmRegion>) -> bool {
+        let (left, right) = unsafe { (left.as_ref(), right.as_ref()) };
+
+        left.range.end == right.range.start
+            && left.permissions == right.permissions
+            && left.copy_on_write == right.copy_on_write
+            && left.backing.is_continued_by(left.bsize(), &right.backing)
+    }
+
+    /// Extend `left` over `right`, which is removed.
+    fn merge(&mut self, left: NonNull<VmRegion>, right: NonNull<VmRegion>) {
+        unsafe {
+            (*left.as_ptr()).range.end = right.as_ref().range.end;
+            self.regions.remove(right);
+            drop(Box::from_raw(right.as_ptr()));
+        }
+    }
+}
+
+impl Drop for VirtualMemory {
+    fn drop(&mut self) {
+        while let Some(node) = self.regions.first() {
+            unsafe {
+                self.regions.remove(node);
+                drop(Box::from_raw(node.as_ptr()));
+            }
+        }
+    }
 }
 
-#[allow(unused)]
-pub struct VMArea {
-    addr: usize,
-    size: usize,
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    const RW: VmPermissions = VmPermissions {
+        readable: true,
+        writable: true,
+        executable: false,
+    };
+    const RX: VmPermissions = VmPermissions {
+        readable: true,
+        writable: false,
+        executable: true,
+    };
+
+    fn page(n: usize) -> VAddr {
+        VAddr(n * PAGE_SIZE)
+    }
+
+    fn layout(vm: &VirtualMemory) -> Vec<(usize, usize)> {
+        vm.regions()
+            .map(|r| (r.range.start.0 / PAGE_SIZE, r.range.end.0 / PAGE_SIZE))
+            .collect()
+    }
+
+    #[test]
+    fn it_finds_regions_by_address() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(10)..page(20), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(30)..page(40), RX, VmBacking::Anonymous).unwrap();
+
+        assert!(vm.find(page(5)).is_none());
+        assert_eq!(vm.find(page(15)).unwrap().range(), page(10)..page(20));
+        assert!(vm.find(page(20)).is_none());
+        assert_eq!(vm.find(page(39) + 1).unwrap().permissions, RX);
+    }
+
+    #[test]
+    fn it_checks_accesses_against_permissions() {
+        const RO: VmPermissions = VmPermissions {
+            readable: true,
+            writable: false,
+            executable: false,
+        };
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(10)..page(20), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(20)..page(30), RO, VmBacking::Anonymous).unwrap();
+
+        assert!(vm.check_access(page(12)..page(25), false));
+        assert!(!vm.check_access(page(12)..page(25), true));
+        assert!(vm.check_access(page(12)..page(20), true));
+        assert!(!vm.check_access(page(5)..page(12), false));
+        assert!(!vm.check_access(page(25)..page(31), false));
+    }
+
+    #[test]
+    fn it_rejects_overlapping_regions() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(10)..page(20), RW, VmBacking::Anonymous).unwrap();
+
+        assert!(matches!(vm.insert(page(15)..page(25), RX, VmBacking::Anonymous),
+                         Err(VmError::Overlap)));
+        assert!(matches!(vm.insert(page(5)..page(11), RX, VmBacking::Anonymous),
+                         Err(VmError::Overlap)));
+        assert!(matches!(vm.insert(page(90)..page(110), RX, VmBacking::Anonymous),
+                         Err(VmError::InvalidRange)));
+    }
+
+    #[test]
+    fn it_maps_in_the_first_large_enough_gap() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(2)..page(10), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(13)..page(20), RX, VmBacking::Anonymous).unwrap();
+
+        assert_eq!(vm.mmap(3 * PAGE_SIZE, RX, VmBacking::Anonymous).unwrap(),
+                   page(10));
+        assert_eq!(vm.mmap(1, RW, VmBacking::Anonymous).unwrap(), page(0));
+        assert!(matches!(vm.mmap(200 * PAGE_SIZE, RW, VmBacking::Anonymous),
+                         Err(VmError::NoSpace(_))));
+    }
+
+    #[test]
+    fn it_merges_compatible_neighbors() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(10), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(20)..page(30), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(10)..page(20), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(30)..page(40), RX, VmBacking::Anonymous).unwrap();
+
+        assert_eq!(layout(&vm), [(0, 30), (30, 40)]);
+    }
+
+    #[test]
+    fn it_merges_file_regions_only_when_contiguous() {
+        static DATA: [u8; 8 * 4096] = [0; 8 * 4096];
+        let file = |offset| VmBacking::File { data: &DATA, offset };
+
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(2), RW, file(0)).unwrap();
+        vm.insert(page(2)..page(4), RW, file(2 * PAGE_SIZE)).unwrap();
+        vm.insert(page(4)..page(6), RW, file(0)).unwrap();
+
+        assert_eq!(layout(&vm), [(0, 4), (4, 6)]);
+    }
+
+    #[test]
+    fn it_forks_writable_regions_as_copy_on_write() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(10), RX, VmBacking::Anonymous).unwrap();
+        vm.insert(page(10)..page(20), RW, VmBacking::Anonymous).unwrap();
+
+        let child = vm.fork();
+        assert_eq!(layout(&child), layout(&vm));
+        for vm in [&vm, &child] {
+            let cow: Vec<_> = vm.regions().map(|r| r.copy_on_write).collect();
+            assert_eq!(cow, [false, true]);
+        }
+    }
+
+    #[test]
+    fn it_splits_regions_to_change_permissions() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(10), RW, VmBacking::Device { paddr: PAddr(0) })
+            .unwrap();
+
+        vm.protect(page(4)..page(6), RX).unwrap();
+        assert_eq!(layout(&vm), [(0, 4), (4, 6), (6, 10)]);
+        match vm.find(page(6)).unwrap().backing {
+            VmBacking::Device { paddr } => assert_eq!(paddr.0, 6 * 4096),
+            _ => panic!("wrong backing"),
+        }
+
+        vm.protect(page(4)..page(6), RW).unwrap();
+        assert_eq!(layout(&vm), [(0, 10)]);
+    }
+
+    #[test]
+    fn it_unmaps_parts_of_regions() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(10), RW, VmBacking::Anonymous).unwrap();
+        vm.insert(page(20)..page(30), RX, VmBacking::Anonymous).unwrap();
+
+        vm.unmap(page(5)..page(25)).unwrap();
+        assert_eq!(layout(&vm), [(0, 5), (25, 30)]);
+    }
+
+    #[test]
+    fn it_replaces_regions_only_when_the_range_is_valid() {
+        let mut vm = VirtualMemory::new(page(0)..page(100));
+        vm.insert(page(0)..page(10), RW, VmBacking::Anonymous).unwrap();
+
+        assert!(matches!(vm.replace(page(5)..page(110), RX,
+                                    VmBacking::Anonymous),
+                         Err(VmError::InvalidRange)));
+        assert_eq!(layout(&vm), [(0, 10)]);
 
-    enabled: bool,
-    writable: bool,
-    executable: bool,
+        vm.replace(page(5)..page(15), RX, VmBacking::Anonymous).unwrap();
+        assert_eq!(layout(&vm), [(0, 5), (5, 15)]);
+        assert_eq!(vm.find(page(5)).unwrap().permissions, RX);
+    }
 }
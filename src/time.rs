@@ -0,0 +1,119 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Boot and subsystem initialization timing. The `scope!()` macro measures the
+//! time spent until the end of the enclosing block; scopes can be nested and
+//! are recorded into a fixed-size buffer, so that the whole boot process can be
+//! printed as a hierarchical timing tree with `print_timings()`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::arch::time::{timestamp, timestamp_frequency};
+use crate::sync::Spinlock;
+use crate::{info, warning};
+
+const MAX_TIMING_RECORDS: usize = 64;
+
+/// The number of timestamp cycles assumed per second when the timestamp
+/// frequency is unknown.
+const FALLBACK_TIMESTAMP_FREQ: u64 = 1_000_000_000;
+
+static TIMINGS: Spinlock<ArrayVec<TimingRecord, MAX_TIMING_RECORDS>>
+    = Spinlock::new(ArrayVec::new_const());
+
+static CURRENT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone)]
+struct TimingRecord {
+    name: &'static str,
+    depth: usize,
+    start: u64,
+    end: Option<u64>,
+}
+
+/// A guard measuring the time elapsed between its creation and its drop. Use
+/// the `scope!()` macro rather than instantiating it directly.
+pub struct ScopeTimer {
+    index: Option<usize>,
+}
+
+impl ScopeTimer {
+    pub fn new(name: &'static str) -> Self {
+        let depth = CURRENT_DEPTH.fetch_add(1, Ordering::SeqCst);
+        let mut timings = TIMINGS.lock();
+        let record = TimingRecord {
+            name,
+            depth,
+            start: timestamp(),
+            end: None,
+        };
+        let index = timings.try_push(record).ok().map(|_| timings.len() - 1);
+
+        Self { index }
+    }
+}
+
+impl Drop for ScopeTimer {
+    fn drop(&mut self) {
+        let end = timestamp();
+
+        if let Some(index) = self.index {
+            TIMINGS.lock()[index].end = Some(end);
+        }
+        CURRENT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Measure the time spent from this point until the end of the enclosing
+/// block, and record it under `name` in the timing tree.
+macro_rules! scope {
+    ($name:expr) => {
+        let _scope_timer = $crate::time::ScopeTimer::new($name);
+    };
+}
+
+pub(crate) use scope;
+
+/// The timestamp frequency, in cycles per second, or a nominal 1 GHz when it is
+/// unknown, for the timeouts and periods that must be measured anyway.
+pub fn timestamp_frequency_or_default() -> u64 {
+    timestamp_frequency().unwrap_or(FALLBACK_TIMESTAMP_FREQ)
+}
+
+/// Print all recorded timings as a tree, each scope being indented under its
+/// parent. Durations are printed in microseconds if the timestamp frequency is
+/// known, or in raw cycles otherwise; unfinished scopes are marked as such.
+pub fn print_timings() {
+    let timings = TIMINGS.lock();
+    let freq = timestamp_frequency();
+
+    if timings.is_full() {
+        warning!("timing buffer is full, some scopes were not recorded");
+    }
+
+    for record in timings.iter() {
+        let indent = record.depth * 2;
+
+        match (record.end, freq) {
+            (Some(end), Some(freq)) => {
+                let us = (end - record.start) as u128 * 1_000_000 / freq as u128;
+                info!("{:indent$}{}: {} µs", "", record.name, us);
+            },
+            (Some(end), None) => {
+                info!("{:indent$}{}: {} cycles", "", record.name,
+                      end - record.start);
+            },
+            (None, _) => {
+                info!("{:indent$}{}: (running)", "", record.name);
+            },
+        }
+    }
+}
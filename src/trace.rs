@@ -0,0 +1,280 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Kernel tracing. Tracepoints, such as `trace_irq_entry!()`, are placed in
+//! hot paths of the kernel; while tracing is enabled, each of them records a
+//! compact event, its timestamp and two arguments, into the current CPU's ring
+//! buffer, the oldest events being overwritten first. Recording never waits
+//! nor allocates: an event is dropped if the buffer is busy, e.g. being dumped.
+//!
+//! Tracing is enabled with the `trace=on` boot option, or the `trace` shell
+//! command, which also dumps the events onto the serial line. The text format
+//! is meant for humans; the raw format is line-oriented, enclosed between
+//! `BEGIN_MARKER` and `END_MARKER` lines, for a host tool to decode:
+//!
+//! ```text
+//! event <cpu> <hex timestamp> <kind ID> <hex arg 0> <hex arg 1>
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::time::timestamp;
+use crate::sync::Spinlock;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+
+pub const BEGIN_MARKER: &str = "--- BEGIN NUCLOID TRACE v1 ---";
+pub const END_MARKER: &str = "--- END NUCLOID TRACE ---";
+
+/// The number of events kept per CPU.
+const BUFFER_LEN: usize = 256;
+
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static BUFFERS: [Spinlock<TraceBuffer>; MAX_CPUS]
+    = [const { Spinlock::new(TraceBuffer::new()) }; MAX_CPUS];
+
+/// The tracepoints; their discriminants are the kind IDs of the raw format,
+/// they must not change.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceEventKind {
+    /// A hardware interrupt was received: IRQ number.
+    IrqEntry = 1,
+    /// A block was allocated from the kernel heap: address, size.
+    Alloc = 2,
+    /// A block was freed to the kernel heap: address, size.
+    Dealloc = 3,
+    /// The CPU switched tasks: previous task ID, next task ID. This is meant
+    /// for the scheduler, once there is one.
+    SchedSwitch = 4,
+}
+
+impl TraceEventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceEventKind::IrqEntry => "irq_entry",
+            TraceEventKind::Alloc => "alloc",
+            TraceEventKind::Dealloc => "dealloc",
+            TraceEventKind::SchedSwitch => "sched_switch",
+        }
+    }
+
+    /// The names of the event's two arguments.
+    fn arg_names(self) -> [&'static str; 2] {
+        match self {
+            TraceEventKind::IrqEntry => ["irq", ""],
+            TraceEventKind::Alloc | TraceEventKind::Dealloc => ["ptr", "size"],
+            TraceEventKind::SchedSwitch => ["prev", "next"],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TraceEvent {
+    pub timestamp: u64,
+    pub cpu: u8,
+    pub kind: TraceEventKind,
+    pub args: [u64; 2],
+}
+
+impl TraceEvent {
+    /// Write the event in the raw format.
+    pub fn write_raw(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(w, "event {} {:x} {} {:x} {:x}",
+                 self.cpu, self.timestamp, self.kind as u8,
+                 self.args[0], self.args[1])
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:>16} {}", self.cpu, self.timestamp, self.kind.name())?;
+
+        for (name, value) in self.kind.arg_names().iter().zip(self.args) {
+            if !name.is_empty() {
+                write!(f, " {name}={value:#x}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct TraceBuffer {
+    events: [Option<TraceEvent>; BUFFER_LEN],
+    /// The number of events recorded since the buffer was last cleared.
+    head: usize,
+}
+
+impl TraceBuffer {
+    const fn new() -> Self {
+        Self {
+            events: [None; BUFFER_LEN],
+            head: 0,
+        }
+    }
+
+    fn push(&mut self, event: TraceEvent) {
+        self.events[self.head % BUFFER_LEN] = Some(event);
+        self.head += 1;
+    }
+
+    /// Iterate over the events, the oldest first.
+    fn iter(&self) -> impl Iterator<Item = &TraceEvent> {
+        let start = self.head.saturating_sub(BUFFER_LEN);
+
+        (start..self.head).filter_map(|i| self.events[i % BUFFER_LEN].as_ref())
+    }
+
+    fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+pub fn set_enabled(enabled: bool) {
+    TRACE_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record an event into the current CPU's buffer, if tracing is enabled; this
+/// is what the tracepoint macros expand to.
+#[inline]
+pub fn record(kind: TraceEventKind, args: [u64; 2]) {
+    if !is_enabled() {
+        return;
+    }
+
+    let cpu = current_cpu_index();
+    let Some(mut buffer) = BUFFERS[cpu.get()].try_lock() else {
+        return;
+    };
+
+    buffer.push(TraceEvent {
+        timestamp: timestamp(),
+        cpu: cpu.get() as u8,
+        kind,
+        args,
+    });
+}
+
+/// All the recorded events of all CPUs, by timestamp.
+pub fn events() -> Vec<TraceEvent> {
+    let mut events = Vec::new();
+
+    for buffer in &BUFFERS {
+        // Allocating while the buffer is locked would drop the `alloc` event.
+        events.reserve(BUFFER_LEN);
+        events.extend(buffer.lock().iter().copied());
+    }
+    events.sort_by_key(|event| event.timestamp);
+
+    events
+}
+
+/// Discard the recorded events of all CPUs.
+pub fn clear() {
+    for buffer in &BUFFERS {
+        buffer.lock().clear();
+    }
+}
+
+/// Write all the recorded events into `w`, in the text format, or in the raw
+/// one if `raw` is set.
+pub fn dump(w: &mut impl fmt::Write, raw: bool) -> fmt::Result {
+    let events = events();
+
+    if raw {
+        writeln!(w, "{BEGIN_MARKER}")?;
+        for event in &events {
+            event.write_raw(w)?;
+        }
+        writeln!(w, "{END_MARKER}")
+    } else {
+        for event in &events {
+            writeln!(w, "{event}")?;
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! trace_irq_entry {
+    ($irq:expr) => ({
+        $crate::trace::record($crate::trace::TraceEventKind::IrqEntry,
+                              [$irq as u64, 0]);
+    });
+}
+
+#[macro_export]
+macro_rules! trace_alloc {
+    ($ptr:expr, $size:expr) => ({
+        $crate::trace::record($crate::trace::TraceEventKind::Alloc,
+                              [$ptr as u64, $size as u64]);
+    });
+}
+
+#[macro_export]
+macro_rules! trace_dealloc {
+    ($ptr:expr, $size:expr) => ({
+        $crate::trace::record($crate::trace::TraceEventKind::Dealloc,
+                              [$ptr as u64, $size as u64]);
+    });
+}
+
+#[macro_export]
+macro_rules! trace_sched_switch {
+    ($prev:expr, $next:expr) => ({
+        $crate::trace::record($crate::trace::TraceEventKind::SchedSwitch,
+                              [$prev as u64, $next as u64]);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64) -> TraceEvent {
+        TraceEvent {
+            timestamp,
+            cpu: 0,
+            kind: TraceEventKind::Alloc,
+            args: [0x1000, 32],
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_latest_events() {
+        let mut buffer = TraceBuffer::new();
+
+        for timestamp in 0..(BUFFER_LEN as u64 + 3) {
+            buffer.push(event(timestamp));
+        }
+
+        assert_eq!(buffer.iter().count(), BUFFER_LEN);
+        assert_eq!(buffer.iter().next().unwrap().timestamp, 3);
+        assert_eq!(buffer.iter().last().unwrap().timestamp,
+                   BUFFER_LEN as u64 + 2);
+    }
+
+    #[test]
+    fn it_formats_events() {
+        let mut raw = alloc::string::String::new();
+        event(0x2a).write_raw(&mut raw).unwrap();
+
+        assert_eq!(raw, "event 0 2a 2 1000 20\n");
+        assert_eq!(alloc::format!("{}", event(42)),
+                   "[0]               42 alloc ptr=0x1000 size=0x20");
+    }
+}
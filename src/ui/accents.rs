@@ -0,0 +1,171 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Tables of the characters produced by dead keys: for each dead key, the list
+//! of `(base, accented)` pairs, lowercase and uppercase alike. A base character
+//! absent from a dead key's table has no precomposed accented form.
+
+use crate::driver::keyboard::Deadkey;
+
+const GRAVE_ACCENT: &[(char, char)] = &[
+    ('a', 'à'), ('e', 'è'), ('i', 'ì'), ('o', 'ò'), ('u', 'ù'), ('w', 'ẁ'),
+    ('y', 'ỳ'), ('n', 'ǹ'),
+    ('A', 'À'), ('E', 'È'), ('I', 'Ì'), ('O', 'Ò'), ('U', 'Ù'), ('W', 'Ẁ'),
+    ('Y', 'Ỳ'), ('N', 'Ǹ'),
+];
+
+const ACUTE_ACCENT: &[(char, char)] = &[
+    ('a', 'á'), ('e', 'é'), ('i', 'í'), ('o', 'ó'), ('u', 'ú'), ('y', 'ý'),
+    ('c', 'ć'), ('g', 'ǵ'), ('k', 'ḱ'), ('l', 'ĺ'), ('m', 'ḿ'), ('n', 'ń'),
+    ('p', 'ṕ'), ('r', 'ŕ'), ('s', 'ś'), ('w', 'ẃ'), ('z', 'ź'),
+    ('A', 'Á'), ('E', 'É'), ('I', 'Í'), ('O', 'Ó'), ('U', 'Ú'), ('Y', 'Ý'),
+    ('C', 'Ć'), ('G', 'Ǵ'), ('K', 'Ḱ'), ('L', 'Ĺ'), ('M', 'Ḿ'), ('N', 'Ń'),
+    ('P', 'Ṕ'), ('R', 'Ŕ'), ('S', 'Ś'), ('W', 'Ẃ'), ('Z', 'Ź'),
+];
+
+const CIRCUMFLEX: &[(char, char)] = &[
+    ('a', 'â'), ('z', 'ẑ'), ('e', 'ê'), ('y', 'ŷ'), ('u', 'û'), ('i', 'î'),
+    ('o', 'ô'), ('s', 'ŝ'), ('g', 'ĝ'), ('h', 'ĥ'), ('j', 'ĵ'), ('w', 'ŵ'),
+    ('c', 'ĉ'),
+    ('A', 'Â'), ('Z', 'Ẑ'), ('E', 'Ê'), ('Y', 'Ŷ'), ('U', 'Û'), ('I', 'Î'),
+    ('O', 'Ô'), ('S', 'Ŝ'), ('G', 'Ĝ'), ('H', 'Ĥ'), ('J', 'Ĵ'), ('W', 'Ŵ'),
+    ('C', 'Ĉ'),
+];
+
+const TILDE: &[(char, char)] = &[
+    ('a', 'ã'), ('e', 'ẽ'), ('i', 'ĩ'), ('o', 'õ'), ('u', 'ũ'), ('y', 'ỹ'),
+    ('n', 'ñ'), ('v', 'ṽ'),
+    ('A', 'Ã'), ('E', 'Ẽ'), ('I', 'Ĩ'), ('O', 'Õ'), ('U', 'Ũ'), ('Y', 'Ỹ'),
+    ('N', 'Ñ'), ('V', 'Ṽ'),
+];
+
+const MACRON: &[(char, char)] = &[
+    ('a', 'ā'), ('e', 'ē'), ('i', 'ī'), ('o', 'ō'), ('u', 'ū'), ('y', 'ȳ'),
+    ('g', 'ḡ'),
+    ('A', 'Ā'), ('E', 'Ē'), ('I', 'Ī'), ('O', 'Ō'), ('U', 'Ū'), ('Y', 'Ȳ'),
+    ('G', 'Ḡ'),
+];
+
+const BREVE: &[(char, char)] = &[
+    ('a', 'ă'), ('e', 'ĕ'), ('i', 'ĭ'), ('o', 'ŏ'), ('u', 'ŭ'), ('g', 'ğ'),
+    ('A', 'Ă'), ('E', 'Ĕ'), ('I', 'Ĭ'), ('O', 'Ŏ'), ('U', 'Ŭ'), ('G', 'Ğ'),
+];
+
+const DIAERESIS: &[(char, char)] = &[
+    ('a', 'ä'), ('e', 'ë'), ('t', 'ẗ'), ('y', 'ÿ'), ('u', 'ü'), ('i', 'ï'),
+    ('o', 'ö'), ('h', 'ḧ'), ('w', 'ẅ'), ('x', 'ẍ'),
+    ('A', 'Ä'), ('E', 'Ë'), ('Y', 'Ÿ'), ('U', 'Ü'), ('I', 'Ï'), ('O', 'Ö'),
+    ('H', 'Ḧ'), ('W', 'Ẅ'), ('X', 'Ẍ'),
+];
+
+const RING: &[(char, char)] = &[
+    ('a', 'å'), ('u', 'ů'), ('w', 'ẘ'), ('y', 'ẙ'),
+    ('A', 'Å'), ('U', 'Ů'),
+];
+
+const CARON: &[(char, char)] = &[
+    ('a', 'ǎ'), ('c', 'č'), ('d', 'ď'), ('e', 'ě'), ('g', 'ǧ'), ('h', 'ȟ'),
+    ('i', 'ǐ'), ('j', 'ǰ'), ('k', 'ǩ'), ('l', 'ľ'), ('n', 'ň'), ('o', 'ǒ'),
+    ('r', 'ř'), ('s', 'š'), ('t', 'ť'), ('u', 'ǔ'), ('z', 'ž'),
+    ('A', 'Ǎ'), ('C', 'Č'), ('D', 'Ď'), ('E', 'Ě'), ('G', 'Ǧ'), ('H', 'Ȟ'),
+    ('I', 'Ǐ'), ('K', 'Ǩ'), ('L', 'Ľ'), ('N', 'Ň'), ('O', 'Ǒ'), ('R', 'Ř'),
+    ('S', 'Š'), ('T', 'Ť'), ('U', 'Ǔ'), ('Z', 'Ž'),
+];
+
+/// Return the table of the accented characters produced by `deadkey`.
+pub fn table(deadkey: &Deadkey) -> &'static [(char, char)] {
+    match deadkey {
+        Deadkey::GraveAccent => GRAVE_ACCENT,
+        Deadkey::AcuteAccent => ACUTE_ACCENT,
+        Deadkey::Circumflex => CIRCUMFLEX,
+        Deadkey::Tilde => TILDE,
+        Deadkey::Macron => MACRON,
+        Deadkey::Breve => BREVE,
+        Deadkey::Diaeresis => DIAERESIS,
+        Deadkey::Ring => RING,
+        Deadkey::Caron => CARON,
+    }
+}
+
+/// Apply `deadkey` onto the base character `c`, if it has an accented form.
+pub fn apply(deadkey: &Deadkey, c: char) -> Option<char> {
+    table(deadkey).iter()
+        .find(|(base, _)| *base == c)
+        .map(|(_, accented)| *accented)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::keyboard::Deadkey;
+    use crate::ui::accents::{apply, table};
+
+    const ALL_DEADKEYS: [Deadkey; 9] = [
+        Deadkey::GraveAccent, Deadkey::AcuteAccent, Deadkey::Circumflex,
+        Deadkey::Tilde, Deadkey::Macron, Deadkey::Breve, Deadkey::Diaeresis,
+        Deadkey::Ring, Deadkey::Caron,
+    ];
+
+    #[test]
+    fn every_entry_applies() {
+        for deadkey in &ALL_DEADKEYS {
+            for &(base, accented) in table(deadkey) {
+                assert_eq!(apply(deadkey, base), Some(accented),
+                           "{deadkey:?} + {base:?}");
+                assert_eq!(apply(deadkey, accented), None);
+            }
+        }
+    }
+
+    #[test]
+    fn no_duplicate_base() {
+        for deadkey in &ALL_DEADKEYS {
+            let table = table(deadkey);
+            for (i, (base, _)) in table.iter().enumerate() {
+                assert!(table[(i + 1)..].iter().all(|(b, _)| b != base),
+                        "{deadkey:?}: duplicate entry for {base:?}");
+            }
+        }
+    }
+
+    /// Every lowercase entry whose accented form has a single-character
+    /// uppercase must have the matching uppercase entry, and vice versa.
+    #[test]
+    fn both_cases() {
+        for deadkey in &ALL_DEADKEYS {
+            for &(base, accented) in table(deadkey) {
+                let mut upper = accented.to_uppercase();
+                let mut lower = accented.to_lowercase();
+
+                if base.is_lowercase() {
+                    if let (Some(upper), None) = (upper.next(), upper.next()) {
+                        if upper != accented {
+                            assert_eq!(
+                                apply(deadkey, base.to_ascii_uppercase()),
+                                Some(upper),
+                                "{deadkey:?} + {base:?}: missing uppercase"
+                            );
+                        }
+                    }
+                } else {
+                    let lower = lower.next().unwrap();
+                    assert_eq!(apply(deadkey, base.to_ascii_lowercase()),
+                               Some(lower),
+                               "{deadkey:?} + {base:?}: missing lowercase");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unaccentable() {
+        assert_eq!(apply(&Deadkey::Ring, 'b'), None);
+        assert_eq!(apply(&Deadkey::Caron, ' '), None);
+    }
+}
@@ -0,0 +1,205 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel console input layer. Characters typed on any input device (the
+//! PS/2 keyboard, a serial line, ...) go through a `LineDiscipline` owned by
+//! the TTY they are echoed on, see `ui::tty`: that of the kernel terminal for
+//! the keyboard, whose events it reads from `driver::input`, or that of the
+//! serial line itself. The line discipline performs echoing and line editing;
+//! while no process is in the foreground of the TTY, its complete lines and
+//! interruptions are pushed into the shared `CONSOLE_INPUT` queue, whence the
+//! kernel shell reads them regardless of where they were typed.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::fmt;
+use arrayvec::ArrayVec;
+
+use crate::sync::Spinlock;
+use crate::ui::unicode;
+
+/// The maximum number of characters in a single line; any further character is
+/// dropped until the line is completed or discarded.
+pub const MAX_LINE_LEN: usize = 256;
+
+const MAX_PENDING_EVENTS: usize = 32;
+
+pub static CONSOLE_INPUT: Spinlock<VecDeque<ConsoleEvent>>
+    = Spinlock::new(VecDeque::new());
+
+#[derive(Debug)]
+pub enum ConsoleEvent {
+    /// A complete line was entered, without its line terminator.
+    Line(String),
+
+    /// The user requested to interrupt the current operation (Ctrl+C); the
+    /// line being edited was discarded.
+    Interrupt,
+}
+
+pub struct LineDiscipline {
+    line: ArrayVec<char, MAX_LINE_LEN>,
+    last_was_cr: bool,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        Self {
+            line: ArrayVec::new_const(),
+            last_was_cr: false,
+        }
+    }
+
+    /// Process one input character, echoing it to `echo` as needed. Returns
+    /// the resulting event if the character completed or discarded the line.
+    ///
+    /// The recognized control characters are:
+    ///   * `\r` or `\n`: complete the line (`\r\n` counts as a single one);
+    ///   * `\x08` (BS) or `\x7f` (DEL): erase the last character, with its
+    ///     combining marks;
+    ///   * `\x03` (ETX, Ctrl+C): discard the line and emit an interruption.
+    ///
+    /// Other control characters are ignored.
+    pub fn input(
+        &mut self,
+        c: char,
+        echo: &mut impl fmt::Write,
+    ) -> Option<ConsoleEvent> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, c == '\r');
+
+        match c {
+            '\n' if last_was_cr => None,
+            '\r' | '\n' => {
+                let _ = echo.write_char('\n');
+                Some(ConsoleEvent::Line(self.line.drain(..).collect()))
+            },
+            '\x08' | '\x7f' => {
+                let width = self.erase_grapheme();
+                for s in ["\x08", " ", "\x08"] {
+                    for _ in 0..width {
+                        let _ = echo.write_str(s);
+                    }
+                }
+                None
+            },
+            '\x03' => {
+                self.line.clear();
+                let _ = echo.write_str("^C\n");
+                Some(ConsoleEvent::Interrupt)
+            },
+            '\x00'..='\x1f' => None,
+            c => {
+                if self.line.try_push(c).is_ok() {
+                    let _ = echo.write_char(c);
+                }
+                None
+            },
+        }
+    }
+
+    /// Erase the last grapheme cluster of the line; return the number of
+    /// columns it took.
+    fn erase_grapheme(&mut self) -> usize {
+        let mut width = 0;
+
+        while let Some(c) = self.line.pop() {
+            width += unicode::char_width(c);
+            match self.line.last() {
+                Some(&prev) if unicode::extends_cluster(prev, c) => continue,
+                _ => break,
+            }
+        }
+
+        width
+    }
+
+    /// Same as `input()`, but directly push the resulting event, if any, into
+    /// the console input queue.
+    pub fn input_and_push(&mut self, c: char, echo: &mut impl fmt::Write) {
+        if let Some(event) = self.input(c, echo) {
+            push_event(event);
+        }
+    }
+}
+
+/// Push an input event into the console input queue. If too many events are
+/// pending, the oldest one is dropped: nobody is reading the console anyway.
+pub fn push_event(event: ConsoleEvent) {
+    let mut queue = CONSOLE_INPUT.lock();
+
+    if queue.len() >= MAX_PENDING_EVENTS {
+        queue.pop_front();
+    }
+    queue.push_back(event);
+}
+
+/// Pop the oldest pending console input event, if any.
+pub fn pop_event() -> Option<ConsoleEvent> {
+    CONSOLE_INPUT.lock().pop_front()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use crate::ui::console::{ConsoleEvent, LineDiscipline};
+
+    fn feed(ldisc: &mut LineDiscipline, s: &str, echo: &mut String)
+        -> Option<ConsoleEvent> {
+        let mut last = None;
+        for c in s.chars() {
+            if let Some(event) = ldisc.input(c, echo) {
+                last = Some(event);
+            }
+        }
+        last
+    }
+
+    #[test]
+    fn it_completes_lines() {
+        let mut ldisc = LineDiscipline::new();
+        let mut echo = String::new();
+
+        let event = feed(&mut ldisc, "héllo\r\n", &mut echo);
+        assert!(matches!(event, Some(ConsoleEvent::Line(l)) if l == "héllo"));
+        assert_eq!(echo, "héllo\n");
+        assert!(feed(&mut ldisc, "\n", &mut echo).is_some());
+    }
+
+    #[test]
+    fn it_erases_characters() {
+        let mut ldisc = LineDiscipline::new();
+        let mut echo = String::new();
+
+        let event = feed(&mut ldisc, "ab\x7f\x7f\x7fc\n", &mut echo);
+        assert!(matches!(event, Some(ConsoleEvent::Line(l)) if l == "c"));
+        assert_eq!(echo, "ab\x08 \x08\x08 \x08c\n");
+    }
+
+    #[test]
+    fn it_erases_whole_grapheme_clusters() {
+        let mut ldisc = LineDiscipline::new();
+        let mut echo = String::new();
+
+        let event = feed(&mut ldisc, "a漢e\u{301}\x7f\x7f\n", &mut echo);
+        assert!(matches!(event, Some(ConsoleEvent::Line(l)) if l == "a"));
+        assert_eq!(echo, "a漢e\u{301}\x08 \x08\x08\x08  \x08\x08\n");
+    }
+
+    #[test]
+    fn it_interrupts() {
+        let mut ldisc = LineDiscipline::new();
+        let mut echo = String::new();
+
+        assert!(matches!(feed(&mut ldisc, "abc\x03", &mut echo),
+                         Some(ConsoleEvent::Interrupt)));
+        let event = feed(&mut ldisc, "d\n", &mut echo);
+        assert!(matches!(event, Some(ConsoleEvent::Line(l)) if l == "d"));
+    }
+}
@@ -0,0 +1,100 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The escape commands of the kernel terminals, in the form `\x1b<fg=@red>`,
+//! where the command list is separated by semicolons; they are understood by
+//! both the graphical terminal and the text-mode one.
+
+use core::str::FromStr;
+
+use crate::ui::theme::TermColor;
+
+#[derive(Debug)]
+pub enum EscapeCommand {
+    SetFgColor(TermColor),
+    ClearFgColor,
+    SetBgColor(TermColor),
+    ClearBgColor,
+    Newline,
+}
+
+impl FromStr for EscapeCommand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cmd, arg) = s.split_once('=')
+            .map(|(k, v)| (k, Some(v)))
+            .unwrap_or((s, None));
+
+        use EscapeCommand::*;
+        Ok(match (cmd, arg) {
+            ("fg", Some(arg)) =>
+                SetFgColor(arg.parse().map_err(|_| ())?),
+            ("!fg", None) => ClearFgColor,
+            ("bg", Some(arg)) =>
+                SetBgColor(arg.parse().map_err(|_| ())?),
+            ("!bg", None) => ClearBgColor,
+            ("nl", None) => Newline,
+            _ => return Err(()),
+        })
+    }
+}
+
+pub struct EscapeIterator<'a> {
+    s: Option<&'a str>,
+    off: usize,
+}
+
+impl<'a> EscapeIterator<'a> {
+    pub fn new(s: &'a str) -> Self {
+        Self {
+            s: Some(s),
+            off: 0,
+        }
+    }
+
+    #[inline]
+    pub fn continuation_offset(&self) -> usize {
+        self.off
+    }
+}
+
+impl Iterator for EscapeIterator<'_> {
+    type Item = EscapeCommand;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.s?;
+
+        if self.off == 0 {
+            if s.len() == 0 || s.as_bytes()[0] != b'<' {
+                return None;
+            }
+            if let Some(end_pos) = s.find('>') {
+                self.off = end_pos + 1;
+                self.s = Some(&s[1..end_pos]);
+            } else {
+                return None;
+            }
+        }
+
+        loop {
+            let s = self.s?;
+            if let Some(pos) = s.find(';') {
+                self.s = Some(&s[(pos + 1)..]);
+                match s[..pos].parse() {
+                    Ok(cmd) => break Some(cmd),
+                    Err(_) => continue,
+                }
+            } else {
+                break self.s.take().and_then(|s| s.parse().ok());
+            }
+        }
+    }
+}
@@ -8,20 +8,46 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+//! Keymaps translate logical keys into characters, depending on the state of
+//! the modifiers. A keymap file has the following layout (little endian):
+//!
+//! ```text
+//! "KEYMAP" nr_mapping:u32
+//! nr_mapping × { key_name:NUL-terminated string, matrix:[u32; 8] }
+//! [ "COMPOSE" nr_compose:u32
+//!   nr_compose × { len:u8, sequence:[u32; len], result:u32 } ]
+//! ```
+//!
+//! The `COMPOSE` section is optional and defines X11-style compose sequences:
+//! after pressing the Compose key, typing one of the sequences produces its
+//! result character, e.g. `'` `e` → `é`.
+
 use alloc::string::ToString;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
 use binrw::{BinRead, NullString};
 use binrw::io::Cursor;
 use hashbrown::HashMap;
 
 use crate::driver::keyboard::{Deadkey, Key};
 
+/// The maximum number of characters in a compose sequence.
+pub const MAX_COMPOSE_LEN: usize = 4;
+
 pub struct KeymapState {
     keymap: Keymap,
     deadkey: Option<Deadkey>,
+    compose: Option<ArrayVec<char, MAX_COMPOSE_LEN>>,
 }
 
 pub struct Keymap {
     map: HashMap<Key, CharMatrix>,
+    compose: Vec<ComposeSequence>,
+}
+
+struct ComposeSequence {
+    sequence: ArrayVec<char, MAX_COMPOSE_LEN>,
+    result: char,
 }
 
 #[derive(Debug)]
@@ -34,9 +60,17 @@ impl KeymapState {
         Self {
             keymap,
             deadkey: None,
+            compose: None,
         }
     }
 
+    /// Start a new compose sequence, as if the Compose key was pressed; any
+    /// pending dead key or compose sequence is discarded.
+    pub fn start_compose(&mut self) {
+        self.deadkey = None;
+        self.compose = Some(ArrayVec::new());
+    }
+
     pub fn glyph(
         &mut self,
         key: Key,
@@ -45,6 +79,16 @@ impl KeymapState {
         shift: bool,
     ) -> Option<char> {
         let c = self.keymap.glyph(key, altgr, capslock, shift)?;
+        self.translate(c)
+    }
+
+    /// Feed the character `c`, as produced by a key, through the pending dead
+    /// key or compose sequence, if any; return the resulting character, or
+    /// `None` if `c` only started or continued a sequence.
+    pub fn translate(&mut self, c: char) -> Option<char> {
+        if self.compose.is_some() {
+            return self.compose(c);
+        }
 
         if let Some(deadkey) = <char as TryInto<Deadkey>>::try_into(c).ok() {
             if let Some(ref curr_deadkey) = self.deadkey {
@@ -61,15 +105,40 @@ impl KeymapState {
                 None
             }
         } else {
-            if let Some(ref deadkey) = self.deadkey {
-                let c = deadkey.apply(c);
-                self.deadkey = None;
-                c
+            if let Some(deadkey) = self.deadkey.take() {
+                // Space produces the accent on its own, and a character
+                // without an accented form is left unaccented rather than
+                // swallowed.
+                match c {
+                    ' ' => deadkey.as_standalone(),
+                    c => deadkey.apply(c).or(Some(c)),
+                }
             } else {
                 Some(c)
             }
         }
     }
+
+    fn compose(&mut self, c: char) -> Option<char> {
+        let sequence = self.compose.as_mut()?;
+
+        if sequence.try_push(c).is_err() {
+            self.compose = None;
+            return None;
+        }
+
+        match self.keymap.compose(sequence) {
+            ComposeMatch::Complete(c) => {
+                self.compose = None;
+                Some(c)
+            },
+            ComposeMatch::Partial => None,
+            ComposeMatch::None => {
+                self.compose = None;
+                None
+            },
+        }
+    }
 }
 
 impl Keymap {
@@ -97,8 +166,37 @@ impl Keymap {
             ]));
         }
 
+        let mut compose = Vec::new();
+
+        // The compose section is optional: older keymap files end right after
+        // the key mappings.
+        if (reader.position() as usize) < data.len() {
+            let header = ComposeHeader::read(&mut reader)
+                .map_err(|_| KeymapError::InvalidKeymapFile)?;
+
+            for _ in 0..header.nr_compose {
+                let entry = ComposeEntry::read(&mut reader)
+                    .map_err(|_| KeymapError::InvalidKeymapFile)?;
+
+                if entry.sequence.is_empty()
+                    || entry.sequence.len() > MAX_COMPOSE_LEN {
+                    return Err(KeymapError::InvalidKeymapFile);
+                }
+
+                compose.push(ComposeSequence {
+                    sequence: entry.sequence.into_iter()
+                        .map(char::from_u32)
+                        .collect::<Option<_>>()
+                        .ok_or(KeymapError::InvalidKeymapFile)?,
+                    result: char::from_u32(entry.result)
+                        .ok_or(KeymapError::InvalidKeymapFile)?,
+                });
+            }
+        }
+
         Ok(Self {
             map,
+            compose,
         })
     }
 
@@ -112,6 +210,30 @@ impl Keymap {
         self.map.get(&key)
             .and_then(|matrix| matrix.get(altgr, capslock, shift))
     }
+
+    fn compose(&self, sequence: &[char]) -> ComposeMatch {
+        let mut is_prefix = false;
+
+        for entry in &self.compose {
+            if entry.sequence.as_slice() == sequence {
+                return ComposeMatch::Complete(entry.result);
+            } else if entry.sequence.starts_with(sequence) {
+                is_prefix = true;
+            }
+        }
+
+        if is_prefix {
+            ComposeMatch::Partial
+        } else {
+            ComposeMatch::None
+        }
+    }
+}
+
+enum ComposeMatch {
+    Complete(char),
+    Partial,
+    None,
 }
 
 struct CharMatrix([Option<char>; 8]);
@@ -129,6 +251,21 @@ struct KeyMapping {
     matrix: [u32; 8],
 }
 
+#[derive(BinRead, Debug)]
+#[br(little, magic = b"COMPOSE")]
+struct ComposeHeader {
+    nr_compose: u32,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct ComposeEntry {
+    len: u8,
+    #[br(count = len)]
+    sequence: Vec<u32>,
+    result: u32,
+}
+
 impl CharMatrix {
     #[inline]
     pub fn get(
@@ -144,3 +281,102 @@ impl CharMatrix {
         self.0[index]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::keyboard::Key;
+    use crate::fs;
+    use crate::ui::keymap::{Keymap, KeymapState};
+
+    fn fr_keymap() -> KeymapState {
+        let data = fs::read("/keymaps/fr.keymap").unwrap();
+        KeymapState::new(Keymap::from_file(data).unwrap())
+    }
+
+    fn compose(state: &mut KeymapState, sequence: &str) -> Option<char> {
+        state.start_compose();
+        sequence.chars().map(|c| state.translate(c)).last().flatten()
+    }
+
+    fn type_keys(state: &mut KeymapState, keys: &[(Key, bool)])
+        -> Option<char> {
+        keys.iter()
+            .map(|&(key, altgr)| state.glyph(key, altgr, false, false))
+            .last()
+            .flatten()
+    }
+
+    #[test]
+    fn it_applies_dead_keys() {
+        let mut state = fr_keymap();
+
+        assert_eq!(type_keys(&mut state, &[(Key::LeftBracket, false),
+                                           (Key::Letter('E'), false)]),
+                   Some('ê'));
+        assert_eq!(type_keys(&mut state, &[(Key::LeftBracket, true),
+                                           (Key::Letter('N'), false)]),
+                   Some('ñ'));
+        assert_eq!(type_keys(&mut state, &[(Key::Digit(1), true),
+                                           (Key::Letter('E'), false)]),
+                   Some('ě'));
+    }
+
+    #[test]
+    fn it_types_standalone_accents() {
+        let mut state = fr_keymap();
+
+        state.glyph(Key::LeftBracket, false, false, false);
+        assert_eq!(state.translate(' '), Some('^'));
+        assert_eq!(type_keys(&mut state, &[(Key::LeftBracket, false),
+                                           (Key::LeftBracket, false)]),
+                   Some('^'));
+    }
+
+    #[test]
+    fn it_leaves_uncomposable_characters_unaccented() {
+        let mut state = fr_keymap();
+
+        assert_eq!(type_keys(&mut state, &[(Key::LeftBracket, false),
+                                           (Key::Letter('X'), false)]),
+                   Some('x'));
+        assert_eq!(state.glyph(Key::Letter('E'), false, false, false),
+                   Some('e'));
+    }
+
+    #[test]
+    fn it_composes_sequences() {
+        let mut state = fr_keymap();
+
+        assert_eq!(compose(&mut state, "'e"), Some('é'));
+        assert_eq!(compose(&mut state, "oe"), Some('œ'));
+        assert_eq!(compose(&mut state, "--"), None);
+        assert_eq!(state.translate('.'), Some('–'));
+        assert_eq!(state.translate('e'), Some('e'));
+    }
+
+    #[test]
+    fn it_drops_unknown_compose_sequences() {
+        let mut state = fr_keymap();
+
+        assert_eq!(compose(&mut state, "'q"), None);
+        assert_eq!(state.translate('e'), Some('e'));
+
+        // The Compose key discards a pending dead key.
+        state.glyph(Key::LeftBracket, false, false, false);
+        assert_eq!(compose(&mut state, "`a"), Some('à'));
+        assert_eq!(state.translate('e'), Some('e'));
+    }
+
+    #[test]
+    fn it_loads_keymaps_without_compose_section() {
+        let data = fs::read("/keymaps/us.keymap").unwrap();
+        let end = data.windows(7).position(|w| w == b"COMPOSE").unwrap();
+        let mut state = KeymapState::new(Keymap::from_file(&data[..end])
+            .unwrap());
+
+        assert_eq!(state.glyph(Key::Letter('E'), false, false, false),
+                   Some('e'));
+        assert_eq!(compose(&mut state, "'"), None);
+        assert_eq!(state.translate('e'), Some('e'));
+    }
+}
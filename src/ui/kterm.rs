@@ -8,16 +8,71 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
-use core::fmt::{Arguments, Write};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::{self, Arguments, Write};
+#[cfg(feature = "fb-terminal")]
+use core::time::Duration;
+#[cfg(feature = "fb-terminal")]
+use thiserror_no_std::Error;
 
-use crate::arch::VesaFramebuffer;
-use crate::logging::{Logger, Severity};
+use crate::arch::logging::LOGGER_SERIAL;
+use crate::driver::screen::{FramebufferScreen, TextScreen};
+use crate::driver::input::{self, InputEvent, Subscription};
+#[cfg(feature = "fb-terminal")]
+use crate::fs::{self, FsError};
+use crate::initcall;
+use crate::logging::{with_log_ring, Logger, Severity};
 use crate::sync::Spinlock;
+#[cfg(feature = "fb-terminal")]
+use crate::task::timer::Timer;
+#[cfg(feature = "fb-terminal")]
+use crate::ui::pxfont::{PxFont, PxFontError};
+#[cfg(feature = "fb-terminal")]
 use crate::ui::term::Terminal;
+use crate::ui::textterm::TextTerminal;
+use crate::ui::tty::Tty;
 
-pub static KERNEL_TERMINAL: Spinlock<Option<Terminal<VesaFramebuffer>>>
+/// The framebuffer of the kernel terminal: the firmware's, or one of a video
+/// driver once it switched the resolution.
+pub type KernelFramebuffer = Box<dyn FramebufferScreen + Send>;
+
+#[cfg(feature = "fb-terminal")]
+pub static KERNEL_TERMINAL: Spinlock<Option<Terminal<KernelFramebuffer>>>
+    = Spinlock::new(None);
+
+/// The text screen of the kernel terminal when the bootloader provided no
+/// linear framebuffer.
+pub type KernelTextScreen = Box<dyn TextScreen + Send>;
+
+/// The kernel terminal in text mode, set instead of `KERNEL_TERMINAL` when
+/// there is no framebuffer, or without the `fb-terminal` feature.
+pub static KERNEL_TEXT_TERMINAL: Spinlock<Option<TextTerminal<KernelTextScreen>>>
     = Spinlock::new(None);
 
+/// A second handle onto the kernel terminal's framebuffer, only ever used by
+/// the panic handler when `KERNEL_TERMINAL` can't be, see `ui::rawterm`. It
+/// follows the terminal onto the framebuffers of video drivers.
+#[cfg(feature = "fb-terminal")]
+pub static mut PANIC_FRAMEBUFFER: Option<KernelFramebuffer> = None;
+
+/// Likewise, a second handle onto the text screen of `KERNEL_TEXT_TERMINAL`.
+pub static mut PANIC_TEXT_SCREEN: Option<KernelTextScreen> = None;
+
+/// The kernel terminal's subscription to the input events, along with the TTY
+/// the typed characters go to.
+static KERNEL_TERMINAL_INPUT: Spinlock<Option<(Subscription, Arc<Tty>)>>
+    = Spinlock::new(None);
+
+/// Where the output of `print!()` is copied to besides the kernel terminal,
+/// e.g. a remote shell session.
+static OUTPUT_MIRROR: Spinlock<Option<fn(Arguments)>> = Spinlock::new(None);
+
+/// The period at which the cursor of the kernel terminal blinks.
+#[cfg(feature = "fb-terminal")]
+const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+
 pub struct TerminalLogger {
     serial: &'static mut (dyn Logger + Send),
 }
@@ -32,31 +87,271 @@ impl TerminalLogger {
 
 impl Logger for TerminalLogger {
     fn log(&mut self, severity: Severity, args: Arguments) {
-        self.serial.log(severity, args.clone());
-
-        let (color, severity_str) = match severity {
-            Severity::Debug => ("\x1b<fg=686868>", "debug"),
-            Severity::Info => ("\x1b<fg=b2b2b2>", "info"),
-            Severity::Notice => ("\x1b<fg=ffffff>", "notice"),
-            Severity::Warning => ("\x1b<fg=ffff54>", "warning"),
-            Severity::Error => ("\x1b<fg=b21818>", "error"),
-            Severity::Critical => ("\x1b<fg=ff0000>", "critic."),
-            Severity::Alert => ("\x1b<fg=ff0000>", "ALERT"),
-            Severity::Emergency => ("\x1b<fg=ff0000>", "EMERG."),
-        };
-        let mut kterm = KERNEL_TERMINAL.lock();
-        if let Some(ref mut kterm) = *kterm {
-            write!(kterm, "{}{:>7}: ", color, severity_str).unwrap();
-            kterm.write_fmt(args).unwrap();
-            write!(kterm, "\x1b<!fg>\n").unwrap();
+        self.serial.log(severity, args);
+
+        with_kernel_terminal(|kterm| print_log_line(kterm, severity, args));
+    }
+}
+
+fn print_log_line(kterm: &mut dyn Write, severity: Severity, args: Arguments) {
+    let (color, severity_str) = match severity {
+        Severity::Debug => ("\x1b<fg=@bright-black>", "debug"),
+        Severity::Info => ("\x1b<fg=@white>", "info"),
+        Severity::Notice => ("\x1b<fg=@bright-white>", "notice"),
+        Severity::Warning => ("\x1b<fg=@bright-yellow>", "warning"),
+        Severity::Error => ("\x1b<fg=@red>", "error"),
+        Severity::Critical => ("\x1b<fg=@bright-red>", "critic."),
+        Severity::Alert => ("\x1b<fg=@bright-red>", "ALERT"),
+        Severity::Emergency => ("\x1b<fg=@bright-red>", "EMERG."),
+    };
+
+    write!(kterm, "{}{:>7}: ", color, severity_str).unwrap();
+    kterm.write_fmt(args).unwrap();
+    write!(kterm, "\x1b<!fg>\n").unwrap();
+}
+
+/// Print onto the kernel terminal what was logged so far, as kept by the log
+/// ring; this is meant for when the terminal is created late in the boot.
+pub fn replay_log() {
+    let (text, wrapped) = with_log_ring(|ring| {
+        let (first, second) = ring.as_slices();
+        ([first, second].concat(), ring.is_wrapped())
+    });
+    let text = String::from_utf8_lossy(&text);
+    // The first line may have been partly overwritten.
+    let skip = if wrapped { 1 } else { 0 };
+
+    with_kernel_terminal(|kterm| {
+        for line in text.lines().skip(skip) {
+            let entry = line.split_once(": ").and_then(|(label, message)| {
+                Some((Severity::from_label(label)?, message))
+            });
+
+            match entry {
+                Some((severity, message)) => {
+                    print_log_line(kterm, severity, format_args!("{message}"));
+                },
+                // The continuation of a message spanning multiple lines.
+                None => writeln!(kterm, "{line}").unwrap(),
+            }
         }
+    });
+}
+
+/// A `fmt::Write` sink printing onto the kernel terminal, if any.
+pub struct KernelTerminalWriter;
+
+impl Write for KernelTerminalWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        with_kernel_terminal(|kterm| {
+            let _ = kterm.write_str(s);
+        });
+
+        Ok(())
     }
 }
 
+/// Run `f` on the kernel terminal, or on the text-mode one if there is no
+/// framebuffer; `f` is not run if there is no terminal at all, in which case
+/// `false` is returned.
+fn with_kernel_terminal(f: impl FnOnce(&mut dyn Write)) -> bool {
+    #[cfg(feature = "fb-terminal")]
+    if let Some(ref mut kterm) = *KERNEL_TERMINAL.lock() {
+        f(kterm);
+        return true;
+    }
+    if let Some(ref mut kterm) = *KERNEL_TEXT_TERMINAL.lock() {
+        f(kterm);
+        return true;
+    }
+
+    false
+}
+
+/// Whether the kernel terminal exists and is not being written to, so that the
+/// panic handler can print onto it.
+pub fn is_available() -> bool {
+    #[cfg(feature = "fb-terminal")]
+    if KERNEL_TERMINAL.is_locked() {
+        return false;
+    }
+    #[cfg(feature = "fb-terminal")]
+    if KERNEL_TERMINAL.lock().is_some() {
+        return true;
+    }
+
+    !KERNEL_TEXT_TERMINAL.is_locked() && KERNEL_TEXT_TERMINAL.lock().is_some()
+}
+
+/// Call `f` with the framebuffer of the kernel terminal; return `None` if it
+/// has none.
+#[cfg(feature = "fb-terminal")]
+pub fn with_framebuffer<R>(
+    f: impl FnOnce(&mut KernelFramebuffer) -> R,
+) -> Option<R> {
+    KERNEL_TERMINAL.lock().as_mut().map(|kterm| f(kterm.framebuffer_mut()))
+}
+
+/// Without the `fb-terminal` feature, the kernel terminal never has a
+/// framebuffer.
+#[cfg(not(feature = "fb-terminal"))]
+pub fn with_framebuffer<R>(
+    _f: impl FnOnce(&mut KernelFramebuffer) -> R,
+) -> Option<R> {
+    None
+}
+
+/// Move the kernel terminal, and the panic handler's handle, onto the
+/// framebuffer `fb`.
+#[cfg(feature = "fb-terminal")]
+pub fn set_framebuffer(fb: KernelFramebuffer) {
+    if let Some(ref mut kterm) = *KERNEL_TERMINAL.lock() {
+        // The previous framebuffer is only dropped by the terminal once the
+        // panic handler no longer refers to it.
+        unsafe { PANIC_FRAMEBUFFER = fb.panic_handle(); }
+        kterm.set_framebuffer(fb);
+    }
+}
+
+#[cfg(feature = "fb-terminal")]
+#[derive(Error, Debug)]
+pub enum FontLoadError {
+    #[error("couldn't read font file: {0}")]
+    Read(#[source] FsError),
+
+    #[error("invalid font file: {0}")]
+    Invalid(#[source] PxFontError),
+
+    #[error("the kernel terminal has no framebuffer")]
+    NoFramebuffer,
+}
+
+/// Switch the kernel terminal to the font in the file at `path`.
+#[cfg(feature = "fb-terminal")]
+pub fn load_font(path: &str) -> Result<(), FontLoadError> {
+    let data = fs::read(path).map_err(FontLoadError::Read)?;
+    let font = PxFont::from_data(data).map_err(FontLoadError::Invalid)?;
+
+    KERNEL_TERMINAL.lock()
+        .as_mut()
+        .ok_or(FontLoadError::NoFramebuffer)?
+        .set_font(font);
+
+    Ok(())
+}
+
+/// Print `text` as a banner on the kernel terminal, with the large size of its
+/// font; in text mode, it is printed as a regular line.
+pub fn print_banner(text: &str) {
+    #[cfg(feature = "fb-terminal")]
+    if let Some(ref mut kterm) = *KERNEL_TERMINAL.lock() {
+        return kterm.print_banner(text);
+    }
+    if let Some(ref mut kterm) = *KERNEL_TEXT_TERMINAL.lock() {
+        let _ = writeln!(kterm, "{text}");
+    }
+}
+
+/// Start receiving the input events on the kernel terminal.
+pub fn init_input() {
+    *KERNEL_TERMINAL_INPUT.lock() = Some((input::subscribe(),
+                                          Arc::new(Tty::new(write_tty))));
+}
+
+/// The TTY of the kernel terminal, `tty0`, once it receives input.
+pub fn tty() -> Option<Arc<Tty>> {
+    KERNEL_TERMINAL_INPUT.lock().as_ref().map(|(_, tty)| tty.clone())
+}
+
+fn write_tty(data: &[u8]) {
+    let _ = KernelTerminalWriter.write_str(&String::from_utf8_lossy(data));
+}
+
+/// Make the cursor of the kernel terminal blink, from now on; there is no
+/// cursor in text mode.
+#[cfg(feature = "fb-terminal")]
+pub fn start_cursor_blink() {
+    Timer::after(CURSOR_BLINK_PERIOD, blink_cursor);
+}
+
+fn start() {
+    init_input();
+    #[cfg(feature = "fb-terminal")]
+    start_cursor_blink();
+}
+
+initcall!(core, start, "Starting the terminal input...");
+
+#[cfg(feature = "fb-terminal")]
+fn blink_cursor() {
+    // Rather skip a blink than wait for another CPU writing to the terminal.
+    if let Some(mut kterm) = KERNEL_TERMINAL.try_lock() {
+        if let Some(kterm) = kterm.as_mut() {
+            kterm.blink_cursor();
+        }
+    }
+
+    Timer::after(CURSOR_BLINK_PERIOD, blink_cursor);
+}
+
+/// Process the input events received since the last call: typed characters
+/// go to the TTY of the kernel terminal. Form feed (Ctrl+L) clears the
+/// terminal, and mouse motions move its pointer.
+pub fn poll_input() {
+    let mut kterm_input = KERNEL_TERMINAL_INPUT.lock();
+    let Some((subscription, tty)) = kterm_input.as_mut() else {
+        return;
+    };
+
+    while let Some(event) = input::try_read_event(subscription) {
+        match event {
+            InputEvent::Char('\x0c') => clear(),
+            InputEvent::Char(c) => tty.input(c),
+            #[cfg(feature = "fb-terminal")]
+            InputEvent::Mouse(mouse) => {
+                if let Some(ref mut kterm) = *KERNEL_TERMINAL.lock() {
+                    kterm.move_pointer(mouse.dx, mouse.dy);
+                }
+            },
+            #[cfg(not(feature = "fb-terminal"))]
+            InputEvent::Mouse(_) => (),
+            InputEvent::Key { .. } => (),
+        }
+    }
+}
+
+/// Clear the kernel terminal, whichever it is.
+fn clear() {
+    #[cfg(feature = "fb-terminal")]
+    if let Some(ref mut kterm) = *KERNEL_TERMINAL.lock() {
+        return kterm.clear();
+    }
+    if let Some(ref mut kterm) = *KERNEL_TEXT_TERMINAL.lock() {
+        kterm.clear();
+    }
+}
+
+/// Copy the output of `print!()` to `mirror`, in addition to the kernel
+/// terminal; `None` to stop copying it.
+pub fn set_output_mirror(mirror: Option<fn(Arguments)>) {
+    *OUTPUT_MIRROR.lock() = mirror;
+}
+
 pub fn _print(args: Arguments) {
-    let mut kterm = KERNEL_TERMINAL.lock();
-    if let Some(ref mut kterm) = *kterm {
+    let printed = with_kernel_terminal(|kterm| {
         let _ = kterm.write_fmt(args);
+    });
+    // Without a terminal, e.g. when the framebuffer can't be used, the serial
+    // line is the console.
+    if !printed {
+        if let Some(serial) = unsafe { LOGGER_SERIAL.as_mut() } {
+            let _ = serial.write_fmt(args);
+        }
+    }
+
+    let mirror = *OUTPUT_MIRROR.lock();
+    if let Some(mirror) = mirror {
+        mirror(args);
     }
 }
 
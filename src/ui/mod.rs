@@ -8,7 +8,25 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+#[cfg(feature = "fb-terminal")]
 pub mod pxfont;
+pub mod font8x8;
+#[cfg(feature = "fb-terminal")]
 pub mod term;
+pub mod escape;
+pub mod theme;
 pub mod kterm;
+#[cfg(feature = "fb-terminal")]
+pub mod rawterm;
+pub mod textterm;
+pub mod unicode;
 pub mod keymap;
+pub mod accents;
+pub mod console;
+pub mod tty;
+pub mod shell;
+#[cfg(feature = "fb-terminal")]
+pub mod splash;
+pub mod script;
+#[cfg(feature = "fb-terminal")]
+pub mod pointer;
@@ -0,0 +1,65 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The mouse pointer's sprite: an arrow whose tip, the hotspot, is the
+//! top-left corner.
+
+use crate::driver::screen::Color;
+
+pub const WIDTH: usize = 11;
+pub const HEIGHT: usize = 17;
+
+const OUTLINE: Color = Color { r: 0, g: 0, b: 0 };
+const FILL: Color = Color { r: 255, g: 255, b: 255 };
+
+/// The sprite, one string per row: `#` is the outline, `.` the fill, and
+/// spaces are transparent.
+const SPRITE: [&str; HEIGHT] = [
+    "#          ",
+    "##         ",
+    "#.#        ",
+    "#..#       ",
+    "#...#      ",
+    "#....#     ",
+    "#.....#    ",
+    "#......#   ",
+    "#.......#  ",
+    "#........# ",
+    "#.....#####",
+    "#..#..#    ",
+    "#.# #..#   ",
+    "##  #..#   ",
+    "#    #..#  ",
+    "     #..#  ",
+    "      ##   ",
+];
+
+/// Iterate over the opaque pixels of the sprite, as `(x, y, color)` relative
+/// to the hotspot.
+pub fn pixels() -> impl Iterator<Item = (usize, usize, Color)> {
+    SPRITE.iter().enumerate().flat_map(|(y, row)| {
+        row.bytes().enumerate().filter_map(move |(x, px)| match px {
+            b'#' => Some((x, y, OUTLINE)),
+            b'.' => Some((x, y, FILL)),
+            _ => None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_row_has_the_sprite_width() {
+        assert!(SPRITE.iter().all(|row| row.len() == WIDTH));
+        assert!(pixels().all(|(x, y, _)| x < WIDTH && y < HEIGHT));
+    }
+}
@@ -8,6 +8,23 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+//! The PXFONT bitmap font format. A version 1 file holds a single size of
+//! monospace glyphs, each spanning a whole number of columns. Version 2 holds
+//! one or more sizes, the second one being a larger variant meant for
+//! headers; glyphs may come with their own advance width, and each size with
+//! kerning pairs, for text that is not laid out on the terminal's grid.
+//!
+//! Version 1: the `PXFONT` magic, the glyph width and height, then glyph blocks
+//! up to the end of the file. Version 2: the `PXFNT2` magic, flags and the
+//! number of sizes; then for each size, its glyph width and height, number of
+//! glyph blocks and of kerning pairs, the glyph blocks and the kerning pairs.
+//!
+//! A glyph block is the range of code points it covers and whether its glyphs
+//! are RGBA, followed by each glyph: its number of columns, its advance width
+//! in pixels if the font has `FLAG_ADVANCES` (0 for the default one), and its
+//! pixels, one byte of coverage each, or four for RGBA.
+
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::char::REPLACEMENT_CHARACTER;
 use core::convert::TryInto;
@@ -16,15 +33,26 @@ use binrw::io::{Cursor, Seek, SeekFrom};
 use hashbrown::HashMap;
 use thiserror_no_std::Error;
 
+use crate::ui::font8x8;
+
+/// Set in version 2 files whose glyphs have an advance width.
+const FLAG_ADVANCES: u8 = 0x01;
+
 pub struct PxFont {
     chars: HashMap<char, Glyph>,
     glyph_width: u8,
     glyph_height: u8,
+    /// The adjustment in pixels of the advance between two characters.
+    kerning: HashMap<(char, char), i8>,
+    /// The larger size of the font, if any.
+    large: Option<Box<PxFont>>,
 }
 
 pub struct Glyph {
     px: Vec<u8>,
     nr_cols: u8,
+    /// The advance width in pixels, if it differs from the glyph's width.
+    advance: Option<u8>,
     is_rgba: bool,
 }
 
@@ -42,6 +70,18 @@ pub enum PxFontError {
     #[error("invalid glyph {0:?}")]
     InvalidGlyph(char),
 
+    #[error("invalid font size header: {0}")]
+    InvalidSizeHeader(#[source] binrw::error::Error),
+
+    #[error("invalid kerning pair: {0}")]
+    InvalidKerningPair(#[source] binrw::error::Error),
+
+    #[error("invalid code point {0:#x} in kerning pair")]
+    InvalidKerningChar(u32),
+
+    #[error("the font has no size")]
+    NoSize,
+
     #[error("the replacement glyph '�' is missing")]
     MissingReplacementGlyph,
 }
@@ -53,6 +93,30 @@ struct FileHeader {
     height: u8,
 }
 
+#[derive(BinRead, Debug)]
+#[br(little, magic = b"PXFNT2")]
+struct FileHeaderV2 {
+    flags: u8,
+    nr_sizes: u8,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct SizeHeader {
+    width: u8,
+    height: u8,
+    nr_blocks: u16,
+    nr_kerning_pairs: u16,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct KerningPair {
+    left: u32,
+    right: u32,
+    adjust: i8,
+}
+
 #[derive(BinRead, Debug)]
 #[br(little)]
 struct GlyphBlock {
@@ -62,61 +126,98 @@ struct GlyphBlock {
 }
 
 impl PxFont {
+    /// Parse a PXFONT file of either version.
     pub fn from_data(data: &[u8]) -> Result<Self, PxFontError> {
         let mut reader = Cursor::new(data);
+        if data.starts_with(b"PXFNT2") {
+            return Self::from_data_v2(&mut reader);
+        }
+
         let header = FileHeader::read(&mut reader)
             .map_err(|e| PxFontError::InvalidHeader(e))?;
-        let mut chars = HashMap::new();
+        let chars = read_glyph_blocks(&mut reader, header.width, header.height,
+                                      false, None)?;
 
-        loop {
-            let block = GlyphBlock::read(&mut reader)
-                .map_err(|e| PxFontError::InvalidGlyphBlockHeader(e))?;
-            let is_rgba = block.rgba > 0;
-            let (start, end) = match (block.start.try_into(), block.end.try_into()) {
-                (Ok(start), Ok(end)) => (start, end),
-                _ => return Err(
-                    PxFontError::InvalidGlyphBlockRange(block.start, block.end)
-                ),
-            };
+        Self::new(chars, header.width, header.height, HashMap::new())
+    }
 
-            for c in start..=end {
-                let mut data = remaining(&reader);
-                let nr_cols = data[0];
-                data = &data[1..];
-
-                let glyph_size = match is_rgba {
-                    false => nr_cols as usize * header.width as usize * header.height as usize,
-                    true => nr_cols as usize * header.width as usize * header.height as usize * 4,
-                };
-
-                if data.len() < glyph_size {
-                    return Err(PxFontError::InvalidGlyph(c));
-                }
-                let glyph = Glyph {
-                    px: data[..glyph_size].to_vec(),
-                    nr_cols,
-                    is_rgba,
-                };
-                reader.seek(SeekFrom::Current(glyph_size as i64 + 1)).unwrap();
-                chars.insert(c, glyph);
-            }
+    fn from_data_v2(reader: &mut Cursor<&[u8]>) -> Result<Self, PxFontError> {
+        let header = FileHeaderV2::read(reader)
+            .map_err(|e| PxFontError::InvalidHeader(e))?;
+        let has_advances = header.flags & FLAG_ADVANCES != 0;
+        let mut sizes = Vec::new();
+
+        for _ in 0..header.nr_sizes {
+            let size = SizeHeader::read(reader)
+                .map_err(|e| PxFontError::InvalidSizeHeader(e))?;
+            let chars = read_glyph_blocks(reader, size.width, size.height,
+                                          has_advances,
+                                          Some(size.nr_blocks as usize))?;
 
-            if remaining(&reader).is_empty() {
-                break;
+            let mut kerning = HashMap::new();
+            for _ in 0..size.nr_kerning_pairs {
+                let pair = KerningPair::read(reader)
+                    .map_err(|e| PxFontError::InvalidKerningPair(e))?;
+                let char_at = |c| char::from_u32(c)
+                    .ok_or(PxFontError::InvalidKerningChar(c));
+                kerning.insert((char_at(pair.left)?, char_at(pair.right)?),
+                               pair.adjust);
             }
+
+            sizes.push(Self::new(chars, size.width, size.height, kerning)?);
         }
 
+        let mut sizes = sizes.into_iter();
+        let mut font = sizes.next().ok_or(PxFontError::NoSize)?;
+        font.large = sizes.next().map(Box::new);
+
+        Ok(font)
+    }
+
+    fn new(
+        chars: HashMap<char, Glyph>,
+        glyph_width: u8,
+        glyph_height: u8,
+        kerning: HashMap<(char, char), i8>,
+    ) -> Result<Self, PxFontError> {
         if !chars.contains_key(&REPLACEMENT_CHARACTER) {
             return Err(PxFontError::MissingReplacementGlyph);
         }
 
         Ok(Self {
             chars,
-            glyph_width: header.width,
-            glyph_height: header.height,
+            glyph_width,
+            glyph_height,
+            kerning,
+            large: None,
         })
     }
 
+    /// Build the minimal 8×8 font compiled into the kernel. It only contains
+    /// printable ASCII and the replacement glyph, and is meant as a fallback
+    /// when the primary font could not be loaded.
+    pub fn builtin() -> Self {
+        let mut chars = HashMap::new();
+        let first = font8x8::ASCII_FIRST as u32;
+
+        for (i, rows) in font8x8::ASCII_GLYPHS.iter().enumerate() {
+            let c = char::from_u32(first + i as u32).unwrap();
+            chars.insert(c, Glyph::from_bitmap(rows));
+        }
+        chars.insert(
+            REPLACEMENT_CHARACTER,
+            Glyph::from_bitmap(&font8x8::REPLACEMENT_GLYPH)
+        );
+
+        Self {
+            chars,
+            glyph_width: font8x8::GLYPH_WIDTH,
+            glyph_height: font8x8::GLYPH_HEIGHT,
+            kerning: HashMap::new(),
+            large: None,
+        }
+    }
+
     #[inline]
     pub fn get_glyph(&self, glyph: char) -> Option<&Glyph> {
         self.chars.get(&glyph)
@@ -136,9 +237,58 @@ impl PxFont {
     pub fn replacement_glyph(&self) -> &Glyph {
         &self.chars[&REPLACEMENT_CHARACTER]
     }
+
+    /// The larger size of the font, meant for headers, if it has one.
+    #[inline]
+    pub fn large(&self) -> Option<&PxFont> {
+        self.large.as_deref()
+    }
+
+    /// The horizontal distance in pixels from the origin of `c` to that of the
+    /// next character, kerning aside.
+    pub fn advance(&self, c: char) -> usize {
+        let glyph = self.get_glyph(c).unwrap_or(self.replacement_glyph());
+
+        glyph.advance.map_or(glyph.nr_columns() * self.glyph_width as usize,
+                             |advance| advance as usize)
+    }
+
+    /// The adjustment in pixels of the advance from `left` to `right`.
+    pub fn kerning(&self, left: char, right: char) -> isize {
+        self.kerning.get(&(left, right)).map_or(0, |&adjust| adjust as isize)
+    }
+
+    /// The width in pixels of `text` laid out with the advances and kerning
+    /// of the font.
+    pub fn text_width(&self, text: &str) -> usize {
+        let mut prev = None;
+
+        text.chars().fold(0, |width, c| {
+            let kerning = prev.map_or(0, |prev| self.kerning(prev, c));
+            prev = Some(c);
+            (width + self.advance(c)).saturating_add_signed(kerning)
+        })
+    }
 }
 
 impl Glyph {
+    /// Make a single-column glyph from a 1-bit bitmap, one byte per row, least
+    /// significant bit first.
+    fn from_bitmap(rows: &[u8]) -> Self {
+        let px = rows.iter()
+            .flat_map(|&row| (0..8).map(move |bit| {
+                if row & (1 << bit) != 0 { 0xff } else { 0x00 }
+            }))
+            .collect();
+
+        Self {
+            px,
+            nr_cols: 1,
+            advance: None,
+            is_rgba: false,
+        }
+    }
+
     #[inline]
     pub fn data(&self) -> &[u8] {
         &self.px
@@ -155,6 +305,122 @@ impl Glyph {
     }
 }
 
+/// Read the glyph blocks of a font size whose glyphs are `width` × `height`
+/// pixels per column: `nr_blocks` of them, or up to the end of the file.
+fn read_glyph_blocks(
+    reader: &mut Cursor<&[u8]>,
+    width: u8,
+    height: u8,
+    has_advances: bool,
+    nr_blocks: Option<usize>,
+) -> Result<HashMap<char, Glyph>, PxFontError> {
+    let mut chars = HashMap::new();
+    let mut block_idx = 0;
+
+    loop {
+        if nr_blocks == Some(block_idx) {
+            break;
+        }
+        block_idx += 1;
+
+        let block = GlyphBlock::read(reader)
+            .map_err(|e| PxFontError::InvalidGlyphBlockHeader(e))?;
+        let is_rgba = block.rgba > 0;
+        let (start, end) = match (block.start.try_into(), block.end.try_into()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Err(
+                PxFontError::InvalidGlyphBlockRange(block.start, block.end)
+            ),
+        };
+
+        for c in start..=end {
+            let header_size = if has_advances { 2 } else { 1 };
+            let data = remaining(reader);
+            if data.len() < header_size {
+                return Err(PxFontError::InvalidGlyph(c));
+            }
+            let nr_cols = data[0];
+            let advance = match has_advances {
+                true => Some(data[1]).filter(|&advance| advance != 0),
+                false => None,
+            };
+            let data = &data[header_size..];
+
+            let mut glyph_size = nr_cols as usize * width as usize
+                * height as usize;
+            if is_rgba {
+                glyph_size *= 4;
+            }
+
+            if data.len() < glyph_size {
+                return Err(PxFontError::InvalidGlyph(c));
+            }
+            let glyph = Glyph {
+                px: data[..glyph_size].to_vec(),
+                nr_cols,
+                advance,
+                is_rgba,
+            };
+            reader.seek(SeekFrom::Current((glyph_size + header_size) as i64))
+                .unwrap();
+            chars.insert(c, glyph);
+        }
+
+        if nr_blocks.is_none() && remaining(reader).is_empty() {
+            break;
+        }
+    }
+
+    Ok(chars)
+}
+
 fn remaining<'a>(cursor: &Cursor<&'a [u8]>) -> &'a [u8] {
     &cursor.get_ref()[(cursor.position() as usize)..]
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use super::*;
+
+    /// A glyph block of 2×1 single-column glyphs for `start..=end`, with the
+    /// advance widths `advances`.
+    fn block(start: char, end: char, advances: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend((start as u32).to_le_bytes());
+        data.extend((end as u32).to_le_bytes());
+        data.push(0);
+        for &advance in advances {
+            data.extend([1, advance, 0xff, 0x00]);
+        }
+        data
+    }
+
+    #[test]
+    fn it_parses_version_2_fonts() {
+        let mut data = vec![];
+        data.extend(b"PXFNT2");
+        data.extend([FLAG_ADVANCES, 2]);
+
+        data.extend([2, 1, 2, 0, 1, 0]);
+        data.extend(block('A', 'B', &[0, 3]));
+        data.extend(block(REPLACEMENT_CHARACTER, REPLACEMENT_CHARACTER, &[0]));
+        data.extend(('A' as u32).to_le_bytes());
+        data.extend(('B' as u32).to_le_bytes());
+        data.push(-1i8 as u8);
+
+        data.extend([2, 1, 1, 0, 0, 0]);
+        data.extend(block(REPLACEMENT_CHARACTER, REPLACEMENT_CHARACTER, &[5]));
+
+        let font = PxFont::from_data(&data).unwrap();
+        assert_eq!((font.glyph_width(), font.glyph_height()), (2, 1));
+        assert_eq!(font.advance('A'), 2);
+        assert_eq!(font.advance('B'), 3);
+        assert_eq!(font.kerning('A', 'B'), -1);
+        assert_eq!(font.text_width("AB"), 4);
+
+        let large = font.large().unwrap();
+        assert_eq!(large.advance('A'), 5);
+        assert!(large.large().is_none());
+    }
+}
@@ -0,0 +1,104 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A bare-bones terminal drawing directly onto a framebuffer with the built-in
+//! 8×8 font. Unlike `Terminal`, it never allocates memory nor keeps any
+//! history: it is meant to report panics happening when the regular terminal
+//! can't be used, e.g. early during the boot or within the kernel allocator.
+
+use core::fmt;
+
+use crate::driver::screen::{Color, FramebufferScreen};
+use crate::ui::font8x8;
+
+const TAB_WIDTH: usize = 8;
+
+pub struct RawTerminal<'a, Fb: FramebufferScreen> {
+    fb: &'a mut Fb,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: Color,
+    bg: Color,
+}
+
+impl<'a, Fb: FramebufferScreen> RawTerminal<'a, Fb> {
+    pub fn new(fb: &'a mut Fb) -> Self {
+        let (width, height) = fb.dimensions();
+
+        Self {
+            fb,
+            cols: width / font8x8::GLYPH_WIDTH as usize,
+            rows: height / font8x8::GLYPH_HEIGHT as usize,
+            col: 0,
+            row: 0,
+            fg: Color { r: 0xff, g: 0xff, b: 0xff },
+            bg: Color { r: 0x00, g: 0x00, b: 0x00 },
+        }
+    }
+
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Fill the whole screen with the current background color and move the
+    /// cursor to the top-left corner.
+    pub fn clear(&mut self) {
+        let (width, height) = self.fb.dimensions();
+        self.fb.fill_rect(0, 0, width, height, self.bg);
+
+        self.col = 0;
+        self.row = 0;
+    }
+
+    pub fn put_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\t' => {
+                let spaces = TAB_WIDTH - self.col % TAB_WIDTH;
+                for _ in 0..spaces {
+                    self.put_char(' ');
+                }
+            },
+            c => {
+                if self.col >= self.cols {
+                    self.new_line();
+                }
+                self.draw_glyph(c);
+                self.col += 1;
+            },
+        }
+    }
+
+    /// There is no scrolling, which would require reading the framebuffer
+    /// back: once the last row is reached, we wrap around to the first one.
+    fn new_line(&mut self) {
+        self.col = 0;
+        self.row = (self.row + 1) % self.rows.max(1);
+    }
+
+    fn draw_glyph(&mut self, c: char) {
+        self.fb.draw_bitmap(self.col * font8x8::GLYPH_WIDTH as usize,
+                            self.row * font8x8::GLYPH_HEIGHT as usize,
+                            font8x8::glyph(c), 1, self.fg, self.bg);
+    }
+}
+
+impl<'a, Fb: FramebufferScreen> fmt::Write for RawTerminal<'a, Fb> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.put_char(c);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Kernel shell scripts: files of command lines run in sequence, such as the
+//! startup script `/etc/kshellrc` run at boot before the interactive shell.
+//!
+//! Any command line, typed or read from a script, can reference variables
+//! defined with the `set` command as `$NAME` or `${NAME}`, `$?` being the
+//! status of the last command (0 on success, 1 on failure); `a && b` and
+//! `a || b` run `b` only if `a` succeeded, respectively failed. Scripts can
+//! also contain comments and conditional blocks, which can be nested:
+//!
+//! ```text
+//! # A comment.
+//! if <command line>
+//!     <run if the command succeeded>
+//! else
+//!     <run otherwise>
+//! end
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+
+use crate::fs;
+use crate::println;
+use crate::sync::Spinlock;
+use crate::ui::shell::{self, Status};
+
+pub const STARTUP_SCRIPT: &str = "/etc/kshellrc";
+
+const MAX_NESTED_BLOCKS: usize = 8;
+
+/// The maximum number of scripts running at once, through `source` commands.
+const MAX_SOURCE_DEPTH: usize = 4;
+
+static VARIABLES: Spinlock<BTreeMap<String, String>>
+    = Spinlock::new(BTreeMap::new());
+
+static SOURCE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// How a command of a `&&` or `||` chain depends on the previous one.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Chain {
+    Always,
+    IfSuccess,
+    IfFailure,
+}
+
+struct Block {
+    /// Whether the lines around the block are being run.
+    parent_active: bool,
+    /// Whether the condition of the `if` succeeded.
+    taken: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn is_active(&self) -> bool {
+        self.parent_active && self.taken != self.in_else
+    }
+}
+
+pub fn var(name: &str) -> Option<String> {
+    VARIABLES.lock().get(name).cloned()
+}
+
+pub fn set_var(name: &str, value: &str) {
+    VARIABLES.lock().insert(name.to_string(), value.to_string());
+}
+
+pub fn unset_var(name: &str) {
+    VARIABLES.lock().remove(name);
+}
+
+pub fn vars() -> Vec<(String, String)> {
+    VARIABLES.lock()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Run the startup script, if there is one.
+pub fn run_startup() {
+    if fs::read(STARTUP_SCRIPT).is_ok() {
+        run_file(STARTUP_SCRIPT);
+    }
+}
+
+/// Run the script at `path`; its status is the one of its last command.
+pub fn run_file(path: &str) -> Status {
+    let text = match fs::read(path) {
+        Ok(data) => match core::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => {
+                println!("{path}: not a text file");
+                return Status::Failure;
+            },
+        },
+        Err(e) => {
+            println!("{path}: {e}");
+            return Status::Failure;
+        },
+    };
+
+    if SOURCE_DEPTH.fetch_add(1, Ordering::SeqCst) >= MAX_SOURCE_DEPTH {
+        SOURCE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+        println!("{path}: too many nested scripts");
+        return Status::Failure;
+    }
+
+    let status = run(path, text);
+    SOURCE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+
+    status
+}
+
+/// Run the script `text`, `name` being used in error messages.
+pub fn run(name: &str, text: &str) -> Status {
+    let mut blocks = ArrayVec::<Block, MAX_NESTED_BLOCKS>::new();
+    let mut status = Status::Success;
+
+    for (line_i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let line_nr = line_i + 1;
+        let active = blocks.last().is_none_or(Block::is_active);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(char::is_whitespace)
+            .map_or((line, ""), |(keyword, rest)| (keyword, rest.trim_start()));
+
+        match keyword {
+            "if" => {
+                let taken = active && shell::execute(rest) == Status::Success;
+                let block = Block { parent_active: active, taken, in_else: false };
+                if blocks.try_push(block).is_err() {
+                    println!("{name}:{line_nr}: too many nested blocks");
+                    return Status::Failure;
+                }
+            },
+            "else" => match blocks.last_mut() {
+                Some(block) if !block.in_else => block.in_else = true,
+                _ => {
+                    println!("{name}:{line_nr}: 'else' without 'if'");
+                    return Status::Failure;
+                },
+            },
+            "end" => if blocks.pop().is_none() {
+                println!("{name}:{line_nr}: 'end' without 'if'");
+                return Status::Failure;
+            },
+            _ if active => status = shell::execute(line),
+            _ => (),
+        }
+    }
+
+    if !blocks.is_empty() {
+        println!("{name}: missing 'end'");
+        return Status::Failure;
+    }
+
+    status
+}
+
+/// Replace the variable references in `line` with their value, as returned by
+/// `lookup`; undefined variables expand to nothing.
+pub fn expand(line: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.split_once('}') {
+                Some(split) => split,
+                None => ("", rest),
+            }
+        } else if let Some(after) = rest.strip_prefix('?') {
+            ("?", after)
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            rest.split_at(end)
+        };
+
+        if name.is_empty() {
+            out.push('$');
+        } else if let Some(value) = lookup(name) {
+            out.push_str(&value);
+        }
+        rest = after;
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Split a command line into the commands of its `&&` and `||` chain.
+pub fn split_chain(line: &str) -> impl Iterator<Item = (Chain, &str)> {
+    let mut rest = Some(line);
+    let mut chain = Chain::Always;
+
+    core::iter::from_fn(move || {
+        let line = rest?;
+        let this_chain = chain;
+        let next_op = [("&&", Chain::IfSuccess), ("||", Chain::IfFailure)]
+            .into_iter()
+            .filter_map(|(op, op_chain)| Some((line.find(op)?, op_chain)))
+            .min_by_key(|&(pos, _)| pos);
+
+        match next_op {
+            Some((pos, op_chain)) => {
+                rest = Some(&line[pos + 2..]);
+                chain = op_chain;
+                Some((this_chain, line[..pos].trim()))
+            },
+            None => {
+                rest = None;
+                Some((this_chain, line.trim()))
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+    use crate::ui::script::{expand, run, set_var, split_chain, var, Chain};
+    use crate::ui::shell::Status;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "kbd" => Some("fr".to_string()),
+            "?" => Some("1".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn it_expands_variables() {
+        assert_eq!(expand("keymap $kbd", lookup), "keymap fr");
+        assert_eq!(expand("echo ${kbd}.keymap $?", lookup), "echo fr.keymap 1");
+        assert_eq!(expand("echo [$nope]", lookup), "echo []");
+        assert_eq!(expand("echo $ ${kbd", lookup), "echo $ ${kbd");
+    }
+
+    #[test]
+    fn it_splits_chains() {
+        let chain: Vec<_> = split_chain("a 1 && b || c").collect();
+        assert_eq!(chain, [
+            (Chain::Always, "a 1"),
+            (Chain::IfSuccess, "b"),
+            (Chain::IfFailure, "c"),
+        ]);
+        assert_eq!(split_chain("").collect::<Vec<_>>(), [(Chain::Always, "")]);
+    }
+
+    #[test]
+    fn it_runs_conditional_blocks() {
+        let status = run("test", "\
+            # A comment.
+            if nosuchcmd
+                set run_branch taken
+            else
+                set run_branch not_taken
+                if echo
+                    set run_nested yes
+                end
+            end
+            set run_status $?
+        ");
+
+        assert_eq!(status, Status::Success);
+        assert_eq!(var("run_branch").as_deref(), Some("not_taken"));
+        assert_eq!(var("run_nested").as_deref(), Some("yes"));
+        assert_eq!(var("run_status").as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn it_rejects_unbalanced_blocks() {
+        assert_eq!(run("test", "end"), Status::Failure);
+        assert_eq!(run("test", "if echo\nelse\nelse\nend"), Status::Failure);
+        assert_eq!(run("test", "if echo\necho"), Status::Failure);
+    }
+
+    #[test]
+    fn it_expands_each_command_of_a_chain_when_it_runs() {
+        run("test", "nosuchcmd || set chain_failed $?");
+        assert_eq!(var("chain_failed").as_deref(), Some("1"));
+        run("test", "nosuchcmd || echo && set chain_succeeded $?");
+        assert_eq!(var("chain_succeeded").as_deref(), Some("0"));
+
+        set_var("chain_value", "x && set chain_injected 1");
+        assert_eq!(run("test", "echo $chain_value"), Status::Success);
+        assert_eq!(var("chain_injected"), None);
+    }
+}
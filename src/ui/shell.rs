@@ -0,0 +1,1117 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The kernel shell: a minimal command interpreter reading its lines from the
+//! console input queue, i.e. from the keyboard, the serial line or a remote
+//! session alike.
+
+#[cfg(feature = "fb-terminal")]
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "fb-terminal")]
+use core::fmt::Write;
+use core::ptr;
+use core::time::Duration;
+use arrayvec::ArrayVec;
+
+use crate::{arch, fs, misc, println, print, trace};
+use crate::arch::logging::LOGGER_SERIAL;
+use crate::arch::mem::{page_permissions, PAGE_SIZE};
+use crate::arch::time::{timestamp, timestamp_frequency};
+use crate::driver::{keyboard, pci};
+#[cfg(feature = "fb-terminal")]
+use crate::driver::virtio;
+use crate::fs::devfs::DeviceKind;
+use crate::mem;
+use crate::mem::kalloc::tracker;
+use crate::mem::{frame, numa, paging, protect, CacheMode, MmioRegion, PAddr,
+                 VAddr};
+use crate::mem::resource::{self, ResourceKind};
+use crate::misc::BinSize;
+#[cfg(feature = "net")]
+use crate::net::kshell;
+use crate::task::{idle, process, timer};
+#[cfg(feature = "profiler")]
+use crate::profile;
+use crate::ui::console::{self, ConsoleEvent};
+use crate::ui::kterm;
+#[cfg(feature = "fb-terminal")]
+use crate::ui::kterm::KERNEL_TERMINAL;
+#[cfg(feature = "fb-terminal")]
+use crate::ui::pxfont::PxFont;
+use crate::ui::script::{self, Chain};
+#[cfg(feature = "fb-terminal")]
+use crate::ui::theme::{Theme, THEMES};
+
+pub const PROMPT: &str = "> ";
+const MAX_ARGS: usize = 16;
+/// The number of bytes `dump` shows by default, and at most.
+const DEFAULT_DUMP_BSIZE: usize = 256;
+const MAX_DUMP_BSIZE: usize = 64 * 1024;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(args: &[&str]) -> Status,
+}
+
+/// The outcome of a command, which `&&`, `||` and script conditions act upon.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl Status {
+    /// The value of the `$?` variable.
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Success => 0,
+            Status::Failure => 1,
+        }
+    }
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "cat",
+        usage: "cat PATH...",
+        help: "print the files, of the initfs or under /proc",
+        run: cmd_cat,
+    },
+    Command {
+        name: "dump",
+        usage: "dump [-p] ADDR [LEN]",
+        help: "hexdump LEN bytes of memory, 256 by default; -p: physical",
+        run: cmd_dump,
+    },
+    Command {
+        name: "echo",
+        usage: "echo [WORD...]",
+        help: "print the words, separated by spaces",
+        run: cmd_echo,
+    },
+    #[cfg(feature = "fb-terminal")]
+    Command {
+        name: "font",
+        usage: "font [PATH]",
+        help: "show the terminal font's size, or load the font at PATH",
+        run: cmd_font,
+    },
+    Command {
+        name: "halt",
+        usage: "halt",
+        help: "power the machine off",
+        run: cmd_halt,
+    },
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list the available commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "iomem",
+        usage: "iomem",
+        help: "show the physical address space map",
+        run: cmd_iomem,
+    },
+    Command {
+        name: "keymap",
+        usage: "keymap [reload | NAME | PATH]",
+        help: "reload the current keymap, or load another one",
+        run: cmd_keymap,
+    },
+    Command {
+        name: "loadkeys",
+        usage: "loadkeys [NAME]",
+        help: "switch to the keymap NAME, or list the available ones",
+        run: cmd_loadkeys,
+    },
+    Command {
+        name: "lsdev",
+        usage: "lsdev",
+        help: "list the device files of /dev",
+        run: cmd_lsdev,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci",
+        help: "list the PCI devices and the drivers claiming them",
+        run: cmd_lspci,
+    },
+    Command {
+        name: "lsports",
+        usage: "lsports",
+        help: "list the I/O ports claimed by drivers",
+        run: cmd_lsports,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        help: "show the physical memory usage",
+        run: cmd_meminfo,
+    },
+    Command {
+        name: "memleak",
+        usage: "memleak",
+        help: "list the suspected kernel memory leaks",
+        run: cmd_memleak,
+    },
+    #[cfg(feature = "fb-terminal")]
+    Command {
+        name: "mode",
+        usage: "mode [WIDTHxHEIGHT]",
+        help: "show the video mode, or switch resolution with virtio-gpu",
+        run: cmd_mode,
+    },
+    Command {
+        name: "peek",
+        usage: "peek [-p] ADDR [8|16|32|64]",
+        help: "read a value of memory, of 32 bits by default; -p: physical",
+        run: cmd_peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke [-p] ADDR VALUE [8|16|32|64]",
+        help: "write a value to memory, of 32 bits by default; -p: physical",
+        run: cmd_poke,
+    },
+    #[cfg(feature = "profiler")]
+    Command {
+        name: "profile",
+        usage: "profile [on | off | clear | report [N]]",
+        help: "control profiling, or show the N most sampled functions",
+        run: cmd_profile,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        help: "list the processes and kernel threads",
+        run: cmd_ps,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "reset the machine",
+        run: cmd_reboot,
+    },
+    Command {
+        name: "set",
+        usage: "set [NAME [VALUE...]]",
+        help: "list the variables, or set or unset one",
+        run: cmd_set,
+    },
+    Command {
+        name: "source",
+        usage: "source PATH",
+        help: "run the script at PATH",
+        run: cmd_source,
+    },
+    #[cfg(feature = "fb-terminal")]
+    Command {
+        name: "theme",
+        usage: "theme [NAME]",
+        help: "show or change the terminal color theme",
+        run: cmd_theme,
+    },
+    Command {
+        name: "top",
+        usage: "top [SECONDS]",
+        help: "show the CPU usage of each task over SECONDS, 1 by default",
+        run: cmd_top,
+    },
+    Command {
+        name: "trace",
+        usage: "trace [on | off | clear | dump [raw]]",
+        help: "control tracing, or dump the trace events onto the serial line",
+        run: cmd_trace,
+    },
+    Command {
+        name: "vmaudit",
+        usage: "vmaudit",
+        help: "list the writable and executable kernel mappings",
+        run: cmd_vmaudit,
+    },
+    Command {
+        name: "vmmap",
+        usage: "vmmap [START END]",
+        help: "show the mapped virtual memory regions",
+        run: cmd_vmmap,
+    },
+];
+
+/// Run the shell forever, executing the commands as their lines are entered.
+pub fn run() -> ! {
+    print!("{PROMPT}");
+
+    loop {
+        kterm::poll_input();
+        #[cfg(feature = "net")]
+        kshell::poll();
+        while let Some(event) = console::pop_event() {
+            match event {
+                ConsoleEvent::Line(line) => { execute(&line); },
+                ConsoleEvent::Interrupt => (),
+            }
+            print!("{PROMPT}");
+        }
+
+        mem::log_stats_if_due();
+        frame::prezero_frames();
+        idle::idle();
+    }
+}
+
+/// Execute a command line after expanding its variables, running each command
+/// of its `&&` and `||` chain depending on the status of the previous one.
+pub fn execute(line: &str) -> Status {
+    let mut status = Status::Success;
+
+    for (chain, command) in script::split_chain(line) {
+        let should_run = match chain {
+            Chain::Always => true,
+            Chain::IfSuccess => status == Status::Success,
+            Chain::IfFailure => status == Status::Failure,
+        };
+
+        // Each command is expanded just before it runs: `$?` is then the
+        // status of the previous command of the chain, and the value of a
+        // variable can't add operators to the chain.
+        if should_run {
+            status = execute_command(&script::expand(command, script::var));
+            script::set_var("?", &format!("{}", status.code()));
+        }
+    }
+
+    status
+}
+
+/// Execute a single command; words are separated by whitespaces.
+fn execute_command(command: &str) -> Status {
+    let mut words = command.split_whitespace();
+    let Some(name) = words.next() else {
+        return Status::Success;
+    };
+
+    let mut args = ArrayVec::<&str, MAX_ARGS>::new();
+    for word in words {
+        if args.try_push(word).is_err() {
+            println!("{name}: too many arguments");
+            return Status::Failure;
+        }
+    }
+
+    match COMMANDS.iter().find(|cmd| cmd.name == name) {
+        Some(cmd) => (cmd.run)(&args),
+        None => {
+            println!("{name}: unknown command; type 'help' for a list");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_cat(args: &[&str]) -> Status {
+    if args.is_empty() {
+        println!("usage: cat PATH...");
+        return Status::Failure;
+    }
+
+    let mut status = Status::Success;
+    for path in args {
+        let content = if path.starts_with(fs::procfs::MOUNT_POINT) {
+            fs::procfs::read(path)
+        } else {
+            fs::read(path).map(String::from_utf8_lossy).map(Into::into)
+        };
+
+        match content {
+            Ok(content) => print!("{content}"),
+            Err(e) => {
+                println!("cat: {path}: {e}");
+                status = Status::Failure;
+            },
+        }
+    }
+
+    status
+}
+
+fn cmd_dump(args: &[&str]) -> Status {
+    let (physical, args) = split_physical_flag(args);
+    let (addr, len) = match args {
+        [addr] => (parse_hex(addr), Some(DEFAULT_DUMP_BSIZE)),
+        [addr, len] => (parse_hex(addr), parse_len(len)),
+        _ => {
+            println!("usage: dump [-p] ADDR [LEN]");
+            return Status::Failure;
+        },
+    };
+    let Some(addr) = addr else {
+        println!("dump: invalid address {}", args[0]);
+        return Status::Failure;
+    };
+    let Some(len) = len.filter(|&len| len > 0 && len <= MAX_DUMP_BSIZE) else {
+        println!("dump: invalid length {}, at most {MAX_DUMP_BSIZE} bytes",
+                 args[1]);
+        return Status::Failure;
+    };
+
+    let window = match MemWindow::open(addr, len, physical, false) {
+        Ok(window) => window,
+        Err(e) => {
+            println!("dump: {e}");
+            return Status::Failure;
+        },
+    };
+    let bytes: Vec<u8> = (0..len)
+        .map(|offset| window.read(offset, 1) as u8)
+        .collect();
+
+    print!("{}", misc::hexdump(addr, &bytes));
+    Status::Success
+}
+
+fn cmd_echo(args: &[&str]) -> Status {
+    for (i, word) in args.iter().enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{word}");
+    }
+    println!();
+
+    Status::Success
+}
+
+#[cfg(feature = "fb-terminal")]
+fn cmd_font(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            let sizes = KERNEL_TERMINAL.lock().as_ref().map(|kterm| {
+                let font = kterm.font();
+                let size = |font: &PxFont| (font.glyph_width(), font.glyph_height());
+                (size(font), font.large().map(size))
+            });
+            let Some(((width, height), large)) = sizes else {
+                println!("font: the kernel terminal has no framebuffer");
+                return Status::Failure;
+            };
+
+            print!("{width}x{height}");
+            if let Some((width, height)) = large {
+                print!(", large {width}x{height}");
+            }
+            println!();
+            Status::Success
+        },
+        [path] => match kterm::load_font(path) {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                println!("font: {path}: {e}");
+                Status::Failure
+            },
+        },
+        _ => {
+            println!("usage: font [PATH]");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_halt(_args: &[&str]) -> Status {
+    arch::power::shutdown();
+}
+
+fn cmd_help(_args: &[&str]) -> Status {
+    for cmd in COMMANDS {
+        println!("{:<32} {}", cmd.usage, cmd.help);
+    }
+
+    Status::Success
+}
+
+fn cmd_iomem(_args: &[&str]) -> Status {
+    let resources = resource::resources();
+
+    for res in &resources {
+        let nested = res.kind == ResourceKind::Device
+            && resources.iter().any(|parent| {
+                parent.kind != ResourceKind::Device && parent.contains(res)
+            });
+        let indent = if nested { "  " } else { "" };
+
+        println!("{indent}{:012x}-{:012x} : {}",
+                 res.start.0, res.end.0 - 1, res.name);
+    }
+
+    Status::Success
+}
+
+fn cmd_keymap(args: &[&str]) -> Status {
+    let result = match args {
+        [] | ["reload"] => keyboard::reload_keymap(),
+        [path] if path.starts_with('/') => keyboard::load_keymap(path),
+        [name] => keyboard::load_keymap_by_name(name),
+        _ => {
+            println!("usage: keymap [reload | NAME | PATH]");
+            return Status::Failure;
+        },
+    };
+
+    if let Err(e) = result {
+        println!("keymap: {e}");
+        return Status::Failure;
+    }
+
+    Status::Success
+}
+
+fn cmd_loadkeys(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            let current = keyboard::keymap_path();
+            for name in keyboard::available_keymaps() {
+                let path = format!("{}{name}.keymap", keyboard::KEYMAP_DIR);
+                let active = current.as_deref() == Some(path.as_str());
+                println!("{} {name}", if active { '*' } else { ' ' });
+            }
+            Status::Success
+        },
+        [name] => match keyboard::load_keymap_by_name(name) {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                println!("loadkeys: {name}: {e}");
+                Status::Failure
+            },
+        },
+        _ => {
+            println!("usage: loadkeys [NAME]");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_lsdev(_args: &[&str]) -> Status {
+    for (name, kind) in fs::devfs::devices() {
+        let kind = match kind {
+            DeviceKind::Char => "char",
+            DeviceKind::Block => "block",
+        };
+        println!("{kind:<5} {}/{name}", fs::devfs::MOUNT_POINT);
+    }
+
+    Status::Success
+}
+
+fn cmd_lspci(_args: &[&str]) -> Status {
+    for dev in pci::devices() {
+        println!("{} {:04x}:{:04x} class {:02x}{:02x}{:02x} : {}",
+                 dev.addr, dev.vendor_id, dev.device_id,
+                 dev.class, dev.subclass, dev.prog_if,
+                 dev.owner().unwrap_or("-"));
+    }
+
+    Status::Success
+}
+
+fn cmd_lsports(_args: &[&str]) -> Status {
+    for claim in arch::ioport::claims() {
+        let end = claim.base as u32 + claim.len as u32 - 1;
+        println!("{:04x}-{end:04x} : {}", claim.base, claim.owner);
+    }
+
+    Status::Success
+}
+
+fn cmd_meminfo(_args: &[&str]) -> Status {
+    let Some(stats) = mem::stats() else {
+        println!("meminfo: no frame allocator configured");
+        return Status::Failure;
+    };
+
+    let rows = [
+        ("total", stats.total),
+        ("free", stats.free),
+        ("  zeroed", stats.zeroed),
+        ("allocated", stats.allocated),
+        ("reserved", stats.reserved()),
+        ("  claimed", stats.claimed_reserved),
+        ("  unclaimed", stats.unclaimed_reserved),
+        ("unusable", stats.unusable),
+    ];
+    for (name, nr_frames) in rows {
+        println!("{name:<12} {nr_frames:>10} frames  {}",
+                 BinSize(mem::frames_bsize(nr_frames)));
+    }
+
+    let nr_nodes = numa::nr_nodes();
+    if nr_nodes > 1 {
+        for node in 0..nr_nodes {
+            let Some(stats) = mem::node_stats(node) else { break };
+            println!("node {node:<7} {:>10} frames  {} free of {}",
+                     stats.free,
+                     BinSize(mem::frames_bsize(stats.free)),
+                     BinSize(mem::frames_bsize(stats.total)));
+        }
+    }
+
+    let cache = fs::cache::stats();
+    println!("page cache   {:>10} pages, {} dirty, {} hits, {} misses, \
+              {} read ahead, {} evicted, {} written back",
+             cache.nr_pages, cache.nr_dirty, cache.hits, cache.misses,
+             cache.read_ahead, cache.evicted, cache.written_back);
+
+    Status::Success
+}
+
+fn cmd_memleak(_args: &[&str]) -> Status {
+    if !tracker::is_enabled() {
+        println!("memleak: allocation tracking is off; boot with kmemleak=on");
+        return Status::Failure;
+    }
+
+    let now = timestamp();
+    let freq = timestamp_frequency();
+    let count = tracker::scan_leaks(|record| {
+        let age = now.saturating_sub(record.timestamp);
+        match freq {
+            Some(freq) => {
+                let ms = age as u128 * 1_000 / freq as u128;
+                println!("{:016x} : {} bytes, {ms} ms old",
+                         record.addr.0, record.bsize);
+            },
+            None => {
+                println!("{:016x} : {} bytes, {age} cycles old",
+                         record.addr.0, record.bsize);
+            },
+        }
+
+        for pc in record.call_site.iter().take_while(|pc| pc.0 != 0) {
+            println!("    at {:016x}", pc.0);
+        }
+    });
+
+    let dropped = tracker::dropped();
+    if dropped > 0 {
+        println!("memleak: {dropped} allocations were not tracked");
+    }
+
+    if count > 0 {
+        println!("{count} suspected leaks");
+        return Status::Failure;
+    }
+
+    println!("no suspected leak");
+    Status::Success
+}
+
+#[cfg(feature = "fb-terminal")]
+fn cmd_mode(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            let Some(mode) = KERNEL_TERMINAL.lock().as_ref().map(|t| t.mode())
+            else {
+                return Status::Failure;
+            };
+            println!("{}x{}, {} bpp", mode.width, mode.height, mode.format.bpp);
+
+            if virtio::gpu::is_present() {
+                match virtio::gpu::display_modes() {
+                    Ok(modes) => for (width, height) in modes {
+                        println!("display: {width}x{height}");
+                    },
+                    Err(e) => println!("mode: {e}"),
+                }
+            }
+            Status::Success
+        },
+        [resolution] => {
+            let Some((width, height)) = resolution.split_once('x')
+                .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            else {
+                println!("mode: invalid resolution '{resolution}'");
+                return Status::Failure;
+            };
+
+            match virtio::gpu::framebuffer(width, height) {
+                Ok(fb) => {
+                    kterm::set_framebuffer(Box::new(fb));
+                    Status::Success
+                },
+                Err(e) => {
+                    println!("mode: {e}");
+                    Status::Failure
+                },
+            }
+        },
+        _ => {
+            println!("usage: mode [WIDTHxHEIGHT]");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_peek(args: &[&str]) -> Status {
+    let (physical, args) = split_physical_flag(args);
+    let (addr, bits) = match args {
+        [addr] => (addr, "32"),
+        [addr, bits] => (addr, *bits),
+        _ => {
+            println!("usage: peek [-p] ADDR [8|16|32|64]");
+            return Status::Failure;
+        },
+    };
+    let Some(width) = parse_width(bits) else {
+        println!("peek: invalid width {bits}");
+        return Status::Failure;
+    };
+    let Some(addr) = parse_hex(addr).filter(|addr| addr % width == 0) else {
+        println!("peek: invalid or unaligned address {addr}");
+        return Status::Failure;
+    };
+
+    match MemWindow::open(addr, width, physical, false) {
+        Ok(window) => {
+            println!("{:#0digits$x}", window.read(0, width),
+                     digits = 2 + 2 * width);
+            Status::Success
+        },
+        Err(e) => {
+            println!("peek: {e}");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_poke(args: &[&str]) -> Status {
+    let (physical, args) = split_physical_flag(args);
+    let (addr, value, bits) = match args {
+        [addr, value] => (addr, value, "32"),
+        [addr, value, bits] => (addr, value, *bits),
+        _ => {
+            println!("usage: poke [-p] ADDR VALUE [8|16|32|64]");
+            return Status::Failure;
+        },
+    };
+    let Some(width) = parse_width(bits) else {
+        println!("poke: invalid width {bits}");
+        return Status::Failure;
+    };
+    let Some(addr) = parse_hex(addr).filter(|addr| addr % width == 0) else {
+        println!("poke: invalid or unaligned address {addr}");
+        return Status::Failure;
+    };
+    let Some(value) = parse_hex(value)
+        .filter(|&value| width == 8 || value >> (8 * width) == 0) else {
+        println!("poke: invalid {bits}-bit value {value}");
+        return Status::Failure;
+    };
+
+    match MemWindow::open(addr, width, physical, true) {
+        Ok(window) => {
+            window.write(0, width, value as u64);
+            Status::Success
+        },
+        Err(e) => {
+            println!("poke: {e}");
+            Status::Failure
+        },
+    }
+}
+
+#[cfg(feature = "profiler")]
+fn cmd_profile(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            println!("profiling is {}",
+                     if profile::is_enabled() { "on" } else { "off" });
+            Status::Success
+        },
+        ["on" | "off"] => match profile::set_enabled(args[0] == "on") {
+            Ok(()) => Status::Success,
+            Err(e) => {
+                println!("profile: {e}");
+                Status::Failure
+            },
+        },
+        ["clear"] => {
+            profile::clear();
+            Status::Success
+        },
+        ["report"] => {
+            print!("{}", profile::report(profile::DEFAULT_REPORT_LEN));
+            Status::Success
+        },
+        ["report", n] => match n.parse() {
+            Ok(n) => {
+                print!("{}", profile::report(n));
+                Status::Success
+            },
+            Err(_) => {
+                println!("profile: invalid count '{n}'");
+                Status::Failure
+            },
+        },
+        _ => {
+            println!("usage: profile [on | off | clear | report [N]]");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_ps(_args: &[&str]) -> Status {
+    println!("  TID   PID  PPID STATE      NAME");
+    for stats in process::stats() {
+        println!("{:>5} {:>5} {:>5} {:<10} {}",
+                 stats.tid, stats.pid, stats.parent_pid,
+                 stats.state.as_str(), stats.name);
+    }
+
+    Status::Success
+}
+
+fn cmd_reboot(_args: &[&str]) -> Status {
+    arch::power::reboot();
+}
+
+fn cmd_set(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            for (name, value) in script::vars() {
+                println!("{name}={value}");
+            }
+        },
+        [name, ..] if !is_valid_var_name(name) => {
+            println!("set: invalid variable name '{name}'");
+            return Status::Failure;
+        },
+        [name] => script::unset_var(name),
+        [name, words @ ..] => script::set_var(name, &words.join(" ")),
+    }
+
+    Status::Success
+}
+
+fn is_valid_var_name(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn cmd_source(args: &[&str]) -> Status {
+    match args {
+        [path] => script::run_file(path),
+        _ => {
+            println!("usage: source PATH");
+            Status::Failure
+        },
+    }
+}
+
+#[cfg(feature = "fb-terminal")]
+fn cmd_theme(args: &[&str]) -> Status {
+    let mut kterm = KERNEL_TERMINAL.lock();
+    let Some(kterm) = kterm.as_mut() else {
+        return Status::Failure;
+    };
+
+    match args {
+        [] => {
+            for theme in THEMES {
+                let is_current = theme.name == kterm.theme().name;
+                let mark = if is_current { '*' } else { ' ' };
+                let _ = writeln!(kterm, "{mark} {}", theme.name);
+            }
+        },
+        [name] => match Theme::by_name(name) {
+            Some(theme) => kterm.set_theme(theme),
+            None => {
+                let _ = writeln!(kterm, "theme: unknown theme '{name}'");
+                return Status::Failure;
+            },
+        },
+        _ => {
+            let _ = writeln!(kterm, "usage: theme [NAME]");
+            return Status::Failure;
+        },
+    }
+
+    Status::Success
+}
+
+fn cmd_top(args: &[&str]) -> Status {
+    let interval = match args {
+        [] => 1,
+        [secs] => match secs.parse() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                println!("top: invalid interval {secs}");
+                return Status::Failure;
+            },
+        },
+        _ => {
+            println!("usage: top [SECONDS]");
+            return Status::Failure;
+        },
+    };
+
+    let nr_cpus = idle::nr_cpus();
+    let before = process::stats();
+    let idle_before: Vec<_> = (0..nr_cpus).map(idle::stats).collect();
+    let start = timestamp();
+    timer::sleep(Duration::from_secs(interval));
+    let elapsed = timestamp().saturating_sub(start).max(1);
+    let after = process::stats();
+
+    let idle_percent = (0..nr_cpus)
+        .map(|cpu| {
+            idle::stats(cpu).idle_percent_since(&idle_before[cpu], elapsed)
+        })
+        .sum::<u64>() / nr_cpus as u64;
+    println!("{nr_cpus} CPUs, {idle_percent}% idle, {} tasks",
+             after.len());
+
+    println!("  TID   PID  PRI STATE            TIME  CPU% NAME");
+    for stats in &after {
+        let prev = before.iter()
+            .find(|prev| prev.tid == stats.tid)
+            .map_or(0, |prev| prev.runtime);
+        let percent = stats.runtime.saturating_sub(prev) as u128 * 100
+            / elapsed as u128;
+
+        println!("{:>5} {:>5} {:>4} {:<10} {:>10} {:>4}% {}",
+                 stats.tid, stats.pid, stats.priority,
+                 stats.state.as_str(), format_runtime(stats.runtime),
+                 percent, stats.name);
+    }
+
+    Status::Success
+}
+
+fn cmd_trace(args: &[&str]) -> Status {
+    match args {
+        [] => {
+            println!("tracing is {}",
+                     if trace::is_enabled() { "on" } else { "off" });
+            Status::Success
+        },
+        ["on"] => {
+            trace::set_enabled(true);
+            Status::Success
+        },
+        ["off"] => {
+            trace::set_enabled(false);
+            Status::Success
+        },
+        ["clear"] => {
+            trace::clear();
+            Status::Success
+        },
+        ["dump"] | ["dump", "raw"] => {
+            let Some(serial) = (unsafe { LOGGER_SERIAL.as_mut() }) else {
+                println!("trace: there is no serial line");
+                return Status::Failure;
+            };
+
+            match trace::dump(serial, args.len() == 2) {
+                Ok(()) => Status::Success,
+                Err(_) => Status::Failure,
+            }
+        },
+        _ => {
+            println!("usage: trace [on | off | clear | dump [raw]]");
+            Status::Failure
+        },
+    }
+}
+
+fn cmd_vmaudit(_args: &[&str]) -> Status {
+    let count = protect::audit_wx(|start, bsize| {
+        println!("{:016x}-{:016x} : W+X ({} KiB)",
+                 start.0, start.0 + bsize - 1, bsize / 1024);
+    });
+
+    if count > 0 {
+        return Status::Failure;
+    }
+
+    println!("no writable and executable kernel mapping");
+    Status::Success
+}
+
+fn cmd_vmmap(args: &[&str]) -> Status {
+    let range = match args {
+        [] => arch::mem::LOWMEM_VA_START..VAddr(usize::MAX),
+        [start, end] => match (parse_hex(start), parse_hex(end)) {
+            (Some(start), Some(end)) if start < end => VAddr(start)..VAddr(end),
+            _ => {
+                println!("vmmap: invalid range {start}..{end}");
+                return Status::Failure;
+            },
+        },
+        _ => {
+            println!("usage: vmmap [START END]");
+            return Status::Failure;
+        },
+    };
+
+    paging::dump(range);
+    Status::Success
+}
+
+/// Format a CPU time in timestamp cycles, in seconds if the timestamp
+/// frequency is known.
+fn format_runtime(cycles: u64) -> String {
+    match timestamp_frequency() {
+        Some(freq) => format!("{}.{:02}s", cycles / freq,
+                              cycles % freq * 100 / freq),
+        None => format!("{cycles}c"),
+    }
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    usize::from_str_radix(&digits.replace('_', ""), 16).ok()
+}
+
+/// Parse a length in bytes, in decimal or in hexadecimal with the `0x` prefix.
+fn parse_len(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(_) => parse_hex(s),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse an access width in bits into bytes.
+fn parse_width(bits: &str) -> Option<usize> {
+    match bits {
+        "8" => Some(1),
+        "16" => Some(2),
+        "32" => Some(4),
+        "64" => Some(8),
+        _ => None,
+    }
+}
+
+/// Strip the leading `-p` flag of the memory inspection commands, telling that
+/// the address is physical.
+fn split_physical_flag<'a>(args: &'a [&'a str]) -> (bool, &'a [&'a str]) {
+    match args {
+        ["-p", rest @ ..] => (true, rest),
+        _ => (false, args),
+    }
+}
+
+/// The memory inspected by `dump`, `peek` and `poke`.
+enum MemWindow {
+    /// Memory mapped in the kernel address space, including RAM through the
+    /// low memory.
+    Mapped(VAddr),
+    /// Device memory, mapped uncached for the time of the command.
+    Device(MmioRegion),
+}
+
+impl MemWindow {
+    /// Give access to the `bsize` bytes at `addr`, a physical address if
+    /// `physical` is set. Physical RAM is accessed through the low memory, any
+    /// other physical range as device memory; a virtual range must be mapped,
+    /// and writable if `write` is set, so that the access can't fault.
+    fn open(
+        addr: usize,
+        bsize: usize,
+        physical: bool,
+        write: bool,
+    ) -> Result<Self, String> {
+        let end = addr.checked_add(bsize)
+            .ok_or_else(|| format!("invalid range at {addr:#x}"))?;
+
+        if physical {
+            let is_ram = resource::resources().iter().any(|res| {
+                res.kind == ResourceKind::SystemRam
+                    && res.start.0 <= addr as u64 && end as u64 <= res.end.0
+            });
+            if !is_ram {
+                return mem::iomap(PAddr(addr as u64), bsize,
+                                  CacheMode::Uncached)
+                    .map(MemWindow::Device)
+                    .map_err(|e| format!("{e}"));
+            }
+
+            let vaddr = PAddr(addr as u64).into_vaddr();
+            if PAddr::from_lowmem_vaddr(vaddr + (bsize - 1)).is_none() {
+                return Err(format!("{addr:#x} is beyond the low memory"));
+            }
+            return Ok(MemWindow::Mapped(vaddr));
+        }
+
+        let first_page = addr - addr % PAGE_SIZE;
+        for page in (first_page..end).step_by(PAGE_SIZE) {
+            let perms = page_permissions(VAddr(page));
+            if !perms.readable {
+                return Err(format!("page {page:#x} is not mapped"));
+            } else if write && !perms.writable {
+                return Err(format!("page {page:#x} is read-only"));
+            }
+        }
+
+        Ok(MemWindow::Mapped(VAddr(addr)))
+    }
+
+    /// Read the value of `width` bytes at `offset`, with a single access.
+    fn read(&self, offset: usize, width: usize) -> u64 {
+        match self {
+            MemWindow::Mapped(base) => {
+                let vaddr = *base + offset;
+                unsafe {
+                    match width {
+                        1 => ptr::read_volatile(vaddr.as_ptr::<u8>()) as u64,
+                        2 => ptr::read_volatile(vaddr.as_ptr::<u16>()) as u64,
+                        4 => ptr::read_volatile(vaddr.as_ptr::<u32>()) as u64,
+                        _ => ptr::read_volatile(vaddr.as_ptr::<u64>()),
+                    }
+                }
+            },
+            MemWindow::Device(region) => match width {
+                1 => region.read8(offset) as u64,
+                2 => region.read16(offset) as u64,
+                4 => region.read32(offset) as u64,
+                _ => region.read64(offset),
+            },
+        }
+    }
+
+    /// Write the value of `width` bytes at `offset`, with a single access.
+    fn write(&self, offset: usize, width: usize, value: u64) {
+        match self {
+            MemWindow::Mapped(base) => {
+                let vaddr = *base + offset;
+                unsafe {
+                    match width {
+                        1 => ptr::write_volatile(vaddr.as_mut_ptr(),
+                                                 value as u8),
+                        2 => ptr::write_volatile(vaddr.as_mut_ptr(),
+                                                 value as u16),
+                        4 => ptr::write_volatile(vaddr.as_mut_ptr(),
+                                                 value as u32),
+                        _ => ptr::write_volatile(vaddr.as_mut_ptr(), value),
+                    }
+                }
+            },
+            MemWindow::Device(region) => match width {
+                1 => region.write8(offset, value as u8),
+                2 => region.write16(offset, value as u16),
+                4 => region.write32(offset, value as u32),
+                _ => region.write64(offset, value),
+            },
+        }
+    }
+}
@@ -0,0 +1,144 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The boot splash: a progress bar along with the current boot step, drawn
+//! directly onto the framebuffer with the built-in 8×8 font. It is shown from
+//! the moment the framebuffer is mapped until the kernel terminal is created,
+//! so that the boot can be followed on machines without a serial line.
+
+use crate::driver::screen::{Color, FramebufferScreen};
+use crate::sync::Spinlock;
+use crate::ui::font8x8;
+use crate::ui::kterm::KernelFramebuffer;
+
+static SPLASH: Spinlock<Option<Splash<KernelFramebuffer>>> = Spinlock::new(None);
+
+const TITLE: &str = "Nucloid";
+/// The scale at which the title's glyphs are drawn.
+const TITLE_SCALE: usize = 4;
+
+const BAR_WIDTH: usize = 320;
+const BAR_HEIGHT: usize = 12;
+/// The space between the title, the progress bar and the status line.
+const MARGIN: usize = 16;
+
+const BACKGROUND: Color = Color { r: 0x10, g: 0x10, b: 0x18 };
+const FOREGROUND: Color = Color { r: 0xe0, g: 0xe0, b: 0xe0 };
+const BAR_BORDER: Color = Color { r: 0x60, g: 0x60, b: 0x70 };
+const BAR_FILL: Color = Color { r: 0x3d, g: 0x8e, b: 0xe8 };
+
+struct Splash<Fb: FramebufferScreen> {
+    fb: Fb,
+    nr_steps: usize,
+    nr_done: usize,
+}
+
+impl<Fb: FramebufferScreen> Splash<Fb> {
+    fn new(mut fb: Fb, nr_steps: usize, nr_done: usize) -> Self {
+        let (width, height) = fb.dimensions();
+        fb.fill_rect(0, 0, width, height, BACKGROUND);
+
+        let mut splash = Self { fb, nr_steps, nr_done };
+        let (x, y) = splash.centered(TITLE.len() * glyph_width(TITLE_SCALE),
+                                     splash.title_y());
+        draw_text(&mut splash.fb, x, y, TITLE, TITLE_SCALE);
+        splash.draw_bar();
+        splash
+    }
+
+    /// Show `status` as the current step, the progress bar counting the steps
+    /// before it as done.
+    fn step(&mut self, status: &str) {
+        self.draw_bar();
+        self.nr_done = (self.nr_done + 1).min(self.nr_steps);
+
+        let (width, _) = self.fb.dimensions();
+        let y = self.bar_y() + BAR_HEIGHT + MARGIN;
+        self.fb.fill_rect(0, y, width, glyph_height(1), BACKGROUND);
+
+        let max_len = width / glyph_width(1);
+        let status = &status[..status.char_indices()
+            .nth(max_len)
+            .map_or(status.len(), |(i, _)| i)];
+        let (x, y) = self.centered(status.chars().count() * glyph_width(1), y);
+        draw_text(&mut self.fb, x, y, status, 1);
+        self.fb.flush();
+    }
+
+    fn draw_bar(&mut self) {
+        let (width, _) = self.fb.dimensions();
+        let bar_width = BAR_WIDTH.min(width.saturating_sub(2 * MARGIN)).max(4);
+        let (x, y) = self.centered(bar_width, self.bar_y());
+
+        self.fb.fill_rect(x, y, bar_width, BAR_HEIGHT, BAR_BORDER);
+        self.fb.fill_rect(x + 1, y + 1, bar_width - 2, BAR_HEIGHT - 2,
+                          BACKGROUND);
+
+        let filled = (bar_width - 4) * self.nr_done / self.nr_steps.max(1);
+        self.fb.fill_rect(x + 2, y + 2, filled, BAR_HEIGHT - 4, BAR_FILL);
+    }
+
+    fn title_y(&self) -> usize {
+        let (_, height) = self.fb.dimensions();
+        let total = glyph_height(TITLE_SCALE) + 2 * MARGIN + BAR_HEIGHT
+            + glyph_height(1);
+        height.saturating_sub(total) / 2
+    }
+
+    fn bar_y(&self) -> usize {
+        self.title_y() + glyph_height(TITLE_SCALE) + MARGIN
+    }
+
+    /// The top-left corner of something `width` pixels wide, centered
+    /// horizontally at the row `y`.
+    fn centered(&self, width: usize, y: usize) -> (usize, usize) {
+        let (screen_width, _) = self.fb.dimensions();
+        (screen_width.saturating_sub(width) / 2, y)
+    }
+}
+
+fn glyph_width(scale: usize) -> usize {
+    font8x8::GLYPH_WIDTH as usize * scale
+}
+
+fn glyph_height(scale: usize) -> usize {
+    font8x8::GLYPH_HEIGHT as usize * scale
+}
+
+/// Draw `text` with its top-left corner at (`x`, `y`), each glyph's pixel
+/// taking `scale`×`scale` screen pixels.
+fn draw_text(fb: &mut impl FramebufferScreen,
+             x: usize, y: usize,
+             text: &str,
+             scale: usize) {
+    for (i, c) in text.chars().enumerate() {
+        fb.draw_bitmap(x + i * glyph_width(scale), y, font8x8::glyph(c), scale,
+                       FOREGROUND, BACKGROUND);
+    }
+}
+
+/// Show the splash on `fb`, for a boot made of `nr_steps` steps of which
+/// `nr_done` are already done.
+pub fn start(fb: KernelFramebuffer, nr_steps: usize, nr_done: usize) {
+    *SPLASH.lock() = Some(Splash::new(fb, nr_steps, nr_done));
+}
+
+/// Report that the boot moved on to the next step, described by `status`; this
+/// does nothing if the splash is not shown.
+pub fn step(status: &str) {
+    if let Some(ref mut splash) = *SPLASH.lock() {
+        splash.step(status);
+    }
+}
+
+/// Stop showing the splash, and give back its framebuffer, if it was shown.
+pub fn finish() -> Option<KernelFramebuffer> {
+    SPLASH.lock().take().map(|splash| splash.fb)
+}
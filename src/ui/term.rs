@@ -8,20 +8,47 @@
  * any later version. See LICENSE file for more information.                  *
  ******************************************************************************/
 
+use alloc::boxed::Box;
 use alloc::collections::VecDeque;
+use alloc::format;
 use alloc::vec;
+use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::fmt;
-use core::str::FromStr;
+use core::ptr::{addr_of_mut, NonNull};
+use hashbrown::HashMap;
 
-use crate::driver::screen::{Color, FramebufferScreen};
-use crate::ui::pxfont::PxFont;
+use crate::collections::{Link, List, ListAdapter};
+use crate::driver::screen::{Color, FramebufferMode, FramebufferScreen};
+use crate::fs;
+use crate::ui::escape::{EscapeCommand, EscapeIterator};
+use crate::ui::pointer;
+use crate::ui::pxfont::{Glyph, PxFont};
+use crate::ui::theme::{self, TermColor, Theme};
+use crate::ui::unicode;
+use crate::warning;
 
-const DEFAULT_FG_COLOR: Color = Color { r: 169, g: 183, b: 198 };
+/// The dimensions of the wallpaper image, `media/wallpaper.data`.
+const WALLPAPER_WIDTH: usize = 1920;
+const WALLPAPER_HEIGHT: usize = 1080;
+
+/// The font of the terminal, unless another one is loaded with `set_font()`.
+pub const DEFAULT_FONT: &str = "/fonts/iosevka.pxfont";
+
+/// The maximum number of blended glyphs kept by the glyph cache.
+const GLYPH_CACHE_SIZE: usize = 512;
+
+/// The maximum number of combining marks drawn over a character; any further
+/// one is dropped.
+const MAX_MARKS: usize = 2;
+
+/// The height in pixels of the cursor, drawn as an underline.
+const CURSOR_HEIGHT: usize = 2;
 
 pub struct Terminal<Fb> {
-    background: &'static [u8],
+    wallpaper: Wallpaper,
     font: PxFont,
+    theme: &'static Theme,
     fb: RefCell<Fb>,
     width_px: usize,
     height_px: usize,
@@ -29,36 +56,210 @@ pub struct Terminal<Fb> {
     rows: usize,
     cursor_x: usize,
     cursor_y: usize,
+    /// Whether the cursor is drawn, which toggles as it blinks.
+    cursor_shown: bool,
     curr_style: GlyphStyle,
     cells: VecDeque<TermCell>,
+    /// The cell of the last character put, along with the last character that
+    /// joined its grapheme cluster, if any.
+    last_cell: Option<(usize, usize, char)>,
+    /// The position in pixels of the mouse pointer, hidden until the mouse
+    /// first moves.
+    pointer: Option<(usize, usize)>,
+    glyph_cache: RefCell<GlyphCache>,
+    /// The row of pixels being composited onto the background, kept so that
+    /// drawing a glyph doesn't allocate.
+    row_buffer: RefCell<Vec<u32>>,
 }
 
 #[derive(Copy, Clone)]
 struct GlyphStyle {
-    fg_color: Color,
-    bg_color: Option<Color>,
+    fg_color: TermColor,
+    bg_color: Option<TermColor>,
 }
 
 #[derive(Copy, Clone)]
 struct TermCell {
     c: char,
+    /// The combining marks drawn over `c`, `'\0'` for none.
+    marks: [char; MAX_MARKS],
     style: GlyphStyle,
 }
 
+/// The identity of a blended glyph: its characters, and its foreground color
+/// as a 0x00RRGGBB value. The background is composited as the glyph is drawn,
+/// so that the same glyph is reused anywhere over the wallpaper.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    c: char,
+    marks: [char; MAX_MARKS],
+    fg: u32,
+}
+
+/// A cache of the glyphs already blended with their foreground color, as rows
+/// of pixels with their opacity in the top byte and their 0x00RRGGBB color
+/// below, see `composite()`. Once full, the least recently used glyph is
+/// evicted and its buffer reused. It must be cleared whenever the font
+/// changes.
+struct GlyphCache {
+    entries: HashMap<GlyphKey, NonNull<CachedGlyph>>,
+    /// The cached glyphs, from the most recently used to the least.
+    lru: List<LruAdapter>,
+    capacity: usize,
+}
+
+unsafe impl Send for GlyphCache {}
+
+struct CachedGlyph {
+    key: GlyphKey,
+    pixels: Vec<u32>,
+    link: Link<CachedGlyph>,
+}
+
+struct LruAdapter;
+
+impl ListAdapter for LruAdapter {
+    type Node = CachedGlyph;
+
+    fn link(node: NonNull<CachedGlyph>) -> NonNull<Link<CachedGlyph>> {
+        unsafe { NonNull::new_unchecked(addr_of_mut!((*node.as_ptr()).link)) }
+    }
+}
+
+impl GlyphCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: List::new(),
+            capacity,
+        }
+    }
+
+    /// The pixels of the glyph `key`, blended by `blend` into an empty buffer
+    /// if it is not cached.
+    fn get_or_insert_with(
+        &mut self,
+        key: GlyphKey,
+        blend: impl FnOnce(&mut Vec<u32>),
+    ) -> &[u32] {
+        let node = if let Some(&node) = self.entries.get(&key) {
+            unsafe { self.lru.remove(node); }
+            node
+        } else {
+            let evicted = if self.entries.len() >= self.capacity {
+                self.lru.pop_back()
+            } else {
+                None
+            };
+            let node = match evicted {
+                Some(node) => {
+                    let glyph = unsafe { &mut *node.as_ptr() };
+                    self.entries.remove(&glyph.key);
+                    glyph.key = key;
+                    glyph.pixels.clear();
+                    node
+                },
+                None => NonNull::from(Box::leak(Box::new(CachedGlyph {
+                    key,
+                    pixels: Vec::new(),
+                    link: Link::new(),
+                }))),
+            };
+
+            blend(unsafe { &mut (*node.as_ptr()).pixels });
+            self.entries.insert(key, node);
+            node
+        };
+
+        unsafe {
+            self.lru.push_front(node);
+            &(*node.as_ptr()).pixels
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        while let Some(node) = self.lru.pop_front() {
+            drop(unsafe { Box::from_raw(node.as_ptr()) });
+        }
+    }
+}
+
+impl Drop for GlyphCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Blend the cached glyph pixel `px` onto `bg`.
+fn composite(px: u32, bg: Color) -> u32 {
+    let fg = Color { r: (px >> 16) as u8, g: (px >> 8) as u8, b: px as u8 };
+
+    Color::blend(fg, (px >> 24) as u8, bg).to_rgb()
+}
+
+/// The wallpaper, scaled to cover the whole screen while keeping its aspect
+/// ratio, and centered.
+struct Wallpaper {
+    /// The 0x00RRGGBB pixels of the image.
+    data: &'static [u32],
+    /// The scaling ratio, from screen to image pixels.
+    num: usize,
+    den: usize,
+    /// The offset in screen pixels of the screen within the scaled image.
+    offset_x: usize,
+    offset_y: usize,
+}
+
+impl Wallpaper {
+    fn new(data: &'static [u32], width_px: usize, height_px: usize) -> Self {
+        let (num, den) = if width_px * WALLPAPER_HEIGHT
+                            >= height_px * WALLPAPER_WIDTH {
+            (WALLPAPER_WIDTH, width_px)
+        } else {
+            (WALLPAPER_HEIGHT, height_px)
+        };
+
+        Self {
+            data,
+            num,
+            den,
+            offset_x: (WALLPAPER_WIDTH * den / num).saturating_sub(width_px) / 2,
+            offset_y: (WALLPAPER_HEIGHT * den / num).saturating_sub(height_px) / 2,
+        }
+    }
+
+    fn pixel_at(&self, x: usize, y: usize) -> u32 {
+        let src_x = ((x + self.offset_x) * self.num / self.den)
+            .min(WALLPAPER_WIDTH - 1);
+        let src_y = ((y + self.offset_y) * self.num / self.den)
+            .min(WALLPAPER_HEIGHT - 1);
+
+        self.data[src_y * WALLPAPER_WIDTH + src_x]
+    }
+}
+
 impl<Fb: FramebufferScreen> Terminal<Fb> {
     pub fn create(fb: Fb) -> Self {
-        let (width_px, height_px) = (fb.dimensions().0, fb.dimensions().1);
-        let font = PxFont::from_data(include_bytes!(
-            concat!(env!("CARGO_MANIFEST_DIR"), "/media/iosevka.pxfont")
-        )).unwrap();
+        let FramebufferMode { width: width_px, height: height_px, .. } = fb.mode();
+        let wallpaper: &'static [u8] = include_bytes_align_as!(u32,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/media/wallpaper.data")
+        );
+        let font = fs::read(DEFAULT_FONT)
+            .map_err(|e| format!("{e}"))
+            .and_then(|data| PxFont::from_data(data).map_err(|e| format!("{e}")))
+            .unwrap_or_else(|e| {
+                warning!("couldn't load terminal font: {e}; using built-in font");
+                PxFont::builtin()
+            });
         let columns = width_px / font.glyph_width() as usize;
         let rows = height_px / font.glyph_height() as usize;
 
         let mut term = Self {
-            background: include_bytes_align_as!(u32,
-                concat!(env!("CARGO_MANIFEST_DIR"), "/media/wallpaper.data")
-            ),
+            wallpaper: Wallpaper::new(unsafe { wallpaper.align_to::<u32>().1 },
+                                      width_px, height_px),
             font,
+            theme: &theme::DARK,
             fb: RefCell::new(fb),
             width_px,
             height_px,
@@ -66,8 +267,13 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
             rows,
             cursor_x: 0,
             cursor_y: 0,
+            cursor_shown: false,
             curr_style: Default::default(),
             cells: VecDeque::new(),
+            last_cell: None,
+            pointer: None,
+            glyph_cache: RefCell::new(GlyphCache::new(GLYPH_CACHE_SIZE)),
+            row_buffer: RefCell::new(Vec::new()),
         };
         term.clear();
 
@@ -80,21 +286,106 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
         self.cells = vec![Default::default(); self.rows * self.columns].into();
         self.cursor_x = 0;
         self.cursor_y = 0;
+        self.cursor_shown = false;
+        self.last_cell = None;
+        self.present();
+    }
+
+    /// Draw the terminal onto another framebuffer, e.g. of another
+    /// resolution; the text is kept, as far as it fits.
+    pub fn set_framebuffer(&mut self, fb: Fb) {
+        let FramebufferMode { width: width_px, height: height_px, .. } = fb.mode();
+
+        self.fb = RefCell::new(fb);
+        self.wallpaper = Wallpaper::new(self.wallpaper.data, width_px, height_px);
+        self.width_px = width_px;
+        self.height_px = height_px;
+        self.pointer = self.pointer
+            .map(|(x, y)| (x.min(width_px - 1), y.min(height_px - 1)));
+        self.relayout();
+        self.rerender();
+    }
+
+    /// Draw the terminal with another font; the text is kept, as far as it
+    /// fits.
+    pub fn set_font(&mut self, font: PxFont) {
+        self.font = font;
+        self.relayout();
+        self.rerender();
+    }
+
+    pub fn font(&self) -> &PxFont {
+        &self.font
+    }
+
+    /// Recompute the number of columns and rows once the screen or the font
+    /// changed, keeping the text as far as it fits.
+    fn relayout(&mut self) {
+        let columns = self.width_px / self.font.glyph_width() as usize;
+        let rows = self.height_px / self.font.glyph_height() as usize;
+
+        // The rows scrolled out are the top ones, to keep the cursor's.
+        let skip = (self.cursor_y + 1).saturating_sub(rows);
+        let mut cells = vec![TermCell::default(); rows * columns];
+        for y in 0..rows.min(self.rows - skip) {
+            for x in 0..columns.min(self.columns) {
+                cells[y * columns + x] = self.cells[(y + skip) * self.columns + x];
+            }
+        }
+
+        self.columns = columns;
+        self.rows = rows;
+        self.cells = cells.into();
+        self.cursor_x = self.cursor_x.min(columns - 1);
+        self.cursor_y -= skip;
+        self.last_cell = None;
+        self.glyph_cache.get_mut().clear();
+    }
+
+    /// The video mode of the framebuffer the terminal is drawn onto.
+    pub fn mode(&self) -> FramebufferMode {
+        self.fb.borrow().mode()
+    }
+
+    /// The framebuffer the terminal is drawn onto, to be drawn upon directly;
+    /// the terminal draws over it at its next update.
+    pub fn framebuffer_mut(&mut self) -> &mut Fb {
+        self.fb.get_mut()
+    }
+
+    pub fn theme(&self) -> &'static Theme {
+        self.theme
+    }
+
+    /// Change the color theme; the text already printed with palette or default
+    /// colors is rendered again with the new theme.
+    pub fn set_theme(&mut self, theme: &'static Theme) {
+        self.theme = theme;
+        self.rerender();
     }
 
     fn clear_visual(&self) {
-        let mut fb = self.fb.borrow_mut();
+        self.draw_wallpaper(0, 0, self.width_px, self.height_px);
+    }
 
-        let dst_row_len = fb.dimensions().0;
-        let mut src_row = unsafe { self.background.align_to::<u32>().1 };
+    /// Draw the wallpaper over the area of `width` × `height` pixels at `x`,
+    /// `y`, clipped to the screen.
+    fn draw_wallpaper(&self, x: usize, y: usize, width: usize, height: usize) {
+        let width = width.min(self.width_px.saturating_sub(x));
+        let mut fb = self.fb.borrow_mut();
+        let mut row = vec![0; width];
 
-        for y in 0..fb.dimensions().1 {
-            fb.copy(0, y, unsafe { (&src_row[..dst_row_len]).align_to::<u32>().1 });
-            src_row = &src_row[1920..];
+        for y in y..(y + height).min(self.height_px) {
+            for (dx, px) in row.iter_mut().enumerate() {
+                *px = self.wallpaper.pixel_at(x + dx, y);
+            }
+            fb.copy(x, y, &row);
         }
     }
 
     pub fn write(&mut self, s: &str) {
+        let cursor_shown = self.cursor_shown;
+        self.hide_cursor();
         let mut it = s.char_indices();
 
         while let Some((i, c)) = it.next() {
@@ -103,9 +394,150 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
             }
             self.putc(c);
         }
+
+        if cursor_shown {
+            self.show_cursor();
+        }
+        self.present();
+    }
+
+    /// Show the cursor if it is hidden, or hide it; this is meant to be called
+    /// periodically, to make it blink.
+    pub fn blink_cursor(&mut self) {
+        if self.cursor_shown {
+            self.hide_cursor();
+        } else {
+            self.show_cursor();
+        }
+        self.present();
+    }
+
+    /// Draw the cursor as an underline of the cell it is on, in the current
+    /// foreground color.
+    fn show_cursor(&mut self) {
+        let glyph_w = self.font.glyph_width() as usize;
+        let glyph_h = self.font.glyph_height() as usize;
+        let color = self.theme.resolve(self.curr_style.fg_color);
+        let mut fb = self.fb.borrow_mut();
+
+        for dy in glyph_h.saturating_sub(CURSOR_HEIGHT)..glyph_h {
+            for dx in 0..glyph_w {
+                fb.put(self.cursor_x * glyph_w + dx,
+                       self.cursor_y * glyph_h + dy, color);
+            }
+        }
+        self.cursor_shown = true;
+    }
+
+    /// Erase the cursor, if drawn, by rendering its cell again.
+    fn hide_cursor(&mut self) {
+        if !self.cursor_shown {
+            return;
+        }
+        self.cursor_shown = false;
+
+        let glyph_w = self.font.glyph_width() as usize;
+        let glyph_h = self.font.glyph_height() as usize;
+        self.restore_area(self.cursor_x * glyph_w, self.cursor_y * glyph_h,
+                          glyph_w, glyph_h);
+    }
+
+    /// Print `text` on a line of its own with the large size of the font, or
+    /// the regular one if it has none, in the current style. The banner is
+    /// laid out with the glyphs' advances and kerning rather than on the grid
+    /// of cells; it doesn't hold any cell, so it is erased whenever the
+    /// terminal is rendered again, e.g. when scrolling.
+    pub fn print_banner(&mut self, text: &str) {
+        self.hide_cursor();
+        let cell_h = self.font.glyph_height() as usize;
+        let nr_rows = (self.banner_font().glyph_height() as usize)
+            .div_ceil(cell_h)
+            .min(self.rows);
+
+        if self.cursor_x > 0 {
+            self.cursor_x = 0;
+            self.advance_y();
+        }
+        if self.cursor_y + nr_rows > self.rows {
+            self.scroll_up(self.cursor_y + nr_rows - self.rows);
+        }
+
+        let font = self.banner_font();
+        let orig_y = self.cursor_y * cell_h;
+        let style_fg = self.theme.resolve(self.curr_style.fg_color);
+        let style_bg = self.curr_style.bg_color.map(|c| self.theme.resolve(c));
+        match style_bg {
+            Some(bg) => self.fb.borrow_mut()
+                .fill_rect(0, orig_y, self.width_px, nr_rows * cell_h, bg),
+            None => self.draw_wallpaper(0, orig_y, self.width_px,
+                                        nr_rows * cell_h),
+        }
+        {
+            let mut fb = self.fb.borrow_mut();
+            let glyph_w = font.glyph_width() as usize;
+            let mut pen_x: usize = 0;
+            let mut prev = None;
+            for c in text.chars() {
+                let kerning = prev.map_or(0, |prev| font.kerning(prev, c));
+                pen_x = pen_x.saturating_add_signed(kerning);
+                prev = Some(c);
+
+                let glyph = font.get_glyph(c)
+                    .unwrap_or(font.replacement_glyph());
+                if !glyph.is_rgba() {
+                    let nr_cols = glyph.nr_columns() * glyph_w;
+                    for (i, &value) in glyph.data().iter().enumerate() {
+                        let x = pen_x + i % nr_cols;
+                        let y = orig_y + i / nr_cols;
+                        if value == 0 || x >= self.width_px || y >= self.height_px {
+                            continue;
+                        }
+                        let bg_color = style_bg
+                            .unwrap_or_else(|| self.bg_color_at(x, y));
+                        fb.put(x, y, Color::blend(style_fg, value, bg_color));
+                    }
+                }
+                pen_x += font.advance(c);
+            }
+        }
+
+        self.cursor_y += nr_rows - 1;
+        self.last_cell = None;
+        self.advance_y();
+        self.present();
+    }
+
+    fn banner_font(&self) -> &PxFont {
+        self.font.large().unwrap_or(&self.font)
+    }
+
+    /// Move the mouse pointer by `dx` and `dy` pixels, within the screen,
+    /// showing it if it was hidden.
+    pub fn move_pointer(&mut self, dx: i32, dy: i32) {
+        let (x, y) = match self.pointer {
+            Some((x, y)) => {
+                self.restore_area(x, y, pointer::WIDTH, pointer::HEIGHT);
+                (x, y)
+            },
+            None => (self.width_px / 2, self.height_px / 2),
+        };
+
+        let x = x.saturating_add_signed(dx as isize).min(self.width_px - 1);
+        let y = y.saturating_add_signed(dy as isize).min(self.height_px - 1);
+        self.pointer = Some((x, y));
+        self.present();
     }
 
     pub fn putc(&mut self, c: char) {
+        self.hide_cursor();
+        if let Some((x, y, prev)) = self.last_cell {
+            if unicode::extends_cluster(prev, c) {
+                self.last_cell = Some((x, y, c));
+                return self.add_mark(x, y, c);
+            }
+        }
+        self.last_cell = None;
+
         match c {
             '\n' => {
                 self.cursor_x = 0;
@@ -113,7 +545,8 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
             },
             '\t' => self.advance_x(8 - (self.cursor_x & 0b111)),
             '\r' => self.cursor_x = 0,
-            '\x00'..='\x1f' | '\x7f' => return,
+            '\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            '\x00'..='\x1f' | '\x7f' => (),
             mut c => {
                 if c == '\u{a0}' || c == '\u{202f}' {
                     c = ' ';
@@ -124,22 +557,20 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
                     self.cursor_x = 0;
                     self.advance_y();
                 }
-                self.render_glyph(
-                    c,
-                    self.cursor_x,
-                    self.cursor_y,
-                    self.curr_style,
-                );
-                *self.cell_at(self.cursor_x, self.cursor_y) = TermCell {
+                let cell = TermCell {
                     c,
+                    marks: ['\0'; MAX_MARKS],
                     style: self.curr_style,
                 };
+                self.render_glyph(cell, self.cursor_x, self.cursor_y);
+                *self.cell_at(self.cursor_x, self.cursor_y) = cell;
                 for i in (self.cursor_x + 1)..(self.cursor_x + glyph_size) {
                     *self.cell_at(i, self.cursor_y) = TermCell {
                         c: '\0',
-                        style: self.curr_style,
+                        ..cell
                     };
                 }
+                self.last_cell = Some((self.cursor_x, self.cursor_y, c));
                 self.advance_x(glyph_size);
             },
         }
@@ -160,6 +591,22 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
         self.rerender();
 
         self.cursor_y = self.cursor_y.saturating_sub(nr_lines);
+        self.last_cell = self.last_cell.and_then(|(x, y, c)| {
+            Some((x, y.checked_sub(nr_lines)?, c))
+        });
+    }
+
+    /// Add the combining character `c` to the cell at `x`, `y`, and render it
+    /// again; characters without a glyph, such as joiners and variation
+    /// selectors, are kept but draw nothing.
+    fn add_mark(&mut self, x: usize, y: usize, c: char) {
+        let cell = self.cell_at(x, y);
+        if let Some(mark) = cell.marks.iter_mut().find(|mark| **mark == '\0') {
+            *mark = c;
+        }
+
+        let cell = *cell;
+        self.render_glyph(cell, x, y);
     }
 
     fn advance_x(&mut self, by: usize) {
@@ -178,43 +625,98 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
         self.cursor_y += 1;
     }
 
+    /// The number of columns taken by `c`, as given by its Unicode width rather
+    /// than by its glyph, so that text aligns even with glyphs of the wrong
+    /// width; emojis are always drawn on two columns.
     fn glyph_size(&self, c: char) -> usize {
-        self.font.get_glyph(c)
-            .unwrap_or(self.font.replacement_glyph())
-            .nr_columns()
+        match self.font.get_glyph(c) {
+            Some(glyph) if glyph.is_rgba() => 2,
+            _ => unicode::char_width(c).max(1),
+        }
     }
 
-    fn render_glyph(
-        &self,
-        c: char,
-        x: usize,
-        y: usize,
-        style: GlyphStyle,
-    ) {
+    /// Render the cell `cell` at `x`, `y`: its character, clipped or padded to
+    /// its width, with its combining marks drawn over it.
+    fn render_glyph(&self, cell: TermCell, x: usize, y: usize) {
+        let TermCell { c, marks, style } = cell;
         let glyph = self.font.get_glyph(c)
             .unwrap_or(self.font.replacement_glyph());
         if glyph.is_rgba() {
             return self.render_emoji(c, x, y, style);
         }
+        let marks = marks.iter()
+            .filter_map(|&mark| self.font.get_glyph(mark))
+            .filter(|glyph| !glyph.is_rgba());
+
+        let glyph_w = self.font.glyph_width() as usize;
+        let glyph_h = self.font.glyph_height() as usize;
+        let orig_x = x * glyph_w;
+        let orig_y = y * glyph_h;
+        let nr_cols = self.glyph_size(c) * glyph_w;
+
+        let style_fg = self.theme.resolve(style.fg_color);
+        let style_bg = style.bg_color.map(|c| self.theme.resolve(c));
+        let key = GlyphKey {
+            c,
+            marks: cell.marks,
+            fg: style_fg.to_rgb(),
+        };
 
-        let orig_x = x * self.font.glyph_width() as usize;
-        let orig_y = y * self.font.glyph_height() as usize;
-        let nr_cols = self.glyph_size(c) * self.font.glyph_width() as usize;
+        let mut cache = self.glyph_cache.borrow_mut();
+        let pixels = cache.get_or_insert_with(key, |pixels| {
+            self.blend_glyph(pixels, glyph, marks, nr_cols, style_fg)
+        });
 
+        let mut row = self.row_buffer.borrow_mut();
         let mut fb = self.fb.borrow_mut();
+        for (dy, glyph_row) in pixels.chunks_exact(nr_cols).enumerate() {
+            let y = orig_y + dy;
+
+            row.clear();
+            row.extend(glyph_row.iter().enumerate().map(|(dx, &px)| {
+                let bg_color = style_bg
+                    .unwrap_or_else(|| self.bg_color_at(orig_x + dx, y));
+                composite(px, bg_color)
+            }));
+            fb.copy(orig_x, y, &row);
+        }
+    }
 
-        for (i, &value) in glyph.data().into_iter().enumerate() {
-            let x = orig_x + i % nr_cols as usize;
-            let y = orig_y + i / nr_cols as usize;
+    /// Blend `glyph` and the marks drawn over it with the color `style_fg`
+    /// into `pixels`, as rows `nr_cols` pixels wide, see `GlyphCache`.
+    fn blend_glyph<'a>(
+        &self,
+        pixels: &mut Vec<u32>,
+        glyph: &'a Glyph,
+        marks: impl Iterator<Item = &'a Glyph>,
+        nr_cols: usize,
+        style_fg: Color,
+    ) {
+        let glyph_w = self.font.glyph_width() as usize;
+        let glyph_h = self.font.glyph_height() as usize;
+
+        // The opacity of the cell's pixels, the strongest of all its glyphs'.
+        pixels.resize(nr_cols * glyph_h, 0);
+        for glyph in core::iter::once(glyph).chain(marks) {
+            let glyph_cols = glyph.nr_columns() * glyph_w;
+
+            for (i, &value) in glyph.data().iter().enumerate() {
+                let (dx, dy) = (i % glyph_cols, i / glyph_cols);
+                if dx < nr_cols && dy < glyph_h {
+                    let px = &mut pixels[dy * nr_cols + dx];
+                    *px = (*px).max((value as u32) << 24);
+                }
+            }
+        }
+
+        for px in pixels.iter_mut() {
+            let value = (*px >> 24) as u16;
             let fg_color = Color {
-                r: (value as u16 * style.fg_color.r as u16 / 255) as u8,
-                g: (value as u16 * style.fg_color.g as u16 / 255) as u8,
-                b: (value as u16 * style.fg_color.b as u16 / 255) as u8,
+                r: (value * style_fg.r as u16 / 255) as u8,
+                g: (value * style_fg.g as u16 / 255) as u8,
+                b: (value * style_fg.b as u16 / 255) as u8,
             };
-            let bg_color = style.bg_color
-                .unwrap_or_else(|| self.bg_color_at(x, y));
-            let color = Color::blend(fg_color, value, bg_color);
-            fb.put(x, y, color);
+            *px |= fg_color.to_rgb();
         }
     }
 
@@ -242,29 +744,79 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
                 b: rgba[0],
             };
             let bg_color = style.bg_color
+                .map(|c| self.theme.resolve(c))
                 .unwrap_or_else(|| self.bg_color_at(x, y));
             let color = Color::blend(fg_color, rgba[3], bg_color);
             fb.put(x, y, color);
         }
     }
 
+    /// Draw the mouse pointer over the text, and make it all visible.
+    fn present(&self) {
+        self.draw_pointer();
+        self.fb.borrow_mut().flush();
+    }
+
+    /// Draw the mouse pointer over whatever is rendered underneath it.
+    fn draw_pointer(&self) {
+        let Some((orig_x, orig_y)) = self.pointer else {
+            return;
+        };
+        let mut fb = self.fb.borrow_mut();
+
+        for (x, y, color) in pointer::pixels() {
+            let (x, y) = (orig_x + x, orig_y + y);
+            if x < self.width_px && y < self.height_px {
+                fb.put(x, y, color);
+            }
+        }
+    }
+
+    /// Render again the area of `width` × `height` pixels at `x`, `y`, e.g.
+    /// to erase the mouse pointer from it.
+    fn restore_area(&self, x: usize, y: usize, width: usize, height: usize) {
+        let end_x = (x + width).min(self.width_px);
+        let end_y = (y + height).min(self.height_px);
+        self.draw_wallpaper(x, y, width, height);
+
+        // Glyphs can span two cells, hence the one to the left.
+        let (glyph_w, glyph_h) = (self.font.glyph_width() as usize,
+                                  self.font.glyph_height() as usize);
+        let columns = (x / glyph_w).saturating_sub(1)
+            ..((end_x + glyph_w - 1) / glyph_w).min(self.columns);
+        let rows = (y / glyph_h)
+            ..((end_y + glyph_h - 1) / glyph_h).min(self.rows);
+
+        for row in rows {
+            for column in columns.clone() {
+                let cell = self.cells[row * self.columns + column];
+                if cell.c != '\0' && cell.c != ' ' {
+                    self.render_glyph(cell, column, row);
+                }
+            }
+        }
+    }
+
     fn bg_color_at(&self, x: usize, y: usize) -> Color {
-        let rgb = &self.background[((y * 1920 + x) * 4)..];
+        let px = self.wallpaper.pixel_at(x, y);
 
-        Color { r: rgb[2], g: rgb[1], b: rgb[0] }
+        Color { r: (px >> 16) as u8, g: (px >> 8) as u8, b: px as u8 }
     }
 
     fn rerender(&mut self) {
         self.clear_visual();
+        self.cursor_shown = false;
 
         for (i, cell) in self.cells.iter().enumerate() {
             let y = i / self.columns;
             let x = i % self.columns;
 
             if cell.c != '\0' && cell.c != ' ' {
-                self.render_glyph(cell.c, x, y, cell.style);
+                self.render_glyph(*cell, x, y);
             }
         }
+
+        self.present();
     }
 
     fn cell_at(&mut self, x: usize, y: usize) -> &mut TermCell {
@@ -287,7 +839,7 @@ impl<Fb: FramebufferScreen> Terminal<Fb> {
 
         match cmd {
             SetFgColor(c) => self.curr_style.fg_color = c,
-            ClearFgColor => self.curr_style.fg_color = DEFAULT_FG_COLOR,
+            ClearFgColor => self.curr_style.fg_color = TermColor::Default,
             SetBgColor(c) => self.curr_style.bg_color = Some(c),
             ClearBgColor => self.curr_style.bg_color = None,
             Newline => {
@@ -311,6 +863,7 @@ impl Default for TermCell {
     fn default() -> Self {
         Self {
             c: ' ',
+            marks: ['\0'; MAX_MARKS],
             style: Default::default(),
         }
     }
@@ -319,91 +872,71 @@ impl Default for TermCell {
 impl Default for GlyphStyle {
     fn default() -> Self {
         Self {
-            fg_color: DEFAULT_FG_COLOR,
+            fg_color: TermColor::Default,
             bg_color: None,
         }
     }
 }
 
-#[derive(Debug)]
-pub enum EscapeCommand {
-    SetFgColor(Color),
-    ClearFgColor,
-    SetBgColor(Color),
-    ClearBgColor,
-    Newline,
-}
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    /// A wallpaper whose pixels are their own `x << 16 | y`.
+    fn coordinates() -> &'static [u32] {
+        let data: Vec<u32> = (0..WALLPAPER_HEIGHT)
+            .flat_map(|y| (0..WALLPAPER_WIDTH).map(move |x| (x << 16 | y) as u32))
+            .collect();
+        data.leak()
+    }
 
-impl FromStr for EscapeCommand {
-    type Err = ();
+    #[test]
+    fn it_evicts_the_least_recently_used_glyph() {
+        let key = |c| GlyphKey {
+            c,
+            marks: ['\0'; MAX_MARKS],
+            fg: 0xffffff,
+        };
+        let mut cache = GlyphCache::new(2);
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (cmd, arg) = s.split_once('=')
-            .map(|(k, v)| (k, Some(v)))
-            .unwrap_or((s, None));
+        assert_eq!(cache.get_or_insert_with(key('a'), |p| p.push(1)), [1]);
+        assert_eq!(cache.get_or_insert_with(key('b'), |p| p.push(2)), [2]);
+        assert_eq!(cache.get_or_insert_with(key('a'), |p| p.push(0)), [1]);
+        assert_eq!(cache.get_or_insert_with(key('c'), |p| p.push(3)), [3]);
 
-        use EscapeCommand::*;
-        Ok(match (cmd, arg) {
-            ("fg", Some(arg)) =>
-                SetFgColor(arg.parse().map_err(|_| ())?),
-            ("!fg", None) => ClearFgColor,
-            ("bg", Some(arg)) =>
-                SetBgColor(arg.parse().map_err(|_| ())?),
-            ("!bg", None) => ClearBgColor,
-            ("nl", None) => Newline,
-            _ => return Err(()),
-        })
+        assert_eq!(cache.get_or_insert_with(key('a'), |p| p.push(0)), [1]);
+        assert_eq!(cache.get_or_insert_with(key('b'), |p| p.push(4)), [4]);
+        assert_eq!(cache.get_or_insert_with(key('c'), |p| p.push(5)), [5]);
     }
-}
 
-pub struct EscapeIterator<'a> {
-    s: Option<&'a str>,
-    off: usize,
-}
+    #[test]
+    fn it_composites_glyphs_onto_their_background() {
+        let bg = Color { r: 0x10, g: 0x20, b: 0x30 };
 
-impl<'a> EscapeIterator<'a> {
-    pub fn new(s: &'a str) -> Self {
-        Self {
-            s: Some(s),
-            off: 0,
-        }
+        assert_eq!(composite(0x00_000000, bg), 0x102030);
+        assert_eq!(composite(0xff_ffffff, bg), 0xffffff);
+        assert_eq!(composite(0xff_ff0000, bg), 0xff0000);
     }
 
-    #[inline]
-    pub fn continuation_offset(&self) -> usize {
-        self.off
-    }
-}
+    #[test]
+    fn it_scales_the_wallpaper_to_cover_the_screen() {
+        let data = coordinates();
 
-impl Iterator for EscapeIterator<'_> {
-    type Item = EscapeCommand;
+        let same = Wallpaper::new(data, 1920, 1080);
+        assert_eq!(same.pixel_at(1919, 1079), 1919 << 16 | 1079);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let s = self.s?;
+        let half = Wallpaper::new(data, 960, 540);
+        assert_eq!(half.pixel_at(10, 20), 20 << 16 | 40);
 
-        if self.off == 0 {
-            if s.len() == 0 || s.as_bytes()[0] != b'<' {
-                return None;
-            }
-            if let Some(end_pos) = s.find('>') {
-                self.off = end_pos + 1;
-                self.s = Some(&s[1..end_pos]);
-            } else {
-                return None;
-            }
-        }
+        // 4:3 screens crop the sides of the 16:9 wallpaper.
+        let narrow = Wallpaper::new(data, 1440, 1080);
+        assert_eq!(narrow.pixel_at(0, 0), 240 << 16);
+        assert_eq!(narrow.pixel_at(1439, 1079), 1679 << 16 | 1079);
 
-        loop {
-            let s = self.s?;
-            if let Some(pos) = s.find(';') {
-                self.s = Some(&s[(pos + 1)..]);
-                match s[..pos].parse() {
-                    Ok(cmd) => break Some(cmd),
-                    Err(_) => continue,
-                }
-            } else {
-                break self.s.take().and_then(|s| s.parse().ok());
-            }
-        }
+        // Screens larger than the wallpaper upscale it.
+        let large = Wallpaper::new(data, 3840, 2400);
+        assert_eq!(large.offset_y, 0);
+        assert_eq!(large.pixel_at(3839, 2399), 1823 << 16 | 1079);
     }
 }
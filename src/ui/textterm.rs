@@ -0,0 +1,185 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! A terminal over a text screen, such as the VGA text mode used when the
+//! bootloader provided no linear framebuffer. It understands the same escape
+//! commands as `Terminal`, see `ui::escape`, but keeps no history: the text
+//! only lives in the screen's memory. It never allocates memory, so that panics can be reported
+//! onto it.
+
+use core::fmt;
+
+use crate::driver::screen::{CharAttrs, Color, TextScreen};
+use crate::ui::escape::{EscapeCommand, EscapeIterator};
+use crate::ui::theme::{self, TermColor, Theme};
+
+const TAB_WIDTH: usize = 8;
+
+const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+
+pub struct TextTerminal<S> {
+    screen: S,
+    theme: &'static Theme,
+    columns: usize,
+    rows: usize,
+    cursor_x: usize,
+    cursor_y: usize,
+    fg_color: TermColor,
+    bg_color: Option<TermColor>,
+}
+
+impl<S: TextScreen> TextTerminal<S> {
+    /// Create a terminal over `screen`, which is cleared.
+    pub fn new(mut screen: S) -> Self {
+        let (columns, rows) = screen.dimensions();
+        screen.clear();
+
+        Self {
+            screen,
+            theme: &theme::DARK,
+            columns,
+            rows,
+            cursor_x: 0,
+            cursor_y: 0,
+            fg_color: TermColor::Default,
+            bg_color: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.screen.clear();
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    pub fn write(&mut self, s: &str) {
+        let mut it = s.char_indices();
+
+        while let Some((i, c)) = it.next() {
+            if c == '\x1b' {
+                let mut escapes = EscapeIterator::new(&s[(i + 1)..]);
+                for cmd in &mut escapes {
+                    self.run_escape_command(cmd);
+                }
+                let _ = it.advance_by(escapes.continuation_offset());
+            }
+            self.putc(c);
+        }
+    }
+
+    pub fn putc(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\t' => {
+                let spaces = TAB_WIDTH - self.cursor_x % TAB_WIDTH;
+                for _ in 0..spaces {
+                    self.putc(' ');
+                }
+            },
+            '\r' => self.cursor_x = 0,
+            '\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            '\x00'..='\x1f' | '\x7f' => (),
+            c => {
+                if self.cursor_x >= self.columns {
+                    self.new_line();
+                }
+                let attrs = CharAttrs {
+                    color: self.theme.resolve(self.fg_color),
+                    bg_color: self.bg_color.map_or(BLACK,
+                                                   |c| self.theme.resolve(c)),
+                };
+                self.screen.put(self.cursor_x, self.cursor_y, c, attrs);
+                self.cursor_x += 1;
+            },
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.rows {
+            self.cursor_y += 1;
+        } else {
+            self.screen.scroll_up(1);
+        }
+    }
+
+    fn run_escape_command(&mut self, cmd: EscapeCommand) {
+        use EscapeCommand::*;
+
+        match cmd {
+            SetFgColor(c) => self.fg_color = c,
+            ClearFgColor => self.fg_color = TermColor::Default,
+            SetBgColor(c) => self.bg_color = Some(c),
+            ClearBgColor => self.bg_color = None,
+            Newline => {
+                if self.cursor_x > 0 {
+                    self.new_line();
+                }
+            },
+        }
+    }
+}
+
+impl<S: TextScreen> fmt::Write for TextTerminal<S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use super::*;
+
+    struct MockScreen {
+        columns: usize,
+        rows: usize,
+        chars: Vec<char>,
+    }
+
+    impl TextScreen for MockScreen {
+        fn dimensions(&self) -> (usize, usize) {
+            (self.columns, self.rows)
+        }
+
+        fn put(&mut self, x: usize, y: usize, c: char, _attrs: CharAttrs) {
+            self.chars[y * self.columns + x] = c;
+        }
+
+        fn scroll_up(&mut self, lines: u8) {
+            self.chars.drain(..(lines as usize * self.columns));
+            self.chars.resize(self.columns * self.rows, ' ');
+        }
+
+        fn clear(&mut self) {
+            self.chars.fill(' ');
+        }
+    }
+
+    fn text(term: &TextTerminal<MockScreen>) -> Vec<char> {
+        term.screen.chars.clone()
+    }
+
+    #[test]
+    fn it_wraps_and_scrolls() {
+        let mut term = TextTerminal::new(MockScreen {
+            columns: 4,
+            rows: 2,
+            chars: vec![' '; 8],
+        });
+
+        term.write("\x1b<fg=@red>ab\x1b<!fg>\ncdefg");
+
+        assert_eq!(text(&term), "cdefg   ".chars().collect::<Vec<_>>());
+        assert_eq!((term.cursor_x, term.cursor_y), (1, 1));
+    }
+}
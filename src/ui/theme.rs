@@ -0,0 +1,122 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! Terminal color themes. A theme defines a 16-color palette, in the usual
+//! ANSI order, and the default foreground color; text colored with palette
+//! entries rather than raw RGB follows the theme when it is changed.
+
+use core::str::FromStr;
+
+use crate::driver::screen::Color;
+
+pub struct Theme {
+    pub name: &'static str,
+    pub default_fg: Color,
+    pub palette: [Color; 16],
+}
+
+/// A color as requested by escape commands: either a raw RGB color, or an index
+/// into the current theme's palette, or the theme's default foreground color.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TermColor {
+    Rgb(Color),
+    Palette(u8),
+    Default,
+}
+
+/// The names of the palette entries, in index order.
+pub const PALETTE_NAMES: [&str; 16] = [
+    "black", "red", "green", "yellow",
+    "blue", "magenta", "cyan", "white",
+    "bright-black", "bright-red", "bright-green", "bright-yellow",
+    "bright-blue", "bright-magenta", "bright-cyan", "bright-white",
+];
+
+pub const DARK: Theme = Theme {
+    name: "dark",
+    default_fg: rgb(0xa9b7c6),
+    palette: [
+        rgb(0x000000), rgb(0xb21818), rgb(0x18b218), rgb(0xb26818),
+        rgb(0x1818b2), rgb(0xb218b2), rgb(0x18b2b2), rgb(0xb2b2b2),
+        rgb(0x686868), rgb(0xff5454), rgb(0x54ff54), rgb(0xffff54),
+        rgb(0x5454ff), rgb(0xff54ff), rgb(0x54ffff), rgb(0xffffff),
+    ],
+};
+
+pub const LIGHT: Theme = Theme {
+    name: "light",
+    default_fg: rgb(0x202020),
+    palette: [
+        rgb(0x000000), rgb(0xa01010), rgb(0x107010), rgb(0x806000),
+        rgb(0x1030a0), rgb(0x801080), rgb(0x107070), rgb(0x606060),
+        rgb(0x404040), rgb(0xd02020), rgb(0x209020), rgb(0xa08000),
+        rgb(0x2050d0), rgb(0xa020a0), rgb(0x209090), rgb(0x000000),
+    ],
+};
+
+pub const SOLARIZED: Theme = Theme {
+    name: "solarized",
+    default_fg: rgb(0x839496),
+    palette: [
+        rgb(0x073642), rgb(0xdc322f), rgb(0x859900), rgb(0xb58900),
+        rgb(0x268bd2), rgb(0xd33682), rgb(0x2aa198), rgb(0xeee8d5),
+        rgb(0x002b36), rgb(0xcb4b16), rgb(0x586e75), rgb(0x657b83),
+        rgb(0x839496), rgb(0x6c71c4), rgb(0x93a1a1), rgb(0xfdf6e3),
+    ],
+};
+
+pub const THEMES: [&Theme; 3] = [&DARK, &LIGHT, &SOLARIZED];
+
+const fn rgb(rgb: u32) -> Color {
+    Color {
+        r: (rgb >> 16) as u8,
+        g: (rgb >> 8) as u8,
+        b: rgb as u8,
+    }
+}
+
+impl Theme {
+    pub fn by_name(name: &str) -> Option<&'static Theme> {
+        THEMES.into_iter().find(|theme| theme.name == name)
+    }
+
+    pub fn resolve(&self, color: TermColor) -> Color {
+        match color {
+            TermColor::Rgb(color) => color,
+            TermColor::Palette(index) => self.palette[index as usize & 0xf],
+            TermColor::Default => self.default_fg,
+        }
+    }
+}
+
+/// Parses either a raw RGB color (`f80`, `ff8000`), or a palette entry as `@`
+/// followed by its index (`@9`) or its name (`@bright-red`).
+impl FromStr for TermColor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(entry) = s.strip_prefix('@') else {
+            return s.parse().map(TermColor::Rgb);
+        };
+
+        let index = match entry.parse::<u8>() {
+            Ok(index) => index,
+            Err(_) => PALETTE_NAMES.iter()
+                .position(|&name| name == entry)
+                .ok_or(())? as u8,
+        };
+
+        if (index as usize) < PALETTE_NAMES.len() {
+            Ok(TermColor::Palette(index))
+        } else {
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,308 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The TTY layer. A `Tty` is a terminal, made of an input device and a screen:
+//! the keyboard and the kernel terminal for `tty0`, or a serial line for
+//! `ttyS0`. Its driver feeds it the characters typed, which go through its
+//! line discipline, and it writes the echo and the output of its readers back
+//! through the driver's output function.
+//!
+//! In canonical mode, input is edited a line at a time by a `LineDiscipline`;
+//! in raw mode, every character is available to readers right away. Ctrl+C
+//! sends `SIGINT` to the foreground process unless signals are disabled. While
+//! there is no foreground process, the complete lines and interruptions go to
+//! the kernel console instead, for the kernel shell; the first process that
+//! reads the TTY becomes its foreground process.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::cmp::min;
+use core::fmt;
+
+use crate::fs::devfs::{put_values, Device};
+use crate::fs::FsError;
+use crate::sync::{Spinlock, WaitQueue};
+use crate::task::process;
+use crate::task::signal::{self, SIGINT};
+use crate::ui::console::{self, ConsoleEvent, LineDiscipline};
+
+/// `ioctl()` request: write the termios flags into the argument, as four
+/// `u32`s: the input, output, control and local flags.
+pub const TCGETS: u32 = 0x5401;
+
+/// `ioctl()` request: set the termios flags from the argument, laid out as for
+/// `TCGETS`; only the local flags are taken into account.
+pub const TCSETS: u32 = 0x5402;
+
+/// `ioctl()` request: write the PID of the foreground process into the
+/// argument, as a `u32`, 0 for none.
+pub const TIOCGPGRP: u32 = 0x540f;
+
+/// `ioctl()` request: make the process whose PID is the argument, as a `u32`,
+/// the foreground process; 0 for none.
+pub const TIOCSPGRP: u32 = 0x5410;
+
+/// Local flag: Ctrl+C sends `SIGINT`.
+pub const ISIG: u32 = 0x1;
+/// Local flag: canonical mode.
+pub const ICANON: u32 = 0x2;
+/// Local flag: echo the characters typed.
+pub const ECHO: u32 = 0x8;
+
+/// The most bytes of input kept for readers; any further byte is dropped.
+const MAX_INPUT_LEN: usize = 4096;
+
+/// How a TTY writes to its screen or line.
+pub type TtyOutput = fn(&[u8]);
+
+pub struct Tty {
+    output: TtyOutput,
+    state: Spinlock<TtyState>,
+    /// Notified when input is available to readers.
+    readable: WaitQueue,
+}
+
+struct TtyState {
+    /// The local flags, `ISIG`, `ICANON` and `ECHO`.
+    lflag: u32,
+    ldisc: LineDiscipline,
+    /// The input available to readers.
+    input: VecDeque<u8>,
+    /// The PID of the foreground process, if any.
+    foreground: Option<u32>,
+}
+
+/// A `fmt::Write` sink echoing through the output of a TTY, if enabled.
+struct Echo {
+    output: TtyOutput,
+    enabled: bool,
+}
+
+impl fmt::Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.enabled {
+            (self.output)(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl Tty {
+    /// A TTY writing to `output`, in canonical mode with echo and signals.
+    pub const fn new(output: TtyOutput) -> Self {
+        Self {
+            output,
+            state: Spinlock::new(TtyState {
+                lflag: ISIG | ICANON | ECHO,
+                ldisc: LineDiscipline::new(),
+                input: VecDeque::new(),
+                foreground: None,
+            }),
+            readable: WaitQueue::new(),
+        }
+    }
+
+    /// Process one character typed on the TTY; this is meant to be called by
+    /// its driver.
+    pub fn input(&self, c: char) {
+        let mut state = self.state.lock();
+        let mut echo = Echo {
+            output: self.output,
+            enabled: state.lflag & ECHO != 0,
+        };
+
+        if c == '\x03' && state.lflag & ISIG != 0 {
+            state.ldisc.input(c, &mut echo);
+            state.interrupt();
+            return;
+        }
+
+        if state.lflag & ICANON == 0 {
+            let mut bytes = [0; 4];
+            let _ = fmt::Write::write_char(&mut echo, c);
+            state.push_input(c.encode_utf8(&mut bytes).as_bytes());
+        } else {
+            match state.ldisc.input(c, &mut echo) {
+                Some(ConsoleEvent::Line(line)) => state.push_line(line),
+                _ => return,
+            }
+        }
+
+        drop(state);
+        self.readable.notify_all();
+    }
+
+    /// Make `pid` the foreground process, which receives the input and the
+    /// signals of the TTY; `None` to give them back to the kernel console.
+    pub fn set_foreground(&self, pid: Option<u32>) {
+        self.state.lock().foreground = pid;
+    }
+
+    pub fn foreground(&self) -> Option<u32> {
+        self.state.lock().foreground
+    }
+
+    /// Pop the input available into `buf`, at most one line in canonical mode;
+    /// `None` if there is none.
+    fn try_read(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut state = self.state.lock();
+        if state.input.is_empty() {
+            return None;
+        }
+
+        let mut len = min(buf.len(), state.input.len());
+        if state.lflag & ICANON != 0 {
+            if let Some(eol) = state.input.iter().position(|&b| b == b'\n') {
+                len = min(len, eol + 1);
+            }
+        }
+        for (dst, src) in buf[..len].iter_mut().zip(state.input.drain(..len)) {
+            *dst = src;
+        }
+        Some(len)
+    }
+}
+
+impl TtyState {
+    fn push_input(&mut self, bytes: &[u8]) {
+        let len = min(bytes.len(), MAX_INPUT_LEN - self.input.len());
+        self.input.extend(&bytes[..len]);
+    }
+
+    /// Hand a complete line over to the foreground process, or to the kernel
+    /// console if there is none.
+    fn push_line(&mut self, line: String) {
+        if self.foreground.is_some() {
+            self.push_input(line.as_bytes());
+            self.push_input(b"\n");
+        } else {
+            console::push_event(ConsoleEvent::Line(line));
+        }
+    }
+
+    /// Send `SIGINT` to the foreground process, discarding the input it didn't
+    /// read yet, or interrupt the kernel console if there is none. A
+    /// foreground process that is gone is forgotten.
+    fn interrupt(&mut self) {
+        self.input.clear();
+        match self.foreground {
+            Some(pid) if signal::send(pid, SIGINT).is_ok() => (),
+            _ => {
+                self.foreground = None;
+                console::push_event(ConsoleEvent::Interrupt);
+            },
+        }
+    }
+}
+
+impl Device for Tty {
+    /// Wait for input, unless the caller is not a process and there is no
+    /// foreground process to wait for: then fail with `WouldBlock`.
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(len) = self.try_read(buf) {
+            return Ok(len);
+        }
+
+        {
+            let mut state = self.state.lock();
+            if state.foreground.is_none() {
+                state.foreground = process::current();
+            }
+            if state.foreground.is_none() {
+                return Err(FsError::WouldBlock);
+            }
+        }
+        Ok(self.readable.wait_until(|| self.try_read(buf)))
+    }
+
+    fn write(&self, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        (self.output)(data);
+        Ok(data.len())
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> Result<usize, FsError> {
+        let mut state = self.state.lock();
+
+        match request {
+            TCGETS => put_values(arg, &[0, 0, 0, state.lflag]),
+            TCSETS => {
+                let [_, _, _, lflag] = get_values(arg)?;
+                if (state.lflag ^ lflag) & ICANON != 0 {
+                    state.ldisc = LineDiscipline::new();
+                }
+                state.lflag = lflag & (ISIG | ICANON | ECHO);
+                Ok(0)
+            },
+            TIOCGPGRP => put_values(arg, &[state.foreground.unwrap_or(0)]),
+            TIOCSPGRP => {
+                let [pid] = get_values(arg)?;
+                state.foreground = (pid != 0).then_some(pid);
+                Ok(0)
+            },
+            _ => Err(FsError::BadIoctl),
+        }
+    }
+}
+
+/// Read `N` values from the `ioctl()` argument `arg`.
+fn get_values<const N: usize>(arg: &[u8]) -> Result<[u32; N], FsError> {
+    let arg = arg.get(..(4 * N)).ok_or(FsError::InvalidArgument)?;
+
+    let mut values = [0; N];
+    for (value, bytes) in values.iter_mut().zip(arg.chunks_exact(4)) {
+        *value = u32::from_ne_bytes(bytes.try_into().unwrap());
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    static OUTPUT: Spinlock<Vec<u8>> = Spinlock::new(Vec::new());
+
+    fn capture(data: &[u8]) {
+        OUTPUT.lock().extend_from_slice(data);
+    }
+
+    #[test]
+    fn it_reads_input_in_canonical_and_raw_modes() {
+        let tty = Tty::new(capture);
+        // No such process: it only matters once interrupted.
+        tty.set_foreground(Some(u32::MAX));
+        "ab\x7fc\nd\n".chars().for_each(|c| tty.input(c));
+
+        let mut buf = [0; 16];
+        assert_eq!(tty.read(0, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ac\n");
+        assert_eq!(tty.read(0, &mut buf).unwrap(), 2);
+        assert_eq!(&OUTPUT.lock()[..], b"ab\x08 \x08c\nd\n");
+
+        let mut termios = [0; 16];
+        termios[12..].copy_from_slice(&ISIG.to_ne_bytes());
+        tty.ioctl(TCSETS, &mut termios).unwrap();
+        "xé".chars().for_each(|c| tty.input(c));
+        assert_eq!(tty.read(0, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], "xé".as_bytes());
+        assert_eq!(OUTPUT.lock().len(), 9);
+
+        let mut pid = [0; 4];
+        tty.ioctl(TIOCGPGRP, &mut pid).unwrap();
+        assert_eq!(u32::from_ne_bytes(pid), u32::MAX);
+        tty.input('\x03');
+        assert_eq!(tty.foreground(), None);
+        assert!(matches!(tty.ioctl(TCGETS, &mut pid),
+                         Err(FsError::InvalidArgument)));
+    }
+}
@@ -0,0 +1,171 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The width of characters on a terminal, and their grouping into grapheme
+//! clusters, i.e. what the user perceives as a single character. Widths
+//! follow the `wcwidth()` conventions: combining marks and format characters
+//! take no column, East Asian wide and fullwidth characters, as well as most
+//! emojis, take two. The clustering is a simplification of UAX #29 that only
+//! attaches zero-width characters, and whatever follows a zero width joiner,
+//! to the preceding character.
+
+use core::ops::RangeInclusive;
+
+pub const ZERO_WIDTH_JOINER: char = '\u{200d}';
+
+/// The characters taking no column: combining marks, which are drawn over the
+/// preceding character, and invisible format characters.
+const ZERO_WIDTH: &[RangeInclusive<u32>] = &[
+    0x0300..=0x036f, 0x0483..=0x0489, 0x0591..=0x05bd, 0x05bf..=0x05bf,
+    0x05c1..=0x05c2, 0x05c4..=0x05c5, 0x05c7..=0x05c7, 0x0610..=0x061a,
+    0x064b..=0x065f, 0x0670..=0x0670, 0x06d6..=0x06dc, 0x06df..=0x06e4,
+    0x06e7..=0x06e8, 0x06ea..=0x06ed, 0x0711..=0x0711, 0x0730..=0x074a,
+    0x07a6..=0x07b0, 0x0900..=0x0902, 0x093a..=0x093a, 0x093c..=0x093c,
+    0x0941..=0x0948, 0x094d..=0x094d, 0x0951..=0x0957, 0x0962..=0x0963,
+    0x0981..=0x0981, 0x09bc..=0x09bc, 0x09c1..=0x09c4, 0x09cd..=0x09cd,
+    0x0e31..=0x0e31, 0x0e34..=0x0e3a, 0x0e47..=0x0e4e, 0x1160..=0x11ff,
+    0x1ab0..=0x1aff, 0x1dc0..=0x1dff, 0x200b..=0x200f, 0x202a..=0x202e,
+    0x2060..=0x2064, 0x20d0..=0x20ff, 0xfe00..=0xfe0f, 0xfe20..=0xfe2f,
+    0xfeff..=0xfeff, 0x1f3fb..=0x1f3ff, 0xe0020..=0xe007f, 0xe0100..=0xe01ef,
+];
+
+/// The characters taking two columns.
+const WIDE: &[RangeInclusive<u32>] = &[
+    0x1100..=0x115f, 0x231a..=0x231b, 0x2329..=0x232a, 0x23e9..=0x23ec,
+    0x23f0..=0x23f0, 0x23f3..=0x23f3, 0x25fd..=0x25fe, 0x2614..=0x2615,
+    0x2648..=0x2653, 0x267f..=0x267f, 0x2693..=0x2693, 0x26a1..=0x26a1,
+    0x26aa..=0x26ab, 0x26bd..=0x26be, 0x26c4..=0x26c5, 0x26ce..=0x26ce,
+    0x26d4..=0x26d4, 0x26ea..=0x26ea, 0x26f2..=0x26f3, 0x26f5..=0x26f5,
+    0x26fa..=0x26fa, 0x26fd..=0x26fd, 0x2705..=0x2705, 0x270a..=0x270b,
+    0x2728..=0x2728, 0x274c..=0x274c, 0x274e..=0x274e, 0x2753..=0x2755,
+    0x2757..=0x2757, 0x2795..=0x2797, 0x27b0..=0x27b0, 0x27bf..=0x27bf,
+    0x2b1b..=0x2b1c, 0x2b50..=0x2b50, 0x2b55..=0x2b55, 0x2e80..=0x303e,
+    0x3041..=0x33ff, 0x3400..=0x4dbf, 0x4e00..=0x9fff, 0xa000..=0xa4cf,
+    0xa960..=0xa97f, 0xac00..=0xd7a3, 0xf900..=0xfaff, 0xfe10..=0xfe19,
+    0xfe30..=0xfe6f, 0xff00..=0xff60, 0xffe0..=0xffe6, 0x1f004..=0x1f004,
+    0x1f0cf..=0x1f0cf, 0x1f18e..=0x1f18e, 0x1f191..=0x1f19a, 0x1f200..=0x1f202,
+    0x1f210..=0x1f23b, 0x1f240..=0x1f248, 0x1f250..=0x1f251, 0x1f300..=0x1f64f,
+    0x1f680..=0x1f6ff, 0x1f900..=0x1f9ff, 0x1fa70..=0x1faff, 0x20000..=0x2fffd,
+    0x30000..=0x3fffd,
+];
+
+/// The number of columns `c` takes on a terminal; control characters take
+/// none.
+pub fn char_width(c: char) -> usize {
+    if c.is_control() || in_table(ZERO_WIDTH, c) {
+        0
+    } else if in_table(WIDE, c) {
+        2
+    } else {
+        1
+    }
+}
+
+/// The number of columns `s` takes on a terminal.
+pub fn str_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// Whether `c`, following `prev`, belongs to the same grapheme cluster.
+pub fn extends_cluster(prev: char, c: char) -> bool {
+    if c.is_control() || prev.is_control() {
+        return false;
+    }
+
+    prev == ZERO_WIDTH_JOINER || in_table(ZERO_WIDTH, c)
+}
+
+/// Split `s` into its grapheme clusters.
+pub fn graphemes(s: &str) -> Graphemes {
+    Graphemes { s }
+}
+
+pub struct Graphemes<'a> {
+    s: &'a str,
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chars = self.s.char_indices();
+        let (_, mut prev) = chars.next()?;
+
+        let end = chars
+            .find(|&(_, c)| !extends_cluster(core::mem::replace(&mut prev, c), c))
+            .map_or(self.s.len(), |(i, _)| i);
+        let (cluster, rest) = self.s.split_at(end);
+        self.s = rest;
+
+        Some(cluster)
+    }
+}
+
+impl<'a> DoubleEndedIterator for Graphemes<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let mut chars = self.s.char_indices().rev().peekable();
+        let (mut start, mut c) = chars.next()?;
+
+        while let Some(&(i, prev)) = chars.peek() {
+            if !extends_cluster(prev, c) {
+                break;
+            }
+            (start, c) = (i, prev);
+            chars.next();
+        }
+        let (rest, cluster) = self.s.split_at(start);
+        self.s = rest;
+
+        Some(cluster)
+    }
+}
+
+fn in_table(table: &[RangeInclusive<u32>], c: char) -> bool {
+    let c = c as u32;
+
+    table.binary_search_by(|range| {
+        if *range.end() < c {
+            core::cmp::Ordering::Less
+        } else if *range.start() > c {
+            core::cmp::Ordering::Greater
+        } else {
+            core::cmp::Ordering::Equal
+        }
+    }).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn it_computes_character_widths() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('λ'), 1);
+        assert_eq!(char_width('\u{301}'), 0);
+        assert_eq!(char_width(ZERO_WIDTH_JOINER), 0);
+        assert_eq!(char_width('漢'), 2);
+        assert_eq!(char_width('😀'), 2);
+        assert_eq!(char_width('\x1b'), 0);
+        assert_eq!(str_width("e\u{301}té 日本"), 8);
+    }
+
+    #[test]
+    fn it_splits_grapheme_clusters() {
+        let s = "e\u{301}a\u{20d7}\u{301}b👩\u{200d}💻";
+
+        assert_eq!(graphemes(s).collect::<Vec<_>>(),
+                   ["e\u{301}", "a\u{20d7}\u{301}", "b", "👩\u{200d}💻"]);
+        assert_eq!(graphemes(s).rev().collect::<Vec<_>>(),
+                   ["👩\u{200d}💻", "b", "a\u{20d7}\u{301}", "e\u{301}"]);
+        assert_eq!(graphemes("").next(), None);
+    }
+}
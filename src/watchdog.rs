@@ -0,0 +1,157 @@
+/******************************************************************************
+ * Copyright © 2021-2023 Kévin Lesénéchal <kevin.lesenechal@gmail.com>        *
+ * This file is part of the Nucloid operating system.                         *
+ *                                                                            *
+ * Nucloid is free software; you can redistribute it and/or modify it under   *
+ * the terms of the GNU General Public License as published by the Free       *
+ * Software Foundation; either version 2 of the License, or (at your option)  *
+ * any later version. See LICENSE file for more information.                  *
+ ******************************************************************************/
+
+//! The hang watchdog. Every CPU records a heartbeat on each timer tick, then
+//! checks the heartbeats of the others: a CPU that didn't tick for longer than
+//! the timeout is reported as stuck and sent a non-maskable interrupt, whose
+//! handler logs its registers and a backtrace of the code it was stuck in. A
+//! stuck CPU is reported once, until it ticks again.
+//!
+//! The watchdog is disabled by default; the `watchdog=<secs>` boot option
+//! enables it with the given timeout. It needs at least two CPUs, as a CPU
+//! can't watch itself: until the application processors are brought up, see
+//! `task::smp`, it has nothing to watch.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::arch;
+use crate::arch::cpu::MachineState;
+use crate::arch::time::timestamp;
+use crate::time::timestamp_frequency_or_default;
+use crate::task::cpu::{current_cpu_index, MAX_CPUS};
+use crate::task::idle::nr_cpus;
+use crate::warning;
+
+/// The number of backtrace frames logged per report.
+const REPORT_DEPTH: usize = 16;
+
+/// The timeout in timestamp cycles; zero if the watchdog is disabled.
+static TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+static HEARTBEATS: [Heartbeat; MAX_CPUS]
+    = [const { Heartbeat::new() }; MAX_CPUS];
+
+struct Heartbeat {
+    /// The timestamp of the CPU's last tick; zero if it never ticked.
+    last_tick: AtomicU64,
+    /// Set once the CPU was reported as stuck, until it ticks again.
+    reported: AtomicBool,
+    /// Set when the CPU is sent a non-maskable interrupt to dump its state.
+    dump_requested: AtomicBool,
+}
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Self {
+            last_tick: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            dump_requested: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Enable the watchdog with a timeout of `timeout_s` seconds, or disable it
+/// with `None`.
+pub fn set_timeout_s(timeout_s: Option<u64>) {
+    let cycles = match timeout_s {
+        Some(secs) => {
+            secs.saturating_mul(timestamp_frequency_or_default()).max(1)
+        },
+        None => 0,
+    };
+    TIMEOUT.store(cycles, Ordering::SeqCst);
+}
+
+/// The timeout in seconds; `None` if the watchdog is disabled.
+pub fn timeout_s() -> Option<u64> {
+    match TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        cycles => Some(cycles / timestamp_frequency_or_default()),
+    }
+}
+
+/// Record a heartbeat for the current CPU and report the other CPUs that are
+/// stuck; this is meant to be called from the timer interrupt handler.
+pub fn tick() {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return;
+    }
+
+    let now = timestamp();
+    let this_cpu = current_cpu_index().get();
+    let heartbeat = &HEARTBEATS[this_cpu];
+    heartbeat.last_tick.store(now, Ordering::SeqCst);
+    heartbeat.reported.store(false, Ordering::SeqCst);
+
+    for cpu in (0..nr_cpus()).filter(|&cpu| cpu != this_cpu) {
+        let heartbeat = &HEARTBEATS[cpu];
+        let last = heartbeat.last_tick.load(Ordering::SeqCst);
+        if is_stale(last, now, timeout)
+            && !heartbeat.reported.swap(true, Ordering::SeqCst) {
+            report(cpu, now - last);
+        }
+    }
+}
+
+#[cold]
+fn report(cpu: usize, elapsed: u64) {
+    warning!("watchdog: CPU {cpu} stuck for {} s",
+             elapsed / timestamp_frequency_or_default());
+    HEARTBEATS[cpu].dump_requested.store(true, Ordering::SeqCst);
+    arch::smp::send_nmi(cpu);
+}
+
+/// Log the state of the current CPU if the watchdog requested it, and return
+/// whether it did; this is meant to be called by the non-maskable interrupt
+/// handler, with the interrupted `machine` state.
+pub fn on_nmi(machine: &MachineState) -> bool {
+    let cpu = current_cpu_index().get();
+    if !HEARTBEATS[cpu].dump_requested.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+
+    warning!("watchdog: CPU {cpu} state:\n{machine}");
+    log_backtrace(machine);
+    true
+}
+
+#[cfg(not(test))]
+fn log_backtrace(machine: &MachineState) {
+    use crate::backtrace::Backtrace;
+
+    let frames = Backtrace::from_machine_state(machine).take(REPORT_DEPTH);
+    for frame in frames {
+        warning!("watchdog:   at 0x{:?}", frame.pc);
+    }
+}
+
+#[cfg(test)]
+fn log_backtrace(_machine: &MachineState) {
+}
+
+/// Whether a CPU whose last tick was at `last` is stuck at `now`; a CPU that
+/// never ticked isn't, as it may not be brought up yet.
+fn is_stale(last: u64, now: u64, timeout: u64) -> bool {
+    last != 0 && now.saturating_sub(last) >= timeout
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_detects_stale_heartbeats() {
+        assert!(!is_stale(0, 5_000, 1_000));
+        assert!(!is_stale(4_500, 5_000, 1_000));
+        assert!(is_stale(4_000, 5_000, 1_000));
+        assert!(!is_stale(6_000, 5_000, 1_000));
+    }
+}